@@ -0,0 +1,177 @@
+//! Benchmarks of the hot kernels: fragment parsing and decoding, bedgraph
+//! merging, kNN search and feature counting. The inputs are synthetic and
+//! generated from fixed seeds, so that timings are comparable across runs.
+
+use anndata::data::CsrNonCanonical;
+use bed_utils::bed::{map::GIntervalIndexSet, BEDLike, BedGraph, GenomicRange, MergeBed};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ndarray::Array2;
+use rand::Rng;
+use snapatac2_core::feature_count::{
+    CompressedFragmentIter, CountingStrategy, FragmentData, RegionCounter,
+};
+use snapatac2_core::genome::ChromSizes;
+use snapatac2_core::preprocessing::PairRead;
+use snapatac2_core::utils::knn::{
+    approximate_nearest_neighbour_graph, exact_nearest_neighbour_graph, nearest_neighbour_graph,
+};
+use snapatac2_core::utils::matmul::CpuBackend;
+use snapatac2_core::utils::rng::{rng_from_seed, Rng as SeededRng};
+use std::str::FromStr;
+
+const CHROM_SIZE: u64 = 10_000_000;
+
+fn chrom_sizes() -> ChromSizes {
+    [("chr1", CHROM_SIZE), ("chr2", CHROM_SIZE)].into_iter().collect()
+}
+
+/// Fragments as they appear in a fragment file, sorted by position.
+fn fragment_lines(n: usize, rng: &mut SeededRng) -> Vec<String> {
+    let mut starts: Vec<u64> = (0..n).map(|_| rng.random_range(0..CHROM_SIZE - 1000)).collect();
+    starts.sort_unstable();
+    starts
+        .into_iter()
+        .map(|start| {
+            let len = rng.random_range(50..800);
+            let barcode = rng.random_range(0..1000);
+            format!("chr1\t{}\t{}\tBC{:04}\t1", start, start + len, barcode)
+        })
+        .collect()
+}
+
+/// Paired-end fragments stored in the compressed format of `.obsm`, i.e., a
+/// cell-by-position matrix whose values are fragment lengths.
+fn compressed_fragments(
+    n_cells: usize,
+    frags_per_cell: usize,
+    rng: &mut SeededRng,
+) -> Vec<(CsrNonCanonical<u32>, usize, usize)> {
+    let genome_size = 2 * CHROM_SIZE as usize;
+    let mut offsets = vec![0];
+    let mut indices = Vec::with_capacity(n_cells * frags_per_cell);
+    let mut data = Vec::with_capacity(n_cells * frags_per_cell);
+    for _ in 0..n_cells {
+        let mut pos: Vec<usize> =
+            (0..frags_per_cell).map(|_| rng.random_range(0..genome_size - 1000)).collect();
+        pos.sort_unstable();
+        indices.extend(pos);
+        data.extend((0..frags_per_cell).map(|_| rng.random_range(50u32..800)));
+        offsets.push(indices.len());
+    }
+    let mat = CsrNonCanonical::from_csr_data(n_cells, genome_size, offsets, indices, data);
+    vec![(mat, 0, n_cells)]
+}
+
+fn fragment_data(chunks: &[(CsrNonCanonical<u32>, usize, usize)]) -> FragmentData {
+    FragmentData::new(
+        chrom_sizes(),
+        CompressedFragmentIter::FragmentPaired(Box::new(chunks.to_vec().into_iter())),
+    )
+}
+
+fn bench_fragment_io(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Fragment");
+    group.sample_size(20);
+    let mut rng = rng_from_seed(0);
+
+    let lines = fragment_lines(100_000, &mut rng);
+    group.throughput(Throughput::Elements(lines.len() as u64));
+    group.bench_function("parse 100k lines", |b| {
+        b.iter(|| lines.iter().map(|x| PairRead::from_str(x).unwrap()).count())
+    });
+
+    let chunks = compressed_fragments(1000, 100, &mut rng);
+    group.throughput(Throughput::Elements(100_000));
+    group.bench_function("decode 1000 cells x 100", |b| {
+        b.iter(|| {
+            fragment_data(&chunks)
+                .into_fragments()
+                .map(|(x, _, _)| x.into_iter().map(|x| x.len()).sum::<usize>())
+                .sum::<usize>()
+        })
+    });
+    group.finish();
+}
+
+fn bench_bedgraph(c: &mut Criterion) {
+    let mut group = c.benchmark_group("BedGraph");
+    group.sample_size(20);
+    let mut rng = rng_from_seed(1);
+
+    for n in [10_000usize, 100_000] {
+        let mut bedgraph: Vec<BedGraph<f64>> = (0..n)
+            .map(|_| {
+                let start = rng.random_range(0..CHROM_SIZE - 1000);
+                let end = start + rng.random_range(50..800);
+                BedGraph::new("chr1", start, end, 1.0)
+            })
+            .collect();
+        bedgraph.sort_by(|a, b| a.start().cmp(&b.start()));
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::new("merge", n), &bedgraph, |b, x| {
+            b.iter(|| x.clone().into_iter().merge_sorted_bedgraph().count())
+        });
+    }
+    group.finish();
+}
+
+fn bench_knn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("kNN");
+    group.sample_size(10);
+    let mut rng = rng_from_seed(2);
+
+    for n in [1000usize, 5000] {
+        let data = Array2::from_shape_fn((n, 30), |_| rng.random::<f64>());
+        let data_f32 = data.mapv(|x| x as f32);
+        group.bench_with_input(BenchmarkId::new("kdtree", n), &data, |b, x| {
+            b.iter(|| nearest_neighbour_graph(x, 15))
+        });
+        group.bench_with_input(BenchmarkId::new("exact", n), &data, |b, x| {
+            b.iter(|| exact_nearest_neighbour_graph(x.view(), 15, 1024, &CpuBackend).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("hnsw", n), &data_f32, |b, x| {
+            b.iter(|| approximate_nearest_neighbour_graph(x, 15))
+        });
+    }
+    group.finish();
+}
+
+fn bench_counting(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Counting");
+    group.sample_size(10);
+    let mut rng = rng_from_seed(3);
+    let chunks = compressed_fragments(1000, 100, &mut rng);
+
+    group.bench_function("tile matrix (500bp)", |b| {
+        b.iter(|| {
+            fragment_data(&chunks)
+                .with_resolution(500)
+                .into_array_iter()
+                .map(|(x, _, _)| x.nnz())
+                .sum::<usize>()
+        })
+    });
+
+    let peaks: GIntervalIndexSet = (0..CHROM_SIZE / 5000)
+        .flat_map(|i| {
+            ["chr1", "chr2"]
+                .into_iter()
+                .map(move |chr| GenomicRange::new(chr, i * 5000, i * 5000 + 500))
+        })
+        .collect();
+    for strategy in [CountingStrategy::Insertion, CountingStrategy::Fragment, CountingStrategy::PIC] {
+        group.bench_function(format!("peak matrix ({:?})", strategy), |b| {
+            b.iter(|| {
+                fragment_data(&chunks)
+                    .set_counting_strategy(strategy)
+                    .into_aggregated_array_iter(RegionCounter::<u32>::new(&peaks))
+                    .map(|(x, _, _)| x.nnz())
+                    .sum::<usize>()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_fragment_io, bench_bedgraph, bench_knn, bench_counting);
+criterion_main!(benches);
@@ -0,0 +1,430 @@
+//! Tn5 insertion bias.
+//!
+//! Tn5 inserts preferentially at certain sequences, so the coverage of a
+//! region partly reflects its sequence composition rather than its
+//! accessibility. [`BiasModel`] estimates the bias of every k-mer centred on
+//! an insertion site as the ratio between its frequency at the observed
+//! insertion sites and its frequency in the genome. The k-mer is read on the
+//! strand of the read at the insertion, so that the bias of the two ends of a
+//! fragment is learned on the same footing. Coverage is corrected by
+//! weighting every insertion by the inverse of the bias of its k-mer, and the
+//! expected bias of a region can be computed from its sequence alone.
+
+use anndata::data::utils::to_csr_data;
+use anndata::{AnnDataOp, ElemCollectionOp};
+use anyhow::{ensure, Context, Result};
+use bed_utils::bed::{BEDLike, BedGraph, GenomicRange, Strand};
+use indexmap::IndexSet;
+use nalgebra_sparse::CsrMatrix;
+use noodles::{core::Region, fasta};
+use rand::Rng as _;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Seek};
+use std::path::{Path, PathBuf};
+
+use crate::feature_count::SnapData;
+use crate::utils::{open_file_for_read, rng::SeedStream};
+
+/// Key for storing the bias model in `.uns`.
+pub const BIAS_MODEL: &str = "tn5_bias";
+
+/// 2-bit encoding of a nucleotide.
+fn encode_base(b: u8) -> Option<usize> {
+    match b {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+fn encode_kmer(seq: &[u8]) -> Option<usize> {
+    seq.iter().try_fold(0, |acc, b| Some((acc << 2) | encode_base(*b)?))
+}
+
+/// Call `f` with the 2-bit encoding of every k-mer of `seq` without
+/// ambiguous bases.
+fn for_each_kmer<F: FnMut(usize)>(seq: &[u8], k: usize, mut f: F) {
+    let mask = (1 << (2 * k)) - 1;
+    let mut kmer = 0;
+    let mut n_valid = 0;
+    for b in seq {
+        match encode_base(*b) {
+            Some(x) => {
+                kmer = ((kmer << 2) | x) & mask;
+                n_valid += 1;
+                if n_valid >= k {
+                    f(kmer);
+                }
+            }
+            None => n_valid = 0,
+        }
+    }
+}
+
+/// Count all k-mers of `seq` without ambiguous bases.
+fn count_kmers(seq: &[u8], k: usize, counts: &mut [u64]) {
+    for_each_kmer(seq, k, |kmer| counts[kmer] += 1);
+}
+
+/// Index of the reverse complement of a k-mer given by its 2-bit encoding.
+fn revcomp_kmer(kmer: usize, k: usize) -> usize {
+    (0..k).fold(0, |acc, i| (acc << 2) | (3 - ((kmer >> (2 * i)) & 3)))
+}
+
+/// The columns of the k-mer count matrix: the k-mers in the order of their
+/// 2-bit encoding, keeping only the smaller of a k-mer and its reverse
+/// complement if `collapse_strand` is true. Returns the column of every k-mer
+/// and the names of the columns.
+pub fn kmer_columns(k: usize, collapse_strand: bool) -> (Vec<usize>, Vec<String>) {
+    let mut columns = vec![0; 1 << (2 * k)];
+    let mut names = Vec::new();
+    for kmer in 0..columns.len() {
+        let rc = revcomp_kmer(kmer, k);
+        if collapse_strand && rc < kmer {
+            columns[kmer] = columns[rc];
+        } else {
+            columns[kmer] = names.len();
+            names.push(
+                (0..k)
+                    .rev()
+                    .map(|i| b"ACGT"[(kmer >> (2 * i)) & 3] as char)
+                    .collect(),
+            );
+        }
+    }
+    (columns, names)
+}
+
+/// Count the k-mers of every region. Positions beyond the end of a chromosome
+/// are ignored, and k-mers containing ambiguous bases are not counted.
+/// Returns the region by k-mer matrix and the names of the k-mers; see
+/// [`kmer_columns`] for the order of the columns. The matrix is sparse, as a
+/// region contains at most as many distinct k-mers as bases.
+pub fn region_kmer_counts<R: BufRead + Seek>(
+    genome: &mut GenomeSequence<R>,
+    regions: &[GenomicRange],
+    k: usize,
+    collapse_strand: bool,
+) -> Result<(CsrMatrix<u32>, Vec<String>)> {
+    ensure!((1..=12).contains(&k), "k must be between 1 and 12");
+    let (columns, names) = kmer_columns(k, collapse_strand);
+    let mut rows = vec![Vec::new(); regions.len()];
+    // Visit the regions by chromosome, so that every chromosome is read once.
+    let mut order: Vec<usize> = (0..regions.len()).collect();
+    order.sort_by(|a, b| regions[*a].chrom().cmp(regions[*b].chrom()));
+    for i in order {
+        let region = &regions[i];
+        let seq = genome.fetch(region.chrom())?;
+        let end = (region.end() as usize).min(seq.len());
+        let start = (region.start() as usize).min(end);
+        let mut kmers = Vec::with_capacity(end - start);
+        for_each_kmer(&seq[start..end], k, |kmer| kmers.push(columns[kmer]));
+        kmers.sort_unstable();
+        rows[i] = kmers
+            .chunk_by(|a, b| a == b)
+            .map(|x| (x[0], x.len() as u32))
+            .collect();
+    }
+    let (r, c, offset, ind, data) = to_csr_data(rows, names.len());
+    Ok((CsrMatrix::try_from_csr_data(r, c, offset, ind, data)?, names))
+}
+
+/// GC content of every region, i.e., the fraction of G and C among the
+/// unambiguous bases. Positions beyond the end of a chromosome are ignored,
+/// and regions without unambiguous bases have a GC content of NaN.
+pub fn region_gc_content<R: BufRead + Seek>(
+    genome: &mut GenomeSequence<R>,
+    regions: &[GenomicRange],
+) -> Result<Vec<f64>> {
+    let mut result = vec![f64::NAN; regions.len()];
+    let mut order: Vec<usize> = (0..regions.len()).collect();
+    order.sort_by(|a, b| regions[*a].chrom().cmp(regions[*b].chrom()));
+    for i in order {
+        let region = &regions[i];
+        let seq = genome.fetch(region.chrom())?;
+        let end = (region.end() as usize).min(seq.len());
+        let start = (region.start() as usize).min(end);
+        let mut counts = [0u64; 4];
+        seq[start..end]
+            .iter()
+            .filter_map(|b| encode_base(*b))
+            .for_each(|x| counts[x] += 1);
+        let n = counts.iter().sum::<u64>();
+        if n > 0 {
+            result[i] = (counts[1] + counts[2]) as f64 / n as f64;
+        }
+    }
+    Ok(result)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BiasModel {
+    pub k: usize,
+    /// Number of sampled insertion sites at each k-mer, read on the strand of
+    /// the insertion and indexed by the 2-bit encoding of the k-mer.
+    pub observed: Vec<u64>,
+    /// Number of occurrences of each k-mer on both strands of the genome.
+    pub background: Vec<u64>,
+    /// Bias of each k-mer, normalized so that the average bias of the genome is 1.
+    pub bias: Vec<f64>,
+}
+
+impl BiasModel {
+    pub fn new(k: usize, observed: Vec<u64>, background: Vec<u64>) -> Self {
+        // A pseudocount of one keeps the bias of rare k-mers finite.
+        let n_obs = observed.iter().sum::<u64>() as f64 + observed.len() as f64;
+        let n_bg = background.iter().sum::<u64>() as f64 + background.len() as f64;
+        let bias = observed
+            .iter()
+            .zip(&background)
+            .map(|(o, b)| ((*o as f64 + 1.0) / n_obs) / ((*b as f64 + 1.0) / n_bg))
+            .collect();
+        Self { k, observed, background, bias }
+    }
+
+    /// Read the model stored in `.uns` of `adata`, if any.
+    pub fn read<A: SnapData>(adata: &A) -> Result<Option<Self>> {
+        adata
+            .uns()
+            .get_item::<String>(BIAS_MODEL)?
+            .map(|x| {
+                serde_json::from_str(&x)
+                    .with_context(|| format!("invalid bias model in '.uns[\"{}\"]'", BIAS_MODEL))
+            })
+            .transpose()
+    }
+
+    /// Store the model in `.uns` of `adata`, replacing any existing model.
+    pub fn write<A: SnapData>(&self, adata: &A) -> Result<()> {
+        adata.uns().add(BIAS_MODEL, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Index of the k-mer centred on the insertion at `pos` (0-based) and read
+    /// on `strand`, if it lies within `seq` and contains no ambiguous bases.
+    /// On the reverse strand, the k-mer is the reverse complement of the
+    /// window mirrored around the insertion.
+    pub fn kmer_at(&self, seq: &[u8], pos: u64, strand: Strand) -> Option<usize> {
+        let k = self.k as u64;
+        let start = match strand {
+            Strand::Forward => pos.checked_sub(k / 2)?,
+            Strand::Reverse => (pos + k / 2 + 1).checked_sub(k)?,
+        } as usize;
+        let kmer = encode_kmer(seq.get(start..start + self.k)?)?;
+        Some(match strand {
+            Strand::Forward => kmer,
+            Strand::Reverse => revcomp_kmer(kmer, self.k),
+        })
+    }
+
+    /// Bias of the insertion at `pos` read on `strand`. Insertions whose k-mer
+    /// is unknown have a bias of 1.
+    pub fn bias_at(&self, seq: &[u8], pos: u64, strand: Strand) -> f64 {
+        self.kmer_at(seq, pos, strand).map_or(1.0, |i| self.bias[i])
+    }
+
+    /// Learn the bias from a random sample of at most `max_insertions`
+    /// insertion sites of `adata`, and the genome sequence in `genome` (FASTA,
+    /// optionally compressed). Chromosome names must match those of `adata`.
+    pub fn fit<A: SnapData, P: AsRef<Path>>(
+        adata: &A,
+        genome: P,
+        k: usize,
+        max_insertions: usize,
+        seed: u64,
+    ) -> Result<Self> {
+        ensure!((1..=12).contains(&k), "k must be between 1 and 12");
+        ensure!(max_insertions > 0, "max_insertions must be positive");
+
+        // Reservoir sampling of the insertion sites.
+        let mut rng = SeedStream::new(seed).derive("tn5_bias").rng(0);
+        let mut chroms = IndexSet::new();
+        let mut sample: Vec<(usize, u64, Strand)> = Vec::new();
+        let mut n_seen = 0u64;
+        adata
+            .get_fragment_iter(adata.fragment_chunk_size()?)?
+            .into_fragments()
+            .for_each(|(fragments, _, _)| {
+                fragments.into_iter().flatten().for_each(|frag| {
+                    frag.to_stranded_insertions().into_iter().for_each(|(ins, strand)| {
+                        n_seen += 1;
+                        let i = if sample.len() < max_insertions {
+                            sample.push((0, 0, strand));
+                            sample.len() - 1
+                        } else {
+                            match rng.random_range(0..n_seen) as usize {
+                                j if j < max_insertions => j,
+                                _ => return,
+                            }
+                        };
+                        let (c, _) = chroms.insert_full(ins.chrom().to_string());
+                        sample[i] = (c, ins.start(), strand);
+                    })
+                })
+            });
+        let mut sites = vec![Vec::new(); chroms.len()];
+        sample.into_iter().for_each(|(c, pos, strand)| sites[c].push((pos, strand)));
+
+        let mut model = Self::new(k, vec![0; 1 << (2 * k)], vec![0; 1 << (2 * k)]);
+        let mut reader = fasta::io::Reader::new(BufReader::new(open_file_for_read(&genome)?));
+        for record in reader.records() {
+            let record = record
+                .with_context(|| format!("cannot read genome: {}", genome.as_ref().display()))?;
+            let name = String::from_utf8_lossy(record.name());
+            if let Some(c) = chroms.get_index_of(name.as_ref()) {
+                let seq = record.sequence().as_ref();
+                sites[c].iter().for_each(|(pos, strand)| {
+                    if let Some(i) = model.kmer_at(seq, *pos, *strand) {
+                        model.observed[i] += 1;
+                    }
+                });
+                count_kmers(seq, k, &mut model.background);
+            }
+        }
+        ensure!(
+            model.observed.iter().any(|x| *x > 0),
+            "no insertion site is found in the genome sequence, please check that chromosome names match",
+        );
+        // Insertions are read on both strands, so the background counts the
+        // k-mers of the reverse strand as well.
+        let background = (0..model.background.len())
+            .map(|i| model.background[i] + model.background[revcomp_kmer(i, k)])
+            .collect();
+        Ok(Self::new(k, model.observed, background))
+    }
+
+    /// Expected bias of every bin of `bin_size` bases of a chromosome, i.e.,
+    /// the average bias of the insertion sites in the bin, on either strand.
+    pub fn expected_bias<R: BufRead + Seek>(
+        &self,
+        genome: &mut GenomeSequence<R>,
+        chrom: &str,
+        bin_size: u64,
+    ) -> Result<Vec<BedGraph<f64>>> {
+        let seq = genome.fetch(chrom)?;
+        let len = seq.len() as u64;
+        Ok((0..len)
+            .step_by(bin_size.max(1) as usize)
+            .map(|start| {
+                let end = (start + bin_size).min(len);
+                let total: f64 = (start..end)
+                    .map(|pos| {
+                        self.bias_at(seq, pos, Strand::Forward) + self.bias_at(seq, pos, Strand::Reverse)
+                    })
+                    .sum();
+                BedGraph::from_bed(
+                    &GenomicRange::new(chrom, start, end),
+                    total / (2 * (end - start)) as f64,
+                )
+            })
+            .collect())
+    }
+}
+
+/// Random access to the chromosome sequences of an indexed FASTA file. The
+/// sequence of the last chromosome accessed is kept in memory, so callers
+/// should access the positions of one chromosome at a time.
+pub struct GenomeSequence<R> {
+    reader: fasta::io::IndexedReader<R>,
+    current: Option<(String, Vec<u8>)>,
+}
+
+impl GenomeSequence<()> {
+    /// Open a FASTA file, creating its index (`.fai`) if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<GenomeSequence<impl BufRead + Seek>> {
+        let path = path.as_ref();
+        let mut fai = path.as_os_str().to_owned();
+        fai.push(".fai");
+        let fai = PathBuf::from(fai);
+        if !fai.exists() {
+            let index = fasta::fs::index(path)
+                .with_context(|| format!("cannot index genome: {}", path.display()))?;
+            fasta::fai::fs::write(&fai, &index)
+                .with_context(|| format!("cannot write genome index: {}", fai.display()))?;
+        }
+        let reader = fasta::io::indexed_reader::Builder::default()
+            .build_from_path(path)
+            .with_context(|| format!("cannot open genome: {}", path.display()))?;
+        Ok(GenomeSequence {
+            reader,
+            current: None,
+        })
+    }
+}
+
+impl<R: BufRead + Seek> GenomeSequence<R> {
+    /// The sequence of a chromosome.
+    pub fn fetch(&mut self, chrom: &str) -> Result<&[u8]> {
+        if self.current.as_ref().map_or(true, |(c, _)| c != chrom) {
+            let region: Region = chrom
+                .parse()
+                .with_context(|| format!("invalid chromosome name: {}", chrom))?;
+            let record = self
+                .reader
+                .query(&region)
+                .with_context(|| format!("chromosome {} is not found in the genome", chrom))?;
+            self.current = Some((chrom.to_string(), record.sequence().as_ref().to_vec()));
+        }
+        Ok(&self.current.as_ref().unwrap().1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_kmers() {
+        let mut counts = vec![0; 16];
+        count_kmers(b"ACGNACg", 2, &mut counts);
+        // AC, CG, AC, CG
+        assert_eq!(counts[1], 2);
+        assert_eq!(counts[6], 2);
+        assert_eq!(counts.iter().sum::<u64>(), 4);
+    }
+
+    #[test]
+    fn test_kmer_columns() {
+        let (columns, names) = kmer_columns(2, true);
+        // 16 dimers, 4 of which are their own reverse complement.
+        assert_eq!(names.len(), 10);
+        assert_eq!(names[0], "AA");
+        // TT is the reverse complement of AA, GT of AC.
+        assert_eq!(columns[15], 0);
+        assert_eq!(names[columns[11]], "AC");
+        assert_eq!(revcomp_kmer(revcomp_kmer(27, 3), 3), 27);
+
+        let (columns, names) = kmer_columns(3, false);
+        assert_eq!(names.len(), 64);
+        assert_eq!(columns, (0..64).collect::<Vec<_>>());
+        assert_eq!(names[6], "ACG");
+    }
+
+    #[test]
+    fn test_bias() {
+        // Insertions only occur at "AC", which is as frequent as "CA" in the genome.
+        let mut observed = vec![0; 16];
+        observed[1] = 98;
+        let mut background = vec![0; 16];
+        background[1] = 49;
+        background[4] = 49;
+        let model = BiasModel::new(2, observed, background);
+        assert!(model.bias[1] > 1.0);
+        assert!(model.bias[4] < 1.0);
+
+        let seq = b"NCACA";
+        assert_eq!(model.kmer_at(seq, 2, Strand::Forward), Some(4));
+        assert_eq!(model.kmer_at(seq, 3, Strand::Forward), Some(1));
+        assert_eq!(model.kmer_at(seq, 1, Strand::Forward), None);
+        assert_eq!(model.bias_at(seq, 1, Strand::Forward), 1.0);
+        assert_eq!(model.kmer_at(seq, 5, Strand::Forward), None);
+        // On the reverse strand, the window [pos, pos + 2) is reverse
+        // complemented: "CA" at 1 reads "TG", "AC" at 2 reads "GT".
+        assert_eq!(model.kmer_at(seq, 1, Strand::Reverse), Some(14));
+        assert_eq!(model.kmer_at(seq, 2, Strand::Reverse), Some(11));
+        assert_eq!(model.kmer_at(seq, 0, Strand::Reverse), None);
+        assert_eq!(model.kmer_at(seq, 4, Strand::Reverse), None);
+    }
+}
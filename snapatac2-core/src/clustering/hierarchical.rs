@@ -0,0 +1,265 @@
+//! Hierarchical merging of clusters based on the similarity of their
+//! pseudobulk accessibility profiles.
+
+use crate::feature_count::aggregator::aggregate_x;
+use crate::utils::similarity::pearson2;
+
+use anndata::AnnDataOp;
+use anyhow::{bail, ensure, Result};
+use ndarray::{Array2, Axis};
+
+/// Similarity measure between pseudobulk profiles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileSimilarity {
+    /// Pearson correlation of the log-transformed CPM-normalized profiles.
+    Pearson,
+    /// Jaccard index of the sets of features with non-zero counts.
+    Jaccard,
+}
+
+impl TryFrom<&str> for ProfileSimilarity {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "pearson" | "correlation" => Ok(ProfileSimilarity::Pearson),
+            "jaccard" => Ok(ProfileSimilarity::Jaccard),
+            _ => bail!("unknown similarity measure: {}", value),
+        }
+    }
+}
+
+/// Linkage criterion used to compute the similarity between two groups of clusters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Linkage {
+    Average,
+    Complete,
+    Single,
+}
+
+impl TryFrom<&str> for Linkage {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "average" => Ok(Linkage::Average),
+            "complete" => Ok(Linkage::Complete),
+            "single" => Ok(Linkage::Single),
+            _ => bail!("unknown linkage: {}", value),
+        }
+    }
+}
+
+/// A merge step of the agglomerative clustering.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Merge {
+    pub left: usize,
+    pub right: usize,
+    pub similarity: f64,
+    /// Number of leaves under the new node.
+    pub size: usize,
+}
+
+/// Binary tree over `n_leaves` leaves. Following the convention of SciPy,
+/// leaves are numbered from `0` to `n_leaves - 1` and the node created by the
+/// `i`-th merge is numbered `n_leaves + i`.
+#[derive(Debug, Clone)]
+pub struct Dendrogram {
+    pub n_leaves: usize,
+    pub merges: Vec<Merge>,
+}
+
+impl Dendrogram {
+    /// Convert to a SciPy-compatible linkage matrix, using `1 - similarity` as the distance.
+    pub fn to_linkage_matrix(&self) -> Array2<f64> {
+        let mut mat = Array2::zeros((self.merges.len(), 4));
+        self.merges.iter().enumerate().for_each(|(i, m)| {
+            mat[[i, 0]] = m.left as f64;
+            mat[[i, 1]] = m.right as f64;
+            mat[[i, 2]] = 1.0 - m.similarity;
+            mat[[i, 3]] = m.size as f64;
+        });
+        mat
+    }
+
+    /// Cut the tree by applying all merges whose similarity is at least `threshold`.
+    /// Returns the group index of every leaf. Groups are numbered by their
+    /// first leaf.
+    pub fn cut(&self, threshold: f64) -> Vec<usize> {
+        let n_nodes = self.n_leaves + self.merges.len();
+        let mut parent: Vec<usize> = (0..n_nodes).collect();
+        fn find(parent: &mut [usize], mut x: usize) -> usize {
+            while parent[x] != x {
+                parent[x] = parent[parent[x]];
+                x = parent[x];
+            }
+            x
+        }
+        self.merges.iter().enumerate().for_each(|(i, m)| {
+            if m.similarity >= threshold {
+                let node = self.n_leaves + i;
+                let l = find(&mut parent, m.left);
+                let r = find(&mut parent, m.right);
+                parent[l] = node;
+                parent[r] = node;
+            }
+        });
+
+        let mut groups = Vec::with_capacity(self.n_leaves);
+        let mut roots: Vec<usize> = Vec::new();
+        for leaf in 0..self.n_leaves {
+            let root = find(&mut parent, leaf);
+            let idx = roots.iter().position(|x| *x == root).unwrap_or_else(|| {
+                roots.push(root);
+                roots.len() - 1
+            });
+            groups.push(idx);
+        }
+        groups
+    }
+}
+
+/// Compute the pairwise similarity between the rows of `profiles`.
+pub fn profile_similarity(profiles: &Array2<f64>, method: ProfileSimilarity) -> Array2<f64> {
+    match method {
+        ProfileSimilarity::Pearson => {
+            let mut normed = profiles.clone();
+            normed.axis_iter_mut(Axis(0)).for_each(|mut row| {
+                let total: f64 = row.sum();
+                if total > 0.0 {
+                    row.mapv_inplace(|x| (x / total * 1e6).ln_1p());
+                }
+            });
+            pearson2(normed.clone(), normed).mapv(|x| if x.is_nan() { 0.0 } else { x })
+        }
+        ProfileSimilarity::Jaccard => {
+            let sets: Vec<Vec<bool>> = profiles
+                .axis_iter(Axis(0))
+                .map(|row| row.iter().map(|x| *x > 0.0).collect())
+                .collect();
+            let n = sets.len();
+            Array2::from_shape_fn((n, n), |(i, j)| {
+                let (inter, union) = sets[i].iter().zip(sets[j].iter()).fold(
+                    (0usize, 0usize),
+                    |(a, b), (x, y)| (a + (*x && *y) as usize, b + (*x || *y) as usize),
+                );
+                if union == 0 { 0.0 } else { inter as f64 / union as f64 }
+            })
+        }
+    }
+}
+
+/// Agglomerative clustering on a symmetric similarity matrix.
+pub fn build_dendrogram(similarity: &Array2<f64>, linkage: Linkage) -> Dendrogram {
+    let n = similarity.nrows();
+    // Similarity between active nodes, indexed by node id.
+    let mut sim: Vec<Vec<f64>> = similarity.rows().into_iter().map(|r| r.to_vec()).collect();
+    let mut active: Vec<usize> = (0..n).collect();
+    let mut sizes = vec![1usize; n];
+    let mut merges = Vec::with_capacity(n.saturating_sub(1));
+
+    while active.len() > 1 {
+        let mut best = (0, 1, f64::NEG_INFINITY);
+        for a in 0..active.len() {
+            for b in (a + 1)..active.len() {
+                let s = sim[active[a]][active[b]];
+                if s > best.2 {
+                    best = (a, b, s);
+                }
+            }
+        }
+        let (a, b, s) = best;
+        let (left, right) = (active[a], active[b]);
+        let node = n + merges.len();
+        let size = sizes[left] + sizes[right];
+
+        let new_row: Vec<f64> = (0..node)
+            .map(|k| {
+                if !active.contains(&k) || k == left || k == right {
+                    return f64::NAN;
+                }
+                let (x, y) = (sim[left][k], sim[right][k]);
+                match linkage {
+                    Linkage::Average => {
+                        (sizes[left] as f64 * x + sizes[right] as f64 * y) / size as f64
+                    }
+                    Linkage::Complete => x.min(y),
+                    Linkage::Single => x.max(y),
+                }
+            })
+            .collect();
+        sim.iter_mut().zip(new_row.iter()).for_each(|(row, v)| row.push(*v));
+        let mut new_row = new_row;
+        new_row.push(1.0);
+        sim.push(new_row);
+        sizes.push(size);
+
+        merges.push(Merge { left, right, similarity: s, size });
+        active.remove(b);
+        active.remove(a);
+        active.push(node);
+    }
+    Dendrogram { n_leaves: n, merges }
+}
+
+/// Build a dendrogram over the clusters from their pseudobulk profiles and
+/// merge the clusters whose similarity is at least `threshold`.
+///
+/// Merged clusters are named by joining the names of their members with "+".
+///
+/// # Returns
+///
+/// The names of the original clusters (the leaves of the dendrogram), the
+/// dendrogram, and the new label of every cell.
+pub fn merge_similar_clusters<A: AnnDataOp>(
+    adata: &A,
+    groupby: &[Option<String>],
+    similarity: ProfileSimilarity,
+    linkage: Linkage,
+    threshold: f64,
+) -> Result<(Vec<String>, Dendrogram, Vec<Option<String>>)> {
+    let (names, profiles) = aggregate_x(adata, Some(groupby))?;
+    let names = names.unwrap();
+    ensure!(names.len() > 1, "at least two clusters are required");
+    let dendrogram = build_dendrogram(&profile_similarity(&profiles, similarity), linkage);
+
+    let groups = dendrogram.cut(threshold);
+    let n_groups = groups.iter().max().map_or(0, |x| x + 1);
+    let mut group_names = vec![Vec::new(); n_groups];
+    names.iter().zip(groups.iter()).for_each(|(name, g)| group_names[*g].push(name.as_str()));
+    let new_name: std::collections::HashMap<&str, String> = names
+        .iter()
+        .zip(groups.iter())
+        .map(|(name, g)| (name.as_str(), group_names[*g].join("+")))
+        .collect();
+    let labels = groupby
+        .iter()
+        .map(|x| x.as_ref().map(|x| new_name[x.as_str()].clone()))
+        .collect();
+    Ok((names, dendrogram, labels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_dendrogram() {
+        let sim = array![
+            [1.0, 0.9, 0.1, 0.2],
+            [0.9, 1.0, 0.3, 0.1],
+            [0.1, 0.3, 1.0, 0.8],
+            [0.2, 0.1, 0.8, 1.0],
+        ];
+        let tree = build_dendrogram(&sim, Linkage::Average);
+        assert_eq!(tree.merges[0], Merge { left: 0, right: 1, similarity: 0.9, size: 2 });
+        assert_eq!(tree.merges[1], Merge { left: 2, right: 3, similarity: 0.8, size: 2 });
+        assert_eq!(tree.merges[2].size, 4);
+        assert!((tree.merges[2].similarity - 0.175).abs() < 1e-12);
+
+        assert_eq!(tree.cut(0.85), vec![0, 0, 1, 2]);
+        assert_eq!(tree.cut(0.5), vec![0, 0, 1, 1]);
+        assert_eq!(tree.cut(0.0), vec![0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,7 @@
+//! Algorithms operating on cell clusters.
+
+pub mod hierarchical;
+pub mod rare;
+
+pub use hierarchical::{merge_similar_clusters, Dendrogram, Linkage, ProfileSimilarity};
+pub use rare::local_outlier_factor;
@@ -0,0 +1,93 @@
+//! Density-based outlier scoring for the detection of rare cell populations.
+
+use anyhow::{ensure, Result};
+use nalgebra_sparse::CsrMatrix;
+use rayon::prelude::*;
+
+/// Compute the local outlier factor (LOF, Breunig et al., 2000) of every cell
+/// from the kNN graph.
+///
+/// The LOF compares the local density of a cell with the local densities of its
+/// neighbors. Cells from rare populations lie in regions that are sparser than
+/// those of their nearest neighbors, which belong to abundant populations, and
+/// therefore have scores noticeably larger than 1.
+/// Cells without neighbors have a score of `NaN`.
+///
+/// # Arguments
+///
+/// * `graph` - The kNN graph whose row `i` stores the distances between cell
+///   `i` and its neighbors.
+pub fn local_outlier_factor(graph: &CsrMatrix<f64>) -> Result<Vec<f64>> {
+    ensure!(graph.nrows() == graph.ncols(), "the kNN graph must be a square matrix");
+
+    // Distance to the farthest neighbor of every cell.
+    let k_distance: Vec<f64> = graph
+        .row_iter()
+        .map(|row| row.values().iter().copied().fold(f64::NAN, f64::max))
+        .collect();
+
+    // Local reachability density.
+    let lrd: Vec<f64> = crate::config::install(None, || {
+        graph
+            .row_iter()
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|row| {
+                if row.nnz() == 0 {
+                    return f64::NAN;
+                }
+                let reach: f64 = row
+                    .col_indices()
+                    .iter()
+                    .zip(row.values())
+                    .map(|(j, d)| d.max(k_distance[*j]))
+                    .sum();
+                if reach == 0.0 {
+                    f64::INFINITY
+                } else {
+                    row.nnz() as f64 / reach
+                }
+            })
+            .collect()
+    });
+
+    let lof = graph
+        .row_iter()
+        .zip(lrd.iter())
+        .map(|(row, lrd_p)| {
+            if row.nnz() == 0 {
+                return f64::NAN;
+            }
+            let neighbors = row.col_indices().iter().map(|j| lrd[*j]).filter(|x| !x.is_nan());
+            let (sum, n) = neighbors.fold((0.0, 0usize), |(s, n), x| (s + x, n + 1));
+            if lrd_p.is_infinite() {
+                // Duplicated cells are considered as inliers.
+                1.0
+            } else if sum.is_infinite() {
+                f64::INFINITY
+            } else {
+                sum / n as f64 / lrd_p
+            }
+        })
+        .collect();
+    Ok(lof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::knn::nearest_neighbour_graph;
+    use ndarray::Array2;
+
+    #[test]
+    fn test_lof() {
+        // A dense grid plus an isolated point.
+        let mut points: Vec<f64> = (0..25).flat_map(|i| [(i / 5) as f64, (i % 5) as f64]).collect();
+        points.extend([20.0, 20.0]);
+        let points = Array2::from_shape_vec((26, 2), points).unwrap();
+        let graph = nearest_neighbour_graph(&points, 5);
+        let lof = local_outlier_factor(&graph).unwrap();
+        let max_inlier = lof[..25].iter().copied().fold(f64::MIN, f64::max);
+        assert!(lof[25] > 3.0 * max_inlier);
+    }
+}
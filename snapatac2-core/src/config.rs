@@ -0,0 +1,435 @@
+//! Global runtime configuration: the number of worker threads and the memory
+//! budget used by the algorithms in this crate.
+//!
+//! Functions that run in parallel execute inside [`install`], which uses the
+//! global thread pool unless a per-call thread count is given. Functions that
+//! buffer data in memory (e.g., external sorting) size their buffers using
+//! [`buffer_size`]. Functions that read fragments in chunks of cells size the
+//! chunks using [`adaptive_chunk_size`], unless a fixed chunk size is set
+//! with [`set_chunk_size`], or for a single call with [`with_chunk_size`].
+//! Fragments on chromosomes rejected by the filter set with
+//! [`set_chrom_filter`] are skipped by every operation reading them, and so
+//! are the fragments of the cells flagged by [`set_cell_mask`]. Records on
+//! chromosomes missing from the chromosome sizes are handled according to
+//! [`set_missing_chrom_policy`], and records extending beyond the end of
+//! their chromosome according to [`set_out_of_bounds_policy`].
+
+use anyhow::{bail, Result};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// 0 means "use the default of rayon", i.e., the number of logical CPUs.
+static NUM_THREADS: AtomicUsize = AtomicUsize::new(0);
+/// Memory limit in bytes. 0 means no limit.
+static MEMORY_LIMIT: AtomicUsize = AtomicUsize::new(0);
+/// Number of cells per chunk. 0 means the chunk size is chosen adaptively.
+static CHUNK_SIZE: AtomicUsize = AtomicUsize::new(0);
+static THREAD_POOL: RwLock<Option<Arc<ThreadPool>>> = RwLock::new(None);
+static CHROM_FILTER: RwLock<Option<ChromFilter>> = RwLock::new(None);
+static CELL_MASK: RwLock<Option<String>> = RwLock::new(None);
+static MISSING_CHROM_POLICY: RwLock<MissingChromPolicy> = RwLock::new(MissingChromPolicy::Skip);
+static OUT_OF_BOUNDS_POLICY: RwLock<OutOfBoundsPolicy> = RwLock::new(OutOfBoundsPolicy::Warn);
+
+thread_local! {
+    /// Number of cells per chunk set by [`with_chunk_size`]. 0 means not set.
+    static LOCAL_CHUNK_SIZE: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Set the number of threads used by parallel algorithms. `None` restores the
+/// default, i.e., the number of logical CPUs.
+pub fn set_num_threads(n: Option<usize>) -> Result<()> {
+    let n = n.unwrap_or(0);
+    let pool = if n == 0 {
+        None
+    } else {
+        Some(Arc::new(ThreadPoolBuilder::new().num_threads(n).build()?))
+    };
+    *THREAD_POOL.write().unwrap() = pool;
+    NUM_THREADS.store(n, Ordering::Relaxed);
+    Ok(())
+}
+
+/// The number of threads used by parallel algorithms.
+pub fn num_threads() -> usize {
+    match NUM_THREADS.load(Ordering::Relaxed) {
+        0 => rayon::current_num_threads(),
+        n => n,
+    }
+}
+
+/// Set the memory budget in bytes. `None` removes the limit.
+pub fn set_memory_limit(bytes: Option<usize>) {
+    MEMORY_LIMIT.store(bytes.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// The memory budget in bytes, if any.
+pub fn memory_limit() -> Option<usize> {
+    match MEMORY_LIMIT.load(Ordering::Relaxed) {
+        0 => None,
+        n => Some(n),
+    }
+}
+
+/// Parse a human readable memory size, e.g., "512M", "8G" or "1024".
+pub fn parse_memory_size(s: &str) -> Result<usize> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let num: f64 = num.trim().parse()?;
+    let scale = match unit.trim().to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1.0,
+        "K" => 1024.0,
+        "M" => 1024.0 * 1024.0,
+        "G" => 1024.0 * 1024.0 * 1024.0,
+        "T" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => bail!("invalid memory size: {}", s),
+    };
+    Ok((num * scale) as usize)
+}
+
+/// Number of items of type `T` that fit in the memory budget, or `default`
+/// if no budget is set. Half of the budget is reserved for other data
+/// structures.
+pub fn buffer_size<T>(default: usize) -> usize {
+    match memory_limit() {
+        None => default,
+        Some(limit) => (limit / 2 / std::mem::size_of::<T>().max(1)).max(1),
+    }
+}
+
+/// Set the number of cells per chunk used when reading fragments. `None`
+/// restores the default, i.e., the chunk size is chosen by [`adaptive_chunk_size`].
+pub fn set_chunk_size(n: Option<usize>) {
+    CHUNK_SIZE.store(n.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// The number of cells per chunk set by [`with_chunk_size`] or
+/// [`set_chunk_size`], if any.
+pub fn chunk_size() -> Option<usize> {
+    match LOCAL_CHUNK_SIZE.with(|x| x.get()) {
+        0 => match CHUNK_SIZE.load(Ordering::Relaxed) {
+            0 => None,
+            n => Some(n),
+        },
+        n => Some(n),
+    }
+}
+
+/// Run `f` with `n` cells per chunk, overriding [`set_chunk_size`] for this
+/// call only. If `n` is `None`, the global setting is used.
+pub fn with_chunk_size<R, F: FnOnce() -> R>(n: Option<usize>, f: F) -> R {
+    let prev = LOCAL_CHUNK_SIZE.with(|x| x.replace(n.unwrap_or(0)));
+    // Restore the previous value even if `f` panics.
+    struct Restore(usize);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            LOCAL_CHUNK_SIZE.with(|x| x.set(self.0));
+        }
+    }
+    let _restore = Restore(prev);
+    f()
+}
+
+/// Number of items of type `T` a chunk should hold when no memory budget is set.
+const ITEMS_PER_CHUNK: usize = 10_000_000;
+const MIN_CHUNK_SIZE: usize = 16;
+const MAX_CHUNK_SIZE: usize = 100_000;
+
+/// Number of cells per chunk such that a chunk holds about 10 million items
+/// of type `T` (e.g., fragments), given the average number of items per cell.
+/// The chunk is made smaller if it would not fit in half of the memory budget
+/// or in a quarter of the available memory.
+pub fn adaptive_chunk_size<T>(items_per_cell: f64) -> usize {
+    let item_size = std::mem::size_of::<T>().max(1);
+    let mut n_items = buffer_size::<T>(ITEMS_PER_CHUNK);
+    if let Some(available) = crate::utils::memory::available_memory() {
+        n_items = n_items.min(available as usize / 4 / item_size);
+    }
+    ((n_items as f64 / items_per_cell.max(1.0)) as usize).clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)
+}
+
+/// Chromosomes to keep. Patterns may contain `*`, which matches any sequence
+/// of characters, e.g., "*_alt" or "chrUn_*".
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChromFilter {
+    /// If not empty, only chromosomes matching one of these patterns are kept.
+    pub include: Vec<String>,
+    /// Chromosomes matching one of these patterns are removed.
+    pub exclude: Vec<String>,
+}
+
+impl ChromFilter {
+    pub fn keep(&self, chrom: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| wildcard_match(p, chrom)))
+            && !self.exclude.iter().any(|p| wildcard_match(p, chrom))
+    }
+
+    /// The chromosomes among `chroms` that are removed by the filter.
+    pub fn removed<'a, I: IntoIterator<Item = &'a String>>(&self, chroms: I) -> HashSet<String> {
+        chroms
+            .into_iter()
+            .filter(|x| !self.keep(x))
+            .cloned()
+            .collect()
+    }
+}
+
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    match parts.split_last() {
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(i) => rest = &rest[i + part.len()..],
+                    None => return false,
+                }
+            }
+            rest.len() >= last.len() && rest.ends_with(last)
+        }
+    }
+}
+
+/// Restrict all operations reading fragments (counting, QC, export, peak
+/// calling, etc.) to a subset of chromosomes. `None` removes the filter.
+pub fn set_chrom_filter(filter: Option<ChromFilter>) {
+    *CHROM_FILTER.write().unwrap() = filter.filter(|f| *f != ChromFilter::default());
+}
+
+/// The chromosome filter set by [`set_chrom_filter`], if any.
+pub fn chrom_filter() -> Option<ChromFilter> {
+    CHROM_FILTER.read().unwrap().clone()
+}
+
+/// Exclude flagged cells from all operations reading fragments or base values
+/// (counting, QC, export, etc.): cells for which the boolean column `key` of
+/// `.obs` is true are treated as having no data. Reading objects without this
+/// column fails. `None` removes the mask.
+pub fn set_cell_mask(key: Option<String>) {
+    *CELL_MASK.write().unwrap() = key;
+}
+
+/// The `.obs` key set by [`set_cell_mask`], if any.
+pub fn cell_mask() -> Option<String> {
+    CELL_MASK.read().unwrap().clone()
+}
+
+/// What to do with records (fragments, coverage intervals, etc.) on
+/// chromosomes missing from the chromosome sizes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissingChromPolicy {
+    /// Fail, reporting the missing chromosomes.
+    Error,
+    /// Skip the records with a warning.
+    #[default]
+    Skip,
+    /// Add the chromosomes, with the largest end coordinate of their records
+    /// as their size. Where the chromosome sizes cannot be changed, e.g.,
+    /// during import, the records are skipped with a warning.
+    Extend,
+}
+
+impl TryFrom<&str> for MissingChromPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(MissingChromPolicy::Error),
+            "skip" => Ok(MissingChromPolicy::Skip),
+            "extend" => Ok(MissingChromPolicy::Extend),
+            _ => bail!("unknown policy for missing chromosomes: {}, must be one of 'error', 'skip' or 'extend'", s),
+        }
+    }
+}
+
+impl std::fmt::Display for MissingChromPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MissingChromPolicy::Error => write!(f, "error"),
+            MissingChromPolicy::Skip => write!(f, "skip"),
+            MissingChromPolicy::Extend => write!(f, "extend"),
+        }
+    }
+}
+
+/// Set the policy for records on chromosomes missing from the chromosome sizes.
+pub fn set_missing_chrom_policy(policy: MissingChromPolicy) {
+    *MISSING_CHROM_POLICY.write().unwrap() = policy;
+}
+
+/// The policy set by [`set_missing_chrom_policy`].
+pub fn missing_chrom_policy() -> MissingChromPolicy {
+    *MISSING_CHROM_POLICY.read().unwrap()
+}
+
+/// What to do with records (fragments, coverage intervals, etc.) extending
+/// beyond the end of their chromosome, which usually indicates that the data
+/// and the chromosome sizes come from different references.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutOfBoundsPolicy {
+    /// Fail, reporting the number of such records.
+    Error,
+    /// Truncate the records at the end of the chromosome, or drop them where
+    /// they cannot be truncated, e.g., during import, with a warning.
+    #[default]
+    Warn,
+    /// Like `Warn`, without the warning.
+    Drop,
+}
+
+impl TryFrom<&str> for OutOfBoundsPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(OutOfBoundsPolicy::Error),
+            "warn" => Ok(OutOfBoundsPolicy::Warn),
+            "drop" => Ok(OutOfBoundsPolicy::Drop),
+            _ => bail!("unknown policy for out-of-bounds records: {}, must be one of 'error', 'warn' or 'drop'", s),
+        }
+    }
+}
+
+impl std::fmt::Display for OutOfBoundsPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutOfBoundsPolicy::Error => write!(f, "error"),
+            OutOfBoundsPolicy::Warn => write!(f, "warn"),
+            OutOfBoundsPolicy::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// Set the policy for records extending beyond the end of their chromosome.
+pub fn set_out_of_bounds_policy(policy: OutOfBoundsPolicy) {
+    *OUT_OF_BOUNDS_POLICY.write().unwrap() = policy;
+}
+
+/// The policy set by [`set_out_of_bounds_policy`].
+pub fn out_of_bounds_policy() -> OutOfBoundsPolicy {
+    *OUT_OF_BOUNDS_POLICY.read().unwrap()
+}
+
+/// Number of records extending beyond the end of their chromosome.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutOfBounds {
+    /// Records truncated at the end of the chromosome.
+    pub clipped: usize,
+    /// Records starting beyond the end of the chromosome, or that could not
+    /// be truncated.
+    pub dropped: usize,
+}
+
+impl OutOfBounds {
+    pub fn is_empty(&self) -> bool {
+        self.clipped == 0 && self.dropped == 0
+    }
+
+    /// Apply the policy set by [`set_out_of_bounds_policy`] to the records
+    /// counted so far. `what` describes the records in messages.
+    pub fn report(&self, what: &str) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        match out_of_bounds_policy() {
+            OutOfBoundsPolicy::Error => bail!(
+                "{} {} extend beyond the end of their chromosome ({} clipped, {} dropped). Check that the chromosome sizes match the reference of the data, or set the policy for out-of-bounds records to 'warn'",
+                self.clipped + self.dropped,
+                what,
+                self.clipped,
+                self.dropped,
+            ),
+            OutOfBoundsPolicy::Warn => log::warn!(
+                "{} {} extend beyond the end of their chromosome: {} are clipped and {} are dropped",
+                self.clipped + self.dropped,
+                what,
+                self.clipped,
+                self.dropped,
+            ),
+            OutOfBoundsPolicy::Drop => {}
+        }
+        Ok(())
+    }
+}
+
+/// Run `f` in a thread pool with `num_threads` threads. If `num_threads` is
+/// `None`, the global setting is used. The chunk size set by
+/// [`with_chunk_size`] carries over to `f`.
+pub fn install<R, F>(num_threads: Option<usize>, f: F) -> R
+where
+    R: Send,
+    F: FnOnce() -> R + Send,
+{
+    let chunk_size = LOCAL_CHUNK_SIZE.with(|x| x.get());
+    let f = move || with_chunk_size(Some(chunk_size).filter(|n| *n > 0), f);
+    match num_threads {
+        Some(n) => ThreadPoolBuilder::new().num_threads(n).build().unwrap().install(f),
+        None => {
+            let pool = THREAD_POOL.read().unwrap().clone();
+            match pool {
+                Some(pool) => pool.install(f),
+                None => f(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memory_size() {
+        assert_eq!(parse_memory_size("1024").unwrap(), 1024);
+        assert_eq!(parse_memory_size("2K").unwrap(), 2048);
+        assert_eq!(parse_memory_size("1.5 GB").unwrap(), 1610612736);
+        assert!(parse_memory_size("3X").is_err());
+    }
+
+    #[test]
+    fn test_adaptive_chunk_size() {
+        let shallow = adaptive_chunk_size::<u64>(1_000.0);
+        let deep = adaptive_chunk_size::<u64>(100_000.0);
+        assert!(shallow > deep);
+        assert!(deep >= MIN_CHUNK_SIZE);
+        assert_eq!(adaptive_chunk_size::<u64>(0.0), MAX_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn test_chrom_filter() {
+        let filter = ChromFilter {
+            include: vec!["chr*".to_string()],
+            exclude: vec!["*_alt".to_string(), "chrUn_*".to_string(), "chrM".to_string()],
+        };
+        assert!(filter.keep("chr1"));
+        assert!(!filter.keep("chr1_KI270706v1_alt"));
+        assert!(!filter.keep("chrUn_GL000195v1"));
+        assert!(!filter.keep("chrM"));
+        assert!(!filter.keep("1"));
+        assert!(wildcard_match("a*b*c", "aXbYc"));
+        assert!(!wildcard_match("a*bc", "abc_"));
+        assert!(!wildcard_match("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn test_with_chunk_size() {
+        assert_eq!(with_chunk_size(Some(7), chunk_size), Some(7));
+        assert_eq!(with_chunk_size(Some(7), || install(Some(2), chunk_size)), Some(7));
+        assert_eq!(LOCAL_CHUNK_SIZE.with(|x| x.get()), 0);
+    }
+
+    #[test]
+    fn test_install() {
+        assert_eq!(install(Some(3), rayon::current_num_threads), 3);
+    }
+}
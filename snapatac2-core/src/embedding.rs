@@ -1,6 +1,17 @@
+use anyhow::{ensure, Result};
+use nalgebra::{DMatrix, DVector};
 use nalgebra_sparse::CsrMatrix;
 use itertools::Itertools;
-use rayon::iter::{ParallelBridge, ParallelIterator};
+use ndarray::{Array1, Array2, ArrayView2, Axis, ShapeBuilder};
+use rand::Rng;
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelBridge, ParallelIterator};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+
+use crate::utils::checkpoint::Checkpoint;
+use crate::utils::knn::nearest_neighbour_graph;
+use crate::utils::matmul::Device;
 
 pub trait InverseDocumentFrequency {
     /// Compute inverse document frequency (IDF) for a given sparse matrix.
@@ -76,22 +87,23 @@ where
         if idf.is_none() {
             idf = Some(vec![0.0; ncols]);
         }
-        let local: Vec<f64> = mat
-            .row_iter()
-            .par_bridge()
-            .map(|row| {
-                let mut local = vec![0.0; ncols];
-                for i in row.col_indices() {
-                    local[*i] += 1.0;
-                }
-                local
-            })
-            .reduce(|| vec![0.0; ncols], |mut a, b| {
-                for (x, y) in a.iter_mut().zip(b) {
-                    *x += y;
-                }
-                a
-            });
+        let local: Vec<f64> = crate::config::install(None, || {
+            mat.row_iter()
+                .par_bridge()
+                .map(|row| {
+                    let mut local = vec![0.0; ncols];
+                    for i in row.col_indices() {
+                        local[*i] += 1.0;
+                    }
+                    local
+                })
+                .reduce(|| vec![0.0; ncols], |mut a, b| {
+                    for (x, y) in a.iter_mut().zip(b) {
+                        *x += y;
+                    }
+                    a
+                })
+        });
         if let Some(ref mut idf_vec) = idf {
             for (x, y) in idf_vec.iter_mut().zip(local) {
                 *x += y;
@@ -114,4 +126,457 @@ where
             })
             .collect()
     }
-}
\ No newline at end of file
+}
+
+/// Options of the randomized PCA.
+#[derive(Debug, Clone)]
+pub struct PcaOptions {
+    pub n_components: usize,
+    /// Number of additional random vectors used to sample the range of the matrix.
+    pub oversampling: usize,
+    /// Number of power iterations. More iterations improve the accuracy when
+    /// the singular values decay slowly.
+    pub n_power_iter: usize,
+    /// If provided, features are weighted by these values (e.g., IDF) and every
+    /// row is normalized to unit L2 norm before the decomposition.
+    pub feature_weights: Option<Vec<f64>>,
+    pub seed: u64,
+    /// If provided, the range estimate of every pass is saved in this
+    /// directory, and a run that was interrupted resumes from the last
+    /// completed pass. A checksum of the input, computed along with the column
+    /// means, ensures that the saved passes are used only for the same input.
+    /// The directory is removed on success.
+    pub checkpoint_dir: Option<PathBuf>,
+    /// The device computing the dense products of the final decomposition.
+    pub device: Device,
+}
+
+impl Default for PcaOptions {
+    fn default() -> Self {
+        Self {
+            n_components: 30,
+            oversampling: 10,
+            n_power_iter: 4,
+            feature_weights: None,
+            seed: 0,
+            checkpoint_dir: None,
+            device: Device::Cpu,
+        }
+    }
+}
+
+/// Result of the PCA.
+#[derive(Debug, Clone)]
+pub struct Pca {
+    /// Principal component scores, of shape `n_obs` x `n_components`.
+    pub scores: Array2<f64>,
+    /// Principal axes in feature space, of shape `n_components` x `n_features`.
+    pub components: Array2<f64>,
+    /// Variance explained by each component.
+    pub explained_variance: Vec<f64>,
+    /// Per-feature mean of the (weighted) input.
+    pub mean: Vec<f64>,
+}
+
+/// Randomized PCA (Halko et al., 2011) on a sparse matrix that is read in chunks.
+///
+/// The matrix is centered implicitly, i.e., the dense centered matrix is
+/// never formed, and it is accessed only through matrix products with
+/// thin dense matrices. `data` must return an iterator over row chunks of
+/// the matrix every time it is called, as the matrix is scanned several times.
+pub fn randomized_pca<F, I>(mut data: F, options: &PcaOptions) -> Result<Pca>
+where
+    F: FnMut() -> I,
+    I: Iterator<Item = CsrMatrix<f64>>,
+{
+    let weights = options.feature_weights.as_deref();
+    let backend = options.device.backend()?;
+    let prep = |mut mat: CsrMatrix<f64>| {
+        if let Some(w) = weights {
+            weight_and_normalize(&mut mat, w);
+        }
+        mat
+    };
+
+    // Column means, and the checksum of the input identifying the checkpoint.
+    let mut n_obs = 0;
+    let mut mean: Vec<f64> = Vec::new();
+    let mut checksum = DefaultHasher::new();
+    data().map(prep).for_each(|mat| {
+        if mean.is_empty() {
+            mean = vec![0.0; mat.ncols()];
+        }
+        n_obs += mat.nrows();
+        (mat.nrows(), mat.ncols()).hash(&mut checksum);
+        mat.row_offsets().hash(&mut checksum);
+        mat.col_indices().hash(&mut checksum);
+        mat.col_indices().iter().zip(mat.values()).for_each(|(j, v)| {
+            mean[*j] += v;
+            v.to_bits().hash(&mut checksum);
+        });
+    });
+    ensure!(n_obs > 1 && !mean.is_empty(), "the input matrix is empty");
+    mean.iter_mut().for_each(|x| *x /= n_obs as f64);
+
+    let mut checkpoint = options
+        .checkpoint_dir
+        .as_ref()
+        .map(|dir| Checkpoint::open(dir, &options.fingerprint(checksum.finish())))
+        .transpose()?;
+    let n_vars = mean.len();
+    let mean_vec = DVector::from_column_slice(&mean);
+
+    let n_components = options.n_components.min(n_obs.min(n_vars));
+    let rank = (n_components + options.oversampling).min(n_obs.min(n_vars));
+
+    // Y = (X - 1 * mean^T) * M
+    let apply = |data: &mut F, m: &DMatrix<f64>| -> DMatrix<f64> {
+        let shift = m.transpose() * &mean_vec;
+        let mut result = DMatrix::zeros(n_obs, m.ncols());
+        let mut offset = 0;
+        data().map(prep).for_each(|mat| {
+            let y = &mat * m;
+            for i in 0..y.nrows() {
+                for c in 0..y.ncols() {
+                    result[(offset + i, c)] = y[(i, c)] - shift[c];
+                }
+            }
+            offset += mat.nrows();
+        });
+        result
+    };
+    let apply_t = |data: &mut F, m: &DMatrix<f64>| -> DMatrix<f64> {
+        let mut result = DMatrix::zeros(n_vars, m.ncols());
+        let mut offset = 0;
+        data().map(prep).for_each(|mat| {
+            mat.row_iter().enumerate().for_each(|(i, row)| {
+                row.col_indices().iter().zip(row.values()).for_each(|(j, v)| {
+                    for c in 0..m.ncols() {
+                        result[(*j, c)] += v * m[(offset + i, c)];
+                    }
+                });
+            });
+            offset += mat.nrows();
+        });
+        let col_sum = m.row_sum();
+        for j in 0..n_vars {
+            for c in 0..m.ncols() {
+                result[(j, c)] -= mean[j] * col_sum[c];
+            }
+        }
+        result
+    };
+
+    // The range estimate after `i` power iterations is saved as stage `range_i`.
+    let resume_from = checkpoint.as_ref().and_then(|c| {
+        (0..=options.n_power_iter).rev().find(|i| c.is_completed(&format!("range_{}", i)))
+    });
+    let (start, mut q) = match resume_from {
+        Some(i) => {
+            let q: DenseMatrix = checkpoint.as_ref().unwrap().load_binary(&format!("range_{}", i))?.unwrap();
+            (i, q.into())
+        }
+        None => {
+            let mut rng = crate::utils::rng::SeedStream::new(options.seed).derive("randomized_pca").rng(0);
+            let omega = DMatrix::from_fn(n_vars, rank, |_, _| rng.random::<f64>() * 2.0 - 1.0);
+            let q = apply(&mut data, &omega).qr().q();
+            if let Some(c) = checkpoint.as_mut() {
+                c.save_binary("range_0", &DenseMatrix::from(&q))?;
+            }
+            (0, q)
+        }
+    };
+    for i in start..options.n_power_iter {
+        let z = apply_t(&mut data, &q).qr().q();
+        q = apply(&mut data, &z).qr().q();
+        if let Some(c) = checkpoint.as_mut() {
+            c.save_binary(&format!("range_{}", i + 1), &DenseMatrix::from(&q))?;
+        }
+    }
+
+    // B = Q^T A, and B B^T = U S^2 U^T. The column-major `B^T` is stored
+    // like the row-major `B`.
+    let bt = apply_t(&mut data, &q);
+    let b = ArrayView2::from_shape((bt.ncols(), n_vars), bt.as_slice())?;
+    let gram = backend.matmul_t(b, b)?;
+    let eigen = DMatrix::from_fn(gram.nrows(), gram.ncols(), |i, j| gram[[i, j]]).symmetric_eigen();
+    let mut order: Vec<usize> = (0..eigen.eigenvalues.len()).collect();
+    order.sort_by(|a, b| eigen.eigenvalues[*b].total_cmp(&eigen.eigenvalues[*a]));
+    order.truncate(n_components);
+
+    // Scores Q U and components U^T B, scaled by the singular values.
+    let singular_values: Vec<f64> =
+        order.iter().map(|k| eigen.eigenvalues[*k].max(0.0).sqrt()).collect();
+    let ut = Array2::from_shape_fn((n_components, eigen.eigenvectors.nrows()), |(c, i)| {
+        eigen.eigenvectors[(i, order[c])]
+    });
+    let q = ArrayView2::from_shape((n_obs, q.ncols()).f(), q.as_slice())?;
+    let mut scores = backend.matmul_t(q, ut.view())?;
+    let mut components = backend.matmul_t(ut.view(), b.t())?;
+    let mut explained_variance = Vec::with_capacity(n_components);
+    singular_values.iter().enumerate().for_each(|(c, s)| {
+        scores.column_mut(c).mapv_inplace(|x| x * s);
+        if *s > 0.0 {
+            components.row_mut(c).mapv_inplace(|x| x / s);
+        } else {
+            components.row_mut(c).fill(0.0);
+        }
+        explained_variance.push(s * s / (n_obs - 1) as f64);
+    });
+
+    if let Some(c) = checkpoint {
+        c.finish()?;
+    }
+    Ok(Pca { scores, components, explained_variance, mean })
+}
+
+impl PcaOptions {
+    /// Parameters that determine the intermediate results of the PCA, with
+    /// the checksum of the (weighted) input.
+    fn fingerprint(&self, checksum: u64) -> String {
+        serde_json::json!({
+            "n_components": self.n_components,
+            "oversampling": self.oversampling,
+            "n_power_iter": self.n_power_iter,
+            "seed": self.seed,
+            "input": format!("{:016x}", checksum),
+        })
+        .to_string()
+    }
+}
+
+/// Column-major dense matrix in a form that can be saved to a checkpoint.
+#[derive(bitcode::Encode, bitcode::Decode)]
+struct DenseMatrix {
+    nrows: usize,
+    ncols: usize,
+    data: Vec<f64>,
+}
+
+impl From<&DMatrix<f64>> for DenseMatrix {
+    fn from(m: &DMatrix<f64>) -> Self {
+        Self { nrows: m.nrows(), ncols: m.ncols(), data: m.as_slice().to_vec() }
+    }
+}
+
+impl From<DenseMatrix> for DMatrix<f64> {
+    fn from(m: DenseMatrix) -> Self {
+        DMatrix::from_vec(m.nrows, m.ncols, m.data)
+    }
+}
+
+/// Learn per-cell modality weights, in the spirit of the weighted nearest
+/// neighbor analysis (Hao et al., 2021).
+///
+/// For every modality, the embedding of each cell is predicted from the
+/// average embedding of its nearest neighbors, where neighbors are found either
+/// in the same modality or in the other modalities. A modality receives a larger
+/// weight in cells where its own neighbors predict the cell better than the
+/// neighbors from the other modalities do. Weights of each cell sum to 1.
+///
+/// # Arguments
+///
+/// * `embeddings` - Per-modality embeddings, each of shape `n_obs` x `n_dims`.
+/// * `k` - Number of nearest neighbors.
+pub fn modality_weights(embeddings: &[ArrayView2<'_, f64>], k: usize) -> Result<Array2<f64>> {
+    ensure!(!embeddings.is_empty(), "no modality is provided");
+    let n_obs = embeddings[0].nrows();
+    ensure!(
+        embeddings.iter().all(|x| x.nrows() == n_obs),
+        "all modalities must have the same number of cells"
+    );
+    let n_mod = embeddings.len();
+    if n_mod == 1 {
+        return Ok(Array2::ones((n_obs, 1)));
+    }
+
+    let graphs: Vec<CsrMatrix<f64>> = embeddings
+        .iter()
+        .map(|x| nearest_neighbour_graph(x, k))
+        .collect();
+    // Kernel bandwidth: distance to the k-th nearest neighbor.
+    let bandwidth: Vec<Vec<f64>> = graphs
+        .iter()
+        .map(|g| {
+            g.row_iter()
+                .map(|row| row.values().iter().copied().fold(0.0, f64::max).max(1e-8))
+                .collect()
+        })
+        .collect();
+
+    let mut scores = Array2::<f64>::zeros((n_obs, n_mod));
+    crate::config::install(None, || {
+        scores
+            .axis_iter_mut(Axis(0))
+            .into_par_iter()
+            .enumerate()
+            .for_each(|(i, mut score)| {
+                for m in 0..n_mod {
+                    let emb = &embeddings[m];
+                    let affinity = |source: usize| -> f64 {
+                        let neighbors = graphs[source].row(i);
+                        let mut pred = Array1::<f64>::zeros(emb.ncols());
+                        neighbors.col_indices().iter().for_each(|j| pred += &emb.row(*j));
+                        pred /= neighbors.nnz().max(1) as f64;
+                        let dist = (&emb.row(i) - &pred).mapv(|x| x * x).sum().sqrt();
+                        (-dist / bandwidth[m][i]).exp()
+                    };
+                    let within = affinity(m);
+                    let cross = (0..n_mod).filter(|s| *s != m).map(affinity).sum::<f64>()
+                        / (n_mod - 1) as f64;
+                    score[m] = within / (cross + 1e-4);
+                }
+                // Softmax across modalities.
+                let max = score.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                score.mapv_inplace(|x| (x - max).exp());
+                let sum = score.sum();
+                score /= sum;
+            })
+    });
+    Ok(scores)
+}
+
+/// Weight the features and normalize every row to unit L2 norm.
+fn weight_and_normalize(input: &mut CsrMatrix<f64>, feature_weights: &[f64]) {
+    crate::config::install(None, || {
+        input.row_iter_mut().par_bridge().for_each(|mut row| {
+            let (indices, data) = row.cols_and_values_mut();
+            indices
+                .iter()
+                .zip(data.iter_mut())
+                .for_each(|(i, x)| *x *= feature_weights[*i]);
+
+            let norm = data.iter().map(|x| x * x).sum::<f64>().sqrt();
+            if norm > 0.0 {
+                data.iter_mut().for_each(|x| *x /= norm);
+            }
+        })
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra_sparse::CooMatrix;
+
+    #[test]
+    fn test_randomized_pca() {
+        // A 30 x 8 matrix of rank 2.
+        let dense = DMatrix::from_fn(30, 8, |i, j| {
+            let (a, b) = ((i % 7) as f64, (i % 5) as f64);
+            a * (j as f64 + 1.0) + b * ((j % 3) as f64)
+        });
+        let mut coo = CooMatrix::new(30, 8);
+        for i in 0..30 {
+            for j in 0..8 {
+                if dense[(i, j)] != 0.0 {
+                    coo.push(i, j, dense[(i, j)]);
+                }
+            }
+        }
+        let csr = CsrMatrix::from(&coo);
+        let chunks: Vec<CsrMatrix<f64>> = (0..30)
+            .step_by(7)
+            .map(|i| {
+                let idx: Vec<usize> = (i..(i + 7).min(30)).collect();
+                let mut coo = CooMatrix::new(idx.len(), 8);
+                idx.iter().enumerate().for_each(|(r, k)| {
+                    let row = csr.row(*k);
+                    row.col_indices().iter().zip(row.values()).for_each(|(j, v)| coo.push(r, *j, *v));
+                });
+                CsrMatrix::from(&coo)
+            })
+            .collect();
+
+        let options = PcaOptions { n_components: 2, ..Default::default() };
+        let pca = randomized_pca(|| chunks.clone().into_iter(), &options).unwrap();
+
+        let mean = dense.row_mean();
+        let centered = DMatrix::from_fn(30, 8, |i, j| dense[(i, j)] - mean[j]);
+        let cov = centered.transpose() * &centered / 29.0;
+        let mut expected: Vec<f64> = cov.symmetric_eigen().eigenvalues.iter().copied().collect();
+        expected.sort_by(|a, b| b.total_cmp(a));
+        pca.explained_variance.iter().zip(expected).for_each(|(a, b)| {
+            assert!((a - b).abs() / b < 1e-8);
+        });
+
+        // Scores are the projections of the centered data onto the components.
+        for i in 0..30 {
+            for c in 0..2 {
+                let proj: f64 = (0..8).map(|j| centered[(i, j)] * pca.components[[c, j]]).sum();
+                assert!((proj - pca.scores[[i, c]]).abs() < 1e-6);
+            }
+        }
+
+        // Interrupt a run after the first power iteration and resume it.
+        let dir = tempfile::tempdir().unwrap();
+        let options = PcaOptions {
+            n_components: 2,
+            checkpoint_dir: Some(dir.path().join("pca")),
+            ..Default::default()
+        };
+        let mut n_scans = 0;
+        let interrupted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            randomized_pca(
+                || {
+                    n_scans += 1;
+                    assert!(n_scans <= 4, "interrupted");
+                    chunks.clone().into_iter()
+                },
+                &options,
+            )
+        }));
+        assert!(interrupted.is_err());
+        let mut n_scans = 0;
+        let resumed = randomized_pca(
+            || {
+                n_scans += 1;
+                chunks.clone().into_iter()
+            },
+            &options,
+        )
+        .unwrap();
+        // The initial range and the first power iteration are not recomputed.
+        assert_eq!(n_scans, 1 + 2 * (options.n_power_iter - 1) + 1);
+        assert_eq!(resumed.scores, pca.scores);
+        assert!(!dir.path().join("pca").exists());
+
+        // The checkpoint of a different input is not used.
+        let mut n_scans = 0;
+        let options = PcaOptions { n_power_iter: 0, ..options };
+        let interrupted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            randomized_pca(
+                || {
+                    n_scans += 1;
+                    assert!(n_scans <= 2, "interrupted");
+                    chunks.clone().into_iter()
+                },
+                &options,
+            )
+        }));
+        assert!(interrupted.is_err());
+        let mut n_scans = 0;
+        randomized_pca(
+            || {
+                n_scans += 1;
+                chunks.clone().into_iter().rev()
+            },
+            &options,
+        )
+        .unwrap();
+        assert_eq!(n_scans, 3);
+    }
+
+    #[test]
+    fn test_modality_weights() {
+        // The first modality separates two groups, the second one is noise.
+        let informative = Array2::from_shape_fn((60, 3), |(i, j)| {
+            (if i < 30 { 0.0 } else { 10.0 }) + ((i * 7 + j * 13) as f64).sin() * 0.1
+        });
+        let noise = Array2::from_shape_fn((60, 3), |(i, j)| ((i * 31 + j * 17) as f64).sin());
+        let weights = modality_weights(&[informative.view(), noise.view()], 5).unwrap();
+        assert_eq!(weights.shape(), &[60, 2]);
+        weights.rows().into_iter().for_each(|w| assert!((w.sum() - 1.0).abs() < 1e-12));
+        assert!(weights.column(0).mean().unwrap() > 0.5);
+    }
+}
@@ -1,25 +1,36 @@
-use crate::feature_count::{CountingStrategy, SnapData};
+use crate::bias::{BiasModel, GenomeSequence};
+use crate::feature_count::{
+    read_coverage_cache, CountingStrategy, FragmentData, ReadErrorSlot, SnapData, ValueType,
+};
+use crate::preprocessing::SummaryType;
+use crate::config::{ChromFilter, MissingChromPolicy, OutOfBounds};
 use crate::genome::ChromSizes;
+use crate::utils::track_stats::{TrackStats, TrackSummary};
 use crate::{
-    preprocessing::Fragment,
+    preprocessing::{Fragment, SingleRead},
     utils::{self, Compression},
 };
 
+use anndata::{AnnDataOp, ElemCollectionOp};
 use anyhow::{bail, ensure, Context, Result};
 use bed_utils::bed::MergeBed;
 use bed_utils::extsort::ExternalChunk;
 use bed_utils::{
-    bed::{map::GIntervalMap, BEDLike, BedGraph},
+    bed::{map::GIntervalMap, BEDLike, BedGraph, GenomicRange, Strand},
     extsort::ExternalSorterBuilder,
 };
 use bigtools::BigWigWrite;
 use indicatif::{style::ProgressStyle, ParallelProgressIterator, ProgressIterator};
 use itertools::Itertools;
-use log::info;
+use indexmap::{IndexMap, IndexSet};
+use log::{debug, info, warn};
+use polars::frame::DataFrame;
+use polars::prelude::DataType;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use smallvec::SmallVec;
 use std::fs::OpenOptions;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     io::Write,
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
@@ -43,6 +54,366 @@ impl std::str::FromStr for CoverageOutputFormat {
     }
 }
 
+/// The output file of a group. Group names may be nested, with the levels
+/// separated by "/", e.g., "B cell/control", in which case every level but the
+/// last is a subdirectory of `dir`.
+fn group_path(dir: &Path, prefix: &str, group: &str, suffix: &str) -> Result<PathBuf> {
+    let mut levels: Vec<&str> = group.split('/').collect();
+    let filename = prefix.to_string() + levels.pop().unwrap() + suffix;
+    let mut path = dir.to_path_buf();
+    for x in levels.into_iter().chain(std::iter::once(filename.as_str())) {
+        if x.is_empty() || !sanitize_filename::is_sanitized(x) {
+            bail!("invalid filename: {}", x);
+        }
+        path.push(x);
+    }
+    let parent = path.parent().unwrap();
+    std::fs::create_dir_all(parent)
+        .with_context(|| format!("cannot create directory: {}", parent.display()))?;
+    Ok(path)
+}
+
+/// The files written for a group of cells by [`Exporter::export_coverage`].
+#[derive(Debug, Clone)]
+pub struct ExportedTrack {
+    pub coverage: PathBuf,
+    /// The fragment file, if requested with [`FragmentOutput`].
+    pub fragments: Option<PathBuf>,
+    /// The summary statistics of the track, if requested.
+    pub stats: Option<TrackStats>,
+    /// The fragments beyond the end of their chromosome.
+    pub out_of_bounds: OutOfBounds,
+    /// The number of fragments on the spike-in chromosomes, if any.
+    pub spike_in_count: Option<f64>,
+}
+
+/// Fragment files written by [`Exporter::export_coverage`] from the same
+/// sorted stream as the coverage, saving a separate pass over the fragments.
+/// The files are named `{prefix}{group}{suffix}` and sorted by coordinate.
+pub struct FragmentOutput<'a> {
+    pub suffix: &'a str,
+    pub barcodes: Option<&'a Vec<&'a str>>,
+    pub compression: Option<Compression>,
+    pub compression_level: Option<u32>,
+}
+
+/// Sorted ranges of consecutive rows of the cells of every group.
+fn group_rows<'a>(group_by: &[&'a str]) -> HashMap<&'a str, Vec<(usize, usize)>> {
+    let mut rows: HashMap<&str, Vec<(usize, usize)>> = HashMap::new();
+    group_by.iter().enumerate().for_each(|(i, g)| {
+        let ranges = rows.entry(*g).or_default();
+        match ranges.last_mut() {
+            Some((_, end)) if *end == i => *end = i + 1,
+            _ => ranges.push((i, i + 1)),
+        }
+    });
+    rows
+}
+
+/// Fragments of `modality` of the cells, reading only the rows of the cells of
+/// the selected groups, see [`group_rows`]. This speeds up the export of a few
+/// groups, whether or not the cells are sorted by group. Cells of other groups
+/// may be absent from the output. Errors reading the fragments are stored in
+/// the returned slot, see [`check_read_error`].
+fn selected_fragments<T: SnapData + ?Sized, F: Fn(&str) -> bool>(
+    data: &T,
+    group_by: &[&str],
+    selected: F,
+    modality: Option<&str>,
+) -> Result<(FragmentData, ReadErrorSlot)> {
+    let chunk_size = data.modality_chunk_size(modality)?;
+    let ranges: Vec<_> = group_rows(group_by)
+        .into_iter()
+        .filter(|(g, _)| selected(g))
+        .flat_map(|(_, x)| x)
+        .sorted()
+        .collect();
+    let n_rows: usize = ranges.iter().map(|(a, b)| b - a).sum();
+    let error = ReadErrorSlot::default();
+    let fragments = if n_rows == group_by.len() {
+        data.get_modality_fragment_iter(chunk_size, modality)?
+    } else {
+        debug!("Reading the fragments of {} of {} cells", n_rows, group_by.len());
+        data.get_fragment_iter_rows(chunk_size, &ranges, modality, &error)?
+    };
+    Ok((fragments, error))
+}
+
+/// Return the item of `x`, or store the error in `slot` and return `None`, so
+/// that the iteration stops at the first error, see [`check_read_error`].
+fn capture_error<T, E: Into<anyhow::Error>>(slot: &ReadErrorSlot, x: std::result::Result<T, E>) -> Option<T> {
+    match x {
+        Ok(x) => Some(x),
+        Err(e) => {
+            slot.lock().unwrap().get_or_insert(e.into());
+            None
+        }
+    }
+}
+
+/// Return the error stored by [`selected_fragments`] or [`capture_error`], if any.
+fn check_read_error(error: &ReadErrorSlot) -> Result<()> {
+    match error.lock().unwrap().take() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Sort the ranges counted in the coverage, each with a weight of 1.
+fn sorted_ranges<'a, I>(
+    ranges: I,
+    sorter: ExternalSorterBuilder,
+    error: &'a ReadErrorSlot,
+) -> Result<Box<dyn Iterator<Item = (GenomicRange, f64)> + 'a>>
+where
+    I: Iterator<Item = GenomicRange> + 'a,
+{
+    Ok(Box::new(
+        sorter
+            .build()?
+            .sort_by(ranges, |a, b| a.compare(b))?
+            .map_while(|x| capture_error(error, x))
+            .map(|x| (x, 1.0)),
+    ))
+}
+
+/// Sort the insertions of the fragments. With a bias model, every insertion
+/// is weighted by the inverse of the bias of the k-mer read on the strand of
+/// its read, so the insertions are sorted as 1-bp reads carrying the strand.
+/// Insertions on chromosomes missing from the genome are not corrected.
+fn sorted_insertions<'a, I>(
+    fragments: I,
+    sorter: ExternalSorterBuilder,
+    bias_correction: Option<(&'a BiasModel, &Path)>,
+    error: &'a ReadErrorSlot,
+) -> Result<Box<dyn Iterator<Item = (GenomicRange, f64)> + 'a>>
+where
+    I: Iterator<Item = Fragment> + 'a,
+{
+    let (model, genome) = match bias_correction {
+        None => return sorted_ranges(fragments.flat_map(|x| x.to_insertions()), sorter, error),
+        Some(x) => x,
+    };
+    let mut genome = GenomeSequence::open(genome)?;
+    let insertions = fragments.flat_map(|x| {
+        x.to_stranded_insertions()
+            .into_iter()
+            .map(|(ins, strand)| {
+                Fragment::Single(SingleRead {
+                    chrom: ins.chrom().to_string(),
+                    start: ins.start(),
+                    end: ins.end(),
+                    barcode: None,
+                    count: 1,
+                    strand,
+                })
+            })
+            .collect::<SmallVec<[_; 2]>>()
+    });
+    Ok(Box::new(
+        sorter
+            .build()?
+            .sort_by(insertions, |a, b| a.compare(b))?
+            .map_while(|x| capture_error(error, x))
+            .map(move |x| {
+                let strand = x.strand().unwrap_or(Strand::Forward);
+                let w = genome
+                    .fetch(x.chrom())
+                    .map_or(1.0, |seq| 1.0 / model.bias_at(seq, x.start(), strand));
+                (x.to_genomic_range(), w)
+            }),
+    ))
+}
+
+/// How [`obs_group_labels`] handles the cells with a missing value in one of
+/// the keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingGroup {
+    /// Raise an error.
+    Error,
+    /// Leave the cells out of every group.
+    Drop,
+    /// Group the cells with a missing value together, see [`ObsGroups`].
+    Keep,
+}
+
+impl std::str::FromStr for MissingGroup {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(MissingGroup::Error),
+            "drop" => Ok(MissingGroup::Drop),
+            "keep" => Ok(MissingGroup::Keep),
+            _ => Err(format!("unknown missing value policy: {}", s)),
+        }
+    }
+}
+
+/// The groups of the cells given by their values in some `.obs` columns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObsGroups {
+    /// The label of every cell, or `None` for the cells left out by
+    /// [`MissingGroup::Drop`]. The values of the cell are separated by "/",
+    /// so that each key is a directory level of the output, see
+    /// [`group_path`]; "/" in the values themselves is replaced by "+".
+    /// Missing values kept by [`MissingGroup::Keep`] are written "NA", with
+    /// "_" appended as long as this is a value of the key.
+    pub labels: Vec<Option<String>>,
+    /// The values of the cells of every label, `None` for the missing values.
+    pub values: IndexMap<String, Vec<Option<String>>>,
+    /// A label given to no group, for the cells without a group.
+    unlabeled: String,
+}
+
+impl ObsGroups {
+    /// The group of every cell and the selected groups, as taken by the
+    /// exporters. The cells without a group are not selected.
+    pub fn select<'a>(
+        &'a self,
+        selections: Option<HashSet<&'a str>>,
+    ) -> (Vec<&'a str>, Option<HashSet<&'a str>>) {
+        let group_by = self
+            .labels
+            .iter()
+            .map(|x| x.as_deref().unwrap_or(&self.unlabeled))
+            .collect();
+        let selections = if self.labels.iter().any(|x| x.is_none()) {
+            let selections = selections
+                .unwrap_or_else(|| self.values.keys().map(|x| x.as_str()).collect())
+                .into_iter()
+                .filter(|x| *x != self.unlabeled)
+                .collect();
+            Some(selections)
+        } else {
+            selections
+        };
+        (group_by, selections)
+    }
+}
+
+/// The groups of the cells given by their values in the `.obs` columns
+/// `keys`. Categorical columns are decoded.
+pub fn obs_group_labels<A: AnnDataOp>(
+    adata: &A,
+    keys: &[&str],
+    missing: MissingGroup,
+) -> Result<ObsGroups> {
+    group_labels(&adata.read_obs()?, keys, missing)
+}
+
+fn group_labels(obs: &DataFrame, keys: &[&str], missing: MissingGroup) -> Result<ObsGroups> {
+    ensure!(!keys.is_empty(), "no group keys are given");
+    let columns = keys
+        .iter()
+        .map(|key| {
+            let column = obs
+                .column(key)
+                .with_context(|| format!("'.obs[\"{}\"]' does not exist", key))?;
+            Ok(column.cast(&DataType::String)?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let columns = columns.iter().map(|x| x.str()).collect::<Result<Vec<_>, _>>()?;
+    // The name of the missing values of every key, distinct from its values.
+    let missing_names: Vec<String> = columns
+        .iter()
+        .map(|column| {
+            let values: HashSet<String> = column.into_iter().flatten().map(escape_level).collect();
+            let mut name = "NA".to_string();
+            while values.contains(&name) {
+                name.push('_');
+            }
+            name
+        })
+        .collect();
+
+    let mut values = IndexMap::new();
+    let labels = (0..obs.height())
+        .map(|i| {
+            let mut cell = Vec::with_capacity(keys.len());
+            for (key, column) in keys.iter().zip(&columns) {
+                match (column.get(i), missing) {
+                    (Some(x), _) => cell.push(Some(x.to_string())),
+                    (None, MissingGroup::Keep) => cell.push(None),
+                    (None, MissingGroup::Drop) => return Ok(None),
+                    (None, MissingGroup::Error) => bail!(
+                        "'.obs[\"{}\"]' has a missing value in row {}, drop or keep the cells with missing values instead",
+                        key, i
+                    ),
+                }
+            }
+            let label = cell
+                .iter()
+                .zip(&missing_names)
+                .map(|(x, na)| x.as_deref().map_or(na.clone(), escape_level))
+                .join("/");
+            values.entry(label.clone()).or_insert(cell);
+            Ok(Some(label))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut unlabeled = String::new();
+    while values.contains_key(&unlabeled) {
+        unlabeled.push('_');
+    }
+    Ok(ObsGroups {
+        labels,
+        values,
+        unlabeled,
+    })
+}
+
+/// A value as a level of a group label, see [`ObsGroups::labels`].
+fn escape_level(x: &str) -> String {
+    x.replace('/', "+")
+}
+
+/// Write the fragments of every cell to the writer of its group, and finish
+/// the writers. Cells whose group has no writer are skipped.
+fn write_fragments<T: SnapData + ?Sized>(
+    data: &T,
+    barcodes: Option<&Vec<&str>>,
+    group_by: &Vec<&str>,
+    min_fragment_length: Option<u64>,
+    max_fragment_length: Option<u64>,
+    modality: Option<&str>,
+    writers: HashMap<&str, Mutex<utils::FileWriter>>,
+) -> Result<()> {
+    let style = ProgressStyle::with_template(
+        "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})",
+    )?;
+    let (mut fragment_data, error) =
+        selected_fragments(data, group_by, |g| writers.contains_key(g), modality)?;
+    if let Some(min_len) = min_fragment_length {
+        fragment_data = fragment_data.min_fragment_size(min_len);
+    }
+    if let Some(max_len) = max_fragment_length {
+        fragment_data = fragment_data.max_fragment_size(max_len);
+    }
+
+    fragment_data
+        .into_fragment_groups(|i| group_by[i])
+        .progress_with_style(style)
+        .try_for_each(|group| {
+            crate::config::install(None, || {
+                group.into_par_iter().try_for_each(|(k, frags)| {
+                    if let Some(fl) = writers.get(k) {
+                        let mut fl = fl.lock().unwrap();
+                        frags.into_iter().try_for_each(|(i, mut f)| {
+                            if let Some(barcodes) = barcodes {
+                                f.set_barcode(Some(barcodes[i]));
+                            }
+                            writeln!(fl, "{}", f)
+                        })?;
+                    }
+                    anyhow::Ok(())
+                })
+            })
+        })?;
+    check_read_error(&error)?;
+    writers
+        .into_values()
+        .try_for_each(|w| w.into_inner().unwrap().finish())
+}
+
 impl<T> Exporter for T where T: SnapData {}
 
 pub trait Exporter: SnapData {
@@ -53,6 +424,7 @@ pub trait Exporter: SnapData {
         selections: Option<HashSet<&str>>,
         min_fragment_length: Option<u64>,
         max_fragment_length: Option<u64>,
+        modality: Option<&str>,
         dir: P,
         prefix: &str,
         suffix: &str,
@@ -66,51 +438,53 @@ pub trait Exporter: SnapData {
         }
         std::fs::create_dir_all(&dir)
             .with_context(|| format!("cannot create directory: {}", dir.as_ref().display()))?;
-        let files = groups
-            .into_iter()
-            .map(|x| {
-                let filename = prefix.to_string() + x + suffix;
-                if !sanitize_filename::is_sanitized(&filename) {
-                    bail!("invalid filename: {}", filename);
-                }
-                let filename = dir.as_ref().join(filename);
-                let writer = utils::open_file_for_write(&filename, compression, compression_level)?;
-                Ok((x, (filename, Arc::new(Mutex::new(writer)))))
-            })
-            .collect::<Result<HashMap<_, _>>>()?;
-
-        let style = ProgressStyle::with_template(
-            "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})",
-        )?;
-        let mut fragment_data = self.get_fragment_iter(1000)?;
-        if let Some(min_len) = min_fragment_length {
-            fragment_data = fragment_data.min_fragment_size(min_len);
-        }
-        if let Some(max_len) = max_fragment_length {
-            fragment_data = fragment_data.max_fragment_size(max_len);
+        let mut files = HashMap::new();
+        let mut writers = HashMap::new();
+        for x in groups {
+            let filename = group_path(dir.as_ref(), prefix, x, suffix)?;
+            let writer = utils::open_file_for_write(&filename, compression, compression_level)?;
+            files.insert(x.to_string(), filename);
+            writers.insert(x, Mutex::new(writer));
         }
+        write_fragments(
+            self,
+            barcodes,
+            group_by,
+            min_fragment_length,
+            max_fragment_length,
+            modality,
+            writers,
+        )?;
+        Ok(files)
+    }
 
-        fragment_data
-            .into_fragment_groups(|i| group_by[i])
-            .progress_with_style(style)
-            .try_for_each(|group| {
-                group.into_par_iter().try_for_each(|(k, frags)| {
-                    if let Some((_, fl)) = files.get(k) {
-                        let mut fl = fl.lock().unwrap();
-                        frags.into_iter().try_for_each(|(i, mut f)| {
-                            if let Some(barcodes) = barcodes {
-                                f.set_barcode(Some(barcodes[i]));
-                            }
-                            writeln!(fl, "{}", f)
-                        })?;
-                    }
-                    anyhow::Ok(())
-                })
-            })?;
-        Ok(files
-            .into_iter()
-            .map(|(k, (v, _))| (k.to_string(), v))
-            .collect())
+    /// Export the fragments of a single group to `output`, which can be a
+    /// named pipe or "-" for the standard output, e.g., to pipe the
+    /// fragments into other programs without temporary files.
+    fn export_group_fragments<P: AsRef<Path>>(
+        &self,
+        barcodes: Option<&Vec<&str>>,
+        group_by: &Vec<&str>,
+        group: &str,
+        min_fragment_length: Option<u64>,
+        max_fragment_length: Option<u64>,
+        modality: Option<&str>,
+        output: P,
+        compression: Option<Compression>,
+        compression_level: Option<u32>,
+    ) -> Result<()> {
+        ensure!(self.n_obs() == group_by.len(), "lengths differ");
+        let writer = utils::open_file_for_write(output, compression, compression_level)?;
+        let writers = HashMap::from([(group, Mutex::new(writer))]);
+        write_fragments(
+            self,
+            barcodes,
+            group_by,
+            min_fragment_length,
+            max_fragment_length,
+            modality,
+            writers,
+        )
     }
 
     fn export_serialized_fragments<P: AsRef<Path>>(
@@ -120,6 +494,7 @@ pub trait Exporter: SnapData {
         selections: Option<HashSet<&str>>,
         min_fragment_length: Option<u64>,
         max_fragment_length: Option<u64>,
+        modality: Option<&str>,
         dir: P,
         prefix: &str,
     ) -> Result<HashMap<String, ExternalChunk<Fragment>>> {
@@ -133,11 +508,7 @@ pub trait Exporter: SnapData {
         let files = groups
             .into_iter()
             .map(|x| {
-                let filename = prefix.to_string() + x + ".bin";
-                if !sanitize_filename::is_sanitized(&filename) {
-                    bail!("invalid filename: {}", filename);
-                }
-                let filename = dir.as_ref().join(filename);
+                let filename = group_path(dir.as_ref(), prefix, x, ".bin")?;
                 let writer = bed_utils::extsort::ExternalChunkBuilder::new(
                     OpenOptions::new()
                         .read(true)
@@ -154,7 +525,8 @@ pub trait Exporter: SnapData {
         let style = ProgressStyle::with_template(
             "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})",
         )?;
-        let mut fragment_data = self.get_fragment_iter(1000)?;
+        let (mut fragment_data, error) =
+            selected_fragments(self, group_by, |g| files.contains_key(g), modality)?;
         if let Some(min_len) = min_fragment_length {
             fragment_data = fragment_data.min_fragment_size(min_len);
         }
@@ -166,33 +538,29 @@ pub trait Exporter: SnapData {
             .into_fragment_groups(|i| group_by[i])
             .progress_with_style(style)
             .try_for_each(|group| {
-                group.into_par_iter().try_for_each(|(k, frags)| {
-                    if let Some(fl) = files.get(k) {
-                        let mut fl = fl.lock().unwrap();
-                        frags.into_iter().try_for_each(|(i, mut f)| {
-                            if let Some(barcodes) = barcodes {
-                                f.set_barcode(Some(barcodes[i]));
-                            }
-                            fl.add(f)
-                        })?;
-                    }
-                    anyhow::Ok(())
+                crate::config::install(None, || {
+                    group.into_par_iter().try_for_each(|(k, frags)| {
+                        if let Some(fl) = files.get(k) {
+                            let mut fl = fl.lock().unwrap();
+                            frags.into_iter().try_for_each(|(i, mut f)| {
+                                if let Some(barcodes) = barcodes {
+                                    f.set_barcode(Some(barcodes[i]));
+                                }
+                                fl.add(f)
+                            })?;
+                        }
+                        anyhow::Ok(())
+                    })
                 })
             })?;
-        Ok(files
+        check_read_error(&error)?;
+        files
             .into_iter()
             .map(|(k, v)| {
-                (
-                    k.to_string(),
-                    Arc::into_inner(v)
-                        .unwrap()
-                        .into_inner()
-                        .unwrap()
-                        .finish()
-                        .unwrap(),
-                )
+                let chunk = Arc::into_inner(v).unwrap().into_inner().unwrap().finish()?;
+                Ok((k.to_string(), chunk))
             })
-            .collect())
+            .collect()
     }
 
     fn export_coverage<P: AsRef<Path> + std::marker::Sync>(
@@ -202,21 +570,38 @@ pub trait Exporter: SnapData {
         resolution: usize,
         blacklist_regions: Option<&GIntervalMap<()>>,
         normalization: Option<Normalization>,
+        value_cap: Option<ValueCap>,
         include_for_norm: Option<&GIntervalMap<()>>,
         exclude_for_norm: Option<&GIntervalMap<()>>,
+        spike_in: Option<&SpikeIn>,
+        effective_genome_size: Option<u64>,
         min_fragment_length: Option<u64>,
         max_fragment_length: Option<u64>,
+        modality: Option<&str>,
         counting_strategy: CountingStrategy,
+        bias_correction: Option<(&BiasModel, &Path)>,
         smooth_base: Option<u64>,
+        smooth_kernel: SmoothKernel,
         dir: P,
         prefix: &str,
         suffix: &str,
         format: CoverageOutputFormat,
         compression: Option<Compression>,
         compression_level: Option<u32>,
+        fragment_output: Option<FragmentOutput>,
+        track_stats: Option<&[f64]>,
         temp_dir: Option<P>,
         num_threads: Option<usize>,
-    ) -> Result<HashMap<String, PathBuf>> {
+    ) -> Result<HashMap<String, ExportedTrack>> {
+        if let Some((_, genome)) = bias_correction {
+            ensure!(
+                matches!(counting_strategy, CountingStrategy::Insertion),
+                "bias correction requires the insertion counting strategy"
+            );
+            // Fail early, and create the index of the genome only once.
+            GenomeSequence::open(genome)?;
+        }
+
         // Create directory
         std::fs::create_dir_all(&dir)
             .with_context(|| format!("cannot create directory: {}", dir.as_ref().display()))?;
@@ -233,11 +618,12 @@ pub trait Exporter: SnapData {
 
         info!("Exporting fragments...");
         let fragment_files = self.export_serialized_fragments(
-            None,
+            fragment_output.as_ref().and_then(|x| x.barcodes),
             group_by,
             selections,
             min_fragment_length,
             max_fragment_length,
+            modality,
             temp_dir.path(),
             "",
         )?;
@@ -249,70 +635,453 @@ pub trait Exporter: SnapData {
         )
         .unwrap();
 
-        let pool = if let Some(n) = num_threads {
-            rayon::ThreadPoolBuilder::new().num_threads(n)
-        } else {
-            rayon::ThreadPoolBuilder::new()
-        };
-        pool.build().unwrap().install(|| {
+        crate::config::install(num_threads, || {
             fragment_files
                 .into_iter()
                 .collect::<Vec<_>>()
                 .into_par_iter()
                 .map(|(grp, chunk)| {
-                    let output = dir
-                        .as_ref()
-                        .join(prefix.to_string() + grp.replace("/", "+").as_str() + suffix);
+                    let output = group_path(dir.as_ref(), prefix, &grp, suffix)?;
+
+                    // `chunk_size` is the number of items fitting in the memory limit.
+                    let new_sorter = |chunk_size: usize| {
+                        let mut sorter = ExternalSorterBuilder::new().with_tmp_dir(temp_dir.path());
+                        if crate::config::memory_limit().is_some() {
+                            let n = num_threads.unwrap_or_else(crate::config::num_threads);
+                            sorter = sorter.with_chunk_size(chunk_size / n.max(1));
+                        }
+                        sorter
+                    };
+                    let range_size = crate::config::buffer_size::<GenomicRange>(0);
 
-                    let fragments: Box<dyn Iterator<Item = _>> = match counting_strategy {
-                        CountingStrategy::Fragment => {
-                            Box::new(chunk.map(|x| x.unwrap().to_genomic_range()))
+                    // Errors reading or writing the fragments stop the iteration
+                    // and are stored in `error`.
+                    let error = ReadErrorSlot::default();
+                    let fragment_writer = std::cell::RefCell::new(None);
+                    let mut fragment_file = None;
+                    // With bias correction, the insertions are sorted along with
+                    // the strand of their read, see [`sorted_insertions`].
+                    let insertion_size = match bias_correction {
+                        None => range_size,
+                        Some(_) => crate::config::buffer_size::<Fragment>(0),
+                    };
+                    let weighted: Box<dyn Iterator<Item = (GenomicRange, f64)> + '_> = match &fragment_output {
+                        None => {
+                            let chunk = chunk.map_while(|x| capture_error(&error, x));
+                            match counting_strategy {
+                                CountingStrategy::Fragment => sorted_ranges(
+                                    chunk.map(|x| x.to_genomic_range()),
+                                    new_sorter(range_size),
+                                    &error,
+                                )?,
+                                CountingStrategy::Insertion => sorted_insertions(
+                                    chunk,
+                                    new_sorter(insertion_size),
+                                    bias_correction,
+                                    &error,
+                                )?,
+                                CountingStrategy::PIC => sorted_ranges(
+                                    chunk.flat_map(move |x| paired_insertions(&x, resolution as u64)),
+                                    new_sorter(range_size),
+                                    &error,
+                                )?,
+                            }
                         }
-                        CountingStrategy::Insertion => {
-                            Box::new(chunk.flat_map(|x| x.unwrap().to_insertions()))
+                        // Sort the fragments themselves, and write them out as they
+                        // stream into the coverage.
+                        Some(out) => {
+                            let path = group_path(dir.as_ref(), prefix, &grp, out.suffix)?;
+                            fragment_file = Some(path.clone());
+                            *fragment_writer.borrow_mut() = Some(utils::open_file_for_write(
+                                &path,
+                                out.compression,
+                                out.compression_level,
+                            )?);
+                            let sorted = new_sorter(crate::config::buffer_size::<Fragment>(0))
+                                .build()?
+                                .sort_by(chunk.map_while(|x| capture_error(&error, x)), |a, b| {
+                                    a.compare(b)
+                                })?
+                                .map_while(|x| capture_error(&error, x))
+                                .map_while(|x| {
+                                    let mut writer = fragment_writer.borrow_mut();
+                                    capture_error(&error, writeln!(writer.as_mut().unwrap(), "{}", x))
+                                        .map(|_| x)
+                                });
+                            match counting_strategy {
+                                CountingStrategy::Fragment => {
+                                    Box::new(sorted.map(|x| (x.to_genomic_range(), 1.0)))
+                                }
+                                CountingStrategy::Insertion => sorted_insertions(
+                                    sorted,
+                                    new_sorter(insertion_size),
+                                    bias_correction,
+                                    &error,
+                                )?,
+                                CountingStrategy::PIC => sorted_ranges(
+                                    sorted.flat_map(move |x| paired_insertions(&x, resolution as u64)),
+                                    new_sorter(range_size),
+                                    &error,
+                                )?,
+                            }
                         }
-                        _ => todo!(),
                     };
-                    let fragments = ExternalSorterBuilder::new()
-                        .with_tmp_dir(temp_dir.path())
-                        .build()?
-                        .sort_by(fragments, |a, b| a.compare(b))?
-                        .map(Result::unwrap);
 
                     // Make BedGraph
-                    let bedgraph = create_bedgraph_from_sorted_fragments(
-                        fragments,
+                    let mut out_of_bounds = OutOfBounds::default();
+                    let track = CoverageTrack::new(
+                        weighted,
                         &chrom_sizes,
                         resolution as u64,
                         smooth_base,
+                        smooth_kernel,
                         blacklist_regions,
                         normalization,
+                        value_cap,
                         include_for_norm,
                         exclude_for_norm,
-                    );
-
-                    match format {
-                        CoverageOutputFormat::BedGraph => {
-                            let mut writer = utils::open_file_for_write(
-                                &output,
-                                compression,
-                                compression_level,
-                            )?;
-                            bedgraph
-                                .into_iter()
-                                .for_each(|x| writeln!(writer, "{}", x).unwrap());
-                        }
-                        CoverageOutputFormat::BigWig => {
-                            create_bigwig_from_bedgraph(bedgraph, &chrom_sizes, &output)?;
-                        }
+                        spike_in,
+                        effective_genome_size,
+                        Some(temp_dir.path()),
+                        &mut out_of_bounds,
+                    )
+                    .with_context(|| format!("cannot compute the coverage of group '{}'", grp))?;
+                    check_read_error(&error)
+                        .with_context(|| format!("cannot read the fragments of group '{}'", grp))?;
+                    if let Some(writer) = fragment_writer.into_inner() {
+                        writer
+                            .finish()
+                            .with_context(|| format!("cannot write the fragments of group '{}'", grp))?;
                     }
+                    out_of_bounds.report(&format!("fragments of group '{}'", grp))?;
 
-                    Ok((grp.to_string(), output))
+                    // The records are summarized as they stream into the output.
+                    let mut summary = track_stats.map(|_| TrackSummary::default());
+                    write_coverage_track(
+                        &track,
+                        format,
+                        &output,
+                        compression,
+                        compression_level,
+                        summary.as_mut(),
+                    )
+                    .with_context(|| format!("cannot write the coverage of group '{}'", grp))?;
+                    let stats = track_stats
+                        .zip(summary)
+                        .map(|(quantiles, summary)| summary.finish(chrom_sizes.total_size(), quantiles));
+
+                    let track = ExportedTrack {
+                        coverage: output,
+                        fragments: fragment_file,
+                        stats,
+                        out_of_bounds,
+                        spike_in_count: spike_in.map(|_| track.spike_in_count),
+                    };
+                    Ok((grp.to_string(), track))
                 })
                 .progress_with_style(style)
                 .collect()
         })
     }
+
+    /// Export the coverage of each group of cells from the coverage cache,
+    /// without scanning the fragments. This is much faster than
+    /// [`Exporter::export_coverage`] and meant for a quick look: the values
+    /// are insertion counts at the resolution of the cache, and fragments are
+    /// not filtered by size. See [`crate::feature_count::build_coverage_cache`].
+    fn export_coverage_from_cache<P: AsRef<Path>>(
+        &self,
+        group_by: &Vec<&str>,
+        selections: Option<HashSet<&str>>,
+        normalization: Option<Normalization>,
+        dir: P,
+        prefix: &str,
+        suffix: &str,
+        format: CoverageOutputFormat,
+        compression: Option<Compression>,
+        compression_level: Option<u32>,
+    ) -> Result<HashMap<String, PathBuf>> {
+        ensure!(
+            group_by.len() == self.n_obs(),
+            "Length of group_by must match number of cells"
+        );
+        let cache = read_coverage_cache(self, self.fragment_chunk_size()?)?
+            .context("no valid coverage cache is found, please run `build_coverage_cache` first")?;
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("cannot create directory: {}", dir.as_ref().display()))?;
+
+        let mut counts: HashMap<&str, BTreeMap<usize, u64>> = HashMap::new();
+        cache.data.for_each(|(mat, s, _)| {
+            mat.row_iter().enumerate().for_each(|(i, row)| {
+                let grp = group_by[s + i];
+                if selections.as_ref().map_or(true, |x| x.contains(grp)) {
+                    let count = counts.entry(grp).or_default();
+                    row.col_indices().iter().zip(row.values()).for_each(|(j, v)| {
+                        *count.entry(*j).or_insert(0) += *v as u64;
+                    });
+                }
+            })
+        });
+
+        let chrom_sizes = self.read_chrom_sizes()?;
+        let bin_size = cache.info.bin_size as u64;
+        counts
+            .into_iter()
+            .map(|(grp, count)| {
+                let output = group_path(dir.as_ref(), prefix, grp, suffix)?;
+                let total = count.values().sum::<u64>() as f64;
+                let mut bedgraph: Vec<_> = count
+                    .into_iter()
+                    .map(|(j, v)| BedGraph::from_bed(&cache.index.get_region(j), v as f64))
+                    .collect();
+                let norm_factor = match normalization {
+                    None => 1.0,
+                    Some(Normalization::RPKM) => total * bin_size as f64 / 1e9,
+                    Some(Normalization::CPM) => total / 1e6,
+                    // The sum of the signal of all bins, as in `CoverageTrack`.
+                    Some(Normalization::BPM) => {
+                        BinnedValues::from_bedgraph(&bedgraph, bin_size, false).sum / 1e6
+                    }
+                    Some(Normalization::RPGC) => {
+                        bail!("RPGC normalization is not supported with the coverage cache")
+                    }
+                    Some(Normalization::Quantile) | Some(Normalization::ZScore) => 1.0,
+                    Some(Normalization::SpikeIn) => {
+                        bail!("spike-in normalization is not supported with the coverage cache")
+                    }
+                };
+                bedgraph.iter_mut().for_each(|x| x.value /= norm_factor);
+                if let Some(norm) = normalization {
+                    transform_bedgraph(&mut bedgraph, norm, bin_size, &chrom_sizes);
+                }
+                match format {
+                    CoverageOutputFormat::BedGraph => {
+                        let mut writer =
+                            utils::open_file_for_write(&output, compression, compression_level)?;
+                        bedgraph.into_iter().try_for_each(|x| writeln!(writer, "{}", x))?;
+                        writer.finish()?;
+                    }
+                    CoverageOutputFormat::BigWig => {
+                        create_bigwig_from_bedgraph(bedgraph, &chrom_sizes, &output)?;
+                    }
+                }
+                Ok((grp.to_string(), output))
+            })
+            .collect()
+    }
+
+    /// Export the per-base values imported by `import_values`, e.g.,
+    /// methylation levels, of each group of cells. Values are aggregated over
+    /// the cells of a group and the bases of bins of `resolution` bases, by
+    /// their sum, their mean or the number of records. Records lacking the
+    /// requested value type, e.g., counts of values imported as fractions,
+    /// are skipped, and bins without records are not written.
+    fn export_base_values<P: AsRef<Path>>(
+        &self,
+        group_by: &Vec<&str>,
+        selections: Option<HashSet<&str>>,
+        resolution: usize,
+        value_type: ValueType,
+        summary_type: SummaryType,
+        dir: P,
+        prefix: &str,
+        suffix: &str,
+        format: CoverageOutputFormat,
+        compression: Option<Compression>,
+        compression_level: Option<u32>,
+    ) -> Result<HashMap<String, PathBuf>> {
+        ensure!(
+            group_by.len() == self.n_obs(),
+            "Length of group_by must match number of cells"
+        );
+        ensure!(resolution > 0, "resolution must be positive");
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("cannot create directory: {}", dir.as_ref().display()))?;
+        let chrom_sizes = self.read_chrom_sizes()?;
+        let style = ProgressStyle::with_template(
+            "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})",
+        )?;
+
+        // Sum and number of records of every bin, keyed by chromosome index and bin.
+        let mut values: HashMap<&str, BTreeMap<(usize, u64), (f64, u64)>> = HashMap::new();
+        let chunk_size = crate::config::chunk_size().unwrap_or(500);
+        self.get_base_iter(chunk_size)?
+            .into_values()
+            .progress_with_style(style)
+            .for_each(|(chunk, start, _)| {
+                chunk.into_iter().enumerate().for_each(|(i, xs)| {
+                    let grp = group_by[start + i];
+                    if selections.as_ref().map_or(true, |x| x.contains(grp)) {
+                        let acc = values.entry(grp).or_default();
+                        xs.into_iter().for_each(|x| {
+                            let v = match value_type {
+                                ValueType::Ratio => Some(x.value()),
+                                ValueType::Numerator => x.numerator().map(f32::from),
+                                ValueType::Denominator => x.denominator().map(f32::from),
+                            };
+                            if let (Some(v), Some(c)) = (v, chrom_sizes.get_index_of(&x.chrom)) {
+                                let bin = acc.entry((c, x.pos / resolution as u64)).or_insert((0.0, 0));
+                                bin.0 += v as f64;
+                                bin.1 += 1;
+                            }
+                        });
+                    }
+                })
+            });
+
+        let chroms: Vec<(&String, u64)> = (&chrom_sizes).into_iter().map(|(k, v)| (k, *v)).collect();
+        values
+            .into_iter()
+            .map(|(grp, bins)| {
+                let output = group_path(dir.as_ref(), prefix, grp, suffix)?;
+                let bedgraph = bins.into_iter().map(|((c, bin), (sum, n))| {
+                    let (chrom, size) = chroms[c];
+                    let start = bin * resolution as u64;
+                    let value = match summary_type {
+                        SummaryType::Sum => sum,
+                        SummaryType::Mean => sum / n as f64,
+                        SummaryType::Count => n as f64,
+                    };
+                    BedGraph::new(chrom, start, (start + resolution as u64).min(size), value)
+                });
+                match format {
+                    CoverageOutputFormat::BedGraph => {
+                        let mut writer =
+                            utils::open_file_for_write(&output, compression, compression_level)?;
+                        for x in bedgraph {
+                            writeln!(writer, "{}", x)?;
+                        }
+                        writer.finish()?;
+                    }
+                    CoverageOutputFormat::BigWig => {
+                        create_bigwig_from_bedgraph(bedgraph, &chrom_sizes, &output)?;
+                    }
+                }
+                Ok((grp.to_string(), output))
+            })
+            .collect()
+    }
+}
+
+/// Write the expected Tn5 insertion bias of every bin of `bin_size` bases of
+/// the chromosomes in `chrom_sizes`, computed from the genome sequence.
+pub fn export_expected_bias<P: AsRef<Path>>(
+    model: &BiasModel,
+    genome: P,
+    chrom_sizes: &ChromSizes,
+    bin_size: u64,
+    output: P,
+    format: CoverageOutputFormat,
+    compression: Option<Compression>,
+    compression_level: Option<u32>,
+) -> Result<()> {
+    ensure!(bin_size > 0, "bin size must be positive");
+    let mut genome = GenomeSequence::open(genome)?;
+    // The bins are computed one chromosome at a time, as they are written.
+    let error = ReadErrorSlot::default();
+    let bedgraph = chrom_sizes
+        .into_iter()
+        .map_while(|(chrom, _)| capture_error(&error, model.expected_bias(&mut genome, chrom, bin_size)))
+        .flatten()
+        .flat_map(|x| clip_bed(x, chrom_sizes));
+    match format {
+        CoverageOutputFormat::BedGraph => {
+            let mut writer = utils::open_file_for_write(&output, compression, compression_level)?;
+            for x in bedgraph {
+                writeln!(writer, "{}", x)?;
+            }
+            writer.finish()?;
+        }
+        CoverageOutputFormat::BigWig => {
+            create_bigwig_from_bedgraph(bedgraph, chrom_sizes, output.as_ref())?;
+        }
+    }
+    check_read_error(&error)
+}
+
+/// Exact coverage of every base of `region` for each group of cells, without
+/// binning or merging. `group_by` gives the group of each cell; only the
+/// groups in `selections` are computed, if given.
+///
+/// For fragment data, the coverage of a base is the number of fragments
+/// spanning it or, with insertion counting, the number of insertions at it.
+/// For base-value data, it is the number of cells with a value at the base.
+pub fn base_coverage<A: SnapData>(
+    adata: &A,
+    region: &GenomicRange,
+    group_by: &[&str],
+    selections: Option<&HashSet<&str>>,
+    counting_strategy: CountingStrategy,
+    min_fragment_size: Option<u64>,
+    max_fragment_size: Option<u64>,
+) -> Result<HashMap<String, Vec<u32>>> {
+    ensure!(adata.n_obs() == group_by.len(), "lengths differ");
+    let chrom_sizes = adata.read_chrom_sizes()?;
+    let size = chrom_sizes
+        .get(region.chrom())
+        .with_context(|| format!("chromosome {} is not found", region.chrom()))?;
+    ensure!(
+        region.start() < region.end() && region.end() <= size,
+        "region {} is empty or beyond the end of the chromosome",
+        region.pretty_show()
+    );
+    let selected = |g: &str| selections.map_or(true, |s| s.contains(g));
+    let mut result: HashMap<String, Vec<u32>> = group_by
+        .iter()
+        .filter(|g| selected(g))
+        .map(|g| (g.to_string(), vec![0; region.len() as usize]))
+        .collect();
+    let mut add = |group: &str, start: u64, end: u64| {
+        if let Some(cov) = result.get_mut(group) {
+            let start = start.max(region.start());
+            let end = end.min(region.end());
+            for i in start..end {
+                cov[(i - region.start()) as usize] += 1;
+            }
+        }
+    };
+
+    match adata
+        .fragment_chunk_size()
+        .and_then(|n| adata.get_fragment_iter(n))
+    {
+        Ok(mut fragments) => {
+            if let Some(x) = min_fragment_size {
+                fragments = fragments.min_fragment_size(x);
+            }
+            if let Some(x) = max_fragment_size {
+                fragments = fragments.max_fragment_size(x);
+            }
+            fragments.into_fragment_groups(|i| group_by[i]).for_each(|groups| {
+                groups.into_iter().for_each(|(group, frags)| {
+                    frags
+                        .iter()
+                        .filter(|(_, f)| f.chrom() == region.chrom())
+                        .for_each(|(_, f)| match counting_strategy {
+                            CountingStrategy::Fragment => add(group, f.start(), f.end()),
+                            CountingStrategy::Insertion | CountingStrategy::PIC => f
+                                .to_insertions()
+                                .into_iter()
+                                .for_each(|x| add(group, x.start(), x.end())),
+                        });
+                })
+            });
+        }
+        Err(_) => {
+            let chunk_size = crate::config::chunk_size().unwrap_or(500);
+            adata
+                .get_base_iter(chunk_size)?
+                .into_values()
+                .for_each(|(values, start, _)| {
+                    values.into_iter().enumerate().for_each(|(i, xs)| {
+                        xs.into_iter()
+                            .filter(|x| x.chrom == region.chrom())
+                            .for_each(|x| add(group_by[start + i], x.pos, x.pos + 1));
+                    })
+                });
+        }
+    }
+    Ok(result)
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -324,7 +1093,12 @@ pub enum Normalization {
     BPM, // Bins Per Million mapped reads, same as TPM in RNA-seq. BPM (per bin) =
     // number of reads per bin / sum of all reads per bin (in millions).
     RPGC, // Reads per genomic content. RPGC (per bin) =
-          // number of reads per bin / scaling factor for 1x average coverage.
+    // number of reads per bin / scaling factor for 1x average coverage.
+    Quantile, // Quantile of the bin among the covered bins of the track, in (0, 1].
+    ZScore, // (number of reads per bin - mean) / standard deviation, computed over
+            // all bins of the genome, including the uncovered ones.
+    SpikeIn, // number of reads per bin * scale / number of reads on the spike-in
+             // chromosomes, see `SpikeIn`.
 }
 
 impl std::str::FromStr for Normalization {
@@ -335,11 +1109,85 @@ impl std::str::FromStr for Normalization {
             "CPM" => Ok(Normalization::CPM),
             "BPM" => Ok(Normalization::BPM),
             "RPGC" => Ok(Normalization::RPGC),
+            "QUANTILE" => Ok(Normalization::Quantile),
+            "ZSCORE" => Ok(Normalization::ZScore),
+            "SPIKEIN" => Ok(Normalization::SpikeIn),
             _ => Err(format!("unknown normalization method: {}", s)),
         }
     }
 }
 
+/// Spike-in chromosomes, e.g., the genome of E. coli or Drosophila added to
+/// CUT&Tag or ChIP experiments. Their fragments are not written to the
+/// coverage tracks; with [`Normalization::SpikeIn`], they are counted in
+/// every group to scale its track by `scale / count`.
+#[derive(Debug, Clone)]
+pub struct SpikeIn {
+    /// Patterns of the names of the spike-in chromosomes, see [`ChromFilter`].
+    pub chroms: ChromFilter,
+    pub scale: f64,
+}
+
+impl SpikeIn {
+    pub fn new(patterns: Vec<String>, scale: f64) -> Self {
+        Self {
+            chroms: ChromFilter { include: patterns, exclude: Vec::new() },
+            scale,
+        }
+    }
+
+    pub fn contains(&self, chrom: &str) -> bool {
+        !self.chroms.include.is_empty() && self.chroms.keep(chrom)
+    }
+}
+
+/// The kernel used to smooth coverage tracks over a window of `smooth_base` bases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmoothKernel {
+    /// Every base of the window has the same weight, i.e., a moving average.
+    Flat,
+    /// Gaussian weights, with a standard deviation of a sixth of the window.
+    Gaussian,
+    /// Epanechnikov (parabolic) weights, vanishing just outside the window.
+    Epanechnikov,
+}
+
+impl TryFrom<&str> for SmoothKernel {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "flat" => Ok(SmoothKernel::Flat),
+            "gaussian" => Ok(SmoothKernel::Gaussian),
+            "epanechnikov" => Ok(SmoothKernel::Epanechnikov),
+            _ => bail!("smoothing kernel must be one of 'flat', 'gaussian' or 'epanechnikov'"),
+        }
+    }
+}
+
+impl SmoothKernel {
+    /// The normalized weights of the bases at offsets `-right..=left` from
+    /// the smoothed position, or `None` for the flat kernel.
+    fn weights(&self, left: u64, right: u64) -> Option<Vec<f64>> {
+        let n = left + right + 1;
+        let center = (left as f64 - right as f64) / 2.0;
+        let offsets = (0..n).map(|i| i as f64 - right as f64 - center);
+        let weights: Vec<f64> = match self {
+            SmoothKernel::Flat => return None,
+            SmoothKernel::Gaussian => {
+                let sd = n as f64 / 6.0;
+                offsets.map(|d| (-0.5 * (d / sd).powi(2)).exp()).collect()
+            }
+            SmoothKernel::Epanechnikov => {
+                let h = (n + 1) as f64 / 2.0;
+                offsets.map(|d| 1.0 - (d / h).powi(2)).collect()
+            }
+        };
+        let total: f64 = weights.iter().sum();
+        Some(weights.into_iter().map(|x| x / total).collect())
+    }
+}
+
 /// Create a BedGraph file from fragments.
 ///
 /// The values represent the sequence coverage (or sequencing depth), which refers
@@ -368,54 +1216,600 @@ fn create_bedgraph_from_sorted_fragments<I, B>(
     normalization: Option<Normalization>,
     include_for_norm: Option<&GIntervalMap<()>>,
     exclude_for_norm: Option<&GIntervalMap<()>>,
-) -> Vec<BedGraph<f64>>
+) -> Result<Vec<BedGraph<f64>>>
 where
     I: Iterator<Item = B>,
     B: BEDLike,
 {
-    let mut norm_factor = 0u64;
-    let mut bedgraph: Vec<_> = fragments
-        .flat_map(|frag| {
-            if blacklist_regions.map_or(false, |bl| bl.is_overlapped(&frag)) {
-                None
+    let mut out_of_bounds = OutOfBounds::default();
+    let (bedgraph, _) = create_weighted_bedgraph_from_sorted_fragments(
+        fragments.map(|x| (x, 1.0)),
+        chrom_sizes,
+        bin_size,
+        smooth_base,
+        SmoothKernel::Flat,
+        blacklist_regions,
+        normalization,
+        None,
+        include_for_norm,
+        exclude_for_norm,
+        None,
+        None,
+        &mut out_of_bounds,
+    )?;
+    out_of_bounds.report("fragments")?;
+    Ok(bedgraph)
+}
+
+/// Like [`create_bedgraph_from_sorted_fragments`], with every fragment
+/// contributing its weight rather than 1 to the coverage and to the
+/// normalization factor. Fragments on the `spike_in` chromosomes are left out
+/// of the coverage; their total weight is returned along with the coverage.
+/// `effective_genome_size` is the mappable genome size used by RPGC
+/// normalization, which defaults to the total size of the chromosomes.
+fn create_weighted_bedgraph_from_sorted_fragments<I, B>(
+    fragments: I,
+    chrom_sizes: &ChromSizes,
+    bin_size: u64,
+    smooth_base: Option<u64>,
+    smooth_kernel: SmoothKernel,
+    blacklist_regions: Option<&GIntervalMap<()>>,
+    normalization: Option<Normalization>,
+    value_cap: Option<ValueCap>,
+    include_for_norm: Option<&GIntervalMap<()>>,
+    exclude_for_norm: Option<&GIntervalMap<()>>,
+    spike_in: Option<&SpikeIn>,
+    effective_genome_size: Option<u64>,
+    out_of_bounds: &mut OutOfBounds,
+) -> Result<(Vec<BedGraph<f64>>, f64)>
+where
+    I: Iterator<Item = (B, f64)>,
+    B: BEDLike,
+{
+    let track = CoverageTrack::new(
+        fragments,
+        chrom_sizes,
+        bin_size,
+        smooth_base,
+        smooth_kernel,
+        blacklist_regions,
+        normalization,
+        value_cap,
+        include_for_norm,
+        exclude_for_norm,
+        spike_in,
+        effective_genome_size,
+        None::<&Path>,
+        out_of_bounds,
+    )?;
+    let bedgraph = track.records()?.collect();
+    track.check_read_error()?;
+    Ok((bedgraph, track.spike_in_count))
+}
+
+/// A coverage track computed from sorted fragments. The merged records are
+/// spilled to a temporary file rather than kept in memory, and are capped,
+/// normalized and smoothed on the fly as they are read back by
+/// [`CoverageTrack::records`]. Only the statistics needed by the
+/// normalization are kept in memory, so that deep tracks at a resolution of
+/// 1 base can be written with a bounded amount of memory.
+struct CoverageTrack {
+    spill: tempfile::NamedTempFile,
+    /// The chromosome sizes, resolved with the policy for missing chromosomes.
+    chrom_sizes: ChromSizes,
+    max_value: f64,
+    norm_factor: f64,
+    transform: Option<ValueTransform>,
+    /// The left and right window lengths and the kernel weights.
+    smoothing: Option<(u64, u64, Option<Vec<f64>>)>,
+    spike_in_count: f64,
+    /// The first error reading the spilled records back, see
+    /// [`CoverageTrack::check_read_error`].
+    read_error: ReadErrorSlot,
+}
+
+impl CoverageTrack {
+    /// See [`create_weighted_bedgraph_from_sorted_fragments`] for the
+    /// arguments. The records are spilled to `temp_dir`, or to the default
+    /// temporary directory if it is `None`.
+    fn new<I, B, P>(
+        fragments: I,
+        chrom_sizes: &ChromSizes,
+        bin_size: u64,
+        smooth_base: Option<u64>,
+        smooth_kernel: SmoothKernel,
+        blacklist_regions: Option<&GIntervalMap<()>>,
+        normalization: Option<Normalization>,
+        value_cap: Option<ValueCap>,
+        include_for_norm: Option<&GIntervalMap<()>>,
+        exclude_for_norm: Option<&GIntervalMap<()>>,
+        spike_in: Option<&SpikeIn>,
+        effective_genome_size: Option<u64>,
+        temp_dir: Option<P>,
+        out_of_bounds: &mut OutOfBounds,
+    ) -> Result<Self>
+    where
+        I: Iterator<Item = (B, f64)>,
+        B: BEDLike,
+        P: AsRef<Path>,
+    {
+        let mut spill = match temp_dir {
+            Some(dir) => tempfile::NamedTempFile::new_in(dir)?,
+            None => tempfile::NamedTempFile::new()?,
+        };
+        let mut norm_factor = 0.0;
+        let mut spike_in_count = 0.0;
+        // The largest end coordinate of the chromosomes missing from `chrom_sizes`.
+        let mut missing: IndexMap<String, u64> = IndexMap::new();
+        let mut writer = std::io::BufWriter::new(spill.as_file_mut());
+        fragments
+            .flat_map(|(frag, weight)| {
+                if spike_in.map_or(false, |s| s.contains(frag.chrom())) {
+                    spike_in_count += weight;
+                    return None;
+                }
+                // Count the fragments beyond the end of their chromosome, which
+                // are clipped or dropped below. Bins extending beyond the end are
+                // clipped as well but are not counted.
+                if let Some(size) = chrom_sizes.get(frag.chrom()) {
+                    if frag.start() >= size {
+                        out_of_bounds.dropped += 1;
+                    } else if frag.end() > size {
+                        out_of_bounds.clipped += 1;
+                    }
+                }
+                if blacklist_regions.map_or(false, |bl| bl.is_overlapped(&frag)) {
+                    None
+                } else {
+                    if include_for_norm.map_or(true, |x| x.is_overlapped(&frag))
+                        && !exclude_for_norm.map_or(false, |x| x.is_overlapped(&frag))
+                    {
+                        norm_factor += frag.len() as f64 * weight;
+                    }
+                    let mut frag = BedGraph::from_bed(&frag, weight);
+                    fit_to_bin(&mut frag, bin_size);
+                    Some(frag)
+                }
+            })
+            .merge_sorted_bedgraph()
+            .try_for_each(|x| {
+                if chrom_sizes.get(x.chrom()).is_none() {
+                    let end = missing.entry(x.chrom().to_string()).or_insert(0);
+                    *end = (*end).max(x.end());
+                }
+                writeln!(writer, "{}", x)
+            })?;
+        writer.flush()?;
+        drop(writer);
+
+        let missing: Vec<GenomicRange> = missing
+            .into_iter()
+            .map(|(chrom, end)| GenomicRange::new(chrom, 0, end))
+            .collect();
+        let mut track = Self {
+            spill,
+            chrom_sizes: chrom_sizes
+                .resolve_missing(&missing, crate::config::missing_chrom_policy())?,
+            max_value: f64::INFINITY,
+            norm_factor: 1.0,
+            transform: None,
+            smoothing: None,
+            spike_in_count,
+            read_error: ReadErrorSlot::default(),
+        };
+
+        // Collect the statistics of the capped values in a second pass.
+        let with_histogram = matches!(value_cap, Some(ValueCap::Quantile(_)))
+            || matches!(normalization, Some(Normalization::Quantile));
+        let mut values = BinnedValues::new(with_histogram);
+        if let Some(ValueCap::Max(max)) = value_cap {
+            track.max_value = max;
+        }
+        track
+            .raw_records()?
+            .for_each(|x| values.add(x.value.min(track.max_value), x.len().div_ceil(bin_size)));
+        track.check_read_error()?;
+        if let Some(ValueCap::Quantile(q)) = value_cap {
+            track.max_value = values.quantile(q);
+            values = values.capped(track.max_value);
+        }
+
+        track.norm_factor = match normalization {
+            None => 1.0,
+            Some(Normalization::RPKM) => norm_factor * bin_size as f64 / 1e9,
+            Some(Normalization::CPM) => norm_factor / 1e6,
+            Some(Normalization::BPM) => values.sum / 1e6,
+            Some(Normalization::RPGC) => {
+                let genome_size =
+                    effective_genome_size.unwrap_or_else(|| track.chrom_sizes.total_size());
+                ensure!(genome_size > 0, "the effective genome size must be positive");
+                norm_factor / genome_size as f64
+            }
+            Some(Normalization::Quantile) | Some(Normalization::ZScore) => 1.0,
+            Some(Normalization::SpikeIn) => {
+                let spike_in = spike_in
+                    .context("spike-in normalization requires spike-in chromosomes")?;
+                ensure!(spike_in_count > 0.0, "no fragments are found on the spike-in chromosomes");
+                spike_in_count / spike_in.scale
+            }
+        };
+        track.transform = normalization
+            .and_then(|norm| ValueTransform::new(norm, &values, bin_size, &track.chrom_sizes));
+
+        if let Some(smooth_base) = smooth_base.filter(|x| *x > 0) {
+            let smooth_left = (smooth_base - 1) / 2;
+            let smooth_right = smooth_base - 1 - smooth_left;
+            track.smoothing = Some((
+                smooth_left,
+                smooth_right,
+                smooth_kernel.weights(smooth_left, smooth_right),
+            ));
+        }
+        Ok(track)
+    }
+
+    /// The merged records as spilled, clipped to the chromosome sizes. The
+    /// records stop at the first one that cannot be read back, whose error
+    /// is returned by [`CoverageTrack::check_read_error`].
+    fn raw_records(&self) -> Result<impl Iterator<Item = BedGraph<f64>> + '_> {
+        let reader = std::io::BufReader::new(self.spill.reopen()?);
+        Ok(bed_utils::bed::io::Reader::new(reader, None)
+            .into_records()
+            .map_while(|x: Result<BedGraph<f64>, _>| match x {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    let mut error = self.read_error.lock().unwrap();
+                    error.get_or_insert(anyhow::anyhow!("cannot read the spilled coverage: {}", e));
+                    None
+                }
+            })
+            .flat_map(|x| clip_bed(x, &self.chrom_sizes)))
+    }
+
+    /// Return the error, if any, met while reading the records back, so that
+    /// a track truncated by a failed read is not taken for a complete one.
+    fn check_read_error(&self) -> Result<()> {
+        match self.read_error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// The records of the track, in order.
+    fn records(&self) -> Result<impl Iterator<Item = BedGraph<f64>> + '_> {
+        let records = self.raw_records()?.map(|mut x| {
+            x.value = x.value.min(self.max_value) / self.norm_factor;
+            if let Some(transform) = &self.transform {
+                x.value = transform.apply(x.value);
+            }
+            x
+        });
+        Ok(match &self.smoothing {
+            None => itertools::Either::Left(records),
+            Some((left, right, weights)) => itertools::Either::Right(smooth_bedgraph_with(
+                records,
+                *left,
+                *right,
+                weights.as_deref(),
+                &self.chrom_sizes,
+            )),
+        })
+    }
+}
+
+/// Write the records of `track` to `output` as they are read back, updating
+/// `summary` with every record. A failure to read the records back is
+/// returned rather than leaving a truncated track behind.
+fn write_coverage_track(
+    track: &CoverageTrack,
+    format: CoverageOutputFormat,
+    output: &Path,
+    compression: Option<Compression>,
+    compression_level: Option<u32>,
+    mut summary: Option<&mut TrackSummary>,
+) -> Result<()> {
+    let bedgraph = track.records()?.inspect(|x| {
+        if let Some(summary) = summary.as_mut() {
+            summary.update(x);
+        }
+    });
+    match format {
+        CoverageOutputFormat::BedGraph => {
+            let mut writer = utils::open_file_for_write(output, compression, compression_level)?;
+            bedgraph.try_for_each(|x| writeln!(writer, "{}", x))?;
+            writer.finish()?;
+        }
+        CoverageOutputFormat::BigWig => write_bigwig(bedgraph, &track.chrom_sizes, output)?,
+    }
+    track.check_read_error()
+}
+
+/// The insertions of a fragment counted by [`CountingStrategy::PIC`] in bins
+/// of `bin_size` bases: the two insertions of a fragment count once if they
+/// fall into the same bin, as in the count matrices.
+fn paired_insertions(fragment: &Fragment, bin_size: u64) -> SmallVec<[GenomicRange; 2]> {
+    let mut insertions = fragment.to_insertions();
+    if insertions.len() == 2 && insertions[0].start() / bin_size == insertions[1].start() / bin_size {
+        insertions.pop();
+    }
+    insertions
+}
+
+/// A limit on the values of the bins of a coverage track, applied before
+/// normalization so that a few artifactual hotspots do not dominate the
+/// scale of the track.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueCap {
+    /// Cap the values at the given maximum.
+    Max(f64),
+    /// Winsorize the values at the given quantile of the bins with signal.
+    Quantile(f64),
+}
+
+/// The relative width of the buckets of [`BinnedValues`] before any of them
+/// are merged.
+const HISTOGRAM_RELATIVE_WIDTH: f64 = 1e-3;
+
+/// The maximum number of buckets of [`BinnedValues`]. When it is exceeded,
+/// adjacent buckets are merged in pairs, doubling their relative width.
+const HISTOGRAM_MAX_BUCKETS: usize = 4096;
+
+/// A bucket of [`BinnedValues`], ordered as the values it holds. The
+/// positive bucket `k` holds the values in `(gamma^(k-1), gamma^k]`, and the
+/// negative bucket `k` the values whose absolute value is in that range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Bucket {
+    Negative(std::cmp::Reverse<i64>),
+    Zero,
+    Positive(i64),
+}
+
+impl Bucket {
+    fn new(value: f64, gamma: f64) -> Self {
+        let index = || (value.abs().ln() / gamma.ln()).ceil() as i64;
+        if value > 0.0 {
+            Self::Positive(index())
+        } else if value < 0.0 {
+            Self::Negative(std::cmp::Reverse(index()))
+        } else {
+            Self::Zero
+        }
+    }
+
+    /// The bucket holding the values of this bucket once the width of the
+    /// buckets is doubled, i.e., `gamma` is squared.
+    fn merged(self) -> Self {
+        let merge = |k: i64| (k + 1).div_euclid(2);
+        match self {
+            Self::Negative(std::cmp::Reverse(k)) => Self::Negative(std::cmp::Reverse(merge(k))),
+            Self::Zero => Self::Zero,
+            Self::Positive(k) => Self::Positive(merge(k)),
+        }
+    }
+}
+
+/// The number of bins in a bucket of [`BinnedValues`], with the range and the
+/// sums of their values.
+#[derive(Debug, Clone, Copy)]
+struct BucketCount {
+    n_bins: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    sum_sq: f64,
+}
+
+impl BucketCount {
+    fn new(value: f64, n_bins: u64) -> Self {
+        Self {
+            n_bins,
+            min: value,
+            max: value,
+            sum: value * n_bins as f64,
+            sum_sq: value * value * n_bins as f64,
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.n_bins += other.n_bins;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.sum_sq += other.sum_sq;
+    }
+}
+
+/// The distribution of the values of the bins of a track, where each record
+/// counts as many times as the bins it spans. If quantiles are needed, the
+/// values are also counted in a histogram whose buckets have a bounded
+/// relative width, so that its size does not depend on the number of distinct
+/// values. Quantiles are exact as long as each bucket holds a single distinct
+/// value, e.g., for integer counts below a few hundreds.
+#[derive(Debug, Clone)]
+struct BinnedValues {
+    histogram: Option<BTreeMap<Bucket, BucketCount>>,
+    /// The ratio of the bounds of the buckets.
+    gamma: f64,
+    n_bins: u64,
+    sum: f64,
+    sum_sq: f64,
+}
+
+impl BinnedValues {
+    fn new(with_histogram: bool) -> Self {
+        Self {
+            histogram: with_histogram.then(BTreeMap::new),
+            gamma: (1.0 + HISTOGRAM_RELATIVE_WIDTH) / (1.0 - HISTOGRAM_RELATIVE_WIDTH),
+            n_bins: 0,
+            sum: 0.0,
+            sum_sq: 0.0,
+        }
+    }
+
+    fn from_bedgraph(bedgraph: &[BedGraph<f64>], bin_size: u64, with_histogram: bool) -> Self {
+        let mut values = Self::new(with_histogram);
+        bedgraph
+            .iter()
+            .for_each(|x| values.add(x.value, x.len().div_ceil(bin_size)));
+        values
+    }
+
+    fn add(&mut self, value: f64, n_bins: u64) {
+        self.insert(Bucket::new(value, self.gamma), BucketCount::new(value, n_bins));
+    }
+
+    fn insert(&mut self, bucket: Bucket, count: BucketCount) {
+        self.n_bins += count.n_bins;
+        self.sum += count.sum;
+        self.sum_sq += count.sum_sq;
+        if let Some(histogram) = self.histogram.as_mut() {
+            histogram
+                .entry(bucket)
+                .and_modify(|x| x.merge(&count))
+                .or_insert(count);
+            if histogram.len() > HISTOGRAM_MAX_BUCKETS {
+                self.merge_buckets();
+            }
+        }
+    }
+
+    /// Merge adjacent buckets in pairs.
+    fn merge_buckets(&mut self) {
+        let mut merged: BTreeMap<Bucket, BucketCount> = BTreeMap::new();
+        self.histogram
+            .take()
+            .unwrap()
+            .into_iter()
+            .for_each(|(bucket, count)| {
+                merged
+                    .entry(bucket.merged())
+                    .and_modify(|x| x.merge(&count))
+                    .or_insert(count);
+            });
+        self.gamma *= self.gamma;
+        self.histogram = Some(merged);
+    }
+
+    fn histogram(&self) -> &BTreeMap<Bucket, BucketCount> {
+        self.histogram
+            .as_ref()
+            .expect("the histogram of the values is not recorded")
+    }
+
+    /// The `q`-th quantile of the values, or infinity if there are none. The
+    /// largest value of the bucket holding the quantile is returned.
+    fn quantile(&self, q: f64) -> f64 {
+        let rank = (q.clamp(0.0, 1.0) * self.n_bins as f64).ceil().max(1.0) as u64;
+        let mut acc = 0;
+        self.histogram()
+            .values()
+            .find(|x| {
+                acc += x.n_bins;
+                acc >= rank
+            })
+            .map_or(f64::INFINITY, |x| x.max)
+    }
+
+    /// The distribution of the values capped at `max`, which must be returned
+    /// by [`BinnedValues::quantile`], so that no bucket holds values on both
+    /// sides of it.
+    fn capped(&self, max: f64) -> Self {
+        let mut capped = Self {
+            histogram: Some(BTreeMap::new()),
+            gamma: self.gamma,
+            n_bins: 0,
+            sum: 0.0,
+            sum_sq: 0.0,
+        };
+        self.histogram().iter().for_each(|(bucket, x)| {
+            if x.max <= max {
+                capped.insert(*bucket, *x);
             } else {
-                if include_for_norm.map_or(true, |x| x.is_overlapped(&frag))
-                    && !exclude_for_norm.map_or(false, |x| x.is_overlapped(&frag))
-                {
-                    norm_factor += frag.len();
-                }
-                let mut frag = BedGraph::from_bed(&frag, 1.0f64);
-                fit_to_bin(&mut frag, bin_size);
-                Some(frag)
+                capped.add(max, x.n_bins);
             }
-        })
-        .merge_sorted_bedgraph()
-        .flat_map(|x| clip_bed(x, chrom_sizes))
-        .collect();
+        });
+        capped
+    }
+}
 
-    let norm_factor = match normalization {
-        None => 1.0,
-        Some(Normalization::RPKM) => (norm_factor * bin_size) as f64 / 1e9,
-        Some(Normalization::CPM) => norm_factor as f64 / 1e6,
-        Some(Normalization::BPM) => {
-            bedgraph
-                .iter()
-                .map(|x| x.value * (x.len() / bin_size) as f64)
-                .sum::<f64>()
-                / 1e6
-        }
-        Some(Normalization::RPGC) => todo!(),
-    };
+/// The normalizations that transform the values of a track rather than
+/// scaling them, i.e., [`Normalization::Quantile`] and [`Normalization::ZScore`].
+#[derive(Debug, Clone)]
+enum ValueTransform {
+    /// The smallest value of each bucket of the histogram in ascending order,
+    /// with the fraction of the bins whose value is not greater than the
+    /// largest value of the bucket.
+    Quantile(Vec<(f64, f64)>),
+    ZScore { mean: f64, sd: f64 },
+}
 
-    bedgraph.iter_mut().for_each(|x| x.value /= norm_factor);
+impl ValueTransform {
+    /// The transform of `normalization` for a track with the given values,
+    /// which must include their histogram for quantile normalization.
+    fn new(
+        normalization: Normalization,
+        values: &BinnedValues,
+        bin_size: u64,
+        chrom_sizes: &ChromSizes,
+    ) -> Option<Self> {
+        match normalization {
+            Normalization::Quantile => {
+                let mut acc = 0;
+                let cdf = values
+                    .histogram()
+                    .values()
+                    .map(|x| {
+                        acc += x.n_bins;
+                        (x.min, acc as f64 / values.n_bins as f64)
+                    })
+                    .collect();
+                Some(Self::Quantile(cdf))
+            }
+            Normalization::ZScore => {
+                let total = chrom_sizes
+                    .into_iter()
+                    .map(|(_, size)| size.div_ceil(bin_size))
+                    .sum::<u64>() as f64;
+                let mean = values.sum / total;
+                let sd = (values.sum_sq / total - mean * mean).max(0.0).sqrt();
+                Some(Self::ZScore { mean, sd })
+            }
+            _ => None,
+        }
+    }
 
-    if let Some(smooth_base) = smooth_base {
-        let smooth_left = (smooth_base - 1) / 2;
-        let smooth_right = smooth_base - 1 - smooth_left;
-        bedgraph = smooth_bedgraph(bedgraph.into_iter(), smooth_left, smooth_right, chrom_sizes);
+    fn apply(&self, value: f64) -> f64 {
+        match self {
+            // Ties, and values in the same bucket, are given the largest quantile.
+            Self::Quantile(cdf) => {
+                let i = cdf.partition_point(|(x, _)| *x <= value);
+                i.checked_sub(1).map_or(0.0, |i| cdf[i].1)
+            }
+            Self::ZScore { mean, sd } => {
+                if *sd > 0.0 {
+                    (value - mean) / sd
+                } else {
+                    0.0
+                }
+            }
+        }
     }
+}
 
-    bedgraph
+/// Apply the normalizations that transform the values of a track rather than
+/// scaling them, i.e., [`Normalization::Quantile`] and [`Normalization::ZScore`].
+/// Each record counts as many times as the bins it spans.
+fn transform_bedgraph(
+    bedgraph: &mut [BedGraph<f64>],
+    normalization: Normalization,
+    bin_size: u64,
+    chrom_sizes: &ChromSizes,
+) {
+    let with_histogram = matches!(normalization, Normalization::Quantile);
+    let values = BinnedValues::from_bedgraph(bedgraph, bin_size, with_histogram);
+    if let Some(transform) = ValueTransform::new(normalization, &values, bin_size, chrom_sizes) {
+        bedgraph.iter_mut().for_each(|x| x.value = transform.apply(x.value));
+    }
 }
 
 fn smooth_bedgraph<I>(
@@ -427,45 +1821,101 @@ fn smooth_bedgraph<I>(
 where
     I: Iterator<Item = BedGraph<f64>>,
 {
-    let mut key = 0;
-    let mut prev = 0;
-    input
-        .chunk_by(|bed| {
-            let k = if prev > bed.start().saturating_sub(left_window_len) {
-                key
-            } else {
-                key += 1;
-                key
-            };
-            prev = bed.end() + right_window_len;
-            k
-        })
-        .into_iter()
-        .flat_map(|(_, group)| smooth_bedgraph_block(group, left_window_len, right_window_len))
-        .flat_map(|bed| clip_bed(bed, chrom_sizes))
-        .collect()
+    smooth_bedgraph_with(input, left_window_len, right_window_len, None, chrom_sizes).collect()
 }
 
-/// Smooth the values in BedGraph. The input is expected to be overlapping blocks.
-fn smooth_bedgraph_block<I>(
-    data: I,
-    ext_left: u64,
-    ext_right: u64,
-) -> impl Iterator<Item = BedGraph<f64>>
+/// The number of input records buffered by [`smooth_bedgraph_with`] before
+/// the smoothed values that are final are emitted.
+const SMOOTH_CHUNK_SIZE: usize = 256;
+
+/// Smooth sorted, non-overlapping records. `weights` are the weights of the
+/// kernel, see [`SmoothKernel::weights`]; a flat window is used if they are
+/// `None`. The input is processed in chunks: the values up to the point that
+/// the next records can no longer change are emitted after each chunk, so
+/// that the memory usage does not depend on the size of the track.
+fn smooth_bedgraph_with<'a, I>(
+    input: I,
+    left_window_len: u64,
+    right_window_len: u64,
+    weights: Option<&'a [f64]>,
+    chrom_sizes: &'a ChromSizes,
+) -> impl Iterator<Item = BedGraph<f64>> + 'a
 where
-    I: IntoIterator<Item = BedGraph<f64>>,
+    I: Iterator<Item = BedGraph<f64>> + 'a,
 {
+    let mut input = input.peekable();
+    let mut pending: Vec<BedGraph<f64>> = Vec::new();
+    std::iter::from_fn(move || {
+        // A chunk never spans two chromosomes, so that the signal does not
+        // leak into the neighbouring chromosomes.
+        for _ in 0..SMOOTH_CHUNK_SIZE {
+            let next = input.next_if(|bed| pending.first().map_or(true, |x| x.chrom() == bed.chrom()));
+            match next {
+                Some(bed) => pending.extend(smooth_record(&bed, left_window_len, right_window_len, weights)),
+                None => break,
+            }
+        }
+        if pending.is_empty() {
+            return None;
+        }
+        // The remaining records start after the next one, and change the
+        // values from `left_window_len` bases before it.
+        let boundary = input
+            .peek()
+            .filter(|bed| bed.chrom() == pending[0].chrom())
+            .map(|bed| bed.start().saturating_sub(left_window_len));
+        pending.sort_unstable_by(|a, b| a.compare(b));
+        let mut output = Vec::new();
+        std::mem::take(&mut pending)
+            .into_iter()
+            .merge_sorted_bedgraph()
+            .for_each(|mut bed| match boundary {
+                Some(b) if bed.end() > b => {
+                    if bed.start() < b {
+                        let mut head = bed.clone();
+                        head.set_end(b);
+                        output.push(head);
+                        bed.set_start(b);
+                    }
+                    pending.push(bed);
+                }
+                _ => output.push(bed),
+            });
+        Some(output)
+    })
+    .flatten()
+    // Join the records split at the chunk boundaries.
+    .coalesce(|a, b| {
+        if a.chrom() == b.chrom() && a.end() == b.start() && a.value == b.value {
+            let mut a = a;
+            a.set_end(b.end());
+            Ok(a)
+        } else {
+            Err((a, b))
+        }
+    })
+    .flat_map(|bed| clip_bed(bed, chrom_sizes))
+}
+
+/// Spread the value of a record over the window around each of its bases.
+fn smooth_record(
+    bed: &BedGraph<f64>,
+    ext_left: u64,
+    ext_right: u64,
+    weights: Option<&[f64]>,
+) -> Vec<BedGraph<f64>> {
     let n_bases = (ext_left + ext_right + 1) as f64;
-    data.into_iter()
-        .flat_map(|bed| {
-            extend(bed.start(), bed.end(), ext_left, ext_right)
-                .into_iter()
-                .map(move |(s, e, n)| {
-                    BedGraph::new(bed.chrom(), s, e, bed.value * n as f64 / n_bases)
-                })
-        })
-        .sorted_unstable_by(|a, b| a.compare(b))
-        .merge_sorted_bedgraph()
+    let extended: Vec<(u64, u64, f64)> = match weights {
+        None => extend(bed.start(), bed.end(), ext_left, ext_right)
+            .into_iter()
+            .map(|(s, e, n)| (s, e, n as f64 / n_bases))
+            .collect(),
+        Some(w) => extend_weighted(bed.start(), bed.end(), ext_left, ext_right, w),
+    };
+    extended
+        .into_iter()
+        .map(|(s, e, w)| BedGraph::new(bed.chrom(), s, e, bed.value * w))
+        .collect()
 }
 
 fn extend(start: u64, end: u64, ext_left: u64, ext_right: u64) -> Vec<(u64, u64, u64)> {
@@ -493,8 +1943,59 @@ fn extend(start: u64, end: u64, ext_left: u64, ext_right: u64) -> Vec<(u64, u64,
         .collect()
 }
 
-/// Create a bigwig file from BedGraph records.
-fn create_bigwig_from_bedgraph<P, I>(
+/// Like [`extend`], but returns the fraction of the kernel `weights` covered
+/// by `[start, end)` at every position, where `weights[d + ext_right]` is the
+/// weight of the base at offset `d` from the smoothed position.
+fn extend_weighted(
+    start: u64,
+    end: u64,
+    ext_left: u64,
+    ext_right: u64,
+    weights: &[f64],
+) -> Vec<(u64, u64, f64)> {
+    let (s, e) = (start as i64, end as i64);
+    let (l, r) = (ext_left as i64, ext_right as i64);
+    // `prefix[k]` is the sum of the first `k` weights. The offsets `d` such
+    // that `i + d` lies within the interval form the range
+    // `max(-r, s - i)..=min(l, e - 1 - i)`.
+    let prefix: Vec<f64> = std::iter::once(0.0)
+        .chain(weights.iter().scan(0.0, |acc, w| {
+            *acc += w;
+            Some(*acc)
+        }))
+        .collect();
+    let weight_at = |i: i64| -> f64 {
+        let lo = (s - i).max(-r) + r;
+        let hi = (e - i).min(l + 1) + r;
+        if lo < hi {
+            prefix[hi as usize] - prefix[lo as usize]
+        } else {
+            0.0
+        }
+    };
+    let mut result = Vec::new();
+    let mut push = |i: i64| {
+        if i >= 0 {
+            result.push((i as u64, i as u64 + 1, weight_at(i)));
+        }
+    };
+    // Positions whose whole window lies within the interval receive the total weight.
+    let (inner_start, inner_end) = (s + r, e - l);
+    if inner_start < inner_end {
+        (s - l..inner_start).for_each(&mut push);
+        (inner_end..e + r).for_each(&mut push);
+        result.push((inner_start as u64, inner_end as u64, prefix[weights.len()]));
+        result.sort_unstable_by_key(|x| x.0);
+    } else {
+        (s - l..e + r).for_each(push);
+    }
+    result
+}
+
+/// Create a bigwig file from BedGraph records. Records on chromosomes missing
+/// from `chrom_sizes` are handled according to the policy for missing
+/// chromosomes (see [`crate::config::set_missing_chrom_policy`]).
+pub(crate) fn create_bigwig_from_bedgraph<P, I>(
     bedgraph: I,
     chrom_sizes: &ChromSizes,
     filename: P,
@@ -503,9 +2004,28 @@ where
     P: AsRef<Path>,
     I: IntoIterator<Item = BedGraph<f64>>,
 {
-    // write to bigwig file
+    if crate::config::missing_chrom_policy() == MissingChromPolicy::Extend {
+        // The sizes of all chromosomes must be known before writing.
+        let bedgraph: Vec<_> = bedgraph.into_iter().collect();
+        let chrom_sizes = chrom_sizes.resolve_missing(&bedgraph, MissingChromPolicy::Extend)?;
+        write_bigwig(bedgraph, &chrom_sizes, filename)
+    } else {
+        write_bigwig(bedgraph, chrom_sizes, filename)
+    }
+}
+
+/// Write BedGraph records to a bigwig file as they stream in. Records on
+/// chromosomes missing from `chrom_sizes` are skipped, or cause an error if
+/// the policy for missing chromosomes is `Error`.
+fn write_bigwig<P, I>(bedgraph: I, chrom_sizes: &ChromSizes, filename: P) -> Result<()>
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = BedGraph<f64>>,
+{
+    let policy = crate::config::missing_chrom_policy();
+    let missing = std::cell::RefCell::new(IndexSet::new());
     BigWigWrite::create_file(
-        filename.as_ref().to_str().unwrap().to_string(),
+        crate::utils::track_ops::utf8_path(filename.as_ref())?.to_string(),
         chrom_sizes
             .into_iter()
             .map(|(k, v)| (k.to_string(), *v as u32))
@@ -513,20 +2033,42 @@ where
     )?
     .write(
         bigtools::beddata::BedParserStreamingIterator::wrap_iter(
-            bedgraph.into_iter().map(|x| {
-                let val = bigtools::Value {
-                    start: x.start() as u32,
-                    end: x.end() as u32,
-                    value: x.value as f32,
-                };
-                let res: Result<_, bigtools::bed::bedparser::BedValueError> =
-                    Ok((x.chrom().to_string(), val));
-                res
-            }),
+            bedgraph
+                .into_iter()
+                .filter(|x| {
+                    let known = chrom_sizes.get(x.chrom()).is_some();
+                    if !known {
+                        missing.borrow_mut().insert(x.chrom().to_string());
+                    }
+                    known
+                })
+                .map(|x| {
+                    let val = bigtools::Value {
+                        start: x.start() as u32,
+                        end: x.end() as u32,
+                        value: x.value as f32,
+                    };
+                    let res: Result<_, bigtools::bed::bedparser::BedValueError> =
+                        Ok((x.chrom().to_string(), val));
+                    res
+                }),
             false,
         ),
         tokio::runtime::Runtime::new().unwrap(),
     )?;
+
+    let missing = missing.into_inner();
+    if !missing.is_empty() {
+        let names = missing.iter().join(", ");
+        if policy == MissingChromPolicy::Error {
+            std::fs::remove_file(filename.as_ref())?;
+            bail!(
+                "chromosomes not found in the chromosome sizes: {}. Provide their sizes, or set the policy for missing chromosomes to 'skip' or 'extend'",
+                names
+            );
+        }
+        warn!("records on chromosomes missing from the chromosome sizes are skipped: {}", names);
+    }
     Ok(())
 }
 
@@ -557,6 +2099,7 @@ mod tests {
     use crate::preprocessing::PairRead;
 
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_bedgraph1() {
@@ -582,6 +2125,7 @@ mod tests {
             None,
             None,
         )
+        .unwrap()
         .into_iter()
         .map(|x| x.value)
         .collect();
@@ -598,6 +2142,7 @@ mod tests {
             None,
             None,
         )
+        .unwrap()
         .into_iter()
         .map(|x| x.value)
         .collect();
@@ -607,14 +2152,14 @@ mod tests {
 
     #[test]
     fn test_bedgraph2() {
-        let reader = crate::utils::open_file_for_read("test/fragments.tsv.gz");
+        let reader = crate::utils::open_file_for_read("test/fragments.tsv.gz").unwrap();
         let mut reader = bed_utils::bed::io::Reader::new(reader, None);
         let fragments: Vec<Fragment> = reader
             .records::<PairRead>()
             .map(|x| x.unwrap().into())
             .collect();
 
-        let reader = crate::utils::open_file_for_read("test/coverage.bdg.gz");
+        let reader = crate::utils::open_file_for_read("test/coverage.bdg.gz").unwrap();
         let mut reader = bed_utils::bed::io::Reader::new(reader, None);
         let mut expected: Vec<BedGraph<f64>> = reader.records().map(|x| x.unwrap()).collect();
 
@@ -629,7 +2174,8 @@ mod tests {
             None,
             None,
             None,
-        );
+        )
+        .unwrap();
         assert_eq!(
             output,
             expected,
@@ -649,7 +2195,8 @@ mod tests {
             Some(Normalization::BPM),
             None,
             None,
-        );
+        )
+        .unwrap();
         let scale_factor: f64 = expected
             .iter()
             .map(|x| x.len() as f64 * x.value)
@@ -671,6 +2218,361 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_bigwig_golden() {
+        use crate::test_support::{assert_track_matches, Tolerance};
+
+        let reader = crate::utils::open_file_for_read("test/fragments.tsv.gz").unwrap();
+        let mut reader = bed_utils::bed::io::Reader::new(reader, None);
+        let fragments: Vec<Fragment> = reader
+            .records::<PairRead>()
+            .map(|x| x.unwrap().into())
+            .collect();
+        let chrom_sizes: ChromSizes = [("chr1", 248956422), ("chr2", 242193529)]
+            .into_iter()
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+
+        let cases = [
+            ("cpm.bdg.gz", 1, None, Some(Normalization::CPM)),
+            ("rpkm_bin100.bdg.gz", 100, None, Some(Normalization::RPKM)),
+            ("smooth5.bdg.gz", 1, Some(5), None),
+        ];
+        for (golden, bin_size, smooth_base, normalization) in cases {
+            let bedgraph = create_bedgraph_from_sorted_fragments(
+                fragments.clone().into_iter(),
+                &chrom_sizes,
+                bin_size,
+                smooth_base,
+                None,
+                normalization,
+                None,
+                None,
+            )
+            .unwrap();
+            let output = dir.path().join(golden.replace(".bdg.gz", ".bw"));
+            create_bigwig_from_bedgraph(bedgraph, &chrom_sizes, &output).unwrap();
+            assert_track_matches(&output, golden, Tolerance::default());
+        }
+    }
+
+    /// The tracks streamed from the spill by the exporter match the goldens,
+    /// which were made by computing the tracks in memory.
+    #[test]
+    fn test_write_coverage_track() {
+        use crate::test_support::{assert_track_matches, Tolerance};
+
+        let reader = crate::utils::open_file_for_read("test/fragments.tsv.gz").unwrap();
+        let mut reader = bed_utils::bed::io::Reader::new(reader, None);
+        let fragments: Vec<Fragment> = reader
+            .records::<PairRead>()
+            .map(|x| x.unwrap().into())
+            .collect();
+        let chrom_sizes: ChromSizes = [("chr1", 248956422), ("chr2", 242193529)]
+            .into_iter()
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let new_track = |bin_size, smooth_base, normalization| {
+            CoverageTrack::new(
+                fragments.iter().map(|x| (x.clone(), 1.0)),
+                &chrom_sizes,
+                bin_size,
+                smooth_base,
+                SmoothKernel::Flat,
+                None,
+                normalization,
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(dir.path()),
+                &mut OutOfBounds::default(),
+            )
+            .unwrap()
+        };
+
+        let cases = [
+            ("cpm.bdg.gz", 1, None, Some(Normalization::CPM)),
+            ("rpkm_bin100.bdg.gz", 100, None, Some(Normalization::RPKM)),
+            ("smooth5.bdg.gz", 1, Some(5), None),
+        ];
+        for (golden, bin_size, smooth_base, normalization) in cases {
+            let track = new_track(bin_size, smooth_base, normalization);
+            let bigwig = dir.path().join(golden.replace(".bdg.gz", ".bw"));
+            let bedgraph = dir.path().join(golden);
+            let mut summary = TrackSummary::default();
+            let format = CoverageOutputFormat::BigWig;
+            write_coverage_track(&track, format, &bigwig, None, None, None).unwrap();
+            let format = CoverageOutputFormat::BedGraph;
+            let compression = Some(Compression::Gzip);
+            write_coverage_track(&track, format, &bedgraph, compression, None, Some(&mut summary))
+                .unwrap();
+            assert_track_matches(&bigwig, golden, Tolerance::default());
+            assert_track_matches(&bedgraph, golden, Tolerance::default());
+        }
+
+        // A spill that cannot be read back is an error, not a truncated track.
+        let track = new_track(1, None, None);
+        std::fs::write(track.spill.path(), "chr1\t0\t10\t1\nchr1\tten\t20\t2\n").unwrap();
+        let output = dir.path().join("corrupted.bdg");
+        let format = CoverageOutputFormat::BedGraph;
+        let err = write_coverage_track(&track, format, &output, None, None, None).unwrap_err();
+        assert!(err.to_string().contains("cannot read the spilled coverage"), "{}", err);
+        assert!(track.check_read_error().is_ok());
+    }
+
+    #[test]
+    fn test_group_rows() {
+        let group_by = vec!["a", "a", "b", "b", "a", "c", "c"];
+        let rows = group_rows(&group_by);
+        assert_eq!(rows["a"], vec![(0, 2), (4, 5)]);
+        assert_eq!(rows["b"], vec![(2, 4)]);
+        assert_eq!(rows["c"], vec![(5, 7)]);
+    }
+
+    #[test]
+    fn test_paired_insertions() {
+        let frag: Fragment = PairRead::new("chr1", 12, 18).into();
+        let starts = |bin_size| {
+            paired_insertions(&frag, bin_size).iter().map(|x| x.start()).collect::<Vec<_>>()
+        };
+        assert_eq!(starts(1), vec![12, 17]);
+        assert_eq!(starts(5), vec![12, 17]);
+        assert_eq!(starts(10), vec![12]);
+    }
+
+    #[test]
+    fn test_group_labels() {
+        use polars::prelude::Column;
+
+        let obs = DataFrame::new(vec![
+            Column::new("cell_type".into(), [Some("B/T"), Some("NK"), None, Some("")]),
+            Column::new("batch".into(), [Some("1"), None, Some("2"), Some("NA")]),
+        ])
+        .unwrap();
+        let labels = |keys: &[&str], missing| group_labels(&obs, keys, missing);
+        assert!(labels(&["cell_type"], MissingGroup::Error).is_err());
+        assert!(labels(&["sample"], MissingGroup::Keep).is_err());
+
+        // Missing values do not clash with the values "NA".
+        let kept = labels(&["cell_type", "batch"], MissingGroup::Keep).unwrap();
+        let expected = ["B+T/1", "NK/NA_", "NA/2", "/NA"].map(|x| Some(x.to_string()));
+        assert_eq!(kept.labels, expected);
+        assert_eq!(kept.values["NK/NA_"], vec![Some("NK".to_string()), None]);
+        assert_eq!(kept.values["/NA"], vec![Some("".to_string()), Some("NA".to_string())]);
+        let (group_by, selections) = kept.select(None);
+        assert_eq!(group_by, vec!["B+T/1", "NK/NA_", "NA/2", "/NA"]);
+        assert!(selections.is_none());
+
+        // The cells with a missing value are left out, but not the group "".
+        let dropped = labels(&["cell_type"], MissingGroup::Drop).unwrap();
+        let expected = [Some("B+T"), Some("NK"), None, Some("")].map(|x| x.map(String::from));
+        assert_eq!(dropped.labels, expected);
+        let (group_by, selections) = dropped.select(None);
+        assert_eq!(group_by, vec!["B+T", "NK", "_", ""]);
+        assert_eq!(selections, Some(["B+T", "NK", ""].into_iter().collect()));
+        let (_, selections) = dropped.select(Some(["", "_", "T"].into_iter().collect()));
+        assert_eq!(selections, Some(["", "T"].into_iter().collect()));
+    }
+
+    #[test]
+    fn test_group_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = group_path(dir.path(), "p_", "B cell/control", ".bw").unwrap();
+        assert_eq!(path, dir.path().join("B cell").join("p_control.bw"));
+        assert!(dir.path().join("B cell").is_dir());
+        assert_eq!(group_path(dir.path(), "", "NK", ".bw").unwrap(), dir.path().join("NK.bw"));
+        assert!(group_path(dir.path(), "", "B cell//control", ".bw").is_err());
+        assert!(group_path(dir.path(), "", "../control", ".bw").is_err());
+    }
+
+    #[test]
+    fn test_transform_bedgraph() {
+        let chrom_sizes: ChromSizes = [("chr1", 100)].into_iter().collect();
+        let input = vec![
+            BedGraph::new("chr1", 0, 20, 2.0),
+            BedGraph::new("chr1", 20, 30, 1.0),
+            BedGraph::new("chr1", 50, 60, 4.0),
+        ];
+
+        let mut output = input.clone();
+        transform_bedgraph(&mut output, Normalization::Quantile, 10, &chrom_sizes);
+        assert_eq!(
+            output.iter().map(|x| x.value).collect::<Vec<_>>(),
+            vec![0.75, 0.25, 1.0]
+        );
+
+        let mut output = input.clone();
+        transform_bedgraph(&mut output, Normalization::ZScore, 10, &chrom_sizes);
+        // Mean 0.9 and standard deviation 1.3 over the 10 bins of chr1.
+        output
+            .iter()
+            .zip([2.0, 1.0, 4.0])
+            .for_each(|(x, v)| assert!((x.value - (v - 0.9) / 1.3).abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_out_of_bounds() {
+        let fragments: Vec<Fragment> = vec![
+            PairRead::new("chr1", 10, 20).into(),
+            PairRead::new("chr1", 40, 60).into(),
+            PairRead::new("chr1", 55, 65).into(),
+        ];
+        let genome: ChromSizes = [("chr1", 50)].into_iter().collect();
+        let mut out_of_bounds = OutOfBounds::default();
+        let output = create_weighted_bedgraph_from_sorted_fragments(
+            fragments.into_iter().map(|x| (x, 1.0)),
+            &genome,
+            1,
+            None,
+            SmoothKernel::Flat,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            &mut out_of_bounds,
+        )
+        .unwrap()
+        .0;
+        assert_eq!(out_of_bounds, OutOfBounds { clipped: 1, dropped: 1 });
+        assert_eq!(output.last().unwrap().end(), 50);
+    }
+
+    #[test]
+    fn test_spike_in() {
+        let fragments: Vec<Fragment> = vec![
+            PairRead::new("chr1", 0, 10).into(),
+            PairRead::new("chr1", 0, 10).into(),
+            PairRead::new("dm6_chr2L", 0, 10).into(),
+            PairRead::new("dm6_chr3R", 0, 10).into(),
+        ];
+        let genome: ChromSizes = [("chr1", 50), ("dm6_chr2L", 50), ("dm6_chr3R", 50)]
+            .into_iter()
+            .collect();
+        let spike_in = SpikeIn::new(vec!["dm6_*".to_string()], 10.0);
+        let (output, count) = create_weighted_bedgraph_from_sorted_fragments(
+            fragments.into_iter().map(|x| (x, 1.0)),
+            &genome,
+            10,
+            None,
+            SmoothKernel::Flat,
+            None,
+            Some(Normalization::SpikeIn),
+            None,
+            None,
+            None,
+            Some(&spike_in),
+            None,
+            &mut OutOfBounds::default(),
+        )
+        .unwrap();
+        assert_eq!(count, 2.0);
+        assert_eq!(output.len(), 1);
+        assert_eq!((output[0].chrom(), output[0].value), ("chr1", 10.0));
+    }
+
+    #[test]
+    fn test_bpm_rpgc() {
+        let fragments: Vec<Fragment> = vec![
+            PairRead::new("chr1", 0, 10).into(),
+            PairRead::new("chr1", 40, 45).into(),
+        ];
+        let genome: ChromSizes = [("chr1", 45)].into_iter().collect();
+        let values = |normalization, effective_genome_size| {
+            create_weighted_bedgraph_from_sorted_fragments(
+                fragments.clone().into_iter().map(|x| (x, 1.0)),
+                &genome,
+                10,
+                None,
+                SmoothKernel::Flat,
+                None,
+                Some(normalization),
+                None,
+                None,
+                None,
+                None,
+                effective_genome_size,
+                &mut OutOfBounds::default(),
+            )
+            .unwrap()
+            .0
+            .into_iter()
+            .map(|x| x.value)
+            .collect::<Vec<_>>()
+        };
+        let assert_all = |values: Vec<f64>, expected: f64| {
+            assert_eq!(values.len(), 2);
+            values.iter().for_each(|x| assert!((x - expected).abs() < 1e-6));
+        };
+
+        // The clipped last bin counts as a full bin.
+        assert_all(values(Normalization::BPM, None), 5e5);
+        // 15 bases sequenced over a genome of 45 or 15 bases.
+        assert_all(values(Normalization::RPGC, None), 3.0);
+        assert_all(values(Normalization::RPGC, Some(15)), 1.0);
+    }
+
+    #[test]
+    fn test_cap_bedgraph() {
+        let input = vec![
+            BedGraph::new("chr1", 0, 20, 2.0),
+            BedGraph::new("chr1", 20, 30, 1.0),
+            BedGraph::new("chr1", 50, 60, 4.0),
+        ];
+        let genome: ChromSizes = [("chr1", 100)].into_iter().collect();
+        let values = |cap| {
+            create_weighted_bedgraph_from_sorted_fragments(
+                input
+                    .iter()
+                    .map(|x| (GenomicRange::new(x.chrom(), x.start(), x.end()), x.value)),
+                &genome,
+                10,
+                None,
+                SmoothKernel::Flat,
+                None,
+                None,
+                Some(cap),
+                None,
+                None,
+                None,
+                None,
+                &mut OutOfBounds::default(),
+            )
+            .unwrap()
+            .0
+            .into_iter()
+            .map(|x| x.value)
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(values(ValueCap::Max(1.5)), vec![1.5, 1.0, 1.5]);
+        // The first record spans two of the four bins.
+        assert_eq!(values(ValueCap::Quantile(0.75)), vec![2.0, 1.0, 2.0]);
+        assert_eq!(values(ValueCap::Quantile(1.0)), vec![2.0, 1.0, 4.0]);
+    }
+
+    #[test]
+    fn test_binned_values() {
+        let value = |i| 1.0001f64.powi(i);
+        let mut values = BinnedValues::new(true);
+        (0..200_000).for_each(|i| values.add(value(i), 1));
+        assert!(values.histogram().len() <= HISTOGRAM_MAX_BUCKETS);
+        assert_eq!(values.n_bins, 200_000);
+        // The buckets are merged twice, so they are about 0.8% wide.
+        let median = values.quantile(0.5);
+        assert!(median >= value(99_999) && median / value(99_999) < 1.01);
+        assert_eq!(values.quantile(1.0), value(199_999));
+
+        let capped = values.capped(median);
+        assert_eq!(capped.n_bins, 200_000);
+        assert_eq!(capped.quantile(1.0), median);
+        let sum: f64 = (0..200_000).map(|i| value(i).min(median)).sum();
+        assert!((capped.sum / sum - 1.0).abs() < 1e-9);
+    }
+
     #[test]
     fn test_extend() {
         assert_eq!(
@@ -777,5 +2679,163 @@ mod tests {
             ],
             200,
         );
+
+        // The signal does not leak into the neighbouring chromosomes.
+        let genome: ChromSizes = [("chr1", 100), ("chr2", 100)].into_iter().collect();
+        let output = smooth_bedgraph(
+            vec![
+                BedGraph::new("chr1", 95, 100, 1.0),
+                BedGraph::new("chr2", 0, 5, 1.0),
+            ]
+            .into_iter(),
+            10,
+            10,
+            &genome,
+        );
+        assert_eq!(output.first().unwrap().start(), 85);
+        assert_eq!(output.last().unwrap().end(), 15);
+        output.iter().for_each(|x| assert!(x.end() <= 100 && x.value < 1.0));
+        output
+            .windows(2)
+            .filter(|x| x[0].chrom() == x[1].chrom())
+            .for_each(|x| assert!(x[0].end() <= x[1].start()));
+
+        // Long inputs are smoothed in chunks, with the same result.
+        let input: Vec<_> = (0..900u64)
+            .map(|i| BedGraph::new("chr1", i * 10, i * 10 + 5, (i % 7 + 1) as f64))
+            .collect();
+        let mut signal = [0.0f64; 10000];
+        input
+            .iter()
+            .for_each(|bed| signal[bed.start() as usize..bed.end() as usize].fill(bed.value));
+        let mut actual = [0.0f64; 10000];
+        smooth_bedgraph(input.into_iter(), 20, 20, &[("chr1", 10000)].into_iter().collect())
+            .iter()
+            .for_each(|bed| actual[bed.start() as usize..bed.end() as usize].fill(bed.value));
+        actual
+            .iter()
+            .zip(moving_average(20, &signal))
+            .for_each(|(a, b)| assert!((a - b).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_smoothing_kernel() {
+        // A flat kernel given as weights matches the moving average.
+        let flat = vec![0.2; 5];
+        for (start, end) in [(10, 11), (10, 13), (10, 30), (1, 3)] {
+            let expected: Vec<(u64, u64, f64)> = extend(start, end, 2, 2)
+                .into_iter()
+                .flat_map(|(s, e, n)| (s..e).map(move |i| (i, i + 1, n as f64 / 5.0)))
+                .collect();
+            let actual: Vec<(u64, u64, f64)> = extend_weighted(start, end, 2, 2, &flat)
+                .into_iter()
+                .flat_map(|(s, e, w)| (s..e).map(move |i| (i, i + 1, w)))
+                .collect();
+            assert_eq!(actual.len(), expected.len());
+            actual.iter().zip(&expected).for_each(|(a, b)| {
+                assert_eq!((a.0, a.1), (b.0, b.1));
+                assert!((a.2 - b.2).abs() < 1e-12);
+            });
+        }
+
+        let genome: ChromSizes = [("chr1", 1000)].into_iter().collect();
+        let input = vec![BedGraph::new("chr1", 100, 110, 3.0), BedGraph::new("chr1", 500, 501, 1.0)];
+        for kernel in [SmoothKernel::Gaussian, SmoothKernel::Epanechnikov] {
+            let weights = kernel.weights(5, 5).unwrap();
+            assert!((weights.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+            assert!(weights[5] > weights[0] && weights[0] > 0.0);
+            assert_eq!(weights[0], weights[10]);
+
+            let output: Vec<_> =
+                smooth_bedgraph_with(input.clone().into_iter(), 5, 5, Some(weights.as_slice()), &genome)
+                    .collect();
+            // The signal is preserved and the peaks are flattened.
+            let total: f64 = output.iter().map(|x| x.value * x.len() as f64).sum();
+            assert!((total - 31.0).abs() < 1e-9);
+            let max = output.iter().map(|x| x.value).fold(0.0, f64::max);
+            assert!(max < 3.0);
+            output.windows(2).for_each(|x| assert!(x[0].end() <= x[1].start()));
+            assert!(output.iter().all(|x| x.start() >= 95 && x.end() <= 506));
+        }
+        assert!(SmoothKernel::try_from("box").is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_fit_to_bin(start in 0u64..1_000_000, len in 1u64..10_000, bin_size in 1u64..1000) {
+            let mut bed = GenomicRange::new("chr1", start, start + len);
+            fit_to_bin(&mut bed, bin_size);
+            prop_assert_eq!(bed.start() % bin_size, 0);
+            prop_assert_eq!(bed.end() % bin_size, 0);
+            prop_assert!(bed.start() <= start && bed.end() >= start + len);
+            prop_assert!(bed.start() + bin_size > start && bed.end() < start + len + bin_size);
+        }
+
+        #[test]
+        fn prop_clip_bed(start in 0u64..2000, len in 1u64..2000, size in 1u64..2000) {
+            let genome: ChromSizes = [("chr1", size)].into_iter().collect();
+            let bed = GenomicRange::new("chr1", start, start + len);
+            match clip_bed(bed, &genome) {
+                None => prop_assert!(start >= size),
+                Some(x) => {
+                    prop_assert!(x.start() < x.end());
+                    prop_assert!(x.end() <= size);
+                    prop_assert_eq!(x.end(), (start + len).min(size));
+                }
+            }
+            prop_assert!(clip_bed(GenomicRange::new("chrX", start, start + len), &genome).is_none());
+        }
+
+        /// The merged bedgraph is sorted, non-overlapping, aligned to bins,
+        /// within chromosome boundaries, and preserves the total coverage.
+        #[test]
+        fn prop_bedgraph_coverage(
+            frags in prop::collection::vec((0u64..5000, 1u64..500), 0..200),
+            bin_size in 1u64..50,
+            chrom_size in 1u64..6000,
+            smooth_base in prop::option::of(0u64..20),
+        ) {
+            let genome: ChromSizes = [("chr1", chrom_size)].into_iter().collect();
+            let mut fragments: Vec<Fragment> = frags
+                .iter()
+                .map(|(s, l)| PairRead::new("chr1", *s, s + l).into())
+                .collect();
+            fragments.sort_by(|a, b| a.compare(b));
+            let output = create_bedgraph_from_sorted_fragments(
+                fragments.clone().into_iter(),
+                &genome,
+                bin_size,
+                smooth_base,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            output.windows(2).try_for_each(|x| {
+                prop_assert!(x[0].end() <= x[1].start());
+                Ok(())
+            })?;
+            for x in output.iter() {
+                prop_assert!(x.start() < x.end() && x.end() <= chrom_size);
+                if smooth_base.is_none() {
+                    prop_assert_eq!(x.start() % bin_size, 0);
+                }
+            }
+
+            if smooth_base.is_none() {
+                let expected: f64 = fragments
+                    .into_iter()
+                    .filter_map(|x| {
+                        let mut x = BedGraph::from_bed(&x, 1.0f64);
+                        fit_to_bin(&mut x, bin_size);
+                        let end = x.end().min(chrom_size);
+                        if x.start() < end { Some((end - x.start()) as f64) } else { None }
+                    })
+                    .sum();
+                let actual: f64 = output.iter().map(|x| x.value * x.len() as f64).sum();
+                prop_assert!((expected - actual).abs() < 1e-6);
+            }
+        }
     }
 }
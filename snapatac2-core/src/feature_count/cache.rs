@@ -0,0 +1,199 @@
+//! Pre-aggregated genome-wide coverage.
+//!
+//! Many quick summaries, e.g., the number of fragments per chromosome or the
+//! coverage of a locus, only need the fragments at a coarse resolution, yet
+//! computing them from the raw fragments requires a full scan of `.obsm`.
+//! The coverage cache stores the number of insertions of every cell in
+//! fixed-size bins of the genome in `.obsm["coverage_cache"]`. It is built
+//! once by [`build_coverage_cache`] and records the checksum of the fragments
+//! it was computed from, so that [`read_coverage_cache`] ignores it once the
+//! fragments have changed. The checksum is computed from the fragments when
+//! the cache is read, which takes a pass over the raw fragments but no
+//! decoding into genomic coordinates.
+
+use anndata::data::CsrNonCanonical;
+use anndata::{AnnDataOp, ArrayData, AxisArraysOp, ElemCollectionOp};
+use anyhow::{ensure, Context, Result};
+use log::warn;
+use nalgebra_sparse::CsrMatrix;
+use serde::{Deserialize, Serialize};
+
+use super::{CompressedFragmentIter, SnapData};
+use crate::genome::GenomeBaseIndex;
+use crate::recovery::{self, Element};
+
+/// Key for storing the coverage cache in the `.obsm` matrix, and its metadata
+/// in `.uns`.
+pub const COVERAGE_CACHE: &str = "coverage_cache";
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// Checksum of the fragments stored in `.obsm`, computed one cell at a time.
+/// It depends on the fragments of each cell and on the order of the cells,
+/// but not on the order of the fragments within a cell, on how the cells are
+/// chunked, or on the storage format, as version 2 matrices are decoded
+/// before they are hashed.
+#[derive(Debug, Clone)]
+pub struct FragmentChecksum(u64);
+
+impl Default for FragmentChecksum {
+    fn default() -> Self {
+        Self(FNV_OFFSET)
+    }
+}
+
+impl FragmentChecksum {
+    /// Add the fragments of the next cell, given as (column, value) pairs of
+    /// the version 1 matrix.
+    pub fn update<V: Copy + Into<i64>>(&mut self, row: impl IntoIterator<Item = (usize, V)>) {
+        let (n, row_hash) = row.into_iter().fold((0u64, 0u64), |(n, acc), (col, val)| {
+            let h = fnv1a(fnv1a(FNV_OFFSET, &(col as u64).to_le_bytes()), &val.into().to_le_bytes());
+            (n + 1, acc.wrapping_add(h))
+        });
+        self.0 = fnv1a(fnv1a(self.0, &n.to_le_bytes()), &row_hash.to_le_bytes());
+    }
+
+    /// Add all rows of a version 1 matrix.
+    pub fn update_csr<V: Copy + Into<i64>>(&mut self, mat: &CsrNonCanonical<V>) {
+        let (offsets, indices, values) = (mat.row_offsets(), mat.col_indices(), mat.values());
+        offsets.windows(2).for_each(|w| {
+            self.update((w[0]..w[1]).map(|k| (indices[k], values[k])));
+        });
+    }
+
+    pub fn finish(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+/// The checksum of the fragments stored in `adata`, computed from the
+/// fragments, as a checksum stored alongside them would not follow their
+/// changes made by other programs.
+pub fn fragment_checksum<A: SnapData>(adata: &A) -> Result<String> {
+    let mut checksum = FragmentChecksum::default();
+    match adata.get_fragment_iter(adata.fragment_chunk_size()?)?.into_inner() {
+        CompressedFragmentIter::FragmentSingle(iter) => {
+            iter.for_each(|(mat, _, _)| checksum.update_csr(&mat))
+        }
+        CompressedFragmentIter::FragmentPaired(iter) => {
+            iter.for_each(|(mat, _, _)| checksum.update_csr(&mat))
+        }
+    }
+    Ok(checksum.finish())
+}
+
+/// Metadata of the coverage cache, stored as JSON in `.uns["coverage_cache"]`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoverageCacheInfo {
+    pub bin_size: usize,
+    pub n_obs: usize,
+    /// Whether the fragments are paired-end, in which case every fragment
+    /// contributes two insertions.
+    pub paired: bool,
+    pub checksum: String,
+}
+
+pub struct CoverageCache {
+    pub info: CoverageCacheInfo,
+    /// Index of the bins, i.e., the columns of the cache.
+    pub index: GenomeBaseIndex,
+    pub data: Box<dyn ExactSizeIterator<Item = (CsrMatrix<u32>, usize, usize)>>,
+}
+
+/// Count the insertions of every cell in bins of `bin_size` bases and store
+/// the result as the coverage cache of `adata`, replacing any existing cache.
+pub fn build_coverage_cache<A: SnapData>(adata: &A, bin_size: usize) -> Result<CoverageCacheInfo> {
+    ensure!(bin_size > 0, "bin size must be positive");
+    let checksum = fragment_checksum(adata)?;
+    let fragments = adata.get_fragment_iter(adata.fragment_chunk_size()?)?;
+    let paired = fragments.is_paired();
+    let chunks = fragments.with_resolution(bin_size).into_array_iter();
+    recovery::guarded(adata, Element::Obsm(COVERAGE_CACHE.to_string()), || {
+        adata.obsm().add_iter(COVERAGE_CACHE, chunks.map(|(mat, _, _)| ArrayData::from(mat)))
+    })?;
+    let info = CoverageCacheInfo {
+        bin_size,
+        n_obs: adata.n_obs(),
+        paired,
+        checksum,
+    };
+    adata.uns().add(COVERAGE_CACHE, serde_json::to_string(&info)?)?;
+    Ok(info)
+}
+
+/// Metadata of the coverage cache of `adata`, if a valid cache exists. A cache
+/// built from different fragments is ignored with a warning.
+pub fn coverage_cache_info<A: SnapData>(adata: &A) -> Result<Option<CoverageCacheInfo>> {
+    if !adata.obsm().keys().iter().any(|x| x == COVERAGE_CACHE) {
+        return Ok(None);
+    }
+    let info: CoverageCacheInfo = match adata.uns().get_item::<String>(COVERAGE_CACHE)? {
+        Some(x) => serde_json::from_str(&x)
+            .with_context(|| format!("invalid metadata in '.uns[\"{}\"]'", COVERAGE_CACHE))?,
+        None => return Ok(None),
+    };
+    if info.n_obs != adata.n_obs() || info.checksum != fragment_checksum(adata)? {
+        warn!("The coverage cache is outdated and will be ignored. Run `build_coverage_cache` to rebuild it.");
+        return Ok(None);
+    }
+    Ok(Some(info))
+}
+
+/// Read the coverage cache of `adata`, if a valid cache exists.
+pub fn read_coverage_cache<A: SnapData>(adata: &A, chunk_size: usize) -> Result<Option<CoverageCache>> {
+    let info = match coverage_cache_info(adata)? {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+    let data = adata
+        .obsm()
+        .get_item_iter::<CsrMatrix<u32>>(COVERAGE_CACHE, chunk_size)
+        .with_context(|| format!("key '{}' is not present in the '.obsm'", COVERAGE_CACHE))?;
+    let index = GenomeBaseIndex::new(&adata.read_chrom_sizes()?).with_step(info.bin_size);
+    Ok(Some(CoverageCache {
+        info,
+        index,
+        data: Box::new(data),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        let mat = CsrNonCanonical::from_csr_data(
+            3,
+            100,
+            vec![0, 2, 2, 3],
+            vec![10, 5, 40],
+            vec![30i32, -20, 50],
+        );
+        let mut a = FragmentChecksum::default();
+        a.update_csr(&mat);
+
+        // Independent of the order within a cell and of the chunking.
+        let mut b = FragmentChecksum::default();
+        b.update(vec![(5, -20i32), (10, 30)]);
+        b.update(Vec::<(usize, i32)>::new());
+        b.update(vec![(40, 50i32)]);
+        assert_eq!(a.finish(), b.finish());
+
+        // Moving a fragment to another cell changes the checksum.
+        let mut c = FragmentChecksum::default();
+        c.update(vec![(10, 30i32)]);
+        c.update(vec![(5, -20i32)]);
+        c.update(vec![(40, 50i32)]);
+        assert_ne!(a.finish(), c.finish());
+    }
+}
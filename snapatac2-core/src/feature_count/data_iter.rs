@@ -1,6 +1,10 @@
+use crate::feature_count::storage::{self, decode_paired, decode_single};
 use crate::feature_count::{CountingStrategy, FeatureCounter};
 use crate::genome::{ChromSizes, GenomeBaseIndex};
-use crate::preprocessing::{Fragment, PairRead, SingleRead, SummaryType};
+use crate::preprocessing::{
+    Fragment, PairRead, SingleRead, SummaryType, LONG_READ_MAX_FRAGMENT_SIZE,
+    LONG_READ_MIN_FRAGMENT_SIZE,
+};
 
 use anndata::backend::{DataType, ScalarType};
 use anndata::data::{ArrayConvert, DynCsrMatrix, Element};
@@ -28,6 +32,47 @@ pub enum CompressedFragmentIter {
     FragmentPaired(Box<dyn Sync + ExactSizeIterator<Item = (CsrNonCanonical<u32>, usize, usize)>>),
 }
 
+/// Iterator over fragment data stored in the version 2 format, see
+/// [`super::storage`]. The fragments are decoded directly from these matrices.
+pub enum EncodedFragmentIter {
+    FragmentSingle(Box<dyn Sync + ExactSizeIterator<Item = (CsrNonCanonical<u64>, usize, usize)>>),
+    FragmentPaired(Box<dyn Sync + ExactSizeIterator<Item = (CsrNonCanonical<u64>, usize, usize)>>),
+}
+
+/// Fragment data in either storage format.
+pub enum FragmentChunks {
+    V1(CompressedFragmentIter),
+    V2(EncodedFragmentIter),
+}
+
+impl From<CompressedFragmentIter> for FragmentChunks {
+    fn from(x: CompressedFragmentIter) -> Self {
+        FragmentChunks::V1(x)
+    }
+}
+
+impl From<EncodedFragmentIter> for FragmentChunks {
+    fn from(x: EncodedFragmentIter) -> Self {
+        FragmentChunks::V2(x)
+    }
+}
+
+impl FragmentChunks {
+    /// Convert the chunks to the version 1 format.
+    fn into_v1(self, index: &GenomeBaseIndex) -> CompressedFragmentIter {
+        let chrom_sizes: ChromSizes = index.chrom_sizes().map(|(chr, size)| (chr.clone(), size)).collect();
+        match self {
+            FragmentChunks::V1(x) => x,
+            FragmentChunks::V2(EncodedFragmentIter::FragmentSingle(iter)) => {
+                CompressedFragmentIter::FragmentSingle(Box::new(decode_single(iter, &chrom_sizes)))
+            }
+            FragmentChunks::V2(EncodedFragmentIter::FragmentPaired(iter)) => {
+                CompressedFragmentIter::FragmentPaired(Box::new(decode_paired(iter, &chrom_sizes)))
+            }
+        }
+    }
+}
+
 /// Helper function to convert single-end fragment data to raw fragments.
 fn single_to_fragments(
     index: GenomeBaseIndex,
@@ -38,45 +83,47 @@ fn single_to_fragments(
         let row_offsets = mat.row_offsets();
         let col_indices = mat.col_indices();
         let values = mat.values();
-        let beds = (0..(row_offsets.len() - 1))
-            .into_par_iter()
-            .map(|i| {
-                let row_start = row_offsets[i];
-                let row_end = row_offsets[i + 1];
-                (row_start..row_end)
-                    .flat_map(|j| {
-                        let (chrom, pos_5p) = index.get_position(col_indices[j]);
-                        if exclude_chroms.contains(chrom) {
-                            None
-                        } else {
-                            let size = values[j] as i64;
-                            let barcode = None;
-                            let count = 1;
-                            let start;
-                            let end;
-                            let strand;
-                            if size > 0 {
-                                start = pos_5p;
-                                end = start.checked_add_signed(size).unwrap();
-                                strand = Strand::Forward;
+        let beds = crate::config::install(None, || {
+            (0..(row_offsets.len() - 1))
+                .into_par_iter()
+                .map(|i| {
+                    let row_start = row_offsets[i];
+                    let row_end = row_offsets[i + 1];
+                    (row_start..row_end)
+                        .flat_map(|j| {
+                            let (chrom, pos_5p) = index.get_position(col_indices[j]);
+                            if exclude_chroms.contains(chrom) {
+                                None
                             } else {
-                                end = pos_5p + 1;
-                                start = end.checked_add_signed(size).unwrap();
-                                strand = Strand::Reverse;
+                                let size = values[j] as i64;
+                                let barcode = None;
+                                let count = 1;
+                                let start;
+                                let end;
+                                let strand;
+                                if size > 0 {
+                                    start = pos_5p;
+                                    end = start.checked_add_signed(size).unwrap();
+                                    strand = Strand::Forward;
+                                } else {
+                                    end = pos_5p + 1;
+                                    start = end.checked_add_signed(size).unwrap();
+                                    strand = Strand::Reverse;
+                                }
+                                Some(SingleRead {
+                                    chrom: chrom.to_string(),
+                                    start,
+                                    end,
+                                    barcode,
+                                    count,
+                                    strand,
+                                }.into())
                             }
-                            Some(SingleRead {
-                                chrom: chrom.to_string(),
-                                start,
-                                end,
-                                barcode,
-                                count,
-                                strand,
-                            }.into())
-                        }
-                    })
-                    .collect()
-            })
-            .collect();
+                        })
+                        .collect()
+                })
+                .collect()
+        });
         (beds, a, b)
     })
 }
@@ -93,47 +140,186 @@ fn pair_to_fragments(
         let row_offsets = mat.row_offsets();
         let col_indices = mat.col_indices();
         let values = mat.values();
-        let beds = (0..(row_offsets.len() - 1))
-            .into_par_iter()
-            .map(|i| {
-                let row_start = row_offsets[i];
-                let row_end = row_offsets[i + 1];
-                (row_start..row_end)
-                    .flat_map(|j| {
-                        let size = values[j] as u64;
-                        let (chrom, start) = index.get_position(col_indices[j]);
-                        if exclude_chroms.contains(chrom)
-                            || min_fragment_size.map_or(false, |x| size < x)
-                            || max_fragment_size.map_or(false, |x| size > x)
-                        {
-                            None
-                        } else {
-                            Some(PairRead {
+        let beds = crate::config::install(None, || {
+            (0..(row_offsets.len() - 1))
+                .into_par_iter()
+                .map(|i| {
+                    let row_start = row_offsets[i];
+                    let row_end = row_offsets[i + 1];
+                    (row_start..row_end)
+                        .flat_map(|j| {
+                            let size = values[j] as u64;
+                            let (chrom, start) = index.get_position(col_indices[j]);
+                            if exclude_chroms.contains(chrom)
+                                || min_fragment_size.map_or(false, |x| size < x)
+                                || max_fragment_size.map_or(false, |x| size > x)
+                            {
+                                None
+                            } else {
+                                Some(PairRead {
+                                    chrom: chrom.to_string(),
+                                    start,
+                                    end: start + size,
+                                    barcode: None,
+                                    count: 1,
+                                    strand: None,
+                                }.into())
+                            }
+                        })
+                        .collect()
+                })
+                .collect()
+        });
+        (beds, a, b)
+    })
+}
+
+/// Decode single-end fragments stored in the version 2 format into raw
+/// fragments. Fragments with an invalid chromosome index, which are reported
+/// by the validation, are skipped.
+fn encoded_single_to_fragments(
+    index: GenomeBaseIndex,
+    exclude_chroms: HashSet<String>,
+    data_iter: impl ExactSizeIterator<Item = (CsrNonCanonical<u64>, usize, usize)>,
+) -> impl ExactSizeIterator<Item = (Vec<Vec<Fragment>>, usize, usize)> {
+    data_iter.map(move |(mat, a, b)| {
+        let row_offsets = mat.row_offsets();
+        let col_indices = mat.col_indices();
+        let values = mat.values();
+        let beds = crate::config::install(None, || {
+            (0..(row_offsets.len() - 1))
+                .into_par_iter()
+                .map(|i| {
+                    let range = row_offsets[i]..row_offsets[i + 1];
+                    storage::decode_row(&col_indices[range.clone()], &values[range])
+                        .filter_map(|(c, pos_5p, size)| {
+                            let chrom = index.chroms.get_index(c)?;
+                            if exclude_chroms.contains(chrom) {
+                                return None;
+                            }
+                            let size = storage::single_read_size(size);
+                            let (start, end, strand) = if size > 0 {
+                                (pos_5p, pos_5p + size as u64, Strand::Forward)
+                            } else {
+                                ((pos_5p + 1).checked_sub(size.unsigned_abs() as u64)?, pos_5p + 1, Strand::Reverse)
+                            };
+                            Some(SingleRead {
                                 chrom: chrom.to_string(),
                                 start,
-                                end: start + size,
+                                end,
                                 barcode: None,
                                 count: 1,
-                                strand: None,
+                                strand,
                             }.into())
-                        }
-                    })
-                    .collect()
-            })
-            .collect();
+                        })
+                        .collect()
+                })
+                .collect()
+        });
+        (beds, a, b)
+    })
+}
+
+/// Decode paired-end fragments stored in the version 2 format into raw
+/// fragments. Fragments with an invalid chromosome index, which are reported
+/// by the validation, are skipped.
+fn encoded_pair_to_fragments(
+    index: GenomeBaseIndex,
+    exclude_chroms: HashSet<String>,
+    min_fragment_size: Option<u64>,
+    max_fragment_size: Option<u64>,
+    data_iter: impl ExactSizeIterator<Item = (CsrNonCanonical<u64>, usize, usize)>,
+) -> impl ExactSizeIterator<Item = (Vec<Vec<Fragment>>, usize, usize)> {
+    data_iter.map(move |(mat, a, b)| {
+        let row_offsets = mat.row_offsets();
+        let col_indices = mat.col_indices();
+        let values = mat.values();
+        let beds = crate::config::install(None, || {
+            (0..(row_offsets.len() - 1))
+                .into_par_iter()
+                .map(|i| {
+                    let range = row_offsets[i]..row_offsets[i + 1];
+                    storage::decode_row(&col_indices[range.clone()], &values[range])
+                        .filter_map(|(c, start, size)| {
+                            let chrom = index.chroms.get_index(c)?;
+                            let size = size as u64;
+                            if exclude_chroms.contains(chrom)
+                                || min_fragment_size.map_or(false, |x| size < x)
+                                || max_fragment_size.map_or(false, |x| size > x)
+                            {
+                                None
+                            } else {
+                                Some(PairRead {
+                                    chrom: chrom.to_string(),
+                                    start,
+                                    end: start + size,
+                                    barcode: None,
+                                    count: 1,
+                                    strand: None,
+                                }.into())
+                            }
+                        })
+                        .collect()
+                })
+                .collect()
+        });
         (beds, a, b)
     })
 }
 
+/// Remove the entries of the rows for which `excluded` is true.
+fn clear_rows<T: Clone>(mat: CsrNonCanonical<T>, excluded: &[bool]) -> CsrNonCanonical<T> {
+    if !excluded.iter().any(|x| *x) {
+        return mat;
+    }
+    let row_offsets = mat.row_offsets();
+    let col_indices = mat.col_indices();
+    let values = mat.values();
+    let mut offsets = Vec::with_capacity(row_offsets.len());
+    let mut indices = Vec::with_capacity(col_indices.len());
+    let mut data = Vec::with_capacity(values.len());
+    offsets.push(0);
+    for i in 0..row_offsets.len() - 1 {
+        if !excluded[i] {
+            let range = row_offsets[i]..row_offsets[i + 1];
+            indices.extend_from_slice(&col_indices[range.clone()]);
+            data.extend_from_slice(&values[range]);
+        }
+        offsets.push(indices.len());
+    }
+    CsrNonCanonical::from_csr_data(mat.nrows(), mat.ncols(), offsets, indices, data)
+}
+
+/// Like [`clear_rows`], for the base values. `excluded` is `None` if no cell
+/// mask is set.
+fn clear_csr_rows<T: Clone>(mat: CsrMatrix<T>, excluded: Option<&[bool]>) -> CsrMatrix<T> {
+    let Some(excluded) = excluded.filter(|x| x.iter().any(|x| *x)) else {
+        return mat;
+    };
+    let mut offsets = Vec::with_capacity(mat.nrows() + 1);
+    let mut indices = Vec::new();
+    let mut data = Vec::new();
+    offsets.push(0);
+    for (row, excluded) in mat.row_iter().zip(excluded) {
+        if !excluded {
+            indices.extend_from_slice(row.col_indices());
+            data.extend_from_slice(row.values());
+        }
+        offsets.push(indices.len());
+    }
+    CsrMatrix::try_from_csr_data(mat.nrows(), mat.ncols(), offsets, indices, data).unwrap()
+}
+
 /// The `FragmentData` struct is used to count the number of reads that overlap
 /// for a given list of genomic features (such as genes, exons, ChIP-Seq peaks, or the like).
 /// It stores the counts as an iterator of tuples, each containing a
 /// compressed sparse row matrix, a start index, and an end index.
 /// The output count matrix can be configured to have a fixed bin size and
-/// to exclude certain chromosomes.
+/// to exclude certain chromosomes. Chromosomes removed by the global filter
+/// (see [`crate::config::set_chrom_filter`]) are always excluded.
 pub struct FragmentData {
     index: GenomeBaseIndex,
-    data_iter: CompressedFragmentIter,
+    data_iter: FragmentChunks,
     resolution: usize,
     exclude_chroms: HashSet<String>,
     min_fragment_size: Option<u64>,
@@ -142,24 +328,33 @@ pub struct FragmentData {
 }
 
 impl FragmentData {
-    pub fn new(chrom_sizes: ChromSizes, data_iter: CompressedFragmentIter) -> Self {
+    pub fn new<T: Into<FragmentChunks>>(chrom_sizes: ChromSizes, data_iter: T) -> Self {
+        let exclude_chroms = crate::config::chrom_filter().map_or(HashSet::new(), |f| {
+            f.removed((&chrom_sizes).into_iter().map(|(chr, _)| chr))
+        });
         Self {
             index: GenomeBaseIndex::new(&chrom_sizes),
-            data_iter,
+            data_iter: data_iter.into(),
             resolution: 1,
-            exclude_chroms: HashSet::new(),
+            exclude_chroms,
             min_fragment_size: None,
             max_fragment_size: None,
             counting_strategy: CountingStrategy::Insertion,
         }
     }
 
+    /// The fragment data as version 1 matrices. Fragments stored in the
+    /// version 2 format are decoded chunk by chunk.
     pub fn into_inner(self) -> CompressedFragmentIter {
-        self.data_iter
+        self.data_iter.into_v1(&self.index)
     }
 
     pub fn is_paired(&self) -> bool {
-        matches!(self.data_iter, CompressedFragmentIter::FragmentPaired(_))
+        matches!(
+            self.data_iter,
+            FragmentChunks::V1(CompressedFragmentIter::FragmentPaired(_))
+                | FragmentChunks::V2(EncodedFragmentIter::FragmentPaired(_))
+        )
     }
 
     pub fn get_gindex(&self) -> GenomeBaseIndex {
@@ -181,19 +376,47 @@ impl FragmentData {
         }
     }
 
+    /// Exclude the cells for which `mask` is true: they are treated as having
+    /// no fragments.
+    pub fn with_cell_mask(mut self, mask: Vec<bool>) -> Self {
+        let mask = std::sync::Arc::new(mask);
+        macro_rules! clear {
+            ($iter:expr) => {
+                Box::new($iter.map(move |(mat, a, b)| (clear_rows(mat, &mask[a..b]), a, b)))
+            };
+        }
+        self.data_iter = match self.data_iter {
+            FragmentChunks::V1(CompressedFragmentIter::FragmentSingle(iter)) => {
+                CompressedFragmentIter::FragmentSingle(clear!(iter)).into()
+            }
+            FragmentChunks::V1(CompressedFragmentIter::FragmentPaired(iter)) => {
+                CompressedFragmentIter::FragmentPaired(clear!(iter)).into()
+            }
+            FragmentChunks::V2(EncodedFragmentIter::FragmentSingle(iter)) => {
+                EncodedFragmentIter::FragmentSingle(clear!(iter)).into()
+            }
+            FragmentChunks::V2(EncodedFragmentIter::FragmentPaired(iter)) => {
+                EncodedFragmentIter::FragmentPaired(clear!(iter)).into()
+            }
+        };
+        self
+    }
+
     /// Set the bin size of the output coverage.
     pub fn with_resolution(mut self, s: usize) -> Self {
         self.resolution = s;
         self
     }
 
-    /// Exclude certain chromosomes from the output coverage.
+    /// Exclude certain chromosomes from the output coverage, in addition to
+    /// those removed by the global chromosome filter.
     pub fn exclude(mut self, chroms: &[&str]) -> Self {
-        self.exclude_chroms = chroms
+        let chroms: Vec<String> = chroms
             .iter()
             .filter(|x| self.index.chroms.contains(**x))
             .map(|x| x.to_string())
             .collect();
+        self.exclude_chroms.extend(chroms);
         self
     }
 
@@ -209,6 +432,15 @@ impl FragmentData {
         self
     }
 
+    /// Mark the fragments as long reads (see [`crate::preprocessing::LongRead`]).
+    /// The fragment sizes are then limited to [`LONG_READ_MIN_FRAGMENT_SIZE`]
+    /// and [`LONG_READ_MAX_FRAGMENT_SIZE`] unless other limits are set.
+    pub fn long_reads(mut self) -> Self {
+        self.min_fragment_size.get_or_insert(LONG_READ_MIN_FRAGMENT_SIZE);
+        self.max_fragment_size.get_or_insert(LONG_READ_MAX_FRAGMENT_SIZE);
+        self
+    }
+
     pub fn set_counting_strategy(mut self, counting_strategy: CountingStrategy) -> Self {
         self.counting_strategy = counting_strategy;
         self
@@ -219,10 +451,20 @@ impl FragmentData {
         self,
     ) -> Box<dyn ExactSizeIterator<Item = (Vec<Vec<Fragment>>, usize, usize)>> {
         match self.data_iter {
-            CompressedFragmentIter::FragmentSingle(iter) => {
+            FragmentChunks::V1(CompressedFragmentIter::FragmentSingle(iter)) => {
                 Box::new(single_to_fragments(self.index, self.exclude_chroms, iter))
             }
-            CompressedFragmentIter::FragmentPaired(iter) => Box::new(pair_to_fragments(
+            FragmentChunks::V1(CompressedFragmentIter::FragmentPaired(iter)) => Box::new(pair_to_fragments(
+                self.index,
+                self.exclude_chroms,
+                self.min_fragment_size,
+                self.max_fragment_size,
+                iter,
+            )),
+            FragmentChunks::V2(EncodedFragmentIter::FragmentSingle(iter)) => {
+                Box::new(encoded_single_to_fragments(self.index, self.exclude_chroms, iter))
+            }
+            FragmentChunks::V2(EncodedFragmentIter::FragmentPaired(iter)) => Box::new(encoded_pair_to_fragments(
                 self.index,
                 self.exclude_chroms,
                 self.min_fragment_size,
@@ -261,8 +503,9 @@ impl FragmentData {
         self,
     ) -> Box<dyn ExactSizeIterator<Item = (CsrMatrix<u32>, usize, usize)>> {
         let index = self.get_gindex();
+        let data_iter = self.data_iter.into_v1(&self.index);
         let ori_index = self.index;
-        match self.data_iter {
+        match data_iter {
             CompressedFragmentIter::FragmentPaired(mat_iter) => {
                 Box::new(mat_iter.map(move |(mat, i, j)| {
                     let new_mat = gen_mat_pair::<u32>(
@@ -298,16 +541,18 @@ impl FragmentData {
         let n_col = counter.num_features();
         let strategy = self.counting_strategy;
         self.into_fragments().map(move |(data, i, j)| {
-            let vec = data
-                .into_par_iter()
-                .map(|beds| {
-                    let mut coverage = counter.clone();
-                    beds.into_iter().for_each(|fragment| {
-                        coverage.insert_fragment(&fragment, &strategy);
-                    });
-                    coverage.get_values()
-                })
-                .collect::<Vec<_>>();
+            let vec = crate::config::install(None, || {
+                data
+                    .into_par_iter()
+                    .map(|beds| {
+                        let mut coverage = counter.clone();
+                        beds.into_iter().for_each(|fragment| {
+                            coverage.insert_fragment(&fragment, &strategy);
+                        });
+                        coverage.get_values()
+                    })
+                    .collect::<Vec<_>>()
+            });
             let (r, c, offset, ind, data) = to_csr_data(vec, n_col);
             (
                 CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap(),
@@ -330,24 +575,26 @@ where
 {
     let row_offsets = mat.row_offsets();
     let col_indices = mat.col_indices();
-    let vec = (0..mat.nrows())
-        .into_par_iter()
-        .map(|row| {
-            let mut count: BTreeMap<usize, T> = BTreeMap::new();
-            let row_start = row_offsets[row];
-            let row_end = row_offsets[row + 1];
-
-            for k in row_start..row_end {
-                let (chrom, pos) = ori_index.get_position(col_indices[k]);
-                if exclude_chroms.is_empty() || !exclude_chroms.contains(chrom) {
-                    let i = new_index.get_position_rev(chrom, pos);
-                    let entry = count.entry(i).or_insert(Zero::zero());
-                    *entry += One::one();
+    let vec = crate::config::install(None, || {
+        (0..mat.nrows())
+            .into_par_iter()
+            .map(|row| {
+                let mut count: BTreeMap<usize, T> = BTreeMap::new();
+                let row_start = row_offsets[row];
+                let row_end = row_offsets[row + 1];
+
+                for k in row_start..row_end {
+                    let (chrom, pos) = ori_index.get_position(col_indices[k]);
+                    if exclude_chroms.is_empty() || !exclude_chroms.contains(chrom) {
+                        let i = new_index.get_position_rev(chrom, pos);
+                        let entry = count.entry(i).or_insert(Zero::zero());
+                        *entry += One::one();
+                    }
                 }
-            }
-            count.into_iter().collect::<Vec<_>>()
-        })
-        .collect::<Vec<_>>();
+                count.into_iter().collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    });
     let (r, c, offset, ind, data) = to_csr_data(vec, new_index.len());
     CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap()
 }
@@ -368,57 +615,59 @@ where
     let row_offsets = mat.row_offsets();
     let col_indices = mat.col_indices();
     let values = mat.values();
-    let vec = (0..mat.nrows())
-        .into_par_iter()
-        .map(|row| {
-            let mut count: BTreeMap<usize, T> = BTreeMap::new();
-            let row_start = row_offsets[row];
-            let row_end = row_offsets[row + 1];
-            for k in row_start..row_end {
-                let (chrom, start) = ori_index.get_position(col_indices[k]);
-                let frag_size = values[k] as u64;
-                let end = start + frag_size - 1;
-                if !exclude_chroms.contains(chrom)
-                    && min_fragment_size.map_or(true, |x| frag_size >= x)
-                    && max_fragment_size.map_or(true, |x| frag_size <= x)
-                {
-                    let start_ = new_index.get_position_rev(chrom, start);
-                    let end_ = new_index.get_position_rev(chrom, end);
-                    match counting_strategy {
-                        CountingStrategy::Insertion => {
-                            [start_, end_].into_iter().for_each(|i| {
-                                count
-                                    .entry(i)
-                                    .and_modify(|x| *x += One::one())
-                                    .or_insert(One::one());
-                            });
-                        }
-                        CountingStrategy::Fragment => {
-                            (start_..=end_).into_iter().for_each(|i| {
-                                count
-                                    .entry(i)
-                                    .and_modify(|x| *x += One::one())
-                                    .or_insert(One::one());
-                            });
-                        }
-                        CountingStrategy::PIC => {
-                            count
-                                .entry(start_)
-                                .and_modify(|x| *x += One::one())
-                                .or_insert(One::one());
-                            if start_ != end_ {
+    let vec = crate::config::install(None, || {
+        (0..mat.nrows())
+            .into_par_iter()
+            .map(|row| {
+                let mut count: BTreeMap<usize, T> = BTreeMap::new();
+                let row_start = row_offsets[row];
+                let row_end = row_offsets[row + 1];
+                for k in row_start..row_end {
+                    let (chrom, start) = ori_index.get_position(col_indices[k]);
+                    let frag_size = values[k] as u64;
+                    let end = start + frag_size - 1;
+                    if !exclude_chroms.contains(chrom)
+                        && min_fragment_size.map_or(true, |x| frag_size >= x)
+                        && max_fragment_size.map_or(true, |x| frag_size <= x)
+                    {
+                        let start_ = new_index.get_position_rev(chrom, start);
+                        let end_ = new_index.get_position_rev(chrom, end);
+                        match counting_strategy {
+                            CountingStrategy::Insertion => {
+                                [start_, end_].into_iter().for_each(|i| {
+                                    count
+                                        .entry(i)
+                                        .and_modify(|x| *x += One::one())
+                                        .or_insert(One::one());
+                                });
+                            }
+                            CountingStrategy::Fragment => {
+                                (start_..=end_).into_iter().for_each(|i| {
+                                    count
+                                        .entry(i)
+                                        .and_modify(|x| *x += One::one())
+                                        .or_insert(One::one());
+                                });
+                            }
+                            CountingStrategy::PIC => {
                                 count
-                                    .entry(end_)
+                                    .entry(start_)
                                     .and_modify(|x| *x += One::one())
                                     .or_insert(One::one());
+                                if start_ != end_ {
+                                    count
+                                        .entry(end_)
+                                        .and_modify(|x| *x += One::one())
+                                        .or_insert(One::one());
+                                }
                             }
                         }
                     }
                 }
-            }
-            count.into_iter().collect::<Vec<_>>()
-        })
-        .collect::<Vec<_>>();
+                count.into_iter().collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    });
     let (r, c, offset, ind, data) = to_csr_data(vec, new_index.len());
     CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap()
 }
@@ -469,28 +718,30 @@ where
                 CsrMatrix::try_from_pattern_and_values(pattern, new_data).unwrap()
             } else {
                 let n = mat.nrows();
-                let vec = (0..n)
-                    .into_par_iter()
-                    .map(|k| {
-                        let row = mat.get_row(k).unwrap();
-                        let mut count: BTreeMap<usize, T> = BTreeMap::new();
-                        row.col_indices()
-                            .into_iter()
-                            .zip(row.values())
-                            .for_each(|(idx, val)| {
-                                let ridx = idx / genome_size;
-                                let cidx = idx % genome_size;
-                                let locus1 = ori_index.get_region(ridx);
-                                let locus2 = ori_index.get_region(cidx);
-                                let i1 = index.get_position_rev(locus1.chrom(), locus1.start());
-                                let i2 = index.get_position_rev(locus2.chrom(), locus2.start());
-                                let i = i1 * new_size + i2;
-                                let val = T::from_u32(*val).unwrap();
-                                *count.entry(i).or_insert(Zero::zero()) += val;
-                            });
-                        count.into_iter().collect::<Vec<_>>()
-                    })
-                    .collect::<Vec<_>>();
+                let vec = crate::config::install(None, || {
+                    (0..n)
+                        .into_par_iter()
+                        .map(|k| {
+                            let row = mat.get_row(k).unwrap();
+                            let mut count: BTreeMap<usize, T> = BTreeMap::new();
+                            row.col_indices()
+                                .into_iter()
+                                .zip(row.values())
+                                .for_each(|(idx, val)| {
+                                    let ridx = idx / genome_size;
+                                    let cidx = idx % genome_size;
+                                    let locus1 = ori_index.get_region(ridx);
+                                    let locus2 = ori_index.get_region(cidx);
+                                    let i1 = index.get_position_rev(locus1.chrom(), locus1.start());
+                                    let i2 = index.get_position_rev(locus2.chrom(), locus2.start());
+                                    let i = i1 * new_size + i2;
+                                    let val = T::from_u32(*val).unwrap();
+                                    *count.entry(i).or_insert(Zero::zero()) += val;
+                                });
+                            count.into_iter().collect::<Vec<_>>()
+                        })
+                        .collect::<Vec<_>>()
+                });
                 let (r, c, offset, ind, data) = to_csr_data(vec, new_size * new_size);
                 CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap()
             };
@@ -597,6 +848,7 @@ pub struct BaseData<I> {
     data_iter: I,
     resolution: usize,
     exclude_chroms: HashSet<String>,
+    cell_mask: Option<std::sync::Arc<Vec<bool>>>,
 }
 
 impl<I> BaseData<I>
@@ -609,6 +861,7 @@ where
             data_iter,
             resolution: 1,
             exclude_chroms: HashSet::new(),
+            cell_mask: None,
         }
     }
 
@@ -616,6 +869,13 @@ where
         self.index.with_step(self.resolution)
     }
 
+    /// Exclude the cells for which `mask` is true: they are treated as having
+    /// no values.
+    pub fn with_cell_mask(mut self, mask: Vec<bool>) -> Self {
+        self.cell_mask = Some(std::sync::Arc::new(mask));
+        self
+    }
+
     /// Set the resolution of the coverage matrix.
     pub fn with_resolution(mut self, s: usize) -> Self {
         self.resolution = s;
@@ -646,37 +906,41 @@ where
             let row_offsets = mat.row_offsets();
             let col_indices = mat.col_indices();
             let values = mat.values();
-            (0..(row_offsets.len() - 1))
-                .into_par_iter()
-                .map(|i| {
-                    let row_start = row_offsets[i];
-                    let row_end = row_offsets[i + 1];
-                    (row_start..row_end)
-                        .flat_map(|j| {
-                            let (chrom, start) = index.get_position(col_indices[j]);
-                            if exclude_chroms.contains(chrom) {
-                                None
-                            } else {
-                                let v = values[j];
-                                Some(BaseValue::from((chrom, start, v)))
-                            }
-                        })
-                        .collect()
-                })
-                .collect()
+            crate::config::install(None, || {
+                (0..(row_offsets.len() - 1))
+                    .into_par_iter()
+                    .map(|i| {
+                        let row_start = row_offsets[i];
+                        let row_end = row_offsets[i + 1];
+                        (row_start..row_end)
+                            .flat_map(|j| {
+                                let (chrom, start) = index.get_position(col_indices[j]);
+                                if exclude_chroms.contains(chrom) {
+                                    None
+                                } else {
+                                    let v = values[j];
+                                    Some(BaseValue::from((chrom, start, v)))
+                                }
+                            })
+                            .collect()
+                    })
+                    .collect()
+            })
         }
 
         let exclude_chroms = self.exclude_chroms;
         let index = self.index;
+        let mask = self.cell_mask;
         self.data_iter.map(move |(mat, a, b)| {
+            let excluded = mask.as_ref().map(|x| &x[a..b]);
             let values = match mat.data_type() {
                 DataType::CsrMatrix(ScalarType::I32) => helper(
-                    CsrMatrix::<i32>::try_from(mat).unwrap(),
+                    clear_csr_rows(CsrMatrix::<i32>::try_from(mat).unwrap(), excluded),
                     &exclude_chroms,
                     &index,
                 ),
                 DataType::CsrMatrix(ScalarType::F32) => helper(
-                    CsrMatrix::<f32>::try_from(mat).unwrap(),
+                    clear_csr_rows(CsrMatrix::<f32>::try_from(mat).unwrap(), excluded),
                     &exclude_chroms,
                     &index,
                 ),
@@ -707,34 +971,36 @@ where
             let row_offsets = mat.row_offsets();
             let col_indices = mat.col_indices();
             let values = mat.values();
-            let vec = (0..mat.nrows())
-                .into_par_iter()
-                .map(|row| {
-                    let mut count: BTreeMap<usize, Vec<f32>> = BTreeMap::new();
-                    let row_start = row_offsets[row];
-                    let row_end = row_offsets[row + 1];
-
-                    for k in row_start..row_end {
-                        let (chrom, pos) = ori_index.get_position(col_indices[k]);
-                        if exclude_chroms.is_empty() || !exclude_chroms.contains(chrom) {
-                            let i = index.get_position_rev(chrom, pos);
-                            let entry = count.entry(i).or_insert(Vec::new());
-                            entry.push(values[k].get_value(val_ty).unwrap());
+            let vec = crate::config::install(None, || {
+                (0..mat.nrows())
+                    .into_par_iter()
+                    .map(|row| {
+                        let mut count: BTreeMap<usize, Vec<f32>> = BTreeMap::new();
+                        let row_start = row_offsets[row];
+                        let row_end = row_offsets[row + 1];
+
+                        for k in row_start..row_end {
+                            let (chrom, pos) = ori_index.get_position(col_indices[k]);
+                            if exclude_chroms.is_empty() || !exclude_chroms.contains(chrom) {
+                                let i = index.get_position_rev(chrom, pos);
+                                let entry = count.entry(i).or_insert(Vec::new());
+                                entry.push(values[k].get_value(val_ty).unwrap());
+                            }
                         }
-                    }
-                    count
-                        .into_iter()
-                        .map(|(k, v)| {
-                            let r = match summary_ty {
-                                SummaryType::Mean => v.iter().sum::<f32>() / v.len() as f32,
-                                SummaryType::Sum => v.iter().sum(),
-                                SummaryType::Count => v.len() as f32,
-                            };
-                            (k, r)
-                        })
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>();
+                        count
+                            .into_iter()
+                            .map(|(k, v)| {
+                                let r = match summary_ty {
+                                    SummaryType::Mean => v.iter().sum::<f32>() / v.len() as f32,
+                                    SummaryType::Sum => v.iter().sum(),
+                                    SummaryType::Count => v.len() as f32,
+                                };
+                                (k, r)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>()
+            });
             let (r, c, offset, ind, data) = to_csr_data(vec, index.len());
             CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap()
         }
@@ -742,10 +1008,12 @@ where
         let index = self.get_gindex();
         let ori_index = self.index;
 
+        let mask = self.cell_mask;
         self.data_iter.map(move |(mat, i, j)| {
+            let excluded = mask.as_ref().map(|x| &x[i..j]);
             let new_mat = match mat.data_type() {
                 DataType::CsrMatrix(ScalarType::I32) => helper(
-                    CsrMatrix::<i32>::try_from(mat).unwrap(),
+                    clear_csr_rows(CsrMatrix::<i32>::try_from(mat).unwrap(), excluded),
                     val_ty,
                     summary_ty,
                     &self.exclude_chroms,
@@ -753,7 +1021,7 @@ where
                     &index,
                 ),
                 DataType::CsrMatrix(ScalarType::F32) => helper(
-                    CsrMatrix::<f32>::try_from(mat).unwrap(),
+                    clear_csr_rows(CsrMatrix::<f32>::try_from(mat).unwrap(), excluded),
                     val_ty,
                     summary_ty,
                     &self.exclude_chroms,
@@ -789,40 +1057,44 @@ where
             T: Copy + Send + Sync + ValueGetter,
             C: FeatureCounter<Value = f32> + Clone + Sync,
         {
-            (0..data.nrows())
-                .into_par_iter()
-                .map(|i| {
-                    let mut coverage = counter.clone();
-                    let row = data.get_row(i).unwrap();
-                    row.col_indices()
-                        .into_iter()
-                        .zip(row.values())
-                        .for_each(|(idx, val)| {
-                            let (chrom, pos) = index.get_position(*idx);
-                            if exclude_chroms.is_empty() || !exclude_chroms.contains(chrom) {
-                                coverage.insert(
-                                    &GenomicRange::new(chrom, pos, pos + 1),
-                                    val.get_value(val_ty).unwrap(),
-                                );
-                            }
-                        });
-                    match summary_ty {
-                        SummaryType::Mean => coverage
-                            .get_values_and_counts()
-                            .map(|(idx, (val, count))| (idx, val / count as f32))
-                            .collect::<Vec<_>>(),
-                        SummaryType::Sum => coverage.get_values(),
-                        _ => unimplemented!("Unsupported summary type"),
-                    }
-                })
-                .collect::<Vec<_>>()
+            crate::config::install(None, || {
+                (0..data.nrows())
+                    .into_par_iter()
+                    .map(|i| {
+                        let mut coverage = counter.clone();
+                        let row = data.get_row(i).unwrap();
+                        row.col_indices()
+                            .into_iter()
+                            .zip(row.values())
+                            .for_each(|(idx, val)| {
+                                let (chrom, pos) = index.get_position(*idx);
+                                if exclude_chroms.is_empty() || !exclude_chroms.contains(chrom) {
+                                    coverage.insert(
+                                        &GenomicRange::new(chrom, pos, pos + 1),
+                                        val.get_value(val_ty).unwrap(),
+                                    );
+                                }
+                            });
+                        match summary_ty {
+                            SummaryType::Mean => coverage
+                                .get_values_and_counts()
+                                .map(|(idx, (val, count))| (idx, val / count as f32))
+                                .collect::<Vec<_>>(),
+                            SummaryType::Sum => coverage.get_values(),
+                            _ => unimplemented!("Unsupported summary type"),
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
         }
 
         let n_col = counter.num_features();
+        let mask = self.cell_mask;
         self.data_iter.map(move |(data, i, j)| {
+            let excluded = mask.as_ref().map(|x| &x[i..j]);
             let vec = match data.data_type() {
                 DataType::CsrMatrix(ScalarType::I32) => helper(
-                    CsrMatrix::<i32>::try_from(data).unwrap(),
+                    clear_csr_rows(CsrMatrix::<i32>::try_from(data).unwrap(), excluded),
                     counter.clone(),
                     val_ty,
                     summary_ty,
@@ -830,7 +1102,7 @@ where
                     &self.index,
                 ),
                 DataType::CsrMatrix(ScalarType::F32) => helper(
-                    CsrMatrix::<f32>::try_from(data).unwrap(),
+                    clear_csr_rows(CsrMatrix::<f32>::try_from(data).unwrap(), excluded),
                     counter.clone(),
                     val_ty,
                     summary_ty,
@@ -885,20 +1157,22 @@ where
         self.iter.map(move |(mat, i, j)| {
             let mat: CsrMatrix<T> = mat.try_convert().unwrap();
             let n = j - i;
-            let vec = (0..n)
-                .into_par_iter()
-                .map(|k| {
-                    let row = mat.get_row(k).unwrap();
-                    let mut coverage = counter.clone();
-                    row.col_indices()
-                        .into_iter()
-                        .zip(row.values())
-                        .for_each(|(idx, val)| {
-                            coverage.insert(&self.regions[*idx], *val);
-                        });
-                    coverage.get_values()
-                })
-                .collect::<Vec<_>>();
+            let vec = crate::config::install(None, || {
+                (0..n)
+                    .into_par_iter()
+                    .map(|k| {
+                        let row = mat.get_row(k).unwrap();
+                        let mut coverage = counter.clone();
+                        row.col_indices()
+                            .into_iter()
+                            .zip(row.values())
+                            .for_each(|(idx, val)| {
+                                coverage.insert(&self.regions[*idx], *val);
+                            });
+                        coverage.get_values()
+                    })
+                    .collect::<Vec<_>>()
+            });
             let (r, c, offset, ind, data) = to_csr_data(vec, n_col);
             (
                 CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap(),
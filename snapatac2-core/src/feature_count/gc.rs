@@ -0,0 +1,168 @@
+//! Correction of the GC content bias of feature counts.
+//!
+//! PCR amplification and Tn5 favour regions of intermediate GC content, so
+//! the total counts of features depend on their GC content. The dependence is
+//! estimated by a local regression (loess) of the log total counts of the
+//! features on their GC content, and the counts of every feature are divided
+//! by the expected fold change of its GC content.
+
+use anndata::data::{ArrayConvert, DynCsrMatrix};
+use anndata::{AnnDataOp, ArrayData, ArrayElemOp, AxisArraysOp};
+use anyhow::{ensure, Result};
+use indicatif::{ProgressIterator, ProgressStyle};
+use nalgebra_sparse::CsrMatrix;
+use serde_json::json;
+
+use crate::provenance;
+
+/// Number of GC content bins on which the regression is computed.
+const N_BINS: usize = 101;
+
+/// Fit a local linear regression of `y` on `x`, with weights `w`, at `x0`.
+/// The neighbourhood of `x0` contains the points closest to it whose total
+/// weight is at least `span` times the total weight of all points.
+fn loess_at(x: &[f64], y: &[f64], w: &[f64], x0: f64, span: f64) -> f64 {
+    let mut dist: Vec<(f64, f64)> = x.iter().zip(w).map(|(xi, wi)| ((xi - x0).abs(), *wi)).collect();
+    dist.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let target = span * w.iter().sum::<f64>();
+    let mut acc = 0.0;
+    let mut h = dist.last().map_or(0.0, |d| d.0);
+    for (d, wi) in dist {
+        acc += wi;
+        if acc >= target {
+            h = d;
+            break;
+        }
+    }
+    let h = h.max(1e-12) * 1.0001;
+
+    let (mut sw, mut sx, mut sy, mut sxx, mut sxy) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for ((xi, yi), wi) in x.iter().zip(y).zip(w) {
+        let u = (xi - x0).abs() / h;
+        if u >= 1.0 {
+            continue;
+        }
+        let k = wi * (1.0 - u.powi(3)).powi(3);
+        sw += k;
+        sx += k * xi;
+        sy += k * yi;
+        sxx += k * xi * xi;
+        sxy += k * xi * yi;
+    }
+    let mean_x = sx / sw;
+    let mean_y = sy / sw;
+    let var = sxx / sw - mean_x * mean_x;
+    if var <= 1e-12 {
+        mean_y
+    } else {
+        mean_y + (sxy / sw - mean_x * mean_y) / var * (x0 - mean_x)
+    }
+}
+
+/// The expected fold change of the counts of every feature due to its GC
+/// content, given the GC content and the total counts of the features.
+/// Features whose GC content is NaN have a fold change of 1.
+pub fn gc_fold_change(gc: &[f64], totals: &[f64], span: f64) -> Vec<f64> {
+    let bin = |g: f64| ((g.clamp(0.0, 1.0) * (N_BINS - 1) as f64).round()) as usize;
+    let mut n = vec![0.0; N_BINS];
+    let mut sum = vec![0.0; N_BINS];
+    gc.iter().zip(totals).filter(|(g, _)| !g.is_nan()).for_each(|(g, t)| {
+        let b = bin(*g);
+        n[b] += 1.0;
+        sum[b] += t.ln_1p();
+    });
+    let bins: Vec<usize> = (0..N_BINS).filter(|b| n[*b] > 0.0).collect();
+    if bins.is_empty() {
+        return vec![1.0; gc.len()];
+    }
+    let x: Vec<f64> = bins.iter().map(|b| *b as f64 / (N_BINS - 1) as f64).collect();
+    let y: Vec<f64> = bins.iter().map(|b| sum[*b] / n[*b]).collect();
+    let w: Vec<f64> = bins.iter().map(|b| n[*b]).collect();
+    let mean = sum.iter().sum::<f64>() / n.iter().sum::<f64>();
+
+    let mut fit = vec![0.0; N_BINS];
+    x.iter().zip(&bins).for_each(|(xi, b)| fit[*b] = loess_at(&x, &y, &w, *xi, span) - mean);
+    gc.iter()
+        .map(|g| if g.is_nan() { 1.0 } else { fit[bin(*g)].exp() })
+        .collect()
+}
+
+/// Correct the counts in `adata.X` for the GC content of the features, `gc`,
+/// and store the result in `.layers[key_added]`. `span` is the fraction of
+/// features used by each local regression. Returns the fold change removed
+/// from every feature.
+pub fn gc_correct<A: AnnDataOp>(
+    adata: &A,
+    gc: &[f64],
+    span: f64,
+    chunk_size: usize,
+    key_added: &str,
+) -> Result<Vec<f64>> {
+    ensure!(span > 0.0 && span <= 1.0, "span must be in (0, 1]");
+    ensure!(
+        gc.len() == adata.n_vars(),
+        "the length of the GC content ({}) does not match the number of features ({})",
+        gc.len(),
+        adata.n_vars()
+    );
+    let style = ProgressStyle::with_template(
+        "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})",
+    )?;
+
+    let mut totals = vec![0.0; adata.n_vars()];
+    for (mat, _, _) in adata.x().iter::<DynCsrMatrix>(chunk_size) {
+        let mat: CsrMatrix<f64> = mat.try_convert()?;
+        mat.col_indices().iter().zip(mat.values()).for_each(|(j, v)| totals[*j] += v);
+    }
+    let fold_change = gc_fold_change(gc, &totals, span);
+
+    let mut error = None;
+    let chunks = adata
+        .x()
+        .iter::<DynCsrMatrix>(chunk_size)
+        .progress_with_style(style)
+        .map_while(|(mat, _, _)| {
+            let mut mat: CsrMatrix<f64> = match mat.try_convert() {
+                Ok(x) => x,
+                Err(e) => {
+                    error = Some(e);
+                    return None;
+                }
+            };
+            let (_, indices, values) = mat.csr_data_mut();
+            indices.iter().zip(values.iter_mut()).for_each(|(j, v)| *v /= fold_change[*j]);
+            Some(ArrayData::from(mat))
+        });
+    adata.layers().add_iter(key_added, chunks)?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+    let params = json!({
+        "span": span,
+        "key_added": key_added,
+    });
+    provenance::record(adata, "gc_correct", params);
+    Ok(fold_change)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gc_fold_change() {
+        // Counts double with every 10% of GC content.
+        let gc: Vec<f64> = (0..500).map(|i| 0.3 + 0.4 * (i % 50) as f64 / 49.0).collect();
+        let totals: Vec<f64> = gc.iter().map(|g| 100.0 * 2f64.powf(g * 10.0) - 1.0).collect();
+        let fc = gc_fold_change(&gc, &totals, 0.3);
+        let corrected: Vec<f64> = totals.iter().zip(&fc).map(|(t, f)| (t + 1.0) / f).collect();
+        let (min, max) = corrected
+            .iter()
+            .fold((f64::MAX, f64::MIN), |(a, b), x| (a.min(*x), b.max(*x)));
+        assert!(max / min < 1.1, "{} {}", min, max);
+
+        let fc = gc_fold_change(&[f64::NAN, 0.5], &[10.0, 10.0], 0.5);
+        assert_eq!(fc[0], 1.0);
+        assert!((fc[1] - 1.0).abs() < 1e-12);
+    }
+}
@@ -1,15 +1,24 @@
 use super::counter::{CountingStrategy, FeatureCounter, GeneCount, RegionCounter, TranscriptCount};
+use super::stack::to_csr;
 use super::ValueType;
-use crate::feature_count::SnapData;
+use crate::feature_count::{FragmentData, ReadErrorSlot, SnapData};
 use crate::genome::{Promoters, Transcript};
 use crate::preprocessing::SummaryType;
+use crate::provenance;
+use crate::recovery::{self, Element};
+use crate::utils::memory::MemoryTracker;
 
+use anndata::backend::ScalarType;
 use anndata::ArrayElemOp;
 use anndata::{data::DataFrameIndex, AnnDataOp, ArrayData};
-use anyhow::{bail, Result};
-use bed_utils::bed::{map::GIntervalIndexSet, BEDLike};
+use anyhow::{anyhow, bail, ensure, Result};
+use bed_utils::bed::{map::GIntervalIndexSet, BEDLike, GenomicRange};
 use indicatif::{ProgressIterator, ProgressStyle};
+use nalgebra_sparse::CsrMatrix;
 use polars::prelude::{Column, DataFrame};
+use serde_json::json;
+use std::collections::HashMap;
+use std::str::FromStr;
 
 /// Create cell by bin matrix.
 ///
@@ -23,6 +32,7 @@ use polars::prelude::{Column, DataFrame};
 /// * `max_fragment_size` - The maximum fragment size.
 /// * `count_frag_as_reads` - Whether to treat fragments as reads during counting.
 /// * `val_type` - Which kind of value to use: numerator, denominator or ratio. Only used for base data.
+/// * `modality` - The modality of the fragments, see [`crate::feature_count::fragment_key`].
 /// * `out` - The output anndata object.
 pub fn create_tile_matrix<A, B>(
     adata: &A,
@@ -34,12 +44,15 @@ pub fn create_tile_matrix<A, B>(
     counting_strategy: CountingStrategy,
     val_type: ValueType,
     summary_type: SummaryType,
+    cell_weights: Option<&[f64]>,
+    modality: Option<&str>,
     out: Option<&B>,
 ) -> Result<()>
 where
     A: SnapData,
     B: AnnDataOp,
 {
+    let tracker = MemoryTracker::start();
     let style = ProgressStyle::with_template(
         "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})",
     )
@@ -48,7 +61,7 @@ where
     let data_iter: Box<dyn ExactSizeIterator<Item = ArrayData>>;
     let feature_names: DataFrameIndex;
 
-    if let Ok(mut fragments) = adata.get_fragment_iter(chunk_size) {
+    if let Some(mut fragments) = read_fragments(adata, chunk_size, modality)? {
         fragments = fragments
             .with_resolution(bin_size)
             .set_counting_strategy(counting_strategy);
@@ -83,20 +96,248 @@ where
     };
 
     let n_feat = feature_names.len();
-    let data_iter = data_iter.progress_with_style(style);
+    let error = ReadErrorSlot::default();
+    let data_iter = weight_rows(
+        data_iter.progress_with_style(style),
+        cell_weights,
+        adata.n_obs(),
+        &error,
+    )?;
     if let Some(adata_out) = out {
         adata_out.set_n_vars(n_feat)?;
-        adata_out.set_x_from_iter(data_iter)?;
+        recovery::guarded(adata_out, Element::X, || {
+            adata_out.set_x_from_iter(data_iter)?;
+            check_weight_error(&error)
+        })?;
         adata_out.set_obs_names(adata.obs_names())?;
         adata_out.set_var_names(feature_names)?;
     } else {
         adata.set_n_vars(n_feat)?;
-        adata.set_x_from_iter(data_iter)?;
+        recovery::guarded(adata, Element::X, || {
+            adata.set_x_from_iter(data_iter)?;
+            check_weight_error(&error)
+        })?;
         adata.set_var_names(feature_names)?;
     }
+    let params = json!({
+        "bin_size": bin_size,
+        "exclude_chroms": exclude_chroms,
+        "min_fragment_size": min_fragment_size,
+        "max_fragment_size": max_fragment_size,
+        "counting_strategy": format!("{:?}", counting_strategy),
+        "value_type": format!("{:?}", val_type),
+        "summary_type": format!("{:?}", summary_type),
+        "cell_weights": cell_weights.is_some(),
+        "modality": modality,
+    });
+    match out {
+        Some(adata_out) => {
+            provenance::inherit(adata, adata_out);
+            provenance::record_tracked(adata_out, "tile_matrix", params, tracker);
+        }
+        None => provenance::record_tracked(adata, "tile_matrix", params, tracker),
+    }
     Ok(())
 }
 
+/// Median fraction of the tiles of every candidate bin size in which a cell has
+/// at least one Tn5 insertion, computed on the first `max_cells` cells. The
+/// fraction decreases with the bin size, as the insertions of a cell are
+/// spread over more tiles. Returns `(bin_size, median_fraction)` pairs.
+pub fn tile_density<A: SnapData>(
+    adata: &A,
+    candidates: &[usize],
+    max_cells: usize,
+    exclude_chroms: Option<&[&str]>,
+) -> Result<Vec<(usize, f64)>> {
+    ensure!(!candidates.is_empty(), "no candidate bin sizes");
+    ensure!(candidates.iter().all(|x| *x > 0), "bin sizes must be positive");
+    let mut fragments = adata.get_fragment_iter(adata.fragment_chunk_size()?)?;
+    if let Some(exclude_chroms) = exclude_chroms {
+        fragments = fragments.exclude(exclude_chroms);
+    }
+    let chrom_sizes: Vec<(String, u64)> = fragments
+        .get_gindex()
+        .chrom_sizes()
+        .map(|(chr, size)| (chr.clone(), size))
+        .collect();
+    let chrom_index: HashMap<&str, usize> = chrom_sizes
+        .iter()
+        .enumerate()
+        .map(|(i, (chr, _))| (chr.as_str(), i))
+        .collect();
+    let n_tiles: Vec<u64> = candidates
+        .iter()
+        .map(|b| chrom_sizes.iter().map(|(_, size)| size.div_ceil(*b as u64)).sum())
+        .collect();
+
+    let mut densities: Vec<Vec<f64>> = vec![Vec::new(); candidates.len()];
+    for (chunk, start, _) in fragments.into_fragments() {
+        if start >= max_cells {
+            break;
+        }
+        chunk.into_iter().take(max_cells - start).for_each(|frags| {
+            let mut sites: Vec<(usize, u64)> = frags
+                .iter()
+                .filter_map(|x| chrom_index.get(x.chrom()).map(|c| (*c, x)))
+                .flat_map(|(c, x)| [(c, x.start()), (c, x.end().saturating_sub(1))])
+                .collect();
+            sites.sort_unstable();
+            candidates.iter().zip(&n_tiles).zip(densities.iter_mut()).for_each(|((b, n), d)| {
+                let mut tiles: Vec<(usize, u64)> =
+                    sites.iter().map(|(c, p)| (*c, p / *b as u64)).collect();
+                tiles.dedup();
+                d.push(tiles.len() as f64 / *n as f64);
+            });
+        });
+    }
+    ensure!(!densities[0].is_empty(), "no cells found");
+    Ok(candidates
+        .iter()
+        .zip(densities)
+        .map(|(b, mut d)| {
+            d.sort_by(|a, b| a.total_cmp(b));
+            let n = d.len();
+            let median = if n % 2 == 1 { d[n / 2] } else { (d[n / 2 - 1] + d[n / 2]) / 2.0 };
+            (*b, median)
+        })
+        .collect())
+}
+
+/// The smallest bin size whose median tile density, as computed by
+/// [`tile_density`], reaches `target_density`, or the largest bin size if none
+/// does.
+pub fn choose_bin_size(densities: &[(usize, f64)], target_density: f64) -> usize {
+    let mut densities = densities.to_vec();
+    densities.sort_by_key(|x| x.0);
+    densities
+        .iter()
+        .find(|(_, d)| *d >= target_density)
+        .or(densities.last())
+        .map_or(0, |x| x.0)
+}
+
+/// The fragments of `modality`, or `None` if `adata` stores no fragments
+/// without modality and no modality is requested, in which case the base
+/// values are counted instead.
+fn read_fragments<A: SnapData>(
+    adata: &A,
+    chunk_size: usize,
+    modality: Option<&str>,
+) -> Result<Option<FragmentData>> {
+    match modality {
+        Some(_) => adata.get_modality_fragment_iter(chunk_size, modality).map(Some),
+        None => Ok(adata.get_fragment_iter(chunk_size).ok()),
+    }
+}
+
+/// Scale the rows of a cell by feature matrix, given in chunks, by the weights
+/// of the cells, e.g., inverse sequencing depths. Weighting during counting
+/// lets metacells and pseudobulks aggregate weighted counts directly. Weighted
+/// counts are stored as `f64`. The iteration stops at the first chunk that
+/// cannot be weighted, whose error is stored in `error`.
+fn weight_rows<'a, I>(
+    data: I,
+    weights: Option<&'a [f64]>,
+    n_obs: usize,
+    error: &ReadErrorSlot,
+) -> Result<Box<dyn Iterator<Item = ArrayData> + 'a>>
+where
+    I: Iterator<Item = ArrayData> + 'a,
+{
+    let Some(weights) = weights else {
+        return Ok(Box::new(data));
+    };
+    ensure!(
+        weights.len() == n_obs,
+        "the number of cell weights ({}) does not match the number of cells ({})",
+        weights.len(),
+        n_obs
+    );
+    ensure!(
+        weights.iter().all(|w| w.is_finite() && *w >= 0.0),
+        "cell weights must be finite and non-negative"
+    );
+    let mut start = 0;
+    let error = error.clone();
+    Ok(Box::new(data.map_while(move |chunk| {
+        let mut mat = to_csr(chunk).map_err(|e| *error.lock().unwrap() = Some(e)).ok()?;
+        mat.row_iter_mut().enumerate().for_each(|(i, mut row)| {
+            row.values_mut().iter_mut().for_each(|v| *v *= weights[start + i]);
+        });
+        start += mat.nrows();
+        Some(ArrayData::from(mat))
+    })))
+}
+
+/// Return the error stored by [`weight_rows`], if any.
+fn check_weight_error(error: &ReadErrorSlot) -> Result<()> {
+    match error.lock().unwrap().take() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Compute the cell by bin coverage of selected regions, e.g., a locus of a few
+/// megabases at 50 bp resolution, and return it in memory instead of storing it
+/// in the AnnData object. Every region is divided into bins of `bin_size` bases,
+/// the last bin of a region being truncated at its end. Returns the matrix and
+/// the names of the bins.
+pub fn create_region_bin_matrix<A: SnapData>(
+    adata: &A,
+    regions: &[GenomicRange],
+    bin_size: u64,
+    counting_strategy: CountingStrategy,
+    min_fragment_size: Option<u64>,
+    max_fragment_size: Option<u64>,
+) -> Result<(CsrMatrix<u32>, Vec<String>)> {
+    if bin_size == 0 {
+        bail!("bin size must be positive");
+    }
+    let bins: GIntervalIndexSet = regions
+        .iter()
+        .flat_map(|region| {
+            (region.start()..region.end())
+                .step_by(bin_size as usize)
+                .map(move |s| {
+                    GenomicRange::new(region.chrom(), s, (s + bin_size).min(region.end()))
+                })
+        })
+        .collect();
+    let counter = RegionCounter::new(&bins);
+    let feature_names = counter.get_feature_ids();
+    let n_cols = feature_names.len();
+
+    let mut fragments = adata
+        .get_fragment_iter(adata.fragment_chunk_size()?)?
+        .set_counting_strategy(counting_strategy);
+    if let Some(min_fragment_size) = min_fragment_size {
+        fragments = fragments.min_fragment_size(min_fragment_size);
+    }
+    if let Some(max_fragment_size) = max_fragment_size {
+        fragments = fragments.max_fragment_size(max_fragment_size);
+    }
+
+    // Stack the chunks of rows.
+    let mut row_offsets = vec![0];
+    let mut col_indices = Vec::new();
+    let mut values = Vec::new();
+    fragments.into_aggregated_array_iter(counter).for_each(|(mat, _, _)| {
+        let offset = col_indices.len();
+        row_offsets.extend(mat.row_offsets()[1..].iter().map(|x| x + offset));
+        col_indices.extend_from_slice(mat.col_indices());
+        values.extend_from_slice(mat.values());
+    });
+    let mat = CsrMatrix::try_from_csr_data(
+        row_offsets.len() - 1,
+        n_cols,
+        row_offsets,
+        col_indices,
+        values,
+    )?;
+    Ok((mat, feature_names))
+}
+
 pub fn create_peak_matrix<A, I, D, B>(
     adata: &A,
     peaks: I,
@@ -106,6 +347,8 @@ pub fn create_peak_matrix<A, I, D, B>(
     summary_type: SummaryType,
     min_fragment_size: Option<u64>,
     max_fragment_size: Option<u64>,
+    cell_weights: Option<&[f64]>,
+    modality: Option<&str>,
     out: Option<&B>,
     use_x: bool,
 ) -> Result<()>
@@ -114,12 +357,174 @@ where
     I: Iterator<Item = D>,
     D: BEDLike + Send + Sync + Clone,
     B: AnnDataOp,
+{
+    let tracker = MemoryTracker::start();
+    let regions: GIntervalIndexSet = peaks.collect();
+    let n_feat = write_region_matrix(
+        adata,
+        &regions,
+        chunk_size,
+        counting_strategy,
+        val_type,
+        summary_type,
+        min_fragment_size,
+        max_fragment_size,
+        cell_weights,
+        modality,
+        out,
+        use_x,
+    )?;
+
+    let params = json!({
+        "n_peaks": n_feat,
+        "counting_strategy": format!("{:?}", counting_strategy),
+        "value_type": format!("{:?}", val_type),
+        "summary_type": format!("{:?}", summary_type),
+        "min_fragment_size": min_fragment_size,
+        "max_fragment_size": max_fragment_size,
+        "use_x": use_x,
+        "cell_weights": cell_weights.is_some(),
+        "modality": modality,
+    });
+    match out {
+        Some(adata_out) => {
+            provenance::inherit(adata, adata_out);
+            provenance::record_tracked(adata_out, "peak_matrix", params, tracker);
+        }
+        None => provenance::record_tracked(adata, "peak_matrix", params, tracker),
+    }
+    Ok(())
+}
+
+/// Create cell by window matrix, where windows of `window_size` bases slide
+/// along the chromosomes by `step` bases. Windows overlap when `step` is
+/// smaller than `window_size`, and a fragment is counted in every window it
+/// overlaps. The last window of a chromosome ends at the end of the
+/// chromosome. Feature names encode the coordinates of the windows, e.g.,
+/// "chr1:250-750".
+pub fn create_window_matrix<A, B>(
+    adata: &A,
+    window_size: u64,
+    step: u64,
+    chunk_size: usize,
+    exclude_chroms: Option<&[&str]>,
+    min_fragment_size: Option<u64>,
+    max_fragment_size: Option<u64>,
+    counting_strategy: CountingStrategy,
+    val_type: ValueType,
+    summary_type: SummaryType,
+    cell_weights: Option<&[f64]>,
+    out: Option<&B>,
+) -> Result<()>
+where
+    A: SnapData,
+    B: AnnDataOp,
+{
+    let tracker = MemoryTracker::start();
+    let chrom_sizes = adata.read_chrom_sizes()?;
+    let windows: GIntervalIndexSet = sliding_windows(
+        (&chrom_sizes)
+            .into_iter()
+            .filter(|(chrom, _)| exclude_chroms.map_or(true, |x| !x.contains(&chrom.as_str())))
+            .map(|(chrom, size)| (chrom.as_str(), *size)),
+        window_size,
+        step,
+    )?
+    .collect();
+    let n_feat = write_region_matrix(
+        adata,
+        &windows,
+        chunk_size,
+        counting_strategy,
+        val_type,
+        summary_type,
+        min_fragment_size,
+        max_fragment_size,
+        cell_weights,
+        None,
+        out,
+        false,
+    )?;
+
+    let params = json!({
+        "window_size": window_size,
+        "step": step,
+        "n_windows": n_feat,
+        "exclude_chroms": exclude_chroms,
+        "min_fragment_size": min_fragment_size,
+        "max_fragment_size": max_fragment_size,
+        "counting_strategy": format!("{:?}", counting_strategy),
+        "value_type": format!("{:?}", val_type),
+        "summary_type": format!("{:?}", summary_type),
+        "cell_weights": cell_weights.is_some(),
+    });
+    match out {
+        Some(adata_out) => {
+            provenance::inherit(adata, adata_out);
+            provenance::record_tracked(adata_out, "window_matrix", params, tracker);
+        }
+        None => provenance::record_tracked(adata, "window_matrix", params, tracker),
+    }
+    Ok(())
+}
+
+/// Windows of `window_size` bases starting every `step` bases along the
+/// chromosomes, given by their names and sizes.
+pub fn sliding_windows<'a, I>(
+    chroms: I,
+    window_size: u64,
+    step: u64,
+) -> Result<impl Iterator<Item = GenomicRange> + 'a>
+where
+    I: IntoIterator<Item = (&'a str, u64)> + 'a,
+{
+    ensure!(window_size > 0, "window size must be positive");
+    ensure!(step > 0, "step must be positive");
+    ensure!(
+        step <= window_size,
+        "step ({}) must not be larger than the window size ({}), otherwise some bases are not covered",
+        step,
+        window_size
+    );
+    Ok(chroms.into_iter().flat_map(move |(chrom, size)| {
+        // The number of windows needed to reach the end of the chromosome.
+        let n = if size <= window_size {
+            1
+        } else {
+            (size - window_size).div_ceil(step) + 1
+        };
+        (0..n).map(move |i| {
+            let start = i * step;
+            GenomicRange::new(chrom, start, (start + window_size).min(size))
+        })
+    }))
+}
+
+/// Count the fragments or values of every cell in `regions` and write the
+/// matrix to `out`, or to `adata` if `out` is None. Returns the number of
+/// regions.
+fn write_region_matrix<A, B>(
+    adata: &A,
+    regions: &GIntervalIndexSet,
+    chunk_size: usize,
+    counting_strategy: CountingStrategy,
+    val_type: ValueType,
+    summary_type: SummaryType,
+    min_fragment_size: Option<u64>,
+    max_fragment_size: Option<u64>,
+    cell_weights: Option<&[f64]>,
+    modality: Option<&str>,
+    out: Option<&B>,
+    use_x: bool,
+) -> Result<usize>
+where
+    A: SnapData,
+    B: AnnDataOp,
 {
     let style = ProgressStyle::with_template(
         "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})",
     )
     .unwrap();
-    let regions: GIntervalIndexSet = peaks.collect();
 
     let data_iter: Box<dyn ExactSizeIterator<Item = ArrayData>>;
     let feature_names: Vec<String>;
@@ -134,7 +539,7 @@ where
             .unwrap()
             .is_floating();
         data_iter = if is_floating {
-            let counter: RegionCounter<f64> = RegionCounter::new(&regions);
+            let counter: RegionCounter<f64> = RegionCounter::new(regions);
             feature_names = counter.get_feature_ids();
             Box::new(
                 adata
@@ -143,7 +548,7 @@ where
                     .map(|x| x.0.into()),
             )
         } else {
-            let counter: RegionCounter<u32> = RegionCounter::new(&regions);
+            let counter: RegionCounter<u32> = RegionCounter::new(regions);
             feature_names = counter.get_feature_ids();
             Box::new(
                 adata
@@ -152,8 +557,8 @@ where
                     .map(|x| x.0.into()),
             )
         };
-    } else if let Ok(mut fragments) = adata.get_fragment_iter(chunk_size) {
-        let counter = RegionCounter::new(&regions);
+    } else if let Some(mut fragments) = read_fragments(adata, chunk_size, modality)? {
+        let counter = RegionCounter::new(regions);
         feature_names = counter.get_feature_ids();
         fragments = fragments.set_counting_strategy(counting_strategy);
         if let Some(min_fragment_size) = min_fragment_size {
@@ -168,7 +573,7 @@ where
                 .map(|x| x.0.into()),
         );
     } else if let Ok(values) = adata.get_base_iter(chunk_size) {
-        let counter = RegionCounter::new(&regions);
+        let counter = RegionCounter::new(regions);
         feature_names = counter.get_feature_ids();
         data_iter = Box::new(
             values
@@ -180,18 +585,141 @@ where
     }
 
     let n_feat = feature_names.len();
-    let data_iter = data_iter.progress_with_style(style);
+    let error = ReadErrorSlot::default();
+    let data_iter = weight_rows(
+        data_iter.progress_with_style(style),
+        cell_weights,
+        adata.n_obs(),
+        &error,
+    )?;
     if let Some(adata_out) = out {
         adata_out.set_n_vars(n_feat)?;
-        adata_out.set_x_from_iter(data_iter)?;
+        recovery::guarded(adata_out, Element::X, || {
+            adata_out.set_x_from_iter(data_iter)?;
+            check_weight_error(&error)
+        })?;
         adata_out.set_obs_names(adata.obs_names())?;
         adata_out.set_var_names(feature_names.into())?;
     } else {
         adata.set_n_vars(n_feat)?;
-        adata.set_x_from_iter(data_iter)?;
+        recovery::guarded(adata, Element::X, || {
+            adata.set_x_from_iter(data_iter)?;
+            check_weight_error(&error)
+        })?;
         adata.set_var_names(feature_names.into())?;
     }
+    Ok(n_feat)
+}
 
+/// Append new cells to an existing cell by region matrix, e.g., a tile or peak
+/// matrix, without recounting the cells already in it. The rows of `matrix`
+/// are written to `out`, followed by the counts of the cells of `adata` in the
+/// regions given by `matrix.var_names`, so that the features of `out` are
+/// exactly those of `matrix`. The counting parameters must be the ones used to
+/// create `matrix`.
+pub fn append_matrix_rows<A, M, B>(
+    adata: &A,
+    matrix: &M,
+    chunk_size: usize,
+    counting_strategy: CountingStrategy,
+    min_fragment_size: Option<u64>,
+    max_fragment_size: Option<u64>,
+    out: &B,
+) -> Result<()>
+where
+    A: SnapData,
+    M: AnnDataOp,
+    B: AnnDataOp,
+{
+    let tracker = MemoryTracker::start();
+    let style = ProgressStyle::with_template(
+        "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})",
+    )
+    .unwrap();
+
+    let scalar = matrix.x().dtype().and_then(|x| x.scalar_type());
+    ensure!(
+        scalar == Some(ScalarType::U32),
+        "the matrix must contain raw counts (u32), found: {:?}",
+        scalar
+    );
+    let var_names = matrix.var_names().into_vec();
+    let ranges = var_names
+        .iter()
+        .map(|x| {
+            GenomicRange::from_str(x)
+                .map_err(|_| anyhow!("feature '{}' is not a genomic region", x))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // Map the features of the counter back to the columns of the matrix.
+    let columns: HashMap<String, usize> = ranges
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.pretty_show(), i))
+        .collect();
+    ensure!(columns.len() == var_names.len(), "the features of the matrix are not unique");
+    let regions: GIntervalIndexSet = ranges.into_iter().collect();
+    let counter = RegionCounter::new(&regions);
+    let to_column: Vec<usize> = counter
+        .get_feature_ids()
+        .iter()
+        .map(|x| columns[x])
+        .collect();
+    let n_vars = var_names.len();
+
+    let mut fragments = adata
+        .get_fragment_iter(chunk_size)?
+        .set_counting_strategy(counting_strategy);
+    if let Some(min_fragment_size) = min_fragment_size {
+        fragments = fragments.min_fragment_size(min_fragment_size);
+    }
+    if let Some(max_fragment_size) = max_fragment_size {
+        fragments = fragments.max_fragment_size(max_fragment_size);
+    }
+    let new_rows = fragments
+        .into_aggregated_array_iter(counter)
+        .progress_with_style(style)
+        .map(|(mat, _, _)| {
+            let (mut offsets, mut indices, mut values) = (vec![0], Vec::new(), Vec::new());
+            mat.row_iter().for_each(|row| {
+                let mut row: Vec<_> = row
+                    .col_indices()
+                    .iter()
+                    .zip(row.values())
+                    .map(|(j, v)| (to_column[*j], *v))
+                    .collect();
+                row.sort_unstable_by_key(|x| x.0);
+                row.into_iter().for_each(|(j, v)| {
+                    indices.push(j);
+                    values.push(v);
+                });
+                offsets.push(indices.len());
+            });
+            let n = offsets.len() - 1;
+            ArrayData::from(CsrMatrix::try_from_csr_data(n, n_vars, offsets, indices, values).unwrap())
+        });
+    let old_rows = matrix.x().iter::<ArrayData>(chunk_size).map(|x| x.0);
+
+    let obs_names: Vec<String> = matrix
+        .obs_names()
+        .into_vec()
+        .into_iter()
+        .chain(adata.obs_names().into_vec())
+        .collect();
+    out.set_n_vars(n_vars)?;
+    recovery::guarded(out, Element::X, || out.set_x_from_iter(old_rows.chain(new_rows)))?;
+    out.set_obs_names(obs_names.into())?;
+    out.set_var_names(matrix.var_names())?;
+
+    let params = json!({
+        "n_obs": matrix.n_obs(),
+        "n_new": adata.n_obs(),
+        "counting_strategy": format!("{:?}", counting_strategy),
+        "min_fragment_size": min_fragment_size,
+        "max_fragment_size": max_fragment_size,
+    });
+    provenance::inherit(matrix, out);
+    provenance::record_tracked(out, "append_matrix_rows", params, tracker);
     Ok(())
 }
 
@@ -206,6 +734,8 @@ pub fn create_gene_matrix<A, B>(
     counting_strategy: CountingStrategy,
     min_fragment_size: Option<u64>,
     max_fragment_size: Option<u64>,
+    cell_weights: Option<&[f64]>,
+    modality: Option<&str>,
     out: Option<&B>,
     use_x: bool,
 ) -> Result<()>
@@ -213,6 +743,8 @@ where
     A: SnapData,
     B: AnnDataOp,
 {
+    let tracker = MemoryTracker::start();
+    let n_transcripts = transcripts.len();
     let promoters = Promoters::new(transcripts, upstream, downstream, include_gene_body);
     let transcript_counter = TranscriptCount::new(&promoters);
     let data: Box<dyn ExactSizeIterator<Item = ArrayData>>;
@@ -254,7 +786,7 @@ where
         }
     } else {
         let mut fragments = adata
-            .get_fragment_iter(chunk_size)?
+            .get_modality_fragment_iter(chunk_size, modality)?
             .set_counting_strategy(counting_strategy);
         if let Some(min_fragment_size) = min_fragment_size {
             fragments = fragments.min_fragment_size(min_fragment_size);
@@ -284,9 +816,14 @@ where
             _ => panic!("id_type must be 'transcript' or 'gene'"),
         }
     }
+    let error = ReadErrorSlot::default();
+    let data = weight_rows(data, cell_weights, adata.n_obs(), &error)?;
 
     if let Some(adata_out) = out {
-        adata_out.set_x_from_iter(data)?;
+        recovery::guarded(adata_out, Element::X, || {
+            adata_out.set_x_from_iter(data)?;
+            check_weight_error(&error)
+        })?;
         adata_out.set_obs_names(adata.obs_names())?;
         adata_out.set_var_names(ids.into())?;
         if let Some(gene_names) = gene_names {
@@ -296,7 +833,10 @@ where
             )])?)?;
         }
     } else {
-        adata.set_x_from_iter(data)?;
+        recovery::guarded(adata, Element::X, || {
+            adata.set_x_from_iter(data)?;
+            check_weight_error(&error)
+        })?;
         adata.set_var_names(ids.into())?;
         if let Some(gene_names) = gene_names {
             adata.set_var(DataFrame::new(vec![Column::new(
@@ -306,5 +846,59 @@ where
         }
     }
 
+    let params = json!({
+        "n_transcripts": n_transcripts,
+        "id_type": id_type,
+        "upstream": upstream,
+        "downstream": downstream,
+        "include_gene_body": include_gene_body,
+        "counting_strategy": format!("{:?}", counting_strategy),
+        "min_fragment_size": min_fragment_size,
+        "max_fragment_size": max_fragment_size,
+        "use_x": use_x,
+        "cell_weights": cell_weights.is_some(),
+        "modality": modality,
+    });
+    match out {
+        Some(adata_out) => {
+            provenance::inherit(adata, adata_out);
+            provenance::record_tracked(adata_out, "gene_matrix", params, tracker);
+        }
+        None => provenance::record_tracked(adata, "gene_matrix", params, tracker),
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose_bin_size() {
+        let densities = [(1000, 0.004), (200, 0.001), (500, 0.002)];
+        assert_eq!(choose_bin_size(&densities, 0.002), 500);
+        assert_eq!(choose_bin_size(&densities, 0.0005), 200);
+        assert_eq!(choose_bin_size(&densities, 0.01), 1000);
+    }
+
+    #[test]
+    fn test_sliding_windows() {
+        let windows: Vec<String> = sliding_windows([("chr1", 1000), ("chr2", 300)], 500, 250)
+            .unwrap()
+            .map(|x| x.pretty_show())
+            .collect();
+        assert_eq!(
+            windows,
+            vec![
+                "chr1:0-500",
+                "chr1:250-750",
+                "chr1:500-1000",
+                "chr2:0-300",
+            ]
+        );
+        let windows: Vec<_> = sliding_windows([("chr1", 1001)], 500, 300).unwrap().collect();
+        assert_eq!(windows.last().unwrap().end(), 1001);
+        assert_eq!(windows.len(), 3);
+        assert!(sliding_windows([("chr1", 1000)], 100, 200).is_err());
+    }
+}
@@ -1,26 +1,52 @@
 pub mod aggregator;
+mod cache;
 mod counter;
 mod data_iter;
+mod gc;
 mod matrix;
+mod reindex;
+mod stack;
+mod storage;
+mod visitor;
 
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
 use anndata::{
-    data::DynCsrMatrix, AnnData, AnnDataOp, AnnDataSet, ArrayElemOp, AxisArraysOp, Backend,
+    data::{CsrNonCanonical, DynCsrMatrix, SelectInfoElem},
+    AnnData, AnnDataOp, AnnDataSet, ArrayElemOp, AxisArraysOp, Backend,
     ElemCollectionOp,
 };
 use anyhow::{bail, Context, Result};
 use bed_utils::bed::GenomicRange;
-pub use counter::{CountingStrategy, FeatureCounter};
+pub use cache::{
+    build_coverage_cache, coverage_cache_info, fragment_checksum, read_coverage_cache,
+    CoverageCache, CoverageCacheInfo, FragmentChecksum, COVERAGE_CACHE,
+};
+pub use counter::{CountingStrategy, FeatureCounter, RegionCounter};
 pub use data_iter::{
-    BaseData, BaseValue, ChromValueIter, CompressedFragmentIter, ContactData, FragmentData,
-    ValueType,
+    BaseData, BaseValue, ChromValueIter, CompressedFragmentIter, ContactData, EncodedFragmentIter,
+    FragmentChunks, FragmentData, ValueType,
+};
+pub use gc::{gc_correct, gc_fold_change};
+pub use matrix::{
+    append_matrix_rows, choose_bin_size, create_gene_matrix, create_peak_matrix,
+    create_region_bin_matrix, create_tile_matrix, create_window_matrix, sliding_windows,
+    tile_density,
 };
-pub use matrix::{create_gene_matrix, create_peak_matrix, create_tile_matrix};
+pub use reindex::{reindex_vars, ReindexPolicy};
+pub use stack::{hstack_csr, stacked_var_names, to_csr};
+pub use storage::{
+    compact_fragment_storage, convert_fragment_storage, decode_paired, decode_single,
+    finish_compaction, fragment_storage, FragmentStorage, FRAGMENT_PAIRED_V2, FRAGMENT_SINGLE_V2,
+};
+pub use visitor::{visit_fragments, FragmentVisitor};
 use num::integer::div_ceil;
 use polars::frame::DataFrame;
 
+use crate::config;
 use crate::genome::ChromSizes;
+use crate::preprocessing::Fragment;
 
 /// Key for storing single-end fragment data in the `.obsm` matrix.
 pub const FRAGMENT_SINGLE: &str = "fragment_single";
@@ -28,15 +54,92 @@ pub const FRAGMENT_SINGLE: &str = "fragment_single";
 /// Key for storing paired-end fragment data in the `.obsm` matrix.
 pub const FRAGMENT_PAIRED: &str = "fragment_paired";
 
+/// The key of the fragments of `modality` in the `.obsm` matrix, e.g.,
+/// "fragment_paired:H3K27ac" for `key` "fragment_paired".
+pub fn modality_key(key: &str, modality: &str) -> String {
+    format!("{}:{}", key, modality)
+}
+
+/// The key of the fragments of `modality` in the `.obsm` matrix, or `key`
+/// itself for the fragments stored without modality.
+pub fn fragment_key(key: &str, modality: Option<&str>) -> String {
+    match modality {
+        None => key.to_string(),
+        Some(modality) => modality_key(key, modality),
+    }
+}
+
 /// Key for storing base values in the `.obsm` matrix.
 pub const BASE_VALUE: &str = "__values__";
 
+/// Key of the read type in the `.uns`, "long" for long reads (see
+/// [`crate::preprocessing::LongRead`]).
+pub const READ_TYPE: &str = "read_type";
+
 /// The `SnapData` trait represents an interface for reading and
 /// manipulating single-cell assay data. It extends the `AnnDataOp` trait,
 /// adding methods for reading chromosome sizes and genome-wide base-resolution coverage.
 pub trait SnapData: AnnDataOp {
     /// Read fragment data stored in the `.obsm` matrix.
-    fn get_fragment_iter(&self, chunk_size: usize) -> Result<FragmentData>;
+    fn get_fragment_iter(&self, chunk_size: usize) -> Result<FragmentData> {
+        self.get_modality_fragment_iter(chunk_size, None)
+    }
+
+    /// Read the fragments of `modality` stored in the `.obsm` matrix, see
+    /// [`fragment_key`]. `None` reads the fragments stored without modality.
+    fn get_modality_fragment_iter(
+        &self,
+        chunk_size: usize,
+        modality: Option<&str>,
+    ) -> Result<FragmentData>;
+
+    /// Like [`SnapData::get_modality_fragment_iter`], but only read the rows
+    /// in `ranges`, sorted ranges of consecutive rows, by chunks of at most
+    /// `chunk_size` of these rows. Other cells may be absent from the output.
+    /// As the rows are read lazily, the first error is stored in `error`, and
+    /// the remaining rows are returned empty, so the caller must check `error`
+    /// once it has read the fragments. The default implementation reads all
+    /// the rows.
+    fn get_fragment_iter_rows(
+        &self,
+        chunk_size: usize,
+        ranges: &[(usize, usize)],
+        modality: Option<&str>,
+        error: &ReadErrorSlot,
+    ) -> Result<FragmentData> {
+        let _ = (ranges, error);
+        self.get_modality_fragment_iter(chunk_size, modality)
+    }
+
+    /// Number of cells per chunk to use with [`SnapData::get_fragment_iter`].
+    /// Unless it is fixed by [`config::set_chunk_size`], the chunk size is
+    /// chosen from the average number of fragments per cell, estimated from the
+    /// first cells, and the memory budget. See [`config::adaptive_chunk_size`].
+    fn fragment_chunk_size(&self) -> Result<usize> {
+        self.modality_chunk_size(None)
+    }
+
+    /// Like [`SnapData::fragment_chunk_size`], for the fragments of `modality`.
+    fn modality_chunk_size(&self, modality: Option<&str>) -> Result<usize> {
+        const SAMPLE_SIZE: usize = 200;
+        if let Some(n) = config::chunk_size() {
+            return Ok(n);
+        }
+        let (n_cells, n_fragments) = match self
+            .get_modality_fragment_iter(SAMPLE_SIZE, modality)?
+            .into_inner()
+        {
+            CompressedFragmentIter::FragmentSingle(mut x) => {
+                x.next().map_or((0, 0), |(mat, i, j)| (j - i, mat.values().len()))
+            }
+            CompressedFragmentIter::FragmentPaired(mut x) => {
+                x.next().map_or((0, 0), |(mat, i, j)| (j - i, mat.values().len()))
+            }
+        };
+        Ok(config::adaptive_chunk_size::<Fragment>(
+            n_fragments as f64 / n_cells.max(1) as f64,
+        ))
+    }
 
     /// Read base values stored in the `.obsm` matrix.
     fn get_base_iter(
@@ -84,22 +187,172 @@ pub trait SnapData: AnnDataOp {
     }
 }
 
+/// The cells excluded by the mask set with [`crate::config::set_cell_mask`],
+/// if the mask is set. It is an error for `.obs` to lack the mask column.
+pub fn read_cell_mask<A: AnnDataOp>(data: &A) -> Result<Option<Vec<bool>>> {
+    let Some(key) = crate::config::cell_mask() else {
+        return Ok(None);
+    };
+    let obs = data.read_obs()?;
+    let column = obs
+        .column(&key)
+        .with_context(|| format!("the cell mask '{}' is not present in the '.obs'", key))?;
+    let mask = column
+        .bool()
+        .with_context(|| format!("'.obs[\"{}\"]' must be boolean to be used as a cell mask", key))?;
+    Ok(Some(mask.into_iter().map(|x| x.unwrap_or(false)).collect()))
+}
+
+/// Apply the settings of the fragments of `data` to `fragments`: the cell mask
+/// (see [`read_cell_mask`]) and the size limits of long reads (see
+/// [`FragmentData::long_reads`]).
+pub fn configure_fragments<A: AnnDataOp>(data: &A, fragments: FragmentData) -> Result<FragmentData> {
+    let fragments = match read_cell_mask(data)? {
+        Some(mask) => fragments.with_cell_mask(mask),
+        None => fragments,
+    };
+    let long_reads = data
+        .uns()
+        .get_item::<String>(READ_TYPE)?
+        .map_or(false, |x| x == "long");
+    Ok(if long_reads { fragments.long_reads() } else { fragments })
+}
+
+/// The first error encountered while reading fragments lazily, see
+/// [`SnapData::get_fragment_iter_rows`].
+pub type ReadErrorSlot = Arc<Mutex<Option<anyhow::Error>>>;
+
+/// Split the rows in `ranges` into batches of at most `chunk_size` rows. A
+/// batch is read as the matrix of all rows from its first to its last row, so
+/// a batch also ends before it would span more than `MAX_SPAN` chunks.
+fn row_batches(ranges: &[(usize, usize)], chunk_size: usize) -> Vec<Vec<usize>> {
+    const MAX_SPAN: usize = 16;
+    let chunk_size = chunk_size.max(1);
+    let mut batches: Vec<Vec<usize>> = Vec::new();
+    ranges.iter().flat_map(|(a, b)| *a..*b).for_each(|i| match batches.last_mut() {
+        Some(batch) if batch.len() < chunk_size && i < batch[0] + MAX_SPAN * chunk_size => {
+            batch.push(i)
+        }
+        _ => batches.push(vec![i]),
+    });
+    batches
+}
+
+/// Place the rows of `mat`, the rows `rows` of a matrix with `n_cols` columns,
+/// in a matrix of all rows from the first to the last of `rows`. The other
+/// rows are empty, as are all rows if `mat` is `None`.
+fn spread_rows<T: Clone>(
+    mat: Option<CsrNonCanonical<T>>,
+    rows: &[usize],
+    n_cols: usize,
+) -> CsrNonCanonical<T> {
+    let start = rows[0];
+    let n_rows = rows[rows.len() - 1] + 1 - start;
+    let Some(mat) = mat else {
+        return CsrNonCanonical::from_csr_data(n_rows, n_cols, vec![0; n_rows + 1], Vec::new(), Vec::new());
+    };
+    if mat.nrows() == n_rows {
+        return mat;
+    }
+    let row_offsets = mat.row_offsets();
+    let mut offsets = Vec::with_capacity(n_rows + 1);
+    offsets.push(0);
+    rows.iter().enumerate().for_each(|(k, i)| {
+        offsets.resize(i - start + 1, row_offsets[k]);
+        offsets.push(row_offsets[k + 1]);
+    });
+    CsrNonCanonical::from_csr_data(
+        n_rows,
+        n_cols,
+        offsets,
+        mat.col_indices().to_vec(),
+        mat.values().to_vec(),
+    )
+}
+
 impl<B: Backend> SnapData for AnnData<B> {
-    fn get_fragment_iter(&self, chunk_size: usize) -> Result<FragmentData> {
+    fn get_modality_fragment_iter(
+        &self,
+        chunk_size: usize,
+        modality: Option<&str>,
+    ) -> Result<FragmentData> {
+        let chrom_sizes = self.read_chrom_sizes()?;
+        let key = |x: &str| fragment_key(x, modality);
         let obsm = self.obsm();
-        let matrices: CompressedFragmentIter =
-            if let Some(insertion) = obsm.get_item_iter(FRAGMENT_SINGLE, chunk_size) {
-                CompressedFragmentIter::FragmentSingle(Box::new(insertion))
-            } else if let Some(fragment) = obsm.get_item_iter(FRAGMENT_PAIRED, chunk_size) {
-                CompressedFragmentIter::FragmentPaired(Box::new(fragment))
+        let matrices: FragmentChunks =
+            if let Some(insertion) = obsm.get_item_iter(&key(FRAGMENT_SINGLE), chunk_size) {
+                CompressedFragmentIter::FragmentSingle(Box::new(insertion)).into()
+            } else if let Some(fragment) = obsm.get_item_iter(&key(FRAGMENT_PAIRED), chunk_size) {
+                CompressedFragmentIter::FragmentPaired(Box::new(fragment)).into()
+            } else if let Some(insertion) = obsm.get_item_iter(&key(FRAGMENT_SINGLE_V2), chunk_size) {
+                EncodedFragmentIter::FragmentSingle(Box::new(insertion)).into()
+            } else if let Some(fragment) = obsm.get_item_iter(&key(FRAGMENT_PAIRED_V2), chunk_size) {
+                EncodedFragmentIter::FragmentPaired(Box::new(fragment)).into()
             } else {
                 bail!(
-                    "one of the following keys must be present in the '.obsm': '{}', '{}'",
-                    FRAGMENT_SINGLE,
-                    FRAGMENT_PAIRED
+                    "one of the following keys must be present in the '.obsm': '{}', '{}', '{}', '{}'",
+                    key(FRAGMENT_SINGLE),
+                    key(FRAGMENT_PAIRED),
+                    key(FRAGMENT_SINGLE_V2),
+                    key(FRAGMENT_PAIRED_V2),
                 )
             };
-        Ok(FragmentData::new(self.read_chrom_sizes()?, matrices))
+        configure_fragments(self, FragmentData::new(chrom_sizes, matrices))
+    }
+
+    fn get_fragment_iter_rows(
+        &self,
+        chunk_size: usize,
+        ranges: &[(usize, usize)],
+        modality: Option<&str>,
+        error: &ReadErrorSlot,
+    ) -> Result<FragmentData> {
+        let chrom_sizes = self.read_chrom_sizes()?;
+        let key = |x: &str| fragment_key(x, modality);
+        let obsm = self.obsm();
+        let batches = row_batches(ranges, chunk_size);
+
+        // Read the rows of every batch from `.obsm[key]`, as a matrix of the
+        // rows from the first to the last row of the batch.
+        macro_rules! read_rows {
+            ($elem:expr, $ty:ty) => {{
+                let elem = $elem;
+                let error = error.clone();
+                let n_cols = elem.shape().map_or(0, |x| x[1]);
+                batches.into_iter().map(move |rows| {
+                    let (start, end) = (rows[0], rows[rows.len() - 1] + 1);
+                    let failed = error.lock().unwrap().is_some();
+                    let mat = if failed {
+                        None
+                    } else {
+                        let select = if end - start == rows.len() {
+                            SelectInfoElem::from(start..end)
+                        } else {
+                            SelectInfoElem::from(rows.clone())
+                        };
+                        elem.slice_axis::<$ty, _>(0, select)
+                            .and_then(|x| x.context("the fragments are empty"))
+                            .with_context(|| format!("failed to read the fragments of rows {}-{}", start, end))
+                            .map_err(|e| *error.lock().unwrap() = Some(e))
+                            .ok()
+                    };
+                    (spread_rows(mat, &rows, n_cols), start, end)
+                })
+            }};
+        }
+
+        let matrices: FragmentChunks = if let Some(elem) = obsm.get(&key(FRAGMENT_SINGLE)) {
+            CompressedFragmentIter::FragmentSingle(Box::new(read_rows!(elem, CsrNonCanonical<i32>))).into()
+        } else if let Some(elem) = obsm.get(&key(FRAGMENT_PAIRED)) {
+            CompressedFragmentIter::FragmentPaired(Box::new(read_rows!(elem, CsrNonCanonical<u32>))).into()
+        } else if let Some(elem) = obsm.get(&key(FRAGMENT_SINGLE_V2)) {
+            EncodedFragmentIter::FragmentSingle(Box::new(read_rows!(elem, CsrNonCanonical<u64>))).into()
+        } else if let Some(elem) = obsm.get(&key(FRAGMENT_PAIRED_V2)) {
+            EncodedFragmentIter::FragmentPaired(Box::new(read_rows!(elem, CsrNonCanonical<u64>))).into()
+        } else {
+            return self.get_modality_fragment_iter(chunk_size, modality);
+        };
+        configure_fragments(self, FragmentData::new(chrom_sizes, matrices))
     }
 
     fn get_base_iter(
@@ -108,7 +361,11 @@ impl<B: Backend> SnapData for AnnData<B> {
     ) -> Result<BaseData<impl ExactSizeIterator<Item = (DynCsrMatrix, usize, usize)>>> {
         let obsm = self.obsm();
         if let Some(data) = obsm.get_item_iter(BASE_VALUE, chunk_size) {
-            Ok(BaseData::new(self.read_chrom_sizes()?, data))
+            let data = BaseData::new(self.read_chrom_sizes()?, data);
+            Ok(match read_cell_mask(self)? {
+                Some(mask) => data.with_cell_mask(mask),
+                None => data,
+            })
         } else {
             bail!("key '_values' is not present in the '.obsm'")
         }
@@ -116,22 +373,34 @@ impl<B: Backend> SnapData for AnnData<B> {
 }
 
 impl<B: Backend> SnapData for AnnDataSet<B> {
-    fn get_fragment_iter(&self, chunk_size: usize) -> Result<FragmentData> {
+    fn get_modality_fragment_iter(
+        &self,
+        chunk_size: usize,
+        modality: Option<&str>,
+    ) -> Result<FragmentData> {
+        let chrom_sizes = self.read_chrom_sizes()?;
+        let key = |x: &str| fragment_key(x, modality);
         let adatas = self.adatas().inner();
         let obsm = adatas.get_obsm();
-        let matrices: CompressedFragmentIter =
-            if let Some(insertion) = obsm.get_item_iter(FRAGMENT_SINGLE, chunk_size) {
-                CompressedFragmentIter::FragmentSingle(Box::new(insertion))
-            } else if let Some(fragment) = obsm.get_item_iter(FRAGMENT_PAIRED, chunk_size) {
-                CompressedFragmentIter::FragmentPaired(Box::new(fragment))
+        let matrices: FragmentChunks =
+            if let Some(insertion) = obsm.get_item_iter(&key(FRAGMENT_SINGLE), chunk_size) {
+                CompressedFragmentIter::FragmentSingle(Box::new(insertion)).into()
+            } else if let Some(fragment) = obsm.get_item_iter(&key(FRAGMENT_PAIRED), chunk_size) {
+                CompressedFragmentIter::FragmentPaired(Box::new(fragment)).into()
+            } else if let Some(insertion) = obsm.get_item_iter(&key(FRAGMENT_SINGLE_V2), chunk_size) {
+                EncodedFragmentIter::FragmentSingle(Box::new(insertion)).into()
+            } else if let Some(fragment) = obsm.get_item_iter(&key(FRAGMENT_PAIRED_V2), chunk_size) {
+                EncodedFragmentIter::FragmentPaired(Box::new(fragment)).into()
             } else {
                 bail!(
-                    "one of the following keys must be present in the '.obsm': '{}', '{}'",
-                    FRAGMENT_SINGLE,
-                    FRAGMENT_PAIRED
+                    "one of the following keys must be present in the '.obsm': '{}', '{}', '{}', '{}'",
+                    key(FRAGMENT_SINGLE),
+                    key(FRAGMENT_PAIRED),
+                    key(FRAGMENT_SINGLE_V2),
+                    key(FRAGMENT_PAIRED_V2),
                 )
             };
-        Ok(FragmentData::new(self.read_chrom_sizes()?, matrices))
+        configure_fragments(self, FragmentData::new(chrom_sizes, matrices))
     }
 
     fn get_base_iter(
@@ -140,9 +409,44 @@ impl<B: Backend> SnapData for AnnDataSet<B> {
     ) -> Result<BaseData<impl ExactSizeIterator<Item = (DynCsrMatrix, usize, usize)>>> {
         let obsm = self.obsm();
         if let Some(data) = obsm.get_item_iter(BASE_VALUE, chunk_size) {
-            Ok(BaseData::new(self.read_chrom_sizes()?, data))
+            let data = BaseData::new(self.read_chrom_sizes()?, data);
+            Ok(match read_cell_mask(self)? {
+                Some(mask) => data.with_cell_mask(mask),
+                None => data,
+            })
         } else {
             bail!("key '_values' is not present in the '.obsm'")
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_row_batches() {
+        assert_eq!(
+            row_batches(&[(0, 3), (5, 6), (8, 10)], 2),
+            vec![vec![0, 1], vec![2, 5], vec![8, 9]],
+        );
+        // Batches do not span more than 16 chunks.
+        assert_eq!(row_batches(&[(0, 1), (63, 65)], 4), vec![vec![0, 63], vec![64]]);
+        assert_eq!(row_batches(&[(0, 1), (64, 65)], 4), vec![vec![0], vec![64]]);
+        assert!(row_batches(&[], 4).is_empty());
+    }
+
+    #[test]
+    fn test_spread_rows() {
+        let mat = CsrNonCanonical::from_csr_data(2, 5, vec![0, 2, 3], vec![0, 4, 2], vec![1, 2, 3]);
+        let spread = spread_rows(Some(mat), &[3, 6], 5);
+        assert_eq!(spread.nrows(), 4);
+        assert_eq!(spread.row_offsets(), &[0, 2, 2, 2, 3]);
+        assert_eq!(spread.col_indices(), &[0, 4, 2]);
+        assert_eq!(spread.values(), &[1, 2, 3]);
+
+        let empty = spread_rows::<i32>(None, &[3, 6], 5);
+        assert_eq!((empty.nrows(), empty.ncols()), (4, 5));
+        assert_eq!(empty.row_offsets(), &[0, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,197 @@
+//! Mapping of a cell by region matrix onto another set of regions, e.g., to
+//! harmonize datasets whose peaks were called separately.
+
+use anndata::backend::ScalarType;
+use anndata::data::{ArrayConvert, DynCsrMatrix};
+use anndata::{AnnDataOp, ArrayData, ArrayElemOp};
+use anyhow::{anyhow, bail, ensure, Result};
+use bed_utils::bed::{map::GIntervalMap, BEDLike, GenomicRange};
+use indicatif::{ProgressIterator, ProgressStyle};
+use nalgebra_sparse::CsrMatrix;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::provenance;
+use crate::recovery::{self, Element};
+
+/// How the counts of the old regions are assigned to the new regions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReindexPolicy {
+    /// A new region takes the counts of the identical old region, if any.
+    Exact,
+    /// A new region takes the sum of the counts of the old regions overlapping it.
+    Overlap,
+    /// Like `Overlap`, but the counts of an old region are weighted by the
+    /// fraction of its length covered by the new region.
+    Fraction,
+}
+
+impl TryFrom<&str> for ReindexPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "exact" => Ok(ReindexPolicy::Exact),
+            "overlap" => Ok(ReindexPolicy::Overlap),
+            "fraction" => Ok(ReindexPolicy::Fraction),
+            _ => bail!("policy must be 'exact', 'overlap' or 'fraction'"),
+        }
+    }
+}
+
+/// For every old region, the new regions it contributes to and the weights of
+/// its counts.
+fn feature_weights(
+    old: &[GenomicRange],
+    new: &[GenomicRange],
+    policy: ReindexPolicy,
+) -> Vec<Vec<(usize, f64)>> {
+    let index: GIntervalMap<usize> = new.iter().enumerate().map(|(i, x)| (x.clone(), i)).collect();
+    old.iter()
+        .map(|region| {
+            let mut hits: Vec<(usize, f64)> = index
+                .find(region)
+                .filter_map(|(_, k)| {
+                    let target = &new[*k];
+                    let overlap = region.end().min(target.end()) - region.start().max(target.start());
+                    match policy {
+                        ReindexPolicy::Exact => (target.start() == region.start()
+                            && target.end() == region.end())
+                        .then_some((*k, 1.0)),
+                        ReindexPolicy::Overlap => Some((*k, 1.0)),
+                        ReindexPolicy::Fraction => {
+                            Some((*k, overlap as f64 / (region.end() - region.start()) as f64))
+                        }
+                    }
+                })
+                .collect();
+            hits.sort_unstable_by_key(|x| x.0);
+            hits
+        })
+        .collect()
+}
+
+fn reindex_chunk(mat: &CsrMatrix<f64>, weights: &[Vec<(usize, f64)>], n_cols: usize) -> CsrMatrix<f64> {
+    let (mut offsets, mut indices, mut values) = (vec![0], Vec::new(), Vec::new());
+    mat.row_iter().for_each(|row| {
+        let mut acc = BTreeMap::new();
+        row.col_indices().iter().zip(row.values()).for_each(|(j, v)| {
+            weights[*j].iter().for_each(|(k, w)| *acc.entry(*k).or_insert(0.0) += v * w);
+        });
+        acc.into_iter().filter(|(_, v)| *v != 0.0).for_each(|(k, v)| {
+            indices.push(k);
+            values.push(v);
+        });
+        offsets.push(indices.len());
+    });
+    CsrMatrix::try_from_csr_data(offsets.len() - 1, n_cols, offsets, indices, values).unwrap()
+}
+
+/// Map the cell by region matrix in `adata.X`, whose `.var_names` are genomic
+/// regions, onto `regions`. The result is stored in `out`, with `regions` as
+/// features. Integer counts stay integers unless `policy` is
+/// [`ReindexPolicy::Fraction`].
+pub fn reindex_vars<A, B>(
+    adata: &A,
+    regions: &[GenomicRange],
+    policy: ReindexPolicy,
+    chunk_size: usize,
+    out: &B,
+) -> Result<()>
+where
+    A: AnnDataOp,
+    B: AnnDataOp,
+{
+    let style = ProgressStyle::with_template(
+        "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})",
+    )?;
+    let old = adata
+        .var_names()
+        .into_vec()
+        .iter()
+        .map(|x| {
+            GenomicRange::from_str(x).map_err(|_| anyhow!("feature '{}' is not a genomic region", x))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let scalar = adata.x().dtype().and_then(|x| x.scalar_type());
+    ensure!(scalar.is_some(), "the matrix is empty");
+    let integer = matches!(scalar, Some(ScalarType::U8 | ScalarType::U16 | ScalarType::U32));
+    let weights = feature_weights(&old, regions, policy);
+    let n_mapped = weights.iter().filter(|x| !x.is_empty()).count();
+    log::info!("{} out of {} features were mapped to the new regions", n_mapped, old.len());
+
+    let n_cols = regions.len();
+    let mut error = None;
+    let chunks = adata
+        .x()
+        .iter::<DynCsrMatrix>(chunk_size)
+        .progress_with_style(style)
+        .map_while(|(mat, _, _)| {
+            let mat: CsrMatrix<f64> = match mat.try_convert() {
+                Ok(x) => x,
+                Err(e) => {
+                    error = Some(e);
+                    return None;
+                }
+            };
+            let result = reindex_chunk(&mat, &weights, n_cols);
+            Some(if integer && policy != ReindexPolicy::Fraction {
+                let (offsets, indices, values) = result.disassemble();
+                let values: Vec<u32> = values.into_iter().map(|x| x.round() as u32).collect();
+                ArrayData::from(
+                    CsrMatrix::try_from_csr_data(offsets.len() - 1, n_cols, offsets, indices, values).unwrap(),
+                )
+            } else {
+                ArrayData::from(result)
+            })
+        });
+    out.set_n_vars(n_cols)?;
+    recovery::guarded(out, Element::X, || out.set_x_from_iter(chunks))?;
+    // The chunks are written as they are converted, so the part of the matrix
+    // written before the error is deleted.
+    if let Some(e) = error {
+        recovery::discard(out, Element::X)?;
+        return Err(e);
+    }
+    out.set_obs_names(adata.obs_names())?;
+    out.set_var_names(regions.iter().map(|x| x.pretty_show()).collect::<Vec<_>>().into())?;
+    let params = json!({
+        "policy": format!("{:?}", policy),
+        "n_features": n_cols,
+        "n_mapped": n_mapped,
+    });
+    provenance::inherit(adata, out);
+    provenance::record(out, "reindex_vars", params);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reindex() {
+        let old = vec![
+            GenomicRange::new("chr1", 0, 100),
+            GenomicRange::new("chr1", 100, 200),
+            GenomicRange::new("chr2", 0, 100),
+        ];
+        let new = vec![GenomicRange::new("chr1", 50, 150), GenomicRange::new("chr2", 0, 100)];
+        let mat = CsrMatrix::try_from_csr_data(
+            2, 3, vec![0, 3, 4], vec![0, 1, 2, 1], vec![2.0, 4.0, 1.0, 3.0],
+        ).unwrap();
+
+        let exact = reindex_chunk(&mat, &feature_weights(&old, &new, ReindexPolicy::Exact), 2);
+        assert_eq!(exact.col_indices(), &[1]);
+        assert_eq!(exact.values(), &[1.0]);
+        assert_eq!(exact.row_offsets(), &[0, 1, 1]);
+
+        let overlap = reindex_chunk(&mat, &feature_weights(&old, &new, ReindexPolicy::Overlap), 2);
+        assert_eq!(overlap.col_indices(), &[0, 1, 0]);
+        assert_eq!(overlap.values(), &[6.0, 1.0, 3.0]);
+
+        let fraction = reindex_chunk(&mat, &feature_weights(&old, &new, ReindexPolicy::Fraction), 2);
+        assert_eq!(fraction.values(), &[3.0, 1.0, 1.5]);
+    }
+}
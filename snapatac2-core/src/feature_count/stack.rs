@@ -0,0 +1,93 @@
+//! Horizontal concatenation of feature spaces, e.g., tiles, motif deviations
+//! and gene activities, into a single cell by feature matrix.
+
+use anndata::{data::ArrayConvert, ArrayData};
+use anyhow::{bail, ensure, Result};
+use nalgebra_sparse::CsrMatrix;
+use ndarray::Array2;
+use std::collections::HashSet;
+
+/// Convert a chunk of `.X` to a sparse matrix of `f64`.
+pub fn to_csr(data: ArrayData) -> Result<CsrMatrix<f64>> {
+    match data {
+        ArrayData::CsrMatrix(csr) => csr.try_convert(),
+        ArrayData::Array(arr) => {
+            let arr: Array2<f64> = arr.try_convert()?;
+            let (mut offsets, mut indices, mut values) = (vec![0], Vec::new(), Vec::new());
+            arr.rows().into_iter().for_each(|row| {
+                row.iter().enumerate().filter(|(_, v)| **v != 0.0).for_each(|(j, v)| {
+                    indices.push(j);
+                    values.push(*v);
+                });
+                offsets.push(indices.len());
+            });
+            Ok(CsrMatrix::try_from_csr_data(arr.nrows(), arr.ncols(), offsets, indices, values)?)
+        }
+        _ => bail!("unsupported array type, expecting a dense or CSR matrix"),
+    }
+}
+
+/// Concatenate matrices with the same number of rows column-wise.
+pub fn hstack_csr(mats: &[CsrMatrix<f64>]) -> Result<CsrMatrix<f64>> {
+    let n_rows = mats.first().map_or(0, |x| x.nrows());
+    ensure!(
+        mats.iter().all(|x| x.nrows() == n_rows),
+        "matrices must have the same number of rows"
+    );
+    let n_cols = mats.iter().map(|x| x.ncols()).sum();
+    let (mut offsets, mut indices, mut values) = (vec![0], Vec::new(), Vec::new());
+    for i in 0..n_rows {
+        let mut shift = 0;
+        for mat in mats {
+            let row = mat.row(i);
+            indices.extend(row.col_indices().iter().map(|j| j + shift));
+            values.extend_from_slice(row.values());
+            shift += mat.ncols();
+        }
+        offsets.push(indices.len());
+    }
+    Ok(CsrMatrix::try_from_csr_data(n_rows, n_cols, offsets, indices, values)?)
+}
+
+/// Names of the features of the concatenated matrix. If `prefix` is true,
+/// names are prefixed by the key of their feature space, e.g., "motif:CTCF";
+/// otherwise the names must be unique across feature spaces.
+pub fn stacked_var_names(keys: &[String], names: &[Vec<String>], prefix: bool) -> Result<Vec<String>> {
+    let result: Vec<String> = keys
+        .iter()
+        .zip(names)
+        .flat_map(|(key, names)| {
+            names.iter().map(move |x| if prefix { format!("{}:{}", key, x) } else { x.clone() })
+        })
+        .collect();
+    let mut seen = HashSet::new();
+    if let Some(dup) = result.iter().find(|x| !seen.insert(x.as_str())) {
+        bail!("duplicated feature name: '{}', use distinct keys or prefixes", dup);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_hstack() {
+        let a = CsrMatrix::try_from_csr_data(2, 2, vec![0, 1, 2], vec![1, 0], vec![1.0, 2.0]).unwrap();
+        let b = to_csr(ArrayData::from(array![[0.0, 3.0, 0.0], [4.0, 0.0, 5.0]])).unwrap();
+        let c = hstack_csr(&[a, b]).unwrap();
+        assert_eq!(c.ncols(), 5);
+        assert_eq!(c.row_offsets(), &[0, 2, 5]);
+        assert_eq!(c.col_indices(), &[1, 3, 0, 2, 4]);
+        assert_eq!(c.values(), &[1.0, 3.0, 2.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn test_var_names() {
+        let keys = vec!["tile".to_string(), "motif".to_string()];
+        let names = vec![vec!["a".to_string()], vec!["a".to_string()]];
+        assert_eq!(stacked_var_names(&keys, &names, true).unwrap(), vec!["tile:a", "motif:a"]);
+        assert!(stacked_var_names(&keys, &names, false).is_err());
+    }
+}
@@ -0,0 +1,573 @@
+//! On-disk formats of the fragments stored in `.obsm`.
+//!
+//! In version 1, the fragments of cell `i` are stored in row `i` of a CSR
+//! matrix whose columns are the base positions of the genome, i.e., the
+//! chromosomes in `.uns["reference_sequences"]` concatenated end to end. The
+//! column of a fragment is its start (or the 5' end of a reverse read), and
+//! the value is its size (negative for reverse reads).
+//!
+//! Version 2 stores the same information more compactly. The column of a
+//! fragment is the index of its chromosome, and the value is a 64-bit integer
+//! whose upper 48 bits hold the distance to the start of the previous
+//! fragment of the same cell on the same chromosome (the start itself for the
+//! first one), and whose lower 16 bits hold the size. For single-end reads,
+//! bit 15 is set for reverse reads and bits 0-14 hold the read length.
+//! Deltas and runs of identical chromosome indices compress much better than
+//! absolute genomic positions.
+//!
+//! Fragments are decoded directly from version 2 matrices when they are
+//! iterated, so every function reading fragments supports both formats.
+//! Version 1 matrices are only reconstructed, chunk by chunk, for the few
+//! consumers working on the matrices themselves, see
+//! [`super::FragmentData::into_inner`].
+
+use anndata::data::CsrNonCanonical;
+use anndata::{AnnDataOp, ArrayData, AxisArraysOp};
+use anyhow::{bail, ensure, Context, Result};
+use indicatif::{style::ProgressStyle, ProgressIterator};
+use std::str::FromStr;
+
+use super::{fragment_key, SnapData, FRAGMENT_PAIRED, FRAGMENT_SINGLE};
+use crate::genome::ChromSizes;
+use crate::recovery::{self, Element};
+
+/// Key for storing single-end fragment data in the version 2 format.
+pub const FRAGMENT_SINGLE_V2: &str = "fragment_single_v2";
+
+/// Key for storing paired-end fragment data in the version 2 format.
+pub const FRAGMENT_PAIRED_V2: &str = "fragment_paired_v2";
+
+const MAX_DELTA: u64 = (1 << 48) - 1;
+
+/// Suffix of the temporary `.obsm` entry holding the compacted fragments until
+/// they replace the original entry.
+const COMPACTION_SUFFIX: &str = "_compacted";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentStorage {
+    V1,
+    V2,
+}
+
+impl FromStr for FragmentStorage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "v1" => Ok(FragmentStorage::V1),
+            "v2" => Ok(FragmentStorage::V2),
+            _ => Err(format!("unknown fragment storage format: {}", s)),
+        }
+    }
+}
+
+/// Fragment sizes as stored in version 1 matrices.
+trait FragmentSize: Copy + std::fmt::Display {
+    fn encode(self) -> Option<u16>;
+    fn decode(x: u16) -> Self;
+}
+
+impl FragmentSize for u32 {
+    fn encode(self) -> Option<u16> {
+        u16::try_from(self).ok().filter(|x| *x > 0)
+    }
+
+    fn decode(x: u16) -> Self {
+        x as u32
+    }
+}
+
+impl FragmentSize for i32 {
+    fn encode(self) -> Option<u16> {
+        let length = u16::try_from(self.unsigned_abs()).ok().filter(|x| *x > 0 && *x < 0x8000)?;
+        Some(if self < 0 { length | 0x8000 } else { length })
+    }
+
+    fn decode(x: u16) -> Self {
+        let length = (x & 0x7FFF) as i32;
+        if x & 0x8000 != 0 {
+            -length
+        } else {
+            length
+        }
+    }
+}
+
+/// Offsets of the chromosomes in the columns of version 1 matrices.
+struct Layout {
+    offsets: Vec<usize>,
+    genome_size: usize,
+}
+
+impl Layout {
+    fn new(chrom_sizes: &ChromSizes) -> Self {
+        let mut genome_size = 0;
+        let offsets = chrom_sizes
+            .into_iter()
+            .map(|(_, size)| {
+                let offset = genome_size;
+                genome_size += *size as usize;
+                offset
+            })
+            .collect();
+        Self { offsets, genome_size }
+    }
+}
+
+fn encode<V: FragmentSize>(mat: &CsrNonCanonical<V>, layout: &Layout) -> Result<CsrNonCanonical<u64>> {
+    let row_offsets = mat.row_offsets();
+    let col_indices = mat.col_indices();
+    let values = mat.values();
+    let mut indices = Vec::with_capacity(values.len());
+    let mut data = Vec::with_capacity(values.len());
+    for i in 0..row_offsets.len() - 1 {
+        let mut prev: Option<(usize, u64)> = None;
+        for j in row_offsets[i]..row_offsets[i + 1] {
+            let pos = col_indices[j];
+            ensure!(pos < layout.genome_size, "position {} is beyond the end of the genome", pos);
+            let chrom = layout.offsets.partition_point(|x| *x <= pos) - 1;
+            let start = (pos - layout.offsets[chrom]) as u64;
+            let delta = match prev {
+                Some((c, s)) if c == chrom => start
+                    .checked_sub(s)
+                    .context("fragments must be sorted by position within each cell")?,
+                _ => start,
+            };
+            ensure!(delta <= MAX_DELTA, "position {} cannot be stored in the v2 format", pos);
+            let size = values[j]
+                .encode()
+                .with_context(|| format!("fragment size {} cannot be stored in the v2 format", values[j]))?;
+            indices.push(chrom);
+            data.push((delta << 16) | size as u64);
+            prev = Some((chrom, start));
+        }
+    }
+    Ok(CsrNonCanonical::from_csr_data(
+        row_offsets.len() - 1,
+        layout.offsets.len(),
+        row_offsets.to_vec(),
+        indices,
+        data,
+    ))
+}
+
+/// Decode the fragments of a row of a version 2 matrix into their chromosome
+/// indices, start positions and encoded sizes.
+pub(crate) fn decode_row<'a>(
+    col_indices: &'a [usize],
+    values: &'a [u64],
+) -> impl Iterator<Item = (usize, u64, u16)> + 'a {
+    let mut prev: Option<(usize, u64)> = None;
+    col_indices.iter().zip(values).map(move |(&chrom, &x)| {
+        let delta = x >> 16;
+        let start = match prev {
+            Some((c, s)) if c == chrom => s + delta,
+            _ => delta,
+        };
+        prev = Some((chrom, start));
+        (chrom, start, x as u16)
+    })
+}
+
+/// The signed size of a single-end read encoded in the version 2 format:
+/// negative for reverse reads.
+pub(crate) fn single_read_size(x: u16) -> i32 {
+    i32::decode(x)
+}
+
+fn decode<V: FragmentSize>(mat: &CsrNonCanonical<u64>, layout: &Layout) -> CsrNonCanonical<V> {
+    let row_offsets = mat.row_offsets();
+    let col_indices = mat.col_indices();
+    let values = mat.values();
+    let mut indices = Vec::with_capacity(values.len());
+    let mut data = Vec::with_capacity(values.len());
+    for i in 0..row_offsets.len() - 1 {
+        let range = row_offsets[i]..row_offsets[i + 1];
+        for (chrom, start, size) in decode_row(&col_indices[range.clone()], &values[range]) {
+            // Invalid chromosome indices are mapped beyond the end of the
+            // genome, where they are caught by the consumers.
+            indices.push(layout.offsets.get(chrom).map_or(layout.genome_size, |x| x + start as usize));
+            data.push(V::decode(size));
+        }
+    }
+    CsrNonCanonical::from_csr_data(
+        row_offsets.len() - 1,
+        layout.genome_size,
+        row_offsets.to_vec(),
+        indices,
+        data,
+    )
+}
+
+/// Decode chunks of single-end fragments stored in the version 2 format.
+pub fn decode_single<I>(
+    chunks: I,
+    chrom_sizes: &ChromSizes,
+) -> impl ExactSizeIterator<Item = (CsrNonCanonical<i32>, usize, usize)> + Sync
+where
+    I: ExactSizeIterator<Item = (CsrNonCanonical<u64>, usize, usize)> + Sync,
+{
+    let layout = Layout::new(chrom_sizes);
+    chunks.map(move |(mat, a, b)| (decode(&mat, &layout), a, b))
+}
+
+/// Decode chunks of paired-end fragments stored in the version 2 format.
+pub fn decode_paired<I>(
+    chunks: I,
+    chrom_sizes: &ChromSizes,
+) -> impl ExactSizeIterator<Item = (CsrNonCanonical<u32>, usize, usize)> + Sync
+where
+    I: ExactSizeIterator<Item = (CsrNonCanonical<u64>, usize, usize)> + Sync,
+{
+    let layout = Layout::new(chrom_sizes);
+    chunks.map(move |(mat, a, b)| (decode(&mat, &layout), a, b))
+}
+
+/// The format of the fragments of `modality` stored in `adata`, if any. See
+/// [`fragment_key`].
+pub fn fragment_storage<A: AnnDataOp>(adata: &A, modality: Option<&str>) -> Option<FragmentStorage> {
+    let keys = adata.obsm().keys();
+    let has = |key: &str| keys.contains(&fragment_key(key, modality));
+    if has(FRAGMENT_SINGLE) || has(FRAGMENT_PAIRED) {
+        Some(FragmentStorage::V1)
+    } else if has(FRAGMENT_SINGLE_V2) || has(FRAGMENT_PAIRED_V2) {
+        Some(FragmentStorage::V2)
+    } else {
+        None
+    }
+}
+
+/// The key of the fragments without modality that `.obsm[key]` stores, if any.
+fn base_key(key: &str) -> Option<&'static str> {
+    let base = key.split_once(':').map_or(key, |(x, _)| x);
+    [FRAGMENT_SINGLE, FRAGMENT_PAIRED, FRAGMENT_SINGLE_V2, FRAGMENT_PAIRED_V2]
+        .into_iter()
+        .find(|x| *x == base)
+}
+
+fn add_guarded<A, I>(adata: &A, key: &str, chunks: I) -> Result<()>
+where
+    A: AnnDataOp,
+    I: Iterator<Item = ArrayData>,
+{
+    recovery::guarded(adata, Element::Obsm(key.to_string()), || adata.obsm().add_iter(key, chunks))
+}
+
+/// Convert the fragments of `modality` stored in `adata` to the given format,
+/// replacing the original `.obsm` entry. Returns `false` if the fragments are
+/// already stored in this format.
+pub fn convert_fragment_storage<A: SnapData>(
+    adata: &A,
+    format: FragmentStorage,
+    chunk_size: usize,
+    modality: Option<&str>,
+) -> Result<bool> {
+    let current = fragment_storage(adata, modality).context("no fragments are stored in '.obsm'")?;
+    if current == format {
+        return Ok(false);
+    }
+    let layout = Layout::new(&adata.read_chrom_sizes()?);
+    let obsm = adata.obsm();
+    let keys = obsm.keys();
+    let (from, to) = [(FRAGMENT_SINGLE, FRAGMENT_SINGLE_V2), (FRAGMENT_PAIRED, FRAGMENT_PAIRED_V2)]
+        .into_iter()
+        .map(|(v1, v2)| if format == FragmentStorage::V2 { (v1, v2) } else { (v2, v1) })
+        .find(|(from, _)| keys.contains(&fragment_key(from, modality)))
+        .context("no fragments are stored in '.obsm'")?;
+    let (from_key, to_key) = (fragment_key(from, modality), fragment_key(to, modality));
+
+    // Encoding errors stop the iteration, which `add_iter` may report as a
+    // different error, so the encoding error takes precedence.
+    let mut error = None;
+    let mut capture = |x: Result<CsrNonCanonical<u64>>| x.map_err(|e| error = Some(e)).ok();
+    macro_rules! convert {
+        ($ty:ty, $f:expr) => {{
+            let iter = obsm
+                .get_item_iter::<CsrNonCanonical<$ty>>(&from_key, chunk_size)
+                .with_context(|| format!("cannot read '.obsm[\"{}\"]'", from_key))?;
+            add_guarded(adata, &to_key, iter.map_while($f))
+        }};
+    }
+    let result = match from {
+        FRAGMENT_SINGLE => convert!(i32, |(mat, _, _)| capture(encode(&mat, &layout)).map(ArrayData::from)),
+        FRAGMENT_PAIRED => convert!(u32, |(mat, _, _)| capture(encode(&mat, &layout)).map(ArrayData::from)),
+        FRAGMENT_SINGLE_V2 => convert!(u64, |(mat, _, _)| Some(ArrayData::from(decode::<i32>(&mat, &layout)))),
+        _ => convert!(u64, |(mat, _, _)| Some(ArrayData::from(decode::<u32>(&mat, &layout)))),
+    };
+    let result = match error {
+        Some(e) => Err(e),
+        None => result,
+    };
+    if let Err(e) = result {
+        // Keep the original data if the conversion failed.
+        recovery::discard(adata, Element::Obsm(to_key))?;
+        return Err(e);
+    }
+    obsm.remove(&from_key)?;
+    Ok(true)
+}
+
+/// Sort the fragments of every row by position and then by size. `n_unsorted`
+/// is incremented by the number of rows that were out of order.
+fn sort_rows<V: Copy + Ord>(mat: &CsrNonCanonical<V>, n_unsorted: &mut usize) -> CsrNonCanonical<V> {
+    let row_offsets = mat.row_offsets();
+    let mut indices = mat.col_indices().to_vec();
+    let mut data = mat.values().to_vec();
+    for i in 0..row_offsets.len() - 1 {
+        let (lo, hi) = (row_offsets[i], row_offsets[i + 1]);
+        let mut row: Vec<_> = indices[lo..hi].iter().copied().zip(data[lo..hi].iter().copied()).collect();
+        if row.windows(2).all(|w| w[0] <= w[1]) {
+            continue;
+        }
+        *n_unsorted += 1;
+        row.sort_unstable();
+        row.into_iter().enumerate().for_each(|(k, (c, v))| {
+            indices[lo + k] = c;
+            data[lo + k] = v;
+        });
+    }
+    CsrNonCanonical::from_csr_data(
+        row_offsets.len() - 1,
+        mat.ncols(),
+        row_offsets.to_vec(),
+        indices,
+        data,
+    )
+}
+
+/// Rewrite the fragments of `modality` stored in `adata` in coordinate-sorted
+/// chunks of `chunk_size` cells, keeping their storage format. Fragments
+/// become out of order after repeated appends and subsets, which hurts the
+/// locality of iteration and prevents the conversion to the version 2 format.
+///
+/// The compacted fragments are first written to a temporary `.obsm` entry, so
+/// the original entry is left untouched if the rewrite fails. A compaction
+/// interrupted while the temporary entry replaced the original one is
+/// completed first, see [`finish_compaction`]. Returns the number of cells
+/// whose fragments were reordered.
+pub fn compact_fragment_storage<A: SnapData>(
+    adata: &A,
+    chunk_size: usize,
+    modality: Option<&str>,
+) -> Result<usize> {
+    ensure!(chunk_size > 0, "chunk_size must be positive");
+    let layout = Layout::new(&adata.read_chrom_sizes()?);
+    finish_compaction(adata, chunk_size)?;
+    let obsm = adata.obsm();
+    let keys = obsm.keys();
+    let base = [FRAGMENT_SINGLE, FRAGMENT_PAIRED, FRAGMENT_SINGLE_V2, FRAGMENT_PAIRED_V2]
+        .into_iter()
+        .find(|k| keys.contains(&fragment_key(k, modality)))
+        .context("no fragments are stored in '.obsm'")?;
+    let key = fragment_key(base, modality);
+    let tmp = format!("{}{}", key, COMPACTION_SUFFIX);
+    if obsm.keys().contains(&tmp) {
+        // Partly written by an interrupted compaction.
+        recovery::guarded(adata, Element::Obsm(tmp.clone()), || obsm.remove(&tmp))?;
+    }
+    let style = ProgressStyle::with_template(
+        "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})",
+    )?;
+
+    let mut n_unsorted = 0;
+    let mut error = None;
+    let mut capture = |x: Result<CsrNonCanonical<u64>>| x.map_err(|e| error = Some(e)).ok();
+    let result = recovery::guarded(adata, Element::Obsm(tmp.clone()), || match base {
+        FRAGMENT_SINGLE => {
+            let iter = obsm
+                .get_item_iter::<CsrNonCanonical<i32>>(&key, chunk_size)
+                .with_context(|| format!("cannot read '.obsm[\"{}\"]'", key))?;
+            let chunks = iter
+                .progress_with_style(style)
+                .map(|(mat, _, _)| sort_rows(&mat, &mut n_unsorted));
+            obsm.add_iter(&tmp, chunks.map(ArrayData::from))
+        }
+        FRAGMENT_PAIRED => {
+            let iter = obsm
+                .get_item_iter::<CsrNonCanonical<u32>>(&key, chunk_size)
+                .with_context(|| format!("cannot read '.obsm[\"{}\"]'", key))?;
+            let chunks = iter
+                .progress_with_style(style)
+                .map(|(mat, _, _)| sort_rows(&mat, &mut n_unsorted));
+            obsm.add_iter(&tmp, chunks.map(ArrayData::from))
+        }
+        FRAGMENT_SINGLE_V2 => {
+            let iter = obsm
+                .get_item_iter::<CsrNonCanonical<u64>>(&key, chunk_size)
+                .with_context(|| format!("cannot read '.obsm[\"{}\"]'", key))?;
+            let chunks = iter.progress_with_style(style).map_while(|(mat, _, _)| {
+                let sorted = sort_rows(&decode::<i32>(&mat, &layout), &mut n_unsorted);
+                capture(encode(&sorted, &layout))
+            });
+            obsm.add_iter(&tmp, chunks.map(ArrayData::from))
+        }
+        _ => {
+            let iter = obsm
+                .get_item_iter::<CsrNonCanonical<u64>>(&key, chunk_size)
+                .with_context(|| format!("cannot read '.obsm[\"{}\"]'", key))?;
+            let chunks = iter.progress_with_style(style).map_while(|(mat, _, _)| {
+                let sorted = sort_rows(&decode::<u32>(&mat, &layout), &mut n_unsorted);
+                capture(encode(&sorted, &layout))
+            });
+            obsm.add_iter(&tmp, chunks.map(ArrayData::from))
+        }
+    });
+    // An encoding error stops the iteration and takes precedence over the
+    // error it may cause in `add_iter`.
+    let result = match error {
+        Some(e) => Err(e),
+        None => result,
+    };
+    if let Err(e) = result {
+        recovery::discard(adata, Element::Obsm(tmp))?;
+        return Err(e);
+    }
+    move_compacted(adata, &key, chunk_size)?;
+    Ok(n_unsorted)
+}
+
+/// Replace `.obsm[key]` by the complete compacted copy of the fragments.
+fn move_compacted<A: AnnDataOp + ?Sized>(adata: &A, key: &str, chunk_size: usize) -> Result<()> {
+    let obsm = adata.obsm();
+    let tmp = format!("{}{}", key, COMPACTION_SUFFIX);
+    if obsm.keys().iter().any(|x| x == key) {
+        obsm.remove(key)?;
+    }
+    macro_rules! move_item {
+        ($ty:ty) => {{
+            let iter = obsm
+                .get_item_iter::<CsrNonCanonical<$ty>>(&tmp, chunk_size)
+                .with_context(|| format!("cannot read '.obsm[\"{}\"]'", tmp))?;
+            obsm.add_iter(key, iter.map(|(mat, _, _)| ArrayData::from(mat)))
+        }};
+    }
+    recovery::guarded(adata, Element::Obsm(key.to_string()), || match base_key(key) {
+        Some(FRAGMENT_SINGLE) => move_item!(i32),
+        Some(FRAGMENT_PAIRED) => move_item!(u32),
+        _ => move_item!(u64),
+    })
+    .with_context(|| format!("failed to restore '.obsm[\"{}\"]', the fragments are kept in '.obsm[\"{}\"]'", key, tmp))?;
+    obsm.remove(&tmp)
+}
+
+/// Complete a compaction that was interrupted after the compacted fragments
+/// had been fully written. Returns the key of the restored entry, if any.
+pub fn finish_compaction<A: AnnDataOp + ?Sized>(adata: &A, chunk_size: usize) -> Result<Option<String>> {
+    let pending = recovery::pending(adata)?;
+    for tmp in adata.obsm().keys() {
+        let Some(key) = tmp.strip_suffix(COMPACTION_SUFFIX) else { continue };
+        if base_key(key).is_some() && !pending.contains(&Element::Obsm(tmp.clone())) {
+            move_compacted(adata, key, chunk_size)?;
+            return Ok(Some(key.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout() -> Layout {
+        Layout::new(&[("chr1", 1000), ("chr2", 500), ("chr3", 2000)].into_iter().collect())
+    }
+
+    #[test]
+    fn test_roundtrip_paired() {
+        // Cell 1 has fragments on chr1 and chr3, cell 2 has no fragments,
+        // cell 3 has duplicated fragments on chr2.
+        let mat = CsrNonCanonical::from_csr_data(
+            3,
+            3500,
+            vec![0, 3, 3, 5],
+            vec![10, 990, 1600, 1200, 1200],
+            vec![50u32, 10, 300, 65535, 65535],
+        );
+        let encoded = encode(&mat, &layout()).unwrap();
+        assert_eq!(encoded.col_indices(), &[0, 0, 2, 1, 1]);
+        assert_eq!(encoded.values()[1], (980 << 16) | 10);
+        let decoded: CsrNonCanonical<u32> = decode(&encoded, &layout());
+        assert_eq!(decoded.row_offsets(), mat.row_offsets());
+        assert_eq!(decoded.col_indices(), mat.col_indices());
+        assert_eq!(decoded.values(), mat.values());
+    }
+
+    #[test]
+    fn test_roundtrip_single() {
+        let mat = CsrNonCanonical::from_csr_data(1, 3500, vec![0, 3], vec![5, 1499, 1500], vec![-36i32, 50, 32767]);
+        let decoded: CsrNonCanonical<i32> = decode(&encode(&mat, &layout()).unwrap(), &layout());
+        assert_eq!(decoded.col_indices(), mat.col_indices());
+        assert_eq!(decoded.values(), mat.values());
+    }
+
+    #[test]
+    fn test_decode_fragments() {
+        use crate::feature_count::{CompressedFragmentIter, EncodedFragmentIter, FragmentData};
+
+        let chrom_sizes: ChromSizes = [("chr1", 1000), ("chr2", 500), ("chr3", 2000)].into_iter().collect();
+        let to_strings = |data: FragmentData| -> Vec<Vec<String>> {
+            data.into_fragments()
+                .flat_map(|(x, _, _)| x)
+                .map(|x| x.iter().map(|f| f.to_string()).collect())
+                .collect()
+        };
+
+        let paired = CsrNonCanonical::from_csr_data(
+            2,
+            3500,
+            vec![0, 3, 5],
+            vec![10, 990, 1600, 1200, 1200],
+            vec![50u32, 10, 300, 65535, 65535],
+        );
+        let encoded = encode(&paired, &layout()).unwrap();
+        let v1 = FragmentData::new(
+            chrom_sizes.clone(),
+            CompressedFragmentIter::FragmentPaired(Box::new(vec![(paired, 0, 2)].into_iter())),
+        );
+        let v2 = FragmentData::new(
+            chrom_sizes.clone(),
+            EncodedFragmentIter::FragmentPaired(Box::new(vec![(encoded, 0, 2)].into_iter())),
+        );
+        assert_eq!(to_strings(v1), to_strings(v2));
+
+        let single = CsrNonCanonical::from_csr_data(1, 3500, vec![0, 3], vec![5, 1499, 1500], vec![50i32, -36, 32767]);
+        let encoded = encode(&single, &layout()).unwrap();
+        let v1 = FragmentData::new(
+            chrom_sizes.clone(),
+            CompressedFragmentIter::FragmentSingle(Box::new(vec![(single, 0, 1)].into_iter())),
+        );
+        let v2 = FragmentData::new(
+            chrom_sizes,
+            EncodedFragmentIter::FragmentSingle(Box::new(vec![(encoded, 0, 1)].into_iter())),
+        );
+        assert_eq!(to_strings(v1), to_strings(v2));
+    }
+
+    #[test]
+    fn test_sort_rows() {
+        let mat = CsrNonCanonical::from_csr_data(
+            3,
+            3500,
+            vec![0, 3, 5, 7],
+            vec![1600, 10, 990, 5, 20, 30, 30],
+            vec![300u32, 50, 10, 1, 2, 8, 4],
+        );
+        let mut n_unsorted = 0;
+        let sorted = sort_rows(&mat, &mut n_unsorted);
+        assert_eq!(n_unsorted, 2);
+        assert_eq!(sorted.row_offsets(), mat.row_offsets());
+        assert_eq!(sorted.col_indices(), &[10, 990, 1600, 5, 20, 30, 30]);
+        assert_eq!(sorted.values(), &[50, 10, 300, 1, 2, 4, 8]);
+        assert!(encode(&sorted, &layout()).is_ok());
+    }
+
+    #[test]
+    fn test_encode_invalid() {
+        let unsorted = CsrNonCanonical::from_csr_data(1, 3500, vec![0, 2], vec![20, 10], vec![5u32, 5]);
+        assert!(encode(&unsorted, &layout()).is_err());
+        let too_long = CsrNonCanonical::from_csr_data(1, 3500, vec![0, 1], vec![20], vec![70000u32]);
+        assert!(encode(&too_long, &layout()).is_err());
+        let beyond = CsrNonCanonical::from_csr_data(1, 3500, vec![0, 1], vec![3500], vec![5u32]);
+        assert!(encode(&beyond, &layout()).is_err());
+    }
+}
@@ -0,0 +1,104 @@
+//! Custom per-fragment statistics.
+//!
+//! A [`FragmentVisitor`] receives every fragment along with the index of its
+//! cell. [`FragmentData::visit`] runs any number of visitors in a single pass
+//! over the fragments, so that downstream crates can compute their own
+//! per-cell statistics without re-implementing the decoding of the fragment
+//! storage, the chromosome filter, the cell mask or the fragment size limits.
+//! Closures taking `(usize, &Fragment)` are visitors too.
+
+use anyhow::Result;
+
+use super::{FragmentData, SnapData};
+use crate::preprocessing::Fragment;
+
+/// A statistic computed from the fragments of every cell.
+pub trait FragmentVisitor {
+    /// Called for every fragment. Cells are visited in increasing order of
+    /// their index, and the fragments of a cell are consecutive.
+    fn on_fragment(&mut self, cell_idx: usize, fragment: &Fragment);
+
+    /// Called after the last fragment of every cell, including the cells
+    /// without fragments.
+    fn on_cell_end(&mut self, _cell_idx: usize) {}
+}
+
+impl<F: FnMut(usize, &Fragment)> FragmentVisitor for F {
+    fn on_fragment(&mut self, cell_idx: usize, fragment: &Fragment) {
+        self(cell_idx, fragment)
+    }
+}
+
+impl FragmentData {
+    /// Run the visitors in a single pass over the fragments.
+    pub fn visit(self, visitors: &mut [&mut dyn FragmentVisitor]) {
+        visit_chunks(self.into_fragments(), visitors)
+    }
+}
+
+/// Run the visitors in a single pass over the fragments of `adata`.
+pub fn visit_fragments<A: SnapData>(
+    adata: &A,
+    visitors: &mut [&mut dyn FragmentVisitor],
+) -> Result<()> {
+    adata
+        .get_fragment_iter(adata.fragment_chunk_size()?)?
+        .visit(visitors);
+    Ok(())
+}
+
+fn visit_chunks<I>(chunks: I, visitors: &mut [&mut dyn FragmentVisitor])
+where
+    I: Iterator<Item = (Vec<Vec<Fragment>>, usize, usize)>,
+{
+    chunks.for_each(|(cells, start, _)| {
+        cells.into_iter().enumerate().for_each(|(i, fragments)| {
+            let cell_idx = start + i;
+            visitors.iter_mut().for_each(|v| {
+                fragments.iter().for_each(|x| v.on_fragment(cell_idx, x));
+                v.on_cell_end(cell_idx);
+            });
+        })
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preprocessing::PairRead;
+    use bed_utils::bed::BEDLike;
+
+    /// Length of the longest fragment of every cell.
+    struct MaxLength(Vec<u64>);
+
+    impl FragmentVisitor for MaxLength {
+        fn on_fragment(&mut self, _cell_idx: usize, fragment: &Fragment) {
+            let len = fragment.len();
+            if let Some(x) = self.0.last_mut() {
+                *x = (*x).max(len);
+            }
+        }
+
+        fn on_cell_end(&mut self, _cell_idx: usize) {
+            self.0.push(0);
+        }
+    }
+
+    #[test]
+    fn test_visit_chunks() {
+        let frag = |start, end| -> Fragment { PairRead::new("chr1", start, end).into() };
+        let chunks = vec![
+            (vec![vec![frag(0, 10), frag(5, 50)], vec![]], 0, 2),
+            (vec![vec![frag(20, 40)]], 2, 3),
+        ];
+
+        let mut max_length = MaxLength(vec![0]);
+        let mut counts = vec![0; 3];
+        let mut count = |i: usize, _: &Fragment| counts[i] += 1;
+        visit_chunks(chunks.into_iter(), &mut [&mut max_length, &mut count]);
+
+        max_length.0.pop();
+        assert_eq!(max_length.0, vec![45, 0, 20]);
+        assert_eq!(counts, vec![2, 0, 1]);
+    }
+}
@@ -0,0 +1,219 @@
+//! Differential footprinting around anchor sites.
+//!
+//! DNA bound by a protein is protected from Tn5, so that the insertions
+//! aggregated over the binding sites of a transcription factor show a dip at
+//! the motif, the footprint, flanked by accessible DNA. To compare the
+//! footprints of two groups of cells, the insertions are counted at every
+//! offset from the anchors of each motif for each group, which allows testing
+//! every position, and within and around the center of the anchors for each
+//! cell, which allows testing the depth of the footprint by permuting cells.
+//! As Tn5 prefers some sequences, the depth can be corrected by the expected
+//! insertion bias around the anchors, see [`expected_bias`].
+
+use anyhow::{ensure, Result};
+use bed_utils::bed::{map::GIntervalMap, BEDLike, GenomicRange, Strand};
+use ndarray::Array2;
+use std::io::{BufRead, Seek};
+
+use crate::bias::{BiasModel, GenomeSequence};
+use crate::feature_count::SnapData;
+use crate::nucleosome::Anchor;
+
+/// Insertions around the anchors of a motif.
+#[derive(Debug, Clone)]
+pub struct FootprintCounts {
+    /// Insertions of the two groups of cells (rows) at every offset from the
+    /// anchors (columns), from `-flank` to `flank`.
+    pub profiles: Array2<u64>,
+    /// Insertions within `center` bases of the anchors of every cell of the
+    /// two groups, in the order of the cells.
+    pub center: Vec<u32>,
+    /// Insertions in the rest of the windows, i.e., the flanks, of every cell
+    /// of the two groups, in the order of the cells.
+    pub flanks: Vec<u32>,
+}
+
+struct FootprintCounter<'a> {
+    motifs: &'a [Vec<Anchor>],
+    index: GIntervalMap<(usize, usize)>,
+    flank: u64,
+    center: u64,
+    counts: Vec<FootprintCounts>,
+}
+
+impl<'a> FootprintCounter<'a> {
+    fn new(motifs: &'a [Vec<Anchor>], n_cells: usize, flank: u64, center: u64) -> Self {
+        let index = motifs
+            .iter()
+            .enumerate()
+            .flat_map(|(m, anchors)| {
+                anchors.iter().enumerate().map(move |(i, x)| {
+                    let region =
+                        GenomicRange::new(&x.chrom, x.pos.saturating_sub(flank), x.pos + flank + 1);
+                    (region, (m, i))
+                })
+            })
+            .collect();
+        let counts = motifs
+            .iter()
+            .map(|_| FootprintCounts {
+                profiles: Array2::zeros((2, 2 * flank as usize + 1)),
+                center: vec![0; n_cells],
+                flanks: vec![0; n_cells],
+            })
+            .collect();
+        Self {
+            motifs,
+            index,
+            flank,
+            center,
+            counts,
+        }
+    }
+
+    /// Add an insertion of `cell`, the index of the cell among the cells of
+    /// the two groups, which belongs to `group`, at `pos`.
+    fn add(&mut self, cell: usize, group: usize, chrom: &str, pos: u64) {
+        let query = GenomicRange::new(chrom, pos, pos + 1);
+        self.index.find(&query).for_each(|(_, (m, i))| {
+            let anchor = &self.motifs[*m][*i];
+            let offset = if anchor.reverse {
+                anchor.pos as i64 - pos as i64
+            } else {
+                pos as i64 - anchor.pos as i64
+            };
+            let counts = &mut self.counts[*m];
+            counts.profiles[[group, (offset + self.flank as i64) as usize]] += 1;
+            if offset.unsigned_abs() <= self.center {
+                counts.center[cell] += 1;
+            } else {
+                counts.flanks[cell] += 1;
+            }
+        });
+    }
+}
+
+/// Count the insertions around the anchors of every motif. `groups` gives the
+/// group, 0 or 1, of each cell, or `None` for the cells left out. Offsets are
+/// measured along the strand of the anchors, and insertions within `flank`
+/// bases of several anchors are counted once for each.
+pub fn footprint_counts<A: SnapData>(
+    adata: &A,
+    motifs: &[Vec<Anchor>],
+    groups: &[Option<usize>],
+    flank: u64,
+    center: u64,
+) -> Result<Vec<FootprintCounts>> {
+    ensure!(adata.n_obs() == groups.len(), "lengths differ");
+    ensure!(
+        groups.iter().flatten().all(|x| *x < 2),
+        "cells must belong to group 0 or 1"
+    );
+    ensure!(center < flank, "the center must be narrower than the flanks");
+    // Index of every cell among the cells of the two groups.
+    let cells: Vec<Option<usize>> = groups
+        .iter()
+        .scan(0, |n, g| {
+            Some(g.map(|_| {
+                *n += 1;
+                *n - 1
+            }))
+        })
+        .collect();
+    let n_cells = cells.iter().flatten().count();
+    let mut counter = FootprintCounter::new(motifs, n_cells, flank, center);
+    let fragments = adata.get_fragment_iter(adata.fragment_chunk_size()?)?;
+    fragments.into_fragments().for_each(|(values, start, _)| {
+        values.into_iter().enumerate().for_each(|(i, frags)| {
+            if let (Some(group), Some(cell)) = (groups[start + i], cells[start + i]) {
+                frags.into_iter().for_each(|frag| {
+                    frag.to_insertions()
+                        .into_iter()
+                        .for_each(|x| counter.add(cell, group, x.chrom(), x.start()))
+                });
+            }
+        })
+    });
+    Ok(counter.counts)
+}
+
+/// Expected Tn5 insertion bias at every offset (columns), from `-flank` to
+/// `flank`, from the anchors of every motif (rows), i.e., the average over the
+/// anchors of the bias of the insertion sites on either strand. Anchors on
+/// chromosomes missing from the genome are ignored, and motifs without any
+/// anchor in the genome have a bias of 1.
+pub fn expected_bias<R: BufRead + Seek>(
+    model: &BiasModel,
+    genome: &mut GenomeSequence<R>,
+    motifs: &[Vec<Anchor>],
+    flank: u64,
+) -> Result<Array2<f64>> {
+    let mut result = Array2::from_elem((motifs.len(), 2 * flank as usize + 1), 1.0);
+    // Visit the anchors by chromosome, so that every chromosome is read once.
+    let mut anchors: Vec<(usize, &Anchor)> = motifs
+        .iter()
+        .enumerate()
+        .flat_map(|(m, anchors)| anchors.iter().map(move |x| (m, x)))
+        .collect();
+    anchors.sort_by(|a, b| a.1.chrom.cmp(&b.1.chrom));
+    let mut totals = Array2::<f64>::zeros(result.dim());
+    let mut n_anchors = vec![0usize; motifs.len()];
+    for (m, anchor) in anchors {
+        let seq = match genome.fetch(&anchor.chrom) {
+            Ok(seq) => seq,
+            Err(_) => continue,
+        };
+        n_anchors[m] += 1;
+        totals.row_mut(m).indexed_iter_mut().for_each(|(i, x)| {
+            let offset = i as i64 - flank as i64;
+            let pos = if anchor.reverse {
+                anchor.pos as i64 - offset
+            } else {
+                anchor.pos as i64 + offset
+            };
+            *x += if pos < 0 {
+                1.0
+            } else {
+                (model.bias_at(seq, pos as u64, Strand::Forward)
+                    + model.bias_at(seq, pos as u64, Strand::Reverse))
+                    / 2.0
+            };
+        });
+    }
+    n_anchors.iter().enumerate().filter(|(_, n)| **n > 0).for_each(|(m, n)| {
+        result.row_mut(m).assign(&(&totals.row(m) / *n as f64));
+    });
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_footprint_counter() {
+        let anchor = |pos, reverse| Anchor {
+            chrom: "chr1".to_string(),
+            pos,
+            reverse,
+        };
+        let motifs = vec![vec![anchor(1000, false), anchor(2000, true)], vec![anchor(1005, false)]];
+        let mut counter = FootprintCounter::new(&motifs, 2, 10, 2);
+        counter.add(0, 0, "chr1", 998);
+        counter.add(0, 0, "chr1", 2002);
+        counter.add(1, 1, "chr1", 1990);
+        counter.add(1, 1, "chr2", 1000);
+
+        let counts = &counter.counts[0];
+        // Offsets are reversed on the minus strand.
+        assert_eq!(counts.profiles[[0, 8]], 2);
+        assert_eq!(counts.profiles[[1, 20]], 1);
+        assert_eq!(counts.profiles.sum(), 3);
+        assert_eq!((counts.center.clone(), counts.flanks.clone()), (vec![2, 0], vec![0, 1]));
+
+        // The insertion at 998 is also near the anchor of the second motif.
+        let counts = &counter.counts[1];
+        assert_eq!(counts.profiles[[0, 3]], 1);
+        assert_eq!((counts.center.clone(), counts.flanks.clone()), (vec![0, 0], vec![1, 0]));
+    }
+}
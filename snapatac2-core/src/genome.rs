@@ -18,9 +18,11 @@
 //!
 //! The module aims to provide a comprehensive, efficient, and flexible way to handle and manipulate
 //! genomic feature counts in Rust.
+use crate::config::MissingChromPolicy;
 use anyhow::{bail, Context, Result};
 use bed_utils::bed::map::GIntervalIndexSet;
-use bed_utils::bed::GenomicRange;
+use bed_utils::bed::{BEDLike, GenomicRange};
+use itertools::Itertools;
 use indexmap::map::IndexMap;
 use indexmap::IndexSet;
 use noodles::gff::feature::record::Strand;
@@ -196,6 +198,46 @@ impl ChromSizes {
         self.0.get(chrom).copied()
     }
 
+    pub fn get_index_of(&self, chrom: &str) -> Option<usize> {
+        self.0.get_index_of(chrom)
+    }
+
+    /// Apply `policy` to the chromosomes of `records` missing from the sizes.
+    /// Returns the chromosome sizes to use: with the `Extend` policy, the
+    /// missing chromosomes are added with the largest end coordinate of their
+    /// records as their size.
+    pub fn resolve_missing<'a, B, I>(&self, records: I, policy: MissingChromPolicy) -> Result<ChromSizes>
+    where
+        B: BEDLike + 'a,
+        I: IntoIterator<Item = &'a B>,
+    {
+        let mut missing: IndexMap<String, u64> = IndexMap::new();
+        records.into_iter().for_each(|x| {
+            if self.get(x.chrom()).is_none() {
+                let size = missing.entry(x.chrom().to_string()).or_insert(0);
+                *size = (*size).max(x.end());
+            }
+        });
+        if missing.is_empty() {
+            return Ok(self.clone());
+        }
+        let names = missing.keys().join(", ");
+        match policy {
+            MissingChromPolicy::Error => bail!(
+                "chromosomes not found in the chromosome sizes: {}. Provide their sizes, or set the policy for missing chromosomes to 'skip' or 'extend'",
+                names
+            ),
+            MissingChromPolicy::Skip => {
+                log::warn!("records on chromosomes missing from the chromosome sizes are skipped: {}", names);
+                Ok(self.clone())
+            }
+            MissingChromPolicy::Extend => {
+                log::warn!("chromosomes missing from the chromosome sizes are added: {}", names);
+                Ok(self.0.clone().into_iter().chain(missing).collect())
+            }
+        }
+    }
+
     pub fn to_dataframe(&self) -> DataFrame {
         DataFrame::new(vec![
             Column::new(
@@ -312,6 +354,13 @@ impl GenomeBaseIndex {
             })
     }
 
+    /// The size of a chromosome in base pairs.
+    pub fn chrom_size(&self, chrom: &str) -> Option<u64> {
+        let i = self.chroms.get_index_of(chrom)?;
+        let prev = if i == 0 { 0 } else { self.base_accum_len[i - 1] };
+        Some(self.base_accum_len[i] - prev)
+    }
+
     /// Check if the index contains the given chromosome.
     pub fn contain_chrom(&self, chrom: &str) -> bool {
         self.chroms.contains(chrom)
@@ -444,9 +493,24 @@ impl GenomeBaseIndex {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bed_utils::bed::BEDLike;
     use std::str::FromStr;
 
+    #[test]
+    fn test_resolve_missing() {
+        let chrom_sizes: ChromSizes = [("chr1", 100)].into_iter().collect();
+        let records = vec![
+            GenomicRange::new("chr1", 0, 10),
+            GenomicRange::new("chrX", 0, 50),
+            GenomicRange::new("chrX", 60, 80),
+        ];
+        let skipped = chrom_sizes.resolve_missing(&records, MissingChromPolicy::Skip).unwrap();
+        assert_eq!(skipped, chrom_sizes);
+        assert!(chrom_sizes.resolve_missing(&records, MissingChromPolicy::Error).is_err());
+        assert!(chrom_sizes.resolve_missing(&records[..1], MissingChromPolicy::Error).is_ok());
+        let extended = chrom_sizes.resolve_missing(&records, MissingChromPolicy::Extend).unwrap();
+        assert_eq!(extended, [("chr1", 100), ("chrX", 80)].into_iter().collect());
+    }
+
     #[test]
     fn test_index1() {
         let chrom_sizes = vec![
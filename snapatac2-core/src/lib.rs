@@ -1,11 +1,28 @@
+pub mod config;
 pub mod genome;
 pub mod preprocessing;
+pub mod provenance;
+pub mod recovery;
 pub mod feature_count;
 pub mod export;
+pub mod bias;
+pub mod liftover;
+pub mod tile;
 pub mod motif;
 pub mod network;
+pub mod segmentation;
+pub mod nucleosome;
+pub mod footprint;
 pub mod embedding;
+pub mod metrics;
+pub mod clustering;
+pub mod model;
+pub mod pipeline;
+pub mod simulation;
+pub mod validation;
 pub mod utils;
+#[cfg(test)]
+mod test_support;
 
 pub use feature_count::SnapData;
 pub use preprocessing::QualityControl;
\ No newline at end of file
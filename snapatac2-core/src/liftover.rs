@@ -0,0 +1,184 @@
+//! Coordinate conversion between genome assemblies or species with UCSC chain
+//! files, as done by the `liftOver` tool.
+//!
+//! A chain aligns a part of the source genome to the target genome as a list
+//! of ungapped blocks. A region is mapped with the chain aligning the most of
+//! its bases, and the result spans the target positions of the aligned bases.
+
+use anyhow::{bail, ensure, Context, Result};
+use bed_utils::bed::{map::GIntervalMap, BEDLike, GenomicRange, Strand};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::utils::open_file_for_read;
+
+#[derive(Debug, Clone)]
+struct Chain {
+    target: String,
+    target_size: u64,
+    target_strand: Strand,
+    /// Ungapped blocks: start in the source, start in the target (on
+    /// `target_strand`), and size.
+    blocks: Vec<(u64, u64, u64)>,
+}
+
+impl Chain {
+    /// Map the bases of `[start, end)` aligned by the chain. Returns the
+    /// number of aligned bases and the span of their target positions on the
+    /// forward strand.
+    fn map(&self, start: u64, end: u64) -> Option<(u64, u64, u64)> {
+        let first = self.blocks.partition_point(|(s, _, size)| s + size <= start);
+        let mut n_aligned = 0;
+        let mut span: Option<(u64, u64)> = None;
+        for (s, t, size) in &self.blocks[first..] {
+            if *s >= end {
+                break;
+            }
+            let from = start.max(*s);
+            let to = end.min(s + size);
+            if from >= to {
+                continue;
+            }
+            n_aligned += to - from;
+            let (mut t_from, mut t_to) = (t + from - s, t + to - s);
+            if matches!(self.target_strand, Strand::Reverse) {
+                (t_from, t_to) = (self.target_size - t_to, self.target_size - t_from);
+            }
+            span = Some(span.map_or((t_from, t_to), |(a, b)| (a.min(t_from), b.max(t_to))));
+        }
+        span.map(|(a, b)| (n_aligned, a, b))
+    }
+}
+
+/// The chains of a chain file, indexed by their source intervals.
+pub struct LiftOver {
+    chains: Vec<Chain>,
+    index: GIntervalMap<usize>,
+}
+
+impl LiftOver {
+    /// Read a chain file, optionally compressed.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        Self::from_reader(BufReader::new(open_file_for_read(path)?))
+            .with_context(|| format!("cannot read chain file: {}", path.display()))
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self> {
+        let mut chains = Vec::new();
+        let mut regions = Vec::new();
+        // The current chain, with the current positions in the source and the target.
+        let mut current: Option<(Chain, u64, u64)> = None;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() || fields[0].starts_with('#') {
+                continue;
+            }
+            let parse = |x: &str| -> Result<u64> {
+                x.parse()
+                    .with_context(|| format!("line {}: invalid number '{}'", i + 1, x))
+            };
+            if fields[0] == "chain" {
+                ensure!(fields.len() >= 12, "line {}: invalid chain header", i + 1);
+                if current.is_some() {
+                    bail!("line {}: the previous chain is not terminated", i + 1);
+                }
+                ensure!(fields[4] == "+", "line {}: the source strand must be '+'", i + 1);
+                let target_strand = match fields[9] {
+                    "+" => Strand::Forward,
+                    "-" => Strand::Reverse,
+                    x => bail!("line {}: invalid strand '{}'", i + 1, x),
+                };
+                let (source_start, source_end) = (parse(fields[5])?, parse(fields[6])?);
+                let source = GenomicRange::new(fields[2], source_start, source_end);
+                regions.push((source, chains.len()));
+                let chain = Chain {
+                    target: fields[7].to_string(),
+                    target_size: parse(fields[8])?,
+                    target_strand,
+                    blocks: Vec::new(),
+                };
+                current = Some((chain, source_start, parse(fields[10])?));
+            } else {
+                let Some((chain, s, t)) = current.as_mut() else {
+                    bail!("line {}: alignment data outside of a chain", i + 1);
+                };
+                let size = parse(fields[0])?;
+                chain.blocks.push((*s, *t, size));
+                match fields.len() {
+                    1 => chains.push(current.take().unwrap().0),
+                    3 => {
+                        *s += size + parse(fields[1])?;
+                        *t += size + parse(fields[2])?;
+                    }
+                    _ => bail!("line {}: invalid alignment data", i + 1),
+                }
+            }
+        }
+        if current.is_some() {
+            bail!("the last chain is not terminated");
+        }
+        Ok(Self {
+            chains,
+            index: regions.into_iter().collect(),
+        })
+    }
+
+    /// Map a region to the target genome. The region is mapped with the chain
+    /// aligning the most of its bases, provided that they make at least
+    /// `min_match` of its length; otherwise `None` is returned.
+    pub fn map(&self, region: &GenomicRange, min_match: f64) -> Option<GenomicRange> {
+        let len = (region.end() - region.start()).max(1);
+        self.index
+            .find(region)
+            .filter_map(|(_, i)| {
+                let chain = &self.chains[*i];
+                chain
+                    .map(region.start(), region.end())
+                    .map(|(n, start, end)| (n, GenomicRange::new(&chain.target, start, end)))
+            })
+            .max_by_key(|(n, _)| *n)
+            .filter(|(n, _)| *n as f64 >= min_match * len as f64)
+            .map(|(_, x)| x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAIN: &str = "\
+chain 1000 chr1 1000 + 100 300 chrA 2000 + 1000 1210
+50 10 20
+140
+
+chain 500 chr2 500 + 0 100 chrB 300 - 0 100
+100
+";
+
+    #[test]
+    fn test_liftover() {
+        let liftover = LiftOver::from_reader(CHAIN.as_bytes()).unwrap();
+        // Within the first block.
+        assert_eq!(
+            liftover.map(&GenomicRange::new("chr1", 110, 140), 0.95),
+            Some(GenomicRange::new("chrA", 1010, 1040))
+        );
+        // Spanning the gap: 10 of 40 bases are unaligned.
+        let region = GenomicRange::new("chr1", 140, 180);
+        assert_eq!(liftover.map(&region, 0.95), None);
+        assert_eq!(
+            liftover.map(&region, 0.5),
+            Some(GenomicRange::new("chrA", 1040, 1090))
+        );
+        // Reverse strand.
+        assert_eq!(
+            liftover.map(&GenomicRange::new("chr2", 10, 20), 1.0),
+            Some(GenomicRange::new("chrB", 280, 290))
+        );
+        assert_eq!(liftover.map(&GenomicRange::new("chr3", 10, 20), 0.1), None);
+        let unterminated = "chain 1 chr1 10 + 0 10 chrA 10 + 0 10\n10 1 1\n";
+        assert!(LiftOver::from_reader(unterminated.as_bytes()).is_err());
+    }
+}
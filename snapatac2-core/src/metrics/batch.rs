@@ -0,0 +1,218 @@
+//! Batch-mixing diagnostics computed on the k-nearest neighbor graph.
+//!
+//! Both metrics take the kNN graph as a sparse matrix whose row `i` stores the
+//! neighbors of cell `i` and the corresponding distances, i.e., the matrix
+//! produced by `nearest_neighbour_graph` and stored in `.obsp['distances']`.
+
+use super::encode_labels;
+
+use anyhow::{bail, ensure, Result};
+use nalgebra_sparse::CsrMatrix;
+use rayon::prelude::*;
+use statrs::distribution::{ChiSquared, ContinuousCDF};
+use std::hash::Hash;
+
+/// Result of the k-nearest neighbor batch effect test (kBET).
+#[derive(Debug, Clone)]
+pub struct KBetResult {
+    /// Fraction of tested cells whose neighborhood batch composition differs
+    /// significantly from the global batch composition.
+    pub rejection_rate: f64,
+    /// Per-cell p-values of the chi-squared test. Cells without neighbors have `NaN`.
+    pub p_values: Vec<f64>,
+}
+
+/// Compute the kBET rejection rate (Büttner et al., 2019).
+///
+/// For every cell, the batch composition of its neighborhood is compared
+/// against the global batch composition with a Pearson's chi-squared test.
+/// A low rejection rate indicates that batches are well mixed.
+///
+/// # Arguments
+///
+/// * `graph` - The kNN graph, of shape `n_obs` x `n_obs`.
+/// * `batch` - Batch labels of the cells.
+/// * `alpha` - Significance level of the test.
+pub fn kbet<T: Hash + Eq>(graph: &CsrMatrix<f64>, batch: &[T], alpha: f64) -> Result<KBetResult> {
+    ensure!(
+        graph.nrows() == batch.len(),
+        "the number of rows in the graph ({}) does not match the number of labels ({})",
+        graph.nrows(),
+        batch.len(),
+    );
+    let (codes, n_batch) = encode_labels(batch);
+    if n_batch < 2 {
+        bail!("kBET requires at least two batches");
+    }
+
+    let mut freq = vec![0.0; n_batch];
+    codes.iter().for_each(|&b| freq[b] += 1.0);
+    let n = codes.len() as f64;
+    freq.iter_mut().for_each(|x| *x /= n);
+
+    let chi2 = ChiSquared::new((n_batch - 1) as f64)?;
+    let p_values: Vec<f64> = crate::config::install(None, || {
+        graph
+            .row_iter()
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|row| {
+                let k = row.nnz();
+                if k == 0 {
+                    return f64::NAN;
+                }
+                let mut observed = vec![0.0; n_batch];
+                row.col_indices().iter().for_each(|&j| observed[codes[j]] += 1.0);
+                let stat: f64 = observed
+                    .iter()
+                    .zip(freq.iter())
+                    .map(|(o, p)| {
+                        let e = p * k as f64;
+                        (o - e).powi(2) / e
+                    })
+                    .sum();
+                1.0 - chi2.cdf(stat)
+            })
+            .collect()
+    });
+
+    let (n_tested, n_rejected) = p_values
+        .iter()
+        .filter(|p| !p.is_nan())
+        .fold((0usize, 0usize), |(t, r), p| (t + 1, r + (*p < alpha) as usize));
+    ensure!(n_tested > 0, "the kNN graph does not contain any edges");
+
+    Ok(KBetResult {
+        rejection_rate: n_rejected as f64 / n_tested as f64,
+        p_values,
+    })
+}
+
+/// Compute the Local Inverse Simpson's Index (LISI) for each cell (Korsunsky et al., 2019).
+///
+/// The neighbors of each cell are weighted by a Gaussian kernel whose bandwidth
+/// is calibrated to match the given perplexity. The LISI is the effective
+/// number of distinct labels in the weighted neighborhood. When `labels` are
+/// batches (iLISI), larger values indicate better mixing; when `labels` are cell
+/// types (cLISI), values close to 1 indicate that cell types remain separated.
+///
+/// # Arguments
+///
+/// * `graph` - The kNN graph with distances, of shape `n_obs` x `n_obs`.
+/// * `labels` - Labels of the cells.
+/// * `perplexity` - Effective number of neighbors. It should be smaller than
+///   the number of neighbors in the graph.
+pub fn lisi<T: Hash + Eq>(graph: &CsrMatrix<f64>, labels: &[T], perplexity: f64) -> Result<Vec<f64>> {
+    ensure!(
+        graph.nrows() == labels.len(),
+        "the number of rows in the graph ({}) does not match the number of labels ({})",
+        graph.nrows(),
+        labels.len(),
+    );
+    ensure!(perplexity > 0.0, "perplexity must be positive");
+    let (codes, n_label) = encode_labels(labels);
+
+    let result = crate::config::install(None, || {
+        graph
+            .row_iter()
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|row| {
+                if row.nnz() == 0 {
+                    return f64::NAN;
+                }
+                let probs = calibrate_kernel(row.values(), perplexity, 1e-5, 50);
+                let mut label_probs = vec![0.0; n_label];
+                row.col_indices()
+                    .iter()
+                    .zip(probs)
+                    .for_each(|(&j, p)| label_probs[codes[j]] += p);
+                1.0 / label_probs.into_iter().map(|p| p * p).sum::<f64>()
+            })
+            .collect()
+    });
+    Ok(result)
+}
+
+/// Compute Gaussian kernel weights over the distances by binary search on the
+/// precision, such that the entropy of the weights matches `ln(perplexity)`.
+fn calibrate_kernel(distances: &[f64], perplexity: f64, tol: f64, max_iter: usize) -> Vec<f64> {
+    let log_u = perplexity.ln();
+    let mut beta = 1.0;
+    let mut beta_min = f64::NEG_INFINITY;
+    let mut beta_max = f64::INFINITY;
+    let mut probs = vec![0.0; distances.len()];
+
+    for _ in 0..max_iter {
+        probs
+            .iter_mut()
+            .zip(distances)
+            .for_each(|(p, d)| *p = (-d * beta).exp());
+        let sum: f64 = probs.iter().sum();
+        if sum == 0.0 {
+            break;
+        }
+        let entropy = sum.ln()
+            + beta * distances.iter().zip(&probs).map(|(d, p)| d * p).sum::<f64>() / sum;
+        probs.iter_mut().for_each(|p| *p /= sum);
+
+        let diff = entropy - log_u;
+        if diff.abs() < tol {
+            return probs;
+        }
+        if diff > 0.0 {
+            beta_min = beta;
+            beta = if beta_max.is_infinite() { beta * 2.0 } else { (beta + beta_max) / 2.0 };
+        } else {
+            beta_max = beta;
+            beta = if beta_min.is_infinite() { beta / 2.0 } else { (beta + beta_min) / 2.0 };
+        }
+    }
+
+    if probs.iter().all(|p| p.is_finite()) && probs.iter().sum::<f64>() > 0.0 {
+        probs
+    } else {
+        vec![1.0 / distances.len() as f64; distances.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a graph in which every cell is connected to the cells selected by `f`.
+    fn make_graph(n: usize, f: impl Fn(usize, usize) -> bool) -> CsrMatrix<f64> {
+        let mut indptr = vec![0];
+        let mut indices = Vec::new();
+        for i in 0..n {
+            (0..n).filter(|&j| j != i && f(i, j)).for_each(|j| indices.push(j));
+            indptr.push(indices.len());
+        }
+        let data = vec![1.0; indices.len()];
+        CsrMatrix::try_from_csr_data(n, n, indptr, indices, data).unwrap()
+    }
+
+    #[test]
+    fn test_separated_batches() {
+        let batch: Vec<usize> = (0..20).map(|i| i / 10).collect();
+        let graph = make_graph(20, |i, j| batch[i] == batch[j]);
+
+        let result = kbet(&graph, &batch, 0.05).unwrap();
+        assert_eq!(result.rejection_rate, 1.0);
+
+        let scores = lisi(&graph, &batch, 5.0).unwrap();
+        scores.iter().for_each(|x| assert!((x - 1.0).abs() < 1e-8));
+    }
+
+    #[test]
+    fn test_mixed_batches() {
+        let batch: Vec<usize> = (0..20).map(|i| i % 2).collect();
+        let graph = make_graph(20, |_, _| true);
+
+        let result = kbet(&graph, &batch, 0.05).unwrap();
+        assert_eq!(result.rejection_rate, 0.0);
+
+        let scores = lisi(&graph, &batch, 5.0).unwrap();
+        scores.iter().for_each(|x| assert!(*x > 1.9 && *x <= 2.0));
+    }
+}
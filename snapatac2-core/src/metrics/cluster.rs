@@ -0,0 +1,266 @@
+//! Metrics for evaluating clusterings, either against another labeling
+//! (ARI, NMI, homogeneity) or against the embedding (silhouette).
+
+use super::encode_labels;
+
+use anyhow::{ensure, Result};
+use ndarray::{s, Array1, Array2, ArrayView1, ArrayView2};
+use rayon::prelude::*;
+use std::hash::Hash;
+use std::ops::Range;
+
+/// Contingency table between two labelings, stored as a dense matrix whose
+/// rows correspond to the labels in `a` and columns to the labels in `b`.
+pub struct Contingency {
+    pub table: Vec<Vec<usize>>,
+    pub row_sums: Vec<usize>,
+    pub col_sums: Vec<usize>,
+    pub n: usize,
+}
+
+impl Contingency {
+    pub fn new<A: Hash + Eq, B: Hash + Eq>(a: &[A], b: &[B]) -> Result<Self> {
+        ensure!(
+            a.len() == b.len(),
+            "label vectors have different lengths: {} vs {}",
+            a.len(),
+            b.len(),
+        );
+        let (a, n_a) = encode_labels(a);
+        let (b, n_b) = encode_labels(b);
+        let mut table = vec![vec![0; n_b]; n_a];
+        let mut row_sums = vec![0; n_a];
+        let mut col_sums = vec![0; n_b];
+        a.iter().zip(b.iter()).for_each(|(&i, &j)| {
+            table[i][j] += 1;
+            row_sums[i] += 1;
+            col_sums[j] += 1;
+        });
+        Ok(Self { table, row_sums, col_sums, n: a.len() })
+    }
+}
+
+fn comb2(n: usize) -> f64 {
+    let n = n as f64;
+    n * (n - 1.0) / 2.0
+}
+
+/// Adjusted Rand index between two labelings.
+pub fn adjusted_rand_index<A: Hash + Eq, B: Hash + Eq>(a: &[A], b: &[B]) -> Result<f64> {
+    let cont = Contingency::new(a, b)?;
+    let sum_comb: f64 = cont.table.iter().flatten().map(|&x| comb2(x)).sum();
+    let sum_a: f64 = cont.row_sums.iter().map(|&x| comb2(x)).sum();
+    let sum_b: f64 = cont.col_sums.iter().map(|&x| comb2(x)).sum();
+    let total = comb2(cont.n);
+    if total == 0.0 {
+        return Ok(1.0);
+    }
+    let expected = sum_a * sum_b / total;
+    let max_index = (sum_a + sum_b) / 2.0;
+    if max_index == expected {
+        // Both labelings are trivial (a single cluster or all singletons).
+        return Ok(1.0);
+    }
+    Ok((sum_comb - expected) / (max_index - expected))
+}
+
+fn entropy(counts: &[usize], n: usize) -> f64 {
+    let n = n as f64;
+    counts
+        .iter()
+        .filter(|&&x| x > 0)
+        .map(|&x| {
+            let p = x as f64 / n;
+            -p * p.ln()
+        })
+        .sum()
+}
+
+fn mutual_info(cont: &Contingency) -> f64 {
+    let n = cont.n as f64;
+    cont.table
+        .iter()
+        .enumerate()
+        .flat_map(|(i, row)| row.iter().enumerate().map(move |(j, &x)| (i, j, x)))
+        .filter(|(_, _, x)| *x > 0)
+        .map(|(i, j, x)| {
+            let x = x as f64;
+            x / n * (n * x / (cont.row_sums[i] as f64 * cont.col_sums[j] as f64)).ln()
+        })
+        .sum()
+}
+
+/// Normalized mutual information between two labelings, using the arithmetic
+/// mean of the two entropies as the normalizer.
+pub fn normalized_mutual_info<A: Hash + Eq, B: Hash + Eq>(a: &[A], b: &[B]) -> Result<f64> {
+    let cont = Contingency::new(a, b)?;
+    let h_a = entropy(&cont.row_sums, cont.n);
+    let h_b = entropy(&cont.col_sums, cont.n);
+    if h_a == 0.0 && h_b == 0.0 {
+        return Ok(1.0);
+    }
+    Ok((mutual_info(&cont) / ((h_a + h_b) / 2.0)).clamp(0.0, 1.0))
+}
+
+/// Homogeneity, completeness and V-measure of a clustering (Rosenberg and Hirschberg, 2007).
+///
+/// A clustering is homogeneous if every cluster contains only members of a
+/// single class, and complete if all members of a class are assigned to the
+/// same cluster. The V-measure is their harmonic mean.
+///
+/// # Arguments
+///
+/// * `classes` - Ground truth labels.
+/// * `clusters` - Cluster labels to evaluate.
+pub fn homogeneity_completeness_v_measure<A: Hash + Eq, B: Hash + Eq>(
+    classes: &[A],
+    clusters: &[B],
+) -> Result<(f64, f64, f64)> {
+    let cont = Contingency::new(classes, clusters)?;
+    let h_c = entropy(&cont.row_sums, cont.n);
+    let h_k = entropy(&cont.col_sums, cont.n);
+    let mi = mutual_info(&cont);
+    let homogeneity = if h_c == 0.0 { 1.0 } else { mi / h_c };
+    let completeness = if h_k == 0.0 { 1.0 } else { mi / h_k };
+    let v_measure = if homogeneity + completeness == 0.0 {
+        0.0
+    } else {
+        2.0 * homogeneity * completeness / (homogeneity + completeness)
+    };
+    Ok((homogeneity, completeness, v_measure))
+}
+
+/// Silhouette coefficient of every observation, using Euclidean distances.
+///
+/// The distances are computed in blocks of `chunk_size` x `chunk_size`
+/// observations, so that the memory usage does not grow quadratically with
+/// the number of observations. Observations in singleton clusters have a
+/// silhouette of 0.
+pub fn silhouette_samples<T: Hash + Eq>(
+    data: ArrayView2<'_, f64>,
+    labels: &[T],
+    chunk_size: usize,
+) -> Result<Vec<f64>> {
+    ensure!(
+        data.nrows() == labels.len(),
+        "the number of rows in the data ({}) does not match the number of labels ({})",
+        data.nrows(),
+        labels.len(),
+    );
+    let (codes, n_clusters) = encode_labels(labels);
+    ensure!(n_clusters >= 2, "silhouette requires at least two clusters");
+    let mut sizes = vec![0usize; n_clusters];
+    codes.iter().for_each(|&c| sizes[c] += 1);
+
+    let n = data.nrows();
+    let chunk_size = chunk_size.max(1);
+    let sq_norms: Array1<f64> = data.outer_iter().map(|x| x.dot(&x)).collect();
+    let mut result = Vec::with_capacity(n);
+    for start in (0..n).step_by(chunk_size) {
+        let end = (start + chunk_size).min(n);
+        // The sum of the distances from every observation of the chunk to
+        // the observations of every cluster.
+        let dist_sum = crate::config::install(None, || {
+            (0..n.div_ceil(chunk_size))
+                .into_par_iter()
+                .map(|k| {
+                    let col_start = k * chunk_size;
+                    let col_end = (col_start + chunk_size).min(n);
+                    let dist = block_distances(data, sq_norms.view(), start..end, col_start..col_end);
+                    let mut acc = Array2::<f64>::zeros((end - start, n_clusters));
+                    dist.outer_iter().zip(acc.outer_iter_mut()).for_each(|(d, mut a)| {
+                        d.iter()
+                            .zip(&codes[col_start..col_end])
+                            .for_each(|(d, &c)| a[c] += d)
+                    });
+                    acc
+                })
+                .reduce(|| Array2::zeros((end - start, n_clusters)), |a, b| a + b)
+        });
+        result.extend(dist_sum.outer_iter().zip(&codes[start..end]).map(|(dist_sum, &own)| {
+            if sizes[own] <= 1 {
+                return 0.0;
+            }
+            let a = dist_sum[own] / (sizes[own] - 1) as f64;
+            let b = dist_sum
+                .iter()
+                .zip(sizes.iter())
+                .enumerate()
+                .filter(|(c, (_, s))| *c != own && **s > 0)
+                .map(|(_, (d, s))| d / *s as f64)
+                .fold(f64::INFINITY, f64::min);
+            let m = a.max(b);
+            if m == 0.0 { 0.0 } else { (b - a) / m }
+        }));
+    }
+    Ok(result)
+}
+
+/// Euclidean distances between the observations in `rows` and those in
+/// `cols`, computed from the squared norms `sq_norms` of all observations.
+fn block_distances(
+    data: ArrayView2<'_, f64>,
+    sq_norms: ArrayView1<'_, f64>,
+    rows: Range<usize>,
+    cols: Range<usize>,
+) -> Array2<f64> {
+    let x = data.slice(s![rows.clone(), ..]);
+    let y = data.slice(s![cols.clone(), ..]);
+    let mut dist = x.dot(&y.t()) * -2.0;
+    dist.indexed_iter_mut().for_each(|((i, j), d)| {
+        let (i, j) = (rows.start + i, cols.start + j);
+        // Rounding errors must not give a non-zero distance to itself.
+        *d = if i == j { 0.0 } else { (*d + sq_norms[i] + sq_norms[j]).max(0.0).sqrt() };
+    });
+    dist
+}
+
+/// Mean silhouette coefficient over all observations.
+pub fn silhouette_score<T: Hash + Eq>(
+    data: ArrayView2<'_, f64>,
+    labels: &[T],
+    chunk_size: usize,
+) -> Result<f64> {
+    let s = silhouette_samples(data, labels, chunk_size)?;
+    Ok(s.iter().sum::<f64>() / s.len() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ari() {
+        let a = [0, 0, 1, 1, 2, 2];
+        let b = ["x", "x", "y", "y", "z", "z"];
+        assert!((adjusted_rand_index(&a, &b).unwrap() - 1.0).abs() < 1e-12);
+
+        let a = [0, 0, 0, 1, 1, 1];
+        let b = [0, 0, 1, 1, 2, 2];
+        assert!((adjusted_rand_index(&a, &b).unwrap() - 0.24242424242424246).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_information_metrics() {
+        let a = [0, 0, 0, 1, 1, 1];
+        let b = [0, 0, 1, 1, 2, 2];
+        assert!((normalized_mutual_info(&a, &b).unwrap() - 0.5158037429793889).abs() < 1e-12);
+
+        let (h, c, v) = homogeneity_completeness_v_measure(&a, &b).unwrap();
+        assert!((h - 0.6666666666666669).abs() < 1e-12);
+        assert!((c - 0.420619835714305).abs() < 1e-12);
+        assert!((v - 0.5158037429793889).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_silhouette() {
+        let data = ndarray::array![[0.0, 0.0], [0.0, 1.0], [10.0, 0.0], [10.0, 1.0]];
+        let labels = [0, 0, 1, 1];
+        let s1 = silhouette_score(data.view(), &labels, 1).unwrap();
+        let s2 = silhouette_score(data.view(), &labels, 3).unwrap();
+        let s3 = silhouette_score(data.view(), &labels, 100).unwrap();
+        assert!((s1 - s2).abs() < 1e-12 && (s1 - s3).abs() < 1e-12);
+        let expected = 1.0 - 1.0 / ((10.0 + 101.0f64.sqrt()) / 2.0);
+        assert!((s1 - expected).abs() < 1e-12);
+    }
+}
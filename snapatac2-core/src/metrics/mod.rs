@@ -0,0 +1,32 @@
+//! Quality metrics computed on embeddings, neighborhood graphs, labels and
+//! pseudobulk profiles.
+
+pub mod batch;
+pub mod cluster;
+pub mod sample;
+pub mod stability;
+
+pub use batch::{kbet, lisi, KBetResult};
+pub use cluster::{
+    adjusted_rand_index, homogeneity_completeness_v_measure, normalized_mutual_info,
+    silhouette_samples, silhouette_score,
+};
+pub use sample::{log_cpm, sample_qc, CorrelationMethod, SampleQC};
+pub use stability::{bootstrap_stability, BootstrapOptions, ClusterStability};
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Encode arbitrary labels as consecutive integers starting from 0.
+/// Returns the codes and the number of distinct labels.
+pub fn encode_labels<T: Hash + Eq>(labels: &[T]) -> (Vec<usize>, usize) {
+    let mut levels: HashMap<&T, usize> = HashMap::new();
+    let codes = labels
+        .iter()
+        .map(|x| {
+            let n = levels.len();
+            *levels.entry(x).or_insert(n)
+        })
+        .collect();
+    (codes, levels.len())
+}
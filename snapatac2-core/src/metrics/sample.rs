@@ -0,0 +1,141 @@
+//! Sample-level quality control on pseudobulk profiles.
+//!
+//! With a handful of samples, the principal components are obtained from the
+//! eigendecomposition of the `n_samples` x `n_samples` Gram matrix, which is
+//! exact and cheap whatever the number of features.
+
+use anyhow::{ensure, Result};
+use nalgebra::{DMatrix, SymmetricEigen};
+use ndarray::{Array2, Axis};
+
+use crate::utils::similarity::{pearson2, spearman2};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorrelationMethod {
+    Pearson,
+    Spearman,
+}
+
+impl TryFrom<&str> for CorrelationMethod {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "pearson" => Ok(CorrelationMethod::Pearson),
+            "spearman" => Ok(CorrelationMethod::Spearman),
+            _ => anyhow::bail!("correlation method must be 'pearson' or 'spearman'"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SampleQC {
+    /// Principal component scores, of shape `n_samples` x `n_comps`.
+    pub pca: Array2<f64>,
+    /// Fraction of the variance explained by each component.
+    pub variance_ratio: Vec<f64>,
+    /// Pairwise correlations between samples.
+    pub correlation: Array2<f64>,
+    /// Indices of the features used.
+    pub features: Vec<usize>,
+}
+
+/// Transform the counts of every sample to log2(counts per million + 1).
+pub fn log_cpm(mat: &mut Array2<f64>) {
+    mat.rows_mut().into_iter().for_each(|mut row| {
+        let total: f64 = row.sum();
+        let scale = if total > 0.0 { 1e6 / total } else { 0.0 };
+        row.mapv_inplace(|x| (x * scale + 1.0).log2());
+    });
+}
+
+/// Compute the PCA and the pairwise correlations of samples from their
+/// pseudobulk profiles, given as log-normalized values of shape
+/// `n_samples` x `n_features`. If `n_top_features` is given, only the most
+/// variable features are used.
+pub fn sample_qc(
+    mat: &Array2<f64>,
+    n_comps: usize,
+    n_top_features: Option<usize>,
+    method: CorrelationMethod,
+) -> Result<SampleQC> {
+    let n = mat.nrows();
+    ensure!(n >= 2, "at least two samples are required");
+
+    let variance = mat.var_axis(Axis(0), 0.0);
+    let mut features: Vec<usize> = (0..mat.ncols()).collect();
+    if let Some(k) = n_top_features {
+        features.sort_by(|a, b| variance[*b].total_cmp(&variance[*a]).then(a.cmp(b)));
+        features.truncate(k);
+        features.sort_unstable();
+    }
+    let mut x = mat.select(Axis(1), &features);
+    let mean = x.mean_axis(Axis(0)).unwrap();
+    x -= &mean;
+
+    let gram = x.dot(&x.t());
+    let eigen = SymmetricEigen::new(DMatrix::from_fn(n, n, |i, j| gram[[i, j]]));
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|a, b| eigen.eigenvalues[*b].total_cmp(&eigen.eigenvalues[*a]));
+    let total: f64 = eigen.eigenvalues.iter().map(|x| x.max(0.0)).sum();
+    // At most n - 1 components have a non-zero variance after centering.
+    let n_comps = n_comps.min(n - 1);
+    let mut pca = Array2::zeros((n, n_comps));
+    let mut variance_ratio = Vec::with_capacity(n_comps);
+    for (k, i) in order.into_iter().take(n_comps).enumerate() {
+        let lambda = eigen.eigenvalues[i].max(0.0);
+        let u = eigen.eigenvectors.column(i);
+        // Make the signs deterministic: the largest loading is positive.
+        let sign = u
+            .iter()
+            .fold(0.0f64, |acc, x| if x.abs() > acc.abs() { *x } else { acc })
+            .signum();
+        for j in 0..n {
+            pca[[j, k]] = sign * u[j] * lambda.sqrt();
+        }
+        variance_ratio.push(if total > 0.0 { lambda / total } else { 0.0 });
+    }
+
+    let profiles = mat.select(Axis(1), &features);
+    let correlation = match method {
+        CorrelationMethod::Pearson => pearson2(profiles.clone(), profiles),
+        CorrelationMethod::Spearman => spearman2(profiles.clone(), profiles),
+    };
+    Ok(SampleQC {
+        pca,
+        variance_ratio,
+        correlation,
+        features,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_sample_qc() {
+        // Two pairs of similar samples, differing along the first feature.
+        let mat = array![
+            [10.0, 1.0, 5.0],
+            [11.0, 1.5, 5.0],
+            [0.0, 1.0, 5.0],
+            [1.0, 1.5, 5.0],
+        ];
+        let qc = sample_qc(&mat, 5, Some(2), CorrelationMethod::Pearson).unwrap();
+        assert_eq!(qc.features, vec![0, 1]);
+        assert_eq!(qc.pca.shape(), &[4, 3]);
+        assert!(qc.variance_ratio[0] > 0.99);
+        assert!((qc.variance_ratio.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!(qc.pca[[0, 0]] * qc.pca[[2, 0]] < 0.0);
+        assert!((qc.pca[[0, 0]] - qc.pca[[1, 0]]).abs() < 1.5);
+        assert_eq!(qc.correlation.shape(), &[4, 4]);
+        assert!((qc.correlation[[0, 0]] - 1.0).abs() < 1e-9);
+
+        let mut counts = array![[1.0, 3.0], [0.0, 0.0]];
+        log_cpm(&mut counts);
+        assert!((counts[[0, 1]] - (750_001.0f64).log2()).abs() < 1e-9);
+        assert_eq!(counts[[1, 0]], 0.0);
+    }
+}
@@ -0,0 +1,167 @@
+//! Cluster stability assessment by bootstrap resampling.
+
+use super::cluster::adjusted_rand_index;
+use super::encode_labels;
+
+use anyhow::{ensure, Result};
+use crate::utils::rng::SeedStream;
+use itertools::Itertools;
+use rand::Rng;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Stability of a reference clustering under bootstrap resampling.
+#[derive(Debug, Clone)]
+pub struct ClusterStability {
+    /// Mean Jaccard index of each reference cluster, in the order the clusters
+    /// first appear in the reference labels. A reference cluster is compared
+    /// against its best matching cluster in every bootstrap replicate.
+    pub jaccard: Vec<f64>,
+    /// Adjusted Rand index of every bootstrap replicate.
+    pub ari: Vec<f64>,
+}
+
+/// Parameters controlling the resampling.
+#[derive(Debug, Clone)]
+pub struct BootstrapOptions {
+    /// Number of bootstrap replicates.
+    pub n_iter: usize,
+    /// Number of cells drawn with replacement in every replicate, as a
+    /// fraction of the number of cells.
+    pub cell_fraction: f64,
+    /// Total number of features and the fraction retained in every replicate,
+    /// drawn without replacement. If `None`, all features are used.
+    pub features: Option<(usize, f64)>,
+    pub seed: u64,
+}
+
+impl Default for BootstrapOptions {
+    fn default() -> Self {
+        Self { n_iter: 20, cell_fraction: 1.0, features: None, seed: 2024 }
+    }
+}
+
+/// Assess the stability of `reference` clustering.
+///
+/// In every replicate, cells are drawn with replacement (and optionally a
+/// random subset of features without replacement) and passed to `cluster_fn`
+/// in ascending order, so that the copies of a cell are adjacent. It must
+/// return the cluster label of every drawn cell in the same order.
+/// The new labels of the distinct drawn cells, taken from their first copy,
+/// are then compared to their reference labels.
+pub fn bootstrap_stability<L, M, F>(
+    reference: &[L],
+    options: &BootstrapOptions,
+    mut cluster_fn: F,
+) -> Result<ClusterStability>
+where
+    L: Hash + Eq,
+    M: Hash + Eq,
+    F: FnMut(&[usize], Option<&[usize]>) -> Result<Vec<M>>,
+{
+    ensure!(options.cell_fraction > 0.0, "cell_fraction must be positive");
+    ensure!(!reference.is_empty(), "the reference clustering is empty");
+    let n_cells = reference.len();
+    let n_sampled = ((n_cells as f64 * options.cell_fraction).round() as usize).max(1);
+    let (ref_codes, n_clusters) = encode_labels(reference);
+    let seeds = SeedStream::new(options.seed);
+
+    let mut jaccard_sum = vec![0.0; n_clusters];
+    let mut jaccard_n = vec![0usize; n_clusters];
+    let mut ari = Vec::with_capacity(options.n_iter);
+    for iter in 0..options.n_iter {
+        // Every replicate has its own stream so that it can be reproduced alone.
+        let mut rng = seeds.rng(iter as u64);
+        let mut cells: Vec<usize> = (0..n_sampled).map(|_| rng.random_range(0..n_cells)).collect();
+        cells.sort_unstable();
+        let features = options.features.map(|(n, frac)| {
+            let k = ((n as f64 * frac).round() as usize).clamp(1, n);
+            let mut idx = rand::seq::index::sample(&mut rng, n, k).into_vec();
+            idx.sort_unstable();
+            idx
+        });
+
+        let labels = cluster_fn(&cells, features.as_deref())?;
+        ensure!(
+            labels.len() == cells.len(),
+            "clustering returned {} labels for {} cells",
+            labels.len(),
+            cells.len(),
+        );
+        let (labels, _) = encode_labels(&labels);
+        let (cells, labels): (Vec<usize>, Vec<usize>) = cells
+            .into_iter()
+            .zip(labels)
+            .dedup_by(|a, b| a.0 == b.0)
+            .unzip();
+        let ref_labels: Vec<_> = cells.iter().map(|&i| ref_codes[i]).collect();
+        ari.push(adjusted_rand_index(&ref_labels, &labels)?);
+
+        best_match_jaccard(&ref_labels, &labels)
+            .into_iter()
+            .for_each(|(c, j)| {
+                jaccard_sum[c] += j;
+                jaccard_n[c] += 1;
+            });
+    }
+
+    let jaccard = jaccard_sum
+        .into_iter()
+        .zip(jaccard_n)
+        .map(|(s, n)| if n == 0 { f64::NAN } else { s / n as f64 })
+        .collect();
+    Ok(ClusterStability { jaccard, ari })
+}
+
+/// For each cluster in `reference`, compute the largest Jaccard index
+/// against the clusters in `query`.
+fn best_match_jaccard(reference: &[usize], query: &[usize]) -> Vec<(usize, f64)> {
+    let mut overlap: HashMap<(usize, usize), usize> = HashMap::new();
+    let mut ref_size: HashMap<usize, usize> = HashMap::new();
+    let mut query_size: HashMap<usize, usize> = HashMap::new();
+    reference.iter().zip(query).for_each(|(&r, &q)| {
+        *overlap.entry((r, q)).or_default() += 1;
+        *ref_size.entry(r).or_default() += 1;
+        *query_size.entry(q).or_default() += 1;
+    });
+    let mut best: HashMap<usize, f64> = HashMap::new();
+    overlap.into_iter().for_each(|((r, q), n)| {
+        let j = n as f64 / (ref_size[&r] + query_size[&q] - n) as f64;
+        let e = best.entry(r).or_insert(0.0);
+        if j > *e {
+            *e = j;
+        }
+    });
+    best.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_perfectly_stable() {
+        let reference: Vec<usize> = (0..100).map(|i| i / 25).collect();
+        let options = BootstrapOptions { n_iter: 5, ..Default::default() };
+        let result = bootstrap_stability(&reference, &options, |cells, _| {
+            Ok(cells.iter().map(|i| reference[*i] + 10).collect::<Vec<_>>())
+        }).unwrap();
+        result.jaccard.iter().for_each(|x| assert_eq!(*x, 1.0));
+        result.ari.iter().for_each(|x| assert!((x - 1.0).abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_with_replacement() {
+        let reference: Vec<usize> = (0..100).map(|i| i / 25).collect();
+        let options = BootstrapOptions { n_iter: 3, ..Default::default() };
+        let mut n_distinct = Vec::new();
+        bootstrap_stability(&reference, &options, |cells, _| {
+            assert_eq!(cells.len(), 100);
+            assert!(cells.windows(2).all(|x| x[0] <= x[1]));
+            n_distinct.push(cells.iter().dedup().count());
+            Ok(cells.iter().map(|i| reference[*i]).collect::<Vec<_>>())
+        }).unwrap();
+        // About 63% of the cells are drawn in every replicate.
+        n_distinct.iter().for_each(|x| assert!(*x > 40 && *x < 85));
+    }
+}
@@ -0,0 +1,630 @@
+//! Serialization of fitted models, so that query datasets can be mapped onto a
+//! reference atlas later or by other users.
+//!
+//! Models are stored as JSON documents, optionally compressed with gzip or zstd
+//! depending on the file extension. Every document carries a schema version;
+//! files written by older versions of the schema are upgraded when loaded.
+
+use crate::embedding::Pca;
+use crate::metrics::encode_labels;
+use crate::utils::{open_file_for_read, open_file_for_write, Compression};
+
+use anndata::{
+    data::{ArrayConvert, DynCsrMatrix},
+    AnnDataOp, ArrayElemOp,
+};
+use anyhow::{bail, ensure, Context, Result};
+use indexmap::IndexSet;
+use log::warn;
+use nalgebra_sparse::CsrMatrix;
+use ndarray::{Array1, Array2, ArrayView2, Axis};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Current version of the model schema.
+pub const MODEL_SCHEMA_VERSION: u32 = 1;
+
+/// A dense row-major matrix.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Matrix {
+    pub nrows: usize,
+    pub ncols: usize,
+    pub data: Vec<f64>,
+}
+
+impl Matrix {
+    pub fn to_array(&self) -> Result<Array2<f64>> {
+        self.check()?;
+        Ok(Array2::from_shape_vec((self.nrows, self.ncols), self.data.clone())?)
+    }
+
+    fn check(&self) -> Result<()> {
+        ensure!(
+            self.data.len() == self.nrows * self.ncols,
+            "malformed matrix: expecting {} x {} values, but got {}",
+            self.nrows,
+            self.ncols,
+            self.data.len(),
+        );
+        Ok(())
+    }
+}
+
+impl From<ArrayView2<'_, f64>> for Matrix {
+    fn from(value: ArrayView2<'_, f64>) -> Self {
+        Self {
+            nrows: value.nrows(),
+            ncols: value.ncols(),
+            data: value.iter().copied().collect(),
+        }
+    }
+}
+
+/// Linear embedding of the cells. The coordinates of a cell are computed as
+/// `(x - mean) * basis^T`, where `x` is the cell's count vector after feature
+/// weighting and L2 normalization (if `feature_weights` is present).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbeddingModel {
+    pub method: String,
+    pub feature_weights: Option<Vec<f64>>,
+    pub mean: Option<Vec<f64>>,
+    /// Basis vectors of shape `n_components` x `n_features`.
+    pub basis: Matrix,
+    /// Eigenvalues or explained variances of the components.
+    pub eigenvalues: Vec<f64>,
+}
+
+impl EmbeddingModel {
+    pub fn from_pca(pca: &Pca, feature_weights: Option<Vec<f64>>) -> Self {
+        Self {
+            method: "pca".to_string(),
+            feature_weights,
+            mean: Some(pca.mean.clone()),
+            basis: pca.components.view().into(),
+            eigenvalues: pca.explained_variance.clone(),
+        }
+    }
+}
+
+/// Reference cells used to build the nearest neighbor index. The index itself
+/// is rebuilt from the coordinates when the model is loaded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KnnModel {
+    pub n_neighbors: usize,
+    pub reference: Matrix,
+    pub labels: Option<Vec<String>>,
+}
+
+impl KnnModel {
+    /// Label each cell by a majority vote among its `n_neighbors` nearest
+    /// reference cells. Ties are broken in favor of the label seen first in
+    /// the reference.
+    pub fn predict(&self, embedding: ArrayView2<'_, f64>) -> Result<Vec<String>> {
+        let labels = self.labels.as_ref().context("the kNN model has no reference labels")?;
+        let reference = self.reference.to_array()?;
+        ensure!(reference.nrows() > 0, "the kNN model has no reference cells");
+        ensure!(
+            embedding.ncols() == reference.ncols(),
+            "the embedding has {} dimensions, but the reference cells have {}",
+            embedding.ncols(),
+            reference.ncols(),
+        );
+        let k = self.n_neighbors.clamp(1, reference.nrows());
+        let (codes, n_labels) = encode_labels(labels);
+        let mut names = vec![""; n_labels];
+        codes.iter().zip(labels).for_each(|(c, l)| names[*c] = l.as_str());
+        Ok(crate::config::install(None, || {
+            embedding
+                .outer_iter()
+                .into_par_iter()
+                .map(|x| {
+                    let mut dist: Vec<(f64, usize)> = reference
+                        .rows()
+                        .into_iter()
+                        .map(|r| (&r - &x).mapv(|v| v * v).sum())
+                        .enumerate()
+                        .map(|(i, d)| (d, i))
+                        .collect();
+                    dist.select_nth_unstable_by(k - 1, |a, b| a.0.total_cmp(&b.0));
+                    let mut votes = vec![0usize; n_labels];
+                    dist[..k].iter().for_each(|(_, i)| votes[codes[*i]] += 1);
+                    let best = (0..n_labels).fold(0, |acc, i| if votes[i] > votes[acc] { i } else { acc });
+                    names[best].to_string()
+                })
+                .collect()
+        }))
+    }
+}
+
+/// Cluster centroids in the embedding space.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterModel {
+    pub names: Vec<String>,
+    pub centroids: Matrix,
+}
+
+impl ClusterModel {
+    /// Compute the centroid of each cluster.
+    pub fn from_labels<T: ToString + std::hash::Hash + Eq>(
+        embedding: ArrayView2<'_, f64>,
+        labels: &[T],
+    ) -> Result<Self> {
+        ensure!(
+            embedding.nrows() == labels.len(),
+            "the number of cells does not match the number of labels"
+        );
+        let (codes, n) = encode_labels(labels);
+        let mut names = vec![String::new(); n];
+        let mut centroids = Array2::<f64>::zeros((n, embedding.ncols()));
+        let mut sizes = vec![0.0; n];
+        codes.iter().zip(labels).zip(embedding.rows()).for_each(|((c, l), row)| {
+            names[*c] = l.to_string();
+            sizes[*c] += 1.0;
+            centroids.row_mut(*c).scaled_add(1.0, &row);
+        });
+        centroids
+            .rows_mut()
+            .into_iter()
+            .zip(sizes)
+            .for_each(|(mut row, s)| row /= s);
+        Ok(Self { names, centroids: centroids.view().into() })
+    }
+
+    /// Assign each cell to the cluster with the nearest centroid.
+    pub fn predict(&self, embedding: ArrayView2<'_, f64>) -> Result<Vec<String>> {
+        let centroids = self.centroids.to_array()?;
+        ensure!(
+            embedding.ncols() == centroids.ncols(),
+            "the embedding has {} dimensions, but the centroids have {}",
+            embedding.ncols(),
+            centroids.ncols(),
+        );
+        Ok(embedding
+            .rows()
+            .into_iter()
+            .map(|x| {
+                let (best, _) = centroids
+                    .rows()
+                    .into_iter()
+                    .map(|c| (&c - &x).mapv(|v| v * v).sum())
+                    .enumerate()
+                    .fold((0, f64::INFINITY), |acc, (i, d)| if d < acc.1 { (i, d) } else { acc });
+                self.names[best].clone()
+            })
+            .collect())
+    }
+}
+
+/// Parameters of a batch correction method, e.g., the cluster centroids of Harmony.
+///
+/// Only the "harmony" method is supported. Its `centroids` parameter holds
+/// the cluster centroids in the embedding space and its `offsets` parameter
+/// the batch effect of every cluster, both of shape `n_clusters` x `n_components`.
+/// The optional `sigma` parameter (1 x 1, default 0.1) sets the softness of
+/// the cluster assignments.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchCorrectionModel {
+    pub method: String,
+    pub parameters: BTreeMap<String, Matrix>,
+}
+
+impl BatchCorrectionModel {
+    fn parameter(&self, name: &str) -> Result<Array2<f64>> {
+        self.parameters
+            .get(name)
+            .with_context(|| format!("the {} batch correction has no '{}' parameter", self.method, name))?
+            .to_array()
+    }
+
+    fn check(&self, n_components: Option<usize>) -> Result<()> {
+        ensure!(
+            self.method == "harmony",
+            "unsupported batch correction method: {}, only 'harmony' is supported",
+            self.method,
+        );
+        let centroids = self.parameter("centroids")?;
+        let offsets = self.parameter("offsets")?;
+        ensure!(
+            centroids.dim() == offsets.dim(),
+            "the centroids have shape {:?}, but the offsets have shape {:?}",
+            centroids.dim(),
+            offsets.dim(),
+        );
+        if let Some(n) = n_components {
+            ensure!(
+                centroids.ncols() == n,
+                "the batch correction has {} dimensions, but the embedding has {}",
+                centroids.ncols(),
+                n,
+            );
+        }
+        if self.parameters.contains_key("sigma") {
+            ensure!(self.parameter("sigma")?.dim() == (1, 1), "sigma must be a 1 x 1 matrix");
+        }
+        Ok(())
+    }
+
+    /// Prepare the correction of embeddings with `n_components` dimensions.
+    pub fn correction(&self, n_components: usize) -> Result<BatchCorrection> {
+        self.check(Some(n_components))?;
+        let mut centroids = self.parameter("centroids")?;
+        centroids.rows_mut().into_iter().for_each(|mut c| {
+            let norm = c.dot(&c).sqrt();
+            if norm > 0.0 {
+                c /= norm;
+            }
+        });
+        let sigma = if self.parameters.contains_key("sigma") {
+            self.parameter("sigma")?[[0, 0]]
+        } else {
+            0.1
+        };
+        ensure!(sigma > 0.0, "sigma must be positive");
+        Ok(BatchCorrection { centroids, offsets: self.parameter("offsets")?, sigma })
+    }
+}
+
+/// Harmony correction prepared from a [`BatchCorrectionModel`].
+pub struct BatchCorrection {
+    /// Cluster centroids scaled to unit L2 norm.
+    centroids: Array2<f64>,
+    offsets: Array2<f64>,
+    sigma: f64,
+}
+
+impl BatchCorrection {
+    /// Remove the batch effects from an embedding. Every cell is softly
+    /// assigned to the clusters based on the cosine distance to the
+    /// centroids, as in Harmony, and the offsets of the clusters, weighted by
+    /// the assignment probabilities, are subtracted from its coordinates.
+    pub fn apply(&self, embedding: &mut Array2<f64>) {
+        embedding.rows_mut().into_iter().for_each(|mut x| {
+            let norm = x.dot(&x).sqrt().max(f64::MIN_POSITIVE);
+            let dist = self.centroids.dot(&x).mapv(|v| 2.0 * (1.0 - v / norm));
+            let min = dist.fold(f64::INFINITY, |a, b| a.min(*b));
+            let mut prob = dist.mapv(|d| (-(d - min) / self.sigma).exp());
+            prob /= prob.sum();
+            x -= &prob.dot(&self.offsets);
+        });
+    }
+}
+
+/// A fitted reference model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReferenceModel {
+    pub schema_version: u32,
+    /// Version of the library that created the model.
+    pub created_by: String,
+    /// Names of the features the model was fitted on.
+    pub feature_names: Vec<String>,
+    pub embedding: Option<EmbeddingModel>,
+    pub knn: Option<KnnModel>,
+    pub clusters: Option<ClusterModel>,
+    pub batch_correction: Option<BatchCorrectionModel>,
+}
+
+impl ReferenceModel {
+    pub fn new(feature_names: Vec<String>) -> Self {
+        Self {
+            schema_version: MODEL_SCHEMA_VERSION,
+            created_by: format!("snapatac2-core {}", env!("CARGO_PKG_VERSION")),
+            feature_names,
+            embedding: None,
+            knn: None,
+            clusters: None,
+            batch_correction: None,
+        }
+    }
+
+    /// Write the model to a file. Files ending with ".gz" or ".zst" are compressed.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let compression = match path.as_ref().extension().and_then(|x| x.to_str()) {
+            Some("gz") => Some(Compression::Gzip),
+            Some("zst") => Some(Compression::Zstd),
+            _ => None,
+        };
+        let mut writer = open_file_for_write(&path, compression, None)?;
+        serde_json::to_writer(&mut writer, self)
+            .with_context(|| format!("cannot write model: {}", path.as_ref().display()))?;
+        writer.finish()
+    }
+
+    /// Read a model from a file, upgrading it to the current schema if needed.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        ensure!(path.as_ref().exists(), "file not found: {}", path.as_ref().display());
+        let value: serde_json::Value = serde_json::from_reader(open_file_for_read(&path)?)
+            .with_context(|| format!("cannot parse model: {}", path.as_ref().display()))?;
+        let version = value
+            .get("schema_version")
+            .and_then(|x| x.as_u64())
+            .context("missing schema version")? as u32;
+        if version > MODEL_SCHEMA_VERSION {
+            bail!(
+                "model schema version {} is newer than the supported version {}, please upgrade snapatac2",
+                version,
+                MODEL_SCHEMA_VERSION,
+            );
+        }
+        // Version 1 is the first version of the schema, so no migration is needed yet.
+        let model: Self = serde_json::from_value(value)?;
+        model
+            .validate()
+            .with_context(|| format!("invalid model: {}", path.as_ref().display()))?;
+        Ok(model)
+    }
+
+    /// Check that the shapes of the components are consistent with each other
+    /// and with the features of the model.
+    pub fn validate(&self) -> Result<()> {
+        let n_features = self.feature_names.len();
+        if let Some(model) = self.embedding.as_ref() {
+            model.basis.check()?;
+            ensure!(
+                model.basis.ncols == n_features,
+                "the embedding basis has {} columns, but the model has {} features",
+                model.basis.ncols,
+                n_features,
+            );
+            if let Some(weights) = model.feature_weights.as_ref() {
+                ensure!(
+                    weights.len() == n_features,
+                    "expecting {} feature weights, but got {}",
+                    n_features,
+                    weights.len(),
+                );
+            }
+            if let Some(mean) = model.mean.as_ref() {
+                ensure!(
+                    mean.len() == n_features,
+                    "expecting {} means, but got {}",
+                    n_features,
+                    mean.len(),
+                );
+            }
+        }
+        if let Some(knn) = self.knn.as_ref() {
+            knn.reference.check()?;
+            if let Some(labels) = knn.labels.as_ref() {
+                ensure!(
+                    labels.len() == knn.reference.nrows,
+                    "expecting {} reference labels, but got {}",
+                    knn.reference.nrows,
+                    labels.len(),
+                );
+            }
+        }
+        if let Some(clusters) = self.clusters.as_ref() {
+            clusters.centroids.check()?;
+            ensure!(
+                clusters.names.len() == clusters.centroids.nrows,
+                "expecting {} cluster names, but got {}",
+                clusters.centroids.nrows,
+                clusters.names.len(),
+            );
+        }
+        if let Some(batch) = self.batch_correction.as_ref() {
+            batch.parameters.values().try_for_each(|x| x.check())?;
+            batch.check(self.embedding.as_ref().map(|x| x.basis.nrows))?;
+        }
+        Ok(())
+    }
+
+    /// Project cells onto the embedding of the model, and remove the batch
+    /// effects if the model has batch correction parameters. `mat` must have
+    /// the same features, in the same order, as the model.
+    pub fn transform(&self, mat: CsrMatrix<f64>) -> Result<Array2<f64>> {
+        self.validate()?;
+        self.projection()?.transform(mat)
+    }
+
+    fn projection(&self) -> Result<Projection<'_>> {
+        let model = self.embedding.as_ref().context("the model does not contain an embedding")?;
+        let basis = model.basis.to_array()?;
+        let basis_nrows = basis.nrows();
+        let shift = model.mean.as_ref().map(|mean| basis.dot(&ndarray::ArrayView1::from(mean)));
+        Ok(Projection {
+            n_features: self.feature_names.len(),
+            feature_weights: model.feature_weights.as_deref(),
+            basis,
+            shift,
+            batch_correction: self
+                .batch_correction
+                .as_ref()
+                .map(|x| x.correction(basis_nrows))
+                .transpose()?,
+        })
+    }
+
+    /// Project the cells in `adata` onto the embedding of the model. Features
+    /// are matched by name; features absent from `adata` are treated as zeros.
+    pub fn transform_anndata<A: AnnDataOp>(&self, adata: &A, chunk_size: usize) -> Result<Array2<f64>> {
+        let features: IndexSet<&str> = self.feature_names.iter().map(|x| x.as_str()).collect();
+        let col_map: Vec<Option<usize>> = adata
+            .var_names()
+            .into_vec()
+            .iter()
+            .map(|x| features.get_index_of(x.as_str()))
+            .collect();
+        let n_found = col_map.iter().flatten().count();
+        ensure!(n_found > 0, "none of the features in the model is present in the data");
+        if n_found < features.len() {
+            warn!(
+                "{} out of {} features in the model are missing from the data",
+                features.len() - n_found,
+                features.len(),
+            );
+        }
+
+        self.validate()?;
+        let projection = self.projection()?;
+        let n_features = features.len();
+        let chunks = adata
+            .x()
+            .iter::<DynCsrMatrix>(chunk_size)
+            .map(|(mat, _, _)| {
+                let mat: CsrMatrix<f64> = mat.try_convert()?;
+                let mut indptr = vec![0];
+                let mut indices = Vec::new();
+                let mut data = Vec::new();
+                mat.row_iter().for_each(|row| {
+                    let mut entries: Vec<_> = row
+                        .col_indices()
+                        .iter()
+                        .zip(row.values())
+                        .filter_map(|(j, v)| col_map[*j].map(|j| (j, *v)))
+                        .collect();
+                    entries.sort_unstable_by_key(|x| x.0);
+                    entries.into_iter().for_each(|(j, v)| {
+                        indices.push(j);
+                        data.push(v);
+                    });
+                    indptr.push(indices.len());
+                });
+                let mat = CsrMatrix::try_from_csr_data(
+                    indptr.len() - 1, n_features, indptr, indices, data,
+                )?;
+                projection.transform(mat)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let views: Vec<_> = chunks.iter().map(|x| x.view()).collect();
+        Ok(ndarray::concatenate(Axis(0), &views)?)
+    }
+}
+
+/// The parts of a validated model needed to project cells.
+struct Projection<'a> {
+    n_features: usize,
+    feature_weights: Option<&'a [f64]>,
+    basis: Array2<f64>,
+    shift: Option<Array1<f64>>,
+    batch_correction: Option<BatchCorrection>,
+}
+
+impl Projection<'_> {
+    fn transform(&self, mut mat: CsrMatrix<f64>) -> Result<Array2<f64>> {
+        ensure!(
+            mat.ncols() == self.n_features,
+            "expecting {} features, but got {}",
+            self.n_features,
+            mat.ncols(),
+        );
+        if let Some(weights) = self.feature_weights {
+            mat.row_iter_mut().for_each(|mut row| {
+                let (indices, data) = row.cols_and_values_mut();
+                indices.iter().zip(data.iter_mut()).for_each(|(i, x)| *x *= weights[*i]);
+                let norm = data.iter().map(|x| x * x).sum::<f64>().sqrt();
+                if norm > 0.0 {
+                    data.iter_mut().for_each(|x| *x /= norm);
+                }
+            });
+        }
+        let mut result = Array2::zeros((mat.nrows(), self.basis.nrows()));
+        mat.row_iter().zip(result.rows_mut()).for_each(|(row, mut out)| {
+            row.col_indices().iter().zip(row.values()).for_each(|(j, v)| {
+                out.scaled_add(*v, &self.basis.column(*j));
+            });
+            if let Some(shift) = self.shift.as_ref() {
+                out -= shift;
+            }
+        });
+        if let Some(batch) = self.batch_correction.as_ref() {
+            batch.apply(&mut result);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    fn test_model() -> ReferenceModel {
+        let mut model = ReferenceModel::new(vec!["a".into(), "b".into(), "c".into()]);
+        model.embedding = Some(EmbeddingModel {
+            method: "pca".into(),
+            feature_weights: None,
+            mean: Some(vec![1.0, 0.0, 0.0]),
+            basis: array![[1.0, 0.0, 0.0], [0.0, 1.0, 1.0]].view().into(),
+            eigenvalues: vec![2.0, 1.0],
+        });
+        let emb = array![[0.0, 0.0], [0.0, 1.0], [5.0, 5.0]];
+        model.clusters = Some(ClusterModel::from_labels(emb.view(), &["x", "x", "y"]).unwrap());
+        model
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let model = test_model();
+        for name in ["model.json", "model.json.gz", "model.json.zst"] {
+            let path = dir.path().join(name);
+            model.save(&path).unwrap();
+            assert_eq!(ReferenceModel::load(&path).unwrap(), model);
+        }
+
+        let path = dir.path().join("future.json");
+        let mut future = serde_json::to_value(&model).unwrap();
+        future["schema_version"] = (MODEL_SCHEMA_VERSION + 1).into();
+        std::fs::write(&path, future.to_string()).unwrap();
+        assert!(ReferenceModel::load(&path).is_err());
+
+        let path = dir.path().join("malformed.json");
+        let mut malformed = model.clone();
+        malformed.embedding.as_mut().unwrap().basis.data.pop();
+        std::fs::write(&path, serde_json::to_string(&malformed).unwrap()).unwrap();
+        assert!(ReferenceModel::load(&path).is_err());
+
+        let mut malformed = model.clone();
+        malformed.embedding.as_mut().unwrap().feature_weights = Some(vec![1.0]);
+        std::fs::write(&path, serde_json::to_string(&malformed).unwrap()).unwrap();
+        assert!(ReferenceModel::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_transform() {
+        let model = test_model();
+        let mat = CsrMatrix::try_from_csr_data(
+            2, 3, vec![0, 2, 3], vec![0, 2, 1], vec![3.0, 1.0, 2.0],
+        ).unwrap();
+        let emb = model.transform(mat).unwrap();
+        assert_eq!(emb, array![[2.0, 1.0], [-1.0, 2.0]]);
+        assert_eq!(model.clusters.unwrap().predict(emb.view()).unwrap(), vec!["x", "x"]);
+    }
+
+    #[test]
+    fn test_knn_predict() {
+        let knn = KnnModel {
+            n_neighbors: 3,
+            reference: array![[0.0, 0.0], [0.0, 1.0], [1.0, 0.0], [5.0, 5.0], [5.0, 6.0]].view().into(),
+            labels: Some(vec!["x".into(), "x".into(), "y".into(), "y".into(), "y".into()]),
+        };
+        let query = array![[0.1, 0.1], [5.0, 5.5]];
+        assert_eq!(knn.predict(query.view()).unwrap(), vec!["x", "y"]);
+        assert!(knn.predict(array![[0.0]].view()).is_err());
+    }
+
+    #[test]
+    fn test_batch_correction() {
+        let mut model = test_model();
+        let mut parameters = BTreeMap::new();
+        parameters.insert("centroids".to_string(), array![[1.0, 0.0], [0.0, 1.0]].view().into());
+        parameters.insert("offsets".to_string(), array![[1.0, 0.0], [0.0, 2.0]].view().into());
+        parameters.insert("sigma".to_string(), array![[1e-3]].view().into());
+        model.batch_correction = Some(BatchCorrectionModel { method: "harmony".into(), parameters });
+        let mat = CsrMatrix::try_from_csr_data(
+            2, 3, vec![0, 1, 2], vec![0, 1], vec![6.0, 3.0],
+        ).unwrap();
+        let emb = model.transform(mat).unwrap();
+        assert!((emb[[0, 0]] - 4.0).abs() < 1e-9 && emb[[0, 1]].abs() < 1e-9);
+        assert!((emb[[1, 0]] + 1.0).abs() < 1e-9 && (emb[[1, 1]] - 1.0).abs() < 1e-9);
+
+        model.batch_correction.as_mut().unwrap().method = "scanorama".into();
+        assert!(model.validate().is_err());
+        model.batch_correction.as_mut().unwrap().method = "harmony".into();
+        model.batch_correction.as_mut().unwrap().parameters.remove("offsets");
+        assert!(model.validate().is_err());
+    }
+}
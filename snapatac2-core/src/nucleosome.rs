@@ -0,0 +1,299 @@
+//! Nucleosome positioning from paired-end fragments.
+//!
+//! In a V-plot, i.e., the fragments plotted by midpoint and length, the
+//! fragments spanning a nucleosome (147-250 bp) are centred on its dyad while
+//! the short fragments (< 120 bp) come from the linker DNA and nucleosome-free
+//! regions around it. As in NucleoATAC, the two populations are separated by
+//! fragment length: the smoothed density of nucleosomal fragment midpoints
+//! gives the dyad positions, and its ratio to the density of all informative
+//! fragments gives the nucleosome occupancy along the selected regions.
+
+use anyhow::{ensure, Result};
+use bed_utils::bed::{map::GIntervalMap, BEDLike, GenomicRange};
+use ndarray::Array2;
+use std::collections::HashMap;
+
+use crate::feature_count::SnapData;
+
+#[derive(Debug, Clone)]
+pub struct NucleosomeOptions {
+    /// Maximum length of nucleosome-free fragments.
+    pub nfr_max: u64,
+    /// Minimum length of nucleosomal fragments.
+    pub nuc_min: u64,
+    /// Maximum length of nucleosomal fragments.
+    pub nuc_max: u64,
+    /// Standard deviation, in bases, of the Gaussian kernel used for smoothing.
+    pub smooth_sd: f64,
+    /// Minimum distance between two dyads.
+    pub min_spacing: u64,
+    /// Minimum smoothed density of nucleosomal fragments at a dyad.
+    pub min_signal: f64,
+}
+
+impl Default for NucleosomeOptions {
+    fn default() -> Self {
+        Self {
+            nfr_max: 120,
+            nuc_min: 147,
+            nuc_max: 250,
+            smooth_sd: 25.0,
+            min_spacing: 120,
+            min_signal: 0.1,
+        }
+    }
+}
+
+/// Number of nucleosomal and nucleosome-free fragment midpoints at every
+/// position of a region.
+#[derive(Debug, Clone)]
+pub struct FragmentProfile {
+    pub region: GenomicRange,
+    pub nucleosomal: Vec<u32>,
+    pub nucleosome_free: Vec<u32>,
+}
+
+/// A nucleosome call.
+#[derive(Debug, Clone)]
+pub struct Dyad {
+    pub chrom: String,
+    pub pos: u64,
+    /// Smoothed density of nucleosomal fragment midpoints at the dyad.
+    pub signal: f64,
+    pub occupancy: f64,
+}
+
+impl FragmentProfile {
+    pub fn new(region: GenomicRange) -> Self {
+        let n = region.len() as usize;
+        Self {
+            region,
+            nucleosomal: vec![0; n],
+            nucleosome_free: vec![0; n],
+        }
+    }
+
+    fn add(&mut self, midpoint: u64, len: u64, opts: &NucleosomeOptions) {
+        let i = (midpoint - self.region.start()) as usize;
+        if len <= opts.nfr_max {
+            self.nucleosome_free[i] += 1;
+        } else if len >= opts.nuc_min && len <= opts.nuc_max {
+            self.nucleosomal[i] += 1;
+        }
+    }
+
+    /// Nucleosome occupancy at every position, i.e., the fraction of the
+    /// smoothed fragment density contributed by nucleosomal fragments.
+    pub fn occupancy(&self, opts: &NucleosomeOptions) -> Vec<f64> {
+        let nuc = gaussian_smooth(&self.nucleosomal, opts.smooth_sd);
+        let nfr = gaussian_smooth(&self.nucleosome_free, opts.smooth_sd);
+        nuc.iter()
+            .zip(nfr.iter())
+            .map(|(a, b)| if a + b > 0.0 { a / (a + b) } else { 0.0 })
+            .collect()
+    }
+
+    /// Call the dyads as the local maxima of the smoothed density of
+    /// nucleosomal fragments, from the strongest to the weakest, discarding
+    /// those closer than `min_spacing` to a stronger one.
+    pub fn call_dyads(&self, opts: &NucleosomeOptions) -> Vec<Dyad> {
+        let signal = gaussian_smooth(&self.nucleosomal, opts.smooth_sd);
+        let occupancy = self.occupancy(opts);
+        let mut candidates: Vec<usize> = (0..signal.len())
+            .filter(|&i| {
+                signal[i] >= opts.min_signal
+                    && (i == 0 || signal[i] > signal[i - 1])
+                    && (i + 1 == signal.len() || signal[i] >= signal[i + 1])
+            })
+            .collect();
+        candidates.sort_by(|a, b| signal[*b].total_cmp(&signal[*a]));
+        let mut selected: Vec<usize> = Vec::new();
+        for i in candidates {
+            if selected.iter().all(|j| i.abs_diff(*j) as u64 >= opts.min_spacing) {
+                selected.push(i);
+            }
+        }
+        selected.sort_unstable();
+        selected
+            .into_iter()
+            .map(|i| Dyad {
+                chrom: self.region.chrom().to_string(),
+                pos: self.region.start() + i as u64,
+                signal: signal[i],
+                occupancy: occupancy[i],
+            })
+            .collect()
+    }
+}
+
+/// Smooth the counts with a Gaussian kernel truncated at 3 standard deviations.
+fn gaussian_smooth(counts: &[u32], sd: f64) -> Vec<f64> {
+    if sd <= 0.0 {
+        return counts.iter().map(|x| *x as f64).collect();
+    }
+    let half = (3.0 * sd).ceil() as usize;
+    let kernel: Vec<f64> = (0..=2 * half)
+        .map(|i| {
+            let x = i as f64 - half as f64;
+            (-0.5 * (x / sd).powi(2)).exp()
+        })
+        .collect();
+    let norm: f64 = kernel.iter().sum();
+    let mut result = vec![0.0; counts.len()];
+    counts.iter().enumerate().filter(|(_, c)| **c > 0).for_each(|(i, c)| {
+        kernel.iter().enumerate().for_each(|(k, w)| {
+            if let Some(j) = (i + k).checked_sub(half) {
+                if j < result.len() {
+                    result[j] += *c as f64 * w / norm;
+                }
+            }
+        });
+    });
+    result
+}
+
+/// Call `f` with the group, chromosome, midpoint and length of every fragment.
+/// `group_by` gives the group of each cell.
+fn for_each_fragment<A, F>(adata: &A, group_by: &[&str], mut f: F) -> Result<()>
+where
+    A: SnapData,
+    F: FnMut(&str, &str, u64, u64),
+{
+    ensure!(adata.n_obs() == group_by.len(), "lengths differ");
+    let fragments = adata.get_fragment_iter(adata.fragment_chunk_size()?)?;
+    ensure!(fragments.is_paired(), "V-plots require paired-end fragments");
+    fragments
+        .into_fragment_groups(|i| group_by[i])
+        .for_each(|groups| {
+            groups.into_iter().for_each(|(group, frags)| {
+                frags.into_iter().for_each(|(_, frag)| {
+                    let midpoint = (frag.start() + frag.end()) / 2;
+                    f(group, frag.chrom(), midpoint, frag.len())
+                })
+            })
+        });
+    Ok(())
+}
+
+/// Compute the fragment profile of every region for every group of cells.
+/// `group_by` gives the group of each cell. Fragments are assigned to the
+/// regions containing their midpoint.
+pub fn fragment_profiles<A: SnapData>(
+    adata: &A,
+    regions: &[GenomicRange],
+    group_by: &[&str],
+    opts: &NucleosomeOptions,
+) -> Result<HashMap<String, Vec<FragmentProfile>>> {
+    let index: GIntervalMap<usize> = regions
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.clone(), i))
+        .collect();
+    let mut profiles: HashMap<String, Vec<FragmentProfile>> = HashMap::new();
+    for_each_fragment(adata, group_by, |group, chrom, midpoint, len| {
+        let mid = GenomicRange::new(chrom, midpoint, midpoint + 1);
+        let mut hits = index.find(&mid).peekable();
+        if hits.peek().is_some() {
+            let group_profiles = profiles.entry(group.to_string()).or_insert_with(|| {
+                regions.iter().map(|x| FragmentProfile::new(x.clone())).collect()
+            });
+            hits.for_each(|(_, i)| group_profiles[*i].add(midpoint, len, opts));
+        }
+    })?;
+    group_by.iter().for_each(|g| {
+        profiles.entry(g.to_string()).or_insert_with(|| {
+            regions.iter().map(|x| FragmentProfile::new(x.clone())).collect()
+        });
+    });
+    Ok(profiles)
+}
+
+/// A site around which V-plots are aggregated. Offsets are measured along
+/// the strand of the anchor.
+#[derive(Debug, Clone)]
+pub struct Anchor {
+    pub chrom: String,
+    pub pos: u64,
+    pub reverse: bool,
+}
+
+/// Compute the V-plot of every group of cells aggregated over the anchors.
+/// The result has `max_len + 1` rows, the fragment lengths, and `2 * flank + 1`
+/// columns, the offsets of the fragment midpoints from the anchors, from
+/// `-flank` to `flank`. Fragments near several anchors are counted once for each.
+pub fn vplots<A: SnapData>(
+    adata: &A,
+    anchors: &[Anchor],
+    group_by: &[&str],
+    flank: u64,
+    max_len: u64,
+) -> Result<HashMap<String, Array2<u32>>> {
+    let index: GIntervalMap<usize> = anchors
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            let region = GenomicRange::new(&x.chrom, x.pos.saturating_sub(flank), x.pos + flank + 1);
+            (region, i)
+        })
+        .collect();
+    let shape = (max_len as usize + 1, 2 * flank as usize + 1);
+    let mut result: HashMap<String, Array2<u32>> = HashMap::new();
+    for_each_fragment(adata, group_by, |group, chrom, midpoint, len| {
+        if len > max_len {
+            return;
+        }
+        let mid = GenomicRange::new(chrom, midpoint, midpoint + 1);
+        let mut hits = index.find(&mid).peekable();
+        if hits.peek().is_some() {
+            let mat = result
+                .entry(group.to_string())
+                .or_insert_with(|| Array2::zeros(shape));
+            hits.for_each(|(_, i)| {
+                let anchor = &anchors[*i];
+                let offset = if anchor.reverse {
+                    anchor.pos as i64 - midpoint as i64
+                } else {
+                    midpoint as i64 - anchor.pos as i64
+                };
+                mat[[len as usize, (offset + flank as i64) as usize]] += 1;
+            });
+        }
+    })?;
+    group_by.iter().for_each(|g| {
+        result.entry(g.to_string()).or_insert_with(|| Array2::zeros(shape));
+    });
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gaussian_smooth() {
+        let smoothed = gaussian_smooth(&[0, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0], 1.0);
+        assert!((smoothed.iter().sum::<f64>() - 10.0).abs() < 1e-9);
+        assert!(smoothed[5] > smoothed[4] && smoothed[4] > smoothed[3]);
+        assert!((smoothed[4] - smoothed[6]).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_call_dyads() {
+        let opts = NucleosomeOptions {
+            smooth_sd: 5.0,
+            ..Default::default()
+        };
+        let mut profile = FragmentProfile::new(GenomicRange::new("chr1", 1000, 1600));
+        // Nucleosomes at 1100 and 1400, with linker fragments in between.
+        for _ in 0..20 {
+            profile.add(1100, 160, &opts);
+            profile.add(1400, 180, &opts);
+            profile.add(1250, 60, &opts);
+        }
+        profile.add(1150, 160, &opts);
+        let dyads = profile.call_dyads(&opts);
+        assert_eq!(dyads.iter().map(|x| x.pos).collect::<Vec<_>>(), vec![1100, 1400]);
+        assert!(dyads[0].occupancy > 0.9);
+        assert!(profile.occupancy(&opts)[250] < 0.1);
+    }
+}
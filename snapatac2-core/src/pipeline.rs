@@ -0,0 +1,397 @@
+//! Declarative analysis recipes.
+//!
+//! A recipe lists the steps of a standard analysis (import, QC filtering, tile
+//! matrix, feature selection, embedding, clustering and track export) with
+//! their parameters, in TOML or JSON:
+//!
+//! ```toml
+//! [input]
+//! fragment_file = "fragments.tsv.gz"
+//! output = "result.h5ad"
+//! genome = "hg38"
+//!
+//! [[steps]]
+//! step = "import"
+//! min_num_fragments = 500
+//!
+//! [[steps]]
+//! step = "tile_matrix"
+//! bin_size = 500
+//! ```
+//!
+//! Parameters that are omitted take their usual default values. After each
+//! step, its key is appended to `.uns["snapatac2_pipeline"]` of the output
+//! object, so an interrupted run can be resumed from the first step that has
+//! not been completed. Changing the parameters of a step invalidates the
+//! checkpoints of that step and all subsequent ones.
+
+use crate::export::Normalization;
+use crate::feature_count::CountingStrategy;
+
+use anndata::{AnnDataOp, ElemCollectionOp};
+use anyhow::{bail, ensure, Context, Result};
+use ndarray::Array1;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub const CHECKPOINT_KEY: &str = "snapatac2_pipeline";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Recipe {
+    pub input: Input,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Input {
+    pub fragment_file: PathBuf,
+    /// The backed AnnData file storing the results and the checkpoints.
+    pub output: PathBuf,
+    /// Name of a built-in genome, e.g., "hg38". Used for chromosome sizes and
+    /// gene annotations.
+    #[serde(default)]
+    pub genome: Option<String>,
+    /// Chromosome sizes, used if `genome` is not given.
+    #[serde(default)]
+    pub chrom_sizes: Option<BTreeMap<String, u64>>,
+    #[serde(default = "default_true")]
+    pub is_paired: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Step {
+    Import {
+        #[serde(default = "default_min_num_fragments")]
+        min_num_fragments: u64,
+        #[serde(default = "default_true")]
+        sorted_by_barcode: bool,
+        #[serde(default = "default_chrm")]
+        chrm: Vec<String>,
+        #[serde(default)]
+        whitelist: Option<PathBuf>,
+    },
+    /// Filter cells by the number of fragments and the TSS enrichment score.
+    /// The TSS enrichment score is computed if a TSS threshold is set.
+    QcFilter {
+        #[serde(default = "default_min_counts")]
+        min_counts: Option<u64>,
+        #[serde(default)]
+        max_counts: Option<u64>,
+        #[serde(default = "default_min_tsse")]
+        min_tsse: Option<f64>,
+        #[serde(default)]
+        max_tsse: Option<f64>,
+    },
+    TileMatrix {
+        #[serde(default = "default_bin_size")]
+        bin_size: usize,
+        #[serde(default = "default_exclude_chroms")]
+        exclude_chroms: Vec<String>,
+        #[serde(default = "default_counting_strategy")]
+        counting_strategy: String,
+    },
+    SelectFeatures {
+        #[serde(default = "default_n_features")]
+        n_features: usize,
+        #[serde(default)]
+        blacklist: Option<PathBuf>,
+    },
+    Embed {
+        #[serde(default)]
+        method: EmbedMethod,
+        #[serde(default = "default_n_comps")]
+        n_comps: usize,
+        #[serde(default)]
+        random_state: u64,
+    },
+    Cluster {
+        #[serde(default)]
+        method: ClusterMethod,
+        #[serde(default = "default_n_neighbors")]
+        n_neighbors: usize,
+        #[serde(default = "default_resolution")]
+        resolution: f64,
+        /// Number of clusters, used by k-means.
+        #[serde(default)]
+        n_clusters: Option<usize>,
+        #[serde(default)]
+        random_state: u64,
+    },
+    ExportTracks {
+        #[serde(default)]
+        groupby: Option<String>,
+        out_dir: PathBuf,
+        #[serde(default = "default_track_bin_size")]
+        bin_size: usize,
+        #[serde(default = "default_normalization")]
+        normalization: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbedMethod {
+    #[default]
+    Spectral,
+    Pca,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClusterMethod {
+    #[default]
+    Leiden,
+    Kmeans,
+}
+
+fn default_true() -> bool {
+    true
+}
+fn default_min_num_fragments() -> u64 {
+    200
+}
+fn default_chrm() -> Vec<String> {
+    vec!["chrM".to_string(), "M".to_string()]
+}
+fn default_min_counts() -> Option<u64> {
+    Some(1000)
+}
+fn default_min_tsse() -> Option<f64> {
+    Some(5.0)
+}
+fn default_bin_size() -> usize {
+    500
+}
+fn default_exclude_chroms() -> Vec<String> {
+    ["chrM", "chrY", "M", "Y"].iter().map(|x| x.to_string()).collect()
+}
+fn default_counting_strategy() -> String {
+    "paired-insertion".to_string()
+}
+fn default_n_features() -> usize {
+    500000
+}
+fn default_n_comps() -> usize {
+    30
+}
+fn default_n_neighbors() -> usize {
+    50
+}
+fn default_resolution() -> f64 {
+    1.0
+}
+fn default_track_bin_size() -> usize {
+    10
+}
+fn default_normalization() -> Option<String> {
+    Some("RPKM".to_string())
+}
+
+impl Step {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Step::Import { .. } => "import",
+            Step::QcFilter { .. } => "qc_filter",
+            Step::TileMatrix { .. } => "tile_matrix",
+            Step::SelectFeatures { .. } => "select_features",
+            Step::Embed { .. } => "embed",
+            Step::Cluster { .. } => "cluster",
+            Step::ExportTracks { .. } => "export_tracks",
+        }
+    }
+
+    /// Steps that must run before this one.
+    fn requires(&self) -> &'static [&'static str] {
+        match self {
+            Step::Import { .. } => &[],
+            Step::QcFilter { .. } | Step::TileMatrix { .. } => &["import"],
+            Step::SelectFeatures { .. } => &["tile_matrix"],
+            Step::Embed { .. } => &["select_features"],
+            Step::Cluster { .. } => &["embed"],
+            Step::ExportTracks { .. } => &["import"],
+        }
+    }
+}
+
+impl Recipe {
+    /// Parse a recipe. `format` is either "toml" or "json".
+    pub fn parse(s: &str, format: &str) -> Result<Self> {
+        let recipe: Self = match format {
+            "toml" => toml::from_str(s)?,
+            "json" => serde_json::from_str(s)?,
+            _ => bail!("recipe format must be 'toml' or 'json', got '{}'", format),
+        };
+        recipe.validate()?;
+        Ok(recipe)
+    }
+
+    /// Read a recipe from a file. The format is determined by the extension.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read recipe: {}", path.display()))?;
+        let format = match path.extension().and_then(|x| x.to_str()) {
+            Some("toml") => "toml",
+            _ => "json",
+        };
+        Self::parse(&text, format).with_context(|| format!("invalid recipe: {}", path.display()))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.input.genome.is_some() || self.input.chrom_sizes.is_some(),
+            "either 'genome' or 'chrom_sizes' must be given in the input section"
+        );
+        ensure!(
+            matches!(self.steps.first(), Some(Step::Import { .. })),
+            "the first step must be 'import'"
+        );
+        for (i, step) in self.steps.iter().enumerate() {
+            for req in step.requires() {
+                ensure!(
+                    self.steps[..i].iter().any(|x| x.name() == *req),
+                    "step {} ('{}') requires a preceding '{}' step",
+                    i + 1,
+                    step.name(),
+                    req
+                );
+            }
+            match step {
+                Step::Import { .. } if i > 0 => bail!("'import' can only be the first step"),
+                Step::QcFilter { min_tsse, max_tsse, .. } => ensure!(
+                    (min_tsse.is_none() && max_tsse.is_none()) || self.input.genome.is_some(),
+                    "filtering by TSS enrichment requires 'genome' in the input section"
+                ),
+                Step::TileMatrix { bin_size, counting_strategy, .. } => {
+                    ensure!(*bin_size > 0, "bin_size must be positive");
+                    CountingStrategy::try_from(counting_strategy.as_str())?;
+                }
+                Step::Cluster { method: ClusterMethod::Kmeans, n_clusters: None, .. } => {
+                    bail!("'n_clusters' must be given for k-means clustering")
+                }
+                Step::ExportTracks { groupby, normalization, .. } => {
+                    if let Some(norm) = normalization {
+                        Normalization::from_str(norm).map_err(anyhow::Error::msg)?;
+                    }
+                    ensure!(
+                        groupby.is_some() || self.steps[..i].iter().any(|x| x.name() == "cluster"),
+                        "'groupby' must be given if there is no preceding 'cluster' step"
+                    );
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The checkpoint key of the `i`-th step. It includes all parameters, so
+    /// that a step is re-run when its parameters change.
+    pub fn step_key(&self, i: usize) -> String {
+        format!("{}:{}", i, serde_json::to_string(&self.steps[i]).unwrap())
+    }
+
+    /// Index of the first step that has not been completed, given the keys
+    /// of the completed steps.
+    pub fn first_pending_step(&self, completed: &[String]) -> usize {
+        (0..self.steps.len())
+            .zip(completed)
+            .take_while(|(i, key)| self.step_key(*i) == **key)
+            .count()
+    }
+}
+
+/// Keys of the steps completed so far.
+pub fn completed_steps<A: AnnDataOp>(adata: &A) -> Result<Vec<String>> {
+    Ok(adata
+        .uns()
+        .get_item::<Array1<String>>(CHECKPOINT_KEY)?
+        .map_or(Vec::new(), |x| x.to_vec()))
+}
+
+/// Mark the `i`-th step as completed. Checkpoints of later steps are removed,
+/// as they were computed from a different state.
+pub fn mark_completed<A: AnnDataOp>(adata: &A, i: usize, key: String) -> Result<()> {
+    let mut completed = completed_steps(adata)?;
+    completed.truncate(i);
+    completed.push(key);
+    adata.uns().add(CHECKPOINT_KEY, Array1::from_vec(completed))
+}
+
+/// Index of the step from which a run of `recipe` on `adata` resumes.
+pub fn resume_step<A: AnnDataOp>(recipe: &Recipe, adata: &A) -> Result<usize> {
+    Ok(recipe.first_pending_step(&completed_steps(adata)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECIPE: &str = r#"
+[input]
+fragment_file = "fragments.tsv.gz"
+output = "result.h5ad"
+genome = "hg38"
+
+[[steps]]
+step = "import"
+
+[[steps]]
+step = "qc_filter"
+min_counts = 500
+
+[[steps]]
+step = "tile_matrix"
+
+[[steps]]
+step = "select_features"
+
+[[steps]]
+step = "embed"
+
+[[steps]]
+step = "cluster"
+resolution = 0.5
+
+[[steps]]
+step = "export_tracks"
+out_dir = "tracks"
+"#;
+
+    #[test]
+    fn test_parse_recipe() {
+        let recipe = Recipe::parse(RECIPE, "toml").unwrap();
+        assert_eq!(recipe.steps.len(), 7);
+        assert_eq!(
+            recipe.steps[1],
+            Step::QcFilter { min_counts: Some(500), max_counts: None, min_tsse: Some(5.0), max_tsse: None }
+        );
+        assert!(matches!(recipe.steps[2], Step::TileMatrix { bin_size: 500, .. }));
+
+        let json = serde_json::to_string(&recipe).unwrap();
+        assert_eq!(Recipe::parse(&json, "json").unwrap(), recipe);
+
+        let bad = RECIPE.replace("step = \"select_features\"", "step = \"select_features\"\nn_feature = 10");
+        assert!(Recipe::parse(&bad, "toml").is_err());
+        let bad = RECIPE.replace("[[steps]]\nstep = \"tile_matrix\"\n", "");
+        assert!(Recipe::parse(&bad, "toml").is_err());
+    }
+
+    #[test]
+    fn test_first_pending_step() {
+        let recipe = Recipe::parse(RECIPE, "toml").unwrap();
+        let mut completed: Vec<String> = (0..3).map(|i| recipe.step_key(i)).collect();
+        assert_eq!(recipe.first_pending_step(&completed), 3);
+
+        let mut changed = recipe.clone();
+        changed.steps[1] = Step::QcFilter { min_counts: Some(1000), max_counts: None, min_tsse: None, max_tsse: None };
+        assert_eq!(changed.first_pending_step(&completed), 1);
+
+        completed.truncate(0);
+        assert_eq!(recipe.first_pending_step(&completed), 0);
+    }
+}
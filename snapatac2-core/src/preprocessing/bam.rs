@@ -1,7 +1,7 @@
 mod mark_duplicates;
 mod header;
 mod flagstat;
-pub use mark_duplicates::{group_bam_by_barcode, BarcodeLocation};
+pub use mark_duplicates::{group_bam_by_barcode, BarcodeLocation, DedupPolicy};
 pub use flagstat::{filter_bam, FlagStat, BamQC};
 
 use bstr::BString;
@@ -90,6 +90,8 @@ impl FragmentQC {
 /// * `umi_tag` - Extract UMI from TAG fields of BAM records.
 /// * `umi_regex` - Extract UMI from read names of BAM records using regular expressions.
 ///     See `barcode_regex` for more details.
+/// * `dedup_policy` - How PCR duplicates are identified. If `None`, duplicates are
+///     identified by coordinates and, when `umi_tag` or `umi_regex` is set, UMIs.
 /// * `shift_left` - Insertion site correction for the left end.
 /// * `shift_right` - Insertion site correction for the right end.
 /// * `chunk_size` - The size of data retained in memory when performing sorting. Larger chunk sizes
@@ -106,6 +108,7 @@ pub fn make_fragment_file<P1: AsRef<Path>, P2: AsRef<Path>, P3: AsRef<Path>>(
     barcode_regex: Option<&str>,
     umi_tag: Option<[u8; 2]>,
     umi_regex: Option<&str>,
+    dedup_policy: Option<DedupPolicy>,
     shift_left: i64,
     shift_right: i64,
     mapq: Option<u8>,
@@ -136,6 +139,10 @@ pub fn make_fragment_file<P1: AsRef<Path>, P2: AsRef<Path>, P3: AsRef<Path>>(
             None => None,
         },
     };
+    let dedup_policy = dedup_policy.unwrap_or(DedupPolicy::default_for(umi.is_some()));
+    if dedup_policy == DedupPolicy::Umi && umi.is_none() {
+        bail!("UMI-aware deduplication requires umi_tag or umi_regex to be set");
+    }
 
     let mut reader = bam::io::reader::Builder::default().build_from_path(bam_file)?;
 
@@ -173,20 +180,23 @@ pub fn make_fragment_file<P1: AsRef<Path>, P2: AsRef<Path>, P3: AsRef<Path>>(
     group_bam_by_barcode(
         filtered_records,
         is_paired,
+        dedup_policy,
         temp_dir,
         chunk_size,
     )
     .into_fragments(&header)
     .progress_with(spinner)
-    .for_each(|barcode| barcode.into_iter().for_each(|mut rec| {
+    .try_for_each(|barcode| barcode.into_iter().try_for_each(|mut rec| {
         if rec.strand().is_none() { // perform fragment length correction for paired-end reads
             rec.set_start(rec.start().saturating_add_signed(shift_left));
             rec.set_end(rec.end().saturating_add_signed(shift_right));
         }
         if rec.len() > 0 {
             fragment_qc.update(&rec);
-            writeln!(output, "{}", rec).unwrap();
+            writeln!(output, "{}", rec)?;
         }
-    }));
+        std::io::Result::Ok(())
+    }))?;
+    output.finish()?;
     Ok((library_qc, fragment_qc))
 }
\ No newline at end of file
@@ -90,6 +90,47 @@ impl BarcodeLocation {
     }
 }
 
+/// How duplicate reads are identified. Reads are only compared with reads of
+/// the same cell barcode.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum DedupPolicy {
+    /// Reads (or read pairs) whose 5' ends and orientation are identical.
+    Coordinate,
+    /// Read pairs whose leftmost reads have the same 5' end and orientation,
+    /// regardless of the position of the mate. Same as `Coordinate` for
+    /// single-end reads.
+    FivePrime,
+    /// Like `Coordinate`, but reads must also have the same UMI.
+    Umi,
+    /// Do not remove duplicates.
+    None,
+}
+
+impl DedupPolicy {
+    /// The default policy: UMI-aware if UMIs are available.
+    pub fn default_for(has_umi: bool) -> Self {
+        if has_umi {
+            DedupPolicy::Umi
+        } else {
+            DedupPolicy::Coordinate
+        }
+    }
+}
+
+impl std::str::FromStr for DedupPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "coordinate" => Ok(DedupPolicy::Coordinate),
+            "5prime" | "five_prime" => Ok(DedupPolicy::FivePrime),
+            "umi" => Ok(DedupPolicy::Umi),
+            "none" => Ok(DedupPolicy::None),
+            _ => Err(format!("unknown deduplication policy: {}", s)),
+        }
+    }
+}
+
 /// Reads are considered duplicates if and only if they have the same fingerprint.
 #[derive(Eq, PartialEq, Debug, Hash)]
 pub enum FingerPrint {
@@ -107,11 +148,16 @@ pub enum FingerPrint {
         orientation: Orientation,
         barcode: Option<String>,
     },
+    /// Used when duplicates are not removed: read names are unique.
+    ReadName(String),
 }
 
 impl FingerPrint {
     /// Extract the fingerprint from a single-end BAM record.
-    pub fn from_single_read(read: &AlignmentInfo) -> FingerPrint {
+    pub fn from_single_read(read: &AlignmentInfo, policy: DedupPolicy) -> FingerPrint {
+        if policy == DedupPolicy::None {
+            return FingerPrint::ReadName(read.name.clone());
+        }
         let orientation = if read.flags().is_reverse_complemented() {
             Orientation::RR
         } else {
@@ -125,7 +171,7 @@ impl FingerPrint {
                 read.unclipped_end
             },
             orientation,
-            barcode: read.umi.clone(),
+            barcode: if policy == DedupPolicy::Umi { read.umi.clone() } else { None },
         }
     }
 
@@ -133,8 +179,13 @@ impl FingerPrint {
     pub fn from_paired_reads(
         this: &AlignmentInfo,
         other: &AlignmentInfo,
+        policy: DedupPolicy,
     ) -> FingerPrint {
         if this.umi != other.umi { panic!("UMI mismatch"); }
+        if policy == DedupPolicy::None {
+            return FingerPrint::ReadName(this.name.clone());
+        }
+        let umi = if policy == DedupPolicy::Umi { this.umi.clone() } else { None };
 
         let this_flags = this.flags();
         let other_flags = other.flags();
@@ -166,6 +217,14 @@ impl FingerPrint {
             read2 = (this_flags, this_ref, this_coord_5p, this_is_rev);
             read1 = (other_flags, other_ref, other_coord_5p, other_is_rev);
         }
+        if policy == DedupPolicy::FivePrime {
+            return FingerPrint::SingleRead {
+                reference_id: read1.1,
+                coord_5p: read1.2,
+                orientation: if read1.3 { Orientation::RR } else { Orientation::FF },
+                barcode: umi,
+            };
+        }
         let orientation = if read1.3 == read2.3 {
             if read1.3 {
                 if read1.0.is_first_segment() { Orientation::RR } else { Orientation::FF }
@@ -181,7 +240,7 @@ impl FingerPrint {
             left_coord_5p: read1.2,
             right_coord_5p: read2.2,
             orientation,
-            barcode: this.umi.clone(),
+            barcode: umi,
         }
     }
 }
@@ -190,6 +249,7 @@ impl FingerPrint {
 pub fn group_bam_by_barcode<I, P>(
     reads: I,
     is_paired: bool,
+    policy: DedupPolicy,
     temp_dir: Option<P>,
     chunk_size: usize,
 ) -> RecordGroups<impl Iterator<Item = AlignmentInfo>, impl FnMut(&AlignmentInfo) -> String>
@@ -211,7 +271,7 @@ where
         .map(|x| x.unwrap())
         .chunk_by(|x| x.barcode.as_ref().unwrap().clone());
 
-    RecordGroups {is_paired, groups}
+    RecordGroups {is_paired, policy, groups}
 }
 
 pub struct RecordGroups<I, F>
@@ -220,6 +280,7 @@ pub struct RecordGroups<I, F>
         F: FnMut(&AlignmentInfo) -> String,
 {
     is_paired:  bool,
+    policy: DedupPolicy,
     groups: itertools::ChunkBy<String, I, F>,
 }
 
@@ -229,7 +290,7 @@ where
     F: FnMut(&AlignmentInfo) -> String,
 {
     pub fn into_fragments<'a>(&'a self, header: &'a Header) -> impl Iterator<Item = Vec<Fragment>> + 'a {
-        self.groups.into_iter().map(|(_, rec)| get_unique_fragments(rec, header, self.is_paired))
+        self.groups.into_iter().map(|(_, rec)| get_unique_fragments(rec, header, self.is_paired, self.policy))
     }
 }
 
@@ -237,12 +298,13 @@ fn get_unique_fragments<I>(
     reads: I,
     header: &Header,
     is_paired: bool,
+    policy: DedupPolicy,
 ) -> Vec<Fragment>
 where
     I: Iterator<Item = AlignmentInfo>,
 {
     if is_paired {
-        let mut result: Vec<_> = rm_dup_pair(reads).flat_map(move |(rec1, rec2, c)| {
+        let mut result: Vec<_> = rm_dup_pair(reads, policy).flat_map(move |(rec1, rec2, c)| {
             let ref_id1: usize = rec1.reference_sequence_id.try_into().unwrap();
             let ref_id2: usize = rec2.reference_sequence_id.try_into().unwrap();
             if ref_id1 != ref_id2 { return None; }
@@ -262,10 +324,10 @@ where
                 strand: None,
             }.into())
         }).collect();
-        result.par_sort_unstable_by(|a, b| BEDLike::compare(a, b));
+        crate::config::install(None, || result.par_sort_unstable_by(|a, b| BEDLike::compare(a, b)));
         result
     } else {
-        rm_dup_single(reads).map(move |(r, c)| {
+        rm_dup_single(reads, policy).map(move |(r, c)| {
             let ref_id: usize = r.reference_sequence_id.try_into().unwrap();
             SingleRead {
                 chrom: header.reference_sequences().get_index(ref_id).unwrap().0.to_string(),
@@ -284,14 +346,14 @@ where
 }
 
 /// Remove duplicate single-end reads.
-fn rm_dup_single<I>(reads: I) -> impl Iterator<Item = (AlignmentInfo, usize)>
+fn rm_dup_single<I>(reads: I, policy: DedupPolicy) -> impl Iterator<Item = (AlignmentInfo, usize)>
 where
     I: Iterator<Item = AlignmentInfo>,
 {
     let mut result = HashMap::new();
     reads.for_each(|read| {
         let score = read.sum_of_qual_scores;
-        let key = FingerPrint::from_single_read(&read);
+        let key = FingerPrint::from_single_read(&read, policy);
         match result.get_mut(&key) {
             None => { result.insert(key, (read, score, 1)); },
             Some(val) => {
@@ -307,13 +369,13 @@ where
 }
 
 /// Remove duplicate paired-end reads.
-fn rm_dup_pair<I>(reads: I) -> impl Iterator<Item = (AlignmentInfo, AlignmentInfo, usize)>
+fn rm_dup_pair<I>(reads: I, policy: DedupPolicy) -> impl Iterator<Item = (AlignmentInfo, AlignmentInfo, usize)>
 where
     I: Iterator<Item = AlignmentInfo>,
 {
     // Sort the reads by name, so that paired reads are next to each other.
     let mut sorted_reads: Vec<_> = reads.collect();
-    sorted_reads.par_sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    crate::config::install(None, || sorted_reads.par_sort_unstable_by(|a, b| a.name.cmp(&b.name)));
 
     let mut result = HashMap::new();
     sorted_reads.into_iter().fold(None, |state: Option<AlignmentInfo>, cur_rec| match state {
@@ -325,7 +387,7 @@ where
             };
             let score1 = read1.sum_of_qual_scores;
             let score2 = read2.sum_of_qual_scores;
-            let key = FingerPrint::from_paired_reads(&read1, &read2);
+            let key = FingerPrint::from_paired_reads(&read1, &read2, policy);
             match result.get_mut(&key) {
                 None => { result.insert(key, (read1, score1, read2, score2, 1)); },
                 Some(val) => {
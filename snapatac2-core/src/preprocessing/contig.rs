@@ -0,0 +1,252 @@
+//! Handling of the alternate contigs, patches and unplaced scaffolds of
+//! patched references (e.g., GRCh38 with ALT contigs).
+//!
+//! Fragments aligned to such sequences are either dropped, kept as they are,
+//! or lifted over to the primary assembly with a UCSC chain file. The policy
+//! is applied when the fragments are imported, so that every sequence in the
+//! data is also in the chromosome sizes stored alongside.
+
+use anyhow::{bail, Context, Result};
+use bed_utils::bed::{BEDLike, Strand};
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+
+use crate::genome::ChromSizes;
+use crate::preprocessing::Fragment;
+use crate::utils::open_file_for_read;
+
+/// Whether a sequence belongs to the primary assembly. Alternate loci, fix and
+/// novel patches, unlocalized and unplaced scaffolds, decoys and HLA sequences
+/// are not.
+pub fn is_primary_contig(chrom: &str) -> bool {
+    !(chrom.contains('_')
+        || chrom.starts_with("HLA-")
+        || chrom.starts_with("chrUn")
+        || chrom.starts_with("GL")
+        || chrom.starts_with("KI")
+        || chrom.starts_with("JH")
+        || chrom.starts_with("KN")
+        || chrom.starts_with("KQ")
+        || chrom.starts_with("KV")
+        || chrom.starts_with("KZ")
+        || chrom.eq_ignore_ascii_case("chrEBV")
+        || chrom.eq_ignore_ascii_case("EBV"))
+}
+
+/// An ungapped block of a chain: `[start, end)` on the source sequence
+/// aligned to `target_start..` on the target sequence.
+#[derive(Debug, Clone)]
+struct ChainBlock {
+    start: u64,
+    end: u64,
+    target: String,
+    target_start: u64,
+    target_size: u64,
+    reverse: bool,
+}
+
+/// Alignments between sequences, read from a UCSC chain file. As with
+/// liftOver, the reference ("t") sequence of the chains is the source and the
+/// query ("q") sequence is the destination.
+#[derive(Debug, Clone, Default)]
+pub struct ChainMap {
+    blocks: HashMap<String, Vec<ChainBlock>>,
+}
+
+impl ChainMap {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let reader = std::io::BufReader::new(open_file_for_read(path.as_ref())?);
+        Self::from_reader(reader)
+            .with_context(|| format!("failed to read the chain file {}", path.as_ref().display()))
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self> {
+        let mut blocks: HashMap<String, Vec<ChainBlock>> = HashMap::new();
+        // Source name, source position, target name, target position, target size, reverse.
+        let mut current: Option<(String, u64, String, u64, u64, bool)> = None;
+        for line in reader.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() || fields[0].starts_with('#') {
+                continue;
+            }
+            if fields[0] == "chain" {
+                if fields.len() < 12 {
+                    bail!("invalid chain header: {}", line);
+                }
+                current = Some((
+                    fields[2].to_string(),
+                    fields[5].parse()?,
+                    fields[7].to_string(),
+                    fields[10].parse()?,
+                    fields[8].parse()?,
+                    fields[9] == "-",
+                ));
+                continue;
+            }
+            let Some((source, pos, target, target_pos, target_size, reverse)) = current.as_mut() else {
+                bail!("alignment data before the chain header: {}", line);
+            };
+            let size: u64 = fields[0].parse()?;
+            blocks.entry(source.clone()).or_default().push(ChainBlock {
+                start: *pos,
+                end: *pos + size,
+                target: target.clone(),
+                target_start: *target_pos,
+                target_size: *target_size,
+                reverse: *reverse,
+            });
+            if fields.len() >= 3 {
+                *pos += size + fields[1].parse::<u64>()?;
+                *target_pos += size + fields[2].parse::<u64>()?;
+            } else {
+                current = None;
+            }
+        }
+        blocks.values_mut().for_each(|x| x.sort_by_key(|b| b.start));
+        Ok(Self { blocks })
+    }
+
+    /// Map a position to the target sequence. Returns the target sequence,
+    /// the position on its forward strand, and whether the strand is reversed.
+    pub fn map_position(&self, chrom: &str, pos: u64) -> Option<(&str, u64, bool)> {
+        let blocks = self.blocks.get(chrom)?;
+        let i = blocks.partition_point(|b| b.start <= pos).checked_sub(1)?;
+        let block = &blocks[i];
+        if pos >= block.end {
+            return None;
+        }
+        let offset = block.target_start + (pos - block.start);
+        let target_pos = if block.reverse {
+            block.target_size - 1 - offset
+        } else {
+            offset
+        };
+        Some((block.target.as_str(), target_pos, block.reverse))
+    }
+
+    /// Lift a fragment over to the target sequence. Fragments whose ends map
+    /// to different sequences or strands, or to positions inconsistent with
+    /// the fragment length, are discarded.
+    pub fn map_fragment(&self, mut fragment: Fragment) -> Option<Fragment> {
+        let (chrom_a, a, rev_a) = self.map_position(fragment.chrom(), fragment.start())?;
+        let (chrom_b, b, rev_b) = self.map_position(fragment.chrom(), fragment.end() - 1)?;
+        if chrom_a != chrom_b || rev_a != rev_b {
+            return None;
+        }
+        let (start, end) = (a.min(b), a.max(b) + 1);
+        // Reject fragments spanning large gaps of the alignment.
+        if end - start > 2 * fragment.len() {
+            return None;
+        }
+        let chrom = chrom_a.to_string();
+        fragment.set_chrom(&chrom).set_start(start).set_end(end);
+        if rev_a {
+            let flip = |s: Strand| match s {
+                Strand::Forward => Strand::Reverse,
+                Strand::Reverse => Strand::Forward,
+            };
+            match &mut fragment {
+                Fragment::Single(x) => x.strand = flip(x.strand),
+                Fragment::Paired(x) => x.strand = x.strand.map(flip),
+            }
+        }
+        Some(fragment)
+    }
+}
+
+/// How to handle fragments aligned to sequences outside the primary assembly.
+#[derive(Debug, Clone)]
+pub enum ContigPolicy {
+    /// Discard the fragments and the sequences.
+    Drop,
+    /// Keep the fragments as they are.
+    Keep,
+    /// Lift the fragments over to the primary assembly. Fragments that cannot
+    /// be lifted over are discarded.
+    Remap(ChainMap),
+}
+
+impl ContigPolicy {
+    pub fn new(policy: &str, chain_file: Option<&Path>) -> Result<Self> {
+        match policy {
+            "drop" => Ok(ContigPolicy::Drop),
+            "keep" => Ok(ContigPolicy::Keep),
+            "remap" => match chain_file {
+                Some(file) => Ok(ContigPolicy::Remap(ChainMap::from_file(file)?)),
+                None => bail!("a chain file is required to remap alternate contigs"),
+            },
+            _ => bail!("unknown contig policy: {}, must be one of 'drop', 'keep' or 'remap'", policy),
+        }
+    }
+
+    /// The sequences to keep in the chromosome sizes.
+    pub fn chrom_sizes(&self, chrom_sizes: &ChromSizes) -> ChromSizes {
+        match self {
+            ContigPolicy::Keep => chrom_sizes.clone(),
+            _ => chrom_sizes
+                .into_iter()
+                .filter(|(chr, _)| is_primary_contig(chr))
+                .map(|(chr, size)| (chr.clone(), *size))
+                .collect(),
+        }
+    }
+
+    pub fn apply(&self, fragment: Fragment) -> Option<Fragment> {
+        if is_primary_contig(fragment.chrom()) {
+            return Some(fragment);
+        }
+        match self {
+            ContigPolicy::Drop => None,
+            ContigPolicy::Keep => Some(fragment),
+            ContigPolicy::Remap(chain) => chain
+                .map_fragment(fragment)
+                .filter(|x| is_primary_contig(x.chrom())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preprocessing::PairRead;
+
+    #[test]
+    fn test_primary_contig() {
+        assert!(is_primary_contig("chr1"));
+        assert!(is_primary_contig("X"));
+        assert!(is_primary_contig("chrM"));
+        assert!(!is_primary_contig("chr6_GL000250v2_alt"));
+        assert!(!is_primary_contig("chrUn_KI270742v1"));
+        assert!(!is_primary_contig("HLA-A*01:01:01:01"));
+        assert!(!is_primary_contig("GL000220.1"));
+    }
+
+    #[test]
+    fn test_remap() {
+        let chain = "\
+chain 1000 chr1_alt 520 + 0 510 chr1 10000 + 1000 1520 1
+200 10 20
+300
+
+chain 1000 chr2_alt 100 + 0 100 chr2 1000 - 0 100 2
+100
+";
+        let map = ChainMap::from_reader(chain.as_bytes()).unwrap();
+        assert_eq!(map.map_position("chr1_alt", 0), Some(("chr1", 1000, false)));
+        assert_eq!(map.map_position("chr1_alt", 205), None);
+        assert_eq!(map.map_position("chr1_alt", 210), Some(("chr1", 1220, false)));
+        assert_eq!(map.map_position("chr2_alt", 0), Some(("chr2", 999, true)));
+
+        let policy = ContigPolicy::Remap(map);
+        let frag = Fragment::Paired(PairRead::new("chr1_alt", 250, 400));
+        let mapped = policy.apply(frag).unwrap();
+        assert_eq!((mapped.chrom(), mapped.start(), mapped.end()), ("chr1", 1260, 1410));
+        let frag = Fragment::Paired(PairRead::new("chr2_alt", 10, 20));
+        let mapped = policy.apply(frag).unwrap();
+        assert_eq!((mapped.chrom(), mapped.start(), mapped.end()), ("chr2", 980, 990));
+        assert!(policy.apply(Fragment::Paired(PairRead::new("chr3_alt", 10, 20))).is_none());
+        assert!(ContigPolicy::Drop.apply(Fragment::Paired(PairRead::new("chr1_alt", 0, 10))).is_none());
+    }
+}
@@ -1,23 +1,35 @@
-use crate::feature_count::{BaseValue, ContactData, BASE_VALUE, FRAGMENT_PAIRED, FRAGMENT_SINGLE};
+use crate::feature_count::{
+    modality_key, BaseValue, ContactData, BASE_VALUE, FRAGMENT_PAIRED, FRAGMENT_SINGLE,
+};
+use crate::config::{MissingChromPolicy, OutOfBounds};
 use crate::genome::{ChromSizes, GenomeBaseIndex};
 use crate::preprocessing::qc::{Contact, Fragment, FragmentQC, FragmentQCBuilder};
+use crate::provenance;
+use crate::recovery::{self, Element};
+use crate::utils::checkpoint::Checkpoint;
+use crate::utils::memory::{AdaptiveChunks, MemoryTracker};
 
 use super::qc::BaseValueQC;
-use anndata::data::DynCsrMatrix;
+use anndata::data::{CsrNonCanonical, DynCsrMatrix};
 use anndata::{
     data::array::utils::{from_csr_data, to_csr_data},
     AnnDataOp, ArrayData, AxisArraysOp, ElemCollectionOp,
 };
-use anyhow::Result;
-use bed_utils::bed::{map::GIntervalIndexSet, BEDLike, Strand};
+use anyhow::{anyhow, bail, Context, Result};
+use bitcode::{Decode, Encode};
+use bed_utils::bed::{
+    map::{GIntervalIndexSet, GIntervalMap},
+    BEDLike, Strand,
+};
 use indexmap::IndexSet;
 use indicatif::{style::ProgressStyle, ProgressBar, ProgressDrawTarget, ProgressIterator};
 use itertools::Itertools;
-use log::warn;
+use log::{info, warn};
 use nalgebra_sparse::CsrMatrix;
 use polars::prelude::{Column, DataFrame, Series};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use std::collections::{BTreeMap, HashSet};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 /// Import fragments
 /// Fragments are reprensented as a sparse matrix with rows as barcodes and columns as genomic coordinates.
@@ -28,6 +40,15 @@ use std::collections::{BTreeMap, HashSet};
 /// will be encoded as:
 /// X X 3 X -3 X X X X
 /// Note the end coordinate is 5-1=4 as the end coordinate is exclusive.
+///
+/// Fragments overlapping `blacklist` are dropped before they are stored and
+/// before the QC metrics are computed. The number of fragments dropped from
+/// every cell is saved in `.obs["n_blacklisted"]`.
+///
+/// With a `checkpoint`, every chunk of cells is saved in the checkpoint as
+/// it is counted, under the range of barcodes of the stream it covers. An
+/// interrupted import given the same checkpoint and the same fragments reads
+/// the saved chunks back and resumes after the last one.
 pub fn import_fragments<A, I>(
     anndata: &A,
     fragments: I,
@@ -35,13 +56,16 @@ pub fn import_fragments<A, I>(
     mitochrondrial_dna: &HashSet<String>,
     chrom_sizes: &ChromSizes,
     white_list: Option<&HashSet<String>>,
+    blacklist: Option<&GIntervalMap<()>>,
     min_num_fragment: u64,
     chunk_size: usize,
+    checkpoint: Option<&mut Checkpoint>,
 ) -> Result<()>
 where
     A: AnnDataOp,
     I: Iterator<Item = Fragment>,
 {
+    let tracker = MemoryTracker::start();
     let spinner = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr_with_hz(1))
         .with_style(
             ProgressStyle::with_template(
@@ -58,166 +82,686 @@ where
     let genome_index = GenomeBaseIndex::new(chrom_sizes);
     let mut saved_barcodes = Vec::new();
     let mut qc = Vec::new();
+    let mut n_blacklisted = Vec::new();
+
+    // The chunks saved by an interrupted import are read back instead of
+    // being counted again, and their barcodes are skipped in the stream.
+    let saved = checkpoint.as_deref().map_or(Vec::new(), saved_chunks);
+    let n_skipped = saved.last().map_or(0, |(_, end)| *end);
+    if n_skipped > 0 {
+        info!("Resuming the import after {} barcodes", n_skipped);
+    }
+    let checkpoint = std::cell::RefCell::new(checkpoint);
 
     let mut scanned_barcodes = HashSet::new();
+    let mut n_invalid = 0;
+    let mut n_out_of_bounds = 0;
+    let mut error = None;
+    let n_no_barcode = std::cell::Cell::new(0usize);
+    let policy = crate::config::missing_chrom_policy();
+    let missing_chroms = std::cell::RefCell::new(IndexSet::new());
     let frag_grouped = fragments
-        .filter(|x| x.len() > 0)
-        .chunk_by(|x| x.name().unwrap().to_string());
-    let frag_chunked = frag_grouped
-        .into_iter()
-        .progress_with(spinner)
-        .filter(|(key, _)| white_list.map_or(true, |x| x.contains(key)))
-        .chunks(chunk_size);
-    let mut arrays = frag_chunked
-        .into_iter()
-        .map(|chunk| {
-            let data: Vec<(String, Vec<Fragment>)> =
-                chunk.map(|(barcode, x)| (barcode, x.collect())).collect();
-            if is_paired {
-                make_arraydata::<u32>(
-                    data,
-                    mitochrondrial_dna,
-                    &genome_index,
-                    min_num_fragment,
-                    &mut scanned_barcodes,
-                    &mut saved_barcodes,
-                    &mut qc,
-                )
+        .take_while(|x| {
+            // Fragments on chromosomes missing from `chrom_sizes` cannot be
+            // stored, as the sizes are fixed during import.
+            if chrom_sizes.get(x.chrom()).is_none() {
+                missing_chroms.borrow_mut().insert(x.chrom().to_string());
+                policy != MissingChromPolicy::Error
             } else {
-                make_arraydata::<i32>(
-                    data,
-                    mitochrondrial_dna,
-                    &genome_index,
-                    min_num_fragment,
-                    &mut scanned_barcodes,
-                    &mut saved_barcodes,
-                    &mut qc,
-                )
+                true
+            }
+        })
+        .filter(|x| x.end() > x.start())
+        .filter(|x| {
+            let ok = x.name().is_some();
+            if !ok {
+                n_no_barcode.set(n_no_barcode.get() + 1);
             }
+            ok
+        })
+        .chunk_by(|x| x.name().unwrap().to_string());
+    let frag_chunked = AdaptiveChunks::new(
+        frag_grouped
+            .into_iter()
+            .progress_with(spinner)
+            .filter(|(key, _)| white_list.map_or(true, |x| x.contains(key)))
+            .skip(n_skipped)
+            .map(|(barcode, x)| (barcode, x.collect::<Vec<_>>())),
+        chunk_size,
+    );
+    let saved_chunks = saved.into_iter().map(|(start, end)| {
+        checkpoint
+            .borrow()
+            .as_ref()
+            .unwrap()
+            .load_binary::<ImportedChunk>(&chunk_stage(start, end))?
+            .context("missing chunk in the checkpoint")
+    });
+    let mut next = n_skipped;
+    let new_chunks = frag_chunked.map(|data| -> Result<ImportedChunk> {
+        let chunk = if is_paired {
+            ImportedChunk::new::<u32>(data, mitochrondrial_dna, &genome_index, blacklist, min_num_fragment)
+        } else {
+            ImportedChunk::new::<i32>(data, mitochrondrial_dna, &genome_index, blacklist, min_num_fragment)
+        };
+        if let Some(c) = checkpoint.borrow_mut().as_mut() {
+            let end = next + chunk.scanned.len();
+            c.save_binary(&chunk_stage(next, end), &chunk)?;
+            next = end;
+        }
+        Ok(chunk)
+    });
+    let mut arrays = saved_chunks
+        .chain(new_chunks)
+        .map_while(|chunk| {
+            let result = chunk.and_then(|chunk| {
+                if is_paired {
+                    chunk.into_arraydata::<u32>(
+                        genome_index.len(),
+                        &mut scanned_barcodes,
+                        &mut saved_barcodes,
+                        &mut qc,
+                        &mut n_blacklisted,
+                        &mut n_invalid,
+                        &mut n_out_of_bounds,
+                    )
+                } else {
+                    chunk.into_arraydata::<i32>(
+                        genome_index.len(),
+                        &mut scanned_barcodes,
+                        &mut saved_barcodes,
+                        &mut qc,
+                        &mut n_blacklisted,
+                        &mut n_invalid,
+                        &mut n_out_of_bounds,
+                    )
+                }
+            });
+            result.map_err(|e| error = Some(e)).ok()
         })
         .peekable();
-    if arrays.peek().is_some() {
-        anndata.obsm().add_iter(obsm_key, arrays)?;
+    let has_data = arrays.peek().is_some();
+    let written = if has_data {
+        recovery::guarded(anndata, Element::Obsm(obsm_key.to_string()), || {
+            anndata.obsm().add_iter(obsm_key, arrays)
+        })
+    } else {
+        drop(arrays);
+        Ok(())
+    };
+    let missing_chroms = missing_chroms.into_inner();
+    let names = missing_chroms.iter().join(", ");
+    let missing_error = (policy == MissingChromPolicy::Error && !missing_chroms.is_empty()).then(|| {
+        anyhow!(
+            "chromosomes not found in the chromosome sizes: {}. Provide their sizes, or set the policy for missing chromosomes to 'skip'",
+            names
+        )
+    });
+    // The fragments are written as they are read, so the fragments written
+    // before the error are deleted, leaving the AnnData as it was.
+    if let Some(e) = error.or(missing_error).or(written.err()) {
+        if has_data {
+            recovery::discard(anndata, Element::Obsm(obsm_key.to_string()))?;
+        }
+        return Err(e);
+    }
+    if !missing_chroms.is_empty() {
+        warn!("fragments on chromosomes missing from the chromosome sizes are ignored: {}", names);
+    }
+    if n_no_barcode.get() > 0 {
+        warn!("{} fragments without cell barcodes are ignored.", n_no_barcode.get());
+    }
+    if n_invalid > 0 {
+        warn!(
+            "{} fragments with invalid coordinates (e.g., empty fragments) are ignored.",
+            n_invalid,
+        );
+    }
+    // Fragments cannot be truncated, as their sizes are part of the data.
+    OutOfBounds { clipped: 0, dropped: n_out_of_bounds }.report("fragments")?;
+    let n_blacklisted_total: u64 = n_blacklisted.iter().sum();
+    if blacklist.is_some() {
+        info!("{} fragments of the saved cells overlapping the blacklist are dropped.", n_blacklisted_total);
+    }
+    if has_data {
         anndata
             .uns()
             .add("reference_sequences", chrom_sizes.to_dataframe())?;
         anndata.set_obs_names(saved_barcodes.into())?;
-        anndata.set_obs(qc_to_df(qc))?;
+        anndata.set_obs(qc_to_df(qc, blacklist.map(|_| n_blacklisted)))?;
+        provenance::record_tracked(
+            anndata,
+            "import_fragments",
+            json!({
+                "is_paired": is_paired,
+                "mitochondrial_dna": mitochrondrial_dna.iter().sorted().collect::<Vec<_>>(),
+                "n_chroms": chrom_sizes.into_iter().count(),
+                "genome_size": chrom_sizes.total_size(),
+                "white_list_size": white_list.map(|x| x.len()),
+                "n_blacklisted_fragments": blacklist.map(|_| n_blacklisted_total),
+                "min_num_fragment": min_num_fragment,
+                "n_invalid_fragments": n_invalid,
+                "n_out_of_bounds_fragments": n_out_of_bounds,
+            }),
+            tracker,
+        );
     } else {
         warn!("No barcodes passed the QC filter. No data is imported.");
     }
     Ok(())
 }
 
-fn make_arraydata<V>(
-    data: Vec<(String, Vec<Fragment>)>,
+/// Split the barcode of a fragment tagged with its modality, e.g., the
+/// antibody barcode of Paired-Tag, into the cell barcode and the modality.
+/// The modality follows the last occurrence of `sep`.
+pub fn split_modality<'a>(barcode: &'a str, sep: &str) -> Option<(&'a str, &'a str)> {
+    barcode
+        .rsplit_once(sep)
+        .filter(|(cell, modality)| !cell.is_empty() && !modality.is_empty())
+}
+
+/// Import fragments tagged with their modality, e.g., the histone mark of
+/// Paired-Tag, into one layer per modality, `.obsm[key]` with the key given by
+/// [`crate::feature_count::modality_key`]. `fragments` gives the modality and
+/// the untagged fragment of every record, sorted by modality and then by cell
+/// barcode. The rows of every layer are `cells`, which must be sorted, and
+/// fragments of other cells are ignored. The QC metrics are computed on the
+/// fragments of all modalities of a cell, and the number of fragments of every
+/// modality is saved in `.obs["n_fragment_{modality}"]`. The fragments of all
+/// modalities are also merged into `.obsm[key]` without modality, which is
+/// read by the analyses that are not given a modality.
+pub fn import_fragment_modalities<A, I>(
+    anndata: &A,
+    fragments: I,
+    is_paired: bool,
     mitochrondrial_dna: &HashSet<String>,
+    chrom_sizes: &ChromSizes,
+    cells: Vec<String>,
+    blacklist: Option<&GIntervalMap<()>>,
+    chunk_size: usize,
+) -> Result<()>
+where
+    A: AnnDataOp,
+    I: Iterator<Item = (String, Fragment)>,
+{
+    let tracker = MemoryTracker::start();
+    if cells.is_empty() {
+        warn!("No barcodes passed the QC filter. No data is imported.");
+        return Ok(());
+    }
+    let obsm_key = if is_paired {
+        FRAGMENT_PAIRED
+    } else {
+        FRAGMENT_SINGLE
+    };
+    let index: HashMap<&str, usize> = cells
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.as_str(), i))
+        .collect();
+    let genome_index = GenomeBaseIndex::new(chrom_sizes);
+    let mut qc: Vec<_> = cells
+        .iter()
+        .map(|_| FragmentQCBuilder::new(mitochrondrial_dna))
+        .collect();
+    let mut n_blacklisted = vec![0; cells.len()];
+    let mut n_fragment = Vec::new();
+    let mut n_invalid = 0;
+    let mut n_out_of_bounds = 0;
+
+    let policy = crate::config::missing_chrom_policy();
+    let missing_chroms = std::cell::RefCell::new(IndexSet::new());
+    let layers = fragments
+        .take_while(|(_, x)| {
+            if chrom_sizes.get(x.chrom()).is_none() {
+                missing_chroms.borrow_mut().insert(x.chrom().to_string());
+                policy != MissingChromPolicy::Error
+            } else {
+                true
+            }
+        })
+        .chunk_by(|(m, _)| m.clone());
+    let mut written = Vec::new();
+    let mut error = None;
+    for (modality, fragments) in &layers {
+        let key = modality_key(obsm_key, &modality);
+        if written.contains(&key) {
+            error = Some(anyhow!(
+                "the fragments are not sorted by modality: '{}' appears in multiple blocks",
+                modality
+            ));
+            break;
+        }
+        let mut counts = vec![0; cells.len()];
+        let layer_error = std::cell::RefCell::new(None);
+
+        // The rows of all cells, including the empty rows of the cells without
+        // fragments of this modality.
+        let next = std::cell::Cell::new(0);
+        let groups = fragments
+            .map(|(_, x)| x)
+            .chunk_by(|x| x.name().unwrap_or_default().to_string());
+        let mut rows = groups
+            .into_iter()
+            .filter_map(|(barcode, x)| index.get(barcode.as_str()).map(|i| (*i, x.collect::<Vec<_>>())))
+            .map_while(|(i, x)| {
+                if i < next.get() {
+                    *layer_error.borrow_mut() = Some(anyhow!(
+                        "the fragments of '{}' are not sorted in the order of the cells",
+                        cells[i]
+                    ));
+                    None
+                } else {
+                    Some((i, x))
+                }
+            })
+            .flat_map(|(i, x)| {
+                let start = next.replace(i + 1);
+                (start..i).map(|j| (j, Vec::new())).chain(std::iter::once((i, x)))
+            })
+            .chain(std::iter::from_fn(|| {
+                let i = next.get();
+                (i < cells.len()).then(|| {
+                    next.set(i + 1);
+                    (i, Vec::new())
+                })
+            }));
+        let arrays = std::iter::from_fn(|| {
+            let chunk: Vec<_> = rows.by_ref().take(chunk_size.max(1)).collect();
+            (!chunk.is_empty()).then_some(chunk)
+        })
+        .map_while(|chunk| {
+            let result = if is_paired {
+                modality_arraydata::<u32>(
+                    chunk,
+                    mitochrondrial_dna,
+                    &genome_index,
+                    blacklist,
+                    &mut qc,
+                    &mut counts,
+                    &mut n_blacklisted,
+                    &mut n_invalid,
+                    &mut n_out_of_bounds,
+                )
+            } else {
+                modality_arraydata::<i32>(
+                    chunk,
+                    mitochrondrial_dna,
+                    &genome_index,
+                    blacklist,
+                    &mut qc,
+                    &mut counts,
+                    &mut n_blacklisted,
+                    &mut n_invalid,
+                    &mut n_out_of_bounds,
+                )
+            };
+            result.map_err(|e| *layer_error.borrow_mut() = Some(e)).ok()
+        });
+        let result = recovery::guarded(anndata, Element::Obsm(key.clone()), || {
+            anndata.obsm().add_iter(&key, arrays)
+        });
+        drop(rows);
+        written.push(key);
+        if let Some(e) = layer_error.into_inner().or(result.err()) {
+            error = Some(e);
+            break;
+        }
+        n_fragment.push((modality, counts));
+    }
+    drop(layers);
+    if error.is_none() && !written.is_empty() {
+        match merge_layers(anndata, &written, obsm_key, is_paired, chunk_size) {
+            Ok(()) => written.push(obsm_key.to_string()),
+            Err(e) => error = Some(e),
+        }
+    }
+    let missing_chroms = missing_chroms.into_inner();
+    let names = missing_chroms.iter().join(", ");
+    let missing_error = (policy == MissingChromPolicy::Error && !missing_chroms.is_empty()).then(|| {
+        anyhow!(
+            "chromosomes not found in the chromosome sizes: {}. Provide their sizes, or set the policy for missing chromosomes to 'skip'",
+            names
+        )
+    });
+    // The layers are written as the fragments are read, so the layers written
+    // before the error are deleted, leaving the AnnData as it was.
+    if let Some(e) = error.or(missing_error) {
+        for key in written {
+            recovery::discard(anndata, Element::Obsm(key))?;
+        }
+        return Err(e);
+    }
+    if !missing_chroms.is_empty() {
+        warn!("fragments on chromosomes missing from the chromosome sizes are ignored: {}", names);
+    }
+    if n_invalid > 0 {
+        warn!(
+            "{} fragments with invalid coordinates (e.g., empty fragments) are ignored.",
+            n_invalid,
+        );
+    }
+    OutOfBounds { clipped: 0, dropped: n_out_of_bounds }.report("fragments")?;
+    let n_blacklisted_total: u64 = n_blacklisted.iter().sum();
+    if blacklist.is_some() {
+        info!("{} fragments of the saved cells overlapping the blacklist are dropped.", n_blacklisted_total);
+    }
+
+    anndata
+        .uns()
+        .add("reference_sequences", chrom_sizes.to_dataframe())?;
+    let n_cells = cells.len();
+    anndata.set_obs_names(cells.into())?;
+    let qc = qc.into_iter().map(|x| x.finish()).collect();
+    let mut obs = qc_to_df(qc, blacklist.map(|_| n_blacklisted));
+    let modalities: Vec<_> = n_fragment.iter().map(|(m, _)| m.clone()).collect();
+    for (modality, counts) in n_fragment {
+        obs.with_column(Column::new(format!("n_fragment_{}", modality).into(), counts))?;
+    }
+    anndata.set_obs(obs)?;
+    provenance::record_tracked(
+        anndata,
+        "import_fragments",
+        json!({
+            "is_paired": is_paired,
+            "mitochondrial_dna": mitochrondrial_dna.iter().sorted().collect::<Vec<_>>(),
+            "n_chroms": chrom_sizes.into_iter().count(),
+            "genome_size": chrom_sizes.total_size(),
+            "n_cells": n_cells,
+            "modalities": modalities,
+            "n_blacklisted_fragments": blacklist.map(|_| n_blacklisted_total),
+            "n_invalid_fragments": n_invalid,
+            "n_out_of_bounds_fragments": n_out_of_bounds,
+        }),
+        tracker,
+    );
+    Ok(())
+}
+
+/// Write the fragments of every cell in all `layers`, sorted by position and
+/// size, into `.obsm[key]`. The layers have the same rows and are read by
+/// chunks of the same size.
+fn merge_layers<A: AnnDataOp>(
+    anndata: &A,
+    layers: &[String],
+    key: &str,
+    is_paired: bool,
+    chunk_size: usize,
+) -> Result<()> {
+    let obsm = anndata.obsm();
+    macro_rules! merge {
+        ($ty:ty) => {{
+            let mut iters = layers
+                .iter()
+                .map(|x| {
+                    obsm.get_item_iter::<CsrNonCanonical<$ty>>(x, chunk_size.max(1))
+                        .with_context(|| format!("cannot read '.obsm[{}]'", x))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let merged = std::iter::from_fn(move || {
+                let chunks = iters
+                    .iter_mut()
+                    .map(|x| x.next().map(|(mat, _, _)| mat))
+                    .collect::<Option<Vec<_>>>()?;
+                Some(ArrayData::from(merge_rows(&chunks)))
+            });
+            recovery::guarded(anndata, Element::Obsm(key.to_string()), || {
+                obsm.add_iter(key, merged)
+            })
+        }};
+    }
+    if is_paired {
+        merge!(u32)
+    } else {
+        merge!(i32)
+    }
+}
+
+/// Concatenate the rows of `mats`, sorting the entries of every row by column
+/// and then by value.
+fn merge_rows<V: Copy + Ord>(mats: &[CsrNonCanonical<V>]) -> CsrNonCanonical<V> {
+    let n_rows = mats.first().map_or(0, |x| x.nrows());
+    let n_cols = mats.first().map_or(0, |x| x.ncols());
+    let mut offsets = Vec::with_capacity(n_rows + 1);
+    let mut indices = Vec::new();
+    let mut data = Vec::new();
+    offsets.push(0);
+    for i in 0..n_rows {
+        let mut row: Vec<_> = mats
+            .iter()
+            .flat_map(|mat| {
+                let (lo, hi) = (mat.row_offsets()[i], mat.row_offsets()[i + 1]);
+                mat.col_indices()[lo..hi].iter().copied().zip(mat.values()[lo..hi].iter().copied())
+            })
+            .collect();
+        row.sort_unstable();
+        row.into_iter().for_each(|(c, v)| {
+            indices.push(c);
+            data.push(v);
+        });
+        offsets.push(indices.len());
+    }
+    CsrNonCanonical::from_csr_data(n_rows, n_cols, offsets, indices, data)
+}
+
+/// The rows of the fragments of one modality, see
+/// [`import_fragment_modalities`]. The QC metrics of every cell are updated
+/// with the fragments of the modality.
+fn modality_arraydata<'a, V>(
+    rows: Vec<(usize, Vec<Fragment>)>,
+    mitochrondrial_dna: &'a HashSet<String>,
     genome_index: &GenomeBaseIndex,
-    min_num_fragment: u64,
-    scanned_barcodes: &mut HashSet<String>,
-    saved_barcodes: &mut Vec<String>,
-    qc: &mut Vec<FragmentQC>,
-) -> ArrayData
+    blacklist: Option<&GIntervalMap<()>>,
+    qc: &mut [FragmentQCBuilder<'a>],
+    n_fragment: &mut [u64],
+    n_blacklisted: &mut [u64],
+    n_invalid: &mut usize,
+    n_out_of_bounds: &mut usize,
+) -> Result<ArrayData>
 where
-    V: TryFrom<i64> + Ord + std::marker::Send,
+    V: TryFrom<i64> + Into<i64> + Copy + Ord + std::marker::Send,
     ArrayData: From<anndata::data::CsrNonCanonical<V>>,
     ArrayData: From<nalgebra_sparse::CsrMatrix<V>>,
-    <V as TryFrom<i64>>::Error: std::fmt::Debug,
 {
-    let num_features = genome_index.len();
-    let result: Vec<_> = data
-        .into_par_iter()
-        .map(|(barcode, x)| {
-            (
-                barcode,
-                count_fragments::<V>(mitochrondrial_dna, &genome_index, x),
-            )
-        })
-        .collect();
-    let counts = result
-        .into_iter()
-        .filter_map(|(barcode, (q, values))| {
-            if !scanned_barcodes.insert(barcode.clone()) {
-                panic!("Please sort fragment file by barcodes");
+    let result: Vec<_> = crate::config::install(None, || {
+        rows.into_par_iter()
+            .map(|(i, x)| (i, count_fragments::<V>(mitochrondrial_dna, genome_index, blacklist, x)))
+            .collect()
+    });
+    let mut counts = Vec::with_capacity(result.len());
+    for (i, (q, values, invalid, out_of_bounds, blacklisted)) in result {
+        *n_invalid += invalid;
+        *n_out_of_bounds += out_of_bounds;
+        qc[i].merge(q);
+        n_fragment[i] = values.len() as u64;
+        n_blacklisted[i] += blacklisted;
+        counts.push(values);
+    }
+    let (r, c, offset, ind, data) = to_csr_data(counts, genome_index.len());
+    from_csr_data(r, c, offset, ind, data)
+}
+
+/// The cells of a chunk of the fragment stream, counted and filtered. Chunks
+/// are saved in the checkpoint of the import, so they store the fragment
+/// sizes as `i64` whatever the type of the matrix.
+#[derive(Encode, Decode, Default)]
+struct ImportedChunk {
+    /// Barcodes of the chunk, including those of the cells filtered out.
+    scanned: Vec<String>,
+    /// Barcodes of the cells passing the filter, i.e., the rows of the matrix.
+    barcodes: Vec<String>,
+    qc: Vec<FragmentQC>,
+    n_blacklisted: Vec<u64>,
+    n_invalid: usize,
+    n_out_of_bounds: usize,
+    offsets: Vec<usize>,
+    indices: Vec<usize>,
+    values: Vec<i64>,
+}
+
+impl ImportedChunk {
+    fn new<V>(
+        data: Vec<(String, Vec<Fragment>)>,
+        mitochrondrial_dna: &HashSet<String>,
+        genome_index: &GenomeBaseIndex,
+        blacklist: Option<&GIntervalMap<()>>,
+        min_num_fragment: u64,
+    ) -> Self
+    where
+        V: TryFrom<i64> + Into<i64> + Copy + Ord + std::marker::Send,
+    {
+        let result: Vec<_> = crate::config::install(None, || {
+            data.into_par_iter()
+                .map(|(barcode, x)| {
+                    (
+                        barcode,
+                        count_fragments::<V>(mitochrondrial_dna, &genome_index, blacklist, x),
+                    )
+                })
+                .collect()
+        });
+        let mut chunk = Self::default();
+        let mut counts = Vec::new();
+        for (barcode, (q, values, invalid, out_of_bounds, blacklisted)) in result {
+            chunk.scanned.push(barcode.clone());
+            chunk.n_invalid += invalid;
+            chunk.n_out_of_bounds += out_of_bounds;
+            if q.num_unique_fragment >= min_num_fragment {
+                chunk.barcodes.push(barcode);
+                chunk.qc.push(q.finish());
+                chunk.n_blacklisted.push(blacklisted);
+                counts.push(values.into_iter().map(|(i, x)| (i, x.into())).collect::<Vec<(usize, i64)>>());
             }
-            if q.num_unique_fragment < min_num_fragment {
-                return None;
-            } else {
-                saved_barcodes.push(barcode);
-                qc.push(q);
-                Some(values)
+        }
+        let (_, _, offsets, indices, values) = to_csr_data(counts, genome_index.len());
+        chunk.offsets = offsets;
+        chunk.indices = indices;
+        chunk.values = values;
+        chunk
+    }
+
+    /// Add the cells of the chunk to the results of the import, and return
+    /// the rows of the matrix.
+    fn into_arraydata<V>(
+        self,
+        num_features: usize,
+        scanned_barcodes: &mut HashSet<String>,
+        saved_barcodes: &mut Vec<String>,
+        qc: &mut Vec<FragmentQC>,
+        n_blacklisted: &mut Vec<u64>,
+        n_invalid: &mut usize,
+        n_out_of_bounds: &mut usize,
+    ) -> Result<ArrayData>
+    where
+        V: TryFrom<i64> + Into<i64> + Copy + Ord + std::marker::Send,
+        ArrayData: From<anndata::data::CsrNonCanonical<V>>,
+        ArrayData: From<nalgebra_sparse::CsrMatrix<V>>,
+    {
+        for barcode in self.scanned {
+            if !scanned_barcodes.insert(barcode.clone()) {
+                bail!(
+                    "Barcode {} appears in multiple blocks. Please sort fragment file by barcodes",
+                    barcode
+                );
             }
+        }
+        *n_invalid += self.n_invalid;
+        *n_out_of_bounds += self.n_out_of_bounds;
+        let n_rows = self.barcodes.len();
+        saved_barcodes.extend(self.barcodes);
+        qc.extend(self.qc);
+        n_blacklisted.extend(self.n_blacklisted);
+        let values = self
+            .values
+            .into_iter()
+            .map(|x| V::try_from(x).map_err(|_| anyhow!("invalid fragment size: {}", x)))
+            .collect::<Result<Vec<_>>>()?;
+        from_csr_data(n_rows, num_features, self.offsets, self.indices, values)
+    }
+}
+
+/// Name of the checkpoint stage holding the chunk of the `start`-th to the
+/// `end`-th barcodes (exclusive) of the fragment stream.
+fn chunk_stage(start: usize, end: usize) -> String {
+    format!("import_chunk_{}_{}", start, end)
+}
+
+/// The ranges of barcodes of the chunks saved in `checkpoint`, as long as
+/// they follow each other from the first barcode.
+fn saved_chunks(checkpoint: &Checkpoint) -> Vec<(usize, usize)> {
+    let ranges: BTreeMap<usize, usize> = checkpoint
+        .completed()
+        .filter_map(|stage| {
+            let (start, end) = stage.strip_prefix("import_chunk_")?.split_once('_')?;
+            Some((start.parse().ok()?, end.parse().ok()?))
         })
-        .collect::<Vec<_>>();
-    let (r, c, offset, ind, data) = to_csr_data(counts, num_features);
-    from_csr_data(r, c, offset, ind, data).unwrap()
+        .collect();
+    let mut result = Vec::new();
+    let mut next = 0;
+    while let Some(end) = ranges.get(&next) {
+        result.push((next, *end));
+        next = *end;
+    }
+    result
 }
 
-fn count_fragments<V>(
-    mitochrondrial_dna: &HashSet<String>,
+/// Convert fragments to (position, size) pairs. Fragments with invalid
+/// coordinates, i.e., empty fragments or fragments whose size does not fit
+/// in `V`, fragments extending beyond the end of the chromosome and fragments
+/// overlapping the blacklist are skipped and counted separately.
+fn count_fragments<'a, V>(
+    mitochrondrial_dna: &'a HashSet<String>,
     genome_index: &GenomeBaseIndex,
+    blacklist: Option<&GIntervalMap<()>>,
     fragments: Vec<Fragment>,
-) -> (FragmentQC, Vec<(usize, V)>)
+) -> (FragmentQCBuilder<'a>, Vec<(usize, V)>, usize, usize, u64)
 where
     V: TryFrom<i64> + Ord,
-    <V as TryFrom<i64>>::Error: std::fmt::Debug,
 {
     let mut qc = FragmentQCBuilder::new(mitochrondrial_dna);
     let mut values = Vec::new();
+    let mut n_invalid = 0;
+    let mut n_out_of_bounds = 0;
+    let mut n_blacklisted = 0;
     fragments.into_iter().for_each(|f| {
         let chrom = f.chrom();
-        if genome_index.contain_chrom(chrom) {
-            qc.update(&f);
+        if let Some(chrom_size) = genome_index.chrom_size(chrom) {
+            if f.start() >= f.end() {
+                n_invalid += 1;
+                return;
+            }
+            if f.end() > chrom_size {
+                n_out_of_bounds += 1;
+                return;
+            }
+            if blacklist.map_or(false, |x| x.is_overlapped(&f)) {
+                n_blacklisted += 1;
+                return;
+            }
             let start = f.start() as i64;
             let end = f.end() as i64;
             let size = end - start;
-            let pos;
-            let shift: V;
-            if f.is_single() {
-                match f.strand().unwrap() {
-                    Strand::Reverse => {
-                        pos = genome_index.get_position_rev(chrom, (end - 1) as u64);
-                        shift = (-size).try_into().expect(
-                            format!(
-                                "cannot convert size {} to {}",
-                                -size,
-                                std::any::type_name::<V>()
-                            )
-                            .as_str(),
-                        );
-                    }
-                    Strand::Forward => {
-                        pos = genome_index.get_position_rev(chrom, start as u64);
-                        shift = size.try_into().expect(
-                            format!(
-                                "cannot convert size {} to {}",
-                                size,
-                                std::any::type_name::<V>()
-                            )
-                            .as_str(),
-                        );
-                    }
+            let (pos, shift) = match f.strand() {
+                Some(Strand::Reverse) if f.is_single() => {
+                    (genome_index.get_position_rev(chrom, (end - 1) as u64), -size)
                 }
-            } else {
-                pos = genome_index.get_position_rev(chrom, start as u64);
-                shift = size.try_into().expect(
-                    format!(
-                        "cannot convert size {} to {}",
-                        size,
-                        std::any::type_name::<V>()
-                    )
-                    .as_str(),
-                );
+                _ => (genome_index.get_position_rev(chrom, start as u64), size),
+            };
+            match shift.try_into() {
+                Ok(shift) => {
+                    qc.update(&f);
+                    values.push((pos, shift));
+                }
+                Err(_) => n_invalid += 1,
             }
-            values.push((pos, shift));
         }
     });
     values.sort();
-    (qc.finish(), values)
+    (qc, values, n_invalid, n_out_of_bounds, n_blacklisted)
 }
 
-fn qc_to_df(qc: Vec<FragmentQC>) -> DataFrame {
-    DataFrame::new(vec![
+fn qc_to_df(qc: Vec<FragmentQC>, n_blacklisted: Option<Vec<u64>>) -> DataFrame {
+    let mut columns = vec![
         Column::new(
             "n_fragment".into(),
             qc.iter().map(|x| x.num_unique_fragment).collect::<Series>(),
@@ -230,8 +774,11 @@ fn qc_to_df(qc: Vec<FragmentQC>) -> DataFrame {
             "frac_mito".into(),
             qc.iter().map(|x| x.frac_mitochondrial).collect::<Series>(),
         ),
-    ])
-    .unwrap()
+    ];
+    if let Some(n) = n_blacklisted {
+        columns.push(Column::new("n_blacklisted".into(), n));
+    }
+    DataFrame::new(columns).unwrap()
 }
 
 /// Import scHi-C contacts into AnnData
@@ -246,6 +793,7 @@ where
     A: AnnDataOp,
     I: Iterator<Item = Contact>,
 {
+    let tracker = MemoryTracker::start();
     let chrom_sizes: ChromSizes = regions.iter().map(|x| (x.chrom(), x.end())).collect();
 
     let genome_index = GenomeBaseIndex::new(&chrom_sizes);
@@ -274,33 +822,36 @@ where
             })
             .collect();
 
-        let counts: Vec<_> = data
-            .into_par_iter()
-            .map(|x| {
-                let mut count = BTreeMap::new();
-                x.into_iter().for_each(|c| {
-                    if genome_index.contain_chrom(&c.chrom1)
-                        && genome_index.contain_chrom(&c.chrom2)
-                    {
-                        let pos1 = genome_index.get_position_rev(&c.chrom1, c.start1);
-                        let pos2 = genome_index.get_position_rev(&c.chrom2, c.start2);
-                        let i = pos1 * genome_size + pos2;
-                        count
-                            .entry(i)
-                            .and_modify(|x| *x += c.count)
-                            .or_insert(c.count);
-                    }
-                });
-                count.into_iter().collect::<Vec<_>>()
-            })
-            .collect();
+        let counts: Vec<_> = crate::config::install(None, || {
+            data.into_par_iter()
+                .map(|x| {
+                    let mut count = BTreeMap::new();
+                    x.into_iter().for_each(|c| {
+                        if genome_index.contain_chrom(&c.chrom1)
+                            && genome_index.contain_chrom(&c.chrom2)
+                        {
+                            let pos1 = genome_index.get_position_rev(&c.chrom1, c.start1);
+                            let pos2 = genome_index.get_position_rev(&c.chrom2, c.start2);
+                            let i = pos1 * genome_size + pos2;
+                            count
+                                .entry(i)
+                                .and_modify(|x| *x += c.count)
+                                .or_insert(c.count);
+                        }
+                    });
+                    count.into_iter().collect::<Vec<_>>()
+                })
+                .collect()
+        });
 
         let (r, c, offset, ind, data) = to_csr_data(counts, genome_size * genome_size);
         CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap()
     });
     let contact_map = ContactData::new(chrom_sizes, binding3).with_resolution(bin_size);
 
-    anndata.set_x_from_iter(contact_map.into_values::<u32>())?;
+    recovery::guarded(anndata, Element::X, || {
+        anndata.set_x_from_iter(contact_map.into_values::<u32>())
+    })?;
     anndata.set_var_names(anndata.n_vars().into())?;
 
     anndata.uns().add(
@@ -317,6 +868,7 @@ where
         ])?,
     )?;
     anndata.set_obs_names(scanned_barcodes.into_iter().collect())?;
+    provenance::record_tracked(anndata, "import_contacts", json!({ "bin_size": bin_size }), tracker);
     Ok(())
 }
 
@@ -332,32 +884,34 @@ where
     A: AnnDataOp,
     I: Iterator<Item = (String, BaseValue)>,
 {
+    let tracker = MemoryTracker::start();
     fn helper<T: TryFrom<BaseValue, Error = anyhow::Error> + Send>(
         chunk: Vec<Vec<BaseValue>>,
         genome_index: &GenomeBaseIndex,
         genome_size: usize,
     ) -> (Vec<BaseValueQC>, CsrMatrix<T>) {
-        let (qc, counts): (Vec<_>, Vec<_>) = chunk
-            .into_par_iter()
-            .map(|cell_data| {
-                let mut qc = BaseValueQC::new();
-                let mut count = cell_data
-                    .into_iter()
-                    .flat_map(|value| {
-                        let chrom = &value.chrom;
-                        if genome_index.contain_chrom(chrom) {
-                            qc.add();
-                            let pos = genome_index.get_position_rev(chrom, value.pos);
-                            Some((pos, T::try_from(value).unwrap()))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<_>>();
-                count.sort_by(|x, y| x.0.cmp(&y.0));
-                (qc, count)
-            })
-            .unzip();
+        let (qc, counts): (Vec<_>, Vec<_>) = crate::config::install(None, || {
+            chunk.into_par_iter()
+                .map(|cell_data| {
+                    let mut qc = BaseValueQC::new();
+                    let mut count = cell_data
+                        .into_iter()
+                        .flat_map(|value| {
+                            let chrom = &value.chrom;
+                            if genome_index.contain_chrom(chrom) {
+                                qc.add();
+                                let pos = genome_index.get_position_rev(chrom, value.pos);
+                                Some((pos, T::try_from(value).unwrap()))
+                            } else {
+                                None
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    count.sort_by(|x, y| x.0.cmp(&y.0));
+                    (qc, count)
+                })
+                .unzip()
+        });
         let (r, c, offset, ind, csr_data) = to_csr_data(counts, genome_size);
         (
             qc,
@@ -406,7 +960,9 @@ where
         mat
     });
 
-    anndata.obsm().add_iter(BASE_VALUE, arrays)?;
+    recovery::guarded(anndata, Element::Obsm(BASE_VALUE.to_string()), || {
+        anndata.obsm().add_iter(BASE_VALUE, arrays)
+    })?;
     anndata
         .uns()
         .add("reference_sequences", chrom_sizes.to_dataframe())?;
@@ -415,5 +971,107 @@ where
         "num_values".into(),
         qc_metrics.iter().map(|x| x.num_values).collect::<Series>(),
     )])?)?;
+    provenance::record_tracked(
+        anndata,
+        "import_values",
+        json!({
+            "n_chroms": chrom_sizes.into_iter().count(),
+            "genome_size": chrom_sizes.total_size(),
+            "white_list_size": white_list.map(|x| x.len()),
+        }),
+        tracker,
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preprocessing::PairRead;
+    use bed_utils::bed::GenomicRange;
+
+    #[test]
+    fn test_count_invalid_fragments() {
+        let genome_index = GenomeBaseIndex::new(&[("chr1", 100)].into_iter().collect());
+        let fragments: Vec<Fragment> = vec![
+            PairRead::new("chr1", 10, 20).into(),
+            PairRead::new("chr1", 90, 120).into(),
+            PairRead::new("chr1", 150, 160).into(),
+            PairRead::new("chr2", 10, 20).into(),
+            PairRead::new("chr1", 30, 30).into(),
+        ];
+        let (qc, values, n_invalid, n_out_of_bounds, n_blacklisted) =
+            count_fragments::<u32>(&HashSet::new(), &genome_index, None, fragments);
+        assert_eq!(values, vec![(10, 10)]);
+        assert_eq!(n_invalid, 1);
+        assert_eq!(n_out_of_bounds, 2);
+        assert_eq!(n_blacklisted, 0);
+        assert_eq!(qc.num_unique_fragment, 1);
+    }
+
+    #[test]
+    fn test_saved_chunks() {
+        let genome_index = GenomeBaseIndex::new(&[("chr1", 100)].into_iter().collect());
+        let data = vec![
+            ("a".to_string(), vec![PairRead::new("chr1", 10, 20).into()]),
+            ("b".to_string(), vec![]),
+        ];
+        let chunk = ImportedChunk::new::<u32>(data, &HashSet::new(), &genome_index, None, 1);
+        assert_eq!(chunk.scanned, vec!["a", "b"]);
+        assert_eq!(chunk.barcodes, vec!["a"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut checkpoint = Checkpoint::open(dir.path().join("ckpt"), "").unwrap();
+        checkpoint.save_binary(&chunk_stage(0, 2), &chunk).unwrap();
+        checkpoint.save_binary(&chunk_stage(2, 3), &chunk).unwrap();
+        // Chunks after a gap are not used.
+        checkpoint.save_binary(&chunk_stage(5, 7), &chunk).unwrap();
+        assert_eq!(saved_chunks(&checkpoint), vec![(0, 2), (2, 3)]);
+
+        let (mut scanned, mut barcodes, mut qc, mut n_blacklisted) =
+            (HashSet::new(), Vec::new(), Vec::new(), Vec::new());
+        let (mut n_invalid, mut n_out_of_bounds) = (0, 0);
+        let mut add = |chunk: ImportedChunk| {
+            chunk.into_arraydata::<u32>(
+                genome_index.len(),
+                &mut scanned,
+                &mut barcodes,
+                &mut qc,
+                &mut n_blacklisted,
+                &mut n_invalid,
+                &mut n_out_of_bounds,
+            )
+        };
+        let saved = checkpoint.load_binary::<ImportedChunk>(&chunk_stage(0, 2)).unwrap().unwrap();
+        assert!(add(saved).is_ok());
+        assert!(add(chunk).is_err());
+        assert_eq!(barcodes, vec!["a"]);
+    }
+
+    #[test]
+    fn test_split_modality() {
+        assert_eq!(split_modality("AAAC:H3K27ac", ":"), Some(("AAAC", "H3K27ac")));
+        assert_eq!(split_modality("AA:AC:H3K27ac", ":"), Some(("AA:AC", "H3K27ac")));
+        assert_eq!(split_modality("AAAC+H3K4me3", "+"), Some(("AAAC", "H3K4me3")));
+        assert_eq!(split_modality("AAAC", ":"), None);
+        assert_eq!(split_modality("AAAC:", ":"), None);
+    }
+
+    #[test]
+    fn test_count_blacklisted_fragments() {
+        let genome_index = GenomeBaseIndex::new(&[("chr1", 100)].into_iter().collect());
+        let blacklist: GIntervalMap<()> = [(GenomicRange::new("chr1", 15, 25), ())].into_iter().collect();
+        let fragments: Vec<Fragment> = vec![
+            PairRead::new("chr1", 10, 20).into(),
+            PairRead::new("chr1", 24, 30).into(),
+            PairRead::new("chr1", 25, 30).into(),
+            PairRead::new("chr1", 90, 120).into(),
+        ];
+        let (qc, values, _, n_out_of_bounds, n_blacklisted) =
+            count_fragments::<u32>(&HashSet::new(), &genome_index, Some(&blacklist), fragments);
+        assert_eq!(values, vec![(25, 5)]);
+        assert_eq!(n_blacklisted, 2);
+        assert_eq!(n_out_of_bounds, 1);
+        assert_eq!(qc.num_unique_fragment, 1);
+    }
+}
@@ -1,12 +1,20 @@
 mod bam;
+mod contig;
 mod import;
 mod qc;
+mod sort;
 
-pub use bam::{make_fragment_file, BamQC, FlagStat};
-pub use import::{import_contacts, import_fragments, import_values};
+pub use bam::{make_fragment_file, BamQC, DedupPolicy, FlagStat};
+pub use contig::{is_primary_contig, ChainMap, ContigPolicy};
+pub use import::{
+    import_contacts, import_fragment_modalities, import_fragments, import_values, split_modality,
+};
+pub use sort::sort_fragment_file;
 pub use qc::{
     SummaryType,
     get_barcode_count, make_promoter_map,
-    read_tss, CellBarcode, Contact, Fragment, QualityControl, TSSe, TssRegions,
-    SingleRead, PairRead,
+    read_exons, read_tss, CellBarcode, Contact, Fragment, PeakAllocation, QualityControl, Spot, TSSe,
+    TssRegions,
+    SingleRead, PairRead, LongRead, LONG_READ_MIN_FRAGMENT_SIZE, LONG_READ_MAX_FRAGMENT_SIZE,
+    count_methylation_calls,
 };
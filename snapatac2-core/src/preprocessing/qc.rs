@@ -1,6 +1,8 @@
-use anyhow::{Result, bail};
-use bed_utils::bed::{map::GIntervalMap, BEDLike, GenomicRange, ParseError, Strand};
+use anndata::{AnnDataOp, ArrayData, AxisArraysOp};
+use anyhow::{Result, bail, ensure};
+use bed_utils::bed::{map::{GIntervalIndexSet, GIntervalMap}, BEDLike, GenomicRange, ParseError, Strand};
 use bitcode::{Decode, Encode};
+use nalgebra_sparse::CsrMatrix;
 use ndarray::Array2;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use smallvec::{SmallVec, smallvec};
@@ -10,7 +12,11 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use crate::feature_count::{CompressedFragmentIter, SnapData};
+use crate::feature_count::{
+    read_coverage_cache, CompressedFragmentIter, CountingStrategy, FeatureCounter, RegionCounter,
+    SnapData,
+};
+use crate::recovery::{self, Element};
 
 pub type CellBarcode = String;
 
@@ -55,7 +61,35 @@ pub trait QualityControl: SnapData {
             .into_iter()
             .map(|(k, _)| (k, vec![0.0; n]))
             .collect();
-        if let Ok(fragments) = self.get_fragment_iter(2000) {
+        let cache = match self.fragment_chunk_size() {
+            Ok(n) => read_coverage_cache(self, n)?,
+            Err(_) => None,
+        };
+        if let Some(cache) = cache {
+            // Every fragment contributes one insertion (single-end) or two
+            // insertions (paired-end) to its chromosome.
+            let scale = if cache.info.paired { 2.0 } else { 1.0 };
+            let index = cache.index;
+            cache.data.for_each(|(mat, s, _)| {
+                mat.row_iter().enumerate().for_each(|(i, row)| {
+                    let mut counts: HashMap<&String, f32> = HashMap::new();
+                    row.col_indices().iter().zip(row.values()).for_each(|(j, v)| {
+                        *counts.entry(index.get_chrom(*j)).or_insert(0.0) += *v as f32 / scale;
+                    });
+                    counts.into_iter().for_each(|(k, v)| {
+                        if let Some(x) = result.get_mut(k) {
+                            x[s + i] = match mode {
+                                SummaryType::Sum | SummaryType::Count => v,
+                                SummaryType::Mean => 1.0,
+                            };
+                        }
+                    });
+                })
+            });
+        } else if let Ok(fragments) = self
+            .fragment_chunk_size()
+            .and_then(|n| self.get_fragment_iter(n))
+        {
             fragments.into_fragments().for_each(|(data, s, _)| {
                 data.into_iter().enumerate().for_each(|(i, fragments)| {
                     let fragments = fragments.into_iter().map(|x| (x.chrom().to_string(), 1.0));
@@ -97,18 +131,20 @@ pub trait QualityControl: SnapData {
     fn tss_enrichment<'a>(&self, promoter: &'a TssRegions) -> Result<(Vec<f64>, TSSe<'a>)> {
         let library_tsse = Arc::new(Mutex::new(TSSe::new(promoter)));
         let scores = self
-            .get_fragment_iter(2000)?
+            .get_fragment_iter(self.fragment_chunk_size()?)?
             .into_fragments()
             .flat_map(|(list_of_fragments, _, _)| {
-                list_of_fragments
-                    .into_par_iter()
-                    .map(|fragments| {
-                        let mut tsse = TSSe::new(promoter);
-                        fragments.into_iter().for_each(|x| tsse.add(&x));
-                        library_tsse.lock().unwrap().add_from(&tsse);
-                        tsse.result().0
-                    })
-                    .collect::<Vec<_>>()
+                crate::config::install(None, || {
+                    list_of_fragments
+                        .into_par_iter()
+                        .map(|fragments| {
+                            let mut tsse = TSSe::new(promoter);
+                            fragments.into_iter().for_each(|x| tsse.add(&x));
+                            library_tsse.lock().unwrap().add_from(&tsse);
+                            tsse.result().0
+                        })
+                        .collect::<Vec<_>>()
+                })
             })
             .collect();
         Ok((
@@ -117,6 +153,63 @@ pub trait QualityControl: SnapData {
         ))
     }
 
+    /// [ATAC QC] Compute the TSS profile of every cell, i.e., the number of
+    /// insertions at each position of the promoters, oriented by strand and
+    /// aggregated in bins of `bin_size` bases. The cell-by-bin matrix is stored
+    /// in `.obsm[key]`. Returns the offsets of the first base and of the end
+    /// of every bin relative to the TSS. The last bin is clipped to the end of
+    /// the promoters, so it may be narrower than `bin_size`.
+    fn tss_profile(&self, promoter: &TssRegions, bin_size: usize, key: &str) -> Result<Vec<(i64, i64)>> {
+        ensure!(bin_size > 0, "bin size must be positive");
+        let n_bins = promoter.len().div_ceil(bin_size);
+        let chunks = self
+            .get_fragment_iter(self.fragment_chunk_size()?)?
+            .into_fragments()
+            .map(|(list_of_fragments, _, _)| {
+                let rows: Vec<Vec<(usize, u32)>> = crate::config::install(None, || {
+                    list_of_fragments
+                        .into_par_iter()
+                        .map(|fragments| {
+                            let mut counts = vec![0u32; n_bins];
+                            fragments
+                                .iter()
+                                .flat_map(|x| x.to_insertions())
+                                .for_each(|ins| {
+                                    promoter.positions(&ins).for_each(|pos| counts[pos / bin_size] += 1)
+                                });
+                            counts
+                                .into_iter()
+                                .enumerate()
+                                .filter(|(_, c)| *c > 0)
+                                .collect()
+                        })
+                        .collect()
+                });
+                let (mut offsets, mut indices, mut values) = (vec![0], Vec::new(), Vec::new());
+                rows.into_iter().for_each(|row| {
+                    row.into_iter().for_each(|(i, c)| {
+                        indices.push(i);
+                        values.push(c);
+                    });
+                    offsets.push(indices.len());
+                });
+                let n = offsets.len() - 1;
+                ArrayData::from(
+                    CsrMatrix::try_from_csr_data(n, n_bins, offsets, indices, values).unwrap(),
+                )
+            });
+        recovery::guarded(self, Element::Obsm(key.to_string()), || {
+            self.obsm().add_iter(key, chunks)
+        })?;
+        let window_size = promoter.window_size as i64;
+        Ok((0..n_bins)
+            .map(|i| {
+                let end = ((i + 1) * bin_size).min(promoter.len());
+                ((i * bin_size) as i64 - window_size, end as i64 - window_size)
+            })
+            .collect())
+    }
+
     /// [ATAC QC] Compute the fragment size distribution.
     /// The result is stored in a vector where each element represents the number of fragments
     /// and the index represents the fragment length. The first posision of the vector is
@@ -151,7 +244,7 @@ pub trait QualityControl: SnapData {
         count_as_insertion: bool,
     ) -> Result<Array2<f64>> {
         let k = regions.len();
-        let fragments = self.get_fragment_iter(2000)?.into_fragments();
+        let fragments = self.get_fragment_iter(self.fragment_chunk_size()?)?.into_fragments();
         let vec = fragments
             .map(move |(data, start, end)| {
                 let frac = data
@@ -197,10 +290,224 @@ pub trait QualityControl: SnapData {
             .collect::<Vec<_>>();
         Array2::from_shape_vec((self.n_obs(), regions.len()), vec).map_err(Into::into)
     }
+
+    /// Signal portion of tags (SPOT) of every group of cells, as in the ENCODE
+    /// pipeline: the fraction of Tn5 insertions falling in the peaks. The
+    /// enrichment is the SPOT relative to the fraction of the genome covered by
+    /// the peaks, i.e., the SPOT expected for uniformly distributed insertions.
+    /// Fragments outside the size limits or overlapping `blacklist` are ignored.
+    fn spot(
+        &self,
+        peaks: &[GenomicRange],
+        group_by: &[&str],
+        min_fragment_size: Option<u64>,
+        max_fragment_size: Option<u64>,
+        blacklist: Option<&GIntervalMap<()>>,
+    ) -> Result<HashMap<String, Spot>> {
+        ensure!(self.n_obs() == group_by.len(), "lengths differ");
+        let chrom_sizes = self.read_chrom_sizes()?;
+        let genome_size: u64 = chrom_sizes.into_iter().map(|(_, s)| *s).sum();
+        let peaks: Vec<_> = peaks
+            .iter()
+            .filter(|x| chrom_sizes.get(x.chrom()).is_some())
+            .cloned()
+            .collect();
+        let peak_fraction = covered_length(&peaks) as f64 / genome_size as f64;
+        let index: GIntervalMap<()> = peaks.into_iter().map(|x| (x, ())).collect();
+
+        let mut counts: HashMap<String, (u64, u64)> = group_by
+            .iter()
+            .map(|g| (g.to_string(), (0, 0)))
+            .collect();
+        let mut fragment_data = self.get_fragment_iter(self.fragment_chunk_size()?)?;
+        if let Some(x) = min_fragment_size {
+            fragment_data = fragment_data.min_fragment_size(x);
+        }
+        if let Some(x) = max_fragment_size {
+            fragment_data = fragment_data.max_fragment_size(x);
+        }
+        fragment_data
+            .into_fragment_groups(|i| group_by[i])
+            .for_each(|groups| {
+                groups.into_iter().for_each(|(group, frags)| {
+                    let count = counts.get_mut(group).unwrap();
+                    frags
+                        .into_iter()
+                        .filter(|(_, x)| !blacklist.map_or(false, |bl| bl.is_overlapped(x)))
+                        .flat_map(|(_, x)| x.to_insertions())
+                        .for_each(|ins| {
+                            count.0 += 1;
+                            if index.is_overlapped(&ins) {
+                                count.1 += 1;
+                            }
+                        });
+                })
+            });
+        Ok(counts
+            .into_iter()
+            .map(|(group, (n_insertions, n_in_peaks))| {
+                let spot = if n_insertions > 0 {
+                    n_in_peaks as f64 / n_insertions as f64
+                } else {
+                    0.0
+                };
+                let enrichment = if peak_fraction > 0.0 { spot / peak_fraction } else { 0.0 };
+                let result = Spot { n_insertions, n_in_peaks, spot, enrichment };
+                (group, result)
+            })
+            .collect())
+    }
+
+    /// How the fragments of every group of cells are allocated to the peaks
+    /// under every counting strategy: the numbers of fragments adding counts
+    /// to no peak, to one peak, or to several (overlapping or adjacent) peaks,
+    /// and the total counts they add to a peak matrix. Fragments are counted
+    /// exactly as in [`crate::feature_count::create_peak_matrix`].
+    fn peak_allocation(
+        &self,
+        peaks: &[GenomicRange],
+        group_by: &[&str],
+    ) -> Result<HashMap<String, [PeakAllocation; 3]>> {
+        ensure!(self.n_obs() == group_by.len(), "lengths differ");
+        const STRATEGIES: [CountingStrategy; 3] = [
+            CountingStrategy::Fragment,
+            CountingStrategy::Insertion,
+            CountingStrategy::PIC,
+        ];
+        let index: GIntervalIndexSet = peaks.iter().cloned().collect();
+        let mut counter = RegionCounter::<u64>::new(&index);
+
+        let mut result: HashMap<String, [PeakAllocation; 3]> = group_by
+            .iter()
+            .map(|g| (g.to_string(), Default::default()))
+            .collect();
+        self.get_fragment_iter(self.fragment_chunk_size()?)?
+            .into_fragment_groups(|i| group_by[i])
+            .for_each(|groups| {
+                groups.into_iter().for_each(|(group, frags)| {
+                    let alloc = result.get_mut(group).unwrap();
+                    frags.into_iter().for_each(|(_, frag)| {
+                        alloc.iter_mut().zip(STRATEGIES.iter()).for_each(|(a, strategy)| {
+                            counter.reset();
+                            counter.insert_fragment(&frag, strategy);
+                            let values = counter.get_values();
+                            match values.len() {
+                                0 => a.n_none += 1,
+                                1 => a.n_single += 1,
+                                _ => a.n_multiple += 1,
+                            }
+                            a.n_counts += values.iter().map(|(_, v)| v).sum::<u64>();
+                        });
+                    });
+                })
+            });
+        Ok(result)
+    }
+
+    /// [ATAC QC] Count the loci of every cell covered by more than two of its
+    /// fragments, as in AMULET. A diploid nucleus yields at most two fragments
+    /// at any position, so such loci indicate multiplets. Loci on
+    /// `exclude_chroms` or overlapping `blacklist`, e.g., repeats, are ignored.
+    fn fragment_overlap_loci(
+        &self,
+        blacklist: Option<&GIntervalMap<()>>,
+        exclude_chroms: &HashSet<&str>,
+    ) -> Result<Vec<u64>> {
+        Ok(self
+            .get_fragment_iter(self.fragment_chunk_size()?)?
+            .into_fragments()
+            .flat_map(|(list_of_fragments, _, _)| {
+                crate::config::install(None, || {
+                    list_of_fragments
+                        .into_par_iter()
+                        .map(|fragments| {
+                            let intervals = fragments
+                                .iter()
+                                .filter(|x| !exclude_chroms.contains(x.chrom()))
+                                .map(|x| (x.chrom(), x.start(), x.end()));
+                            overlap_loci(intervals, 2)
+                                .into_iter()
+                                .filter(|x| blacklist.map_or(true, |b| !b.is_overlapped(x)))
+                                .count() as u64
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect())
+    }
 }
 
 impl<T: SnapData> QualityControl for T {}
 
+/// See [`QualityControl::spot`].
+#[derive(Debug, Clone)]
+pub struct Spot {
+    pub n_insertions: u64,
+    pub n_in_peaks: u64,
+    pub spot: f64,
+    pub enrichment: f64,
+}
+
+/// See [`QualityControl::peak_allocation`]. Counts of the fragments of a group
+/// under one counting strategy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeakAllocation {
+    /// Fragments adding no count to any peak.
+    pub n_none: u64,
+    /// Fragments adding counts to a single peak.
+    pub n_single: u64,
+    /// Fragments adding counts to more than one peak.
+    pub n_multiple: u64,
+    /// Total counts added to the peaks.
+    pub n_counts: u64,
+}
+
+/// Number of bases covered by the regions, counting overlaps once.
+fn covered_length(regions: &[GenomicRange]) -> u64 {
+    let mut regions: Vec<_> = regions.iter().collect();
+    regions.sort_unstable_by(|a, b| a.compare(b));
+    let mut total = 0;
+    let mut current: Option<(&str, u64, u64)> = None;
+    for x in regions {
+        match current.as_mut() {
+            Some((chrom, _, end)) if *chrom == x.chrom() && x.start() <= *end => {
+                *end = (*end).max(x.end());
+            }
+            _ => {
+                if let Some((_, start, end)) = current {
+                    total += end - start;
+                }
+                current = Some((x.chrom(), x.start(), x.end()));
+            }
+        }
+    }
+    total + current.map_or(0, |(_, start, end)| end - start)
+}
+
+/// Maximal loci covered by more than `max_depth` of the intervals.
+fn overlap_loci<'a>(
+    intervals: impl Iterator<Item = (&'a str, u64, u64)>,
+    max_depth: i64,
+) -> Vec<GenomicRange> {
+    // Ends sort before starts at the same position, as intervals are half-open.
+    let mut events: Vec<_> = intervals
+        .flat_map(|(chrom, start, end)| [(chrom, start, 1), (chrom, end, -1)])
+        .collect();
+    events.sort_unstable();
+    let mut loci = Vec::new();
+    let mut depth = 0;
+    let mut locus_start = None;
+    for (chrom, pos, delta) in events {
+        depth += delta;
+        if depth > max_depth {
+            locus_start.get_or_insert(pos);
+        } else if let Some(start) = locus_start.take() {
+            loci.push(GenomicRange::new(chrom, start, pos));
+        }
+    }
+    loci
+}
+
 
 #[derive(Encode, Decode, Debug, Clone)]
 pub enum Fragment {
@@ -277,6 +584,19 @@ impl Fragment {
             },
         }
     }
+
+    /// The insertions of the fragment and the strand of the read at each of
+    /// them. The two ends of a paired fragment are read on opposite strands.
+    pub fn to_stranded_insertions(&self) -> SmallVec<[(GenomicRange, Strand); 2]> {
+        let insertions = self.to_insertions();
+        match self {
+            Fragment::Single(x) => insertions.into_iter().map(|i| (i, x.strand)).collect(),
+            Fragment::Paired(_) => insertions
+                .into_iter()
+                .zip([Strand::Forward, Strand::Reverse])
+                .collect(),
+        }
+    }
 }
 
 impl BEDLike for Fragment {
@@ -346,8 +666,8 @@ impl BEDLike for Fragment {
 
     fn score(&self) -> Option<bed_utils::bed::Score> {
         match self {
-            Fragment::Single(x) => Some(x.count.try_into().unwrap()),
-            Fragment::Paired(x) => Some(x.count.try_into().unwrap()),
+            Fragment::Single(x) => x.count.try_into().ok(),
+            Fragment::Paired(x) => x.count.try_into().ok(),
         }
     }
 
@@ -414,7 +734,7 @@ impl BEDLike for PairRead {
         self.barcode.as_deref()
     }
     fn score(&self) -> Option<bed_utils::bed::Score> {
-        Some(self.count.try_into().unwrap())
+        self.count.try_into().ok()
     }
     fn strand(&self) -> Option<Strand> {
         self.strand
@@ -488,6 +808,125 @@ impl std::str::FromStr for PairRead {
     }
 }
 
+/// Long reads, e.g., from long-read single-cell ATAC-seq, each spanning a whole
+/// fragment between two Tn5 insertions. The first five columns of a record are
+/// those of [`PairRead`], followed by an optional strand. The remaining
+/// columns, e.g., the `MM` and `ML` methylation tags, are kept in `extra`. As
+/// both ends of a long read are insertions, long reads are stored as
+/// paired-end fragments, whatever their length.
+#[derive(Debug, Clone)]
+pub struct LongRead {
+    pub fragment: PairRead,
+    pub extra: Vec<String>,
+}
+
+/// Default minimum size of long reads, see [`crate::feature_count::FragmentData::long_reads`].
+pub const LONG_READ_MIN_FRAGMENT_SIZE: u64 = 100;
+
+/// Default maximum size of long reads, see [`crate::feature_count::FragmentData::long_reads`].
+pub const LONG_READ_MAX_FRAGMENT_SIZE: u64 = 100_000;
+
+impl LongRead {
+    /// The value of the SAM tag `key`, e.g., "MM" or "ML", found in the extra
+    /// columns as `KEY:TYPE:VALUE`.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.extra.iter().find_map(|x| {
+            let mut fields = x.splitn(3, ':');
+            if fields.next()? != key {
+                return None;
+            }
+            fields.next()?;
+            fields.next()
+        })
+    }
+
+    /// The number of methylation calls of the read and the number of calls
+    /// with a probability of at least 0.5, read from the `ML` tag. Returns
+    /// `None` if the read has no valid `ML` tag.
+    pub fn methylation_calls(&self) -> Option<(u64, u64)> {
+        // The value of `ML:B:C` is the array subtype followed by the
+        // probabilities, scaled to 0-255.
+        let mut values = self.tag("ML")?.split(',');
+        if values.next()? != "C" {
+            return None;
+        }
+        values.filter(|x| !x.is_empty()).try_fold((0, 0), |(n, m), x| {
+            let p: u8 = x.parse().ok()?;
+            Some((n + 1, m + (p >= 128) as u64))
+        })
+    }
+}
+
+impl std::str::FromStr for LongRead {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split('\t');
+        let fragment = PairRead::from_str(&fields.by_ref().take(5).collect::<Vec<_>>().join("\t"))?;
+        let mut extra: Vec<String> = fields.map(|x| x.to_string()).collect();
+        // The strand is optional: a sixth column that is not a strand is an
+        // extra column.
+        let strand = extra
+            .first()
+            .and_then(|x| if x == "." { Some(None) } else { x.parse().ok().map(Some) });
+        if strand.is_some() {
+            extra.remove(0);
+        }
+        Ok(LongRead {
+            fragment: PairRead { strand: strand.flatten(), ..fragment },
+            extra,
+        })
+    }
+}
+
+impl core::fmt::Display for LongRead {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.fragment)?;
+        if !self.extra.is_empty() {
+            if self.fragment.strand.is_none() {
+                write!(f, "\t.")?;
+            }
+            write!(f, "\t{}", self.extra.join("\t"))?;
+        }
+        Ok(())
+    }
+}
+
+impl From<LongRead> for Fragment {
+    fn from(read: LongRead) -> Self {
+        Fragment::Paired(read.fragment)
+    }
+}
+
+/// The number of methylation calls and the number of methylated calls of the
+/// long reads of every cell, see [`LongRead::methylation_calls`]. With
+/// `modality_sep`, the modality is removed from the barcodes, see
+/// [`super::split_modality`].
+pub fn count_methylation_calls<I>(reads: I, modality_sep: Option<&str>) -> HashMap<String, (u64, u64)>
+where
+    I: Iterator<Item = LongRead>,
+{
+    let mut counts: HashMap<String, (u64, u64)> = HashMap::new();
+    reads.for_each(|read| {
+        let Some(barcode) = read.fragment.barcode.as_deref() else {
+            return;
+        };
+        let cell = match modality_sep {
+            Some(sep) => match super::split_modality(barcode, sep) {
+                Some((cell, _)) => cell,
+                None => return,
+            },
+            None => barcode,
+        };
+        if let Some((n, m)) = read.methylation_calls() {
+            let count = counts.entry(cell.to_string()).or_default();
+            count.0 += n;
+            count.1 += m;
+        }
+    });
+    counts
+}
+
 /// Single-ended reads from single-cell ATAC-seq experiment.
 #[derive(Encode, Decode, Debug, Clone)]
 pub struct SingleRead {
@@ -525,7 +964,7 @@ impl BEDLike for SingleRead {
         self.barcode.as_deref()
     }
     fn score(&self) -> Option<bed_utils::bed::Score> {
-        Some(self.count.try_into().unwrap())
+        self.count.try_into().ok()
     }
     fn strand(&self) -> Option<Strand> {
         Some(self.strand)
@@ -646,7 +1085,7 @@ impl BaseValueQC {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
 pub struct FragmentQC {
     pub num_unique_fragment: u64,
     pub frac_mitochondrial: f64,
@@ -679,6 +1118,14 @@ impl<'a> FragmentQCBuilder<'a> {
         }
     }
 
+    /// Add the fragments counted by `other`, e.g., those of another modality
+    /// of the same cell.
+    pub(crate) fn merge(&mut self, other: Self) {
+        self.num_unique_fragment += other.num_unique_fragment;
+        self.num_total_fragment += other.num_total_fragment;
+        self.num_mitochondrial += other.num_mitochondrial;
+    }
+
     pub(crate) fn finish(self) -> FragmentQC {
         let frac_duplicated = 1.0
             - (self.num_unique_fragment + self.num_mitochondrial) as f64
@@ -733,6 +1180,25 @@ pub fn read_tss<R: Read>(file: R) -> impl Iterator<Item = (String, u64, bool)> {
     })
 }
 
+/// Read the exons from a GTF/GFF file.
+pub fn read_exons<R: Read>(file: R) -> impl Iterator<Item = GenomicRange> {
+    let reader = BufReader::new(file);
+    reader.lines().filter_map(|line| {
+        let l = line.unwrap();
+        if l.starts_with('#') {
+            return None;
+        }
+        let elements: Vec<&str> = l.split('\t').collect();
+        if elements.get(2) == Some(&"exon") {
+            let start = elements[3].parse::<u64>().unwrap() - 1;
+            let end = elements[4].parse::<u64>().unwrap();
+            Some(GenomicRange::new(elements[0], start, end))
+        } else {
+            None
+        }
+    })
+}
+
 #[derive(Debug, Clone)]
 pub struct TssRegions {
     pub promoters: GIntervalMap<bool>,
@@ -761,6 +1227,19 @@ impl TssRegions {
     pub fn len(&self) -> usize {
         2 * self.window_size as usize + 1
     }
+
+    /// Positions of an insertion within the promoters it overlaps, counted
+    /// from the 5' end of the promoter.
+    pub fn positions<'a>(&'a self, ins: &'a GenomicRange) -> impl Iterator<Item = usize> + 'a {
+        let start = ins.start();
+        self.promoters.find(ins).map(move |(promoter, is_fwd)| {
+            if *is_fwd {
+                (start - promoter.start()) as usize
+            } else {
+                (promoter.end() - 1 - start) as usize
+            }
+        })
+    }
 }
 
 pub fn make_promoter_map<I: Iterator<Item = (String, u64, bool)>>(
@@ -816,18 +1295,10 @@ impl<'a> TSSe<'a> {
         frag.to_insertions().into_iter().for_each(|ins| {
             self.n_total += 1;
             let mut overlapped = false;
-            self.promoters
-                .promoters
-                .find(&ins)
-                .for_each(|(promoter, is_fwd)| {
-                    overlapped = true;
-                    let pos = if *is_fwd {
-                        ins.start() - promoter.start()
-                    } else {
-                        promoter.end() - 1 - ins.start()
-                    };
-                    self.counts[pos as usize] += 1;
-                });
+            self.promoters.positions(&ins).for_each(|pos| {
+                overlapped = true;
+                self.counts[pos] += 1;
+            });
             if overlapped {
                 self.n_overlapping += 1;
             }
@@ -857,3 +1328,123 @@ impl<'a> TSSe<'a> {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_long_read() {
+        let read = LongRead::from_str("chr1\t100\t25100\tAAC\t1\t+\tMM:Z:A+a,3,0;\tML:B:C,200,100").unwrap();
+        assert_eq!(read.fragment.strand, Some(Strand::Forward));
+        assert_eq!(read.tag("MM"), Some("A+a,3,0;"));
+        assert_eq!(read.methylation_calls(), Some((2, 1)));
+        assert_eq!(read.to_string(), "chr1\t100\t25100\tAAC\t1\t+\tMM:Z:A+a,3,0;\tML:B:C,200,100");
+        let fragment: Fragment = read.into();
+        assert!(!fragment.is_single());
+        assert_eq!((fragment.start(), fragment.end()), (100, 25100));
+        assert_eq!(fragment.to_insertions().len(), 2);
+        let insertions = fragment.to_stranded_insertions();
+        assert!(matches!(insertions[0], (_, Strand::Forward)));
+        assert!(matches!(insertions[1], (_, Strand::Reverse)));
+        assert_eq!(insertions[1].0.start(), 25099);
+
+        // The strand is optional.
+        let read = LongRead::from_str("chr1\t100\t25100\tAAC\t1\tMM:Z:C+m,0;").unwrap();
+        assert_eq!(read.fragment.strand, None);
+        assert_eq!(read.extra, vec!["MM:Z:C+m,0;".to_string()]);
+        assert_eq!(read.methylation_calls(), None);
+        assert_eq!(read.to_string(), "chr1\t100\t25100\tAAC\t1\t.\tMM:Z:C+m,0;");
+        assert!(LongRead::from_str("chr1\t100\t25100\tAAC\tx").is_err());
+
+        let reads = ["chr1\t0\t500\tA#x\t1\t+\tML:B:C,255,0", "chr1\t0\t500\tA#y\t1\t+\tML:B:C,130"]
+            .iter()
+            .map(|x| LongRead::from_str(x).unwrap());
+        let counts = count_methylation_calls(reads, Some("#"));
+        assert_eq!(counts.get("A"), Some(&(3, 2)));
+    }
+
+    #[test]
+    fn test_covered_length() {
+        let regions = [
+            GenomicRange::new("chr2", 0, 10),
+            GenomicRange::new("chr1", 50, 60),
+            GenomicRange::new("chr1", 0, 20),
+            GenomicRange::new("chr1", 10, 30),
+            GenomicRange::new("chr1", 30, 40),
+        ];
+        assert_eq!(covered_length(&regions), 60);
+        assert_eq!(covered_length(&[]), 0);
+    }
+
+    #[test]
+    fn test_overlap_loci() {
+        let intervals = [
+            ("chr1", 0, 100),
+            ("chr1", 50, 150),
+            ("chr1", 60, 70),
+            ("chr1", 90, 120),
+            ("chr1", 150, 200),
+            ("chr2", 0, 10),
+            ("chr2", 5, 10),
+        ];
+        assert_eq!(
+            overlap_loci(intervals.into_iter(), 2),
+            vec![GenomicRange::new("chr1", 60, 70), GenomicRange::new("chr1", 90, 100)]
+        );
+        assert_eq!(overlap_loci(intervals.into_iter(), 1).len(), 2);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_parse_arbitrary_line(line in ".{0,64}") {
+            let _ = PairRead::from_str(&line);
+            let _ = SingleRead::from_str(&line);
+            let _ = Contact::from_str(&line);
+        }
+
+        #[test]
+        fn prop_parse_fields(
+            fields in prop::collection::vec(
+                prop_oneof!["[0-9]{1,25}", "-?[0-9]{1,5}", "[+.-]", "[a-zA-Z0-9_]{0,8}"],
+                0..8,
+            )
+        ) {
+            let line = fields.join("\t");
+            if let Ok(x) = PairRead::from_str(&line) {
+                let frag: Fragment = x.into();
+                let _ = frag.score();
+                let _ = frag.to_string();
+            }
+            if let Ok(x) = SingleRead::from_str(&line) {
+                let frag: Fragment = x.into();
+                let _ = frag.score();
+            }
+        }
+
+        #[test]
+        fn prop_pair_read_roundtrip(
+            chrom in "[a-zA-Z0-9_]{1,10}",
+            start in 0u64..1 << 40,
+            len in 0u64..100_000,
+            barcode in prop::option::of("[ACGT]{4,16}"),
+            count in any::<u32>(),
+            strand in prop::option::of(prop_oneof![Just(Strand::Forward), Just(Strand::Reverse)]),
+        ) {
+            let read = PairRead {
+                chrom,
+                start,
+                end: start + len,
+                barcode,
+                count,
+                strand,
+            };
+            let parsed = PairRead::from_str(&read.to_string()).unwrap();
+            prop_assert_eq!(parsed.to_string(), read.to_string());
+            prop_assert_eq!(parsed.count, count);
+            let _ = Fragment::from(parsed).score();
+        }
+    }
+}
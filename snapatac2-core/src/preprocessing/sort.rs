@@ -0,0 +1,159 @@
+//! Sorting and indexing of fragment files.
+//!
+//! Genome browsers and most tools reading fragments at random (e.g., IGV or
+//! ArchR) require the fragment file to be sorted by coordinate, compressed
+//! with BGZF and indexed with tabix. Aligners such as chromap write fragments
+//! in arbitrary order, so [`sort_fragment_file`] sorts them with a bounded
+//! amount of memory, spilling to disk when needed, and writes the compressed
+//! file together with its index.
+
+use anyhow::{bail, Context, Result};
+use bitcode::{Decode, Encode};
+use bed_utils::extsort::ExternalSorterBuilder;
+use noodles::{bgzf, core::Position, csi, tabix};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::utils::open_file_for_read;
+
+/// A line of a fragment file, with the coordinates used for sorting.
+#[derive(Encode, Decode, Debug, Clone)]
+struct Record {
+    chrom: String,
+    start: u64,
+    end: u64,
+    line: String,
+}
+
+impl Record {
+    fn parse(line: String) -> Result<Self> {
+        let mut fields = line.split('\t');
+        let (Some(chrom), Some(start), Some(end)) = (fields.next(), fields.next(), fields.next())
+        else {
+            bail!("expecting at least 3 tab-separated fields");
+        };
+        let start: u64 = start.parse().with_context(|| format!("invalid start: {}", start))?;
+        let end: u64 = end.parse().with_context(|| format!("invalid end: {}", end))?;
+        Ok(Self {
+            chrom: chrom.to_string(),
+            start,
+            end,
+            line,
+        })
+    }
+}
+
+/// Sort the fragments in `input` by coordinate (chromosome, start, end), and
+/// write them to `output` compressed with BGZF, along with a tabix index
+/// (`output` + ".tbi"). The input may be plain text, gzip or zstd compressed,
+/// and may contain any number of columns after the first three. Lines starting
+/// with '#' are kept as the header. Returns the path of the index.
+pub fn sort_fragment_file<P1, P2, P3>(input: P1, output: P2, temp_dir: Option<P3>) -> Result<PathBuf>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+{
+    let mut header = Vec::new();
+    let mut error = None;
+    let records = BufReader::new(open_file_for_read(&input)?)
+        .lines()
+        .enumerate()
+        .map_while(|(i, line)| {
+            let record = line.map_err(anyhow::Error::from).and_then(|line| {
+                if line.starts_with('#') {
+                    header.push(line);
+                    Ok(None)
+                } else if line.trim().is_empty() {
+                    Ok(None)
+                } else {
+                    Record::parse(line).map(Some)
+                }
+            });
+            record
+                .with_context(|| format!("failed to parse line {} of {}", i + 1, input.as_ref().display()))
+                .map_err(|e| error = Some(e))
+                .ok()
+        })
+        .flatten();
+
+    let mut sorter = ExternalSorterBuilder::new()
+        .with_chunk_size(crate::config::buffer_size::<Record>(10000000))
+        .with_compression(2);
+    if let Some(tmp) = temp_dir {
+        sorter = sorter.with_tmp_dir(tmp);
+    }
+    let sorted = sorter.build()?.sort_by(records, |a, b| {
+        a.chrom
+            .cmp(&b.chrom)
+            .then(a.start.cmp(&b.start))
+            .then(a.end.cmp(&b.end))
+    })?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    let output = output.as_ref();
+    let mut writer = bgzf::io::Writer::new(
+        File::create(output).with_context(|| format!("cannot create file: {}", output.display()))?,
+    );
+    let mut indexer = tabix::index::Indexer::default();
+    indexer.set_header(csi::binning_index::index::header::Builder::bed().build());
+    for line in header {
+        writeln!(writer, "{}", line)?;
+    }
+    for record in sorted {
+        let record = record?;
+        let start_position = writer.virtual_position();
+        writeln!(writer, "{}", record.line)?;
+        let end_position = writer.virtual_position();
+        // Tabix uses 1-based, closed intervals.
+        let start = Position::try_from(record.start as usize + 1)?;
+        let end = Position::try_from((record.end as usize).max(record.start as usize + 1))?;
+        indexer.add_record(
+            Some((record.chrom.as_str(), start, end, true)),
+            csi::binning_index::index::reference_sequence::bin::Chunk::new(start_position, end_position),
+        )?;
+    }
+    writer.finish()?;
+
+    let mut index_file = output.as_os_str().to_owned();
+    index_file.push(".tbi");
+    let index_file = PathBuf::from(index_file);
+    tabix::fs::write(&index_file, &indexer.build())
+        .with_context(|| format!("cannot write index: {}", index_file.display()))?;
+    Ok(index_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_sort_fragment_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("fragments.tsv");
+        std::fs::write(
+            &input,
+            "# comment\nchr2\t10\t50\tAAA\t1\nchr1\t100\t200\tCCC\t2\nchr1\t5\t80\tAAA\t1\n\n",
+        )
+        .unwrap();
+        let output = dir.path().join("fragments.tsv.gz");
+        let index = sort_fragment_file(&input, &output, None::<PathBuf>).unwrap();
+        assert!(index.exists());
+
+        let mut content = String::new();
+        bgzf::io::Reader::new(File::open(&output).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(
+            content,
+            "# comment\nchr1\t5\t80\tAAA\t1\nchr1\t100\t200\tCCC\t2\nchr2\t10\t50\tAAA\t1\n"
+        );
+
+        std::fs::write(&input, "chr1\tx\t80\n").unwrap();
+        assert!(sort_fragment_file(&input, &output, None::<PathBuf>).is_err());
+    }
+}
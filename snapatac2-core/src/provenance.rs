@@ -0,0 +1,227 @@
+//! Provenance of the data stored in an AnnData object.
+//!
+//! Operations that create or modify data (importing fragments, counting,
+//! embedding, ...) append an entry to `.uns["snapatac2_history"]`, recording
+//! the name of the operation, its parameters, the version of this crate,
+//! the time and, when available, the peak memory used by the operation. Each entry is stored as a JSON document, so the history can
+//! be read by any tool that understands h5ad files. Objects derived from
+//! another one, e.g., count matrices written to a new file, start with the
+//! history of their source.
+
+use anndata::{AnnDataOp, ElemCollectionOp};
+use anyhow::{Context, Result};
+use log::warn;
+use ndarray::Array1;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+use crate::utils::memory::MemoryTracker;
+
+pub const HISTORY_KEY: &str = "snapatac2_history";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProvenanceEntry {
+    pub operation: String,
+    pub parameters: Value,
+    pub version: String,
+    /// Time in RFC 3339 format.
+    pub timestamp: String,
+    /// Peak memory used by the operation in bytes, i.e., the peak resident set
+    /// size of the process during the operation minus that at its start.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peak_memory: Option<u64>,
+}
+
+impl ProvenanceEntry {
+    pub fn new(operation: &str, parameters: Value) -> Self {
+        Self {
+            operation: operation.to_string(),
+            parameters,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            peak_memory: None,
+        }
+    }
+}
+
+/// Append an entry to the history of `adata`. Failures are logged rather
+/// than returned, so that they never abort the operation being recorded.
+pub fn record<A: AnnDataOp>(adata: &A, operation: &str, parameters: Value) {
+    if let Err(e) = append(adata, ProvenanceEntry::new(operation, parameters)) {
+        warn!("Failed to record the provenance of '{}': {}", operation, e);
+    }
+}
+
+/// Like [`record`], also recording the peak memory used by the operation, as
+/// measured by `tracker`.
+pub fn record_tracked<A: AnnDataOp>(
+    adata: &A,
+    operation: &str,
+    parameters: Value,
+    tracker: MemoryTracker,
+) {
+    let entry = ProvenanceEntry {
+        peak_memory: tracker.finish(),
+        ..ProvenanceEntry::new(operation, parameters)
+    };
+    if let Err(e) = append(adata, entry) {
+        warn!("Failed to record the provenance of '{}': {}", operation, e);
+    }
+}
+
+/// Copy the history of `source` to `target`, a new object holding data
+/// derived from `source`, so that its history starts with the import and the
+/// operations performed on `source`. Failures are logged, as in [`record`].
+pub fn inherit<A: AnnDataOp, B: AnnDataOp>(source: &A, target: &B) {
+    let result = source
+        .uns()
+        .get_item::<Array1<String>>(HISTORY_KEY)
+        .and_then(|history| history.map_or(Ok(()), |x| target.uns().add(HISTORY_KEY, x)));
+    if let Err(e) = result {
+        warn!("Failed to copy the provenance: {}", e);
+    }
+}
+
+pub fn append<A: AnnDataOp>(adata: &A, entry: ProvenanceEntry) -> Result<()> {
+    let mut history: Vec<String> = adata
+        .uns()
+        .get_item::<Array1<String>>(HISTORY_KEY)?
+        .map_or(Vec::new(), |x| x.to_vec());
+    history.push(serde_json::to_string(&entry)?);
+    adata.uns().add(HISTORY_KEY, Array1::from_vec(history))
+}
+
+/// Read the history of `adata`, oldest first.
+pub fn read_history<A: AnnDataOp>(adata: &A) -> Result<Vec<ProvenanceEntry>> {
+    adata
+        .uns()
+        .get_item::<Array1<String>>(HISTORY_KEY)?
+        .map_or(Ok(Vec::new()), |x| {
+            x.iter()
+                .enumerate()
+                .map(|(i, s)| {
+                    serde_json::from_str(s)
+                        .with_context(|| format!("invalid entry {} in '.uns[\"{}\"]'", i, HISTORY_KEY))
+                })
+                .collect()
+        })
+}
+
+/// A difference between two histories.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum HistoryDiff {
+    /// The operation was only performed in one of the histories.
+    MissingOperation { operation: String, present_in: Side },
+    /// The operation was performed with different parameter values. `None`
+    /// means the parameter is absent.
+    Parameter {
+        operation: String,
+        parameter: String,
+        left: Option<Value>,
+        right: Option<Value>,
+    },
+    /// The operation was performed with different versions of the crate.
+    Version { operation: String, left: String, right: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// Compare two histories. For each operation, the most recent entries are
+/// compared, as they determine the current state of the data.
+pub fn diff(left: &[ProvenanceEntry], right: &[ProvenanceEntry]) -> Vec<HistoryDiff> {
+    fn latest<'a>(history: &'a [ProvenanceEntry], op: &str) -> Option<&'a ProvenanceEntry> {
+        history.iter().rev().find(|x| x.operation == op)
+    }
+
+    // Keep the order in which operations first appear.
+    let mut seen = BTreeSet::new();
+    let operations: Vec<&str> = left
+        .iter()
+        .chain(right)
+        .map(|x| x.operation.as_str())
+        .filter(|x| seen.insert(*x))
+        .collect();
+
+    let mut result = Vec::new();
+    for op in operations {
+        match (latest(left, op), latest(right, op)) {
+            (Some(l), Some(r)) => {
+                if l.version != r.version {
+                    result.push(HistoryDiff::Version {
+                        operation: op.to_string(),
+                        left: l.version.clone(),
+                        right: r.version.clone(),
+                    });
+                }
+                let keys: BTreeSet<&String> = l
+                    .parameters
+                    .as_object()
+                    .into_iter()
+                    .chain(r.parameters.as_object())
+                    .flat_map(|x| x.keys())
+                    .collect();
+                for key in keys {
+                    let lv = l.parameters.get(key);
+                    let rv = r.parameters.get(key);
+                    if lv != rv {
+                        result.push(HistoryDiff::Parameter {
+                            operation: op.to_string(),
+                            parameter: key.clone(),
+                            left: lv.cloned(),
+                            right: rv.cloned(),
+                        });
+                    }
+                }
+            }
+            (l, _) => result.push(HistoryDiff::MissingOperation {
+                operation: op.to_string(),
+                present_in: if l.is_some() { Side::Left } else { Side::Right },
+            }),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_diff() {
+        let left = vec![
+            ProvenanceEntry::new("import_fragments", json!({"min_num_fragment": 200})),
+            ProvenanceEntry::new("tile_matrix", json!({"bin_size": 500})),
+            ProvenanceEntry::new("tile_matrix", json!({"bin_size": 1000})),
+        ];
+        let right = vec![
+            ProvenanceEntry::new("import_fragments", json!({"min_num_fragment": 200})),
+            ProvenanceEntry::new("tile_matrix", json!({"bin_size": 500, "exclude_chroms": ["chrM"]})),
+            ProvenanceEntry::new("spectral", json!({"n_comps": 30})),
+        ];
+        let d = diff(&left, &right);
+        assert_eq!(d.len(), 3);
+        assert_eq!(
+            d[0],
+            HistoryDiff::Parameter {
+                operation: "tile_matrix".to_string(),
+                parameter: "bin_size".to_string(),
+                left: Some(json!(1000)),
+                right: Some(json!(500)),
+            }
+        );
+        assert!(matches!(&d[1], HistoryDiff::Parameter { parameter, left: None, .. } if parameter == "exclude_chroms"));
+        assert_eq!(
+            d[2],
+            HistoryDiff::MissingOperation { operation: "spectral".to_string(), present_in: Side::Right }
+        );
+        assert!(diff(&left, &left).is_empty());
+    }
+}
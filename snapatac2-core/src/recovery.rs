@@ -0,0 +1,228 @@
+//! Crash safety of the modifications of AnnData objects.
+//!
+//! Large elements (`.X` and the matrices in `.obsm`) are written in chunks, so
+//! an interrupted process can leave an element that is only partly written but
+//! still readable. Before such an element is written, its name is added to
+//! `.uns["snapatac2_pending"]`, and it is removed from the list once the
+//! element is complete. [`repair`] deletes the elements that are still listed.
+//!
+//! Files created from scratch are written under a temporary name next to
+//! their destination, synced to disk and renamed, see [`write_atomic`].
+
+use anndata::{AnnDataOp, ArrayElemOp, AxisArraysOp, ElemCollectionOp};
+use anyhow::{bail, Context, Result};
+use ndarray::Array1;
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::feature_count::finish_compaction;
+
+pub const PENDING_KEY: &str = "snapatac2_pending";
+
+/// An element of an AnnData object that is written in chunks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Element {
+    X,
+    Obsm(String),
+}
+
+impl fmt::Display for Element {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Element::X => write!(f, "X"),
+            Element::Obsm(key) => write!(f, "obsm/{}", key),
+        }
+    }
+}
+
+impl FromStr for Element {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('/') {
+            None if s == "X" => Ok(Element::X),
+            Some(("obsm", key)) if !key.is_empty() => Ok(Element::Obsm(key.to_string())),
+            _ => bail!("invalid element: {}", s),
+        }
+    }
+}
+
+/// The elements of `adata` whose writing has started but not finished.
+pub fn pending<A: AnnDataOp + ?Sized>(adata: &A) -> Result<Vec<Element>> {
+    adata
+        .uns()
+        .get_item::<Array1<String>>(PENDING_KEY)?
+        .map_or(Ok(Vec::new()), |x| x.iter().map(|s| s.parse()).collect())
+}
+
+fn set_pending<A: AnnDataOp + ?Sized>(adata: &A, elements: &[Element]) -> Result<()> {
+    if elements.is_empty() {
+        if adata.uns().keys().iter().any(|x| x == PENDING_KEY) {
+            adata.uns().remove(PENDING_KEY)?;
+        }
+        Ok(())
+    } else {
+        let names = elements.iter().map(|x| x.to_string()).collect();
+        adata.uns().add(PENDING_KEY, Array1::from_vec(names))
+    }
+}
+
+/// Run `f`, which writes `element` into `adata`, marking the element as
+/// pending while it runs. If `f` fails, the element stays marked as pending,
+/// as it may have been partly written.
+pub fn guarded<A, T, F>(adata: &A, element: Element, f: F) -> Result<T>
+where
+    A: AnnDataOp + ?Sized,
+    F: FnOnce() -> Result<T>,
+{
+    let mut elements = pending(adata)?;
+    if !elements.contains(&element) {
+        elements.push(element.clone());
+        set_pending(adata, &elements)?;
+    }
+    let result = f()?;
+    let elements: Vec<_> = pending(adata)?.into_iter().filter(|x| *x != element).collect();
+    set_pending(adata, &elements)?;
+    Ok(result)
+}
+
+/// Delete `element` from `adata`, e.g., after writing it failed, and unmark
+/// it as pending.
+pub fn discard<A: AnnDataOp + ?Sized>(adata: &A, element: Element) -> Result<()> {
+    remove(adata, &element)?;
+    let elements: Vec<_> = pending(adata)?.into_iter().filter(|x| *x != element).collect();
+    set_pending(adata, &elements)
+}
+
+/// Delete `element` from `adata` if it exists. Returns whether it existed.
+fn remove<A: AnnDataOp + ?Sized>(adata: &A, element: &Element) -> Result<bool> {
+    let exists = match element {
+        Element::X => adata.x().shape().is_some(),
+        Element::Obsm(key) => adata.obsm().keys().contains(key),
+    };
+    if exists {
+        match element {
+            Element::X => adata.del_x()?,
+            Element::Obsm(key) => adata.obsm().remove(key)?,
+        }
+    }
+    Ok(exists)
+}
+
+/// Create the file `path` by calling `f` on a temporary path in the same
+/// directory, then sync the file and rename it to `path`. `path` is either
+/// left untouched or replaced by a complete file.
+pub fn write_atomic<T, F>(path: &Path, f: F) -> Result<T>
+where
+    F: FnOnce(&Path) -> Result<T>,
+{
+    let tmp = temporary_path(path)?;
+    let result = f(&tmp).and_then(|x| {
+        File::open(&tmp)?.sync_all()?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("cannot rename {} to {}", tmp.display(), path.display()))?;
+        Ok(x)
+    });
+    match result {
+        Ok(x) => {
+            // Persist the rename. Directories cannot be opened on some
+            // platforms, in which case the rename is left to the OS.
+            if let Some(dir) = path.parent().and_then(|d| File::open(non_empty(d)).ok()) {
+                dir.sync_all().ok();
+            }
+            Ok(x)
+        }
+        Err(e) => {
+            std::fs::remove_file(&tmp).ok();
+            Err(e)
+        }
+    }
+}
+
+/// The temporary path used by [`write_atomic`] to create `path`.
+pub fn temporary_path(path: &Path) -> Result<PathBuf> {
+    let name = path
+        .file_name()
+        .with_context(|| format!("invalid file name: {}", path.display()))?;
+    let mut tmp = std::ffi::OsString::from(".");
+    tmp.push(name);
+    tmp.push(".partial");
+    Ok(path.with_file_name(tmp))
+}
+
+fn non_empty(dir: &Path) -> &Path {
+    if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    }
+}
+
+/// The result of [`repair`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RepairReport {
+    /// Elements that were found partly written.
+    pub partial: Vec<String>,
+    /// Elements that were deleted.
+    pub removed: Vec<String>,
+    /// Elements that were restored from a complete copy.
+    pub restored: Vec<String>,
+}
+
+/// Find the elements of `adata` left partly written by an interrupted
+/// operation and, unless `dry_run` is true, delete them so that they can be
+/// computed again. Fragments whose compaction was interrupted are restored
+/// from the compacted copy when it is complete.
+pub fn repair<A: AnnDataOp>(adata: &A, dry_run: bool, chunk_size: usize) -> Result<RepairReport> {
+    let mut report = RepairReport::default();
+    let elements = pending(adata)?;
+    for element in elements.iter() {
+        report.partial.push(element.to_string());
+        if dry_run {
+            continue;
+        }
+        if remove(adata, element)? {
+            report.removed.push(element.to_string());
+        }
+    }
+    if !dry_run {
+        set_pending(adata, &[])?;
+        if let Some(key) = finish_compaction(adata, chunk_size)? {
+            report.restored.push(Element::Obsm(key).to_string());
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_element() {
+        for s in ["X", "obsm/fragment_paired"] {
+            assert_eq!(s.parse::<Element>().unwrap().to_string(), s);
+        }
+        assert!("obsm/".parse::<Element>().is_err());
+        assert!("layers/counts".parse::<Element>().is_err());
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.txt");
+        write_atomic(&path, |tmp| Ok(std::fs::write(tmp, "a")?)).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a");
+
+        let failed: Result<()> = write_atomic(&path, |tmp| {
+            std::fs::write(tmp, "b")?;
+            bail!("interrupted")
+        });
+        assert!(failed.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a");
+        assert!(!temporary_path(&path).unwrap().exists());
+    }
+}
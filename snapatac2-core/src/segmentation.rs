@@ -0,0 +1,342 @@
+//! Chromatin state segmentation.
+//!
+//! Following ChromHMM, the genome is divided into fixed-size bins and the
+//! signal of every track (e.g., the pseudobulk coverage of a cell type, a
+//! condition or a histone modification) is binarized in each bin by a Poisson
+//! test against the genome-wide average of the track. A hidden Markov model
+//! whose states emit independent Bernoulli variables, one per track, is then
+//! learned with the Baum-Welch algorithm, and every bin is assigned to its
+//! most likely state with the Viterbi algorithm. Each state thus captures a
+//! combination of tracks, and consecutive bins in the same state form a
+//! segment.
+
+use anyhow::{ensure, Result};
+use bed_utils::bed::GenomicRange;
+use log::info;
+use ndarray::{Array1, Array2, ArrayView1, Axis};
+use rand::Rng as _;
+use statrs::distribution::{DiscreteCDF, Poisson};
+use std::path::Path;
+
+use crate::genome::ChromSizes;
+use crate::utils::{read_bedgraph_intervals, rng::SeedStream};
+
+/// Binarized signal of a chromosome, of shape (bins, tracks).
+pub struct BinarizedChrom {
+    pub chrom: String,
+    pub data: Array2<bool>,
+}
+
+/// Read coverage tracks in BedGraph format and compute the average signal of
+/// every track in bins of `bin_size` bases. Returns one matrix of shape
+/// (bins, tracks) per chromosome, in the order of `chrom_sizes`.
+pub fn read_binned_tracks<P: AsRef<Path>>(
+    files: &[P],
+    chrom_sizes: &ChromSizes,
+    bin_size: u64,
+) -> Result<Vec<(String, Array2<f64>)>> {
+    ensure!(bin_size > 0, "bin size must be positive");
+    ensure!(!files.is_empty(), "at least one track is required");
+    let mut result: Vec<_> = chrom_sizes
+        .into_iter()
+        .map(|(chrom, size)| {
+            let n_bins = size.div_ceil(bin_size) as usize;
+            (chrom.clone(), Array2::zeros((n_bins, files.len())))
+        })
+        .collect();
+    for (j, file) in files.iter().enumerate() {
+        for (chrom, intervals) in read_bedgraph_intervals(file)? {
+            let Some(i) = chrom_sizes.get_index_of(&chrom) else {
+                continue;
+            };
+            let mat = &mut result[i].1;
+            let n_bins = mat.nrows() as u64;
+            for (start, end, value) in intervals {
+                let mut pos = start;
+                while pos < end && pos / bin_size < n_bins {
+                    let bin = pos / bin_size;
+                    let next = ((bin + 1) * bin_size).min(end);
+                    mat[[bin as usize, j]] += value * (next - pos) as f64 / bin_size as f64;
+                    pos = next;
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Binarize the signal: a bin is marked as present for a track if the
+/// probability of observing at least its signal, under a Poisson distribution
+/// whose mean is the average signal of the track, is below `pvalue`.
+pub fn binarize(tracks: Vec<(String, Array2<f64>)>, pvalue: f64) -> Vec<BinarizedChrom> {
+    let n_tracks = tracks.first().map_or(0, |x| x.1.ncols());
+    let n_bins: usize = tracks.iter().map(|x| x.1.nrows()).sum();
+    let thresholds: Vec<f64> = (0..n_tracks)
+        .map(|j| {
+            let total: f64 = tracks.iter().map(|x| x.1.column(j).sum()).sum();
+            let mean = total / n_bins.max(1) as f64;
+            poisson_threshold(mean, pvalue)
+        })
+        .collect();
+    tracks
+        .into_iter()
+        .map(|(chrom, mat)| {
+            let data = Array2::from_shape_fn(mat.dim(), |(i, j)| mat[[i, j]] >= thresholds[j]);
+            BinarizedChrom { chrom, data }
+        })
+        .collect()
+}
+
+/// The smallest count `k` such that P(X >= k) < `pvalue` for X ~ Poisson(`mean`).
+fn poisson_threshold(mean: f64, pvalue: f64) -> f64 {
+    if mean <= 0.0 {
+        return f64::MIN_POSITIVE;
+    }
+    let dist = Poisson::new(mean).unwrap();
+    let mut k = 1u64;
+    while dist.sf(k - 1) >= pvalue {
+        k += 1;
+    }
+    k as f64
+}
+
+/// A hidden Markov model with multivariate Bernoulli emissions.
+#[derive(Debug, Clone)]
+pub struct BernoulliHmm {
+    /// Initial state probabilities.
+    pub initial: Array1<f64>,
+    /// Transition probabilities, of shape (states, states).
+    pub transition: Array2<f64>,
+    /// Probability that each state emits each track, of shape (states, tracks).
+    pub emission: Array2<f64>,
+    /// Log-likelihood of the data after each iteration of the training.
+    pub log_likelihood: Vec<f64>,
+}
+
+/// Lower bound of the probabilities, which keeps the logarithms finite.
+const MIN_PROB: f64 = 1e-10;
+
+impl BernoulliHmm {
+    /// A random model with `n_states` states, favoring self-transitions.
+    pub fn new(n_states: usize, n_tracks: usize, seed: u64) -> Self {
+        let mut rng = SeedStream::new(seed).derive("segmentation").rng(0);
+        let initial = Array1::from_elem(n_states, 1.0 / n_states as f64);
+        let mut transition = Array2::from_shape_fn((n_states, n_states), |(i, j)| {
+            if i == j {
+                n_states as f64
+            } else {
+                rng.random::<f64>()
+            }
+        });
+        transition
+            .rows_mut()
+            .into_iter()
+            .for_each(|mut row| {
+                let s = row.sum();
+                row /= s;
+            });
+        let emission = Array2::from_shape_fn((n_states, n_tracks), |_| rng.random_range(0.05..0.95));
+        Self {
+            initial,
+            transition,
+            emission,
+            log_likelihood: Vec::new(),
+        }
+    }
+
+    pub fn n_states(&self) -> usize {
+        self.initial.len()
+    }
+
+    /// Probability of the observations of a bin under each state.
+    fn emission_prob(&self, obs: ArrayView1<bool>) -> Array1<f64> {
+        self.emission
+            .rows()
+            .into_iter()
+            .map(|e| {
+                e.iter()
+                    .zip(obs.iter())
+                    .map(|(p, o)| if *o { *p } else { 1.0 - *p })
+                    .product::<f64>()
+                    .max(MIN_PROB)
+            })
+            .collect()
+    }
+
+    /// Learn the parameters with the Baum-Welch algorithm, treating every
+    /// chromosome as an independent sequence. Stops after `max_iter`
+    /// iterations or once the log-likelihood improves by less than `tol`.
+    pub fn fit(&mut self, data: &[BinarizedChrom], max_iter: usize, tol: f64) {
+        let (n_states, n_tracks) = self.emission.dim();
+        for iter in 0..max_iter {
+            let mut initial = Array1::<f64>::zeros(n_states);
+            let mut transition = Array2::<f64>::zeros((n_states, n_states));
+            let mut emission = Array2::<f64>::zeros((n_states, n_tracks));
+            let mut occupancy = Array1::<f64>::zeros(n_states);
+            let mut log_likelihood = 0.0;
+
+            for chrom in data.iter().filter(|x| x.data.nrows() > 0) {
+                let obs = &chrom.data;
+                let n = obs.nrows();
+                let emit: Vec<_> = obs.rows().into_iter().map(|o| self.emission_prob(o)).collect();
+
+                // Forward pass with scaling.
+                let mut alpha = Array2::<f64>::zeros((n, n_states));
+                let mut scale = vec![0.0; n];
+                for t in 0..n {
+                    let prior = if t == 0 {
+                        self.initial.clone()
+                    } else {
+                        alpha.row(t - 1).dot(&self.transition)
+                    };
+                    let a = prior * &emit[t];
+                    scale[t] = a.sum().max(f64::MIN_POSITIVE);
+                    alpha.row_mut(t).assign(&(a / scale[t]));
+                }
+                log_likelihood += scale.iter().map(|s| s.ln()).sum::<f64>();
+
+                // Backward pass, accumulating the expected counts.
+                let mut beta = Array1::<f64>::ones(n_states);
+                for t in (0..n).rev() {
+                    let gamma = &alpha.row(t) * &beta;
+                    let gamma = &gamma / gamma.sum().max(f64::MIN_POSITIVE);
+                    occupancy += &gamma;
+                    obs.row(t).iter().enumerate().for_each(|(j, o)| {
+                        if *o {
+                            emission.column_mut(j).scaled_add(1.0, &gamma);
+                        }
+                    });
+                    if t == 0 {
+                        initial += &gamma;
+                    } else {
+                        // xi(i, j) = alpha(t-1, i) * A(i, j) * e(t, j) * beta(t, j) / scale(t)
+                        let b = &emit[t] * &beta;
+                        let prev = alpha.row(t - 1);
+                        let xi = &self.transition
+                            * &prev.view().insert_axis(Axis(1))
+                            * &b.view().insert_axis(Axis(0))
+                            / scale[t];
+                        transition += &xi;
+                        beta = self.transition.dot(&b) / scale[t];
+                    }
+                }
+            }
+
+            let total = initial.sum().max(f64::MIN_POSITIVE);
+            self.initial = initial.mapv(|x| (x / total).max(MIN_PROB));
+            transition.rows_mut().into_iter().for_each(|mut row| {
+                let s = row.sum().max(f64::MIN_POSITIVE);
+                row.mapv_inplace(|x| (x / s).max(MIN_PROB));
+            });
+            self.transition = transition;
+            emission
+                .rows_mut()
+                .into_iter()
+                .zip(occupancy.iter())
+                .for_each(|(mut row, occ)| {
+                    row.mapv_inplace(|x| (x / occ.max(f64::MIN_POSITIVE)).clamp(MIN_PROB, 1.0 - MIN_PROB))
+                });
+            self.emission = emission;
+
+            info!("Iteration {}: log-likelihood = {:.4}", iter + 1, log_likelihood);
+            let improvement = self
+                .log_likelihood
+                .last()
+                .map_or(f64::INFINITY, |prev| log_likelihood - prev);
+            self.log_likelihood.push(log_likelihood);
+            if improvement.abs() < tol {
+                break;
+            }
+        }
+    }
+
+    /// The most likely state of every bin.
+    pub fn viterbi(&self, obs: &Array2<bool>) -> Vec<usize> {
+        let n = obs.nrows();
+        let n_states = self.n_states();
+        if n == 0 {
+            return Vec::new();
+        }
+        let log_trans = self.transition.mapv(f64::ln);
+        let mut score = self.initial.mapv(f64::ln) + self.emission_prob(obs.row(0)).mapv(f64::ln);
+        let mut backtrack = Array2::<usize>::zeros((n, n_states));
+        for t in 1..n {
+            let log_emit = self.emission_prob(obs.row(t)).mapv(f64::ln);
+            let mut next = Array1::zeros(n_states);
+            for j in 0..n_states {
+                let (best, s) = (0..n_states)
+                    .map(|i| (i, score[i] + log_trans[[i, j]]))
+                    .max_by(|a, b| a.1.total_cmp(&b.1))
+                    .unwrap();
+                backtrack[[t, j]] = best;
+                next[j] = s + log_emit[j];
+            }
+            score = next;
+        }
+        let mut state = (0..n_states).max_by(|a, b| score[*a].total_cmp(&score[*b])).unwrap();
+        let mut path = vec![0; n];
+        for t in (0..n).rev() {
+            path[t] = state;
+            state = backtrack[[t, state]];
+        }
+        path
+    }
+}
+
+/// Merge consecutive bins in the same state into segments.
+pub fn to_segments(
+    chrom: &str,
+    states: &[usize],
+    bin_size: u64,
+    chrom_size: u64,
+) -> Vec<(GenomicRange, usize)> {
+    let mut segments = Vec::new();
+    let mut start = 0;
+    for i in 1..=states.len() {
+        if i == states.len() || states[i] != states[start] {
+            let region = GenomicRange::new(
+                chrom,
+                start as u64 * bin_size,
+                (i as u64 * bin_size).min(chrom_size),
+            );
+            segments.push((region, states[start]));
+            start = i;
+        }
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bed_utils::bed::BEDLike;
+    use ndarray::array;
+
+    #[test]
+    fn test_segmentation() {
+        // Alternating blocks of "track 0 on" and "track 1 on".
+        let data = Array2::from_shape_fn((400, 2), |(i, j)| (i / 50) % 2 == j);
+        let chroms = vec![BinarizedChrom {
+            chrom: "chr1".to_string(),
+            data: data.clone(),
+        }];
+        let mut model = BernoulliHmm::new(2, 2, 0);
+        model.fit(&chroms, 50, 1e-6);
+        let ll = &model.log_likelihood;
+        assert!(ll.windows(2).all(|w| w[1] >= w[0] - 1e-6));
+
+        let states = model.viterbi(&data);
+        let segments = to_segments("chr1", &states, 200, 79_950);
+        assert_eq!(segments.len(), 8);
+        assert_eq!(segments.last().unwrap().0.end(), 79_950);
+        assert_ne!(segments[0].1, segments[1].1);
+        assert_eq!(segments[0].1, segments[2].1);
+    }
+
+    #[test]
+    fn test_binarize() {
+        let tracks = vec![("chr1".to_string(), array![[0.0], [1.0], [20.0], [0.0]])];
+        let bin = binarize(tracks, 1e-4);
+        assert_eq!(bin[0].data.column(0).to_vec(), vec![false, false, true, false]);
+    }
+}
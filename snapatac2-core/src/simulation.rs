@@ -0,0 +1,298 @@
+//! Simulation of single-cell ATAC-seq fragments.
+//!
+//! Cells are drawn from a mixture of cell types, each with its own set of
+//! accessible peaks on top of peaks shared by all types. Sequencing depth
+//! follows a log-normal distribution, and fragment lengths follow the
+//! nucleosome-free/mono-/di-nucleosome pattern of real data. Doublets and
+//! batch effects (per-batch changes in peak accessibility) can be added.
+//!
+//! Every cell is generated from its own random stream, so the fragments of a
+//! cell only depend on the seed and the index of the cell.
+
+use crate::genome::ChromSizes;
+use crate::preprocessing::{Fragment, PairRead};
+use crate::utils::rng::{Rng as SimRng, SeedStream};
+
+use anyhow::{ensure, Result};
+use bed_utils::bed::{BEDLike, GenomicRange};
+use rand::Rng;
+
+#[derive(Debug, Clone)]
+pub struct SimulationOptions {
+    pub n_cells: usize,
+    pub n_cell_types: usize,
+    pub n_peaks: usize,
+    /// Fraction of peaks that are specific to one cell type. The remaining
+    /// peaks are accessible in all cell types.
+    pub specific_peak_fraction: f64,
+    pub peak_width: u64,
+    /// Mean number of fragments per cell.
+    pub mean_depth: f64,
+    /// Standard deviation of the log depth.
+    pub depth_sd: f64,
+    /// Fraction of fragments in peaks. The rest are uniformly distributed
+    /// over the genome.
+    pub frip: f64,
+    pub doublet_rate: f64,
+    pub n_batches: usize,
+    /// Fraction of peaks whose accessibility is changed in every batch.
+    pub batch_effect: f64,
+    pub chrom_sizes: ChromSizes,
+    pub seed: u64,
+}
+
+impl Default for SimulationOptions {
+    fn default() -> Self {
+        Self {
+            n_cells: 1000,
+            n_cell_types: 5,
+            n_peaks: 5000,
+            specific_peak_fraction: 0.5,
+            peak_width: 500,
+            mean_depth: 5000.0,
+            depth_sd: 0.5,
+            frip: 0.5,
+            doublet_rate: 0.05,
+            n_batches: 1,
+            batch_effect: 0.0,
+            chrom_sizes: (1..=5).map(|i| (format!("chr{}", i), 20_000_000)).collect(),
+            seed: 0,
+        }
+    }
+}
+
+/// Ground truth of a simulated cell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatedCell {
+    pub barcode: String,
+    pub cell_type: usize,
+    pub batch: usize,
+    /// The second cell type if the cell is a doublet.
+    pub doublet_with: Option<usize>,
+    /// Expected number of fragments.
+    pub depth: usize,
+}
+
+pub struct Simulation {
+    options: SimulationOptions,
+    seeds: SeedStream,
+    pub peaks: Vec<GenomicRange>,
+    pub cells: Vec<SimulatedCell>,
+    /// Cumulative peak weights of every (cell type, batch) pair.
+    profiles: Vec<Vec<f64>>,
+}
+
+impl Simulation {
+    pub fn new(options: SimulationOptions) -> Result<Self> {
+        ensure!(options.n_cell_types > 0, "n_cell_types must be positive");
+        ensure!(options.n_batches > 0, "n_batches must be positive");
+        ensure!(options.n_peaks > 0, "n_peaks must be positive");
+        ensure!((0.0..=1.0).contains(&options.frip), "frip must be in [0, 1]");
+        ensure!((0.0..=1.0).contains(&options.doublet_rate), "doublet_rate must be in [0, 1]");
+        ensure!(
+            (&options.chrom_sizes).into_iter().all(|(_, s)| *s > options.peak_width + 1000),
+            "chromosomes must be longer than the peaks"
+        );
+        let seeds = SeedStream::new(options.seed);
+
+        let mut rng = seeds.derive("peaks").rng(0);
+        let mut peaks: Vec<GenomicRange> = (0..options.n_peaks)
+            .map(|_| {
+                let (chrom, start) = random_position(&mut rng, &options.chrom_sizes, options.peak_width);
+                GenomicRange::new(chrom, start, start + options.peak_width)
+            })
+            .collect();
+        peaks.sort_by(|a, b| a.compare(b));
+
+        // Specific peaks are accessible in one cell type and have a low
+        // background accessibility in the others.
+        let mut rng = seeds.derive("profiles").rng(0);
+        let owner: Vec<Option<usize>> = (0..options.n_peaks)
+            .map(|_| {
+                if rng.random::<f64>() < options.specific_peak_fraction {
+                    Some(rng.random_range(0..options.n_cell_types))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let batch_factors: Vec<Vec<f64>> = (0..options.n_batches)
+            .map(|_| {
+                (0..options.n_peaks)
+                    .map(|_| {
+                        if rng.random::<f64>() < options.batch_effect {
+                            // Log-uniform in [1/4, 4].
+                            4f64.powf(rng.random::<f64>() * 2.0 - 1.0)
+                        } else {
+                            1.0
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        let profiles = (0..options.n_cell_types)
+            .flat_map(|t| {
+                let owner = &owner;
+                batch_factors.iter().map(move |factors| {
+                    let mut acc = 0.0;
+                    owner
+                        .iter()
+                        .zip(factors)
+                        .map(|(o, f)| {
+                            acc += f * match o {
+                                Some(x) if *x != t => 0.05,
+                                _ => 1.0,
+                            };
+                            acc
+                        })
+                        .collect::<Vec<f64>>()
+                })
+            })
+            .collect();
+
+        let mut rng = seeds.derive("cells").rng(0);
+        let cells = (0..options.n_cells)
+            .map(|i| {
+                let cell_type = rng.random_range(0..options.n_cell_types);
+                let batch = rng.random_range(0..options.n_batches);
+                let doublet_with = if rng.random::<f64>() < options.doublet_rate {
+                    Some(rng.random_range(0..options.n_cell_types))
+                } else {
+                    None
+                };
+                let mut depth = (options.mean_depth.ln() - options.depth_sd.powi(2) / 2.0
+                    + options.depth_sd * standard_normal(&mut rng))
+                .exp();
+                if doublet_with.is_some() {
+                    depth *= 2.0;
+                }
+                SimulatedCell {
+                    barcode: format!("cell_{:06}", i),
+                    cell_type,
+                    batch,
+                    doublet_with,
+                    depth: depth.round().max(1.0) as usize,
+                }
+            })
+            .collect();
+
+        Ok(Self { options, seeds, peaks, cells, profiles })
+    }
+
+    /// Generate the fragments of the `i`-th cell, sorted by genomic position.
+    pub fn cell_fragments(&self, i: usize) -> Vec<Fragment> {
+        let cell = &self.cells[i];
+        let mut rng = self.seeds.derive("fragments").rng(i as u64);
+        let batch = cell.batch;
+        let n_batches = self.options.n_batches;
+        let mut fragments: Vec<PairRead> = (0..cell.depth)
+            .map(|k| {
+                // Half of the fragments of a doublet come from the second cell type.
+                let cell_type = match cell.doublet_with {
+                    Some(t) if k % 2 == 1 => t,
+                    _ => cell.cell_type,
+                };
+                let len = fragment_length(&mut rng);
+                let (chrom, start) = if rng.random::<f64>() < self.options.frip {
+                    let weights = &self.profiles[cell_type * n_batches + batch];
+                    let x = rng.random::<f64>() * weights[weights.len() - 1];
+                    let peak = &self.peaks[weights.partition_point(|w| *w < x).min(weights.len() - 1)];
+                    let center = rng.random_range(peak.start()..peak.end());
+                    (peak.chrom().to_string(), center.saturating_sub(len / 2))
+                } else {
+                    let (chrom, start) = random_position(&mut rng, &self.options.chrom_sizes, len);
+                    (chrom.to_string(), start)
+                };
+                let end = (start + len).min(self.options.chrom_sizes.get(&chrom).unwrap());
+                PairRead {
+                    chrom,
+                    start,
+                    end,
+                    barcode: Some(cell.barcode.clone().into()),
+                    count: 1,
+                    strand: None,
+                }
+            })
+            .collect();
+        let chrom_order = |c: &str| self.options.chrom_sizes.get_index_of(c);
+        fragments.sort_by(|a, b| {
+            chrom_order(&a.chrom).cmp(&chrom_order(&b.chrom)).then(a.start.cmp(&b.start))
+        });
+        fragments.into_iter().map(Into::into).collect()
+    }
+
+    /// Fragments of all cells, grouped by cell barcode.
+    pub fn fragments(&self) -> impl Iterator<Item = Fragment> + '_ {
+        (0..self.cells.len()).flat_map(move |i| self.cell_fragments(i))
+    }
+}
+
+/// A uniformly random position such that `[start, start + len)` lies within
+/// a chromosome, choosing chromosomes proportionally to their sizes.
+fn random_position<'a>(rng: &mut SimRng, chrom_sizes: &'a ChromSizes, len: u64) -> (&'a str, u64) {
+    let total: u64 = chrom_sizes.into_iter().map(|(_, s)| s.saturating_sub(len)).sum();
+    let mut x = rng.random_range(0..total.max(1));
+    for (chrom, size) in chrom_sizes {
+        let n = size.saturating_sub(len);
+        if x < n {
+            return (chrom.as_str(), x);
+        }
+        x -= n;
+    }
+    let (chrom, _) = chrom_sizes.into_iter().next().unwrap();
+    (chrom.as_str(), 0)
+}
+
+/// Fragment lengths with the typical nucleosomal periodicity: nucleosome-free
+/// fragments (~100bp), mono-nucleosome (~200bp) and di-nucleosome (~380bp).
+fn fragment_length(rng: &mut SimRng) -> u64 {
+    let (mean, sd) = match rng.random::<f64>() {
+        x if x < 0.6 => (100.0, 30.0),
+        x if x < 0.9 => (200.0, 30.0),
+        _ => (380.0, 40.0),
+    };
+    (mean + sd * standard_normal(rng)).max(30.0) as u64
+}
+
+/// Box-Muller transform.
+fn standard_normal(rng: &mut SimRng) -> f64 {
+    let u1: f64 = 1.0 - rng.random::<f64>();
+    let u2: f64 = rng.random();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulation() {
+        let options = SimulationOptions {
+            n_cells: 20,
+            n_peaks: 100,
+            mean_depth: 200.0,
+            doublet_rate: 0.2,
+            n_batches: 2,
+            batch_effect: 0.1,
+            seed: 7,
+            ..Default::default()
+        };
+        let sim = Simulation::new(options.clone()).unwrap();
+        assert_eq!(sim.cells.len(), 20);
+
+        let fragments: Vec<_> = sim.fragments().collect();
+        assert_eq!(fragments.len(), sim.cells.iter().map(|x| x.depth).sum::<usize>());
+        fragments.iter().for_each(|f| {
+            assert!(f.start() < f.end());
+            assert!(f.end() <= options.chrom_sizes.get(f.chrom()).unwrap());
+        });
+
+        // Deterministic given the seed.
+        let sim2 = Simulation::new(options).unwrap();
+        assert_eq!(sim.cells, sim2.cells);
+        assert_eq!(
+            format!("{}", sim.cell_fragments(3)[0]),
+            format!("{}", sim2.cell_fragments(3)[0]),
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! Helpers shared by the unit tests.
+//!
+//! Golden files are known-good coverage tracks stored under `test/golden` as
+//! gzipped bedGraph, so that changes to them can be reviewed in diffs.
+//! [`assert_track_matches`] compares a bedGraph or bigWig file produced by a
+//! test with a golden file, position by position and within a tolerance.
+//! Setting the environment variable `SNAPATAC2_UPDATE_GOLDEN=1` rewrites the
+//! golden files from the test outputs instead; the new files must be reviewed
+//! before they are committed.
+
+use anyhow::{Context, Result};
+use bed_utils::bed::{io::Reader, BEDLike, BedGraph};
+use bigtools::BigWigRead;
+use indexmap::{IndexMap, IndexSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::utils::{open_file_for_read, open_file_for_write, Compression};
+
+/// Maximum difference allowed between two values `a` and `b`:
+/// `|a - b| <= abs + rel * max(|a|, |b|)`.
+#[derive(Debug, Clone, Copy)]
+pub struct Tolerance {
+    pub abs: f64,
+    pub rel: f64,
+}
+
+impl Default for Tolerance {
+    /// BigWig files store values as `f32`.
+    fn default() -> Self {
+        Self { abs: 1e-6, rel: 1e-5 }
+    }
+}
+
+impl Tolerance {
+    fn accepts(&self, a: f64, b: f64) -> bool {
+        (a - b).abs() <= self.abs + self.rel * a.abs().max(b.abs())
+    }
+}
+
+/// An interval on which two tracks differ.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub chrom: String,
+    pub start: u64,
+    pub end: u64,
+    pub actual: f64,
+    pub expected: f64,
+}
+
+/// Read a bedGraph or bigWig file, depending on its extension.
+pub fn read_track<P: AsRef<Path>>(path: P) -> Result<Vec<BedGraph<f64>>> {
+    let path = path.as_ref();
+    let name = path.to_string_lossy().to_lowercase();
+    if name.ends_with(".bw") || name.ends_with(".bigwig") {
+        let mut reader = BigWigRead::open_file(path.to_str().unwrap())
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let chroms = reader.chroms().to_vec();
+        let mut result = Vec::new();
+        for chrom in chroms {
+            for x in reader.get_interval(&chrom.name, 0, chrom.length)? {
+                let x = x?;
+                result.push(BedGraph::new(&chrom.name, x.start as u64, x.end as u64, x.value as f64));
+            }
+        }
+        Ok(result)
+    } else {
+        Reader::new(open_file_for_read(path)?, None)
+            .records()
+            .map(|x| x.with_context(|| format!("invalid record in {}", path.display())))
+            .collect()
+    }
+}
+
+/// Compare two tracks position by position. Positions not covered by a track
+/// have a value of 0, so the way values are split into intervals does not
+/// matter. Adjacent mismatched positions with the same values are reported
+/// as one interval.
+pub fn compare_tracks(
+    actual: &[BedGraph<f64>],
+    expected: &[BedGraph<f64>],
+    tolerance: Tolerance,
+) -> Vec<Mismatch> {
+    let by_chrom = |track: &[BedGraph<f64>]| {
+        let mut result: IndexMap<String, Vec<(u64, u64, f64)>> = IndexMap::new();
+        track.iter().for_each(|x| {
+            result
+                .entry(x.chrom().to_string())
+                .or_default()
+                .push((x.start(), x.end(), x.value))
+        });
+        result.values_mut().for_each(|x| x.sort_by_key(|x| x.0));
+        result
+    };
+    let actual = by_chrom(actual);
+    let expected = by_chrom(expected);
+    let chroms: IndexSet<&String> = actual.keys().chain(expected.keys()).collect();
+
+    let empty = Vec::new();
+    let mut result: Vec<Mismatch> = Vec::new();
+    for chrom in chroms {
+        let a = actual.get(chrom).unwrap_or(&empty);
+        let b = expected.get(chrom).unwrap_or(&empty);
+        let mut bounds: Vec<u64> = a.iter().chain(b).flat_map(|(s, e, _)| [*s, *e]).collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+        let (mut i, mut j) = (0, 0);
+        for w in bounds.windows(2) {
+            let (start, end) = (w[0], w[1]);
+            let va = value_at(a, &mut i, start);
+            let vb = value_at(b, &mut j, start);
+            if tolerance.accepts(va, vb) {
+                continue;
+            }
+            match result.last_mut() {
+                Some(last)
+                    if last.chrom == *chrom
+                        && last.end == start
+                        && last.actual == va
+                        && last.expected == vb =>
+                {
+                    last.end = end
+                }
+                _ => result.push(Mismatch {
+                    chrom: chrom.clone(),
+                    start,
+                    end,
+                    actual: va,
+                    expected: vb,
+                }),
+            }
+        }
+    }
+    result
+}
+
+/// The value of the sorted intervals `track` at `pos`, where `idx` is the
+/// index of the first interval that may contain `pos`. Positions must be
+/// visited in increasing order.
+fn value_at(track: &[(u64, u64, f64)], idx: &mut usize, pos: u64) -> f64 {
+    while *idx < track.len() && track[*idx].1 <= pos {
+        *idx += 1;
+    }
+    match track.get(*idx) {
+        Some((s, _, v)) if *s <= pos => *v,
+        _ => 0.0,
+    }
+}
+
+/// Path of a golden file, relative to the crate root.
+pub fn golden_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("test").join("golden").join(name)
+}
+
+/// Check that the track in `output` matches the golden file `name`, see the
+/// module documentation.
+pub fn assert_track_matches<P: AsRef<Path>>(output: P, name: &str, tolerance: Tolerance) {
+    let actual = read_track(output).unwrap();
+    let golden = golden_path(name);
+    if std::env::var("SNAPATAC2_UPDATE_GOLDEN").is_ok_and(|x| x == "1") {
+        write_golden(&actual, &golden).unwrap();
+        return;
+    }
+    let expected = read_track(&golden)
+        .with_context(|| format!("cannot read golden file {}", golden.display()))
+        .unwrap();
+    let mismatches = compare_tracks(&actual, &expected, tolerance);
+    if !mismatches.is_empty() {
+        let shown = mismatches
+            .iter()
+            .take(10)
+            .map(|x| {
+                format!(
+                    "  {}:{}-{}: {} (expected {})",
+                    x.chrom, x.start, x.end, x.actual, x.expected
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        panic!(
+            "track differs from {} in {} intervals:\n{}",
+            golden.display(),
+            mismatches.len(),
+            shown
+        );
+    }
+}
+
+fn write_golden(track: &[BedGraph<f64>], path: &Path) -> Result<()> {
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let mut writer = open_file_for_write(path, Some(Compression::Gzip), None)?;
+    for x in track {
+        writeln!(writer, "{}\t{}\t{}\t{}", x.chrom(), x.start(), x.end(), x.value)?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_tracks() {
+        let a = vec![
+            BedGraph::new("chr1", 0, 10, 1.0),
+            BedGraph::new("chr1", 10, 20, 1.0),
+            BedGraph::new("chr2", 0, 5, 2.0),
+        ];
+        let b = vec![
+            BedGraph::new("chr1", 0, 20, 1.0000001),
+            BedGraph::new("chr2", 0, 3, 2.0),
+            BedGraph::new("chr3", 0, 5, 0.0),
+        ];
+        let mismatches = compare_tracks(&a, &b, Tolerance::default());
+        assert_eq!(
+            mismatches,
+            vec![Mismatch {
+                chrom: "chr2".to_string(),
+                start: 3,
+                end: 5,
+                actual: 2.0,
+                expected: 0.0,
+            }]
+        );
+        assert!(compare_tracks(&a, &a, Tolerance { abs: 0.0, rel: 0.0 }).is_empty());
+    }
+}
@@ -0,0 +1,370 @@
+//! Binned signal of arbitrary regions, computed on demand for genome browsers.
+//!
+//! The fragments of every group of cells are sorted by position once, with a
+//! bounded amount of memory, and written to a temporary file. Only a sparse
+//! index of the file, the start of every [`BLOCK_SIZE`]-th fragment, is kept
+//! in memory, so that a query reads from disk the blocks overlapping the
+//! requested region.
+
+use anyhow::{bail, ensure, Result};
+use bed_utils::bed::{BEDLike, GenomicRange, Strand};
+use bed_utils::extsort::ExternalSorterBuilder;
+use bitcode::{Decode, Encode};
+use indexmap::{IndexMap, IndexSet};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+use crate::preprocessing::Fragment;
+use crate::SnapData;
+
+/// Number of fragments between two entries of the in-memory index.
+pub const BLOCK_SIZE: usize = 1024;
+
+/// The signal reported for every bin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackType {
+    /// Number of Tn5 insertions in the bin.
+    Insertion,
+    /// Number of fragments overlapping the bin.
+    Fragment,
+}
+
+impl TryFrom<&str> for TrackType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "insertion" => Ok(TrackType::Insertion),
+            "fragment" => Ok(TrackType::Fragment),
+            _ => bail!("track type must be 'insertion' or 'fragment'"),
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+struct Entry {
+    start: u64,
+    end: u64,
+    count: u32,
+    /// Whether the start and the end of the fragment are insertion sites.
+    /// Single-end reads only have one insertion site.
+    insertions: (bool, bool),
+}
+
+/// Size of an entry in the file.
+const ENTRY_BYTES: usize = 21;
+
+impl Entry {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.start.to_le_bytes())?;
+        writer.write_all(&self.end.to_le_bytes())?;
+        writer.write_all(&self.count.to_le_bytes())?;
+        writer.write_all(&[self.insertions.0 as u8 | (self.insertions.1 as u8) << 1])?;
+        Ok(())
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut buf = [0u8; ENTRY_BYTES];
+        reader.read_exact(&mut buf)?;
+        Ok(Self {
+            start: u64::from_le_bytes(buf[0..8].try_into()?),
+            end: u64::from_le_bytes(buf[8..16].try_into()?),
+            count: u32::from_le_bytes(buf[16..20].try_into()?),
+            insertions: (buf[20] & 1 != 0, buf[20] & 2 != 0),
+        })
+    }
+}
+
+/// Fragments of a group on a chromosome, stored contiguously in the file.
+#[derive(Debug)]
+struct ChromIndex {
+    /// Index of the first fragment in the file.
+    offset: u64,
+    n_entries: u64,
+    max_len: u64,
+    /// Start of every `block_size`-th fragment.
+    block_starts: Vec<u64>,
+}
+
+#[derive(Debug, Default)]
+struct GroupIndex {
+    n_cells: usize,
+    n_insertions: u64,
+    chroms: HashMap<String, ChromIndex>,
+}
+
+/// Fragments of groups of cells, sorted by position on disk.
+pub struct FragmentIndex {
+    groups: IndexMap<String, GroupIndex>,
+    block_size: usize,
+    file: NamedTempFile,
+}
+
+impl FragmentIndex {
+    /// Index the fragments of every cell whose group is not `None`. Temporary
+    /// files are created in `temp_dir`, or the default temporary directory.
+    pub fn new<A: SnapData>(
+        data: &A,
+        groups: &[Option<&str>],
+        temp_dir: Option<&Path>,
+    ) -> Result<Self> {
+        ensure!(
+            groups.len() == data.n_obs(),
+            "the number of groups ({}) does not match the number of cells ({})",
+            groups.len(),
+            data.n_obs()
+        );
+        let mut group_names: IndexMap<String, GroupIndex> = IndexMap::new();
+        let group_ids: Vec<Option<usize>> = groups
+            .iter()
+            .map(|g| {
+                g.map(|g| {
+                    let entry = group_names.entry(g.to_string());
+                    let i = entry.index();
+                    entry.or_default().n_cells += 1;
+                    i
+                })
+            })
+            .collect();
+        let mut chrom_names: IndexSet<String> = IndexSet::new();
+        let mut n_insertions = vec![0u64; group_names.len()];
+
+        let fragments = data
+            .get_fragment_iter(data.fragment_chunk_size()?)?
+            .into_fragments()
+            .flat_map(|(list_of_fragments, start, _)| {
+                let mut entries = Vec::new();
+                list_of_fragments
+                    .into_iter()
+                    .enumerate()
+                    .for_each(|(i, fragments)| {
+                        let Some(group) = group_ids[start + i] else {
+                            return;
+                        };
+                        fragments.into_iter().for_each(|frag| {
+                            let insertions = match &frag {
+                                Fragment::Single(x) => match x.strand {
+                                    Strand::Forward => (true, false),
+                                    Strand::Reverse => (false, true),
+                                },
+                                Fragment::Paired(_) => (true, true),
+                            };
+                            let entry = Entry {
+                                start: frag.start(),
+                                end: frag.end(),
+                                count: frag.count(),
+                                insertions,
+                            };
+                            n_insertions[group] += entry.count as u64
+                                * (insertions.0 as u64 + insertions.1 as u64);
+                            let chrom = match chrom_names.get_index_of(frag.chrom()) {
+                                Some(i) => i,
+                                None => chrom_names.insert_full(frag.chrom().to_string()).0,
+                            };
+                            entries.push((group, chrom, entry));
+                        });
+                    });
+                entries
+            });
+
+        let mut sorter = ExternalSorterBuilder::new()
+            .with_chunk_size(crate::config::buffer_size::<(usize, usize, Entry)>(10000000))
+            .with_compression(2);
+        if let Some(tmp) = temp_dir {
+            sorter = sorter.with_tmp_dir(tmp);
+        }
+        let sorted = sorter.build()?.sort_by(fragments, |a, b| {
+            (a.0, a.1, a.2.start, a.2.end).cmp(&(b.0, b.1, b.2.start, b.2.end))
+        })?;
+        group_names
+            .values_mut()
+            .zip(n_insertions)
+            .for_each(|(g, n)| g.n_insertions = n);
+
+        let file = match temp_dir {
+            Some(dir) => NamedTempFile::new_in(dir)?,
+            None => NamedTempFile::new()?,
+        };
+        let sorted = sorted.map(|x| {
+            let (group, chrom, entry) = x?;
+            Ok::<_, anyhow::Error>((group, chrom_names[chrom].as_str(), entry))
+        });
+        Self::build(group_names, sorted, file, BLOCK_SIZE)
+    }
+
+    /// Write the fragments, sorted by group, chromosome and position, to
+    /// `file` and index them.
+    fn build<'a, I>(
+        mut groups: IndexMap<String, GroupIndex>,
+        sorted: I,
+        file: NamedTempFile,
+        block_size: usize,
+    ) -> Result<Self>
+    where
+        I: Iterator<Item = Result<(usize, &'a str, Entry)>>,
+    {
+        let mut writer = BufWriter::new(file.as_file());
+        let mut current: Option<(usize, &str)> = None;
+        let mut index: Option<ChromIndex> = None;
+        let mut n = 0u64;
+        for x in sorted {
+            let (group, chrom, entry) = x?;
+            if current != Some((group, chrom)) {
+                if let (Some((g, c)), Some(i)) = (current, index.take()) {
+                    groups[g].chroms.insert(c.to_string(), i);
+                }
+                current = Some((group, chrom));
+                index = Some(ChromIndex {
+                    offset: n,
+                    n_entries: 0,
+                    max_len: 0,
+                    block_starts: Vec::new(),
+                });
+            }
+            let i = index.as_mut().unwrap();
+            if i.n_entries % block_size as u64 == 0 {
+                i.block_starts.push(entry.start);
+            }
+            i.n_entries += 1;
+            i.max_len = i.max_len.max(entry.end - entry.start);
+            entry.write_to(&mut writer)?;
+            n += 1;
+        }
+        if let (Some((g, c)), Some(i)) = (current, index) {
+            groups[g].chroms.insert(c.to_string(), i);
+        }
+        writer.flush()?;
+        drop(writer);
+        Ok(Self { groups, block_size, file })
+    }
+
+    /// The groups, with their numbers of cells.
+    pub fn groups(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.groups.iter().map(|(k, v)| (k.as_str(), v.n_cells))
+    }
+
+    /// Compute the signal of `group` in bins of `bin_size` tiling `region`.
+    /// The last bin is truncated at the end of the region. If `normalize` is
+    /// true, insertion counts are scaled to counts per million insertions of
+    /// the group, and fragment counts are divided by the number of cells.
+    pub fn query(
+        &self,
+        group: &str,
+        region: &GenomicRange,
+        bin_size: u64,
+        track: TrackType,
+        normalize: bool,
+    ) -> Result<Vec<f64>> {
+        ensure!(bin_size > 0, "bin size must be positive");
+        ensure!(region.end() > region.start(), "the region is empty");
+        let Some(index) = self.groups.get(group) else {
+            bail!("unknown group: {}", group);
+        };
+        let (start, end) = (region.start(), region.end());
+        let n_bins = (end - start).div_ceil(bin_size) as usize;
+        let mut values = vec![0.0; n_bins];
+        let Some(chrom) = index.chroms.get(region.chrom()) else {
+            return Ok(values);
+        };
+        // The last block starting before the first fragment that may overlap the region.
+        let block = chrom
+            .block_starts
+            .partition_point(|x| x + chrom.max_len <= start)
+            .saturating_sub(1);
+        let first = (block * self.block_size) as u64;
+        let mut file = File::open(self.file.path())?;
+        file.seek(SeekFrom::Start((chrom.offset + first) * ENTRY_BYTES as u64))?;
+        let mut reader = BufReader::new(file);
+        for _ in first..chrom.n_entries {
+            let x = Entry::read_from(&mut reader)?;
+            if x.start >= end {
+                break;
+            }
+            if x.end <= start {
+                continue;
+            }
+            let count = x.count as f64;
+            match track {
+                TrackType::Insertion => {
+                    let sites = [(x.insertions.0, x.start), (x.insertions.1, x.end - 1)];
+                    for (is_site, pos) in sites {
+                        if is_site && pos >= start && pos < end {
+                            values[((pos - start) / bin_size) as usize] += count;
+                        }
+                    }
+                }
+                TrackType::Fragment => {
+                    let i = ((x.start.max(start) - start) / bin_size) as usize;
+                    let j = ((x.end.min(end) - 1 - start) / bin_size) as usize;
+                    values[i..=j].iter_mut().for_each(|v| *v += count);
+                }
+            }
+        }
+        if normalize {
+            let scale = match track {
+                TrackType::Insertion => 1e6 / index.n_insertions.max(1) as f64,
+                TrackType::Fragment => 1.0 / index.n_cells.max(1) as f64,
+            };
+            values.iter_mut().for_each(|v| *v *= scale);
+        }
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query() {
+        let entries = vec![
+            Entry { start: 5, end: 25, count: 1, insertions: (true, true) },
+            Entry { start: 12, end: 18, count: 2, insertions: (true, true) },
+            Entry { start: 30, end: 90, count: 1, insertions: (false, true) },
+            Entry { start: 200, end: 210, count: 1, insertions: (true, true) },
+        ];
+        let other = Entry { start: 0, end: 10, count: 5, insertions: (true, true) };
+        let sorted = entries
+            .into_iter()
+            .map(|x| (0, "chr1", x))
+            .chain([(1, "chr1", other)])
+            .map(Ok);
+        let groups = ["a", "c"]
+            .into_iter()
+            .map(|g| {
+                let group = GroupIndex { n_cells: 2, n_insertions: 9, chroms: HashMap::new() };
+                (g.to_string(), group)
+            })
+            .collect();
+        let file = NamedTempFile::new().unwrap();
+        let index = FragmentIndex::build(groups, sorted, file, 2).unwrap();
+        assert_eq!(index.groups["a"].chroms["chr1"].block_starts, vec![5, 30]);
+
+        let region = GenomicRange::new("chr1", 10, 45);
+        let ins = index.query("a", &region, 10, TrackType::Insertion, false).unwrap();
+        assert_eq!(ins, vec![4.0, 1.0, 0.0, 0.0]);
+        let frag = index.query("a", &region, 10, TrackType::Fragment, false).unwrap();
+        assert_eq!(frag, vec![3.0, 1.0, 1.0, 1.0]);
+        let frag = index.query("a", &region, 10, TrackType::Fragment, true).unwrap();
+        assert_eq!(frag, vec![1.5, 0.5, 0.5, 0.5]);
+
+        let ins = index
+            .query("a", &GenomicRange::new("chr1", 80, 100), 10, TrackType::Insertion, false)
+            .unwrap();
+        assert_eq!(ins, vec![1.0, 0.0]);
+        let ins = index
+            .query("a", &GenomicRange::new("chr1", 195, 215), 10, TrackType::Insertion, false)
+            .unwrap();
+        assert_eq!(ins, vec![1.0, 1.0]);
+        let ins = index
+            .query("c", &GenomicRange::new("chr1", 0, 10), 10, TrackType::Insertion, false)
+            .unwrap();
+        assert_eq!(ins, vec![10.0]);
+        let other = GenomicRange::new("chr2", 0, 10);
+        assert_eq!(index.query("a", &other, 5, TrackType::Fragment, false).unwrap(), vec![0.0, 0.0]);
+        assert!(index.query("b", &region, 10, TrackType::Fragment, false).is_err());
+    }
+}
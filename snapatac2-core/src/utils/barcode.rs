@@ -0,0 +1,108 @@
+//! Renaming of cell barcodes on output.
+//!
+//! Cell names stored in `.obs_names` often differ from the barcodes expected
+//! by downstream tools, e.g., Cell Ranger appends a "-1" suffix and merged
+//! datasets prepend sample names. [`BarcodeMap`] computes the exported name
+//! of every cell without touching the stored data, and refuses mappings that
+//! would give two cells the same name.
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub struct BarcodeMap {
+    strip_suffix: Option<Regex>,
+    separator: String,
+}
+
+impl Default for BarcodeMap {
+    fn default() -> Self {
+        Self {
+            strip_suffix: None,
+            separator: "_".to_string(),
+        }
+    }
+}
+
+impl BarcodeMap {
+    /// Remove the suffix matching `pattern`, a regular expression anchored at
+    /// the end of the barcode, e.g., "-1" or "-\d+".
+    pub fn strip_suffix(mut self, pattern: &str) -> Result<Self> {
+        let re = Regex::new(&format!("(?:{})$", pattern))
+            .with_context(|| format!("invalid suffix pattern: {}", pattern))?;
+        self.strip_suffix = Some(re);
+        Ok(self)
+    }
+
+    /// Separator between the sample prefix and the barcode.
+    pub fn with_separator(mut self, separator: &str) -> Self {
+        self.separator = separator.to_string();
+        self
+    }
+
+    pub fn map(&self, barcode: &str, prefix: Option<&str>) -> String {
+        let barcode = match &self.strip_suffix {
+            Some(re) => re.replace(barcode, ""),
+            None => barcode.into(),
+        };
+        match prefix {
+            Some(p) => format!("{}{}{}", p, self.separator, barcode),
+            None => barcode.into_owned(),
+        }
+    }
+
+    /// Map the barcodes of all cells, `prefixes` giving the sample prefix of
+    /// each cell. Fails if two cells are mapped to the same name.
+    pub fn map_all(&self, barcodes: &[&str], prefixes: Option<&[&str]>) -> Result<Vec<String>> {
+        if let Some(p) = prefixes {
+            if p.len() != barcodes.len() {
+                bail!(
+                    "the number of prefixes ({}) does not match the number of barcodes ({})",
+                    p.len(),
+                    barcodes.len()
+                );
+            }
+        }
+        let mapped: Vec<_> = barcodes
+            .iter()
+            .enumerate()
+            .map(|(i, bc)| self.map(bc, prefixes.map(|p| p[i])))
+            .collect();
+        let mut seen: HashMap<&str, usize> = HashMap::with_capacity(mapped.len());
+        for (i, name) in mapped.iter().enumerate() {
+            if let Some(j) = seen.insert(name, i) {
+                bail!(
+                    "cells {} ('{}') and {} ('{}') are both renamed to '{}'",
+                    j,
+                    barcodes[j],
+                    i,
+                    barcodes[i],
+                    name
+                );
+            }
+        }
+        Ok(mapped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_barcode_map() {
+        let map = BarcodeMap::default().strip_suffix("-1").unwrap();
+        assert_eq!(map.map("AAAC-1", None), "AAAC");
+        assert_eq!(map.map("AAAC-10", None), "AAAC-10");
+        assert_eq!(map.map("AAAC-1", Some("s1")), "s1_AAAC");
+
+        let map = BarcodeMap::default().strip_suffix(r"-\d+").unwrap().with_separator("#");
+        assert_eq!(
+            map.map_all(&["A-1", "A-2"], Some(&["s1", "s2"])).unwrap(),
+            vec!["s1#A", "s2#A"]
+        );
+        assert!(map.map_all(&["A-1", "A-2"], None).is_err());
+        assert!(map.map_all(&["A-1"], Some(&["s1", "s2"])).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+//! Checkpoints of long-running operations.
+//!
+//! A checkpoint is a directory holding the intermediate results of an
+//! operation, one file per completed stage, together with a manifest that
+//! records the parameters (the "fingerprint") the results were computed with.
+//! Opening a checkpoint whose fingerprint differs from the current one
+//! discards the stale results, so an operation never resumes from the state
+//! of a different run. Files are written to a temporary location first and
+//! renamed into place, so a crash during a write never leaves a truncated
+//! stage behind.
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "manifest.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    fingerprint: String,
+    completed: BTreeSet<String>,
+}
+
+#[derive(Debug)]
+pub struct Checkpoint {
+    dir: PathBuf,
+    manifest: Manifest,
+}
+
+impl Checkpoint {
+    /// Open the checkpoint stored in `dir`, creating the directory if needed.
+    /// Results computed with a different `fingerprint` are removed.
+    pub fn open<P: AsRef<Path>>(dir: P, fingerprint: &str) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("cannot create checkpoint directory: {}", dir.display()))?;
+        let manifest_file = dir.join(MANIFEST);
+        let manifest = if manifest_file.exists() {
+            serde_json::from_slice::<Manifest>(&fs::read(&manifest_file)?)
+                .with_context(|| format!("invalid checkpoint manifest: {}", manifest_file.display()))?
+        } else {
+            Manifest::default()
+        };
+
+        let mut checkpoint = Self { dir, manifest };
+        if checkpoint.manifest.fingerprint != fingerprint {
+            if !checkpoint.manifest.completed.is_empty() {
+                log::warn!(
+                    "Parameters differ from those of the checkpoint in {}, starting over",
+                    checkpoint.dir.display()
+                );
+            }
+            for stage in std::mem::take(&mut checkpoint.manifest.completed) {
+                let _ = fs::remove_file(checkpoint.path(&stage));
+            }
+            checkpoint.manifest.fingerprint = fingerprint.to_string();
+            checkpoint.write_manifest()?;
+        } else if !checkpoint.manifest.completed.is_empty() {
+            log::info!(
+                "Resuming from the checkpoint in {} ({} completed stages)",
+                checkpoint.dir.display(),
+                checkpoint.manifest.completed.len()
+            );
+        }
+        Ok(checkpoint)
+    }
+
+    pub fn is_completed(&self, stage: &str) -> bool {
+        self.manifest.completed.contains(stage)
+    }
+
+    /// The completed stages, in lexicographic order.
+    pub fn completed(&self) -> impl Iterator<Item = &str> {
+        self.manifest.completed.iter().map(|x| x.as_str())
+    }
+
+    /// Location of the file holding the result of `stage`. Callers producing
+    /// files themselves (e.g., through an external sorter) should write to
+    /// this path and call [`Checkpoint::mark_completed`] afterwards.
+    pub fn path(&self, stage: &str) -> PathBuf {
+        self.dir.join(stage)
+    }
+
+    pub fn mark_completed(&mut self, stage: &str) -> Result<()> {
+        self.manifest.completed.insert(stage.to_string());
+        self.write_manifest()
+    }
+
+    /// Load the result of a completed stage, stored as JSON.
+    pub fn load<T: DeserializeOwned>(&self, stage: &str) -> Result<Option<T>> {
+        self.read(stage)?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .with_context(|| format!("invalid checkpoint stage: {}", stage))
+            })
+            .transpose()
+    }
+
+    /// Save the result of a stage as JSON and mark the stage as completed.
+    pub fn save<T: Serialize>(&mut self, stage: &str, value: &T) -> Result<()> {
+        write_atomic(&self.path(stage), &serde_json::to_vec(value)?)?;
+        self.mark_completed(stage)
+    }
+
+    /// Like [`Checkpoint::load`], for large values stored in binary format.
+    pub fn load_binary<T: bitcode::DecodeOwned>(&self, stage: &str) -> Result<Option<T>> {
+        self.read(stage)?
+            .map(|bytes| {
+                bitcode::decode(&bytes).with_context(|| format!("invalid checkpoint stage: {}", stage))
+            })
+            .transpose()
+    }
+
+    /// Like [`Checkpoint::save`], for large values stored in binary format.
+    pub fn save_binary<T: bitcode::Encode + ?Sized>(&mut self, stage: &str, value: &T) -> Result<()> {
+        write_atomic(&self.path(stage), &bitcode::encode(value))?;
+        self.mark_completed(stage)
+    }
+
+    /// Remove the checkpoint once the operation has finished.
+    pub fn finish(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)
+            .with_context(|| format!("cannot remove checkpoint directory: {}", self.dir.display()))
+    }
+
+    fn read(&self, stage: &str) -> Result<Option<Vec<u8>>> {
+        if self.is_completed(stage) {
+            Ok(Some(fs::read(self.path(stage))?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn write_manifest(&self) -> Result<()> {
+        write_atomic(&self.dir.join(MANIFEST), &serde_json::to_vec_pretty(&self.manifest)?)
+    }
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)
+        .with_context(|| format!("cannot write checkpoint file: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ckpt");
+
+        let mut ckpt = Checkpoint::open(&path, "a").unwrap();
+        assert!(ckpt.load::<Vec<u64>>("counts").unwrap().is_none());
+        ckpt.save("counts", &vec![1u64, 2, 3]).unwrap();
+        ckpt.save_binary("matrix", &vec![0.5f64; 4]).unwrap();
+
+        let ckpt = Checkpoint::open(&path, "a").unwrap();
+        assert_eq!(ckpt.load::<Vec<u64>>("counts").unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(ckpt.load_binary::<Vec<f64>>("matrix").unwrap(), Some(vec![0.5; 4]));
+
+        // A different fingerprint discards the previous results.
+        let ckpt = Checkpoint::open(&path, "b").unwrap();
+        assert!(!ckpt.is_completed("counts"));
+        assert!(!ckpt.path("matrix").exists());
+        ckpt.finish().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -1,9 +1,12 @@
+use anyhow::{anyhow, ensure, Context, Result};
 use nalgebra_sparse::CsrMatrix;
-use ndarray::{AsArray, Ix2};
+use ndarray::{s, ArrayView2, AsArray, Ix2};
 use kdtree::kdtree::KdTree;
 use smallvec::SmallVec;
 use rayon::{iter::{IntoParallelIterator, IndexedParallelIterator}, prelude::ParallelIterator};
-use hora::core::ann_index::ANNIndex;
+use super::matmul::MatmulBackend;
+use hora::core::ann_index::{ANNIndex, SerializableIndex};
+use hora::index::{hnsw_idx::HNSWIndex, hnsw_params::HNSWParams};
 use kdtree::distance::squared_euclidean;
 
 pub fn nearest_neighbour_graph<'a, A>(
@@ -20,15 +23,66 @@ where
         kdtree.add(point.into_iter().cloned().collect::<SmallVec<[f64;64]>>(), i).unwrap();
     });
 
-    let result = points.outer_iter().into_par_iter().enumerate().map(|(i, point)| {
-        let point = point.into_iter().cloned().collect::<SmallVec<[f64;64]>>();
-        kdtree.iter_nearest(point.as_slice(), &squared_euclidean)
-            .unwrap()
-            .filter_map(|(distance, index)| if *index == i { None } else { Some((*index, distance.sqrt())) })
-            .take(k)
-            .collect()
-    }).collect::<Vec<_>>();
-    to_csr_matrix(result)
+    let result = crate::config::install(None, || {
+        points.outer_iter().into_par_iter().enumerate().map(|(i, point)| {
+            let point = point.into_iter().cloned().collect::<SmallVec<[f64;64]>>();
+            kdtree.iter_nearest(point.as_slice(), &squared_euclidean)
+                .unwrap()
+                .filter_map(|(distance, index)| if *index == i { None } else { Some((*index, distance.sqrt())) })
+                .take(k)
+                .collect()
+        }).collect::<Vec<_>>()
+    });
+    to_csr_matrix(result, points.nrows())
+}
+
+/// Exact k-nearest neighbor graph using brute-force search.
+///
+/// Points are processed in blocks of `block_size` rows, and the squared
+/// Euclidean distances between two blocks are computed with a single matrix
+/// product by `backend`, so the memory usage is `O(block_size^2)`. Query
+/// blocks are processed in parallel.
+pub fn exact_nearest_neighbour_graph<B: MatmulBackend + ?Sized>(
+    points: ArrayView2<'_, f64>,
+    k: usize,
+    block_size: usize,
+    backend: &B,
+) -> Result<CsrMatrix<f64>> {
+    let n = points.nrows();
+    let block_size = block_size.max(1);
+    let norms: Vec<f64> = points.outer_iter().map(|x| x.dot(&x)).collect();
+    let blocks: Vec<usize> = (0..n).step_by(block_size).collect();
+    let result = crate::config::install(None, || {
+        blocks.into_par_iter().map(|start| {
+            let end = (start + block_size).min(n);
+            let queries = points.slice(s![start..end, ..]);
+            let mut neighbors: Vec<Vec<(f64, usize)>> = vec![Vec::with_capacity(k + 1); end - start];
+            for ref_start in (0..n).step_by(block_size) {
+                let ref_end = (ref_start + block_size).min(n);
+                let dot = backend.matmul_t(queries, points.slice(s![ref_start..ref_end, ..]))?;
+                dot.outer_iter().zip(neighbors.iter_mut()).enumerate().for_each(|(i, (row, nn))| {
+                    let i = start + i;
+                    row.iter().enumerate().for_each(|(j, x)| {
+                        let j = ref_start + j;
+                        if i == j {
+                            return;
+                        }
+                        let d = (norms[i] + norms[j] - 2.0 * x).max(0.0);
+                        if nn.len() < k || d < nn[nn.len() - 1].0 {
+                            let pos = nn.partition_point(|(x, _)| *x <= d);
+                            nn.insert(pos, (d, j));
+                            nn.truncate(k);
+                        }
+                    });
+                });
+            }
+            Ok(neighbors
+                .into_iter()
+                .map(|nn| nn.into_iter().map(|(d, j)| (j, d.sqrt())).collect::<Vec<_>>())
+                .collect::<Vec<_>>())
+        }).collect::<Result<Vec<_>>>()
+    })?;
+    Ok(to_csr_matrix(result.into_iter().flatten(), n))
 }
 
 pub fn approximate_nearest_neighbour_graph<'a, A>(
@@ -39,23 +93,127 @@ where
     A: AsArray<'a, f32, Ix2>
 {
     let points = points.into();
-    let shape = points.shape();
-    let mut index = hora::index::hnsw_idx::HNSWIndex::<f32, usize>::new(
-        shape[1],
-        &hora::index::hnsw_params::HNSWParams::<f32>::default().max_item(shape[0].max(1000000)),
-    );
-    for (i, sample) in points.outer_iter().enumerate() {
-        index.add(sample.to_vec().as_slice(), i).unwrap();
+    AnnIndex::new(points).unwrap().search(points, k)
+}
+
+/// A HNSW index for approximate nearest neighbor search using Euclidean
+/// distances. Items are identified by their insertion order.
+///
+/// The index can be serialized to bytes, so that it can be stored alongside
+/// the data (e.g., in `.uns`) and reused or extended later without being rebuilt.
+/// The maximum number of items of the HNSW graph is fixed when the index is
+/// built, see [`AnnIndex::capacity`].
+pub struct AnnIndex {
+    index: HNSWIndex<f32, usize>,
+    dimension: usize,
+    n_items: usize,
+    capacity: usize,
+}
+
+impl AnnIndex {
+    /// Build an index of `points`, with room for twice as many items.
+    pub fn new(points: ArrayView2<'_, f32>) -> Result<Self> {
+        Self::with_capacity(points, (2 * points.nrows()).max(1000000))
+    }
+
+    /// Build an index of `points` holding at most `capacity` items.
+    pub fn with_capacity(points: ArrayView2<'_, f32>, capacity: usize) -> Result<Self> {
+        let dimension = points.ncols();
+        let index = HNSWIndex::<f32, usize>::new(
+            dimension,
+            &HNSWParams::<f32>::default().max_item(capacity),
+        );
+        let mut index = Self { index, dimension, n_items: 0, capacity };
+        index.add(points)?;
+        Ok(index)
+    }
+
+    /// Number of items in the index.
+    pub fn len(&self) -> usize {
+        self.n_items
+    }
+
+    /// Maximum number of items in the index. An index must be rebuilt to
+    /// hold more items.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n_items == 0
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    /// Append new items to the index. Their ids continue from the current
+    /// number of items.
+    pub fn add(&mut self, points: ArrayView2<'_, f32>) -> Result<()> {
+        ensure!(
+            points.ncols() == self.dimension,
+            "expecting points of dimension {}, but got {}",
+            self.dimension,
+            points.ncols(),
+        );
+        ensure!(
+            self.n_items + points.nrows() <= self.capacity,
+            "the index holds at most {} items, cannot add {} items to the {} items",
+            self.capacity,
+            points.nrows(),
+            self.n_items,
+        );
+        for (i, sample) in points.outer_iter().enumerate() {
+            self.index.add(sample.to_vec().as_slice(), self.n_items + i).map_err(|e| anyhow!(e))?;
+        }
+        self.index.build(hora::core::metrics::Metric::Euclidean).map_err(|e| anyhow!(e))?;
+        self.n_items += points.nrows();
+        Ok(())
+    }
+
+    /// Search the `k` nearest neighbors of every query point. The result is a
+    /// sparse matrix of shape `n_queries` x `n_items` storing the distances.
+    pub fn search(&self, queries: ArrayView2<'_, f32>, k: usize) -> CsrMatrix<f32> {
+        let result = crate::config::install(None, || {
+            queries.outer_iter().into_par_iter().map(|row| {
+                self.index.search_nodes(row.to_vec().as_slice(), k).into_iter()
+                    .map(|(n, d)| (n.idx().unwrap(), d)).collect::<Vec<_>>()
+            }).collect::<Vec<_>>()
+        });
+        to_csr_matrix(result, self.n_items)
+    }
+
+    /// Serialize the index. The first 24 bytes store the dimension, the
+    /// number of items and the capacity, followed by the HNSW graph.
+    pub fn to_bytes(&mut self) -> Result<Vec<u8>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("index");
+        let path = path.to_str().unwrap();
+        self.index.dump(path).map_err(|e| anyhow!(e))?;
+        let mut bytes = Vec::new();
+        bytes.extend((self.dimension as u64).to_le_bytes());
+        bytes.extend((self.n_items as u64).to_le_bytes());
+        bytes.extend((self.capacity as u64).to_le_bytes());
+        bytes.extend(std::fs::read(path)?);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        ensure!(bytes.len() > 24, "invalid index: too few bytes");
+        let dimension = u64::from_le_bytes(bytes[0..8].try_into()?) as usize;
+        let n_items = u64::from_le_bytes(bytes[8..16].try_into()?) as usize;
+        let capacity = u64::from_le_bytes(bytes[16..24].try_into()?) as usize;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("index");
+        std::fs::write(&path, &bytes[24..])?;
+        let index = HNSWIndex::load(path.to_str().unwrap())
+            .map_err(|e| anyhow!(e))
+            .context("failed to load the HNSW index")?;
+        Ok(Self { index, dimension, n_items, capacity })
     }
-    index.build(hora::core::metrics::Metric::Euclidean).unwrap();
-    let result = points.outer_iter().into_par_iter().map(|row| {
-        index.search_nodes(row.to_vec().as_slice(), k).into_iter()
-            .map(|(n, d)| (n.idx().unwrap(), d)).collect::<Vec<_>>()
-    }).collect::<Vec<_>>();
-    to_csr_matrix(result)
 }
 
-fn to_csr_matrix<I, D>(iter: I) -> CsrMatrix<D>
+fn to_csr_matrix<I, D>(iter: I, ncols: usize) -> CsrMatrix<D>
 where
     I: IntoIterator<Item = Vec<(usize, D)>>,
 {
@@ -74,6 +232,58 @@ where
     });
     indptr.push(n.try_into().unwrap());
     CsrMatrix::try_from_csr_data(
-        indptr.len() - 1, indptr.len() - 1, indptr, indices, data
+        indptr.len() - 1, ncols, indptr, indices, data
     ).unwrap()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::matmul::CpuBackend;
+    use ndarray::Array2;
+
+    #[test]
+    fn test_ann_index_persistence() {
+        let points = Array2::from_shape_fn((200, 3), |(i, j)| (i * (j + 1)) as f32);
+        let (head, tail) = points.view().split_at(ndarray::Axis(0), 150);
+        let mut index = AnnIndex::new(head).unwrap();
+        let bytes = index.to_bytes().unwrap();
+
+        let mut index = AnnIndex::from_bytes(&bytes).unwrap();
+        assert_eq!(index.len(), 150);
+        assert_eq!(index.capacity(), 1000000);
+        index.add(tail).unwrap();
+        assert_eq!(index.len(), 200);
+
+        let graph = index.search(points.view(), 1);
+        assert_eq!(graph.ncols(), 200);
+        graph.row_iter().enumerate().for_each(|(i, row)| assert_eq!(row.col_indices(), &[i]));
+    }
+
+    #[test]
+    fn test_ann_index_capacity() {
+        let points = Array2::from_shape_fn((20, 3), |(i, j)| (i * (j + 1)) as f32);
+        let (head, tail) = points.view().split_at(ndarray::Axis(0), 15);
+        let mut index = AnnIndex::with_capacity(head, 16).unwrap();
+        assert!(index.add(tail).is_err());
+        assert_eq!(index.len(), 15);
+        assert!(AnnIndex::with_capacity(points.view(), 16).is_err());
+    }
+
+    #[test]
+    fn test_exact_knn() {
+        let points = Array2::from_shape_fn((50, 4), |(i, j)| ((i * 7 + j * 13) % 17) as f64 + i as f64 * 0.01);
+        let expected = nearest_neighbour_graph(points.view(), 5);
+        for block_size in [1, 7, 100] {
+            let graph = exact_nearest_neighbour_graph(points.view(), 5, block_size, &CpuBackend).unwrap();
+            assert_eq!(graph.row_offsets(), expected.row_offsets());
+            graph.row_iter().zip(expected.row_iter()).for_each(|(a, b)| {
+                let mut a = a.values().to_vec();
+                let mut b = b.values().to_vec();
+                a.sort_by(|x, y| x.partial_cmp(y).unwrap());
+                b.sort_by(|x, y| x.partial_cmp(y).unwrap());
+                a.iter().zip(b.iter()).for_each(|(x, y)| assert!((x - y).abs() < 1e-6));
+            });
+        }
+    }
+}
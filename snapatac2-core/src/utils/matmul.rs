@@ -0,0 +1,279 @@
+//! Dense matrix products of the form `a * b^T`, as used by the exact nearest
+//! neighbor search and the randomized PCA. They run on the CPU by default,
+//! or on a GPU selected with [`Device`] when the crate is built with the
+//! `cuda` (NVIDIA GPUs, through cuBLAS) or `metal` (Apple GPUs) feature.
+
+use anyhow::{bail, ensure, Result};
+use ndarray::{Array2, ArrayView2};
+
+/// Backend computing dense matrix products.
+pub trait MatmulBackend: Sync {
+    /// Compute `a * b^T`.
+    fn matmul_t(&self, a: ArrayView2<'_, f64>, b: ArrayView2<'_, f64>) -> Result<Array2<f64>>;
+}
+
+/// Multi-threaded CPU backend.
+pub struct CpuBackend;
+
+impl MatmulBackend for CpuBackend {
+    fn matmul_t(&self, a: ArrayView2<'_, f64>, b: ArrayView2<'_, f64>) -> Result<Array2<f64>> {
+        check_shapes(&a, &b)?;
+        Ok(a.dot(&b.t()))
+    }
+}
+
+/// The device computing the matrix products.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Device {
+    #[default]
+    Cpu,
+    /// The first CUDA device, requires the `cuda` feature.
+    Cuda,
+    /// The default Metal device, requires the `metal` feature. The products
+    /// are computed in single precision, as Metal has no double precision.
+    Metal,
+}
+
+impl TryFrom<&str> for Device {
+    type Error = anyhow::Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "cpu" => Ok(Device::Cpu),
+            "cuda" | "gpu" => Ok(Device::Cuda),
+            "metal" | "mps" => Ok(Device::Metal),
+            _ => bail!("unknown device: {}, must be one of 'cpu', 'cuda' or 'metal'", s),
+        }
+    }
+}
+
+impl std::fmt::Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Device::Cpu => write!(f, "cpu"),
+            Device::Cuda => write!(f, "cuda"),
+            Device::Metal => write!(f, "metal"),
+        }
+    }
+}
+
+impl Device {
+    /// Initialize the backend of the device. An error is returned if the
+    /// crate is built without the feature of the device, or if the device
+    /// is not available.
+    pub fn backend(self) -> Result<Box<dyn MatmulBackend>> {
+        match self {
+            Device::Cpu => Ok(Box::new(CpuBackend)),
+            #[cfg(feature = "cuda")]
+            Device::Cuda => Ok(Box::new(cuda_backend::CudaBackend::new(0)?)),
+            #[cfg(feature = "metal")]
+            Device::Metal => Ok(Box::new(metal_backend::MetalBackend::new()?)),
+            #[allow(unreachable_patterns)]
+            _ => bail!(
+                "the '{}' device is not supported by this build, please rebuild with the '{}' feature",
+                self,
+                self
+            ),
+        }
+    }
+}
+
+fn check_shapes(a: &ArrayView2<'_, f64>, b: &ArrayView2<'_, f64>) -> Result<()> {
+    ensure!(
+        a.ncols() == b.ncols(),
+        "cannot multiply matrices of shapes {:?} and {:?}^T",
+        a.shape(),
+        b.shape()
+    );
+    Ok(())
+}
+
+#[cfg(feature = "cuda")]
+pub mod cuda_backend {
+    use super::{check_shapes, MatmulBackend};
+    use anyhow::{Context, Result};
+    use cudarc::cublas::{sys::cublasOperation_t, CudaBlas, Gemm, GemmConfig};
+    use cudarc::driver::CudaDevice;
+    use ndarray::{Array2, ArrayView2};
+    use std::sync::{Arc, Mutex};
+
+    /// Matrix products on a CUDA device, computed by cuBLAS in double precision.
+    pub struct CudaBackend {
+        device: Arc<CudaDevice>,
+        /// cuBLAS handles must not be used by several threads at once.
+        blas: Mutex<CudaBlas>,
+    }
+
+    impl CudaBackend {
+        pub fn new(ordinal: usize) -> Result<Self> {
+            let device = CudaDevice::new(ordinal)
+                .with_context(|| format!("cannot open CUDA device {}", ordinal))?;
+            let blas = CudaBlas::new(device.clone()).context("cannot initialize cuBLAS")?;
+            Ok(Self {
+                device,
+                blas: Mutex::new(blas),
+            })
+        }
+    }
+
+    impl MatmulBackend for CudaBackend {
+        fn matmul_t(&self, a: ArrayView2<'_, f64>, b: ArrayView2<'_, f64>) -> Result<Array2<f64>> {
+            check_shapes(&a, &b)?;
+            let (m, n, k) = (a.nrows(), b.nrows(), a.ncols());
+            if m == 0 || n == 0 || k == 0 {
+                return Ok(Array2::zeros((m, n)));
+            }
+            let a = a.as_standard_layout();
+            let b = b.as_standard_layout();
+            let blas = self.blas.lock().unwrap();
+            let a_dev = self.device.htod_sync_copy(a.as_slice().unwrap())?;
+            let b_dev = self.device.htod_sync_copy(b.as_slice().unwrap())?;
+            let mut c_dev = self.device.alloc_zeros::<f64>(m * n)?;
+            // cuBLAS uses the column-major order, in which the row-major
+            // matrices are transposed: `c^T = b * a^T`, where `b` is the
+            // transpose of the column-major `b^T` and `a^T` is taken as is.
+            let config = GemmConfig {
+                transa: cublasOperation_t::CUBLAS_OP_T,
+                transb: cublasOperation_t::CUBLAS_OP_N,
+                m: n as i32,
+                n: m as i32,
+                k: k as i32,
+                alpha: 1.0,
+                lda: k as i32,
+                ldb: k as i32,
+                beta: 0.0,
+                ldc: n as i32,
+            };
+            unsafe { blas.gemm(config, &b_dev, &a_dev, &mut c_dev)? };
+            let c = self.device.dtoh_sync_copy(&c_dev)?;
+            Ok(Array2::from_shape_vec((m, n), c)?)
+        }
+    }
+}
+
+#[cfg(feature = "metal")]
+pub mod metal_backend {
+    use super::{check_shapes, MatmulBackend};
+    use anyhow::{Context, Result};
+    use metal::{
+        CommandQueue, CompileOptions, ComputePipelineState, MTLResourceOptions, MTLSize,
+    };
+    use ndarray::{Array2, ArrayView2};
+    use std::ffi::c_void;
+    use std::sync::Mutex;
+
+    const KERNEL: &str = r#"
+#include <metal_stdlib>
+using namespace metal;
+
+kernel void matmul_t(
+    device const float *a [[buffer(0)]],
+    device const float *b [[buffer(1)]],
+    device float *c [[buffer(2)]],
+    constant uint4 &dims [[buffer(3)]],
+    uint2 gid [[thread_position_in_grid]]
+) {
+    uint m = dims.x, n = dims.y, k = dims.z;
+    if (gid.y >= m || gid.x >= n) {
+        return;
+    }
+    float sum = 0.0;
+    for (uint l = 0; l < k; l++) {
+        sum += a[gid.y * k + l] * b[gid.x * k + l];
+    }
+    c[gid.y * n + gid.x] = sum;
+}
+"#;
+
+    /// Matrix products on the default Metal device, in single precision.
+    pub struct MetalBackend {
+        /// Command queues must not be used by several threads at once.
+        inner: Mutex<(metal::Device, CommandQueue, ComputePipelineState)>,
+    }
+
+    impl MetalBackend {
+        pub fn new() -> Result<Self> {
+            let device = metal::Device::system_default().context("no Metal device is found")?;
+            let library = device
+                .new_library_with_source(KERNEL, &CompileOptions::new())
+                .map_err(anyhow::Error::msg)?;
+            let function = library.get_function("matmul_t", None).map_err(anyhow::Error::msg)?;
+            let pipeline = device
+                .new_compute_pipeline_state_with_function(&function)
+                .map_err(anyhow::Error::msg)?;
+            let queue = device.new_command_queue();
+            Ok(Self {
+                inner: Mutex::new((device, queue, pipeline)),
+            })
+        }
+    }
+
+    impl MatmulBackend for MetalBackend {
+        fn matmul_t(&self, a: ArrayView2<'_, f64>, b: ArrayView2<'_, f64>) -> Result<Array2<f64>> {
+            check_shapes(&a, &b)?;
+            let (m, n, k) = (a.nrows(), b.nrows(), a.ncols());
+            if m == 0 || n == 0 || k == 0 {
+                return Ok(Array2::zeros((m, n)));
+            }
+            let a: Vec<f32> = a.iter().map(|x| *x as f32).collect();
+            let b: Vec<f32> = b.iter().map(|x| *x as f32).collect();
+            let dims = [m as u32, n as u32, k as u32, 0];
+            let (device, queue, pipeline) = &*self.inner.lock().unwrap();
+            objc::rc::autoreleasepool(|| {
+                let buffer = |x: &[f32]| {
+                    device.new_buffer_with_data(
+                        x.as_ptr() as *const c_void,
+                        std::mem::size_of_val(x) as u64,
+                        MTLResourceOptions::StorageModeShared,
+                    )
+                };
+                let (a, b) = (buffer(&a), buffer(&b));
+                let c = device.new_buffer(
+                    (m * n * std::mem::size_of::<f32>()) as u64,
+                    MTLResourceOptions::StorageModeShared,
+                );
+                let command = queue.new_command_buffer();
+                let encoder = command.new_compute_command_encoder();
+                encoder.set_compute_pipeline_state(pipeline);
+                encoder.set_buffer(0, Some(&a), 0);
+                encoder.set_buffer(1, Some(&b), 0);
+                encoder.set_buffer(2, Some(&c), 0);
+                encoder.set_bytes(
+                    3,
+                    std::mem::size_of_val(&dims) as u64,
+                    dims.as_ptr() as *const c_void,
+                );
+                encoder.dispatch_threads(MTLSize::new(n as u64, m as u64, 1), MTLSize::new(16, 16, 1));
+                encoder.end_encoding();
+                command.commit();
+                command.wait_until_completed();
+                let c = unsafe { std::slice::from_raw_parts(c.contents() as *const f32, m * n) };
+                Ok(Array2::from_shape_vec((m, n), c.iter().map(|x| *x as f64).collect())?)
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_backend() {
+        let a = Array2::from_shape_fn((3, 4), |(i, j)| (i * 4 + j) as f64);
+        let b = Array2::from_shape_fn((2, 4), |(i, j)| (i as f64 - j as f64).abs());
+        let c = CpuBackend.matmul_t(a.view(), b.view()).unwrap();
+        assert_eq!(c, a.dot(&b.t()));
+        // Views with any memory layout are accepted.
+        let at = a.t().to_owned();
+        let c = CpuBackend.matmul_t(at.t(), b.view()).unwrap();
+        assert_eq!(c, a.dot(&b.t()));
+        assert!(CpuBackend.matmul_t(a.view(), a.t()).is_err());
+
+        assert_eq!(Device::try_from("CUDA").unwrap(), Device::Cuda);
+        assert!(Device::try_from("tpu").is_err());
+        assert!(Device::Cpu.backend().is_ok());
+        #[cfg(not(feature = "cuda"))]
+        assert!(Device::Cuda.backend().is_err());
+    }
+}
@@ -0,0 +1,200 @@
+//! Memory usage of the current process.
+//!
+//! [`MemoryTracker`] samples the resident set size (RSS) in a background
+//! thread to find the peak memory used by an operation, i.e., the peak RSS
+//! above the RSS at the start of the operation, and [`AdaptiveChunks`]
+//! shrinks the chunks of a chunked computation when the RSS exceeds the
+//! memory budget set by [`crate::config::set_memory_limit`].
+//! The RSS is read from `/proc`, so it is only available on Linux.
+
+use log::{debug, warn};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::config;
+
+const SAMPLING_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Resident set size of the current process in bytes, if available.
+pub fn current_rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|x| x.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+/// Memory available for starting new applications in bytes, if available.
+pub fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|x| x.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+/// Track the peak RSS from the time the tracker is created until
+/// [`MemoryTracker::finish`] is called. The memory used by the operation is
+/// the peak RSS minus the RSS when the tracker was created, so that the memory
+/// held by earlier operations is not counted.
+pub struct MemoryTracker {
+    baseline: u64,
+    peak: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MemoryTracker {
+    pub fn start() -> Self {
+        let baseline = current_rss().unwrap_or(0);
+        let peak = Arc::new(AtomicU64::new(baseline));
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = if baseline > 0 {
+            let (peak, stop) = (peak.clone(), stop.clone());
+            std::thread::Builder::new()
+                .name("memory-tracker".to_string())
+                .spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        if let Some(rss) = current_rss() {
+                            peak.fetch_max(rss, Ordering::Relaxed);
+                        }
+                        std::thread::sleep(SAMPLING_INTERVAL);
+                    }
+                })
+                .ok()
+        } else {
+            None
+        };
+        Self {
+            baseline,
+            peak,
+            stop,
+            handle,
+        }
+    }
+
+    /// Peak memory in bytes used since the tracker was created, if available.
+    pub fn peak(&self) -> Option<u64> {
+        if self.baseline == 0 {
+            return None;
+        }
+        if let Some(rss) = current_rss() {
+            self.peak.fetch_max(rss, Ordering::Relaxed);
+        }
+        Some(self.peak.load(Ordering::Relaxed) - self.baseline)
+    }
+
+    /// Stop tracking and return the peak memory in bytes used since the
+    /// tracker was created, if available.
+    pub fn finish(mut self) -> Option<u64> {
+        let peak = self.peak();
+        self.stop_sampling();
+        peak
+    }
+
+    fn stop_sampling(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for MemoryTracker {
+    fn drop(&mut self) {
+        self.stop_sampling();
+    }
+}
+
+/// Split an iterator into chunks of at most `chunk_size` items. When a memory
+/// budget is set and the RSS exceeds it, the chunk size is halved before the
+/// next chunk is formed; it grows back to `chunk_size` once the RSS drops
+/// below half of the budget.
+pub struct AdaptiveChunks<I> {
+    iter: I,
+    max_size: usize,
+    size: usize,
+}
+
+impl<I: Iterator> AdaptiveChunks<I> {
+    pub fn new(iter: I, chunk_size: usize) -> Self {
+        let chunk_size = chunk_size.max(1);
+        Self { iter, max_size: chunk_size, size: chunk_size }
+    }
+
+    /// The size of the next chunk.
+    pub fn chunk_size(&self) -> usize {
+        self.size
+    }
+
+    fn adapt(&mut self, rss: u64, limit: u64) {
+        if rss > limit && self.size > 1 {
+            self.size = (self.size / 2).max(1);
+            warn!(
+                "Memory usage ({} MB) exceeds the limit ({} MB), reducing the chunk size to {}",
+                rss >> 20,
+                limit >> 20,
+                self.size
+            );
+        } else if rss < limit / 2 && self.size < self.max_size {
+            self.size = (self.size * 2).min(self.max_size);
+            debug!("Increasing the chunk size to {}", self.size);
+        }
+    }
+}
+
+impl<I: Iterator> Iterator for AdaptiveChunks<I> {
+    type Item = Vec<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(limit) = config::memory_limit() {
+            if let Some(rss) = current_rss() {
+                self.adapt(rss, limit as u64);
+            }
+        }
+        let chunk: Vec<_> = self.iter.by_ref().take(self.size).collect();
+        if chunk.is_empty() {
+            None
+        } else {
+            Some(chunk)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_chunks() {
+        let mut chunks = AdaptiveChunks::new(0..100, 16);
+        assert_eq!(chunks.next().unwrap().len(), 16);
+        chunks.adapt(200, 100);
+        chunks.adapt(200, 100);
+        assert_eq!(chunks.chunk_size(), 4);
+        assert_eq!(chunks.next().unwrap(), (16..20).collect::<Vec<_>>());
+        chunks.adapt(75, 100);
+        assert_eq!(chunks.chunk_size(), 4);
+        chunks.adapt(10, 100);
+        assert_eq!(chunks.chunk_size(), 8);
+        assert_eq!(chunks.flatten().count(), 80);
+    }
+
+    #[test]
+    fn test_memory_tracker() {
+        let tracker = MemoryTracker::start();
+        let data = vec![1u8; 1 << 24];
+        let peak = tracker.peak();
+        assert_eq!(peak.is_some(), current_rss().is_some());
+        // The memory held before the tracker was created is not counted.
+        if let Some(peak) = peak {
+            assert!(peak >= 1 << 23 && peak < current_rss().unwrap());
+        }
+        assert!(tracker.finish() >= peak);
+        drop(data);
+    }
+}
@@ -1,11 +1,21 @@
 pub mod similarity;
 pub mod knn;
+pub mod matmul;
+pub mod sampling;
+pub mod rng;
+pub mod checkpoint;
+pub mod memory;
+pub mod barcode;
+pub mod peak_score;
+pub mod track_ops;
+pub mod track_stats;
+pub mod query;
 
 use std::path::Path;
 use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::str::FromStr;
-use anyhow::{Result, Context};
+use anyhow::{bail, Result, Context};
 use std::sync::mpsc::{sync_channel, Receiver};
 use std::thread::JoinHandle;
 
@@ -54,6 +64,52 @@ pub fn clip_peak(mut peak: NarrowPeak, chrom_sizes: &crate::genome::ChromSizes)
     peak
 }
 
+/// Intervals of a BedGraph file as (start, end, value), grouped by chromosome
+/// in the order of the file.
+pub type BedGraphIntervals = Vec<(String, Vec<(u64, u64, f64)>)>;
+
+pub fn read_bedgraph_intervals<P: AsRef<Path>>(path: P) -> Result<BedGraphIntervals> {
+    let mut track: BedGraphIntervals = Vec::new();
+    for x in read_bedgraph_records(path)? {
+        let (chr, record) = x?;
+        match track.last_mut() {
+            Some((chrom, intervals)) if *chrom == chr => intervals.push(record),
+            _ => track.push((chr, vec![record])),
+        }
+    }
+    Ok(track)
+}
+
+/// Read the records of a BedGraph file one at a time, as (chromosome, (start,
+/// end, value)).
+pub fn read_bedgraph_records<P: AsRef<Path>>(
+    path: P,
+) -> Result<impl Iterator<Item = Result<(String, (u64, u64, f64))>>> {
+    let path = path.as_ref().to_path_buf();
+    let lines = BufReader::new(open_file_for_read(&path)?).lines().enumerate();
+    Ok(lines.filter_map(move |(i, line)| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e.into())),
+        };
+        if line.is_empty() || line.starts_with("track") || line.starts_with('#') {
+            return None;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 4 {
+            return Some(Err(anyhow::anyhow!("line {} of {}: expecting 4 fields", i + 1, path.display())));
+        }
+        let parse = || -> Result<_> {
+            Ok((fields[1].parse::<u64>()?, fields[2].parse::<u64>()?, fields[3].parse::<f64>()?))
+        };
+        Some(
+            parse()
+                .with_context(|| format!("line {} of {}: invalid record", i + 1, path.display()))
+                .map(|record| (fields[0].to_string(), record)),
+        )
+    }))
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Compression {
     Gzip,
@@ -72,51 +128,113 @@ impl FromStr for Compression {
     }
 }
 
+/// Open a file for writing, optionally compressed. "-" stands for the
+/// standard output.
 pub fn open_file_for_write<P: AsRef<Path>>(
     filename: P,
     compression: Option<Compression>,
     compression_level: Option<u32>,
-) -> Result<Box<dyn Write + Send>> {
-    let buffer = BufWriter::new(
-        File::create(&filename).with_context(|| format!("cannot create file: {}", filename.as_ref().display()))?
-    );
-    let writer: Box<dyn Write + Send> = match compression {
-        None => Box::new(buffer),
-        Some(Compression::Gzip) => Box::new(flate2::write::GzEncoder::new(buffer, flate2::Compression::new(compression_level.unwrap_or(6)))),
+) -> Result<FileWriter> {
+    let buffer: BufWriter<Box<dyn Write + Send>> = if filename.as_ref() == Path::new("-") {
+        BufWriter::new(Box::new(std::io::stdout()))
+    } else {
+        BufWriter::new(Box::new(
+            File::create(&filename).with_context(|| format!("cannot create file: {}", filename.as_ref().display()))?
+        ))
+    };
+    let encoder = match compression {
+        None => Encoder::Plain(buffer),
+        Some(Compression::Gzip) => Encoder::Gzip(flate2::write::GzEncoder::new(buffer, flate2::Compression::new(compression_level.unwrap_or(6)))),
         Some(Compression::Zstd) => {
             let mut zstd = zstd::stream::Encoder::new(buffer, compression_level.unwrap_or(3) as i32)?;
             zstd.multithread(8)?;
-            Box::new(zstd.auto_finish())
+            Encoder::Zstd(zstd)
         },
     };
-    Ok(writer)
+    Ok(FileWriter(Some(encoder)))
 }
 
-/// Open a file, possibly compressed. Supports gzip and zstd.
-pub fn open_file_for_read<P: AsRef<Path>>(file: P) -> Box<dyn std::io::Read> {
-    match detect_compression(file.as_ref()) {
-        Some(Compression::Gzip) => Box::new(flate2::read::MultiGzDecoder::new(File::open(file.as_ref()).unwrap())),
-        Some(Compression::Zstd) => {
-            let r = zstd::stream::read::Decoder::new(File::open(file.as_ref()).unwrap()).unwrap();
-            Box::new(r)
-        },
-        None => Box::new(File::open(file.as_ref()).unwrap()),
+/// A file opened by [`open_file_for_write`]. Dropping the writer ends the
+/// compressed stream but ignores the errors, so [`FileWriter::finish`] must be
+/// called once everything is written.
+pub struct FileWriter(Option<Encoder>);
+
+enum Encoder {
+    Plain(BufWriter<Box<dyn Write + Send>>),
+    Gzip(flate2::write::GzEncoder<BufWriter<Box<dyn Write + Send>>>),
+    Zstd(zstd::stream::Encoder<'static, BufWriter<Box<dyn Write + Send>>>),
+}
+
+impl Encoder {
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Encoder::Plain(mut w) => w.flush(),
+            Encoder::Gzip(w) => w.finish()?.flush(),
+            Encoder::Zstd(w) => w.finish()?.flush(),
+        }
+    }
+}
+
+impl FileWriter {
+    /// Write the end of the compressed stream and flush the file.
+    pub fn finish(mut self) -> Result<()> {
+        if let Some(encoder) = self.0.take() {
+            encoder.finish().context("cannot finish writing the file")?;
+        }
+        Ok(())
+    }
+
+    fn encoder(&mut self) -> &mut dyn Write {
+        match self.0.as_mut().expect("the file is finished") {
+            Encoder::Plain(w) => w,
+            Encoder::Gzip(w) => w,
+            Encoder::Zstd(w) => w,
+        }
     }
 }
 
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.encoder().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.encoder().flush()
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        if let Some(encoder) = self.0.take() {
+            let _ = encoder.finish();
+        }
+    }
+}
+
+/// Open a file, possibly compressed. Supports gzip and zstd.
+pub fn open_file_for_read<P: AsRef<Path>>(file: P) -> Result<Box<dyn std::io::Read>> {
+    let open = || File::open(file.as_ref())
+        .with_context(|| format!("cannot open file: {}", file.as_ref().display()));
+    let reader: Box<dyn std::io::Read> = match detect_compression(file.as_ref())? {
+        Some(Compression::Gzip) => Box::new(flate2::read::MultiGzDecoder::new(open()?)),
+        Some(Compression::Zstd) => Box::new(zstd::stream::read::Decoder::new(open()?)?),
+        None => Box::new(open()?),
+    };
+    Ok(reader)
+}
+
 /// Determine the file compression type. Supports gzip and zstd.
-fn detect_compression<P: AsRef<Path>>(file: P) -> Option<Compression> {
-    if flate2::read::MultiGzDecoder::new(File::open(file.as_ref()).unwrap()).header().is_some() {
+fn detect_compression<P: AsRef<Path>>(file: P) -> Result<Option<Compression>> {
+    let reader = File::open(file.as_ref())
+        .with_context(|| format!("cannot open file: {}", file.as_ref().display()))?;
+    let compression = if flate2::read::MultiGzDecoder::new(reader).header().is_some() {
         Some(Compression::Gzip)
-    } else if let Some(ext) = file.as_ref().extension() {
-        if ext == "zst" {
-            Some(Compression::Zstd)
-        } else {
-            None
-        }
+    } else if file.as_ref().extension().map_or(false, |ext| ext == "zst") {
+        Some(Compression::Zstd)
     } else {
         None
-    }
+    };
+    Ok(compression)
 }
 
 /// PrefetchIterator allows for prefetching items from an iterator into a buffer.
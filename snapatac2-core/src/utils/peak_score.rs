@@ -0,0 +1,205 @@
+//! Significance tracks of the peak caller.
+//!
+//! MACS3 scores every position by comparing the pileup of the treatment with
+//! the local lambda, the expected pileup estimated from the surrounding
+//! regions. Given the two tracks saved as BedGraph files, [`score_tracks`]
+//! recomputes the -log10 p-value and q-value of every position, so that peaks
+//! can be inspected and re-thresholded without calling them again. The tracks
+//! are streamed, so that memory use does not grow with the size of the genome.
+
+use anyhow::{bail, Result};
+use bed_utils::bed::BedGraph;
+use statrs::distribution::{DiscreteCDF, Poisson};
+use statrs::function::gamma::ln_gamma;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::Path;
+
+use crate::utils::{open_file_for_write, read_bedgraph_records};
+
+/// Pseudocount added to the pileup and the lambda, as in MACS3.
+const PSEUDOCOUNT: f64 = 1.0;
+
+type Record = (String, (u64, u64, f64));
+
+/// Intersect two tracks whose records are sorted by position and which list
+/// the same chromosomes in the same order, as written by MACS3, calling `f`
+/// with the values of both tracks on every interval covered by both.
+fn overlay<A, B, F>(mut a: A, mut b: B, mut f: F) -> Result<()>
+where
+    A: Iterator<Item = Result<Record>>,
+    B: Iterator<Item = Result<Record>>,
+    F: FnMut(&str, u64, u64, f64, f64) -> Result<()>,
+{
+    let mut x = a.next().transpose()?;
+    let mut y = b.next().transpose()?;
+    // The chromosome of the previous record of each track.
+    let (mut prev_a, mut prev_b) = (String::new(), String::new());
+    while let (Some((ca, ia)), Some((cb, ib))) = (&x, &y) {
+        if ca != cb {
+            // Skip the rest of a chromosome that the other track has left.
+            if *cb == prev_a {
+                y = b.next().transpose()?;
+            } else if *ca == prev_b {
+                x = a.next().transpose()?;
+            } else {
+                bail!(
+                    "the tracks must list the same chromosomes in the same order, found {} and {}",
+                    ca,
+                    cb
+                );
+            }
+            continue;
+        }
+        let (end_a, end_b) = (ia.1, ib.1);
+        let start = ia.0.max(ib.0);
+        if start < end_a.min(end_b) {
+            f(ca, start, end_a.min(end_b), ia.2, ib.2)?;
+        }
+        // Move past the interval ending first, or both if they end together.
+        if end_b <= end_a {
+            prev_b.clone_from(cb);
+            y = b.next().transpose()?;
+        }
+        if end_a <= end_b {
+            prev_a.clone_from(ca);
+            x = a.next().transpose()?;
+        }
+    }
+    Ok(())
+}
+
+/// -log10 of the probability of observing at least `k` events under a
+/// Poisson distribution of mean `lambda`. The upper tail is summed in log
+/// space so that highly significant positions do not underflow.
+fn poisson_pscore(k: u64, lambda: f64) -> f64 {
+    if k == 0 {
+        return 0.0;
+    }
+    if k as f64 <= lambda {
+        return -Poisson::new(lambda).unwrap().sf(k - 1).log10();
+    }
+    let ln_lambda = lambda.ln();
+    let mut ln_term = -lambda + k as f64 * ln_lambda - ln_gamma(k as f64 + 1.0);
+    let mut ln_sum = ln_term;
+    let mut i = k;
+    loop {
+        i += 1;
+        ln_term += ln_lambda - (i as f64).ln();
+        if ln_term - ln_sum < -30.0 {
+            break;
+        }
+        ln_sum += (ln_term - ln_sum).exp().ln_1p();
+    }
+    -ln_sum / std::f64::consts::LN_10
+}
+
+/// Convert -log10 p-values to -log10 q-values with the Benjamini-Hochberg
+/// procedure, every base being a test. `histogram` gives the number of bases
+/// at each p-value, keyed by the rounded score.
+fn pscore_to_qscore(histogram: BTreeMap<u64, u64>) -> HashMap<u64, f64> {
+    let total: u64 = histogram.values().sum();
+    let mut n_more_significant = 0;
+    let mut q_prev = f64::INFINITY;
+    histogram
+        .into_iter()
+        .rev()
+        .map(|(key, len)| {
+            n_more_significant += len;
+            let p = key as f64 / SCORE_SCALE;
+            let q = (p + (n_more_significant as f64).log10() - (total as f64).log10())
+                .min(q_prev)
+                .max(0.0);
+            q_prev = q;
+            (key, q)
+        })
+        .collect()
+}
+
+const SCORE_SCALE: f64 = 1e5;
+
+fn score_key(score: f64) -> u64 {
+    (score.min(1e9) * SCORE_SCALE).round() as u64
+}
+
+/// Compute the -log10 p-value and q-value tracks from the treatment pileup and
+/// the local lambda tracks written by MACS3, and write them as BedGraph files.
+/// The p-values are written as the tracks are read, and the q-values are
+/// computed in a second pass over the p-value track.
+pub fn score_tracks<P: AsRef<Path>>(
+    treat_pileup: P,
+    control_lambda: P,
+    pscore_output: P,
+    qscore_output: P,
+) -> Result<()> {
+    let mut histogram: BTreeMap<u64, u64> = BTreeMap::new();
+    let mut p_writer = open_file_for_write(&pscore_output, None, None)?;
+    overlay(
+        read_bedgraph_records(treat_pileup)?,
+        read_bedgraph_records(control_lambda)?,
+        |chrom, start, end, pileup, lambda| {
+            let k = (pileup + PSEUDOCOUNT).round() as u64;
+            let score = poisson_pscore(k, lambda + PSEUDOCOUNT);
+            *histogram.entry(score_key(score)).or_default() += end - start;
+            writeln!(p_writer, "{}", BedGraph::new(chrom, start, end, score))?;
+            Ok(())
+        },
+    )?;
+    p_writer.finish()?;
+
+    let qscores = pscore_to_qscore(histogram);
+    let mut q_writer = open_file_for_write(qscore_output, None, None)?;
+    for x in read_bedgraph_records(&pscore_output)? {
+        let (chrom, (start, end, p)) = x?;
+        let q = qscores[&score_key(p)];
+        writeln!(q_writer, "{}", BedGraph::new(chrom.as_str(), start, end, q))?;
+    }
+    q_writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poisson_pscore() {
+        // P(X >= 1) = 1 - exp(-1) for lambda = 1.
+        let expected = -(1.0 - (-1.0f64).exp()).log10();
+        assert!((poisson_pscore(1, 1.0) - expected).abs() < 1e-9);
+        // The tail computed in log space agrees with the direct computation.
+        let direct = -Poisson::new(2.0).unwrap().sf(9).log10();
+        assert!((poisson_pscore(10, 2.0) - direct).abs() < 1e-6);
+        assert!(poisson_pscore(2000, 1.0).is_finite());
+    }
+
+    #[test]
+    fn test_overlay() {
+        let records = |x: Vec<(&str, u64, u64, f64)>| {
+            x.into_iter()
+                .map(|(c, s, e, v)| Ok((c.to_string(), (s, e, v))))
+                .collect::<Vec<_>>()
+                .into_iter()
+        };
+        let a = records(vec![("chr1", 0, 10, 1.0), ("chr1", 10, 30, 2.0), ("chr2", 0, 10, 5.0)]);
+        let b = records(vec![
+            ("chr1", 5, 20, 3.0),
+            ("chr1", 20, 40, 4.0),
+            ("chr2", 5, 10, 6.0),
+        ]);
+        let mut result = Vec::new();
+        overlay(a, b, |c, s, e, x, y| {
+            result.push((c.to_string(), s, e, x, y));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            result,
+            vec![
+                ("chr1".to_string(), 5, 10, 1.0, 3.0),
+                ("chr1".to_string(), 10, 20, 2.0, 3.0),
+                ("chr1".to_string(), 20, 30, 2.0, 4.0),
+                ("chr2".to_string(), 5, 10, 5.0, 6.0),
+            ]
+        );
+    }
+}
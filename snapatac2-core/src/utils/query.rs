@@ -0,0 +1,444 @@
+//! Boolean predicates on the columns of `.obs` or `.var`.
+//!
+//! [`Predicate::parse`] compiles a simple expression such as
+//! `tsse > 7 && n_fragment >= 1000 && sample != 'ctrl'`. Comparisons of a
+//! column with a number, a quoted string or `true`/`false` are combined with
+//! `&&` (or `and`), `||` (or `or`), `!` (or `not`) and parentheses; column
+//! names containing other characters than letters, digits, `_` and `.` are
+//! written between backquotes. Missing values make comparisons unknown, and
+//! rows whose predicate is unknown are not selected.
+//!
+//! [`Predicate::evaluate`] reads only the columns referenced by the
+//! predicate, one chunk of rows at a time, so that the `.obs` or `.var` of a
+//! backed dataset is never loaded at once.
+
+use anndata::container::InnerDataFrameElem;
+use anndata::data::SelectInfoElem;
+use anndata::{AnnData, AnnDataSet, Backend};
+use anyhow::{bail, ensure, Context, Result};
+use polars::frame::DataFrame;
+use polars::prelude::{Column, DataType};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Number(f64),
+    String(String),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl CmpOp {
+    fn apply<T: PartialOrd + ?Sized>(&self, a: &T, b: &T) -> bool {
+        match self {
+            CmpOp::Lt => a < b,
+            CmpOp::Le => a <= b,
+            CmpOp::Gt => a > b,
+            CmpOp::Ge => a >= b,
+            CmpOp::Eq => a == b,
+            CmpOp::Ne => a != b,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    Compare {
+        column: String,
+        op: CmpOp,
+        value: Literal,
+    },
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(Literal),
+    Op(CmpOp),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match c {
+            _ if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => (Token::LParen, 1),
+            ')' => (Token::RParen, 1),
+            '&' if next == Some('&') => (Token::And, 2),
+            '|' if next == Some('|') => (Token::Or, 2),
+            '!' if next == Some('=') => (Token::Op(CmpOp::Ne), 2),
+            '!' => (Token::Not, 1),
+            '=' if next == Some('=') => (Token::Op(CmpOp::Eq), 2),
+            '<' if next == Some('=') => (Token::Op(CmpOp::Le), 2),
+            '<' => (Token::Op(CmpOp::Lt), 1),
+            '>' if next == Some('=') => (Token::Op(CmpOp::Ge), 2),
+            '>' => (Token::Op(CmpOp::Gt), 1),
+            '\'' | '"' | '`' => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|x| *x == c)
+                    .with_context(|| format!("unterminated quote at position {} of '{}'", i, expr))?;
+                let text: String = chars[i + 1..i + 1 + end].iter().collect();
+                let token = if c == '`' {
+                    Token::Ident(text)
+                } else {
+                    Token::Literal(Literal::String(text))
+                };
+                (token, end + 2)
+            }
+            _ if c.is_ascii_digit() || c == '.' || c == '-' || c == '+' => {
+                let len = chars[i..]
+                    .iter()
+                    .enumerate()
+                    .take_while(|(j, x)| {
+                        x.is_ascii_alphanumeric()
+                            || **x == '.'
+                            || ((**x == '-' || **x == '+')
+                                && (*j == 0 || matches!(chars[i + j - 1], 'e' | 'E')))
+                    })
+                    .count();
+                let text: String = chars[i..i + len].iter().collect();
+                let value = text
+                    .parse::<f64>()
+                    .with_context(|| format!("invalid number '{}' in '{}'", text, expr))?;
+                (Token::Literal(Literal::Number(value)), len)
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|x| x.is_alphanumeric() || **x == '_' || **x == '.')
+                    .count();
+                let text: String = chars[i..i + len].iter().collect();
+                let token = match text.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    "true" | "True" => Token::Literal(Literal::Bool(true)),
+                    "false" | "False" => Token::Literal(Literal::Bool(false)),
+                    _ => Token::Ident(text),
+                };
+                (token, len)
+            }
+            _ => bail!("unexpected character '{}' at position {} of '{}'", c, i, expr),
+        };
+        tokens.push(token);
+        i += len;
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser. `||` binds less tightly than `&&`, which binds
+/// less tightly than `!`.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Predicate> {
+        let mut left = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            left = Predicate::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Predicate> {
+        let mut left = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            left = Predicate::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Predicate> {
+        match self.advance() {
+            Some(Token::Not) => Ok(Predicate::Not(Box::new(self.unary()?))),
+            Some(Token::LParen) => {
+                let inner = self.or()?;
+                ensure!(self.advance() == Some(Token::RParen), "missing closing parenthesis");
+                Ok(inner)
+            }
+            Some(Token::Ident(column)) => {
+                let op = match self.advance() {
+                    Some(Token::Op(op)) => op,
+                    _ => bail!("expecting a comparison operator after '{}'", column),
+                };
+                let value = match self.advance() {
+                    Some(Token::Literal(value)) => value,
+                    _ => bail!("expecting a number, a string or a boolean after '{}'", column),
+                };
+                if !matches!(value, Literal::Number(_)) {
+                    ensure!(
+                        matches!(op, CmpOp::Eq | CmpOp::Ne) || matches!(value, Literal::String(_)),
+                        "booleans can only be compared with '==' or '!='"
+                    );
+                }
+                Ok(Predicate::Compare { column, op, value })
+            }
+            Some(token) => bail!("unexpected token {:?}", token),
+            None => bail!("unexpected end of the expression"),
+        }
+    }
+}
+
+impl Predicate {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let tokens = tokenize(expr)?;
+        let mut parser = Parser { tokens: &tokens, pos: 0 };
+        let predicate = parser
+            .or()
+            .with_context(|| format!("cannot parse the expression '{}'", expr))?;
+        ensure!(
+            parser.pos == tokens.len(),
+            "cannot parse the expression '{}': unexpected trailing tokens",
+            expr
+        );
+        Ok(predicate)
+    }
+
+    /// The columns referenced by the predicate.
+    pub fn columns(&self) -> Vec<&str> {
+        let mut columns = Vec::new();
+        self.collect_columns(&mut columns);
+        columns.sort_unstable();
+        columns.dedup();
+        columns
+    }
+
+    fn collect_columns<'a>(&'a self, columns: &mut Vec<&'a str>) {
+        match self {
+            Predicate::Compare { column, .. } => columns.push(column),
+            Predicate::And(a, b) | Predicate::Or(a, b) => {
+                a.collect_columns(columns);
+                b.collect_columns(columns);
+            }
+            Predicate::Not(a) => a.collect_columns(columns),
+        }
+    }
+
+    /// Evaluate the predicate on every row of `table`, `chunk_size` rows at a
+    /// time. Rows for which the predicate is unknown are not selected.
+    pub fn evaluate<T: ColumnReader + ?Sized>(&self, table: &mut T, chunk_size: usize) -> Result<Vec<bool>> {
+        ensure!(chunk_size > 0, "chunk size must be positive");
+        let columns = self.columns();
+        let missing: Vec<_> = columns.iter().filter(|x| !table.has_column(x)).copied().collect();
+        ensure!(missing.is_empty(), "columns not found: {}", missing.join(", "));
+
+        let n = table.height();
+        let mut mask = Vec::with_capacity(n);
+        for offset in (0..n).step_by(chunk_size) {
+            let len = chunk_size.min(n - offset);
+            let chunk = table.read_columns(&columns, offset, len)?;
+            mask.extend(self.evaluate_chunk(&chunk)?.into_iter().map(|x| x.unwrap_or(false)));
+        }
+        Ok(mask)
+    }
+
+    /// Three-valued evaluation on the rows of `df`.
+    fn evaluate_chunk(&self, df: &DataFrame) -> Result<Vec<Option<bool>>> {
+        Ok(match self {
+            Predicate::Compare { column, op, value } => {
+                compare(df.column(column)?, *op, value)
+                    .with_context(|| format!("cannot compare column '{}'", column))?
+            }
+            Predicate::And(a, b) => {
+                let (a, b) = (a.evaluate_chunk(df)?, b.evaluate_chunk(df)?);
+                a.into_iter()
+                    .zip(b)
+                    .map(|x| match x {
+                        (Some(false), _) | (_, Some(false)) => Some(false),
+                        (Some(true), Some(true)) => Some(true),
+                        _ => None,
+                    })
+                    .collect()
+            }
+            Predicate::Or(a, b) => {
+                let (a, b) = (a.evaluate_chunk(df)?, b.evaluate_chunk(df)?);
+                a.into_iter()
+                    .zip(b)
+                    .map(|x| match x {
+                        (Some(true), _) | (_, Some(true)) => Some(true),
+                        (Some(false), Some(false)) => Some(false),
+                        _ => None,
+                    })
+                    .collect()
+            }
+            Predicate::Not(a) => a
+                .evaluate_chunk(df)?
+                .into_iter()
+                .map(|x| x.map(|x| !x))
+                .collect(),
+        })
+    }
+}
+
+/// A table whose columns can be read separately, a range of rows at a time.
+pub trait ColumnReader {
+    fn height(&self) -> usize;
+
+    fn has_column(&self, name: &str) -> bool;
+
+    /// Read the rows `offset..offset + len` of `columns`.
+    fn read_columns(&mut self, columns: &[&str], offset: usize, len: usize) -> Result<DataFrame>;
+}
+
+impl ColumnReader for DataFrame {
+    fn height(&self) -> usize {
+        DataFrame::height(self)
+    }
+
+    fn has_column(&self, name: &str) -> bool {
+        self.column(name).is_ok()
+    }
+
+    fn read_columns(&mut self, columns: &[&str], offset: usize, len: usize) -> Result<DataFrame> {
+        Ok(self.select(columns.iter().copied())?.slice(offset as i64, len))
+    }
+}
+
+/// `.obs` or `.var` of a backed dataset. Only the requested columns and rows
+/// are read from the file.
+impl<B: Backend> ColumnReader for InnerDataFrameElem<B> {
+    fn height(&self) -> usize {
+        InnerDataFrameElem::height(self)
+    }
+
+    fn has_column(&self, name: &str) -> bool {
+        self.get_column_names().contains(name)
+    }
+
+    fn read_columns(&mut self, columns: &[&str], offset: usize, len: usize) -> Result<DataFrame> {
+        let names = self.get_column_names();
+        let indices = columns
+            .iter()
+            .map(|x| names.get_index_of(*x).with_context(|| format!("column not found: {}", x)))
+            .collect::<Result<Vec<_>>>()?;
+        self.select(&[SelectInfoElem::from(offset..offset + len), SelectInfoElem::from(indices)])
+    }
+}
+
+fn compare(col: &Column, op: CmpOp, value: &Literal) -> Result<Vec<Option<bool>>> {
+    let is_text = matches!(
+        col.dtype(),
+        DataType::String | DataType::Categorical(..) | DataType::Enum(..)
+    );
+    let is_bool = matches!(col.dtype(), DataType::Boolean);
+    Ok(match value {
+        Literal::Number(v) => {
+            ensure!(!is_text && !is_bool, "the column is not numeric");
+            let col = col.cast(&DataType::Float64)?;
+            col.f64()?.into_iter().map(|x| x.map(|x| op.apply(&x, v))).collect()
+        }
+        Literal::String(v) => {
+            ensure!(is_text, "the column does not contain strings");
+            let col = col.cast(&DataType::String)?;
+            col.str()?.into_iter().map(|x| x.map(|x| op.apply(x, v.as_str()))).collect()
+        }
+        Literal::Bool(v) => {
+            ensure!(is_bool, "the column is not boolean");
+            col.bool()?.into_iter().map(|x| x.map(|x| op.apply(&x, v))).collect()
+        }
+    })
+}
+
+/// Datasets whose `.obs` and `.var` can be queried, see [`Predicate::evaluate`].
+pub trait Queryable {
+    /// Evaluate `predicate` on the rows of `.obs`.
+    fn query_obs(&self, predicate: &Predicate, chunk_size: usize) -> Result<Vec<bool>>;
+
+    /// Evaluate `predicate` on the rows of `.var`.
+    fn query_var(&self, predicate: &Predicate, chunk_size: usize) -> Result<Vec<bool>>;
+}
+
+impl<B: Backend> Queryable for AnnData<B> {
+    fn query_obs(&self, predicate: &Predicate, chunk_size: usize) -> Result<Vec<bool>> {
+        predicate.evaluate(&mut *self.get_obs().inner(), chunk_size)
+    }
+
+    fn query_var(&self, predicate: &Predicate, chunk_size: usize) -> Result<Vec<bool>> {
+        predicate.evaluate(&mut *self.get_var().inner(), chunk_size)
+    }
+}
+
+impl<B: Backend> Queryable for AnnDataSet<B> {
+    fn query_obs(&self, predicate: &Predicate, chunk_size: usize) -> Result<Vec<bool>> {
+        predicate.evaluate(&mut *self.get_obs().inner(), chunk_size)
+    }
+
+    fn query_var(&self, predicate: &Predicate, chunk_size: usize) -> Result<Vec<bool>> {
+        predicate.evaluate(&mut *self.get_var().inner(), chunk_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let p = Predicate::parse("tsse > 7 && !(n_fragment < 1e3 or `my col` == 'a b')").unwrap();
+        assert_eq!(p.columns(), vec!["my col", "n_fragment", "tsse"]);
+        assert_eq!(
+            Predicate::parse("x >= -1.5").unwrap(),
+            Predicate::Compare { column: "x".into(), op: CmpOp::Ge, value: Literal::Number(-1.5) }
+        );
+        assert!(Predicate::parse("x > ").is_err());
+        assert!(Predicate::parse("x > 1 y").is_err());
+        assert!(Predicate::parse("(x > 1").is_err());
+        assert!(Predicate::parse("x < true").is_err());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let df = DataFrame::new(vec![
+            Column::new("tsse".into(), [Some(8.0), Some(5.0), None, Some(12.0)]),
+            Column::new("n".into(), [2000u64, 5000, 3000, 10]),
+            Column::new("sample".into(), ["a", "b", "a", "a"]),
+            Column::new("doublet".into(), [false, false, true, false]),
+        ])
+        .unwrap();
+        let eval = |expr: &str| Predicate::parse(expr).unwrap().evaluate(&mut df.clone(), 3).unwrap();
+        assert_eq!(eval("tsse > 7 && n > 1000"), vec![true, false, false, false]);
+        assert_eq!(eval("tsse > 7 || n > 1000"), vec![true, true, true, true]);
+        assert_eq!(eval("!(tsse > 7)"), vec![false, true, false, false]);
+        assert_eq!(eval("sample == 'a' and doublet == false"), vec![true, false, false, true]);
+        assert!(Predicate::parse("sample > 1").unwrap().evaluate(&mut df.clone(), 3).is_err());
+        assert!(Predicate::parse("missing > 1").unwrap().evaluate(&mut df.clone(), 3).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+//! Deterministic random number generation.
+//!
+//! Every stochastic algorithm takes a `u64` seed and obtains its generators
+//! from a [`SeedStream`]. Independent sub-tasks (e.g., bootstrap replicates or
+//! chunks processed in parallel) draw from their own stream, indexed by the
+//! task rather than by the thread, so results do not depend on the number of
+//! threads or on the scheduling order.
+//!
+//! The generator is ChaCha8, whose output is specified and stable across
+//! platforms and versions of `rand`, unlike `StdRng`.
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+pub type Rng = ChaCha8Rng;
+
+/// One step of the SplitMix64 generator, used to derive well-mixed seeds
+/// from arbitrary (possibly correlated) inputs.
+pub fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// A family of independent random number generators derived from one seed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeedStream {
+    seed: u64,
+}
+
+impl SeedStream {
+    pub fn new(seed: u64) -> Self {
+        let mut state = seed;
+        Self { seed: splitmix64(&mut state) }
+    }
+
+    /// Derive an independent family of generators for a named component, so
+    /// that adding random draws to one component does not change the others.
+    pub fn derive(&self, name: &str) -> Self {
+        // FNV-1a hash of the name, mixed with the parent seed.
+        let hash = name.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+        let mut state = self.seed ^ hash;
+        Self { seed: splitmix64(&mut state) }
+    }
+
+    /// The seed of the stream, e.g., to seed a generator outside this crate.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The generator of the `i`-th task.
+    pub fn rng(&self, i: u64) -> Rng {
+        let mut rng = Rng::seed_from_u64(self.seed);
+        rng.set_stream(i);
+        rng
+    }
+}
+
+/// Create a generator from a seed. Equivalent to `SeedStream::new(seed).rng(0)`.
+pub fn rng_from_seed(seed: u64) -> Rng {
+    SeedStream::new(seed).rng(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng as _;
+
+    #[test]
+    fn test_seed_stream() {
+        let stream = SeedStream::new(42);
+        let a: Vec<u64> = (0..4).map(|_| stream.rng(0).random()).collect();
+        assert!(a.iter().all(|x| *x == a[0]));
+
+        let x: u64 = stream.rng(0).random();
+        let y: u64 = stream.rng(1).random();
+        let z: u64 = stream.derive("pca").rng(0).random();
+        assert_ne!(x, y);
+        assert_ne!(x, z);
+        assert_eq!(rng_from_seed(42).random::<u64>(), x);
+    }
+}
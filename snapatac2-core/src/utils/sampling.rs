@@ -0,0 +1,175 @@
+use super::rng::rng_from_seed;
+use ndarray::ArrayView2;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Select at most `max_per_group` elements from every group.
+///
+/// Groups that contain no more than `max_per_group` elements are kept in
+/// full, while larger groups are randomly downsampled. Elements without a
+/// group (`None`) are never selected. Returns a boolean mask in the
+/// original order.
+pub fn stratified_subsample<T: Hash + Eq>(
+    groups: &[Option<T>],
+    max_per_group: usize,
+    seed: u64,
+) -> Vec<bool> {
+    let mut members: HashMap<&T, Vec<usize>> = HashMap::new();
+    let mut order = Vec::new();
+    groups.iter().enumerate().for_each(|(i, g)| {
+        if let Some(g) = g {
+            members
+                .entry(g)
+                .or_insert_with(|| {
+                    order.push(g);
+                    Vec::new()
+                })
+                .push(i);
+        }
+    });
+
+    // Groups are visited in the order of first appearance so that the result
+    // only depends on the seed.
+    let mut rng = rng_from_seed(seed);
+    let mut mask = vec![false; groups.len()];
+    order.into_iter().for_each(|g| {
+        let idx = &members[g];
+        if idx.len() > max_per_group {
+            rand::seq::index::sample(&mut rng, idx.len(), max_per_group)
+                .into_iter()
+                .for_each(|i| mask[idx[i]] = true);
+        } else {
+            idx.iter().for_each(|i| mask[*i] = true);
+        }
+    });
+    mask
+}
+
+/// Select `n` rows of `data` by geometric sketching (Hie et al., 2019).
+///
+/// The space is covered by a grid of hypercubes whose side is chosen, by
+/// binary search, so that about `n` of them contain at least one point.
+/// Points are then drawn one per hypercube, visiting the hypercubes in random
+/// order and repeating until `n` points are selected. Unlike uniform
+/// subsampling, this keeps points from sparse regions, i.e., rare populations.
+/// Returns a boolean mask in the original order.
+pub fn geometric_sketch(data: ArrayView2<f64>, n: usize, seed: u64) -> Vec<bool> {
+    let n_obs = data.nrows();
+    if n >= n_obs {
+        return vec![true; n_obs];
+    }
+    let mut mask = vec![false; n_obs];
+    if n == 0 {
+        return mask;
+    }
+
+    let mins: Vec<f64> = data
+        .columns()
+        .into_iter()
+        .map(|c| c.iter().copied().fold(f64::INFINITY, f64::min))
+        .collect();
+    let range = data
+        .columns()
+        .into_iter()
+        .zip(mins.iter())
+        .map(|(c, m)| c.iter().map(|x| x - m).fold(0.0, f64::max))
+        .fold(0.0, f64::max);
+
+    let grid = |side: f64| -> HashMap<Vec<i64>, Vec<usize>> {
+        let mut boxes: HashMap<Vec<i64>, Vec<usize>> = HashMap::new();
+        data.rows().into_iter().enumerate().for_each(|(i, row)| {
+            let key = row
+                .iter()
+                .zip(mins.iter())
+                .map(|(x, m)| ((x - m) / side).floor() as i64)
+                .collect();
+            boxes.entry(key).or_default().push(i);
+        });
+        boxes
+    };
+
+    // The number of non-empty hypercubes decreases with their side. Find the
+    // largest side with at least `n` of them.
+    let boxes = if range > 0.0 {
+        let (mut lo, mut hi) = (0.0, range * 2.0);
+        let mut best = None;
+        for _ in 0..50 {
+            let mid = (lo + hi) / 2.0;
+            let candidate = grid(mid);
+            if candidate.len() >= n {
+                let done = candidate.len() == n;
+                lo = mid;
+                best = Some(candidate);
+                if done {
+                    break;
+                }
+            } else {
+                hi = mid;
+            }
+        }
+        // Without enough distinct points, use the finest grid.
+        best.unwrap_or_else(|| grid(hi))
+    } else {
+        grid(1.0)
+    };
+
+    // Hypercubes are visited in a deterministic order before shuffling so
+    // that the result only depends on the seed.
+    let mut rng = rng_from_seed(seed);
+    let mut boxes: Vec<Vec<usize>> = boxes.into_values().collect();
+    boxes.sort_unstable_by_key(|x| x[0]);
+    boxes.shuffle(&mut rng);
+    let mut selected = 0;
+    while selected < n {
+        for members in boxes.iter_mut() {
+            if selected == n {
+                break;
+            }
+            if members.is_empty() {
+                continue;
+            }
+            let i = rng.random_range(0..members.len());
+            mask[members.swap_remove(i)] = true;
+            selected += 1;
+        }
+    }
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stratified_subsample() {
+        let groups: Vec<Option<&str>> = (0..100)
+            .map(|i| if i < 90 { Some("a") } else if i < 95 { Some("b") } else { None })
+            .collect();
+        let mask = stratified_subsample(&groups, 10, 0);
+        assert_eq!(mask[..90].iter().filter(|x| **x).count(), 10);
+        assert!(mask[90..95].iter().all(|x| *x));
+        assert!(mask[95..].iter().all(|x| !*x));
+        assert_eq!(mask, stratified_subsample(&groups, 10, 0));
+    }
+
+    #[test]
+    fn test_geometric_sketch() {
+        // A dense population of 990 points and a rare one of 10 points.
+        let mut data = ndarray::Array2::<f64>::zeros((1000, 2));
+        for i in 0..990 {
+            data[[i, 0]] = (i % 30) as f64 * 0.01;
+            data[[i, 1]] = (i / 30) as f64 * 0.01;
+        }
+        for i in 990..1000 {
+            data[[i, 0]] = 10.0 + (i - 990) as f64 * 0.01;
+            data[[i, 1]] = 10.0;
+        }
+        let mask = geometric_sketch(data.view(), 50, 0);
+        assert_eq!(mask.iter().filter(|x| **x).count(), 50);
+        assert!(mask[990..].iter().any(|x| *x));
+        assert_eq!(mask, geometric_sketch(data.view(), 50, 0));
+        assert!(geometric_sketch(data.view(), 2000, 0).iter().all(|x| *x));
+    }
+}
@@ -90,22 +90,26 @@ where
 
     {
         let mat_t = mat.transpose();
-        res.axis_iter_mut(Axis(0)).into_par_iter().enumerate().for_each(|(i, mut row)| {
-            mat.get_lane(i).unwrap().into_iter().for_each(|k| {
-                let k_ = (*k).try_into().unwrap();
-                mat_t.get_lane(k_).unwrap().into_iter().for_each(|j| {
-                    let j_ = (*j).try_into().unwrap();
-                    if j_ > i { row[[j_]] += weights.map_or(1.0, |w| w[k_]); }
-                })
-            });
+        crate::config::install(None, || {
+            res.axis_iter_mut(Axis(0)).into_par_iter().enumerate().for_each(|(i, mut row)| {
+                mat.get_lane(i).unwrap().into_iter().for_each(|k| {
+                    let k_ = (*k).try_into().unwrap();
+                    mat_t.get_lane(k_).unwrap().into_iter().for_each(|j| {
+                        let j_ = (*j).try_into().unwrap();
+                        if j_ > i { row[[j_]] += weights.map_or(1.0, |w| w[k_]); }
+                    })
+                });
+            })
         });
     }
 
-    let sizes: Vec<f64> = (0..n).into_par_iter().map(|i|
-        mat.get_lane(i).unwrap().iter().map(|j|
-            weights.map_or(1.0, |w| w[(*j).try_into().unwrap()])
-        ).sum()
-    ).collect();
+    let sizes: Vec<f64> = crate::config::install(None, || {
+        (0..n).into_par_iter().map(|i|
+            mat.get_lane(i).unwrap().iter().map(|j|
+                weights.map_or(1.0, |w| w[(*j).try_into().unwrap()])
+            ).sum()
+        ).collect()
+    });
 
     (0..n).combinations(2).for_each(|x| {
         let i = x[0];
@@ -139,26 +143,32 @@ where
 
     {
         let mat2_t = mat2.transpose();
-        res.axis_iter_mut(Axis(0)).into_par_iter().enumerate().for_each(|(i, mut row)| {
-            mat1.get_lane(i).unwrap().into_iter().for_each(|k| {
-                let k_ = (*k).try_into().unwrap();
-                mat2_t.get_lane(k_).unwrap().into_iter().for_each(|j| {
-                    row[[(*j).try_into().unwrap()]] += weights.map_or(1.0, |w| w[k_]);
-                })
-            });
+        crate::config::install(None, || {
+            res.axis_iter_mut(Axis(0)).into_par_iter().enumerate().for_each(|(i, mut row)| {
+                mat1.get_lane(i).unwrap().into_iter().for_each(|k| {
+                    let k_ = (*k).try_into().unwrap();
+                    mat2_t.get_lane(k_).unwrap().into_iter().for_each(|j| {
+                        row[[(*j).try_into().unwrap()]] += weights.map_or(1.0, |w| w[k_]);
+                    })
+                });
+            })
         });
     }
 
-    let sizes1: Vec<f64> = (0..n).into_par_iter().map(|i|
-        mat1.get_lane(i).unwrap().iter().map(|j|
-            weights.map_or(1.0, |w| w[(*j).try_into().unwrap()])
-        ).sum()
-    ).collect();
-    let sizes2: Vec<f64> = (0..m).into_par_iter().map(|i|
-        mat2.get_lane(i).unwrap().iter().map(|j|
-            weights.map_or(1.0, |w| w[(*j).try_into().unwrap()])
-        ).sum()
-    ).collect();
+    let sizes1: Vec<f64> = crate::config::install(None, || {
+        (0..n).into_par_iter().map(|i|
+            mat1.get_lane(i).unwrap().iter().map(|j|
+                weights.map_or(1.0, |w| w[(*j).try_into().unwrap()])
+            ).sum()
+        ).collect()
+    });
+    let sizes2: Vec<f64> = crate::config::install(None, || {
+        (0..m).into_par_iter().map(|i|
+            mat2.get_lane(i).unwrap().iter().map(|j|
+                weights.map_or(1.0, |w| w[(*j).try_into().unwrap()])
+            ).sum()
+        ).collect()
+    });
     res.indexed_iter_mut().for_each(|((i,j), x)| {
         let u = sizes1[i] + sizes2[j];
         let v = if u == 0.0 { 1.0 } else { *x / (u - *x) };
@@ -189,14 +199,16 @@ pub fn cosine(
 
     {
         let mat_t = mat.transpose();
-        res.axis_iter_mut(Axis(0)).into_par_iter().enumerate().for_each(|(i, mut row)| {
-            let csr1 = mat.get_row(i).unwrap();
-            csr1.col_indices().into_iter().zip(csr1.values()).for_each(|(k, v1)| {
-                let csr2 = mat_t.get_row(*k).unwrap();
-                csr2.col_indices().into_iter().zip(csr2.values()).for_each(|(j, v2)|
-                    if *j > i { row[[*j]] += v1 * v2; }
-                )
-            });
+        crate::config::install(None, || {
+            res.axis_iter_mut(Axis(0)).into_par_iter().enumerate().for_each(|(i, mut row)| {
+                let csr1 = mat.get_row(i).unwrap();
+                csr1.col_indices().into_iter().zip(csr1.values()).for_each(|(k, v1)| {
+                    let csr2 = mat_t.get_row(*k).unwrap();
+                    csr2.col_indices().into_iter().zip(csr2.values()).for_each(|(j, v2)|
+                        if *j > i { row[[*j]] += v1 * v2; }
+                    )
+                });
+            })
         });
     }
 
@@ -237,14 +249,16 @@ pub fn cosine2(
 
     {
         let mat2_t = mat2.transpose();
-        res.axis_iter_mut(Axis(0)).into_par_iter().enumerate().for_each(|(i, mut row)| {
-            let csr1 = mat1.get_row(i).unwrap();
-            csr1.col_indices().into_iter().zip(csr1.values()).for_each(|(k, v1)| {
-                let csr2 = mat2_t.get_row(*k).unwrap();
-                csr2.col_indices().into_iter().zip(csr2.values()).for_each(|(j, v2)| {
-                    row[[*j]] += v1 * v2;
+        crate::config::install(None, || {
+            res.axis_iter_mut(Axis(0)).into_par_iter().enumerate().for_each(|(i, mut row)| {
+                let csr1 = mat1.get_row(i).unwrap();
+                csr1.col_indices().into_iter().zip(csr1.values()).for_each(|(k, v1)| {
+                    let csr2 = mat2_t.get_row(*k).unwrap();
+                    csr2.col_indices().into_iter().zip(csr2.values()).for_each(|(j, v2)| {
+                        row[[*j]] += v1 * v2;
+                    });
                 });
-            });
+            })
         });
     }
     res
@@ -404,7 +418,9 @@ where
 {
     let n1 = mat_x.nrows();
     let n2 = mat_y.nrows();
-    let dists = (0..n1).cartesian_product(0..n2).collect::<Vec<_>>().into_par_iter()
-        .map(|(i, j)| dist_fn(mat_x.get_row(i).unwrap(), mat_y.get_row(j).unwrap())).collect();
+    let dists = crate::config::install(None, || {
+        (0..n1).cartesian_product(0..n2).collect::<Vec<_>>().into_par_iter()
+            .map(|(i, j)| dist_fn(mat_x.get_row(i).unwrap(), mat_y.get_row(j).unwrap())).collect()
+    });
     ArrayBase::from_vec(dists).into_shape_with_order((n1, n2)).unwrap()
 }
\ No newline at end of file
@@ -0,0 +1,626 @@
+//! Arithmetic on coverage tracks.
+//!
+//! [`combine_tracks`] summarizes several bedGraph or bigWig tracks, e.g., the
+//! coverage of the donors of a cell type, into a single track. The tracks are
+//! streamed and merged by sweeping over the boundaries of their intervals, so
+//! that only the current interval of each track is held in memory. [`region_signal`] summarizes the tracks in a set
+//! of regions instead, e.g., to annotate the tiles of a count matrix with
+//! external ChIP-seq signal, and [`track_correlation`] compares tracks with
+//! reference tracks over the bins of the genome.
+
+use anyhow::{bail, ensure, Context, Result};
+use bed_utils::bed::{BEDLike, BedGraph, GenomicRange};
+use bigtools::BigWigRead;
+use indexmap::IndexMap;
+use ndarray::{Array2, Axis};
+use rayon::prelude::*;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Lines, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::export::{create_bigwig_from_bedgraph, CoverageOutputFormat};
+use crate::genome::ChromSizes;
+use crate::metrics::CorrelationMethod;
+use crate::utils::similarity::{pearson2, spearman2};
+use crate::utils::{open_file_for_read, open_file_for_write, Compression};
+
+type Interval = (u64, u64, f64);
+
+/// How values of the tracks are combined. Positions not covered by a track
+/// count as 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackOp {
+    Mean,
+    Sum,
+    Max,
+    Min,
+}
+
+impl TryFrom<&str> for TrackOp {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "mean" => Ok(TrackOp::Mean),
+            "sum" => Ok(TrackOp::Sum),
+            "max" => Ok(TrackOp::Max),
+            "min" => Ok(TrackOp::Min),
+            _ => bail!("operation must be one of 'mean', 'sum', 'max' or 'min'"),
+        }
+    }
+}
+
+impl TrackOp {
+    fn apply(&self, values: &[f64]) -> f64 {
+        match self {
+            TrackOp::Mean => values.iter().sum::<f64>() / values.len() as f64,
+            TrackOp::Sum => values.iter().sum(),
+            TrackOp::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            TrackOp::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+        }
+    }
+}
+
+/// Length of the windows in which bigWig tracks are read.
+const BIGWIG_WINDOW: u64 = 1_000_000;
+
+/// A track read interval by interval, in the order of the chromosome sizes.
+enum TrackReader {
+    BedGraph {
+        path: PathBuf,
+        lines: Lines<BufReader<Box<dyn Read>>>,
+        line_no: usize,
+        /// The next record, which may be on a following chromosome.
+        pending: Option<(String, Interval)>,
+    },
+    BigWig {
+        reader: BigWigRead<bigtools::utils::reopen::ReopenableFile>,
+        /// The chromosome being read, its size and the start of the next window.
+        position: Option<(String, u64, u64)>,
+        /// The intervals of the current window that have not been read.
+        buffer: VecDeque<Interval>,
+    },
+}
+
+impl TrackReader {
+    fn open(path: &Path) -> Result<Self> {
+        ensure!(path.exists(), "track file not found: {}", path.display());
+        let name = path.to_string_lossy().to_lowercase();
+        if name.ends_with(".bw") || name.ends_with(".bigwig") {
+            let reader = BigWigRead::open_file(utf8_path(path)?)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            Ok(TrackReader::BigWig {
+                reader,
+                position: None,
+                buffer: VecDeque::new(),
+            })
+        } else {
+            Ok(TrackReader::BedGraph {
+                path: path.to_path_buf(),
+                lines: BufReader::new(open_file_for_read(path)?).lines(),
+                line_no: 0,
+                pending: None,
+            })
+        }
+    }
+
+    /// Read the next interval of `chrom`, or `None` at the end of the
+    /// chromosome. Records on chromosomes missing from `chrom_sizes` are skipped.
+    fn next_interval(&mut self, chrom: &str, chrom_sizes: &ChromSizes) -> Result<Option<Interval>> {
+        match self {
+            TrackReader::BigWig {
+                reader,
+                position,
+                buffer,
+            } => {
+                if matches!(position, Some((c, _, _)) if c != chrom) {
+                    *position = None;
+                    buffer.clear();
+                }
+                let (_, size, next) = position.get_or_insert_with(|| {
+                    let size = reader
+                        .chroms()
+                        .iter()
+                        .find(|x| x.name == chrom)
+                        .map_or(0, |x| x.length as u64);
+                    (chrom.to_string(), size, 0)
+                });
+                while buffer.is_empty() && *next < *size {
+                    let end = (*next + BIGWIG_WINDOW).min(*size);
+                    for x in reader.get_interval(chrom, *next as u32, end as u32)? {
+                        let x = x?;
+                        // Intervals crossing the windows are clipped, and the
+                        // pieces are merged back when the track is written.
+                        let (s, e) = ((x.start as u64).max(*next), (x.end as u64).min(end));
+                        if s < e {
+                            buffer.push_back((s, e, x.value as f64));
+                        }
+                    }
+                    *next = end;
+                }
+                Ok(buffer.pop_front())
+            }
+            TrackReader::BedGraph {
+                path,
+                lines,
+                line_no,
+                pending,
+            } => {
+                let current = chrom_sizes
+                    .get_index_of(chrom)
+                    .with_context(|| format!("unknown chromosome: {}", chrom))?;
+                loop {
+                    if pending.is_none() {
+                        *pending = next_record(lines, line_no, path)?;
+                    }
+                    let Some((c, record)) = pending.as_ref() else {
+                        return Ok(None);
+                    };
+                    match chrom_sizes.get_index_of(c) {
+                        None => *pending = None,
+                        Some(i) if i == current => {
+                            let record = *record;
+                            *pending = None;
+                            return Ok(Some(record));
+                        }
+                        Some(i) if i > current => return Ok(None),
+                        Some(_) => bail!(
+                            "{}: chromosome {} at line {} is out of order; tracks must be sorted in the order of the chromosome sizes",
+                            path.display(),
+                            c,
+                            line_no
+                        ),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read all the intervals of `chrom`.
+    fn read_chrom(&mut self, chrom: &str, chrom_sizes: &ChromSizes) -> Result<Vec<Interval>> {
+        std::iter::from_fn(|| self.next_interval(chrom, chrom_sizes).transpose()).collect()
+    }
+}
+
+/// The path as a string, as required by bigtools.
+pub(crate) fn utf8_path(path: &Path) -> Result<&str> {
+    path.to_str()
+        .with_context(|| format!("not a valid UTF-8 path: {}", path.display()))
+}
+
+fn next_record(
+    lines: &mut Lines<BufReader<Box<dyn Read>>>,
+    line_no: &mut usize,
+    path: &Path,
+) -> Result<Option<(String, Interval)>> {
+    for line in lines.by_ref() {
+        let line = line?;
+        *line_no += 1;
+        if line.is_empty() || line.starts_with("track") || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 4 {
+            bail!("line {} of {}: expecting 4 fields", line_no, path.display());
+        }
+        let parse = || -> Result<_> {
+            Ok((fields[1].parse::<u64>()?, fields[2].parse::<u64>()?, fields[3].parse::<f64>()?))
+        };
+        let record = parse()
+            .with_context(|| format!("line {} of {}: invalid record", line_no, path.display()))?;
+        return Ok(Some((fields[0].to_string(), record)));
+    }
+    Ok(None)
+}
+
+/// Sweep over the boundaries of the sorted, non-overlapping intervals of
+/// several tracks on one chromosome, holding only the current interval of
+/// each track.
+struct Sweep {
+    heads: Vec<Option<Interval>>,
+    done: Vec<bool>,
+    values: Vec<f64>,
+    pos: u64,
+}
+
+impl Sweep {
+    fn new(n_tracks: usize) -> Self {
+        Self {
+            heads: vec![None; n_tracks],
+            done: vec![false; n_tracks],
+            values: vec![0.0; n_tracks],
+            pos: 0,
+        }
+    }
+
+    /// The next interval covered by at least one track and over which the
+    /// values of the tracks are constant, combined by `op`. `fetch(t)` reads
+    /// the next interval of track `t`.
+    fn next<F>(&mut self, op: TrackOp, mut fetch: F) -> Result<Option<Interval>>
+    where
+        F: FnMut(usize) -> Result<Option<Interval>>,
+    {
+        for t in 0..self.heads.len() {
+            while !self.done[t] && !matches!(self.heads[t], Some((s, e, _)) if s < e && e > self.pos) {
+                self.heads[t] = fetch(t)?;
+                self.done[t] = self.heads[t].is_none();
+            }
+        }
+        let Some(start) = self.heads.iter().flatten().map(|x| x.0.max(self.pos)).min() else {
+            return Ok(None);
+        };
+        let mut end = u64::MAX;
+        for (t, head) in self.heads.iter().enumerate() {
+            self.values[t] = match head {
+                Some((s, e, v)) if *s <= start => {
+                    end = end.min(*e);
+                    *v
+                }
+                Some((s, _, _)) => {
+                    end = end.min(*s);
+                    0.0
+                }
+                None => 0.0,
+            };
+        }
+        self.pos = end;
+        Ok(Some((start, end, op.apply(&self.values))))
+    }
+}
+
+/// The combined intervals of the tracks, read as they are written. Adjacent
+/// intervals with the same value are merged. The first error stops the
+/// iteration and is stored in `error`.
+struct CombinedTrack<'a> {
+    readers: Vec<TrackReader>,
+    chrom_sizes: &'a ChromSizes,
+    chroms: Vec<&'a str>,
+    op: TrackOp,
+    sweep: Sweep,
+    last: Option<Interval>,
+    error: &'a RefCell<Option<anyhow::Error>>,
+}
+
+impl Iterator for CombinedTrack<'_> {
+    type Item = BedGraph<f64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let chrom = *self.chroms.first()?;
+            let readers = &mut self.readers;
+            let chrom_sizes = self.chrom_sizes;
+            match self
+                .sweep
+                .next(self.op, |t| readers[t].next_interval(chrom, chrom_sizes))
+            {
+                Err(e) => {
+                    self.error.borrow_mut().get_or_insert(e);
+                    self.chroms.clear();
+                    return None;
+                }
+                Ok(Some(x)) => match self.last.as_mut() {
+                    Some(last) if last.1 == x.0 && last.2 == x.2 => last.1 = x.1,
+                    _ => {
+                        if let Some((s, e, v)) = self.last.replace(x) {
+                            return Some(BedGraph::new(chrom, s, e, v));
+                        }
+                    }
+                },
+                Ok(None) => {
+                    self.chroms.remove(0);
+                    self.sweep = Sweep::new(self.readers.len());
+                    if let Some((s, e, v)) = self.last.take() {
+                        return Some(BedGraph::new(chrom, s, e, v));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Combine bedGraph or bigWig tracks (bigWig files are recognized by the
+/// ".bw" or ".bigwig" extension) and write the result. The tracks are merged
+/// as they are read, holding only the current interval of each track.
+/// BedGraph tracks must be sorted in the order of `chrom_sizes`; records on
+/// other chromosomes are skipped.
+pub fn combine_tracks<P: AsRef<Path>>(
+    tracks: &[P],
+    chrom_sizes: &ChromSizes,
+    op: TrackOp,
+    output: P,
+    format: CoverageOutputFormat,
+    compression: Option<Compression>,
+    compression_level: Option<u32>,
+) -> Result<()> {
+    let readers = tracks
+        .iter()
+        .map(|x| TrackReader::open(x.as_ref()))
+        .collect::<Result<Vec<_>>>()?;
+    let error = RefCell::new(None);
+    let records = CombinedTrack {
+        sweep: Sweep::new(readers.len()),
+        readers,
+        chrom_sizes,
+        chroms: chrom_sizes.into_iter().map(|(k, _)| k.as_str()).collect(),
+        op,
+        last: None,
+        error: &error,
+    };
+
+    match format {
+        CoverageOutputFormat::BedGraph => {
+            let mut writer = open_file_for_write(&output, compression, compression_level)?;
+            for x in records {
+                writeln!(writer, "{}", x)?;
+            }
+            writer.finish()?;
+        }
+        CoverageOutputFormat::BigWig => {
+            create_bigwig_from_bedgraph(records, chrom_sizes, output.as_ref())?;
+        }
+    }
+    match error.into_inner() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Summarize the sorted, non-overlapping intervals of a track in the region
+/// from `start` to `end`. Bases not covered by the track count as 0; the sum
+/// is the integral of the signal over the region.
+fn summarize_region(intervals: &[Interval], start: u64, end: u64, op: TrackOp) -> f64 {
+    let mut sum = 0.0;
+    let mut covered = 0;
+    let mut max = f64::NEG_INFINITY;
+    let mut min = f64::INFINITY;
+    for (s, e, v) in intervals.iter().take_while(|x| x.0 < end) {
+        let len = (*e).min(end).saturating_sub((*s).max(start));
+        if len > 0 {
+            sum += v * len as f64;
+            covered += len;
+            max = max.max(*v);
+            min = min.min(*v);
+        }
+    }
+    if covered < end - start {
+        max = max.max(0.0);
+        min = min.min(0.0);
+    }
+    match op {
+        TrackOp::Mean => sum / (end - start).max(1) as f64,
+        TrackOp::Sum => sum,
+        TrackOp::Max => max,
+        TrackOp::Min => min,
+    }
+}
+
+/// Summarize bedGraph or bigWig tracks (bigWig files are recognized by the
+/// ".bw" or ".bigwig" extension) in every region, e.g., the tiles or peaks of
+/// a count matrix. Returns one vector per track, in the order of `regions`.
+/// The tracks are processed in parallel, one chromosome at a time. BedGraph
+/// tracks must be sorted in the order in which the chromosomes first appear
+/// in `regions`; records on other chromosomes are skipped.
+pub fn region_signal<P: AsRef<Path> + Sync>(
+    tracks: &[P],
+    regions: &[GenomicRange],
+    op: TrackOp,
+    num_threads: Option<usize>,
+) -> Result<Vec<Vec<f64>>> {
+    // The regions of every chromosome, sorted by start.
+    let mut by_chrom: IndexMap<&str, Vec<usize>> = IndexMap::new();
+    regions.iter().enumerate().for_each(|(i, x)| {
+        by_chrom.entry(x.chrom()).or_default().push(i);
+    });
+    by_chrom
+        .values_mut()
+        .for_each(|idx| idx.sort_by_key(|i| regions[*i].start()));
+    let chrom_order: ChromSizes = by_chrom.keys().map(|x| (*x, 0)).collect();
+
+    crate::config::install(num_threads, || {
+        tracks
+            .par_iter()
+            .map(|track| {
+                let mut reader = TrackReader::open(track.as_ref())?;
+                let mut result = vec![0.0; regions.len()];
+                for (chrom, idx) in &by_chrom {
+                    let intervals = reader
+                        .read_chrom(chrom, &chrom_order)
+                        .with_context(|| format!("failed to read {}", track.as_ref().display()))?;
+                    // Regions are sorted by start, so intervals ending before the
+                    // start of a region also end before the following regions.
+                    let mut first = 0;
+                    for i in idx {
+                        let region = &regions[*i];
+                        while first < intervals.len() && intervals[first].1 <= region.start() {
+                            first += 1;
+                        }
+                        result[*i] =
+                            summarize_region(&intervals[first..], region.start(), region.end(), op);
+                    }
+                }
+                Ok(result)
+            })
+            .collect()
+    })
+}
+
+/// Correlation of every track with every reference track, computed over the
+/// bins of the genome, e.g., to check the identity of the clusters against
+/// bulk ATAC-seq or ChIP-seq of sorted cell types. The value of a bin is the
+/// mean signal over its bases. If `skip_zeros` is true, bins without signal
+/// in any of the tracks are left out. Returns a matrix of shape
+/// `n_tracks` x `n_references`.
+pub fn track_correlation<P: AsRef<Path> + Sync>(
+    tracks: &[P],
+    references: &[P],
+    chrom_sizes: &ChromSizes,
+    bin_size: u64,
+    method: CorrelationMethod,
+    skip_zeros: bool,
+    num_threads: Option<usize>,
+) -> Result<Array2<f64>> {
+    if bin_size == 0 {
+        bail!("bin size must be positive");
+    }
+    let bins: Vec<GenomicRange> = chrom_sizes
+        .into_iter()
+        .flat_map(|(chrom, size)| {
+            (0..*size)
+                .step_by(bin_size as usize)
+                .map(move |start| GenomicRange::new(chrom, start, (start + bin_size).min(*size)))
+        })
+        .collect();
+    let all: Vec<&Path> = tracks
+        .iter()
+        .chain(references)
+        .map(|x| x.as_ref())
+        .collect();
+    let signal = region_signal(&all, &bins, TrackOp::Mean, num_threads)?;
+    let mut signal = Array2::from_shape_vec(
+        (all.len(), bins.len()),
+        signal.into_iter().flatten().collect(),
+    )?;
+    if skip_zeros {
+        let keep: Vec<usize> = signal
+            .columns()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, x)| x.iter().any(|v| *v != 0.0))
+            .map(|(i, _)| i)
+            .collect();
+        signal = signal.select(Axis(1), &keep);
+    }
+    if signal.ncols() < 2 {
+        bail!("fewer than two bins have signal, the correlation is undefined");
+    }
+    let references = signal.slice_axis(Axis(0), (tracks.len()..).into()).to_owned();
+    let tracks = signal.slice_axis(Axis(0), (..tracks.len()).into()).to_owned();
+    Ok(match method {
+        CorrelationMethod::Pearson => pearson2(tracks, references),
+        CorrelationMethod::Spearman => spearman2(tracks, references),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Combine the sorted, non-overlapping intervals of several tracks on one
+    /// chromosome. Only positions covered by at least one track are reported, and
+    /// adjacent intervals with the same value are merged.
+    fn combine_intervals(tracks: &[Vec<Interval>], op: TrackOp) -> Vec<Interval> {
+        let mut iters: Vec<_> = tracks.iter().map(|x| x.iter().copied()).collect();
+        let mut sweep = Sweep::new(tracks.len());
+        let mut result: Vec<Interval> = Vec::new();
+        while let Some(x) = sweep.next(op, |t| Ok(iters[t].next())).unwrap() {
+            match result.last_mut() {
+                Some(last) if last.1 == x.0 && last.2 == x.2 => last.1 = x.1,
+                _ => result.push(x),
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn test_combine_intervals() {
+        let a = vec![(0, 10, 1.0), (10, 20, 3.0)];
+        let b = vec![(5, 15, 1.0), (30, 40, 2.0)];
+        assert_eq!(
+            combine_intervals(&[a.clone(), b.clone()], TrackOp::Sum),
+            vec![(0, 5, 1.0), (5, 10, 2.0), (10, 15, 4.0), (15, 20, 3.0), (30, 40, 2.0)]
+        );
+        assert_eq!(
+            combine_intervals(&[a.clone(), b.clone()], TrackOp::Max),
+            vec![(0, 10, 1.0), (10, 20, 3.0), (30, 40, 2.0)]
+        );
+        assert_eq!(
+            combine_intervals(&[a, b], TrackOp::Min),
+            vec![(0, 5, 0.0), (5, 15, 1.0), (15, 20, 0.0), (30, 40, 0.0)]
+        );
+    }
+
+    #[test]
+    fn test_region_signal() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.bedgraph");
+        std::fs::write(&a, "chr2\t0\t10\t2\nchr2\t10\t20\t4\nchr1\t5\t10\t1\n").unwrap();
+        let regions: Vec<GenomicRange> = ["chr2:0-20", "chr2:5-15", "chr1:0-10", "chr3:0-10"]
+            .iter()
+            .map(|x| x.parse().unwrap())
+            .collect();
+        let mean = region_signal(&[a.clone()], &regions, TrackOp::Mean, None).unwrap();
+        assert_eq!(mean, vec![vec![3.0, 3.0, 0.5, 0.0]]);
+        let max = region_signal(&[a.clone()], &regions, TrackOp::Max, None).unwrap();
+        assert_eq!(max, vec![vec![4.0, 4.0, 1.0, 0.0]]);
+        let min = region_signal(&[a], &regions, TrackOp::Min, None).unwrap();
+        assert_eq!(min, vec![vec![2.0, 2.0, 0.0, 0.0]]);
+    }
+
+    #[test]
+    fn test_track_correlation() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.bedgraph");
+        let b = dir.path().join("b.bedgraph");
+        let c = dir.path().join("c.bedgraph");
+        std::fs::write(&a, "chr1\t0\t10\t1\nchr1\t10\t20\t2\nchr1\t20\t30\t3\n").unwrap();
+        std::fs::write(&b, "chr1\t0\t10\t2\nchr1\t10\t20\t4\nchr1\t20\t30\t6\n").unwrap();
+        std::fs::write(&c, "chr1\t0\t10\t3\nchr1\t10\t20\t2\nchr1\t20\t30\t1\n").unwrap();
+        let chrom_sizes: ChromSizes = [("chr1", 100)].into_iter().collect();
+        let cor = |skip_zeros| {
+            track_correlation(
+                &[a.clone()],
+                &[b.clone(), c.clone()],
+                &chrom_sizes,
+                10,
+                CorrelationMethod::Pearson,
+                skip_zeros,
+                None,
+            )
+            .unwrap()
+        };
+        let skipped = cor(true);
+        assert_eq!(skipped.shape(), &[1, 2]);
+        assert!((skipped[[0, 0]] - 1.0).abs() < 1e-9);
+        assert!((skipped[[0, 1]] + 1.0).abs() < 1e-9);
+        // The empty bins make the tracks positively correlated.
+        assert!(cor(false)[[0, 1]] > 0.0);
+    }
+
+    #[test]
+    fn test_combine_bedgraph() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.bedgraph");
+        let b = dir.path().join("b.bedgraph");
+        std::fs::write(&a, "chr1\t0\t10\t2\nchr2\t0\t10\t4\n").unwrap();
+        std::fs::write(&b, "track type=bedGraph\nchr1\t0\t10\t4\nchrUn\t0\t5\t1\n").unwrap();
+        let out = dir.path().join("mean.bedgraph");
+        let chrom_sizes: ChromSizes = [("chr1", 100), ("chr2", 100)].into_iter().collect();
+        combine_tracks(
+            &[a.clone(), b.clone()],
+            &chrom_sizes,
+            TrackOp::Mean,
+            out.clone(),
+            CoverageOutputFormat::BedGraph,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&out).unwrap(),
+            "chr1\t0\t10\t3\nchr2\t0\t10\t2\n"
+        );
+
+        let unsorted: ChromSizes = [("chr2", 100), ("chr1", 100)].into_iter().collect();
+        assert!(combine_tracks(
+            &[a, b],
+            &unsorted,
+            TrackOp::Mean,
+            out,
+            CoverageOutputFormat::BedGraph,
+            None,
+            None
+        )
+        .is_err());
+    }
+}
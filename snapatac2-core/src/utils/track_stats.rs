@@ -0,0 +1,197 @@
+//! Summary statistics of coverage tracks.
+//!
+//! Tracks are summarized while they are written, without keeping their values:
+//! quantiles are estimated with a [`QuantileSketch`], a DDSketch (Masson et
+//! al., 2019) whose estimates are within a fixed relative error of the exact
+//! quantiles, using memory logarithmic in the range of the values.
+
+use bed_utils::bed::{BEDLike, BedGraph};
+use std::collections::BTreeMap;
+
+/// A streaming sketch of weighted quantiles with bounded relative error.
+#[derive(Debug, Clone)]
+pub struct QuantileSketch {
+    ln_gamma: f64,
+    gamma: f64,
+    /// Buckets of positive values, indexed by `ceil(log_gamma(x))`.
+    positive: BTreeMap<i32, f64>,
+    /// Buckets of negative values, indexed by `ceil(log_gamma(-x))`.
+    negative: BTreeMap<i32, f64>,
+    zero: f64,
+    total: f64,
+}
+
+impl QuantileSketch {
+    /// Create a sketch whose quantile estimates are within `relative_accuracy`
+    /// of the exact values, e.g., 0.01 for 1%.
+    pub fn new(relative_accuracy: f64) -> Self {
+        let gamma = (1.0 + relative_accuracy) / (1.0 - relative_accuracy);
+        Self {
+            ln_gamma: gamma.ln(),
+            gamma,
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero: 0.0,
+            total: 0.0,
+        }
+    }
+
+    fn index(&self, x: f64) -> i32 {
+        (x.ln() / self.ln_gamma).ceil() as i32
+    }
+
+    fn value(&self, index: i32) -> f64 {
+        2.0 * self.gamma.powi(index) / (self.gamma + 1.0)
+    }
+
+    /// Add `value` with the given weight, e.g., the number of bases it covers.
+    pub fn insert(&mut self, value: f64, weight: f64) {
+        if value > 0.0 {
+            *self.positive.entry(self.index(value)).or_insert(0.0) += weight;
+        } else if value < 0.0 {
+            *self.negative.entry(self.index(-value)).or_insert(0.0) += weight;
+        } else {
+            self.zero += weight;
+        }
+        self.total += weight;
+    }
+
+    /// Total weight of the values.
+    pub fn total(&self) -> f64 {
+        self.total
+    }
+
+    /// Estimate the `q`-th quantile, with `q` in [0, 1]. Returns `None` if
+    /// the sketch is empty.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.total <= 0.0 {
+            return None;
+        }
+        let rank = q.clamp(0.0, 1.0) * self.total;
+        let mut acc = 0.0;
+        for (i, w) in self.negative.iter().rev() {
+            acc += w;
+            if acc >= rank {
+                return Some(-self.value(*i));
+            }
+        }
+        acc += self.zero;
+        if acc >= rank && self.zero > 0.0 {
+            return Some(0.0);
+        }
+        for (i, w) in self.positive.iter() {
+            acc += w;
+            if acc >= rank {
+                return Some(self.value(*i));
+            }
+        }
+        self.positive
+            .keys()
+            .next_back()
+            .map(|i| self.value(*i))
+            .or(Some(0.0))
+    }
+}
+
+/// Summary statistics of a coverage track.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackStats {
+    /// Mean value over the whole genome, uncovered bases counting as 0.
+    pub mean: f64,
+    /// Maximum value.
+    pub max: f64,
+    /// Fraction of the genome with a non-zero value.
+    pub coverage_fraction: f64,
+    /// `(q, value)` pairs: quantiles of the values of the covered bases.
+    pub quantiles: Vec<(f64, f64)>,
+}
+
+/// Accumulate the statistics of a track from its bedGraph records.
+#[derive(Debug, Clone)]
+pub struct TrackSummary {
+    sketch: QuantileSketch,
+    sum: f64,
+    max: f64,
+    covered: u64,
+}
+
+impl Default for TrackSummary {
+    fn default() -> Self {
+        Self {
+            sketch: QuantileSketch::new(0.01),
+            sum: 0.0,
+            max: f64::NEG_INFINITY,
+            covered: 0,
+        }
+    }
+}
+
+impl TrackSummary {
+    pub fn update(&mut self, record: &BedGraph<f64>) {
+        let len = record.len();
+        if len == 0 || record.value == 0.0 {
+            return;
+        }
+        self.sketch.insert(record.value, len as f64);
+        self.sum += record.value * len as f64;
+        self.max = self.max.max(record.value);
+        self.covered += len;
+    }
+
+    /// Compute the statistics of a track spanning `genome_size` bases.
+    pub fn finish(&self, genome_size: u64, quantiles: &[f64]) -> TrackStats {
+        let genome_size = genome_size.max(self.covered).max(1) as f64;
+        TrackStats {
+            mean: self.sum / genome_size,
+            max: if self.covered > 0 { self.max.max(0.0) } else { 0.0 },
+            coverage_fraction: self.covered as f64 / genome_size,
+            quantiles: quantiles
+                .iter()
+                .map(|q| (*q, self.sketch.quantile(*q).unwrap_or(0.0)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantile_sketch() {
+        let mut sketch = QuantileSketch::new(0.01);
+        (1..=10000).for_each(|x| sketch.insert(x as f64, 1.0));
+        for q in [0.1, 0.5, 0.9, 0.99] {
+            let expected = q * 10000.0;
+            let estimate = sketch.quantile(q).unwrap();
+            assert!((estimate - expected).abs() <= 0.011 * expected, "{q}: {estimate}");
+        }
+        assert!(QuantileSketch::new(0.01).quantile(0.5).is_none());
+
+        let mut sketch = QuantileSketch::new(0.01);
+        sketch.insert(-2.0, 1.0);
+        sketch.insert(0.0, 1.0);
+        sketch.insert(3.0, 2.0);
+        assert!((sketch.quantile(0.0).unwrap() + 2.0).abs() < 0.05);
+        assert_eq!(sketch.quantile(0.5).unwrap(), 0.0);
+        assert!((sketch.quantile(1.0).unwrap() - 3.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_track_summary() {
+        let mut summary = TrackSummary::default();
+        [
+            BedGraph::new("chr1", 0, 100, 1.0),
+            BedGraph::new("chr1", 100, 200, 0.0),
+            BedGraph::new("chr1", 200, 250, 4.0),
+        ]
+        .iter()
+        .for_each(|x| summary.update(x));
+        let stats = summary.finish(1000, &[0.5, 1.0]);
+        assert!((stats.mean - 0.3).abs() < 1e-12);
+        assert_eq!(stats.max, 4.0);
+        assert!((stats.coverage_fraction - 0.15).abs() < 1e-12);
+        assert!((stats.quantiles[0].1 - 1.0).abs() < 0.02);
+        assert!((stats.quantiles[1].1 - 4.0).abs() < 0.08);
+    }
+}
@@ -0,0 +1,475 @@
+//! Checks of the invariants that the algorithms in this crate rely on.
+//!
+//! AnnData objects produced by `import_fragments` and friends are consistent
+//! by construction, but objects edited by hand or produced by other tools may
+//! not be. [`validate`] inspects an object without modifying it and returns a
+//! report listing every problem found, instead of failing at the first one.
+
+use crate::feature_count::{
+    fragment_key, CompressedFragmentIter, SnapData, FRAGMENT_PAIRED, FRAGMENT_PAIRED_V2,
+    FRAGMENT_SINGLE, FRAGMENT_SINGLE_V2,
+};
+use crate::genome::{ChromSizes, GenomeBaseIndex};
+use crate::recovery;
+
+use anndata::backend::{DataType, ScalarType};
+use anndata::data::CsrNonCanonical;
+use anndata::{AnnDataOp, ArrayElemOp, AxisArraysOp};
+use anyhow::Result;
+use bed_utils::bed::GenomicRange;
+use serde::Serialize;
+use std::str::FromStr;
+
+/// Maximum number of examples included in a message.
+const MAX_EXAMPLES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The object can be used, but the results may not be what the user expects.
+    Warning,
+    /// The object violates an invariant and some functions will fail or
+    /// return wrong results.
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Issue {
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ValidationReport {
+    /// Names of the checks that were performed.
+    pub checks: Vec<&'static str>,
+    pub issues: Vec<Issue>,
+}
+
+impl ValidationReport {
+    /// Whether no errors were found. Warnings are allowed.
+    pub fn is_valid(&self) -> bool {
+        self.issues.iter().all(|x| x.severity != Severity::Error)
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Issue> {
+        self.issues.iter().filter(|x| x.severity == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Issue> {
+        self.issues.iter().filter(|x| x.severity == Severity::Warning)
+    }
+
+    fn error(&mut self, check: &'static str, message: impl Into<String>) {
+        self.issues.push(Issue { check, severity: Severity::Error, message: message.into() });
+    }
+
+    fn warn(&mut self, check: &'static str, message: impl Into<String>) {
+        self.issues.push(Issue { check, severity: Severity::Warning, message: message.into() });
+    }
+
+    fn run(&mut self, check: &'static str) {
+        self.checks.push(check);
+    }
+}
+
+/// Audit an AnnData object. The following checks are performed:
+///
+/// - `reference_sequences`: `.uns["reference_sequences"]` is readable and
+///   chromosome sizes are positive.
+/// - `reference_genome`: chromosome sizes agree with `reference`, if given.
+/// - `fragment_keys`: fragments are stored under exactly one of the
+///   `.obsm` keys, with the expected dtype and shape.
+/// - `fragment_order`: stored fragments are sorted by position within each
+///   cell and lie within chromosome boundaries.
+/// - `var_names`: genomic regions used as feature names are located on
+///   known chromosomes and within their boundaries.
+/// - `dtype`: the `.X` matrix has a numeric dtype.
+/// - `pending_writes`: no element was left partly written by an interrupted
+///   operation, see [`crate::recovery`].
+///
+/// The fragment checks apply to the fragments of `modality`, see
+/// [`fragment_key`].
+pub fn validate<A: SnapData>(
+    adata: &A,
+    reference: Option<&ChromSizes>,
+    chunk_size: usize,
+    modality: Option<&str>,
+) -> Result<ValidationReport> {
+    let mut report = ValidationReport::default();
+
+    report.run("reference_sequences");
+    let chrom_sizes = match adata.read_chrom_sizes() {
+        Ok(chrom_sizes) => {
+            check_chrom_sizes(&chrom_sizes, &mut report);
+            Some(chrom_sizes)
+        }
+        Err(e) => {
+            report.warn("reference_sequences", format!("cannot read chromosome sizes: {}", e));
+            None
+        }
+    };
+
+    if let (Some(reference), Some(chrom_sizes)) = (reference, chrom_sizes.as_ref()) {
+        report.run("reference_genome");
+        check_reference(chrom_sizes, reference, &mut report);
+    }
+
+    report.run("fragment_keys");
+    let fragments_ok = check_fragment_keys(adata, chrom_sizes.as_ref(), modality, &mut report);
+
+    if fragments_ok && chrom_sizes.is_some() {
+        report.run("fragment_order");
+        check_fragment_order(adata, chunk_size, modality, &mut report)?;
+    }
+
+    if let Some(chrom_sizes) = chrom_sizes.as_ref() {
+        report.run("var_names");
+        check_var_names(adata, chrom_sizes, &mut report);
+    }
+
+    report.run("dtype");
+    if let Some(scalar) = adata.x().dtype().and_then(|x| x.scalar_type()) {
+        if matches!(scalar, ScalarType::String | ScalarType::Bool) {
+            report.error("dtype", format!(".X has a non-numeric dtype: {:?}", scalar));
+        }
+    }
+
+    report.run("pending_writes");
+    match recovery::pending(adata) {
+        Ok(elements) => elements.iter().for_each(|x| {
+            report.error(
+                "pending_writes",
+                format!("'{}' was not completely written, run `repair` to remove it", x),
+            )
+        }),
+        Err(e) => report.warn("pending_writes", format!("cannot read the pending writes: {}", e)),
+    }
+
+    Ok(report)
+}
+
+fn check_chrom_sizes(chrom_sizes: &ChromSizes, report: &mut ValidationReport) {
+    if chrom_sizes.into_iter().next().is_none() {
+        report.error("reference_sequences", "no chromosomes are defined");
+    }
+    let empty: Vec<_> = chrom_sizes
+        .into_iter()
+        .filter(|(_, size)| **size == 0)
+        .map(|(chr, _)| chr.as_str())
+        .collect();
+    if !empty.is_empty() {
+        report.error(
+            "reference_sequences",
+            format!("chromosomes with zero length: {}", examples(&empty)),
+        );
+    }
+}
+
+fn check_reference(chrom_sizes: &ChromSizes, reference: &ChromSizes, report: &mut ValidationReport) {
+    let mut missing = Vec::new();
+    let mut mismatched = Vec::new();
+    for (chr, size) in chrom_sizes {
+        match reference.get(chr) {
+            None => missing.push(chr.clone()),
+            Some(s) if s != *size => mismatched.push(format!("{} ({} vs {})", chr, size, s)),
+            _ => {}
+        }
+    }
+    if !missing.is_empty() {
+        report.error(
+            "reference_genome",
+            format!(
+                "{} chromosomes are absent from the reference genome: {}",
+                missing.len(),
+                examples(&missing)
+            ),
+        );
+    }
+    if !mismatched.is_empty() {
+        report.error(
+            "reference_genome",
+            format!(
+                "{} chromosomes have different sizes in the reference genome: {}",
+                mismatched.len(),
+                examples(&mismatched)
+            ),
+        );
+    }
+}
+
+/// Returns true if the fragments can be read.
+fn check_fragment_keys<A: SnapData>(
+    adata: &A,
+    chrom_sizes: Option<&ChromSizes>,
+    modality: Option<&str>,
+    report: &mut ValidationReport,
+) -> bool {
+    let obsm = adata.obsm();
+    let keys = obsm.keys();
+    let key = |x: &str| fragment_key(x, modality);
+    let has = |x: &str| keys.contains(&key(x));
+    let has_single = has(FRAGMENT_SINGLE);
+    let has_paired = has(FRAGMENT_PAIRED);
+    if has_single && has_paired {
+        report.warn(
+            "fragment_keys",
+            format!(
+                "both '{}' and '{}' are present in '.obsm', only '{}' will be used",
+                key(FRAGMENT_SINGLE),
+                key(FRAGMENT_PAIRED),
+                key(FRAGMENT_SINGLE)
+            ),
+        );
+    }
+
+    let has_v2 = has(FRAGMENT_SINGLE_V2) || has(FRAGMENT_PAIRED_V2);
+    if (has_single || has_paired) && has_v2 {
+        report.warn(
+            "fragment_keys",
+            "fragments are stored in both the v1 and v2 formats, only the v1 format will be used",
+        );
+    }
+
+    let mut ok = true;
+    for (base, expected) in [
+        (FRAGMENT_SINGLE, ScalarType::I32),
+        (FRAGMENT_PAIRED, ScalarType::U32),
+        (FRAGMENT_SINGLE_V2, ScalarType::U64),
+        (FRAGMENT_PAIRED_V2, ScalarType::U64),
+    ] {
+        let key = key(base);
+        let Some(elem) = obsm.get(&key) else { continue };
+        match elem.dtype() {
+            Some(DataType::CsrMatrix(ty)) if ty == expected => {}
+            ty => {
+                ok = false;
+                report.error(
+                    "fragment_keys",
+                    format!(
+                        "'.obsm[\"{}\"]' must be a CSR matrix of {:?}, found: {:?}",
+                        key, expected, ty
+                    ),
+                );
+            }
+        }
+        if let Some(shape) = elem.shape() {
+            if shape[0] != adata.n_obs() {
+                ok = false;
+                report.error(
+                    "fragment_keys",
+                    format!(
+                        "'.obsm[\"{}\"]' has {} rows, but there are {} cells",
+                        key, shape[0], adata.n_obs()
+                    ),
+                );
+            }
+            if let Some(chrom_sizes) = chrom_sizes {
+                // Columns are base positions in the v1 format and chromosomes
+                // in the v2 format.
+                let (n_cols, what) = if expected == ScalarType::U64 {
+                    (chrom_sizes.into_iter().count(), "number of chromosomes")
+                } else {
+                    (chrom_sizes.total_size() as usize, "genome size")
+                };
+                if shape[1] != n_cols {
+                    ok = false;
+                    report.error(
+                        "fragment_keys",
+                        format!(
+                            "'.obsm[\"{}\"]' has {} columns, but the {} is {}",
+                            key, shape[1], what, n_cols
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    // AnnDataSet stores fragments in its components, so we also try to read
+    // them through the generic interface.
+    if ok && adata.get_modality_fragment_iter(1, modality).is_err() {
+        report.warn("fragment_keys", "no fragments are stored in '.obsm'");
+        ok = false;
+    }
+    ok
+}
+
+#[derive(Default)]
+struct FragmentStats {
+    n_unsorted_cells: usize,
+    n_out_of_bounds: usize,
+    n_zero: usize,
+    n_invalid_columns: usize,
+    n_cells: usize,
+}
+
+fn check_fragment_order<A: SnapData>(
+    adata: &A,
+    chunk_size: usize,
+    modality: Option<&str>,
+    report: &mut ValidationReport,
+) -> Result<()> {
+    let data = adata.get_modality_fragment_iter(chunk_size, modality)?;
+    let index = data.get_gindex();
+    let mut stats = FragmentStats::default();
+    match data.into_inner() {
+        CompressedFragmentIter::FragmentSingle(iter) => iter
+            .for_each(|(mat, _, _)| check_fragment_chunk(&mat, &index, false, &mut stats)),
+        CompressedFragmentIter::FragmentPaired(iter) => iter
+            .for_each(|(mat, _, _)| check_fragment_chunk(&mat, &index, true, &mut stats)),
+    }
+
+    if stats.n_cells != adata.n_obs() {
+        report.error(
+            "fragment_order",
+            format!("fragments are stored for {} cells, expected {}", stats.n_cells, adata.n_obs()),
+        );
+    }
+    if stats.n_invalid_columns > 0 {
+        report.error(
+            "fragment_order",
+            format!("{} fragments are located beyond the end of the genome", stats.n_invalid_columns),
+        );
+    }
+    if stats.n_unsorted_cells > 0 {
+        report.error(
+            "fragment_order",
+            format!("fragments of {} cells are not sorted by position", stats.n_unsorted_cells),
+        );
+    }
+    if stats.n_out_of_bounds > 0 {
+        report.error(
+            "fragment_order",
+            format!("{} fragments cross chromosome boundaries", stats.n_out_of_bounds),
+        );
+    }
+    if stats.n_zero > 0 {
+        report.error("fragment_order", format!("{} fragments have zero length", stats.n_zero));
+    }
+    Ok(())
+}
+
+fn check_fragment_chunk<T: Copy + Into<i64>>(
+    mat: &CsrNonCanonical<T>,
+    index: &GenomeBaseIndex,
+    paired: bool,
+    stats: &mut FragmentStats,
+) {
+    let row_offsets = mat.row_offsets();
+    let col_indices = mat.col_indices();
+    let values = mat.values();
+    let genome_size = index.len();
+    stats.n_cells += row_offsets.len().saturating_sub(1);
+    row_offsets.windows(2).for_each(|w| {
+        let cols = &col_indices[w[0]..w[1]];
+        if cols.windows(2).any(|x| x[0] > x[1]) {
+            stats.n_unsorted_cells += 1;
+        }
+        cols.iter().zip(&values[w[0]..w[1]]).for_each(|(col, v)| {
+            let size: i64 = (*v).into();
+            if *col >= genome_size {
+                stats.n_invalid_columns += 1;
+                return;
+            }
+            if size == 0 {
+                stats.n_zero += 1;
+                return;
+            }
+            let (chrom, pos) = index.get_position(*col);
+            let chrom_size = index.chrom_size(chrom).unwrap() as i64;
+            let pos = pos as i64;
+            let (start, end) = if paired || size > 0 {
+                (pos, pos + size)
+            } else {
+                (pos + 1 + size, pos + 1)
+            };
+            if start < 0 || end > chrom_size {
+                stats.n_out_of_bounds += 1;
+            }
+        });
+    });
+}
+
+fn check_var_names<A: AnnDataOp>(adata: &A, chrom_sizes: &ChromSizes, report: &mut ValidationReport) {
+    let names = adata.var_names().into_vec();
+    let regions: Vec<_> = names.iter().map(|x| GenomicRange::from_str(x).ok()).collect();
+    // Feature names are not genomic regions, e.g., gene names.
+    if regions.is_empty() || regions.iter().any(|x| x.is_none()) {
+        return;
+    }
+    let mut unknown = Vec::new();
+    let mut out_of_bounds = Vec::new();
+    regions.into_iter().flatten().zip(names.iter()).for_each(|(region, name)| {
+        match chrom_sizes.get(region.chrom()) {
+            None => unknown.push(name.as_str()),
+            Some(size) if region.end() > size => out_of_bounds.push(name.as_str()),
+            _ => {}
+        }
+    });
+    if !unknown.is_empty() {
+        report.error(
+            "var_names",
+            format!(
+                "{} features are located on unknown chromosomes: {}",
+                unknown.len(),
+                examples(&unknown)
+            ),
+        );
+    }
+    if !out_of_bounds.is_empty() {
+        report.error(
+            "var_names",
+            format!(
+                "{} features extend beyond the end of the chromosome: {}",
+                out_of_bounds.len(),
+                examples(&out_of_bounds)
+            ),
+        );
+    }
+}
+
+fn examples<T: std::fmt::Display>(items: &[T]) -> String {
+    let mut s = items.iter().take(MAX_EXAMPLES).map(|x| x.to_string()).collect::<Vec<_>>().join(", ");
+    if items.len() > MAX_EXAMPLES {
+        s.push_str(", ...");
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_reference() {
+        let data: ChromSizes = [("chr1", 100), ("chr2", 200), ("chrUn", 10)].into_iter().collect();
+        let reference: ChromSizes = [("chr1", 100), ("chr2", 250), ("chr3", 10)].into_iter().collect();
+        let mut report = ValidationReport::default();
+        check_chrom_sizes(&data, &mut report);
+        assert!(report.is_valid());
+        check_reference(&data, &reference, &mut report);
+        assert_eq!(report.errors().count(), 2);
+        assert!(report.issues[0].message.contains("chrUn"));
+        assert!(report.issues[1].message.contains("chr2 (200 vs 250)"));
+    }
+
+    #[test]
+    fn test_check_fragment_chunk() {
+        let index = GenomeBaseIndex::new(&[("chr1", 100), ("chr2", 100)].into_iter().collect());
+        // Cell 1: sorted, valid. Cell 2: unsorted and crossing the end of chr1.
+        let mat = CsrNonCanonical::from_csr_data(
+            2,
+            200,
+            vec![0, 2, 4],
+            vec![10, 120, 95, 20],
+            vec![10u32, 30, 10, 10],
+        );
+        let mut stats = FragmentStats::default();
+        check_fragment_chunk(&mat, &index, true, &mut stats);
+        assert_eq!(stats.n_cells, 2);
+        assert_eq!(stats.n_unsorted_cells, 1);
+        assert_eq!(stats.n_out_of_bounds, 1);
+    }
+}
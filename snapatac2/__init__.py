@@ -1,5 +1,8 @@
 from sys import stderr
 from ._io import read_10x_mtx
+from ._validation import validate, repair
+from ._provenance import history, diff_history
+from ._pipeline import run_recipe
 from importlib.metadata import version
 from . import preprocessing as pp
 from . import tools as tl
@@ -9,6 +12,10 @@ from . import export as ex
 
 from snapatac2._snapatac2 import (
     set_write_options, get_write_options,
+    set_num_threads, get_num_threads, set_memory_limit, get_memory_limit,
+    set_chunk_size, get_chunk_size, set_chrom_filter, get_chrom_filter,
+    set_cell_mask, get_cell_mask, set_missing_chrom_policy, get_missing_chrom_policy,
+    set_out_of_bounds_policy, get_out_of_bounds_policy,
     AnnData, AnnDataSet, PyDNAMotif, PyDNAMotifScanner, PyDNAMotifTest, concat,
     read, read_mtx, read_dataset, read_motifs,
 )
@@ -16,8 +23,12 @@ from snapatac2._snapatac2 import (
 __version__ = version("snapatac2")
 
 __all__ = [
-    "pp", "tl", "pl", "ex", "metrics",
+    "pp", "tl", "pl", "ex", "metrics", "validate", "repair", "history", "diff_history", "run_recipe",
     "set_write_options", "get_write_options",
+    "set_num_threads", "get_num_threads", "set_memory_limit", "get_memory_limit",
+    "set_chunk_size", "get_chunk_size", "set_chrom_filter", "get_chrom_filter",
+    "set_cell_mask", "get_cell_mask", "set_missing_chrom_policy", "get_missing_chrom_policy",
+    "set_out_of_bounds_policy", "get_out_of_bounds_policy",
     "AnnData", "AnnDataSet", "concat", "read", "read_mtx", "read_dataset", "read_10x_mtx", 
     "PyDNAMotif", "PyDNAMotifScanner", "PyDNAMotifTest", "read_motifs",
 ]
@@ -0,0 +1,45 @@
+"""Command line interface of SnapATAC2.
+
+Usage::
+
+    snapatac2 run recipe.toml [--no-resume] [--overwrite] [--n-jobs N]
+    snapatac2 sort-fragments fragments.tsv output.tsv.gz [--tempdir DIR]
+"""
+
+from __future__ import annotations
+
+import argparse
+
+def main(argv: list[str] | None = None) -> None:
+    parser = argparse.ArgumentParser(prog="snapatac2", description="SnapATAC2 command line interface.")
+    subparsers = parser.add_subparsers(dest="command", required=True)
+
+    run = subparsers.add_parser("run", help="Run an analysis described by a recipe file.")
+    run.add_argument("recipe", help="The recipe file (\".toml\" or \".json\").")
+    run.add_argument(
+        "--no-resume", dest="resume", action="store_false",
+        help="Do not resume from the checkpoints stored in the output file.",
+    )
+    run.add_argument("--overwrite", action="store_true", help="Overwrite an existing output file.")
+    run.add_argument("--n-jobs", type=int, default=None, help="Number of parallel jobs.")
+
+    sort = subparsers.add_parser(
+        "sort-fragments", help="Sort a fragment file by coordinate, bgzip and tabix-index it.",
+    )
+    sort.add_argument("fragment_file", help="The fragment file, plain text or compressed.")
+    sort.add_argument("output_file", help="The output file. The index is written to OUTPUT_FILE.tbi.")
+    sort.add_argument("--tempdir", default=None, help="Location to store temporary files.")
+
+    args = parser.parse_args(argv)
+
+    import snapatac2
+    if args.command == "run":
+        adata = snapatac2.run_recipe(
+            args.recipe, resume=args.resume, overwrite=args.overwrite, n_jobs=args.n_jobs,
+        )
+        adata.close()
+    elif args.command == "sort-fragments":
+        snapatac2.pp.sort_fragment_file(args.fragment_file, args.output_file, tempdir=args.tempdir)
+
+if __name__ == "__main__":
+    main()
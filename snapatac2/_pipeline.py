@@ -0,0 +1,188 @@
+from __future__ import annotations
+
+import json
+import logging
+from pathlib import Path
+
+import snapatac2
+import snapatac2._snapatac2 as internal
+
+def run_recipe(
+    recipe: Path | str | dict,
+    *,
+    resume: bool = True,
+    overwrite: bool = False,
+    n_jobs: int | None = None,
+) -> internal.AnnData:
+    """
+    Run a whole analysis described by a declarative recipe.
+
+    A recipe is a TOML or JSON document with an `input` section and a list of
+    `steps`, which are executed in order. The supported steps are "import",
+    "qc_filter", "tile_matrix", "select_features", "embed", "cluster" and
+    "export_tracks". Parameters that are omitted take their usual default values.
+    For example:
+
+    .. code-block:: toml
+
+        [input]
+        fragment_file = "fragments.tsv.gz"
+        output = "result.h5ad"
+        genome = "hg38"
+
+        [[steps]]
+        step = "import"
+
+        [[steps]]
+        step = "qc_filter"
+        min_counts = 1000
+        min_tsse = 5
+
+        [[steps]]
+        step = "tile_matrix"
+
+        [[steps]]
+        step = "select_features"
+
+        [[steps]]
+        step = "embed"
+        method = "spectral"
+
+        [[steps]]
+        step = "cluster"
+        resolution = 0.5
+
+        [[steps]]
+        step = "export_tracks"
+        out_dir = "tracks"
+
+    After each step, a checkpoint is written to `.uns["snapatac2_pipeline"]`
+    of the output file. If the run is interrupted, running the same recipe again
+    resumes from the first step that has not been completed. Changing the
+    parameters of a step re-runs that step and all subsequent steps.
+
+    A recipe file can also be run from the command line with
+    ``snapatac2 run recipe.toml``.
+
+    Parameters
+    ----------
+    recipe
+        A recipe file (".toml" or ".json"), or the recipe as a dictionary.
+    resume
+        Whether to resume from the checkpoints stored in the output file, if it exists.
+    overwrite
+        Whether to overwrite an existing output file when the run starts from
+        the first step, i.e., when `resume` is False or the file has no
+        checkpoints. A file with checkpoints whose "import" step differs from
+        the recipe is overwritten when resuming.
+    n_jobs
+        Number of parallel jobs used by the steps that support it.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
+
+    Returns
+    -------
+    AnnData
+        The output AnnData object, opened in backed mode.
+    """
+    if isinstance(recipe, dict):
+        recipe = json.loads(internal.parse_recipe(json.dumps(recipe), "json"))
+    else:
+        path = Path(recipe)
+        fmt = "toml" if path.suffix == ".toml" else "json"
+        recipe = json.loads(internal.parse_recipe(path.read_text(), fmt))
+
+    input = recipe['input']
+    genome = None
+    if input.get('genome') is not None:
+        genome = getattr(snapatac2.genome, input['genome'], None)
+        if not isinstance(genome, snapatac2.genome.Genome):
+            raise ValueError(f"Unknown genome: {input['genome']}")
+    chrom_sizes = genome if input.get('chrom_sizes') is None else input['chrom_sizes']
+
+    output = Path(input['output'])
+    adata = None
+    start = 0
+    if output.exists():
+        has_checkpoints = False
+        if resume:
+            adata = snapatac2.read(output)
+            has_checkpoints = len(internal.pipeline_completed_steps(adata)) > 0
+            start = internal.pipeline_resume_step(
+                adata, json.dumps({'input': input, 'steps': recipe['steps']})
+            )
+            # The imported data cannot be reused if the import step has changed.
+            if start == 0:
+                adata.close()
+                adata = None
+        if start == 0 and not has_checkpoints and not overwrite:
+            raise FileExistsError(
+                f"{output} exists and has no checkpoints of this recipe. "
+                "Use `overwrite=True` to overwrite it."
+            )
+
+    steps = recipe['steps']
+    for i, (step, key) in enumerate(zip(steps, recipe['keys'])):
+        name = step['step']
+        if i < start:
+            continue
+        logging.info(f"Running step {i + 1}/{len(recipe['steps'])}: {name}")
+        if name == 'import':
+            adata = snapatac2.pp.import_fragments(
+                input['fragment_file'],
+                chrom_sizes=chrom_sizes,
+                is_paired=input['is_paired'],
+                file=output,
+                min_num_fragments=step['min_num_fragments'],
+                sorted_by_barcode=step['sorted_by_barcode'],
+                chrM=step['chrm'],
+                whitelist=step['whitelist'],
+            )
+        elif name == 'qc_filter':
+            if step['min_tsse'] is not None or step['max_tsse'] is not None:
+                snapatac2.metrics.tsse(adata, genome)
+            snapatac2.pp.filter_cells(
+                adata,
+                min_counts=step['min_counts'],
+                max_counts=step['max_counts'],
+                min_tsse=step['min_tsse'],
+                max_tsse=step['max_tsse'],
+            )
+        elif name == 'tile_matrix':
+            snapatac2.pp.add_tile_matrix(
+                adata,
+                bin_size=step['bin_size'],
+                exclude_chroms=step['exclude_chroms'],
+                counting_strategy=step['counting_strategy'],
+            )
+        elif name == 'select_features':
+            snapatac2.pp.select_features(
+                adata, n_features=step['n_features'], blacklist=step['blacklist'], n_jobs=n_jobs,
+            )
+        elif name == 'embed':
+            if step['method'] == 'spectral':
+                snapatac2.tl.spectral(adata, n_comps=step['n_comps'], random_state=step['random_state'])
+            else:
+                snapatac2.tl.pca(adata, n_comps=step['n_comps'], random_state=step['random_state'])
+        elif name == 'cluster':
+            use_rep = 'X_' + _last_step(steps[:i], 'embed')['method']
+            if step['method'] == 'leiden':
+                snapatac2.pp.knn(adata, n_neighbors=step['n_neighbors'], use_rep=use_rep)
+                snapatac2.tl.leiden(adata, resolution=step['resolution'], random_state=step['random_state'])
+            else:
+                snapatac2.tl.kmeans(
+                    adata, n_clusters=step['n_clusters'], random_state=step['random_state'], use_rep=use_rep,
+                )
+        elif name == 'export_tracks':
+            snapatac2.ex.export_coverage(
+                adata,
+                groupby=step['groupby'] or _last_step(steps[:i], 'cluster')['method'],
+                out_dir=step['out_dir'],
+                bin_size=step['bin_size'],
+                normalization=step['normalization'],
+                n_jobs=n_jobs,
+            )
+        internal.mark_pipeline_step(adata, i, key)
+    return adata
+
+def _last_step(steps: list[dict], name: str) -> dict:
+    return [x for x in steps if x['step'] == name][-1]
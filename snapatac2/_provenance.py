@@ -0,0 +1,73 @@
+from __future__ import annotations
+
+import json
+
+from snapatac2._snapatac2 import AnnData, AnnDataSet
+import snapatac2._snapatac2 as internal
+
+def history(adata: AnnData | AnnDataSet) -> list[dict]:
+    """
+    Read the operations performed on an AnnData object.
+
+    Functions that create or modify data, such as
+    :func:`~snapatac2.pp.import_fragments`, :func:`~snapatac2.pp.add_tile_matrix`
+    and :func:`~snapatac2.tl.spectral`, record the operation, its parameters,
+    the version of snapatac2, the time and the peak memory used by the operation
+    in `adata.uns["snapatac2_history"]`.
+
+    Parameters
+    ----------
+    adata
+        The AnnData or AnnDataSet object.
+
+    Returns
+    -------
+    list[dict]
+        The entries, oldest first. Each entry has the keys "operation",
+        "parameters", "version" and "timestamp", and the key "peak_memory"
+        if the memory usage could be measured: the peak resident set size of
+        the process during the operation minus that at its start, in bytes.
+    """
+    return json.loads(internal.read_provenance(adata))
+
+def diff_history(
+    left: AnnData | AnnDataSet | list[dict],
+    right: AnnData | AnnDataSet | list[dict],
+) -> list[dict]:
+    """
+    Compare the operations performed on two AnnData objects.
+
+    For each operation, the most recent entries of the two histories are compared.
+
+    Parameters
+    ----------
+    left
+        An AnnData object or a history returned by :func:`~snapatac2.history`.
+    right
+        An AnnData object or a history returned by :func:`~snapatac2.history`.
+
+    Returns
+    -------
+    list[dict]
+        The differences. Each difference has a "kind" key, which is one of
+        "missing_operation" (with the key "present_in" set to "left" or "right"),
+        "parameter" (with the keys "parameter", "left" and "right"),
+        or "version" (with the keys "left" and "right").
+    """
+    if not isinstance(left, list):
+        left = history(left)
+    if not isinstance(right, list):
+        right = history(right)
+    return json.loads(internal.diff_provenance(json.dumps(left), json.dumps(right)))
+
+def _record(
+    adata: AnnData | AnnDataSet,
+    operation: str,
+    tracker: internal.MemoryTracker | None = None,
+    **parameters,
+) -> None:
+    """Record an operation performed in Python. Parameters that are not
+    JSON serializable are stored as strings. If `tracker` is given, the peak
+    memory used since its creation is recorded as well."""
+    peak_memory = None if tracker is None else tracker.finish()
+    internal.record_provenance(adata, operation, json.dumps(parameters, default=str), peak_memory)
@@ -1,5 +1,7 @@
 import numpy as np
 import logging
+import os
+from pathlib import Path
 
 from anndata import AnnData
 import snapatac2._snapatac2 as internal
@@ -7,12 +9,37 @@ import snapatac2._snapatac2 as internal
 def is_anndata(data) -> bool:
     return isinstance(data, AnnData) or isinstance(data, internal.AnnData) or isinstance(data, internal.AnnDataSet)
 
-def anndata_par(adatas, func, n_jobs=4):
+def to_seed(random_state: int) -> int:
+    """Validate a seed and map it to the unsigned 64-bit seeds of the Rust
+    library. Negative seeds are mapped to their two's complement."""
+    if isinstance(random_state, bool) or not isinstance(random_state, (int, np.integer)):
+        raise TypeError(f"random_state must be an integer, got {type(random_state).__name__}")
+    random_state = int(random_state)
+    if not -2**63 <= random_state < 2**64:
+        raise ValueError(f"random_state must fit in 64 bits, got {random_state}")
+    return random_state % 2**64
+
+def derive_seed(random_state: int, name: str) -> int:
+    """Derive the seed of a named component, e.g., one run of a repeated
+    algorithm, from `random_state`, using the seed streams of the Rust library."""
+    return internal.derive_seed(to_seed(random_state), name)
+
+def get_n_jobs(n_jobs: int | None) -> int:
+    """Resolve the number of parallel jobs. `None` uses the number of threads set
+    by :func:`~snapatac2.set_num_threads`, and a non-positive value all CPUs."""
+    if n_jobs is None:
+        return internal.get_num_threads()
+    if n_jobs <= 0:
+        return os.cpu_count()
+    return n_jobs
+
+def anndata_par(adatas, func, n_jobs=None):
     return anndata_ipar(list(enumerate(adatas)), lambda x: func(x[1]), n_jobs=n_jobs)
 
-def anndata_ipar(inputs, func, n_jobs=4):
+def anndata_ipar(inputs, func, n_jobs=None):
     from tqdm import tqdm
     
+    n_jobs = get_n_jobs(n_jobs)
     exist_in_memory_adata = False
     for _, adata in inputs:
         if isinstance(adata, AnnData):
@@ -47,6 +74,53 @@ def anndata_ipar(inputs, func, n_jobs=4):
         
         return result
 
+def create_anndata_atomic(file, backend, func, **kwargs):
+    """Create a backed AnnData object at `file` and fill it with `func`.
+
+    The object is written under a temporary name in the same directory, synced to
+    disk and renamed to `file` once `func` returns, so that an interrupted
+    process never leaves a partly written file behind. Returns the object
+    reopened from `file`.
+    """
+    file = Path(file)
+    tmp = file.with_name("." + file.name + ".partial")
+    out = internal.AnnData(filename=tmp, backend=backend, **kwargs)
+    try:
+        func(out)
+        out.close()
+        with open(tmp, "rb") as fl:
+            os.fsync(fl.fileno())
+        os.replace(tmp, file)
+    except BaseException:
+        if not out.is_closed():
+            out.close()
+        tmp.unlink(missing_ok=True)
+        raise
+    return internal.read(file, backend=backend)
+
+def repack_anndata(adata):
+    """Rewrite the file of a backed AnnData object in place.
+
+    HDF5 does not release the space of deleted elements, so the file is copied,
+    which only writes the live elements, and the copy replaces the original. The
+    object is reopened from the new file.
+    """
+    file = Path(adata.filename)
+    backend = adata.backend
+    tmp = file.with_name("." + file.name + ".repack")
+    try:
+        adata.copy(tmp, backend=backend).close()
+        with open(tmp, "rb") as fl:
+            os.fsync(fl.fileno())
+        adata.close()
+        os.replace(tmp, file)
+    except BaseException:
+        tmp.unlink(missing_ok=True)
+        raise
+    finally:
+        if adata.is_closed():
+            adata.open(mode='r+')
+
 def get_file_format(suffix):
     suffix = suffix.lower()
     _suffix = suffix
@@ -69,6 +143,11 @@ def get_file_format(suffix):
     
     return format, compression
 
+def sanitize_filename(name) -> str:
+    """Make a group name usable as a file name."""
+    import re
+    return re.sub(r"[^\w.-]", "_", str(name))
+
 def get_igraph_from_adjacency(adj):
     """Get igraph graph from adjacency matrix."""
     import igraph as ig
@@ -97,6 +176,23 @@ def find_elbow(x, saturation=0.01):
             return i
     return None
 
+def read_anchors(anchors):
+    """Read anchor sites as (chromosome, position, is on the reverse strand)
+    from a BED file or a list of regions. The anchor is the center of each
+    region, and is on the reverse strand if the 6th column of the BED file is "-".
+    """
+    import pandas as pd
+
+    if isinstance(anchors, list):
+        regions = [(x.split(":")[0], *x.split(":")[1].split("-")) for x in anchors]
+        return [(chrom, (int(start) + int(end)) // 2, False) for chrom, start, end in regions]
+    bed = pd.read_csv(anchors, sep="\t", header=None, comment="#")
+    strand = bed[5] == "-" if bed.shape[1] > 5 else [False] * len(bed)
+    return [
+        (str(chrom), (int(start) + int(end)) // 2, bool(rev))
+        for chrom, start, end, rev in zip(bed[0], bed[1], bed[2], strand)
+    ]
+
 def fetch_seq(fasta, region):
     chr, x = region.split(':')
     start, end = x.split('-')
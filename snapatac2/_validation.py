@@ -0,0 +1,117 @@
+from __future__ import annotations
+
+import json
+import logging
+
+from snapatac2._snapatac2 import AnnData, AnnDataSet
+import snapatac2._snapatac2 as internal
+from snapatac2.genome import Genome
+
+def validate(
+    adata: AnnData | AnnDataSet,
+    genome: Genome | dict[str, int] | None = None,
+    *,
+    chunk_size: int = 500,
+    modality: str | None = None,
+    verbose: bool = True,
+) -> dict:
+    """
+    Check that an AnnData object satisfies the assumptions made by snapatac2.
+
+    This is useful after editing an object by hand or creating it with other
+    tools. The object is not modified. The following checks are performed:
+
+    - `reference_sequences`: `.uns['reference_sequences']` is readable and
+      chromosome sizes are positive.
+    - `reference_genome`: chromosome sizes agree with `genome`, if given.
+    - `fragment_keys`: fragments are stored in `.obsm` with the expected
+      dtype and shape.
+    - `fragment_order`: fragments are sorted by position within each cell and
+      lie within chromosome boundaries.
+    - `var_names`: genomic regions used as feature names are located on known
+      chromosomes and within their boundaries.
+    - `dtype`: `.X` has a numeric dtype.
+    - `pending_writes`: no element was left partly written by an interrupted
+      operation (see :func:`~snapatac2.repair`).
+
+    Parameters
+    ----------
+    adata
+        The AnnData or AnnDataSet object.
+    genome
+        A Genome object or a dictionary containing chromosome sizes. If provided,
+        the chromosome sizes stored in the object are compared to it.
+    chunk_size
+        Number of cells read at a time.
+    modality
+        Check the fragments of this modality, see the `modality_sep` parameter
+        of :func:`~snapatac2.pp.import_fragments`, instead of the fragments
+        stored without modality.
+    verbose
+        Whether to log the issues found.
+
+    Returns
+    -------
+    dict
+        A report with keys "valid" (whether no errors were found), "checks"
+        (the checks performed) and "issues" (a list of dictionaries with keys
+        "check", "severity" and "message").
+
+    Examples
+    --------
+    >>> import snapatac2 as snap
+    >>> data = snap.pp.import_fragments(snap.datasets.pbmc500(downsample=True), chrom_sizes=snap.genome.hg38, sorted_by_barcode=False)
+    >>> snap.validate(data, snap.genome.hg38)['valid']
+    True
+    """
+    if isinstance(genome, Genome):
+        genome = genome.chrom_sizes
+    report = json.loads(internal.validate(adata, genome, chunk_size, modality))
+    report['valid'] = all(x['severity'] != 'error' for x in report['issues'])
+    if verbose:
+        for issue in report['issues']:
+            log = logging.error if issue['severity'] == 'error' else logging.warning
+            log("[%s] %s", issue['check'], issue['message'])
+    return report
+
+def repair(
+    adata: AnnData | AnnDataSet,
+    *,
+    dry_run: bool = False,
+    chunk_size: int = 2000,
+) -> dict:
+    """
+    Remove the elements left partly written by an interrupted operation.
+
+    Matrices are written to disk in chunks. If a process is killed while it writes
+    `.X` (e.g., :func:`~snapatac2.pp.add_tile_matrix`) or an element of `.obsm`
+    (e.g., :func:`~snapatac2.pp.import_fragments`), the element may be incomplete
+    but still readable. snapatac2 records the elements being written in
+    `.uns['snapatac2_pending']`, and this function deletes the ones that were not
+    completed, so that they can be computed again. Fragments whose compaction
+    (:func:`~snapatac2.pp.compact_fragments`) was interrupted are restored from
+    the compacted copy when it is complete.
+
+    New files, e.g., the output of :func:`~snapatac2.pp.add_tile_matrix` with
+    `inplace=False` and `file` set, are written under a temporary name and renamed
+    once complete, so they never need to be repaired.
+
+    Parameters
+    ----------
+    adata
+        The AnnData or AnnDataSet object.
+    dry_run
+        If True, only report the partly written elements without deleting them.
+    chunk_size
+        Number of cells copied at a time when restoring fragments.
+
+    Returns
+    -------
+    dict
+        A report with keys "partial" (the elements found partly written),
+        "removed" (the elements deleted) and "restored" (the elements restored).
+    """
+    report = json.loads(internal.repair(adata, dry_run, chunk_size))
+    for element in report['partial']:
+        logging.warning("'%s' was not completely written", element)
+    return report
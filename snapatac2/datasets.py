@@ -6,6 +6,8 @@ from pathlib import Path
 import pooch
 
 from snapatac2._snapatac2 import read_motifs, PyDNAMotif
+import snapatac2._snapatac2 as internal
+from snapatac2._utils import to_seed
 
 # This is a global variable used to store all datasets. It is initialized only once
 # when the data is requested.
@@ -253,4 +255,96 @@ def Meuleman_2020() -> list[PyDNAMotif]:
     for motif in motifs:
         motif.name = motif.id.split('_')[0]
         motif.family = motif.id.split('+')[-1]
-    return motifs
\ No newline at end of file
+    return motifs
+
+def simulate(
+    n_cells: int = 1000,
+    n_cell_types: int = 5,
+    n_peaks: int = 5000,
+    specific_peak_fraction: float = 0.5,
+    peak_width: int = 500,
+    mean_depth: float = 5000,
+    depth_sd: float = 0.5,
+    frip: float = 0.5,
+    doublet_rate: float = 0.05,
+    n_batches: int = 1,
+    batch_effect: float = 0.0,
+    chrom_sizes: dict[str, int] | None = None,
+    file: Path | None = None,
+    fragment_file: Path | None = None,
+    random_state: int = 0,
+):
+    """Simulate a scATAC-seq dataset with known ground truth.
+
+    Cells are drawn from `n_cell_types` cell types. Each cell type has its own
+    accessible peaks, in addition to peaks shared by all cell types.
+    The number of fragments per cell follows a log-normal distribution, and
+    fragment lengths show the nucleosomal pattern of real data.
+    The dataset is useful for tests, benchmarks and tutorials.
+
+    Parameters
+    ----------
+    n_cells
+        Number of cells.
+    n_cell_types
+        Number of cell types.
+    n_peaks
+        Number of peaks.
+    specific_peak_fraction
+        Fraction of peaks that are specific to one cell type.
+    peak_width
+        Width of the peaks.
+    mean_depth
+        Mean number of fragments per cell.
+    depth_sd
+        Standard deviation of the log number of fragments per cell.
+    frip
+        Fraction of fragments in peaks.
+    doublet_rate
+        Fraction of cells that are doublets. Doublets have twice the depth,
+        and half of their fragments come from a second cell type.
+    n_batches
+        Number of batches.
+    batch_effect
+        Fraction of peaks whose accessibility is changed in every batch.
+    chrom_sizes
+        Chromosome sizes. If `None`, five chromosomes of 20Mb are used.
+    file
+        File name of the output h5ad file. If `None`, the AnnData is kept in memory.
+    fragment_file
+        If provided, the simulated fragments are also saved to this file.
+    random_state
+        Seed of the random number generator.
+
+    Returns
+    -------
+    AnnData
+        The simulated dataset, with fragments imported as in
+        :func:`~snapatac2.pp.import_fragments`. The ground truth is stored in
+        `.obs["cell_type"]`, `.obs["batch"]` and `.obs["doublet"]`, and the
+        peaks in `.uns["simulated_peaks"]`.
+    """
+    import tempfile
+    from snapatac2.preprocessing import import_fragments
+
+    if chrom_sizes is None:
+        chrom_sizes = {f"chr{i}": 20_000_000 for i in range(1, 6)}
+
+    with tempfile.TemporaryDirectory() as tmpdir:
+        output = Path(tmpdir) / "fragments.tsv.gz" if fragment_file is None else Path(fragment_file)
+        barcodes, cell_types, batches, doublets, peaks = internal.simulate_fragments(
+            output, list(chrom_sizes.items()), n_cells, n_cell_types, n_peaks,
+            specific_peak_fraction, peak_width, mean_depth, depth_sd, frip,
+            doublet_rate, n_batches, batch_effect, to_seed(random_state),
+        )
+        adata = import_fragments(
+            output, chrom_sizes, file=file, min_num_fragments=0, sorted_by_barcode=True,
+        )
+
+    idx = {bc: i for i, bc in enumerate(barcodes)}
+    order = [idx[bc] for bc in adata.obs_names]
+    adata.obs["cell_type"] = [f"type_{cell_types[i]}" for i in order]
+    adata.obs["batch"] = [f"batch_{batches[i]}" for i in order]
+    adata.obs["doublet"] = [doublets[i] is not None for i in order]
+    adata.uns["simulated_peaks"] = peaks
+    return adata
@@ -1,15 +1,17 @@
 from __future__ import annotations
 
+import logging
+import os
 from typing import Literal
 from pathlib import Path
 
 import snapatac2._snapatac2 as internal
-from snapatac2._utils import get_file_format
+from snapatac2._utils import get_file_format, read_anchors, sanitize_filename
 
 def export_fragments(
     adata: internal.AnnData | internal.AnnDataSet,
     groupby: str | list[str],
-    selections: list[str] | None = None,
+    selections: list[str] | list[tuple[str, ...]] | None = None,
     ids: str | list[str] | None = None,
     min_frag_length: int | None = None,
     max_frag_length: int | None = None,
@@ -18,6 +20,12 @@ def export_fragments(
     suffix: str = ".bed.zst",
     compression: Literal["gzip", "zstandard"] | None = None,
     compression_level: int | None = None,
+    strip_suffix: str | None = None,
+    sample_prefix: str | list[str] | None = None,
+    prefix_sep: str = "_",
+    out_file: Path | None = None,
+    modality: str | None = None,
+    chunk_size: int | None = None,
 ) -> dict[str, str]:
     """Export and save fragments in a BED format file.
 
@@ -29,9 +37,13 @@ def export_fragments(
     groupby
         Group the cells. If a list of `str`, each element is the group name of the corresponding cell.
         The length of the list must be equal to `n_obs`. If a `str`, groups are obtained from
-        `.obs[groupby]`. 
+        `.obs[groupby]`. If a list of `.obs` keys, e.g., `["cell_type", "condition"]`,
+        cells are grouped by every combination of their values, and the files are
+        saved in nested directories, e.g., `{out_dir}/{cell_type}/{prefix}{condition}{suffix}`.
+        All combinations are exported in a single pass over the fragments.
+        "/" in group names is replaced by "+" in the file names.
     selections
-        Export only the selected groups.
+        Export only the selected groups. Groups of several keys are given as tuples.
     ids
         Cell ids add to the bed records. If `None`, `.obs_names` is used.
     min_frag_length
@@ -49,47 +61,205 @@ def export_fragments(
     compression_level
         Compression level. 1-9 for gzip, 1-22 for zstandard.
         If `None`, it is set to 6 for gzip and 3 for zstandard.
+    strip_suffix
+        Regular expression matching a suffix to remove from the cell ids,
+        e.g., "-1" or "-\\d+". See :func:`~snapatac2.ex.map_barcodes`.
+    sample_prefix
+        Sample names prepended to the cell ids. If a `str`, the names are
+        obtained from `.obs[sample_prefix]`.
+    prefix_sep
+        Separator between the sample name and the cell id.
+    out_file
+        Write the fragments of a single group to this file instead of
+        `out_dir`. It can be a named pipe or "-" for the standard output,
+        e.g., to pipe the fragments into `sort` or `bgzip` without temporary
+        files. Exactly one group must be selected, either by `selections` or
+        because there is only one group. The output is not compressed unless
+        `compression` is given or the file name ends with ".gz" or ".zst".
+        Note that fragments are written in the order of the cells, not sorted
+        by coordinate.
+    modality
+        Export the fragments of this modality, see the `modality_sep` parameter
+        of :func:`~snapatac2.pp.import_fragments`, instead of the fragments
+        stored without modality.
+    chunk_size
+        Number of cells whose fragments are read at once. If `None`, the value
+        set by :func:`~snapatac2.set_chunk_size` is used, or the chunk size is
+        chosen from the number of fragments per cell and the memory budget.
 
     Returns
     -------
     dict[str, str]
         A dictionary contains `(groupname, filename)` pairs. The file names are
-        formatted as `{prefix}{groupname}{suffix}`.
+        formatted as `{prefix}{groupname}{suffix}`, or `out_file`. Groups of
+        several keys are given as tuples.
 
     See Also
     --------
     export_coverage
+    map_barcodes
     """
-    if isinstance(groupby, str):
-        groupby = adata.obs[groupby]
-    if selections is not None:
-        selections = set(selections)
-    
+    groupby, selections, names = _group_labels(adata, groupby, selections)
+
     if ids is None:
         ids = adata.obs_names
     elif isinstance(ids, str):
         ids = adata.obs[ids]
+    if strip_suffix is not None or sample_prefix is not None:
+        ids = map_barcodes(
+            adata, ids=list(ids), strip_suffix=strip_suffix,
+            sample_prefix=sample_prefix, prefix_sep=prefix_sep,
+        )
+
+    if out_file is not None:
+        groups = set(groupby) if selections is None else set(selections)
+        if len(groups) != 1:
+            raise ValueError(
+                f"out_file requires a single group, but {len(groups)} groups are selected"
+            )
+        group = groups.pop()
+        if compression is None:
+            _, compression = get_file_format(str(out_file))
+        internal.export_group_fragments(
+            adata, list(ids), groupby, group, out_file, min_frag_length,
+            max_frag_length, compression, compression_level, modality, chunk_size,
+        )
+        return {names.get(group, group): str(out_file)}
 
     if compression is None:
         _, compression = get_file_format(suffix)
 
-    return internal.export_fragments(
-        adata, list(ids), list(groupby), out_dir, prefix, suffix, selections, 
-        min_frag_length, max_frag_length, compression, compression_level,
+    files = internal.export_fragments(
+        adata, list(ids), groupby, out_dir, prefix, suffix, selections,
+        min_frag_length, max_frag_length, compression, compression_level, modality,
+        chunk_size,
+    )
+    return {names[k]: v for k, v in files.items()}
+
+def _group_labels(adata, groupby, selections, missing=None):
+    """Compute the group label of every cell, as passed to the exporters.
+
+    If `groupby` gives `.obs` keys and `missing` is given, the labels are
+    computed by :func:`_obs_groups`. Otherwise the label of a cell is the
+    combination of its values, converted to `str` (e.g., "None" or "nan" for
+    missing values) and separated by "/" so that each key is a directory level.
+    "/" in the values themselves is replaced by "+".
+    Returns the labels, the selected labels, and the group name of every label.
+    """
+    escape = lambda x: str(x).replace("/", "+")
+    keys = _obs_keys(adata, groupby)
+    if keys is not None and missing is not None:
+        return _obs_groups(adata, keys, selections, missing, labels=True)
+    if keys is not None and len(keys) > 1:
+        values = list(zip(*[[str(x) for x in adata.obs[k]] for k in keys]))
+        labels = ["/".join(escape(x) for x in v) for v in values]
+        names = dict(zip(labels, values))
+        if selections is not None:
+            selections = {"/".join(escape(x) for x in v) for v in selections}
+        return labels, selections, names
+    if keys is not None:
+        groupby = adata.obs[keys[0]]
+
+    groupby = [str(x) for x in groupby]
+    labels = [escape(x) for x in groupby]
+    names = dict(zip(labels, groupby))
+    if selections is not None:
+        selections = {escape(x) for x in selections}
+    return labels, selections, names
+
+def _obs_groups(adata, keys, selections, missing, labels=False):
+    """The groups of the cells given by the `.obs` columns `keys`, labelled
+    by the exporters. The group name of a label is the value of the key, or
+    the tuple of the values of the keys, with `None` for missing values.
+    Returns the label of every cell if `labels=True` (`None` otherwise), the
+    labels of the selected names, and the group name of every label."""
+    labels, values = internal.obs_group_labels(adata, keys, missing, labels)
+    names = {k: v[0] if len(keys) == 1 else tuple(v) for k, v in values.items()}
+    if selections is not None:
+        index = {v: k for k, v in names.items()}
+        as_name = lambda x: None if x is None else str(x)
+        selections = [
+            as_name(x) if len(keys) == 1 else tuple(as_name(y) for y in x) for x in selections
+        ]
+        selections = {index[x] for x in selections if x in index}
+    return labels, selections, names
+
+def _obs_keys(adata, groupby):
+    """The `.obs` keys of `groupby`, or `None` if `groupby` gives the group of
+    every cell."""
+    if isinstance(groupby, str):
+        return [groupby]
+    if (
+        isinstance(groupby, (list, tuple)) and len(groupby) > 0
+        and all(isinstance(k, str) and k in adata.obs.columns for k in groupby)
+    ):
+        return list(groupby)
+    return None
+
+def map_barcodes(
+    adata: internal.AnnData | internal.AnnDataSet,
+    ids: str | list[str] | None = None,
+    strip_suffix: str | None = None,
+    sample_prefix: str | list[str] | None = None,
+    prefix_sep: str = "_",
+) -> list[str]:
+    """Compute the names under which cells are exported.
+
+    The stored cell names are left untouched; the mapping is applied on the fly
+    by functions writing cell barcodes, e.g., :func:`~snapatac2.ex.export_fragments`.
+    An error is raised if two cells would be given the same name.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+    ids
+        Cell ids to map. If a `str`, the ids are obtained from `.obs[ids]`.
+        If `None`, `.obs_names` is used.
+    strip_suffix
+        Regular expression matching a suffix to remove from the end of each id,
+        e.g., "-1" for barcodes produced by Cell Ranger.
+    sample_prefix
+        Sample names prepended to the ids. If a `str`, the names are obtained
+        from `.obs[sample_prefix]`. Otherwise, it must be a list with one element
+        per cell.
+    prefix_sep
+        Separator between the sample name and the id.
+
+    Returns
+    -------
+    list[str]
+        The new name of every cell.
+
+    Examples
+    --------
+    >>> snap.ex.map_barcodes(data, strip_suffix="-1", sample_prefix="sample")
+    ['pbmc1_AAACAGCCAAGGAATC', 'pbmc1_AAACAGCCAATCCCTT', ...]
+    """
+    if ids is None:
+        ids = adata.obs_names
+    elif isinstance(ids, str):
+        ids = adata.obs[ids]
+    if isinstance(sample_prefix, str):
+        sample_prefix = adata.obs[sample_prefix]
+    if sample_prefix is not None:
+        sample_prefix = [str(x) for x in sample_prefix]
+    return internal.map_barcodes(
+        [str(x) for x in ids], sample_prefix, strip_suffix, prefix_sep,
     )
 
 def export_coverage(
     adata: internal.AnnData | internal.AnnDataSet,
     groupby: str | list[str],
-    selections: list[str] | None = None,
+    selections: list[str] | list[tuple[str, ...]] | None = None,
     bin_size: int = 10,
     blacklist: Path | None = None,
-    normalization: Literal["RPKM", "CPM", "BPM"] | None = "RPKM",
+    normalization: Literal["RPKM", "CPM", "BPM", "RPGC", "quantile", "zscore", "spikein"] | None = "RPKM",
     include_for_norm: list[str] | Path = None,
     exclude_for_norm: list[str] | Path = None,
     min_frag_length: int | None = None,
     max_frag_length: int | None = 2000,
-    counting_strategy: Literal['fragment', 'insertion'] = 'fragment',
+    counting_strategy: Literal['fragment', 'insertion', 'paired-insertion'] = 'fragment',
     smooth_base: int | None = None,
     out_dir: Path = "./",
     prefix: str = "",
@@ -98,8 +268,23 @@ def export_coverage(
     compression: Literal["gzip", "zstandard"] | None = None,
     compression_level: int | None = None,
     tempdir: Path | None = None,
-    n_jobs: int = 8,
-) -> dict[str, str]:
+    n_jobs: int | None = None,
+    use_cache: bool = False,
+    bias_correction: bool = False,
+    genome_fasta: Path | 'snapatac2.genome.Genome' | None = None,
+    peaks: Path | list[str] | None = None,
+    fragment_suffix: str | None = None,
+    max_value: float | None = None,
+    winsorize: float | None = None,
+    smooth_kernel: Literal["flat", "gaussian", "epanechnikov"] = "flat",
+    track_stats: bool | list[float] = False,
+    spike_in: str | list[str] | None = None,
+    spike_in_scale: float = 10000,
+    effective_genome_size: int | None = None,
+    missing: Literal["error", "drop", "keep"] = "error",
+    modality: str | None = None,
+    chunk_size: int | None = None,
+) -> dict[str, str] | tuple[dict[str, str], dict[str, str]]:
     """Export and save coverage in a bedgraph or bigwig format file.
 
     This function first divides cells into groups based on the `groupby` parameter.
@@ -123,9 +308,11 @@ def export_coverage(
     groupby
         Group the cells. If a list of `str`, each element is the group name of the corresponding cell.
         The length of the list must be equal to `n_obs`. If a `str`, groups are obtained from
-        `.obs[groupby]`. 
+        `.obs[groupby]`. If a list of `.obs` keys, cells are grouped by every
+        combination of their values and the files are saved in nested directories,
+        as in :func:`~snapatac2.ex.export_fragments`.
     selections
-        Export only the selected groups.
+        Export only the selected groups. Groups of several keys are given as tuples.
     bin_size
         Size of the bins, in bases, for the output of the bigwig/bedgraph file.
     blacklist
@@ -135,6 +322,18 @@ def export_coverage(
         - RPKM (per bin) = #reads per bin / (#mapped_reads (in millions) * bin length (kb)).
         - CPM (per bin) = #reads per bin / #mapped_reads (in millions).
         - BPM (per bin) = #reads per bin / sum of all reads per bin (in millions).
+        - RPGC (per bin) = #reads per bin / (#mapped bases / effective genome size),
+          i.e., 1x average coverage, see `effective_genome_size`.
+        - quantile (per bin) = fraction of the covered bins of the track whose
+          value is not larger than that of the bin. Values are in (0, 1], and
+          the tracks of all groups follow the same distribution.
+        - zscore (per bin) = (#reads per bin - mean) / standard deviation, where the
+          mean and standard deviation are computed over all bins of the genome.
+          Uncovered bins, whose z-score is `-mean / sd`, are not written.
+        - spikein (per bin) = #reads per bin * `spike_in_scale` / #reads on the
+          spike-in chromosomes of the group, see `spike_in`.
+        quantile and zscore make tracks with very different signal-to-noise
+        ratios visually comparable, but the values are no longer read counts.
     include_for_norm
         A list of string (e.g., ["chr1:1-100", "chr2:2-200"]) or a BED file containing
         the genomic loci to include for normalization.
@@ -151,12 +350,21 @@ def export_coverage(
         Maximum fragment length to be included in the computation.
     counting_strategy
         The strategy to compute feature counts. It must be one of the following:
-        "fragment" or "insertion". "fragment" means the
+        "fragment", "insertion" or "paired-insertion". "fragment" means the
         feature counts are assigned based on the number of fragments that overlap
         with a region of interest. "insertion" means the feature counts are assigned
         based on the number of insertions that overlap with a region of interest.
+        "paired-insertion" is similar to "insertion", but the two insertions of
+        a fragment are counted only once if they fall into the same bin, as in
+        :func:`~snapatac2.pp.make_peak_matrix`.
     smooth_base
         Length of the smoothing window in bases for the output of the bigwig/bedgraph file.
+    smooth_kernel
+        Weights of the bases of the smoothing window. "flat" gives every base
+        the same weight (moving average). "gaussian" uses a Gaussian whose
+        standard deviation is a sixth of the window, and "epanechnikov" a
+        parabola vanishing just outside the window; both give smoother tracks
+        for sparse pseudobulks. Has no effect unless `smooth_base` is set.
     out_dir
         Directory for saving the outputs.
     prefix
@@ -172,16 +380,95 @@ def export_coverage(
         If `None`, it is set to 6 for gzip and 3 for zstandard.
     n_jobs
         Number of threads to use. If `<= 0`, use all available threads.
+        If `None`, the global setting of :func:`~snapatac2.set_num_threads` is used.
+    use_cache
+        Export a quick-look track from the coverage cache built by
+        :func:`~snapatac2.pp.build_coverage_cache` instead of the fragments.
+        The values are insertion counts in the bins of the cache, so `bin_size`,
+        `counting_strategy` and the fragment length filters have no effect.
+        `blacklist`, `include_for_norm`, `exclude_for_norm` and `smooth_base`
+        are not supported in this mode.
+    bias_correction
+        Weight every insertion by the inverse of its Tn5 sequence bias, using
+        the model fitted by :func:`~snapatac2.tl.fit_tn5_bias`. Requires
+        `counting_strategy="insertion"` and `genome_fasta`.
+    genome_fasta
+        A fasta file containing the genome sequences or a Genome object.
+        Only used when `bias_correction=True`.
+    peaks
+        A BED file or a list of strings representing the peaks. If given, the
+        signal portion of tags (SPOT) and the peak enrichment of each group are
+        computed (see :func:`~snapatac2.metrics.spot`), stored in
+        `adata.uns["spot"]`, and written along with the output files to
+        `{prefix}manifest.json` in `out_dir`. `adata` must be writable.
+    fragment_suffix
+        If given, the fragments of each group are also saved, sorted by
+        coordinate, to `{prefix}{groupname}{fragment_suffix}`, e.g., with
+        `fragment_suffix=".bed.gz"`. The fragment files and the coverage are
+        written from the same pass over the fragments, which is faster than
+        calling :func:`~snapatac2.ex.export_fragments` separately. The
+        compression is inferred from the suffix, and the cell barcodes are
+        taken from `.obs_names`. Not supported with `use_cache=True`.
+    max_value
+        Cap the value of every bin at this maximum, before normalization.
+        This prevents a few artifactual hotspots from dominating the scale of
+        the tracks, e.g., the autoscaling of genome browsers.
+    winsorize
+        Cap the value of every bin at this quantile, e.g., 0.999, of the
+        values of the bins with signal, before normalization. Cannot be used
+        together with `max_value`.
+    track_stats
+        Compute summary statistics of every track while it is written and save
+        them to `{prefix}manifest.json` in `out_dir`: the genome-wide mean, the
+        maximum, the fraction of the genome with signal, and the quantiles of
+        the values of the bases with signal, estimated by a streaming sketch
+        within 1% relative error. If True, the quantiles 0.5, 0.9, 0.99 and
+        0.999 are computed; a list of quantiles can be given instead. The
+        highest quantile is also stored as "view_limit", a robust upper limit
+        for the autoscaling of genome browsers. Not supported with `use_cache=True`.
+        Whenever the manifest is written, it also records, for every group, the
+        numbers of fragments clipped or dropped because they extend beyond the
+        end of their chromosome, see :func:`~snapatac2.set_out_of_bounds_policy`.
+    spike_in
+        Names of the spike-in chromosomes, e.g., the E. coli genome carried
+        over in CUT&Tag or the Drosophila chromatin added to ChIP experiments.
+        Names may contain `*`, e.g., "dm6_*" for all chromosomes with a prefix.
+        Fragments on these chromosomes are not written to the tracks. They are
+        counted in every group, and the counts are written to
+        `{prefix}manifest.json` in `out_dir`. Required by `normalization="spikein"`.
+    spike_in_scale
+        The constant of the spike-in normalization, i.e., the value of a bin
+        with as many reads as there are reads on the spike-in chromosomes.
+    effective_genome_size
+        The mappable size of the genome used by the RPGC normalization, e.g.,
+        2913022398 for GRCh38 with 150 bp reads, as listed in the deepTools
+        documentation. If `None`, the total size of the chromosomes is used.
+    missing
+        How to group the cells with a missing value in one of the `.obs` keys
+        of `groupby`: "error" raises an error, "drop" leaves the cells out of
+        every group, and "keep" groups them under the value `None`, written
+        "NA" in the file names, or "NA_" if "NA" is a value of the key.
+    modality
+        Compute the coverage of the fragments of this modality, see the
+        `modality_sep` parameter of :func:`~snapatac2.pp.import_fragments`,
+        instead of the fragments stored without modality. Not supported with
+        `use_cache=True`.
+    chunk_size
+        Number of cells whose fragments are read at once. If `None`, the value
+        set by :func:`~snapatac2.set_chunk_size` is used, or the chunk size is
+        chosen from the number of fragments per cell and the memory budget.
 
     Returns
     -------
-    dict[str, str]
+    dict[str, str] | tuple[dict[str, str], dict[str, str]]
         A dictionary contains `(groupname, filename)` pairs. The file names are
-        formatted as `{prefix}{groupname}{suffix}`.
+        formatted as `{prefix}{groupname}{suffix}`. If `fragment_suffix` is
+        given, a second dictionary with the fragment files is also returned.
 
     See Also
     --------
     export_fragments
+    snapatac2.metrics.spot
 
     Examples
     --------
@@ -214,11 +501,252 @@ def export_coverage(
      'CD4 Naive': './CD4 Naive.bw',
      'cDC': './cDC.bw'}
     """
-    if isinstance(groupby, str):
+    # Groups given by `.obs` keys are computed by the exporter itself.
+    keys = None if use_cache else _obs_keys(adata, groupby)
+    if keys is None:
+        groupby, selections, names = _group_labels(adata, groupby, selections, missing)
+    else:
+        # The exporter labels the cells itself, only the manifest needs them.
+        labels, selections, names = _obs_groups(
+            adata, keys, selections, missing, labels=peaks is not None,
+        )
+
+    if output_format is None:
+        output_format, inferred_compression = get_file_format(suffix)
+        if output_format is None:
+            raise ValueError("Output format cannot be inferred from suffix.")
+        if compression is None:
+            compression = inferred_compression
+
+    bias_genome = None
+    if bias_correction:
+        if genome_fasta is None:
+            raise ValueError("genome_fasta must be provided when bias_correction=True")
+        if counting_strategy != 'insertion':
+            raise ValueError("bias_correction requires counting_strategy='insertion'")
+        if use_cache:
+            raise ValueError("bias_correction is not supported with use_cache=True")
+        bias_genome = genome_fasta if isinstance(genome_fasta, (str, Path)) else genome_fasta.fasta
+
+    if isinstance(spike_in, str):
+        spike_in = [spike_in]
+    if normalization is not None and normalization.lower() == "spikein" and spike_in is None:
+        raise ValueError("spike_in must be provided when normalization='spikein'")
+
+    if use_cache:
+        unsupported = {
+            'blacklist': blacklist, 'include_for_norm': include_for_norm,
+            'exclude_for_norm': exclude_for_norm, 'smooth_base': smooth_base,
+            'fragment_suffix': fragment_suffix, 'max_value': max_value,
+            'winsorize': winsorize, 'track_stats': track_stats or None,
+            'spike_in': spike_in, 'effective_genome_size': effective_genome_size,
+            'missing': None if missing == "error" else missing,
+            'modality': modality,
+        }
+        unsupported = [k for k, v in unsupported.items() if v is not None]
+        if len(unsupported) > 0:
+            raise ValueError(
+                "The following options are not supported with use_cache=True: " + ", ".join(unsupported)
+            )
+        files = internal.export_coverage_from_cache(
+            adata, groupby, out_dir, prefix, suffix, output_format, selections,
+            normalization, compression, compression_level,
+        )
+        stats, out_of_bounds, spike_in_counts = None, None, None
+    else:
+        if n_jobs is not None and n_jobs <= 0:
+            n_jobs = os.cpu_count()
+        fragment_compression = None
+        barcodes = None
+        if fragment_suffix is not None:
+            _, fragment_compression = get_file_format(fragment_suffix)
+            barcodes = list(adata.obs_names)
+        if track_stats is True:
+            track_stats = [0.5, 0.9, 0.99, 0.999]
+        files, fragment_files, stats, out_of_bounds, spike_in_counts = internal.export_coverage(
+            adata, groupby if keys is None else keys, bin_size, out_dir, prefix, suffix, output_format, counting_strategy,
+            selections, blacklist, normalization, include_for_norm, exclude_for_norm, min_frag_length,
+            max_frag_length, smooth_base, compression, compression_level, tempdir, n_jobs,
+            bias_genome, fragment_suffix, barcodes, fragment_compression, None,
+            max_value, winsorize, smooth_kernel, list(track_stats) if track_stats else None,
+            spike_in, spike_in_scale, effective_genome_size, keys is not None, missing,
+            modality, chunk_size,
+        )
+        if keys is not None:
+            groupby = labels
+
+    if peaks is not None or track_stats or spike_in is not None:
+        _write_manifest(
+            adata, files, groupby, peaks, stats if track_stats else None, out_of_bounds,
+            spike_in_counts if spike_in is not None else None,
+            Path(out_dir) / f"{prefix}manifest.json",
+            min_frag_length=min_frag_length, max_frag_length=max_frag_length, blacklist=blacklist,
+        )
+    files = {names[k]: v for k, v in files.items()}
+    if fragment_suffix is not None:
+        return files, {names[k]: v for k, v in fragment_files.items()}
+    return files
+
+def _write_manifest(
+    adata, files, groupby, peaks, stats, out_of_bounds, spike_in, manifest,
+    min_frag_length=None, max_frag_length=None, blacklist=None,
+):
+    """Write the SPOT of the exported groups, if `peaks` is given, computed
+    with the fragment size limits and the blacklist of the export, the
+    statistics of their tracks, if `stats` is given, the numbers of
+    fragments beyond the end of their chromosome, if `out_of_bounds` is
+    given, and the numbers of spike-in fragments, if `spike_in` is given,
+    to the manifest."""
+    import json
+    import snapatac2.metrics
+
+    groups = {group: {"file": str(file)} for group, file in files.items()}
+    if peaks is not None:
+        qc = snapatac2.metrics.spot(
+            adata, peaks, list(groupby), min_frag_length=min_frag_length,
+            max_frag_length=max_frag_length, blacklist=blacklist, inplace=False,
+        )
+        qc = {k: v for k, v in qc.items() if k in files}
+        snapatac2.metrics._store_spot(adata, qc, "spot")
+        for group in groups:
+            groups[group].update(qc[group])
+    if stats is not None:
+        for group, (mean, max_value, coverage, quantiles) in stats.items():
+            groups[group]["stats"] = {
+                "mean": mean,
+                "max": max_value,
+                "coverage_fraction": coverage,
+                "quantiles": {str(q): v for q, v in quantiles},
+                "view_limit": max(quantiles)[1] if len(quantiles) > 0 else max_value,
+            }
+    if out_of_bounds is not None:
+        for group, (clipped, dropped) in out_of_bounds.items():
+            groups[group]["out_of_bounds"] = {"clipped": clipped, "dropped": dropped}
+    if spike_in is not None:
+        for group, count in spike_in.items():
+            groups[group]["spike_in_count"] = count
+    with open(manifest, "w") as f:
+        json.dump({"groups": groups}, f, indent=2)
+
+def base_coverage(
+    adata: internal.AnnData | internal.AnnDataSet,
+    region: str,
+    groupby: str | list[str] | None = None,
+    selections: list[str] | None = None,
+    counting_strategy: Literal['fragment', 'insertion'] = 'fragment',
+    min_frag_length: int | None = None,
+    max_frag_length: int | None = None,
+) -> dict[str, 'np.ndarray']:
+    """Compute the exact coverage of every base of a region.
+
+    Unlike :func:`~snapatac2.ex.export_coverage`, the coverage is neither
+    binned, merged nor normalized, which makes it suitable for fine-grained
+    plots such as footprints in small regions. For fragment data, the coverage
+    of a base is the number of fragments spanning it, or the number of Tn5
+    insertions at it if `counting_strategy="insertion"`. For base-level data
+    (see :func:`~snapatac2.pp.import_values`), it is the number of cells with
+    a value at the base.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    region
+        The region, e.g., "chr1:1000-2000".
+    groupby
+        Group the cells. If a `str`, groups are obtained from `.obs[groupby]`.
+        If None, all cells are used and the result has a single group "all".
+    selections
+        Compute the coverage of the selected groups only.
+    counting_strategy
+        "fragment" or "insertion". Ignored for base-level data.
+    min_frag_length
+        Minimum fragment length to be included in the computation.
+    max_frag_length
+        Maximum fragment length to be included in the computation.
+
+    Returns
+    -------
+    dict[str, np.ndarray]
+        For each group, an array with the coverage of each base of the region.
+    """
+    if groupby is None:
+        groupby = ["all"] * adata.n_obs
+    elif isinstance(groupby, str):
         groupby = adata.obs[groupby]
+    groupby = [str(x) for x in groupby]
     if selections is not None:
         selections = set(selections)
-    
+    return internal.base_coverage(
+        adata, region, groupby, selections, counting_strategy, min_frag_length, max_frag_length,
+    )
+
+def export_values(
+    adata: internal.AnnData | internal.AnnDataSet,
+    groupby: str | list[str],
+    selections: list[str] | None = None,
+    *,
+    bin_size: int = 1,
+    value_type: Literal['target', 'total', 'fraction'] = 'fraction',
+    aggregation: Literal['mean', 'sum', 'count'] = 'mean',
+    out_dir: Path = "./",
+    prefix: str = "",
+    suffix: str = ".bw",
+    output_format: Literal["bedgraph", "bigwig"] | None = None,
+    compression: Literal["gzip", "zstandard"] | None = None,
+    compression_level: int | None = None,
+) -> dict[str, str]:
+    """Export the base-level values of each group of cells as coverage tracks.
+
+    This is the counterpart of :func:`~snapatac2.ex.export_coverage` for
+    base-level data imported with :func:`~snapatac2.pp.import_values`, such as
+    methylation fractions from WGBS. The values of the cells in a group are
+    aggregated at every base, or in every bin if `bin_size > 1`. Bins without
+    any value are absent from the output.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    groupby
+        Group the cells. If a `str`, groups are obtained from
+        `.obs[groupby]`.
+    selections
+        Export the selected groups only.
+    bin_size
+        Size of the bins, in base pairs.
+    value_type
+        "target" uses the numerators of the values (e.g., the number of
+        methylated reads), "total" the denominators (e.g., the number of
+        reads) and "fraction" their ratios.
+    aggregation
+        How the values in a bin are aggregated: "mean", "sum", or "count",
+        the number of values.
+    out_dir
+        Directory for saving the outputs.
+    prefix
+        Text added to the output file name.
+    suffix
+        Text added to the output file name.
+    output_format
+        Output format, either "bedgraph" or "bigwig". If None, it is inferred
+        from the suffix.
+    compression
+        Compression type of bedGraph files. If None, it is inferred from the
+        suffix.
+    compression_level
+        Compression level. 1-9 for gzip, 1-22 for zstandard.
+
+    Returns
+    -------
+    dict[str, str]
+        A dictionary contains `(groupname, filename)` pairs. The file names are
+        formatted as `{prefix}{groupname}{suffix}`.
+    """
+    groupby, selections, names = _group_labels(adata, groupby, selections)
+
     if output_format is None:
         output_format, inferred_compression = get_file_format(suffix)
         if output_format is None:
@@ -226,9 +754,800 @@ def export_coverage(
         if compression is None:
             compression = inferred_compression
 
-    n_jobs = None if n_jobs <= 0 else n_jobs
-    return internal.export_coverage(
-        adata, list(groupby), bin_size, out_dir, prefix, suffix, output_format, counting_strategy,
-        selections, blacklist, normalization, include_for_norm, exclude_for_norm, min_frag_length,
-        max_frag_length, smooth_base, compression, compression_level, tempdir, n_jobs,
-    )
\ No newline at end of file
+    files = internal.export_base_values(
+        adata, groupby, bin_size, out_dir, prefix, suffix, output_format,
+        value_type, aggregation, selections, compression, compression_level,
+    )
+    return {names[k]: v for k, v in files.items()}
+
+def export_expected_bias(
+    adata: internal.AnnData | internal.AnnDataSet,
+    genome_fasta: Path | 'snapatac2.genome.Genome',
+    out_file: Path,
+    bin_size: int = 10,
+    output_format: Literal["bedgraph", "bigwig"] | None = None,
+    compression: Literal["gzip", "zstandard"] | None = None,
+    compression_level: int | None = None,
+) -> Path:
+    """Export the expected Tn5 insertion bias along the genome.
+
+    The value of every bin is the average bias of the insertion sites in the
+    bin, computed from the genome sequence with the model fitted by
+    :func:`~snapatac2.tl.fit_tn5_bias`. Values above 1 indicate sequences
+    Tn5 prefers.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    genome_fasta
+        A fasta file containing the genome sequences or a Genome object.
+    out_file
+        Output file name.
+    bin_size
+        Size of the bins, in bases.
+    output_format
+        Output format. If `None`, it is inferred from the file name.
+    compression
+        Compression type. If `None`, it is inferred from the file name.
+    compression_level
+        Compression level. 1-9 for gzip, 1-22 for zstandard.
+
+    Returns
+    -------
+    Path
+        The output file.
+    """
+    out_file = Path(out_file)
+    if output_format is None:
+        output_format, inferred_compression = get_file_format(str(out_file))
+        if output_format is None:
+            raise ValueError("Output format cannot be inferred from the file name.")
+        if compression is None:
+            compression = inferred_compression
+    if not isinstance(genome_fasta, (str, Path)):
+        genome_fasta = genome_fasta.fasta
+    internal.export_expected_bias(
+        adata, genome_fasta, bin_size, out_file, output_format, compression, compression_level,
+    )
+    return out_file
+
+def combine_tracks(
+    tracks: list[Path] | dict[str, Path],
+    chrom_sizes: dict[str, int] | 'snapatac2.genome.Genome',
+    out_file: Path,
+    op: Literal["mean", "sum", "max", "min"] = "mean",
+    output_format: Literal["bedgraph", "bigwig"] | None = None,
+    compression: Literal["gzip", "zstandard"] | None = None,
+    compression_level: int | None = None,
+) -> Path:
+    """Combine coverage tracks position by position.
+
+    This is typically used to summarize the tracks of replicates or donors,
+    e.g., those produced by :func:`~snapatac2.ex.export_coverage`, into a
+    single track. Positions not covered by a track count as 0, and positions
+    not covered by any track are left out of the output.
+    The tracks are streamed and merged as they are read, so that memory usage
+    does not grow with the size of the genome.
+
+    Parameters
+    ----------
+    tracks
+        The bedGraph or bigWig files to combine. BigWig files are recognized
+        by the ".bw" or ".bigwig" extension. BedGraph files, possibly
+        compressed, must be sorted in the order of `chrom_sizes`. If a `dict`,
+        e.g., the output of :func:`~snapatac2.ex.export_coverage`, its values are used.
+    chrom_sizes
+        Chromosome sizes or a Genome object. Records on other chromosomes are skipped.
+    out_file
+        Output file name.
+    op
+        How the values are combined: "mean", "sum", "max" or "min".
+    output_format
+        Output format. If `None`, it is inferred from the file name.
+    compression
+        Compression type. If `None`, it is inferred from the file name.
+    compression_level
+        Compression level. 1-9 for gzip, 1-22 for zstandard.
+
+    Returns
+    -------
+    Path
+        The output file.
+    """
+    out_file = Path(out_file)
+    if output_format is None:
+        output_format, inferred_compression = get_file_format(str(out_file))
+        if output_format is None:
+            raise ValueError("Output format cannot be inferred from the file name.")
+        if compression is None:
+            compression = inferred_compression
+    if isinstance(tracks, dict):
+        tracks = list(tracks.values())
+    if not isinstance(chrom_sizes, dict):
+        chrom_sizes = chrom_sizes.chrom_sizes
+    internal.combine_tracks(
+        [str(x) for x in tracks], list(chrom_sizes.items()), op, out_file,
+        output_format, compression, compression_level,
+    )
+    return out_file
+
+def track_correlation(
+    tracks: list[Path] | dict[str, Path],
+    references: list[Path] | dict[str, Path],
+    chrom_sizes: dict[str, int] | 'snapatac2.genome.Genome',
+    *,
+    bin_size: int = 10_000,
+    method: Literal["pearson", "spearman"] = "spearman",
+    skip_zeros: bool = True,
+    n_jobs: int | None = None,
+) -> 'polars.DataFrame':
+    """Correlate pseudobulk tracks with reference bulk tracks.
+
+    The genome is divided into bins of `bin_size` bases, the mean signal of
+    every track is computed in every bin, and every track is correlated with
+    every reference track across the bins. Comparing the pseudobulk of each
+    cluster, e.g., exported by :func:`~snapatac2.ex.export_coverage`, with bulk
+    ATAC-seq or ChIP-seq of sorted cell types is a quick sanity check of the
+    identity of the clusters: every cluster should correlate best with the
+    reference of its cell type.
+
+    Parameters
+    ----------
+    tracks
+        The bigWig or bedGraph files of the groups. BigWig files are recognized
+        by the ".bw" or ".bigwig" extension. BedGraph files, possibly
+        compressed, must be sorted in the order of `chrom_sizes`. If a `dict`,
+        e.g., the output of :func:`~snapatac2.ex.export_coverage`, its keys are
+        used as the names of the tracks. Otherwise, the names are the file
+        names without extensions.
+    references
+        The reference tracks, given as `tracks`.
+    chrom_sizes
+        Chromosome sizes or a Genome object. Only these chromosomes are binned.
+    bin_size
+        Size of the bins, in bases.
+    method
+        Correlation method, "pearson" or "spearman". Spearman correlations
+        are insensitive to differences in the scale and normalization of the tracks.
+    skip_zeros
+        Leave out the bins without signal in all tracks and references, which
+        would otherwise inflate the correlations.
+    n_jobs
+        Number of threads to use. If `<= 0`, use all available threads.
+        If `None`, the global setting of :func:`~snapatac2.set_num_threads` is used.
+
+    Returns
+    -------
+    polars.DataFrame
+        The correlation matrix, with a column "group" followed by one column
+        per reference.
+
+    See Also
+    --------
+    export_coverage
+    combine_tracks
+    """
+    import polars as pl
+
+    def names_of(x):
+        if isinstance(x, dict):
+            return list(x.keys()), [str(v) for v in x.values()]
+        return [Path(v).name.split(".")[0] for v in x], [str(v) for v in x]
+
+    track_names, tracks = names_of(tracks)
+    ref_names, references = names_of(references)
+    if len(set(ref_names)) != len(ref_names):
+        raise ValueError("the names of the references must be unique")
+    if not isinstance(chrom_sizes, dict):
+        chrom_sizes = chrom_sizes.chrom_sizes
+    if n_jobs is not None and n_jobs <= 0:
+        n_jobs = os.cpu_count()
+    cor = internal.track_correlation(
+        tracks, references, list(chrom_sizes.items()), bin_size, method, skip_zeros, n_jobs,
+    )
+    return pl.DataFrame({"group": track_names}).with_columns(
+        [pl.Series(name, cor[:, i]) for i, name in enumerate(ref_names)]
+    )
+
+def export_vplot(
+    adata: internal.AnnData | internal.AnnDataSet,
+    anchors: Path | list[str],
+    groupby: str | list[str] | None = None,
+    flank: int = 1000,
+    max_frag_length: int = 500,
+    out_dir: Path = "./",
+    prefix: str = "",
+    suffix: str = ".npy",
+) -> dict[str, Path]:
+    """Export the V-plot of every group of cells around anchor sites.
+
+    The V-plot counts the fragments by length and by the offset of their
+    midpoint from the anchors, aggregated over all anchors. Nucleosomes
+    flanking the anchors appear as dense spots at fragment lengths around
+    150-250 bases, and sites bound by transcription factors as a "V" of short
+    fragments centred on the anchors.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions. The fragments
+        must be paired-end.
+    anchors
+        A BED file or a list of regions, e.g., `["chr1:1-100"]`. The anchor is
+        the center of each region. If the BED file has a strand column (6th
+        column), the offsets of anchors on the minus strand are reversed.
+    groupby
+        Group the cells. If a `str`, groups are obtained from `.obs[groupby]`.
+        If None, all cells are used.
+    flank
+        Number of bases on each side of the anchors.
+    max_frag_length
+        Maximum fragment length.
+    out_dir
+        Directory for saving the outputs.
+    prefix
+        Text added to the output file name.
+    suffix
+        Text added to the output file name. The format is determined by the
+        suffix: ".npy" saves the matrix of shape `(max_frag_length + 1)` x
+        `(2 * flank + 1)`, whose rows are fragment lengths and columns are
+        offsets from `-flank` to `flank`; ".parquet" saves the non-zero
+        entries in a table with the columns "length", "offset" and "count".
+
+    Returns
+    -------
+    dict[str, Path]
+        A dictionary mapping group names to output files.
+    """
+    import numpy as np
+    import pandas as pd
+
+    if suffix.endswith(".npy"):
+        output_format = "npy"
+    elif suffix.endswith(".parquet"):
+        output_format = "parquet"
+    else:
+        raise ValueError("The output format must be npy or parquet.")
+
+    if groupby is None:
+        groupby = ["all"] * adata.n_obs
+    elif isinstance(groupby, str):
+        groupby = adata.obs[groupby]
+    groupby = [str(x) for x in groupby]
+
+    anchors = read_anchors(anchors)
+    vplots = internal.vplot(adata, anchors, groupby, flank, max_frag_length)
+    out_dir = Path(out_dir)
+    out_dir.mkdir(parents=True, exist_ok=True)
+    result = {}
+    for group, mat in vplots.items():
+        filename = out_dir / f"{prefix}{group}{suffix}"
+        if output_format == "npy":
+            np.save(filename, mat)
+        else:
+            length, offset = np.nonzero(mat)
+            pd.DataFrame({
+                "length": length,
+                "offset": offset - flank,
+                "count": mat[length, offset],
+            }).to_parquet(filename)
+        result[group] = filename
+    return result
+
+def export_training_data(
+    adata: internal.AnnData | internal.AnnDataSet,
+    genome_fasta: Path | 'snapatac2.genome.Genome',
+    out_dir: Path,
+    regions: Path | list[str] | None = None,
+    groupby: str | list[str] | None = None,
+    window: int = 1344,
+    target_bin_size: int | None = None,
+    binarize: bool = False,
+    shard_size: int = 1000,
+    output_format: Literal["webdataset", "npz"] = "webdataset",
+    shuffle: bool = True,
+    random_state: int = 0,
+    counting_strategy: Literal['fragment', 'insertion', 'paired-insertion'] = 'insertion',
+    min_frag_size: int | None = None,
+    max_frag_size: int | None = None,
+) -> list[Path]:
+    """Export one-hot sequences and accessibility targets for model training.
+
+    Every region is resized to a window of `window` bases around its center.
+    The sequence of a window is one-hot encoded as an array of shape
+    `window` x 4, with the columns A, C, G and T; other bases, e.g., N, are
+    all zeros. The target of a window is the number of insertions
+    (see `counting_strategy`) of every cell or group of cells in bins of
+    `target_bin_size` bases, as an array of shape `n_groups` x `n_bins`.
+    With the defaults and `binarize=True`, the targets are the accessibility of
+    every cell, as used by scBasset. With groups of cells, e.g., metacells or
+    cell types, and small bins, they are coverage tracks as used by Enformer.
+
+    The samples are written in shards of `shard_size` windows, together with
+    a "metadata.json" file describing the groups and the shapes. With
+    `output_format="webdataset"`, each shard is a tar archive following the
+    WebDataset layout: the sample with key `k` consists of the members
+    `k.seq.npy`, `k.target.npy` and `k.region.txt`. With `output_format="npz"`,
+    each shard is a numpy archive with the arrays "sequence", "target" and
+    "region", stacked over the samples.
+
+    :func:`~snapatac2.pp.import_fragments` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    genome_fasta
+        A fasta file containing the genome sequences or a Genome object.
+    out_dir
+        Directory for saving the shards.
+    regions
+        A list of regions, e.g., `["chr1:1000-2000"]`, or a BED file.
+        If None, `adata.var_names` are used, e.g., the peaks of a peak matrix.
+    groupby
+        Group the cells. If a `str` or a list of `.obs` keys, groups are
+        obtained from `.obs`. Otherwise, a list of group labels, one per cell.
+        If None, every cell is a group of its own.
+    window
+        Size of the windows, in bases.
+    target_bin_size
+        Size of the target bins, in bases. It must divide `window`.
+        If None, the whole window is a single bin.
+    binarize
+        Whether to report 1 for bins with at least one insertion and 0 otherwise.
+    shard_size
+        Number of windows per shard.
+    output_format
+        "webdataset" or "npz".
+    shuffle
+        Whether to shuffle the windows before sharding, so that shards do not
+        cover contiguous parts of the genome.
+    random_state
+        Seed of the random number generator used for shuffling.
+    counting_strategy
+        The strategy to compute the targets. See
+        :func:`~snapatac2.pp.make_peak_matrix` for details.
+    min_frag_size
+        Minimum fragment size to include.
+    max_frag_size
+        Maximum fragment size to include.
+
+    Returns
+    -------
+    list[Path]
+        The shards.
+
+    See Also
+    --------
+    snapatac2.pp.make_region_bin_matrix
+    """
+    import io
+    import json
+    import tarfile
+    import numpy as np
+    import scipy.sparse as sp
+    from pyfaidx import Fasta
+
+    if target_bin_size is None:
+        target_bin_size = window
+    if window <= 0 or window % target_bin_size != 0:
+        raise ValueError("target_bin_size must divide window")
+    if output_format not in ("webdataset", "npz"):
+        raise ValueError("output_format must be 'webdataset' or 'npz'")
+    if not isinstance(genome_fasta, (str, Path)):
+        genome_fasta = genome_fasta.fasta
+    genome = Fasta(str(genome_fasta), one_based_attributes=False)
+
+    # Resize the regions, dropping windows that fall off the chromosomes.
+    if regions is None:
+        regions = list(adata.var_names)
+    elif not isinstance(regions, list):
+        regions = internal.read_regions(Path(regions))
+    windows = []
+    for region in regions:
+        chrom, coord = region.rsplit(":", 1)
+        start, end = (int(x) for x in coord.split("-"))
+        start = (start + end) // 2 - window // 2
+        if chrom in genome and start >= 0 and start + window <= len(genome[chrom]):
+            windows.append((chrom, start))
+    if len(windows) == 0:
+        raise ValueError("no window lies within the genome")
+    n_skipped = len(regions) - len(windows)
+    if n_skipped > 0:
+        logging.warning(f"{n_skipped} windows extending beyond the chromosomes are skipped")
+    if shuffle:
+        order = np.random.default_rng(random_state).permutation(len(windows))
+        windows = [windows[i] for i in order]
+
+    # Targets: bins by groups.
+    mat, bins = internal.mk_region_bin_matrix(
+        adata, [f"{c}:{s}-{s + window}" for c, s in windows], target_bin_size,
+        counting_strategy, min_frag_size, max_frag_size,
+    )
+    if groupby is None:
+        groups = list(adata.obs_names)
+        n_cells = [1] * len(groups)
+    else:
+        labels, _, names = _group_labels(adata, groupby, None)
+        groups = sorted(set(labels))
+        index = {g: i for i, g in enumerate(groups)}
+        indicator = sp.csr_matrix((
+            np.ones(len(labels)), ([index[x] for x in labels], np.arange(len(labels))),
+        ), shape=(len(groups), len(labels)))
+        mat = indicator @ mat
+        n_cells = np.asarray(indicator.sum(axis=1)).ravel().astype(int).tolist()
+        groups = [names[g] if isinstance(names[g], str) else g for g in groups]
+    mat = sp.csr_matrix(mat.T)
+    columns = {b: i for i, b in enumerate(bins)}
+    n_bins = window // target_bin_size
+
+    lookup = np.zeros((256, 4), dtype=np.uint8)
+    for i, base in enumerate("ACGT"):
+        lookup[ord(base), i] = 1
+        lookup[ord(base.lower()), i] = 1
+
+    def sample(chrom, start):
+        seq = genome[chrom][start:start + window].seq
+        seq = lookup[np.frombuffer(seq.encode("ascii"), dtype=np.uint8)]
+        rows = [
+            columns[f"{chrom}:{s}-{s + target_bin_size}"]
+            for s in range(start, start + window, target_bin_size)
+        ]
+        target = mat[rows].toarray().T
+        target = (target > 0).astype(np.uint8) if binarize else target.astype(np.uint32)
+        return seq, target, f"{chrom}:{start}-{start + window}"
+
+    out_dir = Path(out_dir)
+    out_dir.mkdir(parents=True, exist_ok=True)
+    shards = []
+    for k, i in enumerate(range(0, len(windows), shard_size)):
+        samples = [sample(c, s) for c, s in windows[i:i + shard_size]]
+        if output_format == "npz":
+            filename = out_dir / f"shard-{k:06d}.npz"
+            np.savez(
+                filename,
+                sequence=np.stack([x[0] for x in samples]),
+                target=np.stack([x[1] for x in samples]),
+                region=np.array([x[2] for x in samples]),
+            )
+        else:
+            filename = out_dir / f"shard-{k:06d}.tar"
+            with tarfile.open(filename, "w") as tar:
+                for j, (seq, target, region) in enumerate(samples):
+                    key = f"{i + j:09d}"
+                    for name, value in [("seq.npy", seq), ("target.npy", target), ("region.txt", region)]:
+                        buf = io.BytesIO()
+                        if isinstance(value, str):
+                            buf.write(value.encode())
+                        else:
+                            np.save(buf, value)
+                        info = tarfile.TarInfo(f"{key}.{name}")
+                        info.size = buf.tell()
+                        buf.seek(0)
+                        tar.addfile(info, buf)
+        shards.append(filename)
+
+    with open(out_dir / "metadata.json", "w") as f:
+        json.dump({
+            "format": output_format,
+            "shards": [x.name for x in shards],
+            "n_samples": len(windows),
+            "window": window,
+            "target_bin_size": target_bin_size,
+            "sequence_shape": [window, 4],
+            "target_shape": [len(groups), n_bins],
+            "binarize": binarize,
+            "counting_strategy": counting_strategy,
+            "groups": groups,
+            "n_cells": n_cells,
+        }, f, indent=2)
+    return shards
+
+def export_ldsc_annot(
+    regions: dict[str, list[str]],
+    plink_prefix: Path | str,
+    out_dir: Path = "./",
+    prefix: str = "",
+    window: int = 0,
+    thin: bool = True,
+) -> dict[str, list[Path]]:
+    """Export groups of regions as annotations for stratified LD score regression.
+
+    For every group of regions, e.g., the peaks or marker peaks of every
+    cluster, this writes one ".annot.gz" file per chromosome of the PLINK
+    reference panel, in the format expected by LDSC. A SNP is annotated with 1
+    if it lies in a region of the group, extended by `window` bases on each
+    side, and 0 otherwise. The files can be passed to `ldsc.py --l2 --annot`
+    together with the corresponding PLINK files to compute LD scores.
+
+    Parameters
+    ----------
+    regions
+        Groups of regions, e.g., `{"cluster1": ["chr1:1000-1500", ...]}`.
+    plink_prefix
+        The prefix of the PLINK files of the reference panel, e.g.,
+        "1000G_EUR_Phase3_plink/1000G.EUR.QC.". Every file named
+        `{plink_prefix}{chrom}.bim` is used, and the output files are named
+        after the same `{chrom}`. The leading "chr" of chromosome names is
+        ignored when matching the regions and the SNPs.
+    out_dir
+        Directory for saving the outputs.
+    prefix
+        Text added to the output file names.
+        In the file names, characters of the group names other than letters,
+        digits, ".", "-" and "_" are replaced by "_".
+    window
+        Number of bases added on each side of the regions.
+    thin
+        If True, the files contain a single column "ANNOT", as produced by
+        `make_annot.py --thin-annot`. Otherwise, they also contain the columns
+        "CHR", "BP", "SNP" and "CM" of the PLINK files.
+
+    Returns
+    -------
+    dict[str, list[Path]]
+        A dictionary mapping group names to the output files of every chromosome.
+
+    See Also
+    --------
+    snapatac2.tl.variant_enrichment
+    """
+    import numpy as np
+    import pandas as pd
+
+    parent, basename = os.path.split(str(plink_prefix))
+    bim_files = sorted(
+        Path(parent or ".").glob(basename + "*.bim"),
+        key=lambda x: (len(x.name), x.name),
+    )
+    if len(bim_files) == 0:
+        raise ValueError(f"no PLINK files found with the prefix {plink_prefix}")
+    normalize = lambda chrom: chrom[3:] if chrom.lower().startswith("chr") else chrom
+
+    # Merged intervals of every group, by chromosome.
+    intervals = {}
+    for group, rs in regions.items():
+        by_chrom = {}
+        for region in rs:
+            chrom, coord = region.rsplit(":", 1)
+            start, end = (int(x) for x in coord.split("-"))
+            by_chrom.setdefault(normalize(chrom), []).append((max(start - window, 0), end + window))
+        intervals[group] = {}
+        for chrom, xs in by_chrom.items():
+            merged = []
+            for start, end in sorted(xs):
+                if merged and start <= merged[-1][1]:
+                    merged[-1][1] = max(merged[-1][1], end)
+                else:
+                    merged.append([start, end])
+            intervals[group][chrom] = np.array(merged, dtype=np.int64)
+
+    names = {group: sanitize_filename(group) for group in regions}
+    if len(set(names.values())) < len(names):
+        raise ValueError("different groups have the same file name after escaping")
+    out_dir = Path(out_dir)
+    out_dir.mkdir(parents=True, exist_ok=True)
+    result = {group: [] for group in regions}
+    for bim_file in bim_files:
+        name = bim_file.name[len(basename):-len(".bim")]
+        bim = pd.read_csv(
+            bim_file, sep=r"\s+", header=None,
+            names=["CHR", "SNP", "CM", "BP", "A1", "A2"],
+            dtype={"CHR": str, "SNP": str},
+        )
+        chroms = bim["CHR"].map(normalize).to_numpy()
+        # 0-based positions of the SNPs.
+        positions = bim["BP"].to_numpy(dtype=np.int64) - 1
+        for group in regions:
+            annot = np.zeros(len(bim), dtype=np.int64)
+            for chrom in np.unique(chroms):
+                merged = intervals[group].get(chrom)
+                if merged is None:
+                    continue
+                idx = np.flatnonzero(chroms == chrom)
+                i = np.searchsorted(merged[:, 0], positions[idx], side="right") - 1
+                inside = (i >= 0) & (positions[idx] < merged[np.maximum(i, 0), 1])
+                annot[idx] = inside.astype(np.int64)
+            table = pd.DataFrame({"ANNOT": annot})
+            if not thin:
+                table = pd.concat([bim[["CHR", "BP", "SNP", "CM"]], table], axis=1)
+            filename = out_dir / f"{prefix}{names[group]}.{name}.annot.gz"
+            table.to_csv(filename, sep="\t", index=False, compression="gzip")
+            result[group].append(filename)
+    return result
+
+def export_qtl_phenotypes(
+    adata: internal.AnnData | internal.AnnDataSet,
+    donor: str | list[str],
+    *,
+    covariates: list[str] | None = None,
+    donor_covariates: 'pandas.DataFrame' | None = None,
+    n_pcs: int = 5,
+    normalization: Literal["inverse_normal", "log_cpm"] = "inverse_normal",
+    min_cells: int = 10,
+    min_cpm: float = 1.0,
+    out_dir: Path = "./",
+    prefix: str = "",
+) -> dict[str, Path]:
+    """Export donor x peak accessibility matrices for caQTL mapping.
+
+    The counts in `.X` of the cells of every donor are summed into pseudobulk
+    profiles and normalized. The result is written as a phenotype BED file,
+    in the format expected by tensorQTL and FastQTL: the columns "#chr", "start",
+    "end" and "phenotype_id" followed by one column per donor, with one row per
+    peak sorted by position. As the cis-window is centered on the interval
+    `[start, end)`, peaks are represented by their 1-bp summit, i.e., their center.
+    FastQTL additionally requires the file to be compressed with `bgzip` and
+    indexed with `tabix`; tensorQTL reads the gzipped file directly.
+
+    A covariate file is written alongside, with one row per covariate and one
+    column per donor, the first column "id" holding the covariate names.
+    It contains the requested donor covariates, categorical covariates being
+    one-hot encoded, and the top principal components of the phenotypes.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to peaks.
+        To map caQTLs of a single cell type, subset `adata` to its cells first.
+    donor
+        The donor of every cell. If a `str`, donors are obtained from `.obs[donor]`.
+        The donor names must match the sample names of the genotypes.
+    covariates
+        Donor covariates written to the covariate file, e.g., `["sex", "age"]`.
+    donor_covariates
+        A table of covariates indexed by donor. If None, covariates are read from
+        `.obs`, where they must be constant within every donor.
+    n_pcs
+        Number of principal components of the phenotypes added to the covariates.
+    normalization
+        "log_cpm": log2(CPM + 1). "inverse_normal": log2(CPM + 1) followed by a
+        rank-based inverse normal transformation of every peak across donors,
+        as commonly done for QTL mapping.
+    min_cells
+        Donors with fewer cells are discarded.
+    min_cpm
+        Peaks whose mean CPM across donors is below this value are discarded.
+    out_dir
+        Directory for saving the outputs.
+    prefix
+        Text added to the output file names.
+
+    Returns
+    -------
+    dict[str, Path]
+        The paths of the "phenotypes" (`{prefix}phenotypes.bed.gz`) and
+        "covariates" (`{prefix}covariates.txt`) files.
+
+    See Also
+    --------
+    snapatac2.tl.donor_association
+    """
+    import numpy as np
+    import pandas as pd
+    from scipy.stats import norm, rankdata
+    from snapatac2.tools._association import _donor_profiles, _donor_table, _encode
+
+    donor_names, peaks, cpm = _donor_profiles(adata, donor, min_cells, min_cpm)
+    values = np.log2(cpm + 1)
+    if normalization == "inverse_normal":
+        n = values.shape[0]
+        values = norm.ppf((rankdata(values, axis=0) - 0.5) / n)
+    elif normalization != "log_cpm":
+        raise NameError("normalization must be 'inverse_normal' or 'log_cpm'")
+
+    chroms, starts = [], []
+    for peak in peaks:
+        chrom, coord = peak.rsplit(":", 1)
+        start, end = (int(x) for x in coord.split("-"))
+        chroms.append(chrom)
+        starts.append((start + end) // 2)
+    bed = pd.DataFrame({
+        "#chr": chroms,
+        "start": starts,
+        "end": np.asarray(starts) + 1,
+        "phenotype_id": peaks,
+    })
+    bed = pd.concat([bed, pd.DataFrame(values, columns=donor_names)], axis=1)
+    # Chromosomes keep the order of the features.
+    chrom_rank = {c: i for i, c in reversed(list(enumerate(chroms)))}
+    order = sorted(range(len(bed)), key=lambda i: (chrom_rank[chroms[i]], starts[i]))
+    bed = bed.iloc[order]
+
+    tables = []
+    if covariates:
+        table = _donor_table(adata, donor, list(covariates), donor_covariates, donor_names)
+        tables.extend(_encode(table[c]) for c in covariates)
+    n_pcs = min(n_pcs, len(donor_names) - 1, values.shape[1])
+    if n_pcs > 0:
+        centered = values - values.mean(axis=0)
+        u, s, _ = np.linalg.svd(centered, full_matrices=False)
+        tables.append(pd.DataFrame(
+            u[:, :n_pcs] * s[:n_pcs],
+            index=donor_names,
+            columns=[f"PC{i + 1}" for i in range(n_pcs)],
+        ))
+    cov = pd.concat(tables, axis=1) if tables else pd.DataFrame(index=donor_names)
+    cov = cov.T
+    cov.index.name = "id"
+
+    out_dir = Path(out_dir)
+    out_dir.mkdir(parents=True, exist_ok=True)
+    files = {
+        "phenotypes": out_dir / f"{prefix}phenotypes.bed.gz",
+        "covariates": out_dir / f"{prefix}covariates.txt",
+    }
+    bed.to_csv(files["phenotypes"], sep="\t", index=False, compression="gzip")
+    cov.to_csv(files["covariates"], sep="\t")
+    return files
+
+def serve_tiles(
+    adata: internal.AnnData | internal.AnnDataSet,
+    groupby: str | list[str],
+    selections: list[str] | None = None,
+    *,
+    host: str = "127.0.0.1",
+    port: int = 8000,
+    max_bins: int = 10000,
+    tempdir: Path | None = None,
+) -> None:
+    """
+    Serve the signal of groups of cells to genome browsers over HTTP.
+
+    The fragments of the selected groups are sorted by position into a
+    temporary file, of which only a sparse index is kept in memory, and a HTTP
+    server answers queries for the binned signal of any region until it is
+    interrupted (Ctrl-C). This lets genome browser front ends display a
+    dataset without exporting tracks. The following endpoints are provided:
+
+    - `GET /groups`: a JSON list of the groups with their numbers of cells.
+    - `GET /tiles?region=chr1:1000-2000&bin_size=100`: the signal in bins of
+      `bin_size` tiling the region, as JSON
+      `{"region": ..., "bin_size": ..., "tracks": {group: [values]}}`.
+      Optional parameters are `group`, a comma-separated list of groups
+      (default: all groups), `track`, either "insertion" (the number of Tn5
+      insertions, default) or "fragment" (the number of overlapping fragments),
+      and `normalize` ("true" or "false", default), which scales insertion
+      counts to counts per million insertions of the group and fragment
+      counts to counts per cell.
+
+    :func:`~snapatac2.pp.import_fragments` must be ran first in order to use
+    this function. This requires SnapATAC2 to be built with the "server"
+    feature.
+
+    Parameters
+    ----------
+    adata
+        The annotated data matrix.
+    groupby
+        Group the cells. If a `str`, groups are obtained from `.obs[groupby]`.
+    selections
+        Serve only the selected groups.
+    host
+        The address to listen on.
+    port
+        The port to listen on.
+    max_bins
+        The maximum number of bins per query and group.
+    tempdir
+        Location to store the sorted fragments. If `None`, system temporary
+        directory will be used.
+    """
+    if not hasattr(internal, "serve_tiles"):
+        raise ImportError(
+            "SnapATAC2 was built without the tile server. "
+            "Please rebuild it with the 'server' feature enabled."
+        )
+    if isinstance(groupby, str):
+        groupby = adata.obs[groupby]
+    groups = [None if x is None else str(x) for x in groupby]
+    if selections is not None:
+        selections = {str(x) for x in selections}
+        groups = [x if x in selections else None for x in groups]
+    logging.info(f"Serving {len(set(x for x in groups if x is not None))} groups at http://{host}:{port}")
+    internal.serve_tiles(adata, groups, host, port, max_bins, tempdir)
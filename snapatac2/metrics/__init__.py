@@ -1,11 +1,13 @@
 from __future__ import annotations
 
 from pathlib import Path
+import logging
 from typing import Literal
 import numpy as np
 
 import snapatac2
 import snapatac2._snapatac2 as internal
+from snapatac2._utils import to_seed
 from snapatac2.genome import Genome
 
 def tsse(
@@ -14,7 +16,7 @@ def tsse(
     *,
     exclude_chroms: list[str] | str | None = ["chrM", "M"],
     inplace: bool = True,
-    n_jobs: int = 8,
+    n_jobs: int | None = None,
 ) -> np.ndarray | list[np.ndarray] | None:
     """ Compute the TSS enrichment score (TSSe) for each cell.
 
@@ -36,6 +38,7 @@ def tsse(
     n_jobs
         Number of jobs to run in parallel when `adata` is a list.
         If `n_jobs=-1`, all CPUs will be used.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
 
     Returns
     -------
@@ -80,6 +83,72 @@ def tsse(
     else:
         return result
 
+def tss_profile(
+    adata: internal.AnnData | list[internal.AnnData],
+    gene_anno: Genome | Path,
+    *,
+    window_size: int = 2000,
+    bin_size: int = 10,
+    exclude_chroms: list[str] | str | None = ["chrM", "M"],
+    key_added: str = "tss_profile",
+    n_jobs: int | None = None,
+) -> None:
+    """ Compute the per-cell insertion profile around TSSs.
+
+    The profile of a cell is the number of insertions at each position of the
+    regions spanning `window_size` bases on both sides of the TSSs, oriented by
+    strand and aggregated in bins of `bin_size` bases. It allows per-cell and
+    per-cluster TSS enrichment curves to be plotted, see
+    :func:`~snapatac2.pl.tss_profile`, without going through the fragments again.
+
+    :func:`~snapatac2.pp.import_fragments` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+        `adata` could also be a list of AnnData objects.
+        In this case, the function will be applied to each AnnData object in parallel.
+    gene_anno
+        A :class:`~snapatac2.Genome` object or a GTF/GFF file containing the gene annotation.
+    window_size
+        Number of bases on each side of the TSS.
+    bin_size
+        Size of the bins, in bases.
+    exclude_chroms
+        A list of chromosomes to exclude.
+    key_added
+        The cell-by-bin count matrix is stored in `adata.obsm[key_added]`, the
+        offset of the first base of every bin relative to the TSS in
+        `adata.uns[key_added + "_positions"]`, and the width of every bin in
+        `adata.uns[key_added + "_widths"]`. The last bin is narrower than
+        `bin_size` when `2 * window_size + 1` is not a multiple of it.
+    n_jobs
+        Number of jobs to run in parallel when `adata` is a list.
+        If `n_jobs=-1`, all CPUs will be used.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
+    """
+    gene_anno = gene_anno.annotation if isinstance(gene_anno, Genome) else gene_anno
+    if isinstance(exclude_chroms, str):
+        exclude_chroms = [exclude_chroms]
+
+    if isinstance(adata, list):
+        snapatac2._utils.anndata_par(
+            adata,
+            lambda x: tss_profile(
+                x, gene_anno, window_size=window_size, bin_size=bin_size,
+                exclude_chroms=exclude_chroms, key_added=key_added,
+            ),
+            n_jobs=n_jobs,
+        )
+    else:
+        bins = np.array(internal.tss_profile(
+            adata, gene_anno, window_size, bin_size, key_added, exclude_chroms,
+        ))
+        adata.uns[key_added + "_positions"] = bins[:, 0]
+        adata.uns[key_added + "_widths"] = bins[:, 1] - bins[:, 0]
+
 def frip(
     adata: internal.AnnData | list[internal.AnnData],
     regions: dict[str, Path | list[str]],
@@ -87,7 +156,7 @@ def frip(
     normalized: bool = True,
     count_as_insertion: bool = False,
     inplace: bool = True,
-    n_jobs: int = 8,
+    n_jobs: int | None = None,
 ) -> dict[str, list[float]] | list[dict[str, list[float]]] | None:
     """ Add fraction of reads in peaks (FRiP) to the AnnData object.
 
@@ -116,6 +185,7 @@ def frip(
     n_jobs
         Number of jobs to run in parallel when `adata` is a list.
         If `n_jobs=-1`, all CPUs will be used.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
 
     Returns
     -------
@@ -159,13 +229,246 @@ def frip(
     else:
         return result
 
+def spot(
+    adata: internal.AnnData | internal.AnnDataSet,
+    peaks: Path | list[str],
+    groupby: str | list[str] | None = None,
+    *,
+    min_frag_length: int | None = None,
+    max_frag_length: int | None = None,
+    blacklist: Path | None = None,
+    key_added: str = "spot",
+    inplace: bool = True,
+) -> dict[str, dict[str, float]] | None:
+    """ Compute the signal portion of tags (SPOT) and the peak enrichment of each group of cells.
+
+    Following the ENCODE ATAC-seq pipeline, SPOT is the fraction of Tn5
+    insertions of a group falling in the peaks. Because SPOT grows with the
+    size of the peak set, it is also reported relative to the fraction of the
+    genome covered by the peaks, i.e., the SPOT expected if the insertions were
+    uniformly distributed. An enrichment close to 1 indicates no signal.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    peaks
+        A BED file or a list of strings representing the peaks.
+    groupby
+        Group the cells. If a `str`, groups are obtained from `.obs[groupby]`.
+        If None, all cells form a single group "all".
+    min_frag_length
+        Minimum fragment length to be included in the computation.
+    max_frag_length
+        Maximum fragment length to be included in the computation.
+    blacklist
+        A BED file containing the blacklisted regions. Fragments overlapping
+        these regions are ignored.
+    key_added
+        The results are stored in `adata.uns[key_added]`, as a dictionary of
+        arrays with the keys "group", "n_insertions", "n_insertions_in_peaks",
+        "spot" and "enrichment".
+    inplace
+        Whether to store the results in `adata.uns` or return them.
+
+    Returns
+    -------
+    dict[str, dict[str, float]] | None
+        If `inplace = False`, for each group, a dictionary with the number of
+        insertions, the number of insertions in peaks, the SPOT and the enrichment.
+
+    See Also
+    --------
+    frip
+    """
+    if isinstance(peaks, (str, Path)):
+        peaks = internal.read_regions(Path(peaks))
+    if groupby is None:
+        groupby = ["all"] * adata.n_obs
+    elif isinstance(groupby, str):
+        groupby = adata.obs[groupby]
+    groupby = [str(x) for x in groupby]
+
+    result = internal.spot(adata, list(peaks), groupby, min_frag_length, max_frag_length, blacklist)
+    if not inplace:
+        return result
+    _store_spot(adata, result, key_added)
+
+def _store_spot(adata, result: dict[str, dict[str, float]], key_added: str):
+    groups = sorted(result.keys())
+    stats = {"group": np.array(groups)}
+    for k in ["n_insertions", "n_insertions_in_peaks", "spot", "enrichment"]:
+        stats[k] = np.array([result[g][k] for g in groups])
+    adata.uns[key_added] = stats
+
+def peak_allocation(
+    adata: internal.AnnData | internal.AnnDataSet,
+    peaks: Path | list[str],
+    groupby: str | list[str] | None = None,
+) -> 'polars.DataFrame':
+    """ Report how the fragments of each group of cells are allocated to the peaks.
+
+    A peak matrix depends on the counting strategy (see
+    :func:`~snapatac2.pp.make_peak_matrix`): a fragment may add counts to no
+    peak, to a single peak, or to several peaks when it spans neighboring
+    peaks or its two insertions fall in different peaks. It may also add one
+    or two counts to a single peak. This diagnostic counts the fragments of
+    every group in these categories under each strategy, which helps
+    understanding the differences between matrices made with different
+    strategies or peak sets.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    peaks
+        A BED file or a list of strings representing the peaks.
+    groupby
+        Group the cells. If a `str`, groups are obtained from `.obs[groupby]`.
+        If None, all cells form a single group "all".
+
+    Returns
+    -------
+    polars.DataFrame
+        A table with one row per group and counting strategy ("fragment",
+        "insertion" or "paired-insertion"), and the columns "group",
+        "strategy", "n_no_peak", "n_one_peak" and "n_multiple_peaks", the
+        numbers of fragments adding counts to no peak, one peak and more than
+        one peak, and "n_counts", the total counts added to the peak matrix.
+
+    See Also
+    --------
+    spot
+    snapatac2.pp.make_peak_matrix
+    """
+    import polars as pl
+
+    if isinstance(peaks, (str, Path)):
+        peaks = internal.read_regions(Path(peaks))
+    if groupby is None:
+        groupby = ["all"] * adata.n_obs
+    elif isinstance(groupby, str):
+        groupby = adata.obs[groupby]
+    groupby = [str(x) for x in groupby]
+
+    result = internal.peak_allocation(adata, list(peaks), groupby)
+    return pl.DataFrame(
+        result,
+        schema=["group", "strategy", "n_no_peak", "n_one_peak", "n_multiple_peaks", "n_counts"],
+        orient="row",
+    )
+
+def signal_partition(
+    adata: internal.AnnData | list[internal.AnnData],
+    gene_anno: Genome | Path,
+    *,
+    peaks: Path | list[str] | None = None,
+    blacklist: Path | list[str] | None = None,
+    promoter_size: int = 1000,
+    count_as_insertion: bool = False,
+    exclude_chroms: list[str] | str | None = ["chrM", "M"],
+    key_prefix: str = "frac_",
+    inplace: bool = True,
+    n_jobs: int | None = None,
+) -> dict[str, np.ndarray] | list[dict[str, np.ndarray]] | None:
+    """ Compute how the fragments of each cell are partitioned among genomic features.
+
+    The fraction of fragments overlapping promoters, exons, distal peaks
+    (peaks that do not overlap any promoter) and blacklisted regions is computed
+    for every cell, along with the promoter-to-distal ratio. These metrics
+    complement the TSS enrichment score for cell filtering: cells dominated by
+    promoter signal, or with a high fraction of fragments in blacklisted
+    regions, are often of low quality.
+
+    :func:`~snapatac2.pp.import_fragments` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+        `adata` could also be a list of AnnData objects.
+        In this case, the function will be applied to each AnnData object in parallel.
+    gene_anno
+        A :class:`~snapatac2.Genome` object or a GTF/GFF file containing the gene annotation.
+        Promoters are derived from the transcripts, and exons from the exon records.
+    peaks
+        A BED file or a list of strings representing the peaks. If `None`,
+        the distal peak fraction and the promoter-to-distal ratio are not computed.
+    blacklist
+        A BED file or a list of strings representing the blacklisted regions.
+        If `None`, the blacklist fraction is not computed.
+    promoter_size
+        Number of bases on each side of the TSS defining the promoter.
+    count_as_insertion
+        Whether to count transposition events instead of fragments.
+    exclude_chroms
+        A list of chromosomes to exclude from the annotation.
+    key_prefix
+        Prefix of the column names. The results are stored in
+        `{key_prefix}promoter`, `{key_prefix}exon`, `{key_prefix}distal_peak`,
+        `{key_prefix}blacklist` and `promoter_distal_ratio`.
+    inplace
+        Whether to add the results to `adata.obs` or return them as a dictionary.
+    n_jobs
+        Number of jobs to run in parallel when `adata` is a list.
+        If `n_jobs=-1`, all CPUs will be used.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
+
+    Returns
+    -------
+    dict[str, np.ndarray] | list[dict[str, np.ndarray]] | None
+        If `inplace = True`, directly adds the results to `adata.obs`.
+        Otherwise return a dictionary containing the results.
+    """
+    gene_anno = gene_anno.annotation if isinstance(gene_anno, Genome) else gene_anno
+    if isinstance(exclude_chroms, str):
+        exclude_chroms = [exclude_chroms]
+    if isinstance(peaks, (str, Path)):
+        peaks = internal.read_regions(Path(peaks))
+    if isinstance(blacklist, (str, Path)):
+        blacklist = internal.read_regions(Path(blacklist))
+
+    if isinstance(adata, list):
+        return snapatac2._utils.anndata_par(
+            adata,
+            lambda x: signal_partition(
+                x, gene_anno, peaks=peaks, blacklist=blacklist, promoter_size=promoter_size,
+                count_as_insertion=count_as_insertion, exclude_chroms=exclude_chroms,
+                key_prefix=key_prefix, inplace=inplace,
+            ),
+            n_jobs=n_jobs,
+        )
+
+    frac = internal.signal_partition(
+        adata, gene_anno, promoter_size, count_as_insertion,
+        None if peaks is None else list(peaks),
+        None if blacklist is None else list(blacklist),
+        exclude_chroms,
+    )
+    result = {key_prefix + k: np.array(v) for k, v in frac.items()}
+    if "distal_peak" in frac:
+        distal = np.array(frac["distal_peak"])
+        with np.errstate(divide="ignore", invalid="ignore"):
+            result["promoter_distal_ratio"] = np.where(
+                distal > 0, np.array(frac["promoter"]) / distal, np.nan,
+            )
+    if inplace:
+        for k, v in result.items():
+            adata.obs[k] = v
+        return None
+    else:
+        return result
+
 def frag_size_distr(
     adata: internal.AnnData | list[internal.AnnData],
     *,
     max_recorded_size: int = 1000,
     add_key: str = "frag_size_distr",
     inplace: bool = True,
-    n_jobs: int = 8,
+    n_jobs: int | None = None,
 ) -> np.ndarray | list[np.ndarray] | None:
     """ Compute the fragment size distribution of the dataset. 
 
@@ -194,6 +497,7 @@ def frag_size_distr(
     n_jobs
         Number of jobs to run in parallel when `adata` is a list.
         If `n_jobs=-1`, all CPUs will be used.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
 
     Returns
     -------
@@ -218,7 +522,7 @@ def summary_by_chrom(
     adata: internal.AnnData | list[internal.AnnData],
     *,
     mode: Literal['sum', 'mean', 'count'] = 'count',
-    n_jobs: int = 8,
+    n_jobs: int | None = None,
 ) -> dict[str, np.ndarray]:
     """ Compute the cell level summary statistics by chromosome.
 
@@ -239,6 +543,7 @@ def summary_by_chrom(
     n_jobs
         Number of jobs to run in parallel when `adata` is a list.
         If `n_jobs=-1`, all CPUs will be used.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
 
     Returns
     -------
@@ -253,4 +558,513 @@ def summary_by_chrom(
             n_jobs=n_jobs,
         )
     else:
-        return internal.summary_by_chrom(adata, mode)
\ No newline at end of file
+        return internal.summary_by_chrom(adata, mode)
+
+def sex_chrom_ratio(
+    adata: internal.AnnData,
+    *,
+    groupby: str | list[str] | None = None,
+    chrX: list[str] = ["chrX", "X"],
+    chrY: list[str] = ["chrY", "Y"],
+    min_y_fraction: float = 0.02,
+    min_fragments: int = 10,
+    inplace: bool = True,
+) -> dict[str, list] | 'polars.DataFrame' | None:
+    """ Compute sex chromosome fragment ratios and infer the donor sex.
+
+    The fragments of every cell (or every sample, if `groupby` is given) on
+    chromosomes X and Y are counted, and the fraction of them falling on
+    chromosome Y, `chrY / (chrX + chrY)`, is used to infer the sex: "male" if
+    the fraction is at least `min_y_fraction` and "female" otherwise.
+    Female cells still receive a few fragments on chromosome Y from the
+    pseudoautosomal regions and from mismapped reads, so the fraction is rarely
+    zero. Inferring the sex per sample, by pooling the fragments of its cells, is
+    much more reliable than per cell and helps detecting sample swaps.
+
+    :func:`~snapatac2.pp.import_fragments` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    groupby
+        The sample of every cell. If a `str`, samples are obtained from `.obs[groupby]`.
+        If None, the sex is inferred for every cell separately.
+    chrX
+        Names of chromosome X.
+    chrY
+        Names of chromosome Y.
+    min_y_fraction
+        Minimal fraction of the sex chromosome fragments on chromosome Y
+        for a cell or sample to be called "male".
+    min_fragments
+        Minimal number of fragments on chromosomes X and Y. Cells or samples with
+        fewer fragments are called "unknown".
+    inplace
+        Whether to add the results to `adata.obs` or return them.
+
+    Returns
+    -------
+    dict[str, list] | polars.DataFrame | None
+        If `inplace = True`, adds "chrX_frac", "chrY_frac", "chrY_ratio" and "sex"
+        to `adata.obs`, where "chrX_frac" and "chrY_frac" are the fractions of
+        the fragments of every cell on chromosomes X and Y, and "chrY_ratio" and
+        "sex" are computed per cell or per sample.
+        Otherwise return these columns as a dictionary.
+        If `groupby` is given, a table with the columns "sample", "n_cells",
+        "chrX", "chrY", "chrY_ratio" and "sex" is returned in both cases.
+    """
+    import polars as pl
+
+    counts = internal.summary_by_chrom(adata, 'count')
+    total = np.zeros(adata.n_obs)
+    for v in counts.values():
+        total += np.asarray(v)
+    x = sum((np.asarray(counts[c]) for c in chrX if c in counts), np.zeros(adata.n_obs))
+    y = sum((np.asarray(counts[c]) for c in chrY if c in counts), np.zeros(adata.n_obs))
+
+    def infer(x, y):
+        n = x + y
+        ratio = np.divide(y, n, out=np.zeros_like(n, dtype=np.float64), where=n > 0)
+        sex = np.where(n < min_fragments, "unknown", np.where(ratio >= min_y_fraction, "male", "female"))
+        return ratio, sex
+
+    table = None
+    if groupby is None:
+        ratio, sex = infer(x, y)
+    else:
+        if isinstance(groupby, str):
+            groupby = adata.obs[groupby]
+        samples = np.array([str(s) for s in groupby])
+        names, index = np.unique(samples, return_inverse=True)
+        sample_x = np.bincount(index, weights=x, minlength=len(names))
+        sample_y = np.bincount(index, weights=y, minlength=len(names))
+        sample_ratio, sample_sex = infer(sample_x, sample_y)
+        ratio, sex = sample_ratio[index], sample_sex[index]
+        table = pl.DataFrame({
+            "sample": names.tolist(),
+            "n_cells": np.bincount(index, minlength=len(names)),
+            "chrX": sample_x.astype(np.int64),
+            "chrY": sample_y.astype(np.int64),
+            "chrY_ratio": sample_ratio,
+            "sex": sample_sex.tolist(),
+        })
+
+    result = {
+        "chrX_frac": np.divide(x, total, out=np.zeros_like(total), where=total > 0),
+        "chrY_frac": np.divide(y, total, out=np.zeros_like(total), where=total > 0),
+        "chrY_ratio": ratio,
+        "sex": sex.tolist(),
+    }
+    if inplace:
+        for k, v in result.items():
+            adata.obs[k] = v
+        return table
+    else:
+        return result if table is None else table
+
+def replication_timing(
+    adata: internal.AnnData,
+    early: Path | list[str],
+    late: Path | list[str],
+    *,
+    count_as_insertion: bool = False,
+    pseudocount: float = 1.0,
+    min_fragments: int = 100,
+    threshold: float = 3.0,
+    key_prefix: str = "rt_",
+    inplace: bool = True,
+) -> dict[str, np.ndarray] | None:
+    """ Score the replication-timing signal of every cell to identify cycling cells.
+
+    Early-replicating regions of the genome are duplicated in the first half
+    of the S phase, while late-replicating regions are duplicated at its end.
+    Cells in S phase therefore receive more fragments in early-replicating
+    regions relative to late-replicating ones than cells in G1. The score of a
+    cell is the log2 ratio of its fragments in the early and late regions,
+    centered at the median of all cells, which are assumed to be mostly
+    non-cycling. Cells whose score exceeds `threshold` times the robust standard
+    deviation (1.4826 times the median absolute deviation, or the standard
+    deviation if the former is zero) are called cycling.
+    The score depends on the depth of the cells, so it is only computed for
+    cells with at least `min_fragments` fragments in the early and late regions.
+
+    :func:`~snapatac2.pp.import_fragments` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    early
+        A BED file or a list of strings representing the early-replicating
+        regions, e.g., the bins of a Repli-seq track with a positive
+        early-to-late ratio.
+    late
+        A BED file or a list of strings representing the late-replicating regions.
+    count_as_insertion
+        Whether to count transposition events instead of fragments.
+    pseudocount
+        Pseudocount added to the counts before computing the log2 ratio.
+    min_fragments
+        Minimal number of fragments in the early and late regions. Cells with
+        fewer fragments are given a score of NaN and are not called cycling.
+    threshold
+        Minimal robust z-score of the cycling cells.
+    key_prefix
+        Prefix of the column names. The results are stored in
+        `{key_prefix}score`, `{key_prefix}zscore` and `{key_prefix}cycling`.
+    inplace
+        Whether to add the results to `adata.obs` or return them as a dictionary.
+
+    Returns
+    -------
+    dict[str, np.ndarray] | None
+        If `inplace = True`, directly adds the results to `adata.obs`.
+        Otherwise return a dictionary containing the results.
+    """
+    regions = {}
+    for k, v in [("early", early), ("late", late)]:
+        regions[k] = internal.read_regions(Path(v)) if isinstance(v, (str, Path)) else list(v)
+    counts = internal.add_frip(adata, regions, False, count_as_insertion)
+    early_count = np.asarray(counts["early"], dtype=np.float64)
+    late_count = np.asarray(counts["late"], dtype=np.float64)
+
+    valid = early_count + late_count >= min_fragments
+    score = np.full(adata.n_obs, np.nan)
+    score[valid] = np.log2((early_count[valid] + pseudocount) / (late_count[valid] + pseudocount))
+    zscore = np.full(adata.n_obs, np.nan)
+    if valid.any():
+        score[valid] -= np.median(score[valid])
+        scale = 1.4826 * np.median(np.abs(score[valid]))
+        if scale == 0:
+            # More than half of the cells have the median score, e.g., in
+            # shallow data. Fall back to the standard deviation.
+            scale = np.std(score[valid])
+            logging.warning(
+                "The median absolute deviation of the scores is zero, "
+                "the standard deviation is used to compute the z-scores instead"
+            )
+        zscore[valid] = score[valid] / scale if scale > 0 else 0.0
+
+    result = {
+        key_prefix + "score": score,
+        key_prefix + "zscore": zscore,
+        key_prefix + "cycling": np.nan_to_num(zscore, nan=-np.inf) >= threshold,
+    }
+    if inplace:
+        for k, v in result.items():
+            adata.obs[k] = v
+        return None
+    else:
+        return result
+
+def kbet(
+    adata: internal.AnnData | internal.AnnDataSet,
+    batch: str | list[str],
+    *,
+    use_graph: str = 'distances',
+    alpha: float = 0.05,
+    inplace: bool = True,
+) -> float | None:
+    """ Compute the k-nearest neighbor batch effect test (kBET) rejection rate.
+
+    For each cell, the batch composition of its neighborhood is compared against
+    the global batch composition using a chi-squared test. A low rejection rate
+    indicates that cells from different batches are well mixed.
+
+    :func:`~snapatac2.pp.knn` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+    batch
+        Batch labels. If a `str`, labels are obtained from `.obs[batch]`.
+    use_graph
+        The key of the kNN graph in `.obsp`.
+    alpha
+        Significance level of the test.
+    inplace
+        Whether to store the per-cell p-values in `adata.obs['kbet_pvalue']`
+        and the rejection rate in `adata.uns['kbet_rejection_rate']`.
+
+    Returns
+    -------
+    float | None
+        If `inplace = False`, return the rejection rate.
+    """
+    if isinstance(batch, str):
+        batch = adata.obs[batch]
+    batch = [str(x) for x in batch]
+    rate, pvalues = internal.kbet(adata.obsp[use_graph], batch, alpha)
+    if inplace:
+        adata.obs['kbet_pvalue'] = pvalues
+        adata.uns['kbet_rejection_rate'] = rate
+    else:
+        return rate
+
+def lisi(
+    adata: internal.AnnData | internal.AnnDataSet,
+    labels: str | list[str],
+    *,
+    use_graph: str = 'distances',
+    perplexity: float = 30,
+    key_added: str | None = None,
+    inplace: bool = True,
+) -> np.ndarray | None:
+    """ Compute the Local Inverse Simpson's Index (LISI) for each cell.
+
+    When `labels` are batches (iLISI), larger values indicate better mixing,
+    with the maximum being the number of batches. When `labels` are cell types
+    (cLISI), values close to 1 indicate that cell types remain separated.
+
+    :func:`~snapatac2.pp.knn` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+    labels
+        Cell labels. If a `str`, labels are obtained from `.obs[labels]`.
+    use_graph
+        The key of the kNN graph in `.obsp`.
+    perplexity
+        Effective number of neighbors. It should be smaller than the number
+        of neighbors in the graph.
+    key_added
+        The key in `adata.obs` used to store the results.
+        If `None`, it is set to `lisi_{labels}` when `labels` is a `str`, or `lisi` otherwise.
+    inplace
+        Whether to store the results in `adata.obs`.
+
+    Returns
+    -------
+    np.ndarray | None
+        If `inplace = False`, return the per-cell LISI scores.
+    """
+    if key_added is None:
+        key_added = f"lisi_{labels}" if isinstance(labels, str) else "lisi"
+    if isinstance(labels, str):
+        labels = adata.obs[labels]
+    labels = [str(x) for x in labels]
+    result = np.array(internal.lisi(adata.obsp[use_graph], labels, perplexity))
+    if inplace:
+        adata.obs[key_added] = result
+    else:
+        return result
+
+def cluster_stability(
+    adata: internal.AnnData | internal.AnnDataSet,
+    groupby: str | list[str],
+    *,
+    use_rep: str = 'X_spectral',
+    n_neighbors: int = 50,
+    resolution: float = 1,
+    n_iter: int = 20,
+    cell_fraction: float = 1.0,
+    feature_fraction: float = 1.0,
+    random_state: int = 0,
+    inplace: bool = True,
+) -> tuple[dict[str, float], np.ndarray] | None:
+    """ Assess the stability of a clustering by bootstrap resampling.
+
+    In every replicate, cells are drawn with replacement (and optionally a
+    random subset of the dimensions in `adata.obsm[use_rep]` is selected), the
+    kNN graph is rebuilt and the cells are re-clustered with the Leiden
+    algorithm. The new clusters of the distinct drawn cells are compared to the
+    reference clustering. Clusters that are consistently
+    recovered have a mean Jaccard index close to 1.
+    This is useful for choosing the resolution of the clustering.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+    groupby
+        Reference cluster labels. If a `str`, labels are obtained from `.obs[groupby]`.
+    use_rep
+        The key of the embedding in `.obsm` used for re-clustering.
+    n_neighbors
+        Number of neighbors used to build the kNN graph.
+    resolution
+        Resolution of the Leiden clustering.
+    n_iter
+        Number of bootstrap replicates.
+    cell_fraction
+        Number of cells drawn with replacement in every replicate, as a
+        fraction of the number of cells.
+    feature_fraction
+        Fraction of embedding dimensions retained in every replicate, selected
+        without replacement.
+    random_state
+        Seed of the random number generator.
+    inplace
+        Whether to store the results in `adata.uns['cluster_stability']`.
+
+    Returns
+    -------
+    tuple[dict[str, float], np.ndarray] | None
+        If `inplace = False`, return the mean Jaccard index of every reference
+        cluster and the adjusted Rand index of every replicate.
+    """
+    if isinstance(groupby, str):
+        groupby = adata.obs[groupby]
+    reference = [str(x) for x in groupby]
+    data = adata.obsm[use_rep]
+
+    def cluster_fn(cells, features):
+        mat = data[cells, :]
+        if features is not None:
+            mat = mat[:, features]
+        adj = internal.nearest_neighbour_graph(np.ascontiguousarray(mat, dtype=np.float64), n_neighbors)
+        labels = snapatac2.tl.leiden(
+            adj, resolution=resolution, min_cluster_size=1, random_state=random_state,
+        )
+        return [str(x) for x in labels]
+
+    n_features = data.shape[1] if feature_fraction < 1 else None
+    jaccard, ari = internal.bootstrap_stability(
+        reference, cluster_fn, n_iter, cell_fraction, n_features, feature_fraction, to_seed(random_state),
+    )
+    clusters = list(dict.fromkeys(reference))
+    jaccard = dict(zip(clusters, jaccard))
+    if inplace:
+        adata.uns['cluster_stability'] = {'jaccard': jaccard, 'ari': np.array(ari)}
+    else:
+        return jaccard, np.array(ari)
+
+def clustering_scores(
+    labels_true: list[str] | np.ndarray,
+    labels_pred: list[str] | np.ndarray,
+) -> dict[str, float]:
+    """ Compare two clusterings of the same cells.
+
+    Parameters
+    ----------
+    labels_true
+        Ground truth (or reference) labels.
+    labels_pred
+        Cluster labels to evaluate.
+
+    Returns
+    -------
+    dict[str, float]
+        A dictionary containing the adjusted Rand index (`ARI`), the normalized
+        mutual information (`NMI`), `homogeneity`, `completeness` and `v_measure`.
+    """
+    labels_true = [str(x) for x in labels_true]
+    labels_pred = [str(x) for x in labels_pred]
+    h, c, v = internal.homogeneity_completeness_v_measure(labels_true, labels_pred)
+    return {
+        'ARI': internal.adjusted_rand_index(labels_true, labels_pred),
+        'NMI': internal.normalized_mutual_info(labels_true, labels_pred),
+        'homogeneity': h,
+        'completeness': c,
+        'v_measure': v,
+    }
+
+def silhouette(
+    adata: internal.AnnData | internal.AnnDataSet,
+    groupby: str | list[str],
+    *,
+    use_rep: str = 'X_spectral',
+    chunk_size: int = 1000,
+    key_added: str = 'silhouette',
+    inplace: bool = True,
+) -> float | None:
+    """ Compute the silhouette coefficient of every cell on an embedding.
+
+    Distances are computed in blocks of cells so that the memory usage does not
+    grow quadratically with the number of cells.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+    groupby
+        Cluster labels. If a `str`, labels are obtained from `.obs[groupby]`.
+    use_rep
+        The key of the embedding in `.obsm`.
+    chunk_size
+        Distances are computed in blocks of `chunk_size` x `chunk_size` cells.
+        Larger blocks are faster but use more memory.
+    key_added
+        The key in `adata.obs` used to store the per-cell silhouette.
+    inplace
+        Whether to store the per-cell silhouette in `adata.obs[key_added]`.
+
+    Returns
+    -------
+    float | None
+        The mean silhouette score. If `inplace = False`, it is returned.
+    """
+    if isinstance(groupby, str):
+        groupby = adata.obs[groupby]
+    labels = [str(x) for x in groupby]
+    data = np.ascontiguousarray(adata.obsm[use_rep], dtype=np.float64)
+    result = np.array(internal.silhouette(data, labels, chunk_size))
+    if inplace:
+        adata.obs[key_added] = result
+    else:
+        return float(result.mean())
+
+def sample_qc(
+    adata: internal.AnnData | internal.AnnDataSet,
+    groupby: str | list[str],
+    *,
+    n_comps: int = 10,
+    n_top_features: int | None = 5000,
+    method: Literal['pearson', 'spearman'] = 'pearson',
+) -> tuple['polars.DataFrame', 'polars.DataFrame', 'polars.DataFrame']:
+    """ Sample-level PCA and correlations of pseudobulk profiles.
+
+    The counts in `.X` of the cells of every sample are summed and transformed
+    to log2(CPM + 1). The samples are then compared by a principal component
+    analysis and by their pairwise correlations, computed on the most variable
+    features. This helps spotting outlier samples and batch effects before
+    cell-level analyses.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    groupby
+        The sample of every cell. If a `str`, samples are obtained from `.obs[groupby]`.
+    n_comps
+        Number of principal components, at most the number of samples minus one.
+    n_top_features
+        Number of most variable features used. If None, all features are used.
+    method
+        Correlation method, "pearson" or "spearman".
+
+    Returns
+    -------
+    tuple[polars.DataFrame, polars.DataFrame, polars.DataFrame]
+        A table with one row per sample and the columns "sample", "n_cells",
+        "PC1", "PC2", ...; a table with the columns "component" and
+        "variance_ratio", the fraction of the variance explained by each
+        component; and the correlation matrix, with a column "sample"
+        followed by one column per sample.
+    """
+    import polars as pl
+
+    if isinstance(groupby, str):
+        groupby = adata.obs[groupby]
+    samples = [None if x is None else str(x) for x in groupby]
+    names, n_cells, pca, variance_ratio, correlation = internal.pseudobulk_qc(
+        adata, samples, n_comps, method, n_top_features,
+    )
+    pca_table = pl.DataFrame({"sample": names, "n_cells": n_cells}).with_columns(
+        [pl.Series(f"PC{i + 1}", pca[:, i]) for i in range(pca.shape[1])]
+    )
+    variance_table = pl.DataFrame({
+        "component": [f"PC{i + 1}" for i in range(len(variance_ratio))],
+        "variance_ratio": variance_ratio,
+    })
+    correlation_table = pl.DataFrame({"sample": names}).with_columns(
+        [pl.Series(name, correlation[:, i]) for i, name in enumerate(names)]
+    )
+    return pca_table, variance_table, correlation_table
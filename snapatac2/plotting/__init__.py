@@ -12,7 +12,7 @@ from ._network import network_scores, network_edge_stat
 import snapatac2._snapatac2 as internal
 
 __all__ = [
-    'tsse', 'frag_size_distr', 'umap', 'network_scores', 'spectral_eigenvalues',
+    'tsse', 'tss_profile', 'frag_size_distr', 'umap', 'network_scores', 'spectral_eigenvalues',
     'regions', 'motif_enrichment', 'coverage'
 ]
 
@@ -106,6 +106,75 @@ def tsse(
 
     return render_plot(fig, width, height, **kwargs)
 
+def tss_profile(
+    adata: AnnData,
+    groupby: str | list[str] | None = None,
+    use_rep: str = "tss_profile",
+    normalize: bool = True,
+    width: int = 600,
+    height: int = 400,
+    **kwargs,
+) -> 'plotly.graph_objects.Figure' | None:
+    """Plot the aggregated insertion profile around TSSs.
+
+    Parameters
+    ----------
+    adata
+        Annotated data matrix with the per-cell profile computed by
+        :func:`~snapatac2.metrics.tss_profile`.
+    groupby
+        Group the cells and plot one curve per group. If a `str`, groups are
+        obtained from `.obs[groupby]`. If `None`, all cells are aggregated.
+    use_rep
+        Key of the profile in `.obsm`.
+    normalize
+        Whether to divide the counts of every curve by the average count of
+        the first and last 100 bases, i.e., to show the TSS enrichment.
+    width
+        The width of the plot
+    height
+        The height of the plot
+    kwargs
+        Additional arguments passed to :func:`~snapatac2.pl.render_plot` to
+        control the final plot output. Please see :func:`~snapatac2.pl.render_plot`
+        for details.
+
+    Returns
+    -------
+    'plotly.graph_objects.Figure' | None
+        If `show=False` and `out_file=None`, an `plotly.graph_objects.Figure` will be
+        returned, which can then be further customized using the plotly API.
+    """
+    import plotly.graph_objects as go
+
+    if use_rep not in adata.obsm:
+        raise ValueError(f"'{use_rep}' is not found in `.obsm`, please run `metrics.tss_profile` first.")
+    mat = adata.obsm[use_rep]
+    positions = np.asarray(adata.uns[use_rep + "_positions"])
+    widths = np.asarray(adata.uns[use_rep + "_widths"])
+    n_flank = max(1, 100 // widths[0])
+
+    if groupby is None:
+        groups = {"all": np.arange(adata.n_obs)}
+    else:
+        labels = np.asarray(adata.obs[groupby] if isinstance(groupby, str) else groupby)
+        groups = {g: np.where(labels == g)[0] for g in np.unique(labels)}
+
+    fig = go.Figure()
+    for name, idx in groups.items():
+        # Insertions per base, as the last bin may be narrower than the others.
+        profile = np.asarray(mat[idx, :].sum(axis=0)).ravel() / widths
+        if normalize:
+            background = np.concatenate([profile[:n_flank], profile[-n_flank:]]).mean()
+            profile = profile / (background + 0.1)
+        fig.add_trace(go.Scatter(x=positions + widths / 2, y=profile, mode="lines", name=str(name)))
+    fig.update_layout(
+        xaxis_title="Distance to TSS (bp)",
+        yaxis_title="TSS enrichment" if normalize else "Insertions per base",
+    )
+
+    return render_plot(fig, width, height, **kwargs)
+
 def frag_size_distr(
     adata: AnnData | np.ndarray,
     use_rep: str = "frag_size_distr",
@@ -387,6 +456,7 @@ def coverage(
     region: str,
     groupby: str | list[str],
     out_file: str | None = None,
+    use_cache: bool = True,
 ):
     """
     Plot the coverage tracks for different groups of cells. This function requires
@@ -407,6 +477,11 @@ def coverage(
         `.obs[groupby]`.
     out_file
         Path of the output file for saving the output image.
+    use_cache
+        If True and a valid coverage cache exists (see
+        :func:`~snapatac2.pp.build_coverage_cache`), plot the insertion counts
+        in the bins of the cache instead of the base-resolution coverage
+        computed from the fragments.
 
     See Also
     --------
@@ -420,7 +495,7 @@ def coverage(
     groupby = [x for x in groupby]
     signal_values = []
     track_names = []
-    for k, v in sorted(list(internal.get_coverage(adata, region, groupby).items())):
+    for k, v in sorted(list(internal.get_coverage(adata, region, groupby, use_cache).items())):
         track_names.append(k)
         signal_values.append(v)
     signal_values = np.array(signal_values)
@@ -1,8 +1,9 @@
 from ._import_data import *
 from ._basic import *
-from ._knn import knn
+from ._knn import knn, knn_query
 from ._mnn_correct import mnc_correct
 from ._harmony import harmony
 from ._scanorama import scanorama_integrate
 from ._scrublet import scrublet, filter_doublets
+from ._amulet import amulet
 from ._recipe import *
\ No newline at end of file
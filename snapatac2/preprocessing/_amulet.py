@@ -0,0 +1,84 @@
+"""Fragment overlap based multiplet detection, as in AMULET."""
+from __future__ import annotations
+
+from pathlib import Path
+import numpy as np
+import logging
+
+import snapatac2._snapatac2 as internal
+
+def amulet(
+    adata: internal.AnnData | internal.AnnDataSet,
+    blacklist: Path | None = None,
+    exclude_chroms: list[str] | str | None = ["chrX", "chrY", "chrM", "MT", "X", "Y"],
+    q_threshold: float = 0.01,
+    inplace: bool = True,
+) -> tuple[np.ndarray, np.ndarray] | None:
+    """
+    Detect multiplets from the loci covered by more than two fragments.
+
+    In a diploid nucleus, at most two fragments can cover any position of the
+    genome, one per allele. Barcodes containing several nuclei therefore have
+    loci covered by more than two of their fragments. This function counts such
+    loci in every cell and tests whether the count exceeds what is expected
+    from a Poisson distribution whose mean is the average count of all cells,
+    following the AMULET method (Thibodeau et al. 2021). Unlike
+    :func:`~snapatac2.pp.scrublet`, it does not rely on the heterogeneity of
+    cell types and can detect multiplets of cells of the same type.
+
+    :func:`~snapatac2.pp.import_fragments` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    blacklist
+        A BED file of regions, e.g., repeats or blacklisted regions, in which
+        loci are ignored, as they frequently have spurious overlaps.
+    exclude_chroms
+        Chromosomes to ignore, e.g., sex chromosomes, which can be present in
+        more or fewer than two copies, and the mitochondrial genome.
+    q_threshold
+        Cells whose adjusted p-value (Benjamini-Hochberg) is below this
+        threshold are flagged as multiplets.
+    inplace
+        Whether to store the result in the anndata object.
+
+    Returns
+    -------
+    tuple[np.ndarray, np.ndarray] | None
+        If `inplace=True`, it stores the number of loci, the p-values, the
+        adjusted p-values and the multiplet flags in
+        `adata.obs["multiplet_overlaps"]`, `adata.obs["multiplet_pvalue"]`,
+        `adata.obs["multiplet_qvalue"]` and `adata.obs["is_multiplet"]`.
+        Otherwise, it returns the number of loci and the adjusted p-values.
+
+    See Also
+    --------
+    scrublet
+    """
+    from scipy.stats import poisson
+    from snapatac2.tools._diff import _p_adjust_bh
+
+    if exclude_chroms is None:
+        exclude_chroms = []
+    elif isinstance(exclude_chroms, str):
+        exclude_chroms = [exclude_chroms]
+    counts = np.array(
+        internal.fragment_overlap_loci(adata, list(exclude_chroms), blacklist),
+        dtype=np.int64,
+    )
+    mean = counts.mean() if len(counts) > 0 else 0.0
+    pvalues = poisson.sf(counts - 1, mean) if mean > 0 else np.ones(len(counts))
+    qvalues = _p_adjust_bh(pvalues)
+    is_multiplet = qvalues < q_threshold
+    logging.info(f"Detected multiplet rate = {np.mean(is_multiplet)*100:.3f}%")
+
+    if inplace:
+        adata.obs["multiplet_overlaps"] = counts
+        adata.obs["multiplet_pvalue"] = pvalues
+        adata.obs["multiplet_qvalue"] = qvalues
+        adata.obs["is_multiplet"] = is_multiplet
+    else:
+        return counts, qvalues
@@ -2,23 +2,32 @@ from __future__ import annotations
 
 from typing import Literal
 from pathlib import Path
+import os
 import numpy as np
 from anndata import AnnData
 import logging
 
 import snapatac2
 import snapatac2._snapatac2 as internal
+from snapatac2._utils import to_seed
 from snapatac2.genome import Genome
 from snapatac2.preprocessing._cell_calling import filter_cellular_barcodes_ordmag
 
-__all__ = [ 'add_tile_matrix', 'make_peak_matrix', 'make_gene_matrix',
-           'call_cells', 'filter_cells', 'select_features',
+__all__ = [ 'add_tile_matrix', 'recommend_bin_size', 'add_window_matrix', 'make_peak_matrix', 'append_cells', 'reindex_vars', 'hstack', 'gc_correct', 'add_track_signal', 'make_region_bin_matrix', 'make_cytoband_matrix', 'make_gene_matrix',
+           'call_cells', 'filter_cells', 'subsample_cells', 'query', 'sketch_cells', 'select_features',
 ]
 
+def _cell_weights(adata, cell_weights) -> list[float] | None:
+    if cell_weights is None:
+        return None
+    if isinstance(cell_weights, str):
+        cell_weights = adata.obs[cell_weights]
+    return np.asarray(cell_weights, dtype=np.float64).tolist()
+
 def add_tile_matrix(
     adata: internal.AnnData | list[internal.AnnData],
     *,
-    bin_size: int = 500,
+    bin_size: int | Literal['auto'] = 500,
     inplace: bool = True,
     chunk_size: int = 500,
     exclude_chroms: list[str] | str | None = ["chrM", "chrY", "M", "Y"],
@@ -27,9 +36,11 @@ def add_tile_matrix(
     counting_strategy: Literal['fragment', 'insertion', 'paired-insertion'] = 'paired-insertion',
     value_type: Literal['target', 'total', 'fraction'] = 'target',
     summary_type: Literal['sum', 'mean'] = 'sum',
+    cell_weights: np.ndarray | str | None = None,
+    modality: str | None = None,
     file: Path | None = None,
     backend: Literal['hdf5'] = 'hdf5',
-    n_jobs: int = 8,
+    n_jobs: int | None = None,
 ) -> internal.AnnData | None:
     """Generate cell by bin count matrix.
 
@@ -47,6 +58,9 @@ def add_tile_matrix(
         In this case, the function will be applied to each AnnData object in parallel.
     bin_size
         The size of consecutive genomic regions used to record the counts.
+        If "auto", the bin size is chosen from the sparsity of the data by
+        :func:`~snapatac2.pp.recommend_bin_size`, separately for every AnnData
+        object when `adata` is a list.
     inplace
         Whether to add the tile matrix to the AnnData object or return a new AnnData object.
     chunk_size
@@ -79,6 +93,15 @@ def add_tile_matrix(
         The type of summary to use when multiple values are found in a bin. This parameter
         is only used when `.obsm['_values']` exists, which is created by :func:`~snapatac2.pp.import_values`. 
         It must be one of the following: "sum" or "mean".
+    cell_weights
+        Per-cell weights, e.g., inverse sequencing depths or ambient-correction
+        weights, applied to the counts of each cell during counting. Either an
+        array of length `n_obs` or the name of a column in `.obs`.
+        When provided, the matrix stores floating point values.
+    modality
+        Count the fragments of this modality, see the `modality_sep` parameter
+        of :func:`~snapatac2.pp.import_fragments`, instead of the fragments
+        stored without modality.
     file
         File name of the output file used to store the result. If provided, result will
         be saved to a backed AnnData, otherwise an in-memory AnnData is used.
@@ -88,6 +111,7 @@ def add_tile_matrix(
     n_jobs
         Number of jobs to run in parallel when `adata` is a list.
         If `n_jobs=-1`, all CPUs will be used.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
     
     Returns
     -------
@@ -113,7 +137,13 @@ def add_tile_matrix(
         obsm: 'fragment_paired'
     """
     def fun(data, out):
-        internal.mk_tile_matrix(data, bin_size, chunk_size, counting_strategy, value_type, summary_type, exclude_chroms, min_frag_size, max_frag_size, out)
+        size = bin_size
+        if size == 'auto':
+            size = recommend_bin_size(data, exclude_chroms=exclude_chroms)
+        internal.mk_tile_matrix(
+            data, size, chunk_size, counting_strategy, value_type, summary_type, exclude_chroms,
+            min_frag_size, max_frag_size, out, _cell_weights(data, cell_weights), modality,
+        )
 
     if isinstance(exclude_chroms, str):
         exclude_chroms = [exclude_chroms]
@@ -133,10 +163,181 @@ def add_tile_matrix(
                 out = AnnData(obs=adata.obs[:].to_pandas())
             else:
                 out = AnnData(obs=adata.obs[:])
+            fun(adata, out)
+            return out
         else:
-            out = internal.AnnData(filename=file, backend=backend, obs=adata.obs[:])
-        fun(adata, out)
-        return out
+            return snapatac2._utils.create_anndata_atomic(
+                file, backend, lambda out: fun(adata, out), obs=adata.obs[:],
+            )
+
+def recommend_bin_size(
+    adata: internal.AnnData | internal.AnnDataSet,
+    *,
+    target_density: float = 0.003,
+    candidates: list[int] = [200, 500, 1000, 2000, 5000, 10000],
+    max_cells: int = 2000,
+    exclude_chroms: list[str] | str | None = ["chrM", "chrY", "M", "Y"],
+) -> int:
+    """Recommend the bin size of the tile matrix from the sparsity of the data.
+
+    Smaller bins give a finer resolution, but the matrix becomes sparser as
+    the insertions of a cell are spread over more bins. For every candidate
+    bin size, the density of a cell is the fraction of the bins in which the
+    cell has at least one Tn5 insertion. It depends on both the number of
+    fragments of the cell and the size of the genome. This function returns
+    the smallest candidate bin size for which the median density reaches
+    `target_density`, or the largest candidate if none does.
+
+    The default `target_density` corresponds to the usual 500 bp bins for
+    human cells with about 10,000 fragments.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`, with fragments
+        imported by :func:`~snapatac2.pp.import_fragments`.
+    target_density
+        The target median fraction of non-zero bins per cell.
+    candidates
+        Candidate bin sizes.
+    max_cells
+        The density is computed on the first `max_cells` cells.
+    exclude_chroms
+        A list of chromosomes to exclude.
+
+    Returns
+    -------
+    int
+        The recommended bin size.
+
+    See Also
+    --------
+    add_tile_matrix
+    """
+    if isinstance(exclude_chroms, str):
+        exclude_chroms = [exclude_chroms]
+    bin_size, densities = internal.recommend_bin_size(
+        adata, list(candidates), target_density, max_cells, exclude_chroms,
+    )
+    logging.info(
+        "Median density of the tiles: " +
+        ", ".join(f"{b} bp: {d:.2e}" for b, d in densities) +
+        f". Recommended bin size: {bin_size}."
+    )
+    return bin_size
+
+def add_window_matrix(
+    adata: internal.AnnData | list[internal.AnnData],
+    *,
+    window_size: int = 500,
+    step: int = 250,
+    inplace: bool = True,
+    chunk_size: int = 500,
+    exclude_chroms: list[str] | str | None = ["chrM", "chrY", "M", "Y"],
+    min_frag_size: int | None = None,
+    max_frag_size: int | None = None,
+    counting_strategy: Literal['fragment', 'insertion', 'paired-insertion'] = 'paired-insertion',
+    value_type: Literal['target', 'total', 'fraction'] = 'target',
+    summary_type: Literal['sum', 'mean'] = 'sum',
+    cell_weights: np.ndarray | str | None = None,
+    file: Path | None = None,
+    backend: Literal['hdf5'] = 'hdf5',
+    n_jobs: int | None = None,
+) -> internal.AnnData | None:
+    """Generate cell by sliding window count matrix.
+
+    Similar to :func:`~snapatac2.pp.add_tile_matrix`, but the windows of
+    `window_size` bases start every `step` bases and overlap when `step` is
+    smaller than `window_size`. Overlapping windows locate cluster-specific
+    accessibility more finely than tiles without relying on peak calls.
+    A fragment is counted in every window it overlaps. The variable names
+    encode the coordinates of the windows, e.g., "chr1:250-750".
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+        `adata` could also be a list of AnnData objects when `inplace=True`.
+        In this case, the function will be applied to each AnnData object in parallel.
+    window_size
+        The size of the windows.
+    step
+        The distance between the starts of consecutive windows. It must not be
+        larger than `window_size`.
+    inplace
+        Whether to add the window matrix to the AnnData object or return a new AnnData object.
+    chunk_size
+        Increasing the chunk_size speeds up I/O but uses more memory.
+    exclude_chroms
+        A list of chromosomes to exclude.
+    min_frag_size
+        Minimum fragment size to include.
+    max_frag_size
+        Maximum fragment size to include.
+    counting_strategy
+        The strategy to compute feature counts, see :func:`~snapatac2.pp.add_tile_matrix`.
+    value_type
+        The type of value to use from `.obsm['_values']`, see :func:`~snapatac2.pp.add_tile_matrix`.
+    summary_type
+        The type of summary to use when multiple values are found in a window,
+        see :func:`~snapatac2.pp.add_tile_matrix`.
+    cell_weights
+        Per-cell weights applied to the counts of each cell during counting.
+        Either an array of length `n_obs` or the name of a column in `.obs`.
+    file
+        File name of the output file used to store the result. If provided, result will
+        be saved to a backed AnnData, otherwise an in-memory AnnData is used.
+        This has no effect when `inplace=True`.
+    backend
+        The backend to use for storing the result. If `None`, the default backend will be used.
+    n_jobs
+        Number of jobs to run in parallel when `adata` is a list.
+        If `n_jobs=-1`, all CPUs will be used.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
+
+    Returns
+    -------
+    AnnData | ad.AnnData | None
+        An annotated data matrix of shape `n_obs` x `n_vars`. Rows correspond to
+        cells and columns to windows. If `file=None`, an in-memory AnnData will be
+        returned, otherwise a backed AnnData is returned.
+
+    See Also
+    --------
+    add_tile_matrix
+    make_peak_matrix
+    """
+    def fun(data, out):
+        internal.mk_window_matrix(
+            data, window_size, step, chunk_size, counting_strategy, value_type, summary_type,
+            exclude_chroms, min_frag_size, max_frag_size, out, _cell_weights(data, cell_weights),
+        )
+
+    if isinstance(exclude_chroms, str):
+        exclude_chroms = [exclude_chroms]
+
+    if inplace:
+        if isinstance(adata, list):
+            snapatac2._utils.anndata_par(
+                adata,
+                lambda x: fun(x, None),
+                n_jobs=n_jobs,
+            )
+        else:
+            fun(adata, None)
+    else:
+        if file is None:
+            if adata.isbacked:
+                out = AnnData(obs=adata.obs[:].to_pandas())
+            else:
+                out = AnnData(obs=adata.obs[:])
+            fun(adata, out)
+            return out
+        else:
+            return snapatac2._utils.create_anndata_atomic(
+                file, backend, lambda out: fun(adata, out), obs=adata.obs[:],
+            )
 
 def make_peak_matrix(
     adata: internal.AnnData | internal.AnnDataSet,
@@ -153,6 +354,8 @@ def make_peak_matrix(
     counting_strategy: Literal['fragment', 'insertion', 'paired-insertion'] = 'paired-insertion',
     value_type: Literal['target', 'total', 'fraction'] = 'target',
     summary_type: Literal['sum', 'mean'] = 'sum',
+    cell_weights: np.ndarray | str | None = None,
+    modality: str | None = None,
 ) -> internal.AnnData:
     """Generate cell by peak count matrix.
 
@@ -212,6 +415,15 @@ def make_peak_matrix(
         The type of summary to use when multiple values are found in a bin. This parameter
         is only used when `.obsm['_values']` exists, which is created by :func:`~snapatac2.pp.import_values`. 
         It must be one of the following: "sum" or "mean".
+    cell_weights
+        Per-cell weights, e.g., inverse sequencing depths or ambient-correction
+        weights, applied to the counts of each cell during counting. Either an
+        array of length `n_obs` or the name of a column in `.obs`.
+        When provided, the matrix stores floating point values.
+    modality
+        Count the fragments of this modality, see the `modality_sep` parameter
+        of :func:`~snapatac2.pp.import_fragments`, instead of the fragments
+        stored without modality.
 
     Returns
     -------
@@ -256,6 +468,10 @@ def make_peak_matrix(
             with open(peak_file, 'r') as f:
                 peaks = [line.strip() for line in f]
 
+    def fun(out):
+        internal.mk_peak_matrix(adata, peaks, chunk_size, use_x, counting_strategy, value_type, summary_type, min_frag_size, max_frag_size, out,
+                                _cell_weights(adata, cell_weights), modality)
+
     if inplace:
         out = None
     elif file is None:
@@ -264,8 +480,452 @@ def make_peak_matrix(
         else:
             out = AnnData(obs=adata.obs[:])
     else:
-        out = internal.AnnData(filename=file, backend=backend, obs=adata.obs[:])
-    internal.mk_peak_matrix(adata, peaks, chunk_size, use_x, counting_strategy, value_type, summary_type, min_frag_size, max_frag_size, out)
+        return snapatac2._utils.create_anndata_atomic(file, backend, fun, obs=adata.obs[:])
+    fun(out)
+    return out
+
+def append_cells(
+    matrix: internal.AnnData | AnnData,
+    adata: internal.AnnData | internal.AnnDataSet,
+    *,
+    chunk_size: int = 500,
+    min_frag_size: int | None = None,
+    max_frag_size: int | None = None,
+    counting_strategy: Literal['fragment', 'insertion', 'paired-insertion'] = 'paired-insertion',
+    file: Path | None = None,
+    backend: Literal['hdf5'] = 'hdf5',
+) -> internal.AnnData | AnnData:
+    """Append new cells to an existing cell by bin or cell by peak matrix.
+
+    When new samples are added to a dataset, this computes the counts of the new
+    cells only and appends them to an existing matrix produced by
+    :func:`~snapatac2.pp.add_tile_matrix` or :func:`~snapatac2.pp.make_peak_matrix`,
+    instead of recounting every cell. The features of the result are exactly those
+    of `matrix`: new cells are counted in the regions given by `matrix.var_names`,
+    and bins or peaks not covered by the new cells are kept with zero counts.
+
+    The counting parameters must be the same as the ones used to create `matrix`.
+
+    Parameters
+    ----------
+    matrix
+        The existing matrix, whose `.X` contains raw counts and whose `.var_names`
+        are genomic regions, e.g., "chr1:0-500".
+    adata
+        The AnnData or AnnDataSet object containing the fragments of the new cells.
+    chunk_size
+        Chunk size.
+    min_frag_size
+        Minimum fragment size to include.
+    max_frag_size
+        Maximum fragment size to include.
+    counting_strategy
+        The strategy to compute feature counts. It must be one of the following:
+        "fragment", "insertion", or "paired-insertion". See
+        :func:`~snapatac2.pp.add_tile_matrix` for details.
+    file
+        File name of the output file used to store the result. If provided, result will
+        be saved to a backed AnnData, otherwise an in-memory AnnData is used.
+    backend
+        The backend to use for storing the result. If `None`, the default backend will be used.
+
+    Returns
+    -------
+    AnnData
+        A matrix with the rows of `matrix` followed by the rows of the new cells.
+        Only the names of the cells are stored in `.obs`.
+    """
+    def fun(out):
+        internal.append_matrix_rows(
+            adata, matrix, out, chunk_size, counting_strategy, min_frag_size, max_frag_size,
+        )
+
+    if file is None:
+        out = AnnData()
+        fun(out)
+        return out
+    return snapatac2._utils.create_anndata_atomic(file, backend, fun)
+
+def reindex_vars(
+    adata: internal.AnnData | internal.AnnDataSet | AnnData,
+    regions: list[str] | Path,
+    *,
+    policy: Literal['exact', 'overlap', 'fraction'] = 'overlap',
+    chunk_size: int = 2000,
+    file: Path | None = None,
+    backend: Literal['hdf5'] = 'hdf5',
+) -> internal.AnnData | AnnData:
+    """Map a cell by region matrix onto another set of regions.
+
+    This is useful to harmonize datasets whose peak or tile matrices were computed
+    on different region sets, without going back to the fragments. The features of
+    `adata` must be genomic regions, e.g., "chr1:100-600".
+
+    Parameters
+    ----------
+    adata
+        The AnnData or AnnDataSet object containing the matrix in `.X`.
+    regions
+        The new regions, either a list of strings, e.g., "chr1:100-600", or the path
+        of a BED file (optionally gzipped).
+    policy
+        How the counts of the old regions are assigned to the new regions:
+        "exact" keeps the counts of identical regions only, "overlap" sums the
+        counts of the old regions overlapping each new region, and "fraction"
+        weights the counts of every old region by the fraction of its length
+        covered by the new region. With "overlap", an old region overlapping
+        several new regions contributes its counts to each of them.
+    chunk_size
+        Number of cells processed at a time.
+    file
+        File name of the output file used to store the result. If provided, result will
+        be saved to a backed AnnData, otherwise an in-memory AnnData is used.
+    backend
+        The backend to use for storing the result. If `None`, the default backend will be used.
+
+    Returns
+    -------
+    AnnData
+        A cell by region matrix whose features are `regions`. Integer counts remain
+        integers unless `policy="fraction"`. Only the names of the cells are
+        stored in `.obs`.
+    """
+    import gzip
+
+    if isinstance(regions, (str, Path)) and Path(regions).exists():
+        opener = gzip.open if Path(regions).suffix == ".gz" else open
+        with opener(regions, 'rt') as f:
+            regions = [
+                "{}:{}-{}".format(*line.strip().split('\t')[:3])
+                for line in f if line.strip() and not line.startswith('#')
+            ]
+
+    def fun(out):
+        internal.reindex_vars(adata, list(regions), policy, chunk_size, out)
+
+    if file is None:
+        out = AnnData()
+        fun(out)
+        return out
+    return snapatac2._utils.create_anndata_atomic(file, backend, fun)
+
+def hstack(
+    adatas: dict[str, internal.AnnData | AnnData] | list[internal.AnnData | AnnData],
+    *,
+    prefix: bool = True,
+    chunk_size: int = 2000,
+    file: Path | None = None,
+    backend: Literal['hdf5'] = 'hdf5',
+) -> internal.AnnData | AnnData:
+    """Concatenate several feature spaces of the same cells into one matrix.
+
+    Models that need a single design matrix can combine, e.g., a tile matrix,
+    motif deviations and gene activities. The `.X` matrices of the inputs are
+    concatenated column-wise, chunk by chunk. The cells of all inputs must be
+    identical and in the same order.
+
+    Parameters
+    ----------
+    adatas
+        The matrices to concatenate, as a dictionary mapping the name of each
+        feature space to an AnnData object, or a list of AnnData objects, in
+        which case the feature spaces are named "0", "1", ...
+    prefix
+        Whether to prefix feature names with the name of their feature space,
+        e.g., "motif:CTCF". If False, feature names must be unique across inputs.
+    chunk_size
+        Number of cells processed at a time.
+    file
+        File name of the output file used to store the result. If provided, result will
+        be saved to a backed AnnData, otherwise an in-memory AnnData is used.
+    backend
+        The backend to use for storing the result. If `None`, the default backend will be used.
+
+    Returns
+    -------
+    AnnData
+        The combined matrix. `.var['source']` holds the name of the feature space of
+        every feature and `.var['feature']` its original name. Counts remain
+        integers only if all inputs contain unsigned integers.
+    """
+    if not isinstance(adatas, dict):
+        adatas = {str(i): x for i, x in enumerate(adatas)}
+
+    def fun(out):
+        internal.hstack(list(adatas.values()), list(adatas.keys()), out, chunk_size, prefix)
+
+    if file is None:
+        out = AnnData()
+        fun(out)
+        return out
+    return snapatac2._utils.create_anndata_atomic(file, backend, fun)
+
+def gc_correct(
+    adata: internal.AnnData | AnnData,
+    genome_fasta: Path | Genome | None = None,
+    *,
+    gc_key: str = "gc",
+    span: float = 0.3,
+    key_added: str = "gc_corrected",
+    chunk_size: int = 2000,
+) -> None:
+    """Correct the counts of the features for their GC content.
+
+    The total counts of a feature partly reflect its GC content, because of
+    PCR amplification and Tn5 sequence preferences. This function fits a
+    local regression (loess) of the log total counts of the features on their
+    GC content and divides the counts of every feature by its expected fold
+    change. This is useful before copy number inference or comparisons
+    between samples with different GC biases.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    genome_fasta
+        A fasta file containing the genome sequences or a Genome object, used
+        to compute the GC content of the features when `.var[gc_key]` does
+        not exist. Chromosome names must match those of `adata`.
+    gc_key
+        The column of `.var` storing the GC content of the features. It is
+        created if it does not exist.
+    span
+        The fraction of features used by each local regression. Larger values
+        give smoother fits.
+    key_added
+        The corrected counts are stored in `.layers[key_added]`, and the fold
+        change removed from every feature in `.var[key_added + "_fold_change"]`.
+    chunk_size
+        Chunk size.
+
+    See Also
+    --------
+    snapatac2.tl.kmer_counts
+    """
+    if gc_key in adata.var.columns:
+        gc = np.asarray(adata.var[gc_key], dtype=np.float64)
+    else:
+        if genome_fasta is None:
+            raise ValueError(f"'.var[\"{gc_key}\"]' does not exist, 'genome_fasta' must be provided")
+        if isinstance(genome_fasta, Genome):
+            genome_fasta = genome_fasta.fasta
+        gc = np.array(internal.gc_content(genome_fasta, list(adata.var_names)))
+        adata.var[gc_key] = gc
+    fold_change = internal.gc_correct(adata, gc.tolist(), span, chunk_size, key_added)
+    adata.var[key_added + "_fold_change"] = np.array(fold_change)
+
+def add_track_signal(
+    adata: internal.AnnData | AnnData,
+    tracks: Path | list[Path] | dict[str, Path],
+    *,
+    op: Literal["mean", "sum", "max", "min"] = "mean",
+    key_added: str | None = None,
+    n_jobs: int | None = None,
+) -> None:
+    """Annotate the features with the signal of external coverage tracks.
+
+    The signal of every bigWig or bedGraph track, e.g., ChIP-seq tracks of
+    histone marks or transcription factors, is summarized in the regions given
+    by `adata.var_names`, i.e., re-binned onto the tiles of a tile matrix or
+    onto the peaks of a peak matrix. The results can be used as covariates of
+    the features in downstream analyses. The tracks are read in parallel, one
+    chromosome at a time.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    tracks
+        The bigWig or bedGraph files. BigWig files are recognized by the ".bw"
+        or ".bigwig" extension. BedGraph files, possibly compressed, must be
+        sorted in the order in which the chromosomes appear in `adata.var_names`.
+        If a `dict`, e.g., the output of :func:`~snapatac2.ex.export_coverage`,
+        its keys are used as the names of the tracks. Otherwise, the names are
+        the file names without extensions.
+    op
+        How the signal of a track is summarized in a region: "mean" is the
+        average over the bases of the region, "sum" the integral over the
+        region, and "max" or "min" the extreme values. Bases not covered by a
+        track count as 0.
+    key_added
+        If `None`, the signal of every track is stored in `.var[name]`.
+        Otherwise, the signals are stored in `.varm[key_added]`, with one
+        column per track, and the names of the tracks in
+        `.uns[key_added + "_names"]`.
+    n_jobs
+        Number of threads to use. If `<= 0`, use all available threads.
+        If `None`, the global setting of :func:`~snapatac2.set_num_threads` is used.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
+
+    See Also
+    --------
+    snapatac2.ex.combine_tracks
+    gc_correct
+    """
+    if isinstance(tracks, dict):
+        names, tracks = list(tracks.keys()), list(tracks.values())
+    else:
+        if isinstance(tracks, (str, Path)):
+            tracks = [tracks]
+        names = [Path(x).name.split(".")[0] for x in tracks]
+    if len(set(names)) != len(names):
+        raise ValueError("the names of the tracks must be unique")
+    if n_jobs is not None and n_jobs <= 0:
+        n_jobs = os.cpu_count()
+
+    signal = internal.track_signal([str(x) for x in tracks], list(adata.var_names), op, n_jobs)
+    if key_added is None:
+        for name, values in zip(names, signal):
+            adata.var[name] = np.array(values)
+    else:
+        adata.varm[key_added] = np.array(signal).T
+        adata.uns[key_added + "_names"] = names
+
+def make_region_bin_matrix(
+    adata: internal.AnnData | internal.AnnDataSet,
+    regions: Path | list[str] | str,
+    bin_size: int = 50,
+    *,
+    min_frag_size: int | None = None,
+    max_frag_size: int | None = None,
+    counting_strategy: Literal['fragment', 'insertion', 'paired-insertion'] = 'insertion',
+) -> tuple['scipy.sparse.csr_matrix', list[str]]:
+    """Compute the cell by bin coverage of selected regions in memory.
+
+    Unlike :func:`~snapatac2.pp.add_tile_matrix`, which tiles the whole genome
+    and stores the matrix in the AnnData object, this function only tiles the
+    given regions, at any resolution, and returns the matrix directly. This is
+    suited to locus-level models, e.g., a 2 Mb locus at 50 bp resolution.
+
+    :func:`~snapatac2.pp.import_fragments` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    regions
+        A region, e.g., "chr1:1000000-3000000", a list of regions, or a BED file.
+    bin_size
+        Size of the bins, in bases. The last bin of each region is truncated at
+        the end of the region.
+    min_frag_size
+        Minimum fragment size to include.
+    max_frag_size
+        Maximum fragment size to include.
+    counting_strategy
+        The strategy to compute feature counts. See
+        :func:`~snapatac2.pp.make_peak_matrix` for details.
+
+    Returns
+    -------
+    tuple[scipy.sparse.csr_matrix, list[str]]
+        The matrix of shape `n_obs` x `n_bins` and the names of the bins,
+        e.g., "chr1:1000000-1000050".
+
+    See Also
+    --------
+    add_tile_matrix
+    make_peak_matrix
+    """
+    if isinstance(regions, str) and ":" in regions and not Path(regions).exists():
+        regions = [regions]
+    elif not isinstance(regions, list):
+        regions = internal.read_regions(Path(regions))
+    return internal.mk_region_bin_matrix(
+        adata, list(regions), bin_size, counting_strategy, min_frag_size, max_frag_size,
+    )
+
+def make_cytoband_matrix(
+    adata: internal.AnnData | internal.AnnDataSet,
+    cytoband_file: Path,
+    *,
+    level: Literal['arm', 'band'] = 'arm',
+    chroms: list[str] | None = None,
+    chunk_size: int = 500,
+    min_frag_size: int | None = None,
+    max_frag_size: int | None = None,
+    counting_strategy: Literal['fragment', 'insertion', 'paired-insertion'] = 'insertion',
+) -> AnnData:
+    """Generate cell by chromosome arm (or cytoband) count matrix.
+
+    The counts of a few dozen chromosome arms give a compact summary of the
+    coverage of every cell, suited to a quick screening of aneuploidies,
+    e.g., by comparing the fraction of the counts of every arm with that of
+    normal cells. The counts are computed as in
+    :func:`~snapatac2.pp.make_peak_matrix`.
+
+    :func:`~snapatac2.pp.import_fragments` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    cytoband_file
+        A cytoband file as provided by UCSC, e.g., `cytoBand.txt.gz`, with
+        the chromosome, start, end and name of every band, e.g., "p36.33".
+        The arm of a band is given by the first letter of its name.
+    level
+        "arm": count the fragments of every chromosome arm, e.g., "chr1p".
+        "band": count the fragments of every band, e.g., "chr1p36.33".
+    chroms
+        Only count these chromosomes. If None, all the chromosomes of the
+        cytoband file are used.
+    chunk_size
+        Chunk size
+    min_frag_size
+        Minimum fragment size to include.
+    max_frag_size
+        Maximum fragment size to include.
+    counting_strategy
+        The strategy to compute feature counts. See
+        :func:`~snapatac2.pp.make_peak_matrix` for details.
+
+    Returns
+    -------
+    ad.AnnData
+        An annotated data matrix of shape `n_obs` x `n_vars`. Rows correspond to
+        cells and columns to arms or bands. `.var` contains the coordinates of
+        the arms or bands in the columns "chrom", "start" and "end".
+
+    See Also
+    --------
+    make_peak_matrix
+    """
+    import gzip
+    import pandas as pd
+
+    if level not in ('arm', 'band'):
+        raise ValueError("level must be 'arm' or 'band'")
+    opener = gzip.open if Path(cytoband_file).suffix == ".gz" else open
+    features = {}
+    with opener(cytoband_file, 'rt') as f:
+        for line in f:
+            if line.startswith("#") or line.strip() == "":
+                continue
+            chrom, start, end, name = line.rstrip("\n").split("\t")[:4]
+            if name == "" or (chroms is not None and chrom not in chroms):
+                continue
+            key = chrom + (name[0] if level == 'arm' else name)
+            start, end = int(start), int(end)
+            if key in features:
+                features[key] = (chrom, min(start, features[key][1]), max(end, features[key][2]))
+            else:
+                features[key] = (chrom, start, end)
+    if len(features) == 0:
+        raise ValueError("no band found in the cytoband file")
+
+    regions = [f"{chrom}:{start}-{end}" for chrom, start, end in features.values()]
+    out = make_peak_matrix(
+        adata, use_rep=regions, chunk_size=chunk_size, counting_strategy=counting_strategy,
+        min_frag_size=min_frag_size, max_frag_size=max_frag_size,
+    )
+    out.var = pd.DataFrame(
+        list(features.values()), columns=["chrom", "start", "end"], index=list(features.keys()),
+    )
     return out
 
 def make_gene_matrix(
@@ -288,6 +948,8 @@ def make_gene_matrix(
     min_frag_size: int | None = None,
     max_frag_size: int | None = None,
     counting_strategy: Literal['fragment', 'insertion', 'paired-insertion'] = 'paired-insertion',
+    cell_weights: np.ndarray | str | None = None,
+    modality: str | None = None,
 ) -> internal.AnnData:
     """Generate cell by gene activity matrix.
 
@@ -349,6 +1011,15 @@ def make_gene_matrix(
         once if the pair of insertions of a fragment are both within the same region
         of interest [Miao24]_.
         Note that this parameter has no effect if input are single-end reads.
+    cell_weights
+        Per-cell weights, e.g., inverse sequencing depths or ambient-correction
+        weights, applied to the counts of each cell during counting. Either an
+        array of length `n_obs` or the name of a column in `.obs`.
+        When provided, the matrix stores floating point values.
+    modality
+        Count the fragments of this modality, see the `modality_sep` parameter
+        of :func:`~snapatac2.pp.import_fragments`, instead of the fragments
+        stored without modality.
 
     Returns
     -------
@@ -375,6 +1046,13 @@ def make_gene_matrix(
     if isinstance(gene_anno, Genome):
         gene_anno = gene_anno.annotation
 
+    def fun(out):
+        internal.mk_gene_matrix(adata, gene_anno, chunk_size, use_x, id_type,
+            upstream, downstream, include_gene_body,
+            transcript_name_key, transcript_id_key, gene_name_key, gene_id_key,
+            counting_strategy, min_frag_size, max_frag_size, out,
+            _cell_weights(adata, cell_weights), modality)
+
     if inplace:
         out = None
     elif file is None:
@@ -383,18 +1061,15 @@ def make_gene_matrix(
         else:
             out = AnnData(obs=adata.obs[:])
     else:
-        out = internal.AnnData(filename=file, backend=backend, obs=adata.obs[:])
-    internal.mk_gene_matrix(adata, gene_anno, chunk_size, use_x, id_type,
-        upstream, downstream, include_gene_body,
-        transcript_name_key, transcript_id_key, gene_name_key, gene_id_key,
-        counting_strategy, min_frag_size, max_frag_size, out)
+        return snapatac2._utils.create_anndata_atomic(file, backend, fun, obs=adata.obs[:])
+    fun(out)
     return out
 
 def call_cells(
     data: internal.AnnData | list[internal.AnnData],
     use_rep: str | np.ndarray[float],
     inplace: bool = True,
-    n_jobs: int = 8,
+    n_jobs: int | None = None,
 ) -> np.ndarray | None:
     """
     Calling cells based on the number of feature counts.
@@ -418,6 +1093,7 @@ def call_cells(
         Perform computation inplace or return result.
     n_jobs
         Number of parallel jobs to use when `data` is a list.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
 
     Returns
     -------
@@ -453,7 +1129,7 @@ def filter_cells(
     max_counts: int | None = None,
     max_tsse: float | None = None,
     inplace: bool = True,
-    n_jobs: int = 8,
+    n_jobs: int | None = None,
 ) -> np.ndarray | None:
     """
     Filter cell outliers based on counts and numbers of genes expressed.
@@ -480,6 +1156,7 @@ def filter_cells(
         Perform computation inplace or return result.
     n_jobs
         Number of parallel jobs to use when `data` is a list.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
 
     Returns
     -------
@@ -513,6 +1190,169 @@ def filter_cells(
     else:
         return selected_cells
 
+def subsample_cells(
+    data: internal.AnnData | internal.AnnDataSet,
+    groupby: str | list[str],
+    max_cells: int,
+    random_state: int = 0,
+    inplace: bool = True,
+) -> np.ndarray | None:
+    """
+    Select at most `max_cells` cells from every group.
+
+    Groups that contain no more than `max_cells` cells are kept in full, while
+    larger groups are randomly downsampled. This yields a balanced set of cells
+    for differential testing and plotting, where abundant populations would
+    otherwise dominate.
+
+    Parameters
+    ----------
+    data
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+    groupby
+        Key(s) in `data.obs` defining the groups. If a list of keys is given,
+        e.g., `["leiden", "batch"]`, groups are formed by every combination of
+        the values.
+    max_cells
+        Maximum number of cells selected from every group.
+    random_state
+        Seed of the random number generator.
+    inplace
+        Perform computation inplace or return result.
+
+    Returns
+    -------
+    np.ndarray | None:
+        If `inplace = True`, directly subsets the data matrix. Otherwise return
+        a boolean mask of the selected cells.
+    """
+    if isinstance(groupby, str):
+        groupby = [groupby]
+    columns = [data.obs[key] for key in groupby]
+    groups = [
+        None if any(x is None for x in values) else "\t".join(str(x) for x in values)
+        for values in zip(*columns)
+    ]
+    mask = np.array(internal.stratified_subsample(groups, max_cells, to_seed(random_state)))
+    if inplace:
+        selected_cells = np.flatnonzero(mask)
+        if data.isbacked:
+            data.subset(selected_cells)
+        else:
+            data._inplace_subset_obs(selected_cells)
+    else:
+        return mask
+
+def query(
+    data: internal.AnnData | internal.AnnDataSet | AnnData,
+    expr: str,
+    *,
+    axis: Literal["obs", "var"] = "obs",
+    chunk_size: int = 100_000,
+) -> np.ndarray:
+    """
+    Select the cells (or features) satisfying a predicate on their annotations.
+
+    The predicate is evaluated in Rust, chunk by chunk, on the columns of
+    `.obs` (or `.var`) it references, without converting the table to pandas.
+    This is much faster and lighter than `data.obs.query(...)` for backed
+    tables with millions of rows, and the mask can be used to load only the
+    selected cells, e.g., with `data.subset(mask)`.
+
+    Parameters
+    ----------
+    data
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+    expr
+        The predicate, e.g., `"tsse > 7 && n_fragment >= 1000"`. Columns are
+        compared with numbers, quoted strings (e.g., `sample != 'ctrl'`) or
+        `true`/`false`, using `<`, `<=`, `>`, `>=`, `==` and `!=`.
+        Comparisons are combined with `&&` (or `and`), `||` (or `or`), `!`
+        (or `not`) and parentheses. Column names with spaces or other special
+        characters are written between backquotes, e.g., `` `cell type` == 'B' ``.
+        Comparisons with missing values are neither true nor false, and such
+        rows are not selected.
+    axis
+        Evaluate the predicate on `.obs` or on `.var`.
+    chunk_size
+        Number of rows evaluated at a time.
+
+    Returns
+    -------
+    np.ndarray
+        A boolean mask of the selected rows.
+
+    Examples
+    --------
+    >>> import snapatac2 as snap
+    >>> data = snap.read(snap.datasets.pbmc5k(type='h5ad'), backed='r')
+    >>> mask = snap.pp.query(data, "tsse > 10 && n_fragment > 5000")
+    """
+    if axis not in ("obs", "var"):
+        raise ValueError("axis must be 'obs' or 'var'")
+    return np.array(internal.query(data, expr, 0 if axis == "obs" else 1, chunk_size), dtype=bool)
+
+def sketch_cells(
+    data: internal.AnnData | internal.AnnDataSet,
+    n_obs: int,
+    *,
+    use_rep: str | np.ndarray = "X_spectral",
+    n_comps: int | None = 20,
+    random_state: int = 0,
+    inplace: bool = True,
+) -> np.ndarray | None:
+    """
+    Select a representative subset of cells by geometric sketching.
+
+    The embedding is covered by a grid of equal-sized hypercubes, whose size is
+    chosen so that about `n_obs` of them are occupied, and cells are drawn
+    evenly from the occupied hypercubes (Hie et al., 2019). Compared to uniform
+    subsampling, the sketch covers the transcriptional space evenly: rare
+    populations are kept while abundant ones are thinned out. This makes it
+    possible to run expensive steps, e.g., exploring the parameters of
+    :func:`~snapatac2.tl.umap`, on a small subset of a large atlas.
+
+    Parameters
+    ----------
+    data
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+    n_obs
+        Number of cells to select.
+    use_rep
+        The embedding used for sketching, as a key of `data.obsm` or an array.
+    n_comps
+        Number of leading dimensions of the embedding used. Sketching in too
+        many dimensions makes every cell fall into its own hypercube, which
+        degrades to uniform subsampling. If None, all dimensions are used.
+    random_state
+        Seed of the random number generator.
+    inplace
+        Perform computation inplace or return result.
+
+    Returns
+    -------
+    np.ndarray | None:
+        If `inplace = True`, directly subsets the data matrix. Otherwise return
+        a boolean mask of the selected cells.
+
+    See Also
+    --------
+    subsample_cells
+    """
+    mat = data.obsm[use_rep][...] if isinstance(use_rep, str) else use_rep
+    mat = np.asarray(mat, dtype=np.float64)
+    if n_comps is not None:
+        mat = mat[:, :n_comps]
+    mask = np.array(internal.geometric_sketch(np.ascontiguousarray(mat), n_obs, to_seed(random_state)))
+    if inplace:
+        selected_cells = np.flatnonzero(mask)
+        if data.isbacked:
+            data.subset(selected_cells)
+        else:
+            data._inplace_subset_obs(selected_cells)
+    else:
+        return mask
+
 def _find_most_accessible_features(
     feature_count,
     filter_lower_quantile,
@@ -539,7 +1379,7 @@ def select_features(
     blacklist: Path | None = None,
     max_iter: int = 1,
     inplace: bool = True,
-    n_jobs: int = 8,
+    n_jobs: int | None = None,
     verbose: bool = True,
 ) -> np.ndarray | list[np.ndarray] | None:
     """
@@ -591,6 +1431,7 @@ def select_features(
         Perform computation inplace or return result.
     n_jobs
         Number of parallel jobs to use when `adata` is a list.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
     verbose
         Whether to print progress messages.
     
@@ -10,7 +10,7 @@ import multiprocessing as mp
 from functools import partial
 
 import snapatac2._snapatac2 as internal
-from snapatac2._utils import is_anndata
+from snapatac2._utils import is_anndata, get_n_jobs
 
 
 def harmony(
@@ -22,7 +22,7 @@ def harmony(
     groupby: str | list[str] | None = None,
     key_added: str | None = None,
     inplace: bool = True,
-    n_jobs: int = 8,
+    n_jobs: int | None = None,
     **kwargs,
 ) -> np.ndarray | None:
     """
@@ -97,7 +97,7 @@ def harmony(
             for group_idx in group_idxs
         ]
 
-        with mp.Pool(processes=min(n_jobs, len(groups))) as pool:
+        with mp.Pool(processes=min(get_n_jobs(n_jobs), len(groups))) as pool:
             mats = pool.starmap(partial(_harmony, **kwargs), margs)
         for i, group_idx in enumerate(group_idxs):
             mat[group_idx, :] = mats[i]
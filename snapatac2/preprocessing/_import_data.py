@@ -8,7 +8,7 @@ import snapatac2
 import snapatac2._snapatac2 as internal
 from snapatac2.genome import Genome
 
-__all__ = ['make_fragment_file', 'import_fragments', 'import_contacts', 'import_values']
+__all__ = ['make_fragment_file', 'sort_fragment_file', 'import_fragments', 'convert_fragments', 'compact_fragments', 'build_coverage_cache', 'import_contacts', 'import_values']
 
 def make_fragment_file(
     bam_file: Path,
@@ -27,6 +27,7 @@ def make_fragment_file(
     compression: Literal["gzip", "zstandard"] | None = None,
     compression_level: int | None = None,
     tempdir: Path | None = None,
+    dedup_policy: Literal["coordinate", "5prime", "umi", "none"] | None = None,
 ) -> dict[str, float]:
     """
     Convert a BAM file to a fragment file.
@@ -103,6 +104,19 @@ def make_fragment_file(
     tempdir
         Location to store temporary files. If `None`, system temporary directory
         will be used.
+    dedup_policy
+        How PCR duplicates are identified within each cell barcode:
+
+        - "coordinate": reads or read pairs with identical 5' ends and orientation.
+        - "5prime": read pairs whose leftmost reads share the 5' end and orientation,
+          regardless of the position of the mate. This is the same as "coordinate"
+          for single-end reads.
+        - "umi": as "coordinate", but reads must also share the UMI.
+          Requires `umi_tag` or `umi_regex`.
+        - "none": duplicates are kept.
+
+        If `None`, "umi" is used when `umi_tag` or `umi_regex` is set, and
+        "coordinate" otherwise.
 
     Returns
     -------
@@ -146,9 +160,47 @@ def make_fragment_file(
     return internal.make_fragment_file(
         bam_file, output_file, is_paired, shift_left, shift_right, chunk_size,
         barcode_tag, barcode_regex, umi_tag, umi_regex, min_mapq, chrM, source,
-        compression, compression_level, tempdir,
+        compression, compression_level, tempdir, dedup_policy,
     )
 
+def sort_fragment_file(
+    fragment_file: Path,
+    output_file: Path,
+    *,
+    tempdir: Path | None = None,
+) -> Path:
+    """Sort a fragment file by coordinate, compress it with BGZF and index it with tabix.
+
+    Fragment files produced by some aligners, e.g., chromap, are not sorted.
+    This function sorts the fragments by chromosome, start and end, using a
+    bounded amount of memory (see :func:`~snapatac2.set_memory_limit`) and
+    temporary files for large inputs. The output can be opened by genome
+    browsers and tools requiring random access.
+
+    Parameters
+    ----------
+    fragment_file
+        File containing the fragments, either plain text or compressed with
+        gzip or zstandard. Columns after the first three are kept as they are,
+        and lines starting with '#' are kept as the header.
+    output_file
+        The output file, compressed with BGZF. The index is written to
+        `output_file` + ".tbi".
+    tempdir
+        Location to store temporary files. If `None`, system default will be used.
+
+    Returns
+    -------
+    Path
+        The path of the tabix index.
+
+    Examples
+    --------
+    >>> snap.pp.sort_fragment_file("chromap.tsv", "fragments.tsv.gz")
+    PosixPath('fragments.tsv.gz.tbi')
+    """
+    return Path(internal.sort_fragment_file(fragment_file, output_file, tempdir))
+
 def import_fragments(
     fragment_file: Path | list[Path],
     chrom_sizes: Genome | dict[str, int],
@@ -161,8 +213,14 @@ def import_fragments(
     chrM: list[str] = ["chrM", "M"],
     chunk_size: int = 2000,
     tempdir: Path | None = None,
+    checkpoint_dir: Path | None = None,
+    contig_policy: Literal['keep', 'drop', 'remap'] = 'keep',
+    chain_file: Path | None = None,
+    long_reads: bool = False,
+    blacklist: Path | None = None,
+    modality_sep: str | None = None,
     backend: Literal['hdf5'] = 'hdf5',
-    n_jobs: int = 8,
+    n_jobs: int | None = None,
 ) -> internal.AnnData:
     """Import data from fragment files and compute basic QC metrics.
 
@@ -260,11 +318,67 @@ def import_fragments(
     tempdir
         Location to store temporary files. If `None`, system temporary directory
         will be used.
+    checkpoint_dir
+        If provided, the barcodes passing the filters, the fragments sorted by
+        barcode and every chunk of imported cells are saved in this directory.
+        If the import is interrupted, calling this function again with the same
+        directory skips these steps and resumes after the last imported chunk.
+        The directory is removed when the import finishes.
+        If `fragment_file` is a list, a subdirectory is created for each file.
+    contig_policy
+        How to handle fragments aligned to sequences outside the primary assembly,
+        i.e., alternate loci, patches, unplaced scaffolds, decoys and HLA sequences
+        of patched references:
+        - "keep": keep them, as long as the sequences are in `chrom_sizes`.
+        - "drop": discard the fragments and remove the sequences from the
+          chromosome sizes.
+        - "remap": lift the fragments over to the primary assembly using
+          `chain_file`, discarding those that cannot be lifted over. The
+          sequences are removed from the chromosome sizes.
+    chain_file
+        A UCSC chain file aligning the alternate sequences (reference) to the
+        primary assembly (query), as used by liftOver. Required if
+        `contig_policy="remap"`.
+    long_reads
+        Whether the fragment file comes from long-read single-cell ATAC-seq.
+        Each record then spans a whole molecule, which can be several kilobases
+        long, and may carry extra columns such as the strand and methylation
+        tags. Long reads are stored in `.obsm['fragment_paired']`, so
+        `is_paired` is ignored, and `.uns['read_type']` is set to "long".
+        The methylation calls of the `ML` tags are summarized in
+        `.obs['n_methylation_calls']` and `.obs['frac_methylated']`, the
+        fraction of calls with a probability of at least 0.5; per-base values
+        can be imported with :func:`~snapatac2.pp.import_values`. Functions
+        reading long reads ignore fragments shorter than 100 bases or longer
+        than 100,000 bases, unless they are given other limits, e.g.,
+        `min_frag_size` and `max_frag_size` in :func:`~snapatac2.pp.add_tile_matrix`.
+    blacklist
+        A BED file of regions, e.g., the ENCODE blacklist. Fragments overlapping
+        these regions are dropped before they are stored, so that they are
+        absent from every downstream analysis and do not count towards the QC
+        metrics and `min_num_fragments`. The number of fragments dropped from
+        every cell is saved in `.obs['n_blacklisted']`.
+    modality_sep
+        For assays profiling several modalities per cell, e.g., the histone
+        marks of Paired-Tag, the barcode of every record may carry the modality
+        after this separator, e.g., "AAACGA:H3K27ac" with `modality_sep=":"`.
+        The fragments of every modality are stored in their own layer,
+        `.obsm['fragment_paired:{modality}']` (or `'fragment_single:{modality}'`),
+        with the number of fragments of every cell in `.obs['n_fragment_{modality}']`,
+        and records without a modality are ignored. The file is sorted by
+        modality in a single pass, whether or not it is sorted by barcode.
+        Cells are filtered and QC metrics are computed on all modalities together.
+        The fragments of all modalities are also merged into
+        `.obsm['fragment_paired']` (or `'fragment_single'`), which is used by
+        the functions that are not given a modality, e.g., :func:`~snapatac2.metrics.tsse`.
+        Pass `modality` to the counting, export and storage functions, e.g.,
+        :func:`~snapatac2.pp.add_tile_matrix`, to use the fragments of one modality.
     backend
         The backend.
     n_jobs
         Number of jobs to run in parallel when `fragment_file` is a list.
         If `n_jobs=-1`, all CPUs will be used.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
 
     Returns
     -------
@@ -309,22 +423,176 @@ def import_fragments(
                 raise ValueError("The length of 'file' must be the same as the length of 'fragment_file'")
             adatas = [internal.AnnData(filename=f, backend=backend) for f in file]
 
-        snapatac2._utils.anndata_ipar(
-            list(enumerate(adatas)),
-            lambda x: internal.import_fragments(
+        def fun(x):
+            internal.import_fragments(
                 x[1], fragment_file[x[0]], is_paired, chrom_sizes, chrM, min_num_fragments,
                 sorted_by_barcode, chunk_size, whitelist, tempdir,
-            ),
-            n_jobs=n_jobs,
-        )
+                None if checkpoint_dir is None else Path(checkpoint_dir) / str(x[0]),
+                contig_policy, chain_file, long_reads, blacklist, modality_sep,
+            )
+            if long_reads:
+                _add_long_read_info(x[1], fragment_file[x[0]], modality_sep)
+
+        snapatac2._utils.anndata_ipar(list(enumerate(adatas)), fun, n_jobs=n_jobs)
         return adatas
     else:
-        adata = AnnData() if file is None else internal.AnnData(filename=file, backend=backend)
-        internal.import_fragments(
-            adata, fragment_file, is_paired, chrom_sizes, chrM, min_num_fragments,
-            sorted_by_barcode, chunk_size, whitelist, tempdir,
-        )
-        return adata
+        def fun(adata):
+            internal.import_fragments(
+                adata, fragment_file, is_paired, chrom_sizes, chrM, min_num_fragments,
+                sorted_by_barcode, chunk_size, whitelist, tempdir, checkpoint_dir,
+                contig_policy, chain_file, long_reads, blacklist, modality_sep,
+            )
+            if long_reads:
+                _add_long_read_info(adata, fragment_file, modality_sep)
+
+        if file is None:
+            adata = AnnData()
+            fun(adata)
+            return adata
+        return snapatac2._utils.create_anndata_atomic(file, backend, fun)
+
+def _add_long_read_info(adata, fragment_file, modality_sep):
+    """Mark the fragments as long reads and add the methylation calls of every cell to `.obs`."""
+    import numpy as np
+
+    if adata.n_obs == 0:
+        return
+    n_calls, n_methylated = internal.long_read_methylation(
+        fragment_file, list(adata.obs_names), modality_sep,
+    )
+    n_calls = np.array(n_calls, dtype=np.uint64)
+    adata.obs["n_methylation_calls"] = n_calls
+    adata.obs["frac_methylated"] = np.divide(
+        n_methylated, n_calls, out=np.zeros(len(n_calls)), where=n_calls > 0,
+    )
+    adata.uns["read_type"] = "long"
+
+def convert_fragments(
+    adata: internal.AnnData,
+    format: Literal["v1", "v2"] = "v2",
+    *,
+    chunk_size: int = 2000,
+    modality: str | None = None,
+) -> bool:
+    """Convert the fragments stored in an AnnData object to another storage format.
+
+    :func:`~snapatac2.pp.import_fragments` stores fragments in the "v1" format,
+    where the fragments are kept in a sparse matrix whose columns are the base
+    positions of the genome. The "v2" format stores, for every fragment, the index
+    of its chromosome and the distance to the previous fragment of the same cell,
+    together with a 16-bit fragment length. It is considerably smaller on disk.
+    All functions reading fragments support both formats, so the conversion is
+    transparent to the rest of the analysis. Convert the data back to "v1" before
+    sharing it with older versions of snapatac2.
+
+    Fragments are converted in place: `.obsm['fragment_paired']` (or
+    `.obsm['fragment_single']`) is replaced by `.obsm['fragment_paired_v2']`
+    (or `.obsm['fragment_single_v2']`), and vice versa.
+
+    Parameters
+    ----------
+    adata
+        The AnnData object containing fragments.
+    format
+        The target format, "v1" or "v2".
+    chunk_size
+        Number of cells converted at a time.
+    modality
+        Convert the fragments of this modality, see the `modality_sep` parameter
+        of :func:`~snapatac2.pp.import_fragments`, instead of the fragments
+        stored without modality.
+
+    Returns
+    -------
+    bool
+        False if the fragments were already stored in the target format.
+
+    Note
+    ----
+    The "v2" format supports fragments shorter than 65536 bp for paired-end data and
+    reads shorter than 32768 bp for single-end data. The conversion fails, leaving the
+    data unchanged, if longer fragments are present.
+    """
+    return internal.convert_fragment_storage(adata, format, chunk_size, modality)
+
+def compact_fragments(
+    adata: internal.AnnData,
+    *,
+    chunk_size: int = 2000,
+    modality: str | None = None,
+    repack: bool = True,
+) -> int:
+    """Re-sort and compact the fragments stored in an AnnData object.
+
+    After many append or subset operations, the fragments of a cell may no longer be
+    sorted by coordinate, and the chunks of `.obsm['fragment_paired']` (or
+    `.obsm['fragment_single']`) may be unevenly sized. This rewrites the fragments
+    in coordinate-sorted chunks of `chunk_size` cells, which restores the locality
+    of iteration and allows the conversion to the "v2" format
+    (see :func:`~snapatac2.pp.convert_fragments`). The storage format is preserved.
+
+    The fragments are first written to a temporary `.obsm` entry, which replaces
+    the original entry only once it is complete. If the operation fails, the
+    original fragments are left untouched.
+
+    Parameters
+    ----------
+    adata
+        The AnnData object containing fragments.
+    chunk_size
+        Number of cells rewritten at a time.
+    modality
+        Compact the fragments of this modality, see the `modality_sep` parameter
+        of :func:`~snapatac2.pp.import_fragments`, instead of the fragments
+        stored without modality.
+    repack
+        Whether to rewrite the file afterwards to release the space of the
+        original fragments. HDF5 does not release the space of deleted elements,
+        so the file is copied and the copy replaces the original; this needs
+        as much free disk space as the compacted file.
+
+    Returns
+    -------
+    int
+        Number of cells whose fragments were reordered.
+    """
+    n = internal.compact_fragment_storage(adata, chunk_size, modality)
+    if repack:
+        snapatac2._utils.repack_anndata(adata)
+    return n
+
+def build_coverage_cache(
+    adata: internal.AnnData | internal.AnnDataSet,
+    bin_size: int = 500,
+) -> dict:
+    """Build a cache of the genome-wide coverage of every cell.
+
+    The cache stores the number of Tn5 insertions of every cell in bins of
+    `bin_size` bases in `.obsm['coverage_cache']`. Once it is built,
+    :func:`~snapatac2.metrics.summary_by_chrom`, :func:`~snapatac2.pl.coverage`,
+    and :func:`~snapatac2.ex.export_coverage` with `use_cache=True` read the cache
+    instead of scanning the raw fragments, which is much faster for large datasets.
+
+    The cache records the checksum of the fragments it was built from, which
+    is computed again from the fragments whenever the cache is read. If the
+    fragments change afterwards, e.g., because the data were re-imported or
+    modified by another program, the cache is ignored with a warning until it
+    is rebuilt.
+
+    Parameters
+    ----------
+    adata
+        The AnnData or AnnDataSet object containing fragments.
+    bin_size
+        Size of the bins, in bases.
+
+    Returns
+    -------
+    dict
+        Metadata of the cache, including the bin size and the fragment checksum.
+    """
+    import json
+    return json.loads(internal.build_coverage_cache(adata, bin_size))
 
 def import_contacts(
     contact_file: Path,
@@ -1,6 +1,7 @@
 from __future__ import annotations
 
 from typing import Literal
+import hashlib
 import numpy as np
 from scipy.sparse import csr_matrix
 
@@ -12,9 +13,12 @@ def knn(
     n_neighbors: int = 50,
     use_dims: int | list[int] | None = None,
     use_rep: str = 'X_spectral',
-    method: Literal['kdtree', 'hora', 'pynndescent'] = "kdtree",
+    method: Literal['kdtree', 'exact', 'hora', 'pynndescent'] = "kdtree",
     inplace: bool = True,
     random_state: int = 0,
+    store_index: bool = False,
+    block_size: int = 4096,
+    device: Literal["cpu", "cuda", "metal"] = "cpu",
 ) -> csr_matrix | None:
     """
     Compute a neighborhood graph of observations.
@@ -35,6 +39,9 @@ def knn(
     method
         Can be one of the following:
         - 'kdtree': use the kdtree algorithm to find the nearest neighbors.
+        - 'exact': blocked brute-force search. The distances between blocks of
+          `block_size` cells are computed by matrix multiplication, which is
+          faster than 'kdtree' for high-dimensional data.
         - 'hora': use the HNSW algorithm to find the approximate nearest neighbors.
         - 'pynndescent': use the pynndescent algorithm to find the approximate nearest neighbors.
     inplace
@@ -43,6 +50,24 @@ def knn(
         Random seed for approximate nearest neighbor search.
         Note that this is only used when `method='pynndescent'`.
         Currently 'hora' does not support random seed, so the result of 'hora' is not reproducible.
+    store_index
+        Whether to store the HNSW index in `adata.uns["hnsw_index"]`.
+        Only used when `method='hora'` and `adata` is an AnnData object.
+        If an index was built on the same representation and the coordinates
+        of the indexed cells are unchanged, which is checked with a
+        fingerprint of the data stored in `adata.uns["hnsw_index_info"]`, it
+        is reused instead of being rebuilt: cells appended to `adata` since the
+        index was built are added to it incrementally. The index is stored
+        only if `inplace=True`.
+        The stored index can be queried with :func:`~snapatac2.pp.knn_query`,
+        and is used by :func:`~snapatac2.tl.umap` and
+        :func:`~snapatac2.tl.transfer_labels` with `use_index=True`.
+    block_size
+        Number of cells per block when `method='exact'`.
+    device
+        Device computing the distances when `method='exact'`: "cpu", or
+        "cuda" or "metal", which require SnapATAC2 to be built with the feature
+        of the same name. "metal" computes in single precision.
 
     Returns
     -------
@@ -65,7 +90,18 @@ def knn(
             data = data[:, use_dims]
 
     n = data.shape[0]
-    if method == 'hora':
+    if method == 'hora' and store_index and is_anndata(adata):
+        data = np.asarray(data, dtype=np.float32)
+        key = _index_key(use_rep, use_dims)
+        index = _stored_index(adata, data, key, allow_appended=True)
+        if index is None:
+            adj, index = internal.build_ann_index(data, n_neighbors)
+        else:
+            adj, index = internal.update_ann_index(index, data, n_neighbors)
+        if inplace:
+            adata.uns['hnsw_index'] = index
+            adata.uns['hnsw_index_info'] = {'key': key, 'n_obs': n, 'fingerprint': _fingerprint(data)}
+    elif method == 'hora':
         adj = internal.approximate_nearest_neighbour_graph(
             data.astype(np.float32), n_neighbors)
     elif method == 'pynndescent':
@@ -79,10 +115,107 @@ def knn(
         adj.sort_indices()
     elif method == 'kdtree':
         adj = internal.nearest_neighbour_graph(data, n_neighbors)
+    elif method == 'exact':
+        adj = internal.exact_nearest_neighbour_graph(
+            np.asarray(data, dtype=np.float64), n_neighbors, block_size, device)
     else:
-        raise ValueError("method must be one of 'hora', 'pynndescent', 'kdtree'")
+        raise ValueError("method must be one of 'hora', 'pynndescent', 'kdtree', 'exact'")
     
     if inplace:
         adata.obsp['distances'] = adj
     else:
-        return adj
\ No newline at end of file
+        return adj
+
+def knn_query(
+    adata: internal.AnnData | internal.AnnDataSet,
+    query: np.ndarray,
+    n_neighbors: int = 50,
+) -> csr_matrix:
+    """
+    Search the nearest neighbors of new observations using the HNSW index
+    stored by :func:`~snapatac2.pp.knn` with `store_index=True`.
+
+    Parameters
+    ----------
+    adata
+        Annotated data matrix containing the index in `.uns["hnsw_index"]`.
+    query
+        Coordinates of the query observations, in the same representation
+        (and dimensions) as the one used to build the index.
+    n_neighbors
+        The number of nearest neighbors to be searched.
+
+    Returns
+    -------
+    csr_matrix
+        A sparse matrix of shape `(n_query, n_obs)` storing the distances
+        to the nearest neighbors.
+    """
+    if 'hnsw_index' not in adata.uns:
+        raise KeyError("Please call `knn` with `method='hora'` and `store_index=True` first")
+    return internal.query_ann_index(
+        adata.uns['hnsw_index'], np.asarray(query, dtype=np.float32), n_neighbors)
+
+def _index_neighbors(
+    adata: internal.AnnData | internal.AnnDataSet,
+    use_rep: str,
+    use_dims: int | list[int] | None,
+    n_neighbors: int,
+) -> tuple[np.ndarray, np.ndarray] | None:
+    """
+    The nearest neighbors of all cells, searched in the HNSW index stored by
+    :func:`~snapatac2.pp.knn` if it is up to date with `.obsm[use_rep]`.
+
+    Returns
+    -------
+    tuple[np.ndarray, np.ndarray] | None
+        The indices and distances of the neighbors of every cell, of shape
+        `(n_obs, n_neighbors)` and sorted by distance, or `None` if no index
+        built on the current data is stored.
+    """
+    data = adata.obsm[use_rep]
+    if use_dims is not None:
+        data = data[:, :use_dims] if isinstance(use_dims, int) else data[:, use_dims]
+    data = np.asarray(data, dtype=np.float32)
+    index = _stored_index(adata, data, _index_key(use_rep, use_dims))
+    if index is None:
+        return None
+    graph = internal.query_ann_index(index, data, n_neighbors).tocsr()
+    n = graph.shape[0]
+    if np.any(np.diff(graph.indptr) != n_neighbors):
+        return None
+    indices = graph.indices.reshape(n, n_neighbors)
+    distances = graph.data.reshape(n, n_neighbors)
+    order = np.argsort(distances, axis=1, kind="stable")
+    return np.take_along_axis(indices, order, axis=1), np.take_along_axis(distances, order, axis=1)
+
+def _fingerprint(data: np.ndarray) -> str:
+    return hashlib.sha1(np.ascontiguousarray(data, dtype=np.float32).tobytes()).hexdigest()
+
+def _stored_index(
+    adata: internal.AnnData | internal.AnnDataSet,
+    data: np.ndarray,
+    key: str,
+    allow_appended: bool = False,
+) -> np.ndarray | None:
+    """
+    The HNSW index stored in `adata.uns`, if it was built on `data`. With
+    `allow_appended=True`, the index may have been built on the first rows of
+    `data` only, i.e., before cells were appended.
+    """
+    info = adata.uns.get('hnsw_index_info')
+    if 'hnsw_index' not in adata.uns or not isinstance(info, dict) or info.get('key') != key:
+        return None
+    n_obs = int(info['n_obs'])
+    if n_obs > data.shape[0] or (n_obs < data.shape[0] and not allow_appended):
+        return None
+    if _fingerprint(data[:n_obs]) != info['fingerprint']:
+        return None
+    return adata.uns['hnsw_index']
+
+def _index_key(use_rep: str, use_dims: int | list[int] | None) -> str:
+    if use_dims is None:
+        return use_rep
+    if isinstance(use_dims, int):
+        return f"{use_rep}[:{use_dims}]"
+    return f"{use_rep}{list(use_dims)}"
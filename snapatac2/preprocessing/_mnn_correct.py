@@ -5,7 +5,7 @@ import itertools
 from scipy.special import logsumexp
 
 import snapatac2._snapatac2 as internal
-from snapatac2._utils import is_anndata 
+from snapatac2._utils import is_anndata, get_n_jobs
 
 def mnc_correct(
     adata: internal.AnnData | internal.AnnDataSet | np.adarray,
@@ -19,7 +19,7 @@ def mnc_correct(
     groupby: str | list[str] | None = None,
     key_added: str | None = None,
     inplace: bool = True,
-    n_jobs: int = 8,
+    n_jobs: int | None = None,
 ) -> np.ndarray | None:
     """
     A modified MNN-Correct algorithm based on cluster centroid.
@@ -52,6 +52,7 @@ def mnc_correct(
         Whether to store the result in the anndata object.
     n_jobs
         Number of jobs to use for parallelization.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
 
     Returns
     -------
@@ -93,7 +94,7 @@ def mnc_correct(
         group_indices = [x for x in group_indices.values()]
 
         inputs = [(mat[group_idx, :], batch[group_idx]) for group_idx in group_indices]
-        with Pool(n_jobs) as p:
+        with Pool(get_n_jobs(n_jobs)) as p:
             results = p.map(lambda x: _mnc_correct_main(x[0], x[1], n_iter, n_neighbors, n_clusters), inputs)
         for idx, result in zip(group_indices, results):
             mat[idx, :] = result
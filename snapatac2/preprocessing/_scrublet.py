@@ -7,7 +7,7 @@ import scipy.sparse as ss
 import logging
 from anndata import AnnData
 
-from .._utils import chunks, anndata_par
+from .._utils import chunks, anndata_par, derive_seed
 import snapatac2._snapatac2 as internal
 from snapatac2.tools._embedding import spectral
 
@@ -21,7 +21,7 @@ def scrublet(
     use_approx_neighbors=False,
     random_state: int = 0,
     inplace: bool = True,
-    n_jobs: int = 8,
+    n_jobs: int | None = None,
     verbose: bool = True,
 ) -> None:
     """
@@ -65,6 +65,7 @@ def scrublet(
         Whether update the AnnData object inplace
     n_jobs
         Number of jobs to run in parallel.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
     verbose
         Whether to print progress messages.
     
@@ -128,7 +129,7 @@ def filter_doublets(
     probability_threshold: float | None = 0.5,
     score_threshold: float | None = None,
     inplace: bool = True,
-    n_jobs: int = 8,
+    n_jobs: int | None = None,
     verbose: bool = True,
 ) -> np.ndarray | None:
     """Remove doublets according to the doublet probability or doublet score.
@@ -153,6 +154,7 @@ def filter_doublets(
         Perform computation inplace or return result.
     n_jobs
         Number of jobs to run in parallel.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
     verbose
         Whether to print progress messages.
 
@@ -306,8 +308,8 @@ def simulate_doublets(
     n_obs = count_matrix.shape[0]
     n_sim = int(n_obs * sim_doublet_ratio)
 
-    np.random.seed(random_state)
-    pair_ix = np.random.randint(0, n_obs, size=(n_sim, 2))
+    rng = np.random.default_rng(derive_seed(random_state, "scrublet"))
+    pair_ix = rng.integers(0, n_obs, size=(n_sim, 2))
 
     count_matrix_sim = count_matrix[pair_ix[:,0],:] + count_matrix[pair_ix[:,1],:]
     total_counts_sim = total_counts[pair_ix[:,0]] + total_counts[pair_ix[:,1]]
@@ -1,10 +1,17 @@
 from ._embedding import *
 
-from ._clustering import leiden, leiden_sweep, kmeans, dbscan, hdbscan
+from ._clustering import leiden, leiden_sweep, consensus_clustering, kmeans, dbscan, hdbscan, merge_clusters, rare_cells
 from ._smooth import smooth
-from ._call_peaks import macs3, merge_peaks
-from ._diff import marker_regions, diff_test
+from ._call_peaks import macs3, merge_peaks, reproducible_peaks, peak_saturation
+from ._diff import marker_regions, diff_test, diff_methylation
+from ._association import donor_association
 from ._network import *
-from ._motif import motif_enrichment
-from ._integration import transfer_labels
+from ._motif import motif_enrichment, fit_tn5_bias, kmer_counts, rank_tf_drivers
+from ._integration import transfer_labels, annotate_cells
+from ._model import save_model, load_model, map_to_reference
+from ._segmentation import chromatin_states
+from ._nucleosome import nucleosome_positions
+from ._footprint import diff_footprint
+from ._variant import variant_overlap, variant_enrichment
+from ._comparative import liftover, compare_peaks, peak_set_overlap, cross_species_pseudobulk
 from ._misc import *
\ No newline at end of file
@@ -0,0 +1,174 @@
+from __future__ import annotations
+
+import numpy as np
+from scipy.stats import f as f_dist
+
+import snapatac2._snapatac2 as internal
+from snapatac2.tools._diff import _p_adjust_bh
+from snapatac2.tools._misc import aggregate_X
+
+__all__ = ['donor_association']
+
+def donor_association(
+    adata: internal.AnnData | internal.AnnDataSet,
+    donor: str | list[str],
+    test: str,
+    *,
+    covariates: list[str] | None = None,
+    donor_covariates: 'pandas.DataFrame' | None = None,
+    min_cells: int = 10,
+    min_cpm: float = 1.0,
+) -> 'polars.DataFrame':
+    """
+    Test the association between accessibility and donor-level covariates.
+
+    The counts in `.X` of the cells of every donor are summed into pseudobulk
+    profiles, which are transformed to log2(CPM + 1). Every feature is then
+    fitted with a linear model of the donor covariate `test`, adjusted for
+    `covariates`, e.g., `log2(CPM + 1) ~ disease + age + sex`. The association is
+    assessed with an F-test comparing the models with and without `test`.
+    Categorical covariates are one-hot encoded, dropping the first level.
+
+    Using donors rather than cells as replicates accounts for the correlation
+    between the cells of the same donor, which otherwise leads to inflated
+    p-values in population-scale studies.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to features, e.g., peaks.
+        To test a single cell type, subset `adata` to its cells first.
+    donor
+        The donor of every cell. If a `str`, donors are obtained from `.obs[donor]`.
+    test
+        The covariate to test.
+    covariates
+        The covariates to adjust for.
+    donor_covariates
+        A table of covariates indexed by donor. If None, covariates are read from
+        `.obs`, where they must be constant within every donor.
+    min_cells
+        Donors with fewer cells are discarded.
+    min_cpm
+        Features whose mean CPM across donors is below this value are discarded.
+
+    Returns
+    -------
+    polars.DataFrame
+        A table with the columns "feature", "coefficient", "standard error",
+        "statistic", "p-value" and "adjusted p-value", sorted by p-value.
+        "statistic" is the F statistic. If `test` is numeric or has two levels,
+        "coefficient" is the change of log2(CPM + 1) per unit, or relative to the
+        first level, and "statistic" is the square of its t statistic. Otherwise
+        "coefficient" and "standard error" are null.
+    """
+    import polars as pl
+
+    covariates = [] if covariates is None else list(covariates)
+    if test in covariates:
+        raise ValueError(f"'{test}' is both tested and adjusted for")
+
+    donor_names, features, cpm = _donor_profiles(adata, donor, min_cells, min_cpm)
+    y = np.log2(cpm + 1)
+    table = _donor_table(adata, donor, [test] + covariates, donor_covariates, donor_names)
+
+    test_design = _encode(table[test]).to_numpy()
+    reduced = np.column_stack(
+        [np.ones(len(donor_names))] + [_encode(table[c]).to_numpy() for c in covariates]
+    )
+    full = np.column_stack([reduced, test_design])
+    n, p = full.shape
+    q = test_design.shape[1]
+    if n <= p:
+        raise ValueError(f"{n} donors are not enough to fit {p} coefficients")
+
+    def fit(design):
+        beta, _, rank, _ = np.linalg.lstsq(design, y, rcond=None)
+        rss = ((y - design @ beta) ** 2).sum(axis=0)
+        return beta, rss, rank
+
+    beta, rss_full, rank_full = fit(full)
+    _, rss_reduced, rank_reduced = fit(reduced)
+    df_test = rank_full - rank_reduced
+    df_resid = n - rank_full
+    if df_test == 0:
+        raise ValueError(f"'{test}' is collinear with the other covariates")
+
+    with np.errstate(divide='ignore', invalid='ignore'):
+        statistic = ((rss_reduced - rss_full) / df_test) / (rss_full / df_resid)
+    statistic = np.nan_to_num(statistic, nan=0.0, posinf=np.finfo(np.float64).max)
+    pval = f_dist.sf(statistic, df_test, df_resid)
+
+    if q == 1:
+        coefficient = beta[-1, :]
+        inv = np.linalg.pinv(full.T @ full)
+        stderr = np.sqrt(inv[-1, -1] * rss_full / df_resid)
+    else:
+        coefficient = [None] * len(features)
+        stderr = [None] * len(features)
+
+    return pl.DataFrame([
+        pl.Series("feature", features.tolist(), dtype=pl.String),
+        pl.Series("coefficient", coefficient, dtype=pl.Float64),
+        pl.Series("standard error", stderr, dtype=pl.Float64),
+        pl.Series("statistic", statistic),
+        pl.Series("p-value", pval),
+        pl.Series("adjusted p-value", _p_adjust_bh(pval)),
+    ]).sort("p-value")
+
+def _donor_profiles(adata, donor, min_cells, min_cpm):
+    """Pseudobulk CPM of the donors with at least `min_cells` cells, restricted
+    to the features whose mean CPM is at least `min_cpm`."""
+    if isinstance(donor, str):
+        donor = adata.obs[donor]
+    donors = [str(x) for x in donor]
+    names, n_cells = np.unique(donors, return_counts=True)
+    keep = set(names[n_cells >= min_cells])
+    if len(keep) == 0:
+        raise ValueError(f"no donor has at least {min_cells} cells")
+
+    profiles = aggregate_X(adata, groupby=donors, normalize="RPM")
+    obs_names = list(profiles.obs_names)
+    donor_names = [x for x in obs_names if x in keep]
+    cpm = np.asarray(profiles.X)[[obs_names.index(x) for x in donor_names], :]
+    feature_mask = cpm.mean(axis=0) >= min_cpm
+    features = np.array(adata.var_names)[feature_mask]
+    return donor_names, features, cpm[:, feature_mask]
+
+def _donor_table(adata, donor, columns, donor_covariates, donor_names) -> 'pandas.DataFrame':
+    """Covariates of `donor_names`, read from `donor_covariates` or from `.obs`,
+    where they must be constant within every donor."""
+    import pandas as pd
+    import polars as pl
+
+    if donor_covariates is not None:
+        table = donor_covariates[columns].copy()
+        table.index = table.index.astype(str)
+        missing = [x for x in donor_names if x not in table.index]
+        if len(missing) > 0:
+            raise ValueError(f"no covariates for donors: {missing[:5]}")
+        return table.loc[donor_names]
+
+    if isinstance(donor, str):
+        donor = adata.obs[donor]
+    obs = adata.obs[columns]
+    obs = obs.to_pandas() if isinstance(obs, pl.DataFrame) else pd.DataFrame(obs)
+    obs.index = [str(x) for x in donor]
+    inconsistent = [c for c in obs.columns if (obs.groupby(level=0)[c].nunique() > 1).any()]
+    if len(inconsistent) > 0:
+        raise ValueError(f"covariates vary within donors: {inconsistent}")
+    return obs.groupby(level=0, sort=False).first().loc[donor_names]
+
+def _encode(col: 'pandas.Series') -> 'pandas.DataFrame':
+    """Numeric encoding of a covariate. Categorical covariates are one-hot
+    encoded, dropping the first level."""
+    import pandas as pd
+
+    if pd.api.types.is_numeric_dtype(col) and not pd.api.types.is_bool_dtype(col):
+        return col.astype(np.float64).to_frame()
+    dummies = pd.get_dummies(col.astype(str), drop_first=True, dtype=np.float64)
+    if dummies.shape[1] == 0:
+        raise ValueError(f"'{col.name}' has a single level among the donors")
+    dummies.columns = [f"{col.name}_{x}" for x in dummies.columns]
+    return dummies
@@ -5,6 +5,7 @@ from snapatac2._snapatac2 import AnnData, AnnDataSet
 import snapatac2._snapatac2 as _snapatac2
 import logging
 from snapatac2.genome import Genome
+from snapatac2._utils import get_n_jobs, sanitize_filename
 
 
 def macs3(
@@ -16,6 +17,7 @@ def macs3(
     broad_cutoff: float = 0.1,
     replicate: str | list[str] | None = None,
     replicate_qvalue: float | None = None,
+    min_replicates: int | None = None,
     max_frag_size: int | None = None,
     selections: set[str] | None = None,
     nolambda: bool = False,
@@ -25,8 +27,9 @@ def macs3(
     blacklist: Path | None = None,
     key_added: str = "macs3",
     tempdir: Path | None = None,
+    bdg_dir: Path | None = None,
     inplace: bool = True,
-    n_jobs: int = 8,
+    n_jobs: int | None = None,
 ) -> dict[str, "polars.DataFrame"] | None:
     """Call peaks using MACS3.
 
@@ -59,6 +62,10 @@ def macs3(
         This parameter is only used when `replicate` is provided.
         Typically this parameter is used to call peaks in replicates with a more lenient cutoff.
         If not provided, `qvalue` will be used.
+    min_replicates
+        Minimum number of replicates in which a peak must be found to be
+        considered reproducible. If not provided, peaks must be found in all
+        replicates. This parameter is only used when `replicate` is provided.
     max_frag_size
         Maximum fragment size. If provided, fragments with sizes larger than
         `max_frag_size` will be not be used in peak calling.
@@ -85,10 +92,21 @@ def macs3(
     tempdir
         If provided, a temporary directory will be created in the directory.
         Otherwise, a temporary directory will be created in the system default temporary directory.
+    bdg_dir
+        If provided, the intermediate statistics of the peak caller are saved in
+        this directory for each group (or "all" if `groupby` is None), to
+        diagnose or re-threshold the peaks without calling them again:
+        the treatment pileup (`{group}_treat_pileup.bdg`), the local lambda
+        (`{group}_control_lambda.bdg`), the -log10 p-values (`{group}_pscore.bdg`)
+        and q-values (`{group}_qscore.bdg`) of every position, and the number
+        and total length of the peaks obtained with a range of p-value cutoffs
+        (`{group}_cutoff_analysis.txt`). Only the pooled cells of each group are saved
+        when `replicate` is provided.
     inplace
         Whether to store the result inplace.
     n_jobs
         Number of processes to use for peak calling.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
 
     Returns
     -------
@@ -146,8 +164,34 @@ def macs3(
     options.d = extsize
     options.scanwindow = 2 * options.d
 
+    if bdg_dir is not None:
+        bdg_dir = Path(bdg_dir)
+        bdg_dir.mkdir(parents=True, exist_ok=True)
+
+    def _set_bdg_output(name):
+        if bdg_dir is None or name is None:
+            options.store_bdg = False
+            options.cutoff_analysis = False
+        else:
+            prefix = str(bdg_dir / sanitize_filename(name))
+            options.store_bdg = True
+            options.bdg_treat = prefix + "_treat_pileup.bdg"
+            options.bdg_control = prefix + "_control_lambda.bdg"
+            options.cutoff_analysis = True
+            options.cutoff_analysis_file = prefix + "_cutoff_analysis.txt"
+
+    def _save_scores(name):
+        if bdg_dir is not None:
+            prefix = str(bdg_dir / sanitize_filename(name))
+            _snapatac2.peak_score_tracks(
+                prefix + "_treat_pileup.bdg", prefix + "_control_lambda.bdg",
+                prefix + "_pscore.bdg", prefix + "_qscore.bdg",
+            )
+
     if groupby is None:
+        _set_bdg_output("all")
         peaks = _snapatac2.call_peaks_bulk(adata, options, max_frag_size)
+        _save_scores("all")
         if inplace:
             adata.uns[key_added + "_pseudobulk"] = (
                 peaks.to_pandas() if not adata.isbacked else peaks
@@ -166,10 +210,10 @@ def macs3(
             [group_idx[x] for x in groupby],
             replicate,
             max_frag_size,
-            selections,
+            None if selections is None else {group_idx[x] for x in selections if x in group_idx},
         )
 
-        def _call_peaks(tags):
+        def _call_peaks(tags, name):
             import tempfile
 
             tempfile.tempdir = tmpdirname  # Overwrite the default tempdir in MACS3
@@ -178,10 +222,13 @@ def macs3(
             logging.getLogger().setLevel(
                 logging.CRITICAL + 1
             )  # temporarily disable logging
+            _set_bdg_output(name)
             peakdetect = PeakDetect(treat=merged, opt=options)
             peakdetect.call_peaks()
             peakdetect.peaks.filter_fc(fc_low=options.fecutoff)
             merged = peakdetect.peaks
+            _save_scores(name)
+            _set_bdg_output(None)
 
             others = []
             if replicate_qvalue is not None:
@@ -193,13 +240,15 @@ def macs3(
                 others.append(peakdetect.peaks)
 
             logging.getLogger().setLevel(logging.INFO)  # enable logging
-            return _snapatac2.find_reproducible_peaks(merged, others, blacklist)
+            return _snapatac2.find_reproducible_peaks(merged, others, blacklist, min_replicates)
 
         logging.info("Calling peaks...")
+        args = [(v, group_names[int(k)]) for k, v in fragments.items()]
+        n_jobs = get_n_jobs(n_jobs)
         if n_jobs == 1:
-            peaks = [_call_peaks(x) for x in fragments.values()]
+            peaks = [_call_peaks(*x) for x in args]
         else:
-            peaks = _par_map(_call_peaks, [(x,) for x in fragments.values()], n_jobs)
+            peaks = _par_map(_call_peaks, args, n_jobs)
         peaks = {group_names[int(k)]: v for k, v in zip(fragments.keys(), peaks)}
         if inplace:
             if adata.isbacked:
@@ -210,6 +259,207 @@ def macs3(
             return peaks
 
 
+def reproducible_peaks(
+    adata: AnnData | AnnDataSet,
+    groupby: str | list[str],
+    *,
+    n_replicates: int = 2,
+    min_replicates: int | None = None,
+    sample: str | list[str] | None = None,
+    min_cells: int = 25,
+    qvalue: float = 0.05,
+    replicate_qvalue: float | None = None,
+    random_state: int = 0,
+    key_added: str = "reproducible_peaks",
+    inplace: bool = True,
+    **kwargs,
+) -> dict[str, "polars.DataFrame"] | None:
+    """Call reproducible peaks for each group of cells using pseudo-replicates.
+
+    The cells of each group are split into `n_replicates` pseudo-replicates,
+    peaks are called on the pooled cells of the group and on every
+    pseudo-replicate with :func:`~snapatac2.tl.macs3`, and only the peaks of
+    the pooled cells found in at least `min_replicates` pseudo-replicates are
+    kept. This is equivalent to `addReproduciblePeakSet` in ArchR.
+
+    If `sample` is provided, groups with at least `n_replicates` samples of
+    `min_cells` cells or more use these samples as the replicates, each of the
+    remaining cells being assigned to one of them at random. Otherwise, and for
+    the other groups, the cells are split at random.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    groupby
+        Group the cells before peak calling. If a `str`, groups are obtained from
+        `.obs[groupby]`.
+    n_replicates
+        Number of pseudo-replicates per group.
+    min_replicates
+        Minimum number of pseudo-replicates in which a peak must be found.
+        If `None`, peaks must be found in all of them.
+    sample
+        Sample of each cell. If a `str`, samples are obtained from `.obs[sample]`.
+    min_cells
+        Minimum number of cells for a sample to be used as a replicate.
+    qvalue
+        qvalue cutoff used for the pooled cells.
+    replicate_qvalue
+        qvalue cutoff used for the pseudo-replicates. If `None`, `qvalue` is used.
+    random_state
+        Seed of the random number generator.
+    key_added
+        `.uns` key under which to add the peak information.
+    inplace
+        Whether to store the result inplace.
+    kwargs
+        Additional arguments passed to :func:`~snapatac2.tl.macs3`.
+
+    Returns
+    -------
+    dict[str, 'polars.DataFrame'] | None
+        If `inplace=True` it stores the result in `adata.uns[`key_added`]`.
+        Otherwise, it returns the result as dataframes.
+
+    See Also
+    --------
+    macs3
+    """
+    import numpy as np
+
+    if n_replicates < 1:
+        raise ValueError("n_replicates must be positive")
+    if min_replicates is not None and not 1 <= min_replicates <= n_replicates:
+        raise ValueError("min_replicates must be between 1 and n_replicates")
+    groupby = np.asarray(adata.obs[groupby] if isinstance(groupby, str) else groupby)
+    if sample is not None:
+        sample = np.asarray(adata.obs[sample] if isinstance(sample, str) else sample)
+
+    rng = np.random.default_rng(random_state)
+    replicate = np.empty(len(groupby), dtype=object)
+    for group in np.unique(groupby):
+        idx = np.where(groupby == group)[0]
+        reps = None
+        if sample is not None:
+            names, counts = np.unique(sample[idx], return_counts=True)
+            large = names[counts >= min_cells]
+            if len(large) >= n_replicates:
+                # Keep the largest samples and assign the others at random.
+                large = list(large[np.argsort(-counts[counts >= min_cells], kind="stable")][:n_replicates])
+                reps = np.array([
+                    "rep" + str(large.index(s) if s in large else rng.integers(n_replicates))
+                    for s in sample[idx]
+                ], dtype=object)
+        if reps is None:
+            reps = np.array(["rep" + str(i % n_replicates) for i in rng.permutation(len(idx))], dtype=object)
+        replicate[idx] = reps
+
+    peaks = macs3(
+        adata,
+        groupby=list(groupby),
+        qvalue=qvalue,
+        replicate=list(replicate),
+        replicate_qvalue=replicate_qvalue,
+        min_replicates=min_replicates,
+        inplace=False,
+        **kwargs,
+    )
+    if inplace:
+        if adata.isbacked:
+            adata.uns[key_added] = peaks
+        else:
+            adata.uns[key_added] = {k: v.to_pandas() for k, v in peaks.items()}
+    else:
+        return peaks
+
+
+def peak_saturation(
+    adata: AnnData | AnnDataSet,
+    groupby: str | list[str],
+    *,
+    fractions: list[float] = [0.1, 0.25, 0.5, 0.75, 1.0],
+    selections: set[str] | None = None,
+    min_cells: int = 10,
+    random_state: int = 0,
+    **kwargs,
+) -> "polars.DataFrame":
+    """Compute peak-count saturation curves by subsampling the cells of each group.
+
+    For every fraction, that fraction of the cells of each group is sampled
+    and peaks are called on the sampled cells with :func:`~snapatac2.tl.macs3`.
+    The samples are nested: the cells sampled for a fraction are also sampled
+    for the larger fractions. If the number of peaks of a group still grows
+    steeply at the largest fractions, more cells of this group would likely
+    reveal more regulatory elements.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    groupby
+        Group the cells before peak calling. If a `str`, groups are obtained from
+        `.obs[groupby]`.
+    fractions
+        Fractions of the cells of each group to sample, between 0 and 1.
+    selections
+        Compute the curves of the selected groups only.
+    min_cells
+        Subsamples with fewer cells are skipped.
+    random_state
+        Seed of the random number generator.
+    kwargs
+        Additional arguments passed to :func:`~snapatac2.tl.macs3`.
+
+    Returns
+    -------
+    'polars.DataFrame'
+        A DataFrame with 4 columns: "group", "fraction", "n_cells" (the number
+        of sampled cells), and "n_peaks".
+
+    See Also
+    --------
+    macs3
+    """
+    import numpy as np
+    import polars as pl
+
+    if any(not 0 < f <= 1 for f in fractions):
+        raise ValueError("fractions must be in (0, 1]")
+    groupby = np.asarray(adata.obs[groupby] if isinstance(groupby, str) else groupby).astype(str)
+    groups = np.unique(groupby) if selections is None else sorted(set(selections))
+
+    # Rank of every cell in a random permutation of its group.
+    rng = np.random.default_rng(random_state)
+    rank = np.zeros(len(groupby), dtype=np.int64)
+    sizes = {}
+    for group in groups:
+        idx = np.where(groupby == group)[0]
+        rank[idx] = rng.permutation(len(idx))
+        sizes[group] = len(idx)
+
+    result = []
+    for fraction in sorted(fractions):
+        n_cells = {g: int(round(fraction * n)) for g, n in sizes.items()}
+        selected = {g for g, n in n_cells.items() if n >= min_cells}
+        if len(selected) == 0:
+            continue
+        labels = [
+            g if g in selected and rank[i] < n_cells[g] else "__unselected__"
+            for i, g in enumerate(groupby)
+        ]
+        logging.info("Calling peaks on {:.0%} of the cells ...".format(fraction))
+        peaks = macs3(adata, groupby=labels, selections=selected, inplace=False, **kwargs)
+        for group in selected:
+            n_peaks = peaks[group].shape[0] if group in peaks else 0
+            result.append((group, fraction, n_cells[group], n_peaks))
+    return pl.DataFrame(
+        result, schema=["group", "fraction", "n_cells", "n_peaks"], orient="row",
+    ).sort(["group", "fraction"])
+
+
 def merge_peaks(
     peaks: dict[str, "polars.DataFrame"],
     chrom_sizes: dict[str, int] | Genome,
@@ -6,7 +6,7 @@ import numpy as np
 
 import snapatac2
 import snapatac2._snapatac2 as internal
-from snapatac2._utils import get_igraph_from_adjacency, is_anndata
+from snapatac2._utils import get_igraph_from_adjacency, is_anndata, get_n_jobs, derive_seed
 
 def leiden(
     adata: internal.AnnData | internal.AnnDataSet | ss.spmatrix,
@@ -62,13 +62,7 @@ def leiden(
         dim (number of samples) that stores the subgroup id
         (`'0'`, `'1'`, ...) for each cell. Otherwise, returns the array directly.
     """
-    from igraph import set_random_number_generator
-    from collections import Counter
     import polars
-    import random
-
-    random.seed(random_state)
-    set_random_number_generator(random)
 
     if is_anndata(adata):
         adjacency = adata.obsp["distances"]
@@ -77,14 +71,45 @@ def leiden(
         adjacency = adata
 
     gr = get_igraph_from_adjacency(adjacency)
+    weights = np.exp(-np.array(gr.es["weight"])) if weighted else None
+    groups, _ = _leiden_membership(
+        gr, weights, resolution, objective_function, min_cluster_size,
+        n_iterations, derive_seed(random_state, "leiden"),
+    )
 
-    if weighted:
-        weights = np.array(gr.es["weight"])
-        weights = np.exp(-weights)
+    groups = np.array(groups, dtype=np.str_)
+    if inplace:
+        adata.obs[key_added] = polars.Series(
+            groups,
+            dtype=polars.datatypes.Categorical,
+        )
     else:
-        weights = None
+        return groups
 
-    groups = gr.community_leiden(
+
+def _leiden_membership(
+    gr,
+    weights: np.ndarray | None,
+    resolution: float,
+    objective_function: str,
+    min_cluster_size: int,
+    n_iterations: int,
+    seed: int,
+) -> tuple[list[int], list[int]]:
+    """
+    Run the Leiden algorithm on an igraph graph. Returns the cluster ids,
+    ordered by cluster size and with -1 for clusters smaller than
+    `min_cluster_size`, and the raw membership. `seed` is a seed derived
+    with :func:`~snapatac2._utils.derive_seed`; the global random state of
+    Python is left untouched.
+    """
+    from igraph import set_random_number_generator
+    from collections import Counter
+    import random
+
+    set_random_number_generator(random.Random(seed))
+
+    membership = gr.community_leiden(
         objective_function=objective_function,
         weights=weights,
         resolution=resolution,
@@ -96,21 +121,10 @@ def leiden(
     new_cl_id = dict(
         [
             (cl, i) if count >= min_cluster_size else (cl, -1)
-            for (i, (cl, count)) in enumerate(Counter(groups).most_common())
+            for (i, (cl, count)) in enumerate(Counter(membership).most_common())
         ]
     )
-    for i in range(len(groups)):
-        groups[i] = new_cl_id[groups[i]]
-
-    groups = np.array(groups, dtype=np.str_)
-    if inplace:
-        adata.obs[key_added] = polars.Series(
-            groups,
-            dtype=polars.datatypes.Categorical,
-        )
-    else:
-        return groups
-
+    return [new_cl_id[x] for x in membership], membership
 
 def leiden_sweep(
     adata: internal.AnnData | internal.AnnDataSet | ss.spmatrix,
@@ -121,10 +135,21 @@ def leiden_sweep(
     n_iterations: int = -1,
     random_state: int = 0,
     weighted: bool = False,
-    n_jobs: int = 16,
-):
+    n_jobs: int | None = None,
+    *,
+    n_repeats: int = 3,
+    key_added: str | None = "leiden_sweep",
+) -> list[dict]:
     """
-    Perform a sweep over multiple resolutions for Leiden clustering and compute silhouette scores.
+    Perform a sweep over multiple resolutions for Leiden clustering.
+
+    The graph is built once and clustered at every resolution. For each
+    resolution, the number of clusters, the modularity of the partition,
+    the silhouette score and the stability of the clustering are reported.
+    The stability is the mean adjusted Rand index between the clustering and
+    `n_repeats` clusterings obtained with other random seeds: resolutions
+    at which the partition is poorly defined give unstable clusterings.
+    Plotting these values against the resolution helps choose a resolution.
 
     Parameters
     ----------
@@ -134,7 +159,8 @@ def leiden_sweep(
     resolutions
         A list of resolution values to evaluate.
     use_rep
-        Which data in `adata.obsm` to use for clustering. Default is "X_spectral".
+        Which data in `adata.obsm` to use for computing silhouette scores.
+        Default is "X_spectral".
     objective_function
         whether to use the Constant Potts Model (CPM) or modularity.
         Must be either "CPM", "modularity" or "RBConfiguration".
@@ -150,11 +176,23 @@ def leiden_sweep(
         Whether to use the edge weights in the graph
     n_jobs
         The number of parallel jobs to run.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
+    n_repeats
+        Number of additional random seeds used to assess the stability.
+        Set to 0 to skip the stability assessment.
+    key_added
+        If `adata` is an AnnData object, the cluster labels are stored in
+        `adata.obsm[key_added]` as an integer matrix of shape
+        `n_obs` x `len(resolutions)`, where -1 marks cells in clusters smaller
+        than `min_cluster_size`, and the resolutions are stored in
+        `adata.uns[key_added]`. Set to `None` to not store the labels.
 
     Returns
     -------
     list[dict]
-        A dictionary mapping each resolution to its corresponding silhouette score.
+        One dictionary per resolution, with the keys "resolution",
+        "n_clusters", "modularity", "silhouette_score" and "stability"
+        (NaN if `n_repeats = 0`).
     """
     from sklearn.metrics import silhouette_score
     from multiprocess import get_context
@@ -165,34 +203,182 @@ def leiden_sweep(
     else:
         mat = use_rep
         distances = adata
+        key_added = None
+
+    gr = get_igraph_from_adjacency(distances)
+    weights = np.exp(-np.array(gr.es["weight"])) if weighted else None
 
     def _func(resolution):
-        groups = leiden(
-            distances,
-            resolution,
-            objective_function=objective_function,
-            min_cluster_size=min_cluster_size,
-            n_iterations=n_iterations,
-            random_state=random_state,
-            weighted=weighted,
-            inplace=False,
+        groups, membership = _leiden_membership(
+            gr, weights, resolution, objective_function, min_cluster_size,
+            n_iterations, derive_seed(random_state, "leiden"),
         )
-        if len(set(groups)) > 1:
+        n_clusters = len(set(groups))
+        if n_clusters > 1:
             score = silhouette_score(
                 mat,
                 groups,
                 sample_size=20000,
+                random_state=derive_seed(random_state, "silhouette") % 2**32,
             )
         else:
             score = 0
-        return  {
+        labels = [str(x) for x in groups]
+        ari = [
+            internal.adjusted_rand_index(labels, [str(x) for x in _leiden_membership(
+                gr, weights, resolution, objective_function, min_cluster_size,
+                n_iterations, derive_seed(random_state, f"leiden/{i + 1}"),
+            )[0]])
+            for i in range(n_repeats)
+        ]
+        stat = {
             "resolution": resolution,
-            "n_clusters": len(set(groups)),
+            "n_clusters": n_clusters,
+            "modularity": gr.modularity(membership, weights=weights),
             "silhouette_score": score,
+            "stability": float(np.mean(ari)) if n_repeats > 0 else float('nan'),
         }
+        return stat, groups
+
+    n_jobs = get_n_jobs(n_jobs)
+    if n_jobs > 1:
+        with get_context("spawn").Pool(n_jobs) as p:
+            result = list(p.imap(_func, resolutions))
+    else:
+        result = [_func(r) for r in resolutions]
+
+    if key_added is not None:
+        adata.obsm[key_added] = np.array([x[1] for x in result], dtype=np.int64).T
+        adata.uns[key_added] = np.array(resolutions, dtype=np.float64)
+    return [x[0] for x in result]
+
+def consensus_clustering(
+    adata: internal.AnnData | internal.AnnDataSet,
+    resolution: float = 1,
+    n_runs: int = 10,
+    subsample: float = 0.9,
+    objective_function: Literal["CPM", "modularity", "RBConfiguration"] = "modularity",
+    min_cluster_size: int = 5,
+    n_iterations: int = -1,
+    random_state: int = 0,
+    key_added: str = "consensus",
+    weighted: bool = False,
+    inplace: bool = True,
+) -> tuple[np.ndarray, np.ndarray, ss.csr_matrix] | None:
+    """
+    Consensus clustering of cells by Leiden runs with multiple seeds.
+
+    The Leiden algorithm is run `n_runs` times with different random seeds,
+    each time on the subgraph induced by a random subset of the cells.
+    For every edge of the kNN graph, the co-assignment frequency is the
+    fraction of the runs including both cells in which they are assigned to
+    the same cluster. The consensus labels are obtained by clustering the
+    graph weighted by the co-assignment frequencies, so that cells whose
+    assignment depends on the seed are grouped with the cells they are most
+    consistently clustered with.
+    This requires having ran :func:`~snapatac2.pp.knn`.
 
-    with get_context("spawn").Pool(n_jobs) as p:
-        return list(p.imap(_func, resolutions))
+    Parameters
+    ----------
+    adata
+        The annotated data matrix.
+    resolution
+        A parameter value controlling the coarseness of the clustering.
+        Higher values lead to more clusters.
+    n_runs
+        Number of Leiden runs.
+    subsample
+        Fraction of cells included in every run.
+    objective_function
+        whether to use the Constant Potts Model (CPM) or modularity.
+        Must be either "CPM", "modularity" or "RBConfiguration".
+    min_cluster_size
+        The minimum size of consensus clusters.
+    n_iterations
+        How many iterations of the Leiden clustering algorithm to perform.
+        Positive values above 2 define the total number of iterations to perform,
+        -1 has the algorithm run until it reaches its optimal clustering.
+    random_state
+        Seed of the random number generator.
+    key_added
+        `adata.obs` key under which to add the cluster labels.
+    weighted
+        Whether to use the edge weights in the graph in the individual runs.
+    inplace
+        Whether to store the result in the anndata object.
+
+    Returns
+    -------
+    tuple[np.ndarray, np.ndarray, scipy.sparse.csr_matrix] | None
+        If `inplace=True`, update `adata.obs[key_added]` with the consensus
+        labels, `adata.obs[key_added + "_confidence"]` with the mean
+        co-assignment frequency of each cell with its neighbors in the same
+        consensus cluster, and `adata.obsp[key_added + "_coassignment"]` with
+        the co-assignment frequencies of the edges of the kNN graph.
+        Otherwise, return the labels, the confidences and the co-assignment
+        matrix.
+    """
+    import igraph as ig
+    import polars
+
+    if not 0 < subsample <= 1:
+        raise ValueError("subsample must be in (0, 1]")
+    adjacency = adata.obsp["distances"]
+    gr = get_igraph_from_adjacency(adjacency)
+    n = gr.vcount()
+    edges = np.array(gr.get_edgelist(), dtype=np.int64).reshape(-1, 2)
+    source, target = edges[:, 0], edges[:, 1]
+
+    rng = np.random.default_rng(derive_seed(random_state, "subsample"))
+    n_sampled = np.zeros(len(edges))
+    n_same = np.zeros(len(edges))
+    for i in range(n_runs):
+        if subsample < 1:
+            cells = np.sort(rng.choice(n, max(1, int(n * subsample)), replace=False))
+            sub = gr.induced_subgraph(cells)
+        else:
+            cells = np.arange(n)
+            sub = gr
+        weights = np.exp(-np.array(sub.es["weight"])) if weighted else None
+        _, membership = _leiden_membership(
+            sub, weights, resolution, objective_function, 1, n_iterations,
+            derive_seed(random_state, f"leiden/{i}"),
+        )
+        labels = np.full(n, -1)
+        labels[cells] = membership
+        both = (labels[source] >= 0) & (labels[target] >= 0)
+        n_sampled += both
+        n_same += both & (labels[source] == labels[target])
+    coassignment = np.divide(
+        n_same, n_sampled, out=np.zeros(len(edges)), where=n_sampled > 0,
+    )
+
+    consensus = ig.Graph(n=n, edges=edges.tolist(), directed=False)
+    groups, _ = _leiden_membership(
+        consensus, coassignment, resolution, objective_function, min_cluster_size,
+        n_iterations, derive_seed(random_state, "consensus"),
+    )
+    groups = np.array(groups)
+
+    within = groups[source] == groups[target]
+    total = np.bincount(source[within], coassignment[within], minlength=n) + \
+        np.bincount(target[within], coassignment[within], minlength=n)
+    count = np.bincount(source[within], minlength=n) + np.bincount(target[within], minlength=n)
+    confidence = np.divide(total, count, out=np.zeros(n), where=count > 0)
+    coassignment = ss.csr_matrix(
+        (coassignment, (source, target)), shape=(n, n),
+    )
+
+    groups = np.array(groups, dtype=np.str_)
+    if inplace:
+        adata.obs[key_added] = polars.Series(
+            groups,
+            dtype=polars.datatypes.Categorical,
+        )
+        adata.obs[key_added + "_confidence"] = confidence
+        adata.obsp[key_added + "_coassignment"] = coassignment
+    else:
+        return groups, confidence, coassignment
 
 def kmeans(
     adata: internal.AnnData | internal.AnnDataSet | np.ndarray,
@@ -360,6 +546,7 @@ def dbscan(
     n_jobs
         The number of parallel jobs to run. None means 1 unless in a
         joblib.parallel_backend context. -1 means using all processors.
+        Defaults to the number of threads set by :func:`~snapatac2.set_num_threads`.
     use_rep
         Which data in `adata.obsm` to use for clustering. Default is "X_spectral".
     key_added
@@ -389,3 +576,116 @@ def dbscan(
         values=groups.astype("U"),
         categories=sorted(map(str, np.unique(groups))),
     )
+
+
+def merge_clusters(
+    adata: internal.AnnData | internal.AnnDataSet,
+    groupby: str,
+    threshold: float,
+    similarity: Literal["pearson", "jaccard"] = "pearson",
+    linkage: Literal["average", "complete", "single"] = "average",
+    key_added: str | None = None,
+    inplace: bool = True,
+) -> tuple[np.ndarray, dict] | None:
+    """
+    Merge clusters with similar chromatin accessibility profiles.
+
+    Pseudobulk profiles are computed by summing `adata.X` over the cells of each
+    cluster. A dendrogram is built over the clusters by agglomerative clustering,
+    and clusters whose similarity is at least `threshold` are merged.
+    Merged clusters are named by joining the names of their members with "+".
+
+    Parameters
+    ----------
+    adata
+        The annotated data matrix.
+    groupby
+        `adata.obs` key containing the cluster labels.
+    threshold
+        Clusters with similarity no less than this value are merged.
+    similarity
+        Similarity between pseudobulk profiles:
+        - 'pearson': Pearson correlation of the log-transformed CPM-normalized profiles.
+        - 'jaccard': Jaccard index of the sets of accessible features.
+    linkage
+        Linkage criterion used to compute the similarity between groups of clusters.
+    key_added
+        `adata.obs` key under which to add the merged labels.
+        If `None`, it is set to `{groupby}_merged`.
+    inplace
+        Whether to store the result in the anndata object.
+
+    Returns
+    -------
+    tuple[np.ndarray, dict] | None
+        If `inplace=True`, update `adata.obs[key_added]` with the merged labels and
+        store the dendrogram in `adata.uns[key_added]`, which contains the
+        names of the original clusters (`'clusters'`) and a SciPy-compatible
+        linkage matrix (`'linkage'`) whose distances are `1 - similarity`.
+        Otherwise, return the merged labels and the dendrogram.
+    """
+    import polars
+
+    if key_added is None:
+        key_added = f"{groupby}_merged"
+    labels = [None if x is None else str(x) for x in adata.obs[groupby]]
+    names, linkage_matrix, merged = internal.merge_clusters(
+        adata, labels, similarity, linkage, threshold,
+    )
+    tree = {"clusters": np.array(names, dtype=np.str_), "linkage": linkage_matrix}
+    # Cells without a cluster stay unlabeled rather than being labeled "None".
+    merged = np.array(merged, dtype=object)
+    if inplace:
+        adata.obs[key_added] = polars.Series(merged, dtype=polars.datatypes.Categorical)
+        adata.uns[key_added] = tree
+    else:
+        return merged, tree
+
+
+def rare_cells(
+    adata: internal.AnnData | internal.AnnDataSet | ss.spmatrix,
+    threshold: float | None = 2.0,
+    key_added: str = "rarity",
+    inplace: bool = True,
+) -> np.ndarray | None:
+    """
+    Score cells by how likely they belong to rare populations.
+
+    The rarity score is the local outlier factor (LOF) computed on the kNN graph.
+    It compares the local density of a cell with the local densities of its
+    neighbors: cells in sparse regions next to abundant populations receive
+    scores well above 1. This complements resolution sweeps of the Leiden
+    algorithm, which tend to absorb small populations into larger clusters.
+    This requires having ran :func:`~snapatac2.pp.knn`.
+
+    Parameters
+    ----------
+    adata
+        The annotated data matrix or sparse adjacency matrix of the kNN graph.
+    threshold
+        Cells with scores above this value are flagged as putative rare cells
+        in `adata.obs[f"{key_added}_flag"]`. If `None`, no flag is added.
+    key_added
+        `adata.obs` key under which to add the rarity scores.
+    inplace
+        Whether to store the result in the anndata object.
+
+    Returns
+    -------
+    np.ndarray | None
+        If `inplace=True`, update `adata.obs[key_added]` with the per-cell scores.
+        Otherwise, return the scores.
+    """
+    if is_anndata(adata):
+        graph = adata.obsp["distances"]
+    else:
+        inplace = False
+        graph = adata
+
+    scores = internal.rarity_score(graph)
+    if inplace:
+        adata.obs[key_added] = scores
+        if threshold is not None:
+            adata.obs[f"{key_added}_flag"] = scores > threshold
+    else:
+        return scores
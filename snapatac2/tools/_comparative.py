@@ -0,0 +1,380 @@
+from __future__ import annotations
+
+from pathlib import Path
+import numpy as np
+
+import snapatac2._snapatac2 as internal
+from snapatac2.genome import Genome
+
+__all__ = ['liftover', 'compare_peaks', 'peak_set_overlap', 'cross_species_pseudobulk']
+
+def liftover(
+    regions: list[str],
+    chain_file: Path,
+    min_match: float = 0.95,
+) -> list[str | None]:
+    """
+    Map regions to another genome assembly or species with a chain file.
+
+    This follows the UCSC `liftOver` tool: a region is mapped with the chain
+    aligning the most of its bases, and the result spans the positions of the
+    aligned bases in the other genome.
+
+    Parameters
+    ----------
+    regions
+        A list of regions, e.g., `["chr1:1000-1500"]`.
+    chain_file
+        A chain file from the source to the target genome, e.g.,
+        "hg38ToMm10.over.chain.gz".
+    min_match
+        Minimum fraction of the bases of a region that must be aligned.
+
+    Returns
+    -------
+    list[str | None]
+        The mapped regions, or None for regions that cannot be mapped.
+    """
+    return internal.liftover(list(regions), chain_file, min_match)
+
+def compare_peaks(
+    peaks: list[str],
+    other_peaks: list[str],
+    chain_file: Path,
+    min_match: float = 0.95,
+) -> tuple['polars.DataFrame', dict[str, float]]:
+    """
+    Compare peak sets of two species or assemblies.
+
+    The peaks are mapped to the other genome with :func:`~snapatac2.tl.liftover`
+    and intersected with the peaks of the other genome. A peak is *conserved*
+    if it can be mapped and its mapped region overlaps a peak of the other
+    genome; the pair then forms an orthologous region, as used by
+    :func:`~snapatac2.tl.cross_species_pseudobulk`.
+
+    Parameters
+    ----------
+    peaks
+        The peaks of the source genome.
+    other_peaks
+        The peaks of the target genome.
+    chain_file
+        A chain file from the source to the target genome.
+    min_match
+        Minimum fraction of the bases of a peak that must be aligned.
+
+    Returns
+    -------
+    tuple[polars.DataFrame, dict[str, float]]
+        A table with one row per peak and the columns "peak", "mapped_region"
+        (null if the peak cannot be mapped) and "ortholog" (the peak of the
+        target genome with the largest overlap, or null), and a dictionary of
+        statistics: "n_peaks", "n_mapped", "n_conserved", "fraction_mapped",
+        "fraction_conserved" (both relative to "n_peaks") and
+        "fraction_other_conserved" (the fraction of `other_peaks` that are
+        the ortholog of a peak).
+    """
+    import polars as pl
+
+    peaks = list(peaks)
+    mapped = liftover(peaks, chain_file, min_match)
+    is_mapped = [i for i, x in enumerate(mapped) if x is not None]
+    orthologs = [None] * len(peaks)
+    if len(is_mapped) > 0 and len(other_peaks) > 0:
+        other_peaks = list(other_peaks)
+        query = _parse_regions([mapped[i] for i in is_mapped])
+        other = _parse_regions(other_peaks)
+        conserved = np.flatnonzero(_overlapping(query, other))
+        for k, j in zip(conserved, _best_overlap(query, other, conserved)):
+            orthologs[is_mapped[k]] = other_peaks[j]
+
+    n_peaks = len(peaks)
+    n_mapped = len(is_mapped)
+    n_conserved = sum(x is not None for x in orthologs)
+    n_other = len(set(other_peaks))
+    stats = {
+        "n_peaks": n_peaks,
+        "n_mapped": n_mapped,
+        "n_conserved": n_conserved,
+        "fraction_mapped": n_mapped / n_peaks if n_peaks > 0 else 0.0,
+        "fraction_conserved": n_conserved / n_peaks if n_peaks > 0 else 0.0,
+        "fraction_other_conserved": (
+            len(set(x for x in orthologs if x is not None)) / n_other if n_other > 0 else 0.0
+        ),
+    }
+    table = pl.DataFrame(
+        {"peak": peaks, "mapped_region": mapped, "ortholog": orthologs},
+        schema={"peak": pl.String, "mapped_region": pl.String, "ortholog": pl.String},
+    )
+    return table, stats
+
+def peak_set_overlap(
+    peak_sets: dict[str, list[str] | Path],
+    *,
+    genome: Genome | dict[str, int] | int | None = None,
+    n_bootstrap: int = 200,
+    confidence: float = 0.95,
+    random_state: int = 0,
+) -> 'polars.DataFrame':
+    """
+    Compute overlap statistics between peak sets.
+
+    Every pair of peak sets, e.g., the peaks called by SnapATAC2 and published
+    peaks, is compared by:
+
+    - the number of peaks of each set overlapping a peak of the other set;
+    - the Jaccard index, i.e., the number of bases covered by both sets divided
+      by the number of bases covered by either set, together with a bootstrap
+      confidence interval obtained by resampling the peaks of both sets;
+    - a one-sided Fisher's exact test of the enrichment of overlaps, computed on
+      peak counts as in `bedtools fisher`: the number of intervals covered by
+      neither set is estimated from the genome size and the mean length of
+      the merged intervals.
+
+    Parameters
+    ----------
+    peak_sets
+        The peak sets, as lists of regions, e.g., `["chr1:1000-1500"]`, or BED files.
+    genome
+        The genome, its chromosome sizes or its total size, used by the Fisher's
+        exact test. If None, the test is skipped.
+    n_bootstrap
+        Number of bootstrap samples. If 0, no confidence interval is computed.
+    confidence
+        Confidence level of the intervals.
+    random_state
+        Seed of the random number generator.
+
+    Returns
+    -------
+    polars.DataFrame
+        A table with one row per pair of peak sets and the columns "set1",
+        "set2", "n_peaks1", "n_peaks2", "n_overlap1" and "n_overlap2" (the
+        number of peaks of each set overlapping the other set), "jaccard",
+        "jaccard_lower", "jaccard_upper", "odds_ratio" and "p-value".
+    """
+    import itertools
+    import polars as pl
+    from scipy.stats import fisher_exact
+
+    if isinstance(genome, Genome):
+        genome = genome.chrom_sizes
+    if isinstance(genome, dict):
+        genome = sum(genome.values())
+
+    sets = {}
+    for name, peaks in peak_sets.items():
+        if isinstance(peaks, (str, Path)):
+            peaks = internal.read_regions(Path(peaks))
+        sets[name] = _parse_regions(peaks)
+
+    rng = np.random.default_rng(random_state)
+    alpha = (1 - confidence) / 2
+    rows = []
+    for a, b in itertools.combinations(sets.keys(), 2):
+        chroms_a, starts_a, ends_a = sets[a]
+        chroms_b, starts_b, ends_b = sets[b]
+        overlap_a = _overlapping(sets[a], sets[b]).sum()
+        overlap_b = _overlapping(sets[b], sets[a]).sum()
+        jaccard = _jaccard(sets[a], sets[b])
+        lower = upper = None
+        if n_bootstrap > 0 and len(starts_a) > 0 and len(starts_b) > 0:
+            samples = []
+            for _ in range(n_bootstrap):
+                i = rng.integers(len(starts_a), size=len(starts_a))
+                j = rng.integers(len(starts_b), size=len(starts_b))
+                samples.append(_jaccard(
+                    (chroms_a[i], starts_a[i], ends_a[i]),
+                    (chroms_b[j], starts_b[j], ends_b[j]),
+                ))
+            lower, upper = np.quantile(samples, [alpha, 1 - alpha])
+
+        odds_ratio = pval = None
+        if genome is not None:
+            union = _merge(
+                np.concatenate([chroms_a, chroms_b]),
+                np.concatenate([starts_a, starts_b]),
+                np.concatenate([ends_a, ends_b]),
+            )
+            mean_length = (union[2] - union[1]).mean() if len(union[1]) > 0 else 1
+            only_a = len(starts_a) - overlap_a
+            only_b = len(starts_b) - overlap_b
+            neither = max(int(genome / mean_length) - overlap_a - only_a - only_b, 0)
+            odds_ratio, pval = fisher_exact(
+                [[overlap_a, only_a], [only_b, neither]], alternative="greater",
+            )
+        rows.append((
+            a, b, len(starts_a), len(starts_b), int(overlap_a), int(overlap_b),
+            jaccard, lower, upper, odds_ratio, pval,
+        ))
+
+    schema = {
+        "set1": pl.String, "set2": pl.String,
+        "n_peaks1": pl.Int64, "n_peaks2": pl.Int64,
+        "n_overlap1": pl.Int64, "n_overlap2": pl.Int64,
+        "jaccard": pl.Float64, "jaccard_lower": pl.Float64, "jaccard_upper": pl.Float64,
+        "odds_ratio": pl.Float64, "p-value": pl.Float64,
+    }
+    return pl.DataFrame(rows, schema=schema, orient="row")
+
+def _parse_regions(regions: list[str]) -> tuple[np.ndarray, np.ndarray, np.ndarray]:
+    chroms, starts, ends = [], [], []
+    for region in regions:
+        chrom, coord = region.rsplit(":", 1)
+        start, end = coord.split("-")
+        chroms.append(chrom)
+        starts.append(int(start))
+        ends.append(int(end))
+    return np.array(chroms, dtype=str), np.array(starts, dtype=np.int64), np.array(ends, dtype=np.int64)
+
+def _merge(chroms, starts, ends):
+    """Merge overlapping intervals. The result is sorted by chromosome and start."""
+    if len(starts) == 0:
+        return chroms, starts, ends
+    order = np.lexsort((starts, chroms))
+    chroms, starts, ends = chroms[order], starts[order], ends[order]
+    same_chrom = np.concatenate([[False], chroms[1:] == chroms[:-1]])
+    # Running maximum of the ends within every chromosome, obtained by
+    # offsetting every chromosome beyond the ends of the previous ones.
+    offset = (np.cumsum(~same_chrom) - 1) * (ends.max() + 1)
+    max_end = np.maximum.accumulate(ends + offset) - offset
+    new = ~same_chrom
+    new[1:] |= starts[1:] > max_end[:-1]
+    group = np.cumsum(new) - 1
+    idx = np.flatnonzero(new)
+    merged_ends = np.zeros(len(idx), dtype=np.int64)
+    np.maximum.at(merged_ends, group, ends)
+    return chroms[idx], starts[idx], merged_ends
+
+def _coverage(regions) -> int:
+    _, starts, ends = _merge(*regions)
+    return int((ends - starts).sum())
+
+def _jaccard(a, b) -> float:
+    union = _coverage(tuple(np.concatenate([x, y]) for x, y in zip(a, b)))
+    if union == 0:
+        return 0.0
+    return (_coverage(a) + _coverage(b) - union) / union
+
+def _overlapping(a, b) -> np.ndarray:
+    """Whether every interval of `a` overlaps an interval of `b`."""
+    chroms_a, starts_a, ends_a = a
+    chroms_b, starts_b, ends_b = _merge(*b)
+    result = np.zeros(len(starts_a), dtype=bool)
+    for chrom in np.unique(chroms_a):
+        idx = np.flatnonzero(chroms_a == chrom)
+        mask = chroms_b == chrom
+        if not mask.any():
+            continue
+        s, e = starts_b[mask], ends_b[mask]
+        i = np.searchsorted(s, ends_a[idx], side="left") - 1
+        result[idx] = (i >= 0) & (e[np.maximum(i, 0)] > starts_a[idx])
+    return result
+
+def _best_overlap(a, b, idx: np.ndarray) -> np.ndarray:
+    """The interval of `b` with the largest overlap with every interval `a[idx]`,
+    which must overlap at least one interval of `b`."""
+    chroms_a, starts_a, ends_a = a
+    chroms_b, starts_b, ends_b = b
+    result = np.zeros(len(idx), dtype=np.int64)
+    for chrom in np.unique(chroms_a[idx]):
+        k = np.flatnonzero(chroms_a[idx] == chrom)
+        j = np.flatnonzero(chroms_b == chrom)
+        j = j[np.argsort(starts_b[j], kind="stable")]
+        s, e = starts_b[j], ends_b[j]
+        # An interval of `b` overlapping [start, end) starts in
+        # (start - max_len, end), where max_len is the longest interval.
+        max_len = (e - s).max()
+        for x in k:
+            start, end = starts_a[idx[x]], ends_a[idx[x]]
+            lo = np.searchsorted(s, start - max_len, side="right")
+            hi = np.searchsorted(s, end, side="left")
+            overlap = np.minimum(e[lo:hi], end) - np.maximum(s[lo:hi], start)
+            result[x] = j[lo + np.argmax(overlap)]
+    return result
+
+def cross_species_pseudobulk(
+    adata: internal.AnnData | internal.AnnDataSet,
+    other_adata: internal.AnnData | internal.AnnDataSet,
+    orthologs: 'polars.DataFrame',
+    groupby: str | list[str],
+    other_groupby: str | list[str],
+    species: tuple[str, str] = ("source", "target"),
+    counting_strategy: str = 'insertion',
+) -> 'anndata.AnnData':
+    """
+    Compute a joint pseudobulk matrix of two species on orthologous regions.
+
+    The fragments of every group of cells of each species are counted in its
+    own coordinates of the orthologous regions, so that the groups of both
+    species can be compared directly, e.g., by correlation or clustering.
+
+    :func:`~snapatac2.pp.import_fragments` must be ran first on both datasets
+    in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The data of the source species.
+    other_adata
+        The data of the target species.
+    orthologs
+        The orthologous regions, as produced by :func:`~snapatac2.tl.compare_peaks`.
+        Rows without an ortholog are ignored.
+    groupby
+        Group the cells of `adata`. If a `str`, groups are obtained from `.obs[groupby]`.
+    other_groupby
+        Group the cells of `other_adata`.
+    species
+        The names of the two species.
+    counting_strategy
+        The strategy to compute feature counts. See
+        :func:`~snapatac2.pp.make_peak_matrix` for details.
+
+    Returns
+    -------
+    anndata.AnnData
+        The pseudobulk matrix of shape `n_groups` x `n_orthologs`, with the
+        species and the group of every row in `.obs["species"]` and
+        `.obs["group"]`, and the regions of both species in `.var[species[0]]`
+        and `.var[species[1]]`.
+    """
+    import pandas as pd
+    import scipy.sparse as sp
+    from anndata import AnnData
+
+    orthologs = orthologs.filter(orthologs["ortholog"].is_not_null())
+    regions = [orthologs["peak"].to_list(), orthologs["ortholog"].to_list()]
+    matrices = []
+    obs = {"species": [], "group": []}
+    for name, data, group, rs in zip(
+        species, [adata, other_adata], [groupby, other_groupby], regions,
+    ):
+        if isinstance(group, str):
+            group = data.obs[group]
+        group = np.array([str(x) for x in group])
+        bin_size = max(_region_length(x) for x in rs) if len(rs) > 0 else 1
+        mat, bins = internal.mk_region_bin_matrix(
+            data, list(rs), bin_size, counting_strategy, None, None,
+        )
+        columns = {b: i for i, b in enumerate(bins)}
+        mat = sp.csr_matrix(mat)[:, [columns[x] for x in rs]]
+        labels = np.unique(group)
+        indicator = sp.csr_matrix(
+            (np.ones(len(group)), (np.searchsorted(labels, group), np.arange(len(group)))),
+            shape=(len(labels), len(group)),
+        )
+        matrices.append(indicator @ mat)
+        obs["species"].extend([name] * len(labels))
+        obs["group"].extend(labels.tolist())
+
+    obs = pd.DataFrame(obs, index=[f"{s}:{g}" for s, g in zip(obs["species"], obs["group"])])
+    var = pd.DataFrame(
+        {species[0]: regions[0], species[1]: regions[1]},
+        index=[f"{a}|{b}" for a, b in zip(*regions)],
+    )
+    return AnnData(X=sp.vstack(matrices).tocsr(), obs=obs, var=var)
+
+def _region_length(region: str) -> int:
+    start, end = region.rsplit(":", 1)[1].split("-")
+    return int(end) - int(start)
@@ -1,6 +1,9 @@
 from __future__ import annotations
 
 from typing import Literal
+from pathlib import Path
+import hashlib
+import os
 import numpy as np
 from scipy.stats import chi2, norm, zscore
 import logging
@@ -63,6 +66,7 @@ def diff_test(
     direction: Literal["positive", "negative", "both"] = "both",
     min_log_fc: float = 0.25,
     min_pct: float = 0.05,
+    checkpoint_dir: Path | None = None,
 ) -> 'polars.DataFrame':
     """
     Identify differentially accessible regions.
@@ -91,6 +95,11 @@ def diff_test(
     min_pct
         Only test features that are detected in a minimum fraction of min_pct
         cells in either of the two populations. 
+    checkpoint_dir
+        If provided, p-values are saved in this directory as the features are
+        tested. Calling this function again with the same directory and inputs
+        resumes an interrupted run, skipping the features that have been tested.
+        The checkpoint is removed when the test finishes.
 
     Returns
     -------
@@ -101,22 +110,7 @@ def diff_test(
     import polars as pl
 
     def to_indices(xs, type):
-        xs = [_convert_to_bool_if_np_bool(x) for x in xs]
-        if all(isinstance(x, bool) for x in xs):
-            return [i for i, value in enumerate(xs) if value]
-        elif all([isinstance(item, str) for item in xs]):
-            if type == "obs":
-                if data.isbacked:
-                    return data.obs_ix(xs)
-                else:
-                    return [data.obs_names.get_loc(x) for x in xs]
-            else:
-                if data.isbacked:
-                    return data.var_ix(xs)
-                else:
-                    return [data.var_names.get_loc(x) for x in xs]
-        else:
-            return xs
+        return _to_indices(data, xs, type)
 
     cell_group1 = to_indices(cell_group1, "obs")
     n_group1 = len(cell_group1)
@@ -145,7 +139,8 @@ def diff_test(
     else:
         features, log_fc = zip(*filtered)
         logging.info("Testing {} features ...".format(len(features)))
-        pvals = _diff_test_helper(cell_by_peak, test_var, features, covariates)
+        checkpoint = None if checkpoint_dir is None else _Checkpoint(checkpoint_dir)
+        pvals = _diff_test_helper(cell_by_peak, test_var, features, covariates, checkpoint)
         var_names = data.var_names
         return pl.DataFrame({
             "feature name": [var_names[i] for i in features],
@@ -154,6 +149,182 @@ def diff_test(
             "adjusted p-value": _p_adjust_bh(pvals),
         }).sort("adjusted p-value")
 
+def diff_methylation(
+    data: AnnData | AnnDataSet,
+    regions: Path | list[str],
+    cell_group1: list[int] | list[str],
+    cell_group2: list[int] | list[str],
+    method: Literal["fisher", "betabinomial"] = "fisher",
+    min_coverage: int = 10,
+    min_diff: float = 0.0,
+    chunk_size: int = 500,
+) -> 'polars.DataFrame':
+    """
+    Identify differentially methylated regions.
+
+    The numbers of methylated and total bases of every region are counted in
+    each cell from the base-level data imported by
+    :func:`~snapatac2.pp.import_values`, and compared between the two groups
+    of cells.
+
+    Parameters
+    ----------
+    data
+        AnnData or AnnDataSet object created by :func:`~snapatac2.pp.import_values`.
+    regions
+        Regions to test, either a list of strings, e.g., ["chr1:1-100", "chr2:2-200"],
+        or a file containing one region per line, see the `peak_file` parameter
+        of :func:`~snapatac2.pp.make_peak_matrix`.
+    cell_group1
+        cells belonging to group 1. This can be a list of cell barcodes, indices or 
+        boolean mask vector.
+    cell_group2
+        cells belonging to group 2. This can be a list of cell barcodes, indices or 
+        boolean mask vector.
+    method
+        "fisher": Fisher's exact test on the methylated and unmethylated counts
+        pooled over the cells of each group.
+        "betabinomial": likelihood ratio test of a beta-binomial model of the
+        counts of individual cells, which accounts for the variability between
+        cells. It is slower, but less prone to false positives when a few
+        cells dominate the counts.
+    min_coverage
+        Only test regions covered by at least this number of bases in both
+        groups.
+    min_diff
+        Only test regions whose methylation levels differ by at least this
+        amount between the two groups.
+    chunk_size
+        Chunk size used when counting the bases.
+
+    Returns
+    -------
+    pl.DataFrame
+        A DataFrame with 7 columns: "region", "methylation 1", "methylation 2"
+        (the methylation levels of the groups), "difference" (group 1 minus
+        group 2), "coverage" (number of bases in both groups), "p-value", and
+        "adjusted p-value".
+    """
+    import polars as pl
+    from scipy.stats import fisher_exact
+    from snapatac2.preprocessing import make_peak_matrix
+
+    if method not in ("fisher", "betabinomial"):
+        raise NameError("method must be 'fisher' or 'betabinomial'")
+    cell_group1 = _to_indices(data, cell_group1, "obs")
+    cell_group2 = _to_indices(data, cell_group2, "obs")
+    cells = cell_group1 + cell_group2
+    n_group1 = len(cell_group1)
+
+    if isinstance(regions, (str, Path)):
+        region_args = dict(peak_file=regions)
+    else:
+        region_args = dict(use_rep=list(regions))
+    methylated, total = [
+        make_peak_matrix(
+            data, value_type=value_type, summary_type="sum", chunk_size=chunk_size, **region_args,
+        )
+        for value_type in ("target", "total")
+    ]
+    names = methylated.var_names
+    methylated = methylated.X[cells, :].tocsc()
+    total = total.X[cells, :].tocsc()
+
+    def group_sum(mat):
+        return (
+            np.ravel(mat[:n_group1, :].sum(axis=0)),
+            np.ravel(mat[n_group1:, :].sum(axis=0)),
+        )
+    m1, m2 = group_sum(methylated)
+    n1, n2 = group_sum(total)
+    with np.errstate(divide="ignore", invalid="ignore"):
+        level1 = m1 / n1
+        level2 = m2 / n2
+    selected = np.where(
+        (n1 >= min_coverage) & (n2 >= min_coverage) & (np.abs(level1 - level2) >= min_diff)
+    )[0]
+    if len(selected) == 0:
+        logging.warning("Zero region left after filtering, perhaps 'min_coverage' or 'min_diff' is too large")
+        return pl.DataFrame()
+
+    logging.info("Testing {} regions ...".format(len(selected)))
+    pvals = []
+    for i in selected:
+        if method == "fisher":
+            table = [[m1[i], n1[i] - m1[i]], [m2[i], n2[i] - m2[i]]]
+            pvals.append(fisher_exact(table)[1])
+        else:
+            m = np.ravel(methylated[:, i].toarray())
+            n = np.ravel(total[:, i].toarray())
+            pvals.append(_betabinomial_test(m[:n_group1], n[:n_group1], m[n_group1:], n[n_group1:]))
+    pvals = np.array(pvals)
+    return pl.DataFrame({
+        "region": [names[i] for i in selected],
+        "methylation 1": level1[selected],
+        "methylation 2": level2[selected],
+        "difference": level1[selected] - level2[selected],
+        "coverage": (n1 + n2)[selected],
+        "p-value": pvals,
+        "adjusted p-value": _p_adjust_bh(pvals),
+    }).sort("adjusted p-value")
+
+def _betabinomial_test(m1, n1, m2, n2) -> float:
+    """
+    Likelihood ratio test of the difference of the mean methylation levels
+    of two groups, where the methylated counts `m` of the cells follow
+    beta-binomial distributions with a dispersion shared by the groups.
+    """
+    from scipy.optimize import minimize
+    from scipy.special import expit
+    from scipy.stats import betabinom
+
+    keep1, keep2 = n1 > 0, n2 > 0
+    m1, n1, m2, n2 = m1[keep1], n1[keep1], m2[keep2], n2[keep2]
+
+    def loglik(m, n, logit_mu, logit_rho):
+        mu = np.clip(expit(logit_mu), 1e-6, 1 - 1e-6)
+        rho = np.clip(expit(logit_rho), 1e-6, 1 - 1e-6)
+        a = mu * (1 - rho) / rho
+        b = (1 - mu) * (1 - rho) / rho
+        return betabinom.logpmf(m, n, a, b).sum()
+
+    def logit(p):
+        p = np.clip(p, 0.01, 0.99)
+        return np.log(p / (1 - p))
+
+    m, n = np.concatenate([m1, m2]), np.concatenate([n1, n2])
+    null = minimize(
+        lambda x: -loglik(m, n, x[0], x[1]),
+        x0=[logit(m.sum() / n.sum()), logit(0.1)],
+        method="Nelder-Mead",
+    )
+    full = minimize(
+        lambda x: -loglik(m1, n1, x[0], x[2]) - loglik(m2, n2, x[1], x[2]),
+        x0=[logit(m1.sum() / n1.sum()), logit(m2.sum() / n2.sum()), null.x[1]],
+        method="Nelder-Mead",
+    )
+    chi = max(2 * (null.fun - full.fun), 0)
+    return chi2.sf(chi, 1)
+
+def _to_indices(data, xs, type):
+    """Convert barcodes, feature names or boolean masks to indices."""
+    xs = [_convert_to_bool_if_np_bool(x) for x in xs]
+    if all(isinstance(x, bool) for x in xs):
+        return [i for i, value in enumerate(xs) if value]
+    elif all([isinstance(item, str) for item in xs]):
+        if type == "obs":
+            if data.isbacked:
+                return data.obs_ix(xs)
+            else:
+                return [data.obs_names.get_loc(x) for x in xs]
+        else:
+            if data.isbacked:
+                return data.var_ix(xs)
+            else:
+                return [data.var_names.get_loc(x) for x in xs]
+    else:
+        return xs
+
 def _p_adjust_bh(p):
     """Benjamini-Hochberg p-value correction for multiple hypothesis testing."""
     p = np.asarray(p, dtype=np.float64)
@@ -190,7 +361,7 @@ def _filter_features(mat1, mat2, peak_indices, direction,
     peak_indices = [i for i in peak_indices if pass_min_pct(i)]
     return [(i, log_fc[i])  for i in peak_indices if adjust_sign(log_fc[i]) >= min_log_fc]
 
-def _diff_test_helper(mat, z, peaks=None, covariate=None) -> list[float]:
+def _diff_test_helper(mat, z, peaks=None, covariate=None, checkpoint=None) -> list[float]:
     """
     Parameters
     ----------
@@ -202,6 +373,8 @@ def _diff_test_helper(mat, z, peaks=None, covariate=None) -> list[float]:
         peak indices
     covariate 
         additional variables to regress out.
+    checkpoint
+        a `_Checkpoint` used to save partial results.
     """
 
     if len(z.shape) == 1:
@@ -216,10 +389,52 @@ def _diff_test_helper(mat, z, peaks=None, covariate=None) -> list[float]:
     if peaks is not None:
         mat = mat[:, peaks]
 
-    return _likelihood_ratio_test_many(np.asarray(X), np.asarray(z), mat)
+    if checkpoint is not None:
+        # The p-values depend on the tested matrix, the test variables and the
+        # covariates, whichever cells and features they come from.
+        fingerprint = hashlib.sha1()
+        for x in (X, z, mat.data, mat.indices, mat.indptr, mat.shape):
+            fingerprint.update(np.ascontiguousarray(x, dtype=np.float64).tobytes())
+        checkpoint.fingerprint = fingerprint.hexdigest()
+    return _likelihood_ratio_test_many(np.asarray(X), np.asarray(z), mat, checkpoint)
 
 
-def _likelihood_ratio_test_many(X, z, Y) -> list[float]:
+class _Checkpoint:
+    """
+    P-values computed so far, saved in `directory`. Results computed with
+    a different `fingerprint`, i.e., from different inputs, are discarded.
+    """
+    interval = 1000
+
+    def __init__(self, directory: Path, fingerprint: str | None = None):
+        self.directory = Path(directory)
+        self.file = self.directory / "diff_test.npz"
+        self.fingerprint = fingerprint
+
+    def load(self) -> list[float]:
+        if not self.file.exists():
+            return []
+        saved = np.load(self.file)
+        if str(saved["fingerprint"]) != self.fingerprint:
+            logging.warning("Inputs differ from those of the checkpoint in {}, starting over".format(self.directory))
+            return []
+        result = saved["pvalues"].tolist()
+        logging.info("Resuming from the checkpoint in {} ({} features tested)".format(self.directory, len(result)))
+        return result
+
+    def save(self, pvalues: list[float]):
+        self.directory.mkdir(parents=True, exist_ok=True)
+        tmp = self.directory / "diff_test.tmp.npz"
+        np.savez(tmp, fingerprint=self.fingerprint, pvalues=np.asarray(pvalues))
+        os.replace(tmp, self.file)
+
+    def finish(self):
+        self.file.unlink(missing_ok=True)
+        if not any(self.directory.iterdir()):
+            self.directory.rmdir()
+
+
+def _likelihood_ratio_test_many(X, z, Y, checkpoint=None) -> list[float]:
     """
     Parameters
     ----------
@@ -229,6 +444,8 @@ def _likelihood_ratio_test_many(X, z, Y) -> list[float]:
         (n_sample, 1), the additional variable.
     Y
         (n_sample, k), labels
+    checkpoint
+        a `_Checkpoint` used to save partial results.
     
     Returns
     -------
@@ -242,11 +459,15 @@ def _likelihood_ratio_test_many(X, z, Y) -> list[float]:
     _, n = Y.shape
     Y.data = np.ones(Y.data.shape)
 
-    result = []
-    for i in tqdm(range(n)):
+    result = [] if checkpoint is None else checkpoint.load()
+    for i in tqdm(range(len(result), n)):
         result.append(
             _likelihood_ratio_test(X0, X1, np.asarray(np.ravel(Y[:, i].todense())))
         )
+        if checkpoint is not None and (i + 1) % checkpoint.interval == 0:
+            checkpoint.save(result)
+    if checkpoint is not None:
+        checkpoint.finish()
     return result
 
 def _likelihood_ratio_test(
@@ -1,16 +1,18 @@
 from __future__ import annotations
 
 from typing import Literal
+from pathlib import Path
 import scipy as sp
 import numpy as np
 import gc
 import logging
 import math
 
-from snapatac2._utils import is_anndata 
+from snapatac2._utils import is_anndata, to_seed
+from snapatac2._provenance import _record
 import snapatac2._snapatac2 as internal
 
-__all__ = ['umap', 'spectral', 'multi_spectral']
+__all__ = ['umap', 'spectral', 'pca', 'embedding', 'multi_spectral', 'onnx_embedding']
 
 def umap(
     adata: internal.AnnData | internal.AnnDataSet | np.ndarray,
@@ -19,6 +21,7 @@ def umap(
     use_rep: str = "X_spectral",
     key_added: str = 'umap',
     random_state: int | None = 0,
+    use_index: bool = False,
     inplace: bool = True,
     **kwargs
 ) -> np.ndarray | None:
@@ -37,6 +40,11 @@ def umap(
         `adata.obs` key under which to add the cluster labels.
     random_state
         Random seed.
+    use_index
+        Take the nearest neighbors from the HNSW index stored by
+        :func:`~snapatac2.pp.knn` with `method='hora'` and `store_index=True`
+        on the same representation, instead of searching them again. The
+        neighbors are searched as usual if no index of the current data is stored.
     inplace
         Whether to store the result in the anndata object.
     **kwargs
@@ -60,6 +68,14 @@ def umap(
     if use_dims is not None:
         data = data[:, :use_dims] if isinstance(use_dims, int) else data[:, use_dims]
 
+    if use_index and is_anndata(adata):
+        from snapatac2.preprocessing._knn import _index_neighbors
+        neighbors = _index_neighbors(adata, use_rep, use_dims, kwargs.get('n_neighbors', 15))
+        if neighbors is None:
+            logging.warning("No HNSW index of the current data is stored, searching the neighbors.")
+        else:
+            kwargs['precomputed_knn'] = (neighbors[0], neighbors[1], None)
+
     umap = UMAP(random_state=random_state,
                 n_components=n_comps,
                 **kwargs).fit_transform(data)
@@ -90,7 +106,7 @@ def spectral(
     weighted_by_sd: bool = True,
     feature_weights: list[float] | None = None,
     inplace: bool = True,
-    num_threads: int = 32,
+    num_threads: int | None = None,
 ) -> tuple[np.ndarray, np.ndarray] | None:
     """
     Perform dimension reduction using Laplacian Eigenmaps.
@@ -164,6 +180,7 @@ def spectral(
         Whether to store the result in the anndata object.
     num_threads
         Number of threads to use in the Nystrom method.
+        If `None`, the global setting of :func:`~snapatac2.set_num_threads` is used.
 
     Returns
     -------
@@ -176,8 +193,6 @@ def spectral(
     --------
     multi_spectral
     """
-    np.random.seed(random_state)
-
     if isinstance(features, str):
         if features in adata.var:
             features = adata.var[features].to_numpy()
@@ -185,6 +200,7 @@ def spectral(
             raise NameError("Please call `select_features` first or explicitly set `features = None`")
 
     n_comps = min(adata.n_vars - 1, adata.n_obs - 1, n_comps)
+    tracker = internal.MemoryTracker()
 
     n_sample, _ = adata.shape
     if sample_size is None:
@@ -202,7 +218,7 @@ def spectral(
 
     if sample_size >= n_sample:
         if distance_metric == "cosine":
-            evals, evecs = internal.spectral_embedding(adata, features, n_comps, random_state, feature_weights)
+            evals, evecs = internal.spectral_embedding(adata, features, n_comps, to_seed(random_state), feature_weights)
         else:
             if feature_weights is None:
                 feature_weights = idf(adata, features)
@@ -217,7 +233,7 @@ def spectral(
                 weighted_by_degree = False
             else:
                 weighted_by_degree = True
-            v, u = internal.spectral_embedding_nystrom(adata, features, n_comps, sample_size, weighted_by_degree, chunk_size, None, num_threads)
+            v, u = internal.spectral_embedding_nystrom(adata, features, n_comps, sample_size, weighted_by_degree, chunk_size, None, num_threads, to_seed(random_state))
             evals, evecs = orthogonalize(v, u)
         else:
             if feature_weights is None:
@@ -247,10 +263,141 @@ def spectral(
     if inplace:
         adata.uns['spectral_eigenvalue'] = evals
         adata.obsm['X_spectral'] = evecs
+        _record(
+            adata, "spectral", tracker, n_comps=n_comps, random_state=random_state,
+            n_features=None if features is None else int(np.sum(features)),
+            sample_size=sample_size, sample_method=sample_method,
+            distance_metric=distance_metric, weighted_by_sd=weighted_by_sd,
+        )
     else:
         return (evals, evecs)
 
 
+def pca(
+    adata: internal.AnnData | internal.AnnDataSet,
+    n_comps: int = 30,
+    features: str | np.ndarray | None = "selected",
+    tfidf: bool = True,
+    n_power_iter: int = 4,
+    chunk_size: int = 5000,
+    random_state: int = 0,
+    checkpoint_dir: Path | None = None,
+    device: Literal["cpu", "cuda", "metal"] = "cpu",
+    inplace: bool = True,
+) -> tuple[np.ndarray, np.ndarray] | None:
+    """
+    Perform dimension reduction using randomized principal component analysis.
+
+    This is an alternative to the Laplacian Eigenmaps computed by :func:`~snapatac2.tl.spectral`.
+    The count matrix is centered implicitly and accessed only through products
+    with thin dense matrices, so the sparse matrix is never densified and is
+    read from disk in chunks when `adata` is backed.
+
+    Parameters
+    ----------
+    adata
+        AnnData or AnnDataSet object.
+    n_comps
+        Number of principal components to compute.
+    features
+        Boolean index mask. True means that the feature is kept.
+        False means the feature is removed. If `features=None`, all features are used.
+    tfidf
+        Whether to weight the features by the inverse document frequency (IDF)
+        and normalize every cell to unit L2 norm before the decomposition.
+    n_power_iter
+        Number of power iterations. More iterations improve the accuracy at the
+        cost of more passes over the data.
+    chunk_size
+        Number of cells read at a time.
+    random_state
+        Seed of the random state generator.
+    checkpoint_dir
+        If provided, intermediate results are saved in this directory after every
+        pass over the data. Calling this function again with the same directory and
+        parameters resumes an interrupted run from the last completed pass.
+        The directory is removed when the computation finishes.
+    device
+        Device computing the dense matrix products of the decomposition:
+        "cpu", or "cuda" or "metal", which require SnapATAC2 to be built with
+        the feature of the same name. "metal" computes in single precision.
+    inplace
+        Whether to store the result in the anndata object.
+
+    Returns
+    -------
+    tuple[np.ndarray, np.ndarray] | None
+        if `inplace=True` it stores the principal components in
+        `adata.obsm["X_pca"]` and the explained variance in `adata.uns["pca_variance"]`.
+        The fitted loadings, feature means, feature names and IDF weights are stored in
+        `adata.uns["pca_components"]`, `adata.uns["pca_mean"]`, `adata.uns["pca_features"]`
+        and `adata.uns["pca_feature_weights"]`, so that they can be saved with
+        :func:`~snapatac2.tl.save_model`.
+        Otherwise, it returns the result as numpy arrays.
+
+    See Also
+    --------
+    spectral
+    """
+    if isinstance(features, str):
+        if features in adata.var:
+            features = adata.var[features].to_numpy()
+        else:
+            raise NameError("Please call `select_features` first or explicitly set `features = None`")
+
+    tracker = internal.MemoryTracker()
+    variance, scores, components, mean, feature_weights = internal.pca_embedding(
+        adata, features, n_comps, tfidf, n_power_iter, chunk_size, to_seed(random_state),
+        checkpoint_dir, device,
+    )
+    if inplace:
+        adata.uns['pca_variance'] = variance
+        adata.uns['pca_components'] = components
+        adata.uns['pca_mean'] = mean
+        adata.uns['pca_features'] = np.asarray(adata.var_names if features is None else np.asarray(adata.var_names)[features])
+        if feature_weights is not None:
+            adata.uns['pca_feature_weights'] = feature_weights
+        adata.obsm['X_pca'] = scores
+        _record(
+            adata, "pca", tracker, n_comps=n_comps, tfidf=tfidf, n_power_iter=n_power_iter,
+            n_features=None if features is None else int(np.sum(features)),
+            random_state=random_state,
+        )
+    else:
+        return (variance, scores)
+
+
+def embedding(
+    adata: internal.AnnData | internal.AnnDataSet,
+    method: Literal["spectral", "pca"] = "spectral",
+    **kwargs,
+) -> tuple[np.ndarray, np.ndarray] | None:
+    """
+    Perform dimension reduction with the selected method.
+
+    Parameters
+    ----------
+    adata
+        AnnData or AnnDataSet object.
+    method
+        - 'spectral': Laplacian Eigenmaps, see :func:`~snapatac2.tl.spectral`.
+        - 'pca': randomized PCA, see :func:`~snapatac2.tl.pca`.
+    kwargs
+        Additional arguments passed to the selected method.
+
+    Returns
+    -------
+    tuple[np.ndarray, np.ndarray] | None
+        The output of the selected method.
+    """
+    if method == "spectral":
+        return spectral(adata, **kwargs)
+    elif method == "pca":
+        return pca(adata, **kwargs)
+    else:
+        raise ValueError("method must be one of 'spectral', 'pca'")
+
+
 class Spectral:
     def __init__(
         self,
@@ -350,6 +497,92 @@ class Spectral:
                 self.evecs = Q
         return (self.evals, self.evecs)
 
+def onnx_embedding(
+    adata: internal.AnnData | internal.AnnDataSet,
+    model: Path,
+    *,
+    mode: Literal["cells", "peaks"] = "cells",
+    genome_fasta: Path | 'snapatac2.genome.Genome' | None = None,
+    window: int = 1344,
+    batch_size: int = 256,
+    output: str | None = None,
+    key_added: str = "X_onnx",
+    inplace: bool = True,
+) -> np.ndarray | None:
+    """
+    Compute embeddings with a pretrained model in the ONNX format.
+
+    This runs a model trained elsewhere, e.g., scBasset or PeakVI exported
+    from PyTorch with :func:`torch.onnx.export`, over the cells or the peaks.
+    The inputs are assembled in batches on the Rust side and passed to
+    ONNX Runtime, so the count matrix is never loaded in memory as a whole.
+    The model must have a single input whose first dimension is the batch,
+    and an output of shape `batch` x `n_dims`.
+
+    This function is only available if SnapATAC2 is built with the "onnx"
+    feature, e.g., `maturin build --release --features onnx`.
+
+    Parameters
+    ----------
+    adata
+        AnnData or AnnDataSet object.
+    model
+        The ONNX model file.
+    mode
+        - 'cells': the input is a batch of rows of `.X`, as a dense float32
+          matrix of shape `batch` x `n_vars`, e.g., the peak counts of PeakVI.
+        - 'peaks': the input is a batch of one-hot encoded sequences of the
+          peaks in `adata.var_names`, as a float32 array of shape
+          `batch` x `window` x 4 with the columns A, C, G and T, e.g., the
+          sequence model of scBasset.
+    genome_fasta
+        A fasta file containing the genome sequences or a Genome object.
+        Required if `mode="peaks"`.
+    window
+        Size of the sequence windows centered on the peaks, in bases.
+        Bases beyond the ends of the chromosomes are encoded as zeros.
+    batch_size
+        Number of cells or peaks per batch.
+    output
+        The name of the output of the model to use. If None, the first output is used.
+    key_added
+        The key under which the embedding is stored.
+    inplace
+        Whether to store the result in the anndata object.
+
+    Returns
+    -------
+    np.ndarray | None
+        If `inplace=True`, it stores the embedding in `adata.obsm[key_added]`
+        for `mode="cells"`, or in `adata.varm[key_added]` for `mode="peaks"`.
+        Otherwise, it returns the embedding.
+    """
+    if not hasattr(internal, "onnx_embed_cells"):
+        raise ImportError(
+            "SnapATAC2 was built without ONNX support. "
+            "Please rebuild it with the 'onnx' feature enabled."
+        )
+    if mode == "cells":
+        result = internal.onnx_embed_cells(adata, model, batch_size, output)
+    elif mode == "peaks":
+        if genome_fasta is None:
+            raise ValueError("genome_fasta must be provided when mode='peaks'")
+        if not isinstance(genome_fasta, (str, Path)):
+            genome_fasta = genome_fasta.fasta
+        result = internal.onnx_embed_regions(
+            genome_fasta, list(adata.var_names), window, model, batch_size, output,
+        )
+    else:
+        raise ValueError("mode must be 'cells' or 'peaks'")
+
+    if inplace:
+        if mode == "cells":
+            adata.obsm[key_added] = result
+        else:
+            adata.varm[key_added] = result
+    else:
+        return result
+
 def orthogonalize(evals, evecs):
     _, sigma, Vt = np.linalg.svd(evecs, full_matrices=False)
     V = Vt.T
@@ -394,13 +627,22 @@ def multi_spectral(
     weights: list[float] | None = None,
     random_state: int = 0,
     weighted_by_sd: bool = True,
-) -> tuple[np.ndarray, np.ndarray]:
+    learn_weights: bool = False,
+    n_neighbors: int = 20,
+) -> tuple[np.ndarray, np.ndarray] | tuple[np.ndarray, np.ndarray, np.ndarray]:
     """
     Compute Laplacian Eigenmaps simultaneously on multiple modalities, with linear
     space and time complexity.
 
     This is similar to :func:`~snapatac2.tl.spectral`, but it can work on multiple modalities.
 
+    When `learn_weights=True`, the relative contribution of each modality is
+    learned for every cell, in the spirit of the weighted nearest neighbor analysis.
+    Each modality is first embedded separately. A modality is then up-weighted in
+    cells where its own nearest neighbors predict the cell better than the
+    neighbors found in the other modalities. The kernel of every modality
+    is reweighted accordingly before computing the joint embedding.
+
     Parameters
     ----------
     adatas
@@ -417,18 +659,22 @@ def multi_spectral(
     weighted_by_sd
         Whether to weight the result eigenvectors by the square root of eigenvalues.
         See :func:`~snapatac2.tl.spectral` for details.
+    learn_weights
+        Whether to learn per-cell modality weights.
+    n_neighbors
+        Number of nearest neighbors used to learn the modality weights.
 
     Returns
     -------
-    tuple[np.ndarray, np.ndarray]
+    tuple[np.ndarray, np.ndarray] | tuple[np.ndarray, np.ndarray, np.ndarray]
         Return the eigenvalues and eigenvectors of the Laplacian matrix.
+        If `learn_weights=True`, also return the learned modality weights,
+        an array of shape `n_obs` x `n_modalities`.
 
     See Also
     --------
     spectral
     """
-    np.random.seed(random_state)
-
     if features is None or isinstance(features, str):
         features = [features] * len(adatas)
     if all(isinstance(f, str) for f in features):
@@ -437,11 +683,16 @@ def multi_spectral(
     if weights is None:
         weights = [1.0 for _ in adatas]
 
-    evals, evecs = internal.multi_spectral_embedding(adatas, features, weights, n_comps, random_state)
+    evals, evecs, cell_weights = internal.multi_spectral_embedding(
+        adatas, features, weights, n_comps, to_seed(random_state),
+        n_neighbors if learn_weights else None,
+    )
 
     if weighted_by_sd:
         idx = [i for i in range(evals.shape[0]) if evals[i] > 0]
         evals = evals[idx]
         evecs = evecs[:, idx] * np.sqrt(evals)
 
+    if learn_weights:
+        return (evals, evecs, cell_weights)
     return (evals, evecs)
@@ -0,0 +1,184 @@
+from __future__ import annotations
+
+from pathlib import Path
+import numpy as np
+
+import snapatac2._snapatac2 as internal
+from snapatac2._snapatac2 import AnnData, AnnDataSet
+from snapatac2._utils import read_anchors
+from snapatac2.genome import Genome
+from snapatac2.tools._diff import _to_indices, _p_adjust_bh
+
+def diff_footprint(
+    data: AnnData | AnnDataSet,
+    motifs: dict[str, Path | list[str]],
+    cell_group1: list[int] | list[str],
+    cell_group2: list[int] | list[str],
+    *,
+    flank: int = 100,
+    center: int = 10,
+    n_permutations: int = 1000,
+    alpha: float = 0.05,
+    bias_correction: bool = False,
+    genome_fasta: Path | Genome | None = None,
+    random_state: int = 0,
+) -> 'polars.DataFrame':
+    """
+    Compare the aggregate footprints of two groups of cells around motif sites.
+
+    The Tn5 insertions of the two groups are counted at every offset, from
+    `-flank` to `flank`, from the sites (anchors) of every motif and
+    aggregated over the sites. Two complementary tests are performed:
+
+    - The depth of the footprint, one minus the ratio between the insertion rate
+      per base within `center` bases of the anchors and that in the flanks, is
+      compared between the groups with a permutation test shuffling the cells
+      between the groups.
+    - The insertions of the two groups at every offset are compared with a
+      two-sided binomial test, given the fraction of the insertions around the
+      anchors coming from the first group. The p-values are adjusted with the
+      Benjamini-Hochberg procedure over the offsets of the motif.
+
+    As Tn5 prefers some sequences, a shallow footprint may reflect the sequence
+    of the sites rather than the binding of the factor. With
+    `bias_correction=True`, the ratio between the insertion rates of the center
+    and of the flanks is divided by the same ratio of the expected insertion
+    bias around the anchors, computed from the genome sequence with the model
+    fitted by :func:`~snapatac2.tl.fit_tn5_bias`. The tests of the offsets
+    compare the groups at the same offset, where the bias is the same, and are
+    not affected.
+
+    :func:`~snapatac2.pp.import_fragments` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    data
+        AnnData or AnnDataSet object.
+    motifs
+        The sites of every motif, e.g., motif matches or ChIP-seq summits, given
+        as a BED file or a list of regions. The anchor is the center of each
+        site, and offsets are reversed for the sites on the minus strand
+        (6th column of the BED file).
+    cell_group1
+        cells belonging to group 1. This can be a list of cell barcodes, indices or
+        boolean mask vector.
+    cell_group2
+        cells belonging to group 2. This can be a list of cell barcodes, indices or
+        boolean mask vector.
+    flank
+        Number of bases on each side of the anchors.
+    center
+        Number of bases on each side of the anchors defining the center of the
+        footprint. It must be smaller than `flank`.
+    n_permutations
+        Number of permutations of the cells used to compute the p-values of
+        the footprint depth.
+    alpha
+        Significance level of the adjusted p-values of the offsets.
+    bias_correction
+        Correct the footprint depths by the expected Tn5 insertion bias.
+    genome_fasta
+        A fasta file containing the genome sequences or a Genome object.
+        Only used when `bias_correction=True`.
+    random_state
+        Seed of the random number generator used for the permutations.
+
+    Returns
+    -------
+    pl.DataFrame
+        A DataFrame with one row per motif and the columns: "motif", "n_sites",
+        "n_insertions_1" and "n_insertions_2", the insertions of the groups
+        around the anchors, "footprint_depth_1" and "footprint_depth_2",
+        "depth_difference", "p-value" and "adjusted p-value" of the depth
+        difference, adjusted over the motifs, and "n_diff_positions", the number
+        of offsets with an adjusted p-value below `alpha` in the binomial test.
+    """
+    import polars as pl
+    from scipy.stats import binom
+
+    if not 0 <= center < flank:
+        raise ValueError("`center` must be non-negative and smaller than `flank`")
+    cell_group1 = _to_indices(data, cell_group1, "obs")
+    cell_group2 = _to_indices(data, cell_group2, "obs")
+    if len(set(cell_group1) & set(cell_group2)) > 0:
+        raise ValueError("the two groups of cells overlap")
+    if bias_correction and genome_fasta is None:
+        raise ValueError("genome_fasta must be provided when bias_correction=True")
+
+    groups = [None] * data.n_obs
+    for i in cell_group1:
+        groups[i] = 0
+    for i in cell_group2:
+        groups[i] = 1
+    names = list(motifs.keys())
+    anchors = [read_anchors(v) for v in motifs.values()]
+    counts = internal.footprint_counts(data, anchors, groups, flank, center)
+
+    cells = np.array(cell_group1 + cell_group2)
+    is_group1 = np.arange(len(cells)) < len(cell_group1)
+    # The counts of the cells are given for the cells of the two groups only.
+    selected = np.flatnonzero([g is not None for g in groups])
+    pos = np.searchsorted(selected, cells)
+    center_counts = np.column_stack([c[pos] for _, c, _ in counts]).astype(np.float64)
+    flank_counts = np.column_stack([f[pos] for _, _, f in counts]).astype(np.float64)
+    center_size, flank_size = 2 * center + 1, 2 * (flank - center)
+    # Ratio between the expected bias of the center and that of the flanks.
+    expected_ratio = np.ones(len(names))
+    if bias_correction:
+        if isinstance(genome_fasta, Genome):
+            genome_fasta = genome_fasta.fasta
+        bias = internal.footprint_expected_bias(data, anchors, genome_fasta, flank)
+        is_center = np.abs(np.arange(-flank, flank + 1)) <= center
+        expected_ratio = bias[:, is_center].mean(axis=1) / bias[:, ~is_center].mean(axis=1)
+
+    def depth(mask):
+        """Footprint depths of the cells in `mask`, for every motif and every row of `mask`."""
+        mask = mask.astype(np.float64)
+        center_rate = mask @ center_counts / center_size
+        flank_rate = mask @ flank_counts / flank_size
+        with np.errstate(divide="ignore", invalid="ignore"):
+            return 1 - center_rate / flank_rate / expected_ratio
+
+    def difference(mask):
+        return depth(mask) - depth(~mask)
+
+    observed = difference(is_group1[np.newaxis, :])[0]
+    rng = np.random.default_rng(random_state)
+    n_extreme = np.zeros(len(names))
+    batch_size = 100
+    for start in range(0, n_permutations, batch_size):
+        n = min(batch_size, n_permutations - start)
+        masks = np.array([rng.permutation(is_group1) for _ in range(n)])
+        null = difference(masks)
+        n_extreme += (np.abs(null) >= np.abs(observed)).sum(axis=0)
+    pvalues = np.where(np.isnan(observed), 1.0, (n_extreme + 1) / (n_permutations + 1))
+
+    n_diff_positions = []
+    for profiles, _, _ in counts:
+        k, n = profiles[0].astype(np.int64), profiles.sum(axis=0).astype(np.int64)
+        total = profiles.sum()
+        if total == 0:
+            n_diff_positions.append(0)
+            continue
+        p = profiles[0].sum() / total
+        position_pvalues = np.where(
+            n > 0,
+            np.minimum(1.0, 2 * np.minimum(binom.cdf(k, n, p), binom.sf(k - 1, n, p))),
+            1.0,
+        )
+        n_diff_positions.append(int((_p_adjust_bh(position_pvalues) < alpha).sum()))
+
+    depth1 = depth(is_group1[np.newaxis, :])[0]
+    depth2 = depth(~is_group1[np.newaxis, :])[0]
+    return pl.DataFrame({
+        "motif": names,
+        "n_sites": [len(x) for x in anchors],
+        "n_insertions_1": [int(x[0].sum()) for x, _, _ in counts],
+        "n_insertions_2": [int(x[1].sum()) for x, _, _ in counts],
+        "footprint_depth_1": depth1,
+        "footprint_depth_2": depth2,
+        "depth_difference": observed,
+        "p-value": pvalues,
+        "adjusted p-value": _p_adjust_bh(pvalues),
+        "n_diff_positions": n_diff_positions,
+    })
@@ -1,5 +1,6 @@
 from __future__ import annotations
 
+from typing import Literal
 import numpy as np
 import logging
 
@@ -11,6 +12,7 @@ def transfer_labels(
     labels: str | list[str],
     n_neighbors: int = 15,
     metric: str = "cosine",
+    use_index: bool = False,
     inplace: bool = True,
 ):
     """
@@ -23,6 +25,14 @@ def transfer_labels(
     use_rep
     labels
         Cell labels. Labels with `None` values will be predicted.
+    use_index
+        Take the nearest neighbors from the HNSW index stored by
+        :func:`~snapatac2.pp.knn` with `method='hora'` and `store_index=True`
+        on `.obsm[use_rep]`, instead of fitting a classifier. Every unlabeled
+        cell gets the most common label among the labeled cells of its
+        `n_neighbors` nearest neighbors, which are Euclidean and ignore
+        `metric`. Cells without labeled neighbors, or all cells if no index of
+        the current data is stored, are classified as usual.
     """
     from sklearn.neighbors import KNeighborsClassifier
     
@@ -33,14 +43,192 @@ def transfer_labels(
     
     embedding = adata.obsm[use_rep] if isinstance(use_rep, str) else use_rep
 
-    model = KNeighborsClassifier(n_neighbors=n_neighbors, metric=metric)
-    X = embedding[labs != None, :]
-    y = labs[labs != None]
-    model.fit(X, y)
-    
-    labs[np.where(labs == None)] = model.predict(embedding[labs == None, :])
+    unlabeled = labs == None
+    if use_index and isinstance(use_rep, str):
+        from snapatac2.preprocessing._knn import _index_neighbors
+        neighbors = _index_neighbors(adata, use_rep, None, n_neighbors)
+        if neighbors is None:
+            logging.warning("No HNSW index of the current data is stored, fitting a classifier.")
+        else:
+            for i in np.flatnonzero(unlabeled):
+                votes = [labs[j] for j in neighbors[0][i] if not unlabeled[j]]
+                if len(votes) > 0:
+                    values, counts = np.unique(np.array(votes, dtype=object), return_counts=True)
+                    labs[i] = values[np.argmax(counts)]
+
+    remaining = labs == None
+    if remaining.any():
+        model = KNeighborsClassifier(n_neighbors=n_neighbors, metric=metric)
+        X = embedding[~unlabeled, :]
+        y = labs[~unlabeled]
+        model.fit(X, y)
+        labs[np.where(remaining)] = model.predict(embedding[remaining, :])
     
     if inplace and isinstance(labels, str):
         adata.obs[labels] = labs
     else:
-        return labs
\ No newline at end of file
+        return labs
+
+def annotate_cells(
+    adata: AnnData | AnnDataSet,
+    reference: 'anndata.AnnData | pandas.DataFrame',
+    *,
+    reference_groupby: str | list[str] | None = None,
+    groupby: str | list[str] | None = None,
+    method: Literal["correlation", "logistic"] = "correlation",
+    temperature: float = 0.05,
+    C: float = 1.0,
+    key_added: str = "predicted_celltype",
+    inplace: bool = True,
+) -> tuple[np.ndarray, 'polars.DataFrame'] | None:
+    """
+    Annotate cells against reference cell-type signatures.
+
+    The reference consists of cell-type signatures over peaks or genes, e.g.,
+    published cell-type specific accessibility profiles, or of annotated
+    cells. The reference and the query are restricted to their shared
+    features (matched by name) and normalized to log(CPM + 1). Cells, or
+    clusters if `groupby` is given, are then classified by one of the
+    following methods:
+
+    - 'correlation': the Pearson correlation with the centroid of every cell
+      type is computed, and converted into probabilities by a softmax with
+      the given `temperature`.
+    - 'logistic': a L2-regularized multinomial logistic regression is trained
+      on the reference profiles.
+
+    Parameters
+    ----------
+    adata
+        The annotated data matrix, e.g., a cell by peak or a cell by gene
+        activity matrix.
+    reference
+        The reference, as an AnnData object or a DataFrame whose rows are
+        profiles and whose columns (`.var_names` for AnnData) are features.
+    reference_groupby
+        The cell type of every row of `reference`. If a `str`, labels are
+        obtained from `reference.obs[reference_groupby]`. If `None`, every row
+        is the signature of a cell type named after the row.
+    groupby
+        If given, classify the pseudobulk profile of every group of cells
+        instead of individual cells. If a `str`, groups are obtained from
+        `.obs[groupby]`.
+    method
+        The classification method: "correlation" or "logistic".
+    temperature
+        Temperature of the softmax used with the "correlation" method.
+        Lower values give sharper probabilities.
+    C
+        Inverse of the regularization strength of the "logistic" method.
+    key_added
+        `adata.obs` key under which to add the predicted labels.
+    inplace
+        Whether to store the result in the anndata object.
+
+    Returns
+    -------
+    tuple[np.ndarray, polars.DataFrame] | None
+        If `inplace=True`, update `adata.obs[key_added]` with the predicted
+        labels, `adata.obs[key_added + "_probability"]` with the probability
+        of the predicted label, and `adata.obsm[key_added + "_probabilities"]`
+        with the probabilities of all cell types, whose names are stored in
+        `adata.uns[key_added + "_classes"]`. Otherwise, return the predicted
+        labels and a DataFrame of probabilities with one column per cell type.
+    """
+    import polars as pl
+    import scipy.sparse as sp
+
+    if hasattr(reference, "columns"):
+        ref_features = [str(x) for x in reference.columns]
+        ref_mat = reference.to_numpy(dtype=np.float64)
+        names = [str(x) for x in reference.index]
+    else:
+        ref_features = list(reference.var_names)
+        ref_mat = reference.X[:]
+        names = [str(x) for x in reference.obs_names]
+    if reference_groupby is None:
+        ref_labels = np.array(names)
+    elif isinstance(reference_groupby, str):
+        ref_labels = np.array([str(x) for x in reference.obs[reference_groupby]])
+    else:
+        ref_labels = np.array([str(x) for x in reference_groupby])
+
+    features = dict((x, i) for i, x in enumerate(adata.var_names))
+    shared = [(i, features[x]) for i, x in enumerate(ref_features) if x in features]
+    if len(shared) == 0:
+        raise ValueError("the data and the reference have no features in common")
+    logging.info(f"Using {len(shared)} features shared with the reference.")
+    ref_idx, idx = (list(x) for x in zip(*shared))
+    ref_mat = _log_cpm(sp.csr_matrix(ref_mat)[:, ref_idx]).toarray()
+
+    if method == "correlation":
+        classes = np.unique(ref_labels)
+        centroids = np.vstack([ref_mat[ref_labels == c, :].mean(axis=0) for c in classes])
+
+        def predict(X):
+            scores = _pearson(X, centroids) / temperature
+            scores = np.exp(scores - scores.max(axis=1, keepdims=True))
+            return scores / scores.sum(axis=1, keepdims=True)
+    elif method == "logistic":
+        from sklearn.linear_model import LogisticRegression
+        model = LogisticRegression(C=C, max_iter=1000).fit(ref_mat, ref_labels)
+        classes = model.classes_
+        predict = model.predict_proba
+    else:
+        raise ValueError("method must be 'correlation' or 'logistic'")
+
+    # The data matrix is read in chunks of cells, which are classified one
+    # chunk at a time, or summed into the pseudobulk profiles of the groups.
+    if groupby is not None:
+        if isinstance(groupby, str):
+            groupby = adata.obs[groupby]
+        groups = np.array([str(x) for x in groupby])
+        group_names, group_index = np.unique(groups, return_inverse=True)
+        indicator = sp.csr_matrix(
+            (np.ones(len(groups)), (group_index, np.arange(len(groups)))),
+            shape=(len(group_names), len(groups)),
+        )
+        X = sp.csr_matrix((len(group_names), len(idx)))
+        for batch, start, end in adata.chunked_X(2000):
+            X += indicator[:, start:end] @ sp.csr_matrix(batch)[:, idx]
+        probs = predict(_log_cpm(X))
+    else:
+        probs = np.vstack([
+            predict(_log_cpm(sp.csr_matrix(batch)[:, idx]))
+            for batch, _, _ in adata.chunked_X(2000)
+        ])
+
+    if groupby is not None:
+        probs = probs[group_index, :]
+    classes = np.array([str(x) for x in classes])
+    labels = classes[np.argmax(probs, axis=1)]
+    if inplace:
+        adata.obs[key_added] = labels
+        adata.obs[key_added + "_probability"] = probs.max(axis=1)
+        adata.obsm[key_added + "_probabilities"] = probs
+        adata.uns[key_added + "_classes"] = classes
+    else:
+        return labels, pl.DataFrame(probs, schema=list(classes))
+
+def _log_cpm(mat):
+    """log(CPM + 1) transformation of the rows of a sparse matrix."""
+    import scipy.sparse as sp
+
+    mat = sp.csr_matrix(mat, dtype=np.float64)
+    total = np.ravel(mat.sum(axis=1))
+    scale = np.divide(1e6, total, out=np.zeros_like(total), where=total > 0)
+    mat = sp.csr_matrix(sp.diags(scale) @ mat)
+    mat.data = np.log1p(mat.data)
+    return mat
+
+def _pearson(X, centroids: np.ndarray) -> np.ndarray:
+    """Pearson correlation between the rows of a sparse matrix and the rows of a dense matrix."""
+    n = X.shape[1]
+    centered = centroids - centroids.mean(axis=1, keepdims=True)
+    numerator = np.asarray(X @ centered.T)
+    mean = np.ravel(X.sum(axis=1)) / n
+    sq = np.ravel(X.multiply(X).sum(axis=1)) - n * mean ** 2
+    x_norm = np.sqrt(np.maximum(sq, 0))
+    c_norm = np.sqrt((centered ** 2).sum(axis=1))
+    denom = np.outer(x_norm, c_norm)
+    return np.divide(numerator, denom, out=np.zeros_like(numerator), where=denom > 0)
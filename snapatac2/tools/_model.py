@@ -0,0 +1,133 @@
+from __future__ import annotations
+
+import json
+from pathlib import Path
+import numpy as np
+
+from snapatac2._snapatac2 import AnnData, AnnDataSet
+import snapatac2._snapatac2 as internal
+
+def save_model(
+    adata: AnnData | AnnDataSet,
+    filename: Path,
+    use_rep: str = "X_pca",
+    groupby: str | None = None,
+    n_neighbors: int = 15,
+    batch_correction: dict[str, np.ndarray] | None = None,
+    batch_method: str = "harmony",
+) -> None:
+    """
+    Save the fitted embedding of a reference dataset so that query datasets
+    can be mapped onto it later with :func:`~snapatac2.tl.map_to_reference`.
+
+    The model is stored as a versioned JSON document. It is compressed if
+    `filename` ends with ".gz" or ".zst".
+
+    Parameters
+    ----------
+    adata
+        The reference AnnData or AnnDataSet object, on which
+        :func:`~snapatac2.tl.pca` has been run.
+    filename
+        Output file name.
+    use_rep
+        The embedding of the reference cells, used to build the kNN index
+        and the cluster centroids.
+    groupby
+        Key in `adata.obs` containing the cell labels. If provided, query
+        cells are labeled by a majority vote among their nearest reference
+        cells. The centroids of the clusters are saved as well.
+    n_neighbors
+        Number of neighbors used when mapping query cells.
+    batch_correction
+        Parameters of the batch correction method as a dictionary of 2D arrays,
+        applied to the embedding of the query cells. For Harmony, "centroids"
+        holds the cluster centroids and "offsets" the batch effect of every
+        cluster, both of shape `n_clusters` x `n_components`. The optional
+        "sigma" (1 x 1) sets the softness of the cluster assignments.
+    batch_method
+        Name of the batch correction method. Only "harmony" is supported.
+    """
+    if 'pca_components' not in adata.uns:
+        raise KeyError("Please call `pca` first")
+    feature_weights = adata.uns.get('pca_feature_weights', None)
+    labels = None
+    if groupby is not None:
+        labels = [str(x) for x in adata.obs[groupby]]
+    internal.save_reference_model(
+        str(filename),
+        [str(x) for x in adata.uns['pca_features']],
+        "pca",
+        np.asarray(adata.uns['pca_components'], dtype=np.float64),
+        list(adata.uns['pca_variance']),
+        mean=np.asarray(adata.uns['pca_mean'], dtype=np.float64),
+        feature_weights=None if feature_weights is None else np.asarray(feature_weights, dtype=np.float64),
+        reference=np.asarray(adata.obsm[use_rep], dtype=np.float64),
+        labels=labels,
+        n_neighbors=n_neighbors,
+        batch_method=None if batch_correction is None else batch_method,
+        batch_parameters=None if batch_correction is None else {
+            k: np.asarray(v, dtype=np.float64) for k, v in batch_correction.items()
+        },
+    )
+
+def load_model(filename: Path) -> dict:
+    """
+    Read a model saved by :func:`~snapatac2.tl.save_model`.
+
+    Models written by older versions of the schema are upgraded when loaded.
+
+    Parameters
+    ----------
+    filename
+        File name of the model.
+
+    Returns
+    -------
+    dict
+        The model. Matrices are stored as dictionaries with keys
+        "nrows", "ncols" and "data" (row-major).
+    """
+    return json.loads(internal.load_reference_model(str(filename)))
+
+def map_to_reference(
+    adata: AnnData | AnnDataSet,
+    filename: Path,
+    key_added: str = "reference",
+    chunk_size: int = 5000,
+    inplace: bool = True,
+) -> tuple[np.ndarray, list[str] | None] | None:
+    """
+    Project query cells onto a reference model.
+
+    Features are matched by name. Features of the reference that are absent
+    from the query are treated as zeros. If the model has batch correction
+    parameters, the batch effects are removed from the embedding.
+
+    Parameters
+    ----------
+    adata
+        The query AnnData or AnnDataSet object.
+    filename
+        File name of the model saved by :func:`~snapatac2.tl.save_model`.
+    key_added
+        If `inplace=True`, the embedding is stored in `adata.obsm["X_" + key_added]`
+        and the predicted labels in `adata.obs[key_added + "_label"]`.
+    chunk_size
+        Number of cells read at a time.
+    inplace
+        Whether to store the result in the anndata object.
+
+    Returns
+    -------
+    tuple[np.ndarray, list[str] | None] | None
+        The embedding of the query cells and their labels (if the model
+        contains labeled reference cells).
+    """
+    embedding, labels = internal.project_to_reference(adata, str(filename), chunk_size)
+    if inplace:
+        adata.obsm["X_" + key_added] = embedding
+        if labels is not None:
+            adata.obs[key_added + "_label"] = labels
+    else:
+        return embedding, labels
@@ -6,7 +6,7 @@ from pathlib import Path
 import logging
 
 from snapatac2._snapatac2 import PyDNAMotif
-from snapatac2._utils import fetch_seq
+from snapatac2._utils import fetch_seq, to_seed
 from snapatac2.genome import Genome
 from snapatac2.tools._diff import _p_adjust_bh
 
@@ -135,4 +135,228 @@ def motif_enrichment(
     for key in result.keys():
         result[key]['adjusted p-value'] = _p_adjust_bh(result[key]['p-value'])
         result[key] = pl.DataFrame(result[key])
-    return result
\ No newline at end of file
+    return result
+
+def fit_tn5_bias(
+    adata: 'internal.AnnData' | 'internal.AnnDataSet',
+    genome_fasta: Path | Genome,
+    k: int = 6,
+    max_insertions: int = 10_000_000,
+    random_state: int = 0,
+) -> dict:
+    """
+    Learn the sequence bias of Tn5 insertions.
+
+    The bias of every k-mer centred on an insertion site is estimated as the
+    ratio between its frequency at the observed insertion sites and its
+    frequency in the genome. The k-mer is read on the strand of the read at
+    the insertion, i.e., the reverse complement is used for the insertions
+    at the end of the reads on the minus strand. The model is stored in
+    `.uns['tn5_bias']` and is used by :func:`~snapatac2.ex.export_coverage`
+    (with `bias_correction=True`), :func:`~snapatac2.tl.diff_footprint` (with
+    `bias_correction=True`) and :func:`~snapatac2.ex.export_expected_bias`.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    genome_fasta
+        A fasta file containing the genome sequences or a Genome object.
+        Chromosome names must match those of `adata`.
+    k
+        Length of the k-mers, at most 12. There are `4**k` k-mers, so the
+        counts are returned as a sparse matrix.
+    max_insertions
+        Maximum number of insertion sites, sampled at random, used to fit the model.
+    random_state
+        Seed of the random number generator.
+
+    Returns
+    -------
+    dict
+        The model, with keys "k", "observed", "background" and "bias". The
+        k-mers are indexed by their 2-bit encoding (A=0, C=1, G=2, T=3).
+    """
+    import json
+    import snapatac2._snapatac2 as internal
+
+    if isinstance(genome_fasta, Genome):
+        genome_fasta = genome_fasta.fasta
+    return json.loads(internal.fit_tn5_bias(adata, genome_fasta, k, max_insertions, to_seed(random_state)))
+
+def kmer_counts(
+    adata: 'internal.AnnData' | 'internal.AnnDataSet',
+    genome_fasta: Path | Genome,
+    k: int = 4,
+    collapse_strand: bool = True,
+    key_added: str = "kmer",
+    inplace: bool = True,
+) -> tuple['scipy.sparse.csr_matrix', list[str]] | None:
+    """
+    Count the k-mers of every feature.
+
+    The k-mer composition of the peaks or bins in `adata.var_names` is useful
+    as a covariate of sequence-aware models and for correcting GC content
+    biases. K-mers containing ambiguous bases, e.g., N, are not counted.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+    genome_fasta
+        A fasta file containing the genome sequences or a Genome object.
+        Chromosome names must match those of `adata`.
+    k
+        Length of the k-mers, at most 12. There are `4**k` k-mers, so the
+        counts are returned as a sparse matrix.
+    collapse_strand
+        Whether to count a k-mer and its reverse complement together, under
+        the name of the lexicographically smaller one.
+    key_added
+        The key under which the counts are stored.
+    inplace
+        Whether to store the result in the anndata object.
+
+    Returns
+    -------
+    tuple[scipy.sparse.csr_matrix, list[str]] | None
+        If `inplace=True`, it stores the counts, a sparse matrix of shape
+        `n_vars` x `n_kmers`, in `adata.varm[key_added]` and the k-mers in
+        `adata.uns[key_added + "_names"]`. Otherwise, it returns the counts
+        and the k-mers.
+    """
+    import snapatac2._snapatac2 as internal
+
+    if isinstance(genome_fasta, Genome):
+        genome_fasta = genome_fasta.fasta
+    counts, names = internal.kmer_counts(
+        genome_fasta, list(adata.var_names), k, collapse_strand,
+    )
+    if inplace:
+        adata.varm[key_added] = counts
+        adata.uns[key_added + "_names"] = np.array(names)
+    else:
+        return counts, names
+
+def rank_tf_drivers(
+    motif_adata: 'internal.AnnData' | 'internal.AnnDataSet',
+    gene_adata: 'internal.AnnData' | 'internal.AnnDataSet',
+    groupby: str | list[str],
+    motif_to_gene: dict[str, str] | None = None,
+    expression_weight: float = 1.0,
+    min_fraction_expressed: float = 0.0,
+    normalize_genes: bool = True,
+) -> 'polars.DataFrame':
+    """
+    Rank the transcription factors driving every cluster.
+
+    A TF is a likely driver of a cluster when the accessibility of its binding
+    sites is increased in the cluster and the TF itself is expressed. For every
+    cluster, this combines the mean motif deviation z-score, e.g., from
+    chromVAR, with the mean expression (or gene activity) of the TF. Both are
+    standardized across clusters, and TFs are ranked by
+    `deviation_zscore + expression_weight * expression_zscore`.
+
+    Parameters
+    ----------
+    motif_adata
+        A cell by motif matrix of deviation z-scores.
+    gene_adata
+        A cell by gene matrix of expression or gene activity of the same cells,
+        in the same order.
+    groupby
+        Group the cells into clusters. If a `str`, groups are obtained from
+        `motif_adata.obs[groupby]`.
+    motif_to_gene
+        A mapping from motif names to TF gene names. If `None`, a motif is
+        mapped to the first part of its name, split on "_", "." and ":",
+        that matches a gene name (ignoring case), e.g., "MA0139.1_CTCF" is
+        mapped to "CTCF". Unmapped motifs are ignored.
+    expression_weight
+        The weight of the expression in the combined score.
+    min_fraction_expressed
+        TFs expressed in fewer than this fraction of the cells of a cluster
+        are excluded from the ranking of that cluster.
+    normalize_genes
+        Whether to normalize `gene_adata` to log(CPM + 1) before averaging.
+        Set to `False` if it is already normalized.
+
+    Returns
+    -------
+    polars.DataFrame
+        A table with one row per cluster and TF, with the columns "group",
+        "tf", "motif", "deviation", "expression", "fraction_expressed",
+        "deviation_zscore", "expression_zscore", "score" and "rank", sorted
+        by group and rank.
+    """
+    import polars as pl
+    import scipy.sparse as sp
+    from snapatac2.tools._integration import _log_cpm
+
+    if motif_adata.n_obs != gene_adata.n_obs:
+        raise ValueError("the motif and gene matrices must have the same cells")
+    if isinstance(groupby, str):
+        groupby = motif_adata.obs[groupby]
+    groups = np.array([str(x) for x in groupby])
+    group_names, group_index = np.unique(groups, return_inverse=True)
+    indicator = sp.csr_matrix(
+        (np.ones(len(groups)), (group_index, np.arange(len(groups)))),
+        shape=(len(group_names), len(groups)),
+    )
+    sizes = np.ravel(indicator.sum(axis=1))[:, None]
+
+    genes = dict((x.upper(), i) for i, x in enumerate(gene_adata.var_names))
+    pairs = []
+    for j, motif in enumerate(motif_adata.var_names):
+        if motif_to_gene is not None:
+            gene = motif_to_gene.get(motif)
+            gene = None if gene is None else genes.get(gene.upper())
+        else:
+            tokens = motif.replace(".", "_").replace(":", "_").split("_")
+            gene = next((genes[x.upper()] for x in tokens if x.upper() in genes), None)
+        if gene is not None:
+            pairs.append((j, gene))
+    if len(pairs) == 0:
+        raise ValueError("no motif can be mapped to a gene")
+    logging.info(f"{len(pairs)} motifs are mapped to genes.")
+    motif_idx, gene_idx = (list(x) for x in zip(*pairs))
+
+    deviation = motif_adata.X[:]
+    deviation = deviation.toarray() if sp.issparse(deviation) else np.asarray(deviation)
+    deviation = np.asarray(indicator @ deviation[:, motif_idx]) / sizes
+    expr = sp.csr_matrix(gene_adata.X[:], dtype=np.float64)[:, gene_idx]
+    if normalize_genes:
+        expr = _log_cpm(expr)
+    fraction = np.asarray((indicator @ (expr > 0)).todense()) / sizes
+    expr = np.asarray((indicator @ expr).todense()) / sizes
+
+    def zscore(x):
+        sd = x.std(axis=0)
+        return np.divide(x - x.mean(axis=0), sd, out=np.zeros_like(x), where=sd > 0)
+
+    dev_z = zscore(deviation)
+    expr_z = zscore(expr)
+    score = dev_z + expression_weight * expr_z
+
+    motif_names = np.array(motif_adata.var_names)[motif_idx]
+    gene_names = np.array(gene_adata.var_names)[gene_idx]
+    n_groups, n_tfs = score.shape
+    df = pl.DataFrame({
+        "group": np.repeat(group_names, n_tfs),
+        "tf": np.tile(gene_names, n_groups),
+        "motif": np.tile(motif_names, n_groups),
+        "deviation": deviation.ravel(),
+        "expression": expr.ravel(),
+        "fraction_expressed": fraction.ravel(),
+        "deviation_zscore": dev_z.ravel(),
+        "expression_zscore": expr_z.ravel(),
+        "score": score.ravel(),
+    })
+    df = df.filter(pl.col("fraction_expressed") >= min_fraction_expressed)
+    df = df.with_columns(
+        pl.col("score").rank(method="ordinal", descending=True).over("group")
+            .cast(pl.UInt32).alias("rank")
+    )
+    return df.sort(["group", "rank"])
@@ -0,0 +1,89 @@
+from __future__ import annotations
+
+from pathlib import Path
+
+import snapatac2._snapatac2 as internal
+from snapatac2._snapatac2 import AnnData, AnnDataSet
+
+def nucleosome_positions(
+    adata: AnnData | AnnDataSet,
+    regions: Path | list[str],
+    *,
+    groupby: str | list[str] | None = None,
+    out_dir: Path = "./",
+    prefix: str = "",
+    nfr_max: int = 120,
+    nuc_min: int = 147,
+    nuc_max: int = 250,
+    smooth_sd: float = 25,
+    min_spacing: int = 120,
+    min_signal: float = 0.1,
+) -> dict[str, tuple[Path, Path]]:
+    """Call nucleosome positions from paired-end fragments.
+
+    In the V-plot of a region, i.e., its fragments plotted by midpoint and
+    length, fragments spanning a nucleosome are centred on its dyad while
+    short fragments come from the linker DNA and nucleosome-free regions.
+    Following NucleoATAC, the fragments are split by length into nucleosomal
+    (`nuc_min` to `nuc_max` bases) and nucleosome-free (up to `nfr_max` bases)
+    fragments, and the density of their midpoints is smoothed with a Gaussian
+    kernel. The nucleosome occupancy at each position is the fraction of the
+    density contributed by nucleosomal fragments, and the dyads are the local
+    maxima of the nucleosomal density, at least `min_spacing` bases apart.
+
+    Note
+    ----
+    The occupancy is computed at base-pair resolution, so `regions` should
+    be a selection of regions of interest, e.g., promoters or peaks, rather
+    than the whole genome.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions. The fragments
+        must be paired-end.
+    regions
+        A BED file or a list of regions, e.g., `["chr1:1-10000"]`.
+    groupby
+        Group the cells before calling nucleosomes. If a `str`, groups are obtained
+        from `.obs[groupby]`. If None, all cells are used.
+    out_dir
+        Directory for saving the output files.
+    prefix
+        Text added to the output file names.
+    nfr_max
+        Maximum length of nucleosome-free fragments.
+    nuc_min
+        Minimum length of nucleosomal fragments.
+    nuc_max
+        Maximum length of nucleosomal fragments.
+    smooth_sd
+        Standard deviation, in bases, of the Gaussian smoothing kernel.
+    min_spacing
+        Minimum distance between two dyads.
+    min_signal
+        Minimum smoothed density of nucleosomal fragment midpoints at a dyad.
+
+    Returns
+    -------
+    dict[str, tuple[Path, Path]]
+        For each group, the occupancy track in bedGraph format
+        (`{prefix}{group}.occupancy.bedGraph`), and the dyads in BED format
+        (`{prefix}{group}.dyads.bed`) with the smoothed nucleosomal signal and
+        the occupancy at the dyad in the 4th and 5th columns. In the file names,
+        characters of the group names other than letters, digits, ".", "-"
+        and "_" are replaced by "_".
+    """
+    if groupby is None:
+        groupby = ["all"] * adata.n_obs
+    elif isinstance(groupby, str):
+        groupby = adata.obs[groupby]
+    groupby = [str(x) for x in groupby]
+    if not isinstance(regions, list):
+        regions = str(regions)
+
+    return internal.nucleosome_positions(
+        adata, regions, groupby, str(out_dir), prefix, nfr_max, nuc_min, nuc_max,
+        smooth_sd, min_spacing, min_signal,
+    )
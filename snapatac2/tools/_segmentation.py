@@ -0,0 +1,83 @@
+from __future__ import annotations
+
+from pathlib import Path
+
+import snapatac2._snapatac2 as internal
+from snapatac2._utils import to_seed
+from snapatac2.genome import Genome
+
+def chromatin_states(
+    tracks: dict[str, Path],
+    chrom_sizes: dict[str, int] | Genome,
+    out_file: Path,
+    *,
+    n_states: int = 8,
+    bin_size: int = 200,
+    pvalue: float = 1e-4,
+    max_iter: int = 200,
+    tol: float = 1e-3,
+    random_state: int = 0,
+) -> dict:
+    """Segment the genome into chromatin states.
+
+    This is a lightweight version of ChromHMM. The signal of every track is
+    averaged in bins of `bin_size` bases and binarized by a Poisson test
+    against the genome-wide average of the track. A hidden Markov model whose
+    states emit every track independently is learned with the Baum-Welch
+    algorithm, and every bin is assigned to its most likely state.
+
+    Typical tracks are the pseudobulk coverage of cell types or conditions,
+    as exported by :func:`~snapatac2.ex.export_coverage` in bedGraph format
+    with `normalization=None`, but any bedGraph track, e.g., of histone
+    modifications, can be used.
+
+    Parameters
+    ----------
+    tracks
+        A dictionary mapping track names to bedGraph files (optionally compressed).
+    chrom_sizes
+        Chromosome sizes. If a :class:`~snapatac2.genome.Genome` is provided,
+        chromosome sizes will be obtained from the genome. Chromosomes not
+        listed here are ignored.
+    out_file
+        The segmentation is written to this file in BED format, the fourth
+        column being the state ("E1", "E2", ...).
+    n_states
+        Number of states.
+    bin_size
+        Size of the bins, in bases.
+    pvalue
+        P-value threshold of the binarization.
+    max_iter
+        Maximum number of iterations of the Baum-Welch algorithm.
+    tol
+        The training stops once the log-likelihood improves by less than `tol`.
+    random_state
+        Seed of the random number generator used to initialize the model.
+
+    Returns
+    -------
+    dict
+        The model: `emission`, a `pandas.DataFrame` of shape `n_states` x `n_tracks`
+        giving the probability that each state marks each track; `transition`, a
+        `pandas.DataFrame` of the probabilities of transition between states;
+        `initial`, the initial state probabilities; and `log_likelihood`, the
+        log-likelihood of the data after each iteration.
+    """
+    import json
+    import numpy as np
+    import pandas as pd
+
+    chrom_sizes = chrom_sizes.chrom_sizes if isinstance(chrom_sizes, Genome) else chrom_sizes
+    names = list(tracks.keys())
+    model = json.loads(internal.segment_genome(
+        [str(tracks[k]) for k in names], list(chrom_sizes.items()), str(out_file),
+        n_states, bin_size, pvalue, max_iter, tol, to_seed(random_state),
+    ))
+    states = [f"E{i+1}" for i in range(n_states)]
+    return {
+        'emission': pd.DataFrame(model['emission'], index=states, columns=names),
+        'transition': pd.DataFrame(model['transition'], index=states, columns=states),
+        'initial': np.array(model['initial']),
+        'log_likelihood': np.array(model['log_likelihood']),
+    }
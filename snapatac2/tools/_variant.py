@@ -0,0 +1,130 @@
+from __future__ import annotations
+
+from pathlib import Path
+import numpy as np
+import rustworkx as rx
+
+import snapatac2._snapatac2 as internal
+from snapatac2.tools._diff import _p_adjust_bh
+
+__all__ = ['variant_overlap', 'variant_enrichment']
+
+def variant_overlap(
+    regions: list[str] | rx.PyDiGraph,
+    variants: Path,
+) -> 'polars.DataFrame':
+    """
+    Find the variants overlapping regions or regulatory links.
+
+    Parameters
+    ----------
+    regions
+        A list of regions, e.g., peaks or differentially accessible regions,
+        or a regulatory network as produced by
+        :func:`~snapatac2.tl.init_network_from_annotation`, whose links from
+        regions to genes are used.
+    variants
+        A VCF file, or a BED file whose 4th column, if present, is the
+        identifier of the variant, e.g., GWAS SNPs or eQTLs. VCF variants
+        without an identifier are named "chrom:pos:ref:alt".
+
+    Returns
+    -------
+    polars.DataFrame
+        A table with the columns "region" and "variant", and "gene" if
+        `regions` is a network, with one row per overlap.
+    """
+    import polars as pl
+
+    genes = None
+    if isinstance(regions, rx.PyDiGraph):
+        links = [
+            (regions[s].id, regions[t].id) for s, t in regions.edge_list()
+            if regions[s].type == "region" and regions[t].type == "gene"
+        ]
+        regions = [r for r, _ in links]
+        genes = [g for _, g in links]
+    overlaps = internal.variant_overlaps(list(regions), variants)
+    result = {
+        "region": [regions[i] for i, _ in overlaps],
+        "variant": [v for _, v in overlaps],
+    }
+    if genes is not None:
+        result["gene"] = [genes[i] for i, _ in overlaps]
+    return pl.DataFrame(result, schema={k: pl.String for k in result})
+
+def variant_enrichment(
+    regions: dict[str, list[str]],
+    variants: Path,
+    background: list[str] | None = None,
+    n_permutations: int = 1000,
+    random_state: int = 0,
+) -> 'polars.DataFrame':
+    """
+    Test the enrichment of variants in groups of regions by permutation.
+
+    For every group, e.g., the marker peaks of a cluster found by
+    :func:`~snapatac2.tl.marker_regions`, the number of variants overlapping
+    its regions is compared to that of random sets of regions of the same size
+    drawn from the background. This does not account for linkage
+    disequilibrium, so it is best used with variants pruned for LD or as a
+    quick screen before stratified LD score regression
+    (see :func:`~snapatac2.ex.export_ldsc_annot`).
+
+    Parameters
+    ----------
+    regions
+        Groups of regions.
+    variants
+        A VCF or BED file of variants, e.g., GWAS SNPs.
+    background
+        A list of regions to draw the random sets from, e.g., all peaks.
+        If None, the union of the regions of all groups is used.
+        The regions of every group must be in the background.
+    n_permutations
+        Number of random sets per group.
+    random_state
+        Seed of the random number generator.
+
+    Returns
+    -------
+    polars.DataFrame
+        A table with the columns "group", "n_regions", "n_variants" (the
+        number of overlapping variants), "expected" (the average over the
+        random sets), "fold_enrichment", "p-value" and "adjusted p-value".
+        The p-value is the fraction of random sets with at least as many
+        variants, with a pseudocount of one.
+    """
+    import polars as pl
+
+    if background is None:
+        background = sorted(set(r for rs in regions.values() for r in rs))
+    index = {r: i for i, r in enumerate(background)}
+    missing = [r for rs in regions.values() for r in rs if r not in index]
+    if len(missing) > 0:
+        raise ValueError(f"{len(missing)} regions, e.g., {missing[0]}, are not in the background")
+    counts = np.zeros(len(background), dtype=np.int64)
+    for i, _ in internal.variant_overlaps(background, variants):
+        counts[i] += 1
+
+    rng = np.random.default_rng(random_state)
+    result = {
+        "group": [], "n_regions": [], "n_variants": [], "expected": [],
+        "fold_enrichment": [], "p-value": [],
+    }
+    for group, rs in regions.items():
+        n = len(rs)
+        observed = int(counts[[index[r] for r in rs]].sum())
+        random = np.array([
+            counts[rng.choice(len(background), n, replace=False)].sum()
+            for _ in range(n_permutations)
+        ])
+        expected = float(random.mean()) if n_permutations > 0 else np.nan
+        result["group"].append(str(group))
+        result["n_regions"].append(n)
+        result["n_variants"].append(observed)
+        result["expected"].append(expected)
+        result["fold_enrichment"].append(observed / expected if expected > 0 else np.nan)
+        result["p-value"].append((1 + np.sum(random >= observed)) / (1 + n_permutations))
+    result["adjusted p-value"] = _p_adjust_bh(result["p-value"])
+    return pl.DataFrame(result)
@@ -1,4 +1,4 @@
-use crate::utils::AnnDataLike;
+use crate::utils::{read_region_map, AnnDataLike};
 
 use anyhow::{bail, Context};
 use bed_utils::bed::{BroadPeak, NarrowPeak, Strand};
@@ -18,7 +18,6 @@ use anndata::Backend;
 use anndata_hdf5::H5;
 use anyhow::{ensure, Result};
 use bed_utils::bed::{
-    io::Reader,
     map::{GIntervalIndexSet, GIntervalMap},
     BEDLike, GenomicRange,
 };
@@ -74,19 +73,16 @@ pub fn py_merge_peaks<'py>(
 }
 
 #[pyfunction]
-#[pyo3(signature = (peaks, replicates, blacklist=None))]
+#[pyo3(signature = (peaks, replicates, blacklist=None, min_replicates=None))]
 pub fn find_reproducible_peaks<'py>(
     peaks: &Bound<'py, PyAny>,
     replicates: Vec<Bound<'py, PyAny>>,
     blacklist: Option<PathBuf>,
+    min_replicates: Option<usize>,
 ) -> Result<PyDataFrame> {
-    let black: GIntervalMap<_> = if let Some(black) = blacklist {
-        Reader::new(utils::open_file_for_read(black), None)
-            .into_records::<GenomicRange>()
-            .map(|x| (x.unwrap(), ()))
-            .collect()
-    } else {
-        GIntervalMap::new()
+    let black = match blacklist {
+        Some(black) => read_region_map(black)?,
+        None => GIntervalMap::new(),
     };
     let replicates = replicates
         .into_iter()
@@ -94,14 +90,17 @@ pub fn find_reproducible_peaks<'py>(
             GIntervalMap::from_iter(get_genomic_ranges(&x).unwrap().into_iter().map(|x| (x, ())))
         })
         .collect::<Vec<_>>();
+    // A peak is reproducible if it is found in at least `min_replicates` replicates.
+    let min_replicates = min_replicates.unwrap_or(replicates.len()).min(replicates.len());
+    let is_reproducible = |x: &GenomicRange| {
+        replicates.iter().filter(|y| y.is_overlapped(x)).count() >= min_replicates
+    };
 
     if let Ok(peaks) = get_narrow_peaks(peaks) {
         Ok(PyDataFrame(narrow_peak_to_dataframe(
             peaks
                 .into_iter()
-                .filter(|x| {
-                    !black.is_overlapped(x) && replicates.iter().all(|y| y.is_overlapped(x))
-                })
+                .filter(|x| !black.is_overlapped(x) && is_reproducible(&x.to_genomic_range()))
                 .collect::<Vec<_>>(),
         )?))
     } else {
@@ -109,27 +108,31 @@ pub fn find_reproducible_peaks<'py>(
         Ok(PyDataFrame(broad_peak_to_dataframe(
             peaks
                 .into_iter()
-                .filter(|x| {
-                    !black.is_overlapped(x) && replicates.iter().all(|y| y.is_overlapped(x))
-                })
+                .filter(|x| !black.is_overlapped(x) && is_reproducible(&x.to_genomic_range()))
                 .collect::<Vec<_>>(),
         )?))
     }
 }
 
+#[pyfunction]
+pub fn peak_score_tracks(
+    treat_pileup: PathBuf,
+    control_lambda: PathBuf,
+    pscore_output: PathBuf,
+    qscore_output: PathBuf,
+) -> Result<()> {
+    utils::peak_score::score_tracks(treat_pileup, control_lambda, pscore_output, qscore_output)
+}
+
 #[pyfunction]
 #[pyo3(signature = (peaks, blacklist=None))]
 pub fn fetch_peaks<'py>(
     peaks: HashMap<String, Bound<'py, PyAny>>,
     blacklist: Option<PathBuf>,
 ) -> Result<HashMap<String, PyDataFrame>> {
-    let black: GIntervalMap<_> = if let Some(black) = blacklist {
-        Reader::new(utils::open_file_for_read(black), None)
-            .into_records::<GenomicRange>()
-            .map(|x| (x.unwrap(), ()))
-            .collect()
-    } else {
-        GIntervalMap::new()
+    let black = match blacklist {
+        Some(black) => read_region_map(black)?,
+        None => GIntervalMap::new(),
     };
     peaks
         .into_iter()
@@ -493,7 +496,7 @@ fn _export_tags<D: SnapData, P: AsRef<std::path::Path>>(
     let style = ProgressStyle::with_template(
         "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})",
     )?;
-    let mut fragments = data.get_fragment_iter(1000)?;
+    let mut fragments = data.get_fragment_iter(data.fragment_chunk_size()?)?;
     if let Some(max_size) = max_frag_size {
         fragments = fragments.max_fragment_size(max_size);
     }
@@ -501,12 +504,14 @@ fn _export_tags<D: SnapData, P: AsRef<std::path::Path>>(
         .into_fragment_groups(|x| keys[x])
         .progress_with_style(style)
         .for_each(|vals| {
-            vals.into_par_iter().for_each(|(i, beds)| {
-                if let Some((_, fl)) = files.get(&i) {
-                    let mut fl = fl.lock().unwrap();
-                    beds.into_iter()
-                        .for_each(|bed|  fl.add(bed.1).unwrap());
-                }
+            snapatac2_core::config::install(None, || {
+                vals.into_par_iter().for_each(|(i, beds)| {
+                    if let Some((_, fl)) = files.get(&i) {
+                        let mut fl = fl.lock().unwrap();
+                        beds.into_iter()
+                            .for_each(|bed|  fl.add(bed.1).unwrap());
+                    }
+                })
             })
         });
     let mut result = HashMap::new();
@@ -552,7 +557,7 @@ fn _call_peaks_bulk<'py, D: SnapData>(
     let style = ProgressStyle::with_template(
         "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})",
     )?;
-    let mut fragments = data.get_fragment_iter(1000)?;
+    let mut fragments = data.get_fragment_iter(data.fragment_chunk_size()?)?;
     if let Some(max_size) = max_frag_size {
         fragments = fragments.max_fragment_size(max_size);
     }
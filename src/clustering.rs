@@ -0,0 +1,43 @@
+use crate::utils::{csr_to_rust, AnnDataLike};
+
+use anndata::Backend;
+use anndata_hdf5::H5;
+use anyhow::Result;
+use numpy::{IntoPyArray, Ix1, Ix2, PyArray};
+use pyo3::prelude::*;
+use snapatac2_core::clustering::{
+    local_outlier_factor, merge_similar_clusters, Linkage, ProfileSimilarity,
+};
+use std::ops::Deref;
+
+/// Merge clusters with similar pseudobulk profiles.
+/// Returns the names of the original clusters, the linkage matrix, and the new cell labels.
+#[pyfunction]
+pub(crate) fn merge_clusters<'py>(
+    py: Python<'py>,
+    anndata: AnnDataLike,
+    groupby: Vec<Option<String>>,
+    similarity: &str,
+    linkage: &str,
+    threshold: f64,
+) -> Result<(Vec<String>, Bound<'py, PyArray<f64, Ix2>>, Vec<Option<String>>)> {
+    let similarity = ProfileSimilarity::try_from(similarity)?;
+    let linkage = Linkage::try_from(linkage)?;
+    macro_rules! run {
+        ($data:expr) => {
+            merge_similar_clusters($data, &groupby, similarity, linkage, threshold)?
+        };
+    }
+    let (names, tree, labels) = crate::with_anndata!(&anndata, run);
+    Ok((names, tree.to_linkage_matrix().into_pyarray(py), labels))
+}
+
+/// Compute the local outlier factor of every cell from the kNN graph.
+#[pyfunction]
+pub(crate) fn rarity_score<'py>(
+    py: Python<'py>,
+    graph: &Bound<'py, PyAny>,
+) -> Result<Bound<'py, PyArray<f64, Ix1>>> {
+    let graph = csr_to_rust(graph)?;
+    Ok(local_outlier_factor(&graph)?.into_pyarray(py))
+}
@@ -0,0 +1,108 @@
+use anyhow::Result;
+use pyo3::prelude::*;
+use snapatac2_core::config;
+
+/// Set the number of threads used by parallel algorithms.
+/// `None` restores the default, i.e., the number of logical CPUs.
+#[pyfunction]
+#[pyo3(signature = (n=None))]
+pub(crate) fn set_num_threads(n: Option<usize>) -> Result<()> {
+    config::set_num_threads(n)
+}
+
+#[pyfunction]
+pub(crate) fn get_num_threads() -> usize {
+    config::num_threads()
+}
+
+/// Set the memory budget, either as a number of bytes or as a string such as "8G".
+/// `None` removes the limit.
+#[pyfunction]
+#[pyo3(signature = (limit=None))]
+pub(crate) fn set_memory_limit(limit: Option<&Bound<'_, PyAny>>) -> Result<()> {
+    let limit = match limit {
+        None => None,
+        Some(x) => match x.extract::<usize>() {
+            Ok(n) => Some(n),
+            Err(_) => Some(config::parse_memory_size(&x.extract::<String>()?)?),
+        },
+    };
+    config::set_memory_limit(limit);
+    Ok(())
+}
+
+#[pyfunction]
+pub(crate) fn get_memory_limit() -> Option<usize> {
+    config::memory_limit()
+}
+
+/// Set the number of cells per chunk used when reading fragments. `None`
+/// restores the default, i.e., the chunk size is chosen from the average
+/// number of fragments per cell and the memory budget.
+#[pyfunction]
+#[pyo3(signature = (n=None))]
+pub(crate) fn set_chunk_size(n: Option<usize>) {
+    config::set_chunk_size(n)
+}
+
+#[pyfunction]
+pub(crate) fn get_chunk_size() -> Option<usize> {
+    config::chunk_size()
+}
+
+/// Restrict all operations reading fragments to the chromosomes matching one
+/// of the `include` patterns (if given) and none of the `exclude` patterns.
+/// Calling it without arguments removes the filter.
+#[pyfunction]
+#[pyo3(signature = (include=None, exclude=None))]
+pub(crate) fn set_chrom_filter(include: Option<Vec<String>>, exclude: Option<Vec<String>>) {
+    config::set_chrom_filter(Some(config::ChromFilter {
+        include: include.unwrap_or_default(),
+        exclude: exclude.unwrap_or_default(),
+    }))
+}
+
+#[pyfunction]
+pub(crate) fn get_chrom_filter() -> Option<(Vec<String>, Vec<String>)> {
+    config::chrom_filter().map(|f| (f.include, f.exclude))
+}
+
+/// Exclude the cells for which the boolean column `key` of `.obs` is true
+/// from all operations reading fragments. Calling it without arguments
+/// removes the mask.
+#[pyfunction]
+#[pyo3(signature = (key=None))]
+pub(crate) fn set_cell_mask(key: Option<String>) {
+    config::set_cell_mask(key)
+}
+
+#[pyfunction]
+pub(crate) fn get_cell_mask() -> Option<String> {
+    config::cell_mask()
+}
+
+/// Set the policy for records on chromosomes missing from the chromosome
+/// sizes: "error", "skip" or "extend".
+#[pyfunction]
+pub(crate) fn set_missing_chrom_policy(policy: &str) -> Result<()> {
+    config::set_missing_chrom_policy(config::MissingChromPolicy::try_from(policy)?);
+    Ok(())
+}
+
+#[pyfunction]
+pub(crate) fn get_missing_chrom_policy() -> String {
+    config::missing_chrom_policy().to_string()
+}
+
+/// Set the policy for records extending beyond the end of their chromosome:
+/// "error", "warn" or "drop".
+#[pyfunction]
+pub(crate) fn set_out_of_bounds_policy(policy: &str) -> Result<()> {
+    config::set_out_of_bounds_policy(config::OutOfBoundsPolicy::try_from(policy)?);
+    Ok(())
+}
+
+#[pyfunction]
+pub(crate) fn get_out_of_bounds_policy() -> String {
+    config::out_of_bounds_policy().to_string()
+}
@@ -1,5 +1,8 @@
 use crate::utils::AnnDataLike;
-use snapatac2_core::utils::PrefetchIterator;
+use snapatac2_core::embedding::{
+    idf_from_chunks_parallel, modality_weights, randomized_pca, PcaOptions,
+};
+use snapatac2_core::utils::{matmul::Device, rng::rng_from_seed, PrefetchIterator};
 
 use anndata::{
     data::{
@@ -19,12 +22,11 @@ use ndarray::{Array1, Array2, Axis};
 use numpy::{array::PyArrayMethods, PyArray1, PyArray2, PyReadonlyArray1, PyReadonlyArray2};
 use pyanndata::data::PyArrayData;
 use pyo3::{ffi::c_str, prelude::*};
-use rand::SeedableRng;
 use rayon::{
     iter::IntoParallelIterator,
     prelude::{ParallelBridge, ParallelIterator},
 };
-use std::{collections::HashSet, ops::Deref};
+use std::{collections::HashSet, ops::Deref, path::PathBuf};
 
 #[pyfunction]
 #[pyo3(signature = (anndata, selected_features, n_components, random_state, feature_weights=None))]
@@ -63,6 +65,70 @@ pub(crate) fn spectral_embedding<'py>(
     ))
 }
 
+/// Randomized PCA on the (optionally TF-IDF transformed) count matrix.
+/// The matrix is read in chunks and never densified.
+#[pyfunction]
+#[pyo3(signature = (
+    anndata, selected_features, n_components, tfidf, n_power_iter, chunk_size, random_state,
+    checkpoint_dir=None, device="cpu",
+))]
+pub(crate) fn pca_embedding<'py>(
+    py: Python<'py>,
+    anndata: AnnDataLike,
+    selected_features: &Bound<'_, PyAny>,
+    n_components: usize,
+    tfidf: bool,
+    n_power_iter: usize,
+    chunk_size: usize,
+    random_state: u64,
+    checkpoint_dir: Option<PathBuf>,
+    device: &str,
+) -> Result<(
+    Bound<'py, PyArray1<f64>>,
+    Bound<'py, PyArray2<f64>>,
+    Bound<'py, PyArray2<f64>>,
+    Bound<'py, PyArray1<f64>>,
+    Option<Bound<'py, PyArray1<f64>>>,
+)> {
+    macro_rules! run {
+        ($data:expr) => {{
+            let slice = pyanndata::data::to_select_elem(selected_features, $data.n_vars())?;
+            let chunks = || {
+                $data.x().iter::<DynCsrMatrix>(chunk_size).map(|(x, _, _)| {
+                    let mat: CsrMatrix<f64> = x.try_convert().unwrap();
+                    mat.select_axis(1, &slice)
+                })
+            };
+            let feature_weights = if tfidf {
+                info!("Compute IDF...");
+                Some(idf_from_chunks_parallel(chunks()))
+            } else {
+                None
+            };
+            let options = PcaOptions {
+                n_components,
+                n_power_iter,
+                feature_weights,
+                seed: random_state,
+                checkpoint_dir: checkpoint_dir.clone(),
+                device: Device::try_from(device)?,
+                ..Default::default()
+            };
+            info!("Compute randomized PCA...");
+            randomized_pca(chunks, &options).map(|pca| (pca, options.feature_weights))
+        }};
+    }
+    let (pca, feature_weights) = crate::with_anndata!(&anndata, run)?;
+
+    Ok((
+        PyArray1::from_vec(py, pca.explained_variance),
+        PyArray2::from_owned_array(py, pca.scores),
+        PyArray2::from_owned_array(py, pca.components),
+        PyArray1::from_vec(py, pca.mean),
+        feature_weights.map(|x| PyArray1::from_vec(py, x)),
+    ))
+}
+
 /// Matrix-free spectral embedding.
 /// The input is assumed to be a csr matrix with rows normalized to unit L2 norm.
 fn spectral_mf(
@@ -136,8 +202,10 @@ pub(crate) fn spectral_embedding_nystrom<'py>(
     weighted_by_degree: bool,
     chunk_size: usize,
     feature_weights: Option<Vec<f64>>,
-    num_threads: usize,
+    num_threads: Option<usize>,
+    random_state: u64,
 ) -> Result<(Bound<'py, PyArray1<f64>>, Bound<'py, PyArray2<f64>>)> {
+    let num_threads = num_threads.unwrap_or_else(snapatac2_core::config::num_threads);
     macro_rules! run {
         ($data:expr) => {{
             // Get feature indices
@@ -146,7 +214,7 @@ pub(crate) fn spectral_embedding_nystrom<'py>(
 
             // Get sample indices
             let n_obs = $data.n_obs();
-            let mut rng = rand::rngs::StdRng::seed_from_u64(2023);
+            let mut rng = rng_from_seed(random_state);
             let idx = if weighted_by_degree {
                 todo!()
                 /*
@@ -271,40 +339,42 @@ impl Nystrom {
         normalize(&mut mat, &self.feature_weights);
         let nrows = mat.nrows();
         let chunk_size = (nrows + num_threads - 1) / num_threads;
-        (0..num_threads)
-            .into_par_iter()
-            .map(|i| {
-                let start = (i * chunk_size).min(nrows);
-                let end = ((i + 1) * chunk_size).min(nrows);
-                let mut qmat = spmm_dense(start, end, &mat, &self.qmat);
-                let mut q_sum = qmat.row_sum_tr();
-                q_sum.iter_mut().enumerate().for_each(|(i, x)| {
-                    *x *= self.evals[i] * scale_factor;
-                });
-                let mut d = &qmat * q_sum;
-
-                // make sure d > 0
-                let mut d_min = f64::MAX;
-                d.iter().for_each(|x| {
-                    if *x > 0.0 && *x < d_min {
-                        d_min = *x
-                    }
-                });
-                d_min = d_min.sqrt();
-                d.iter_mut().for_each(|x| {
-                    if *x <= 0.0 {
-                        *x = d_min
-                    } else {
-                        *x = x.sqrt();
-                    }
-                });
-
-                qmat.row_iter_mut().enumerate().for_each(|(i, mut row)| {
-                    row /= d[i];
-                });
-                qmat
-            })
-            .collect()
+        snapatac2_core::config::install(Some(num_threads), || {
+            (0..num_threads)
+                .into_par_iter()
+                .map(|i| {
+                    let start = (i * chunk_size).min(nrows);
+                    let end = ((i + 1) * chunk_size).min(nrows);
+                    let mut qmat = spmm_dense(start, end, &mat, &self.qmat);
+                    let mut q_sum = qmat.row_sum_tr();
+                    q_sum.iter_mut().enumerate().for_each(|(i, x)| {
+                        *x *= self.evals[i] * scale_factor;
+                    });
+                    let mut d = &qmat * q_sum;
+
+                    // make sure d > 0
+                    let mut d_min = f64::MAX;
+                    d.iter().for_each(|x| {
+                        if *x > 0.0 && *x < d_min {
+                            d_min = *x
+                        }
+                    });
+                    d_min = d_min.sqrt();
+                    d.iter_mut().for_each(|x| {
+                        if *x <= 0.0 {
+                            *x = d_min
+                        } else {
+                            *x = x.sqrt();
+                        }
+                    });
+
+                    qmat.row_iter_mut().enumerate().for_each(|(i, mut row)| {
+                        row /= d[i];
+                    });
+                    qmat
+                })
+                .collect()
+        })
     }
 
     fn transform(&self, mut mat: CsrMatrix<f64>, scale_factor: usize) -> DMatrix<f64> {
@@ -415,15 +485,17 @@ where
 
 /// feature weighting and L2 norm normalization.
 fn normalize(input: &mut CsrMatrix<f64>, feature_weights: &[f64]) {
-    input.row_iter_mut().par_bridge().for_each(|mut row| {
-        let (indices, data) = row.cols_and_values_mut();
-        indices
-            .iter()
-            .zip(data.iter_mut())
-            .for_each(|(i, x)| *x *= feature_weights[*i]);
+    snapatac2_core::config::install(None, || {
+        input.row_iter_mut().par_bridge().for_each(|mut row| {
+            let (indices, data) = row.cols_and_values_mut();
+            indices
+                .iter()
+                .zip(data.iter_mut())
+                .for_each(|(i, x)| *x *= feature_weights[*i]);
 
-        let norm = data.iter().map(|x| x * x).sum::<f64>().sqrt();
-        data.iter_mut().for_each(|x| *x /= norm);
+            let norm = data.iter().map(|x| x * x).sum::<f64>().sqrt();
+            data.iter_mut().for_each(|x| *x /= norm);
+        })
     });
 }
 
@@ -501,7 +573,12 @@ fn hstack(m1: CsrMatrix<f64>, m2: CsrMatrix<f64>) -> CsrMatrix<f64> {
 }
 
 /// Multi-view spectral embedding.
+///
+/// If `n_neighbors` is given, per-cell modality weights are learned from the
+/// per-view embeddings and used to reweight the kernel of every view. The learned
+/// weights are returned along with the embedding.
 #[pyfunction]
+#[pyo3(signature = (anndata, selected_features, weights, n_components, random_state, n_neighbors=None))]
 pub(crate) fn multi_spectral_embedding<'py>(
     py: Python<'py>,
     anndata: Vec<AnnDataLike>,
@@ -509,7 +586,12 @@ pub(crate) fn multi_spectral_embedding<'py>(
     weights: Vec<f64>,
     n_components: usize,
     random_state: i64,
-) -> Result<(Bound<'py, PyArray1<f64>>, Bound<'py, PyArray2<f64>>)> {
+    n_neighbors: Option<usize>,
+) -> Result<(
+    Bound<'py, PyArray1<f64>>,
+    Bound<'py, PyArray2<f64>>,
+    Option<Bound<'py, PyArray2<f64>>>,
+)> {
     info!("Compute normalized views...");
     let mats = anndata
         .into_iter()
@@ -533,7 +615,7 @@ pub(crate) fn multi_spectral_embedding<'py>(
                     let norm = if mat.nrows() <= 2000 {
                         frobenius_norm(&mat)
                     } else {
-                        frobenius_norm(&sample_csr(&mat, 2000))
+                        frobenius_norm(&sample_csr(&mat, 2000, random_state as u64))
                     };
                     anyhow::Ok((norm, mat))
                 }};
@@ -548,7 +630,7 @@ pub(crate) fn multi_spectral_embedding<'py>(
         .map(|(n, w)| w / n)
         .collect::<Vec<_>>();
     let w_sum = ws.iter().sum::<f64>();
-    let mat = mats
+    let mut mats = mats
         .into_iter()
         .zip(ws.into_iter())
         .map(|((_, mut mat), w)| {
@@ -556,14 +638,41 @@ pub(crate) fn multi_spectral_embedding<'py>(
             mat.values_mut().iter_mut().for_each(|x| *x *= w);
             mat
         })
-        .reduce(|a, b| hstack(a, b))
-        .unwrap();
+        .collect::<Vec<_>>();
+
+    let cell_weights = if let Some(k) = n_neighbors {
+        info!("Learn modality weights...");
+        let views = mats
+            .iter()
+            .map(|mat| {
+                let (evals, mut evecs, _) = spectral_mf(mat.clone(), n_components, random_state)?;
+                evecs
+                    .axis_iter_mut(Axis(1))
+                    .zip(evals.iter())
+                    .for_each(|(mut col, v)| col *= v.max(0.0).sqrt());
+                anyhow::Ok(evecs)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let cell_weights =
+            modality_weights(&views.iter().map(|x| x.view()).collect::<Vec<_>>(), k)?;
+        mats.iter_mut().enumerate().for_each(|(m, mat)| {
+            mat.row_iter_mut().enumerate().for_each(|(i, mut row)| {
+                let w = cell_weights[[i, m]].sqrt();
+                row.values_mut().iter_mut().for_each(|x| *x *= w);
+            });
+        });
+        Some(cell_weights)
+    } else {
+        None
+    };
+    let mat = mats.into_iter().reduce(|a, b| hstack(a, b)).unwrap();
 
     info!("Compute embedding...");
     let (evals, evecs, _) = spectral_mf(mat, n_components, random_state)?;
     Ok((
         PyArray1::from_owned_array(py, evals),
         PyArray2::from_owned_array(py, evecs),
+        cell_weights.map(|x| PyArray2::from_owned_array(py, x)),
     ))
 }
 
@@ -588,8 +697,8 @@ fn frobenius_norm(x: &CsrMatrix<f64>) -> f64 {
     (sum - x.nrows() as f64).sqrt()
 }
 
-fn sample_csr(mat: &CsrMatrix<f64>, n: usize) -> CsrMatrix<f64> {
-    let mut rng = rand::rngs::StdRng::seed_from_u64(2023);
+fn sample_csr(mat: &CsrMatrix<f64>, n: usize, seed: u64) -> CsrMatrix<f64> {
+    let mut rng = rng_from_seed(seed);
     let idx = rand::seq::index::sample(&mut rng, mat.nrows(), n).into_vec();
     mat.select_axis(0, SelectInfoElem::from(idx))
 }
@@ -1,13 +1,22 @@
-use crate::utils::{read_genomic_ranges, AnnDataLike};
+use crate::utils::{read_genomic_ranges, read_region_map, AnnDataLike};
 use snapatac2_core::{
-    export::{CoverageOutputFormat, Exporter, Normalization},
-    utils, SnapData,
+    bias::{BiasModel, BIAS_MODEL},
+    config,
+    export::{
+        self, CoverageOutputFormat, Exporter, MissingGroup, Normalization, SmoothKernel, SpikeIn,
+        ValueCap,
+    },
+    feature_count::{read_coverage_cache, CoverageCache, ValueType},
+    preprocessing::SummaryType,
+    utils::{self, barcode::BarcodeMap},
+    SnapData,
 };
 
 use anndata::Backend;
 use anndata_hdf5::H5;
-use anyhow::{ensure, Result};
-use bed_utils::bed::{io::Reader, map::GIntervalMap, BEDLike, GenomicRange};
+use anyhow::{ensure, Context, Result};
+use bed_utils::bed::{BEDLike, GenomicRange};
+use numpy::{IntoPyArray, PyArray1, PyArray2};
 use pyo3::{prelude::*, pybacked::PyBackedStr};
 use std::ops::Deref;
 use std::str::FromStr;
@@ -18,7 +27,8 @@ use std::{
 
 #[pyfunction]
 #[pyo3(signature = (anndata, barcodes, group_by, dir, prefix, suffix, selections=None,
-       min_frag_length=None, max_frag_length=None, compression=None, compression_level=None))]
+       min_frag_length=None, max_frag_length=None, compression=None, compression_level=None,
+       modality=None, chunk_size=None))]
 pub fn export_fragments(
     anndata: AnnDataLike,
     barcodes: Vec<PyBackedStr>,
@@ -31,6 +41,8 @@ pub fn export_fragments(
     max_frag_length: Option<u64>,
     compression: Option<&str>,
     compression_level: Option<u32>,
+    modality: Option<&str>,
+    chunk_size: Option<usize>,
 ) -> Result<HashMap<String, PathBuf>> {
     let barcodes = barcodes.iter().map(|x| x.as_ref()).collect();
     let group_by = group_by.iter().map(|x| x.as_ref()).collect();
@@ -45,6 +57,7 @@ pub fn export_fragments(
                 selections,
                 min_frag_length,
                 max_frag_length,
+                modality,
                 dir,
                 prefix,
                 suffix,
@@ -53,14 +66,80 @@ pub fn export_fragments(
             )
         };
     }
-    crate::with_anndata!(&anndata, run)
+    config::with_chunk_size(chunk_size, || crate::with_anndata!(&anndata, run))
+}
+
+#[pyfunction]
+#[pyo3(signature = (anndata, barcodes, group_by, group, output, min_frag_length=None,
+       max_frag_length=None, compression=None, compression_level=None, modality=None,
+       chunk_size=None))]
+pub fn export_group_fragments(
+    anndata: AnnDataLike,
+    barcodes: Vec<PyBackedStr>,
+    group_by: Vec<PyBackedStr>,
+    group: &str,
+    output: PathBuf,
+    min_frag_length: Option<u64>,
+    max_frag_length: Option<u64>,
+    compression: Option<&str>,
+    compression_level: Option<u32>,
+    modality: Option<&str>,
+    chunk_size: Option<usize>,
+) -> Result<()> {
+    let barcodes = barcodes.iter().map(|x| x.as_ref()).collect();
+    let group_by = group_by.iter().map(|x| x.as_ref()).collect();
+    let compression = compression
+        .map(|x| utils::Compression::from_str(x).map_err(anyhow::Error::msg))
+        .transpose()?;
+    macro_rules! run {
+        ($data:expr) => {
+            $data.export_group_fragments(
+                Some(&barcodes),
+                &group_by,
+                group,
+                min_frag_length,
+                max_frag_length,
+                modality,
+                output,
+                compression,
+                compression_level,
+            )
+        };
+    }
+    config::with_chunk_size(chunk_size, || crate::with_anndata!(&anndata, run))
+}
+
+/// Names of the cells on output: `strip_suffix` is removed from the end of
+/// each barcode and the corresponding element of `prefixes` is prepended.
+/// Fails if two cells end up with the same name.
+#[pyfunction]
+#[pyo3(signature = (barcodes, prefixes=None, strip_suffix=None, separator="_"))]
+pub fn map_barcodes(
+    barcodes: Vec<PyBackedStr>,
+    prefixes: Option<Vec<PyBackedStr>>,
+    strip_suffix: Option<&str>,
+    separator: &str,
+) -> Result<Vec<String>> {
+    let mut map = BarcodeMap::default().with_separator(separator);
+    if let Some(pattern) = strip_suffix {
+        map = map.strip_suffix(pattern)?;
+    }
+    let barcodes: Vec<&str> = barcodes.iter().map(|x| x.as_ref()).collect();
+    let prefixes: Option<Vec<&str>> = prefixes
+        .as_ref()
+        .map(|p| p.iter().map(|x| x.as_ref()).collect());
+    map.map_all(&barcodes, prefixes.as_deref())
 }
 
 #[pyfunction]
 #[pyo3(signature = (anndata, group_by, resolution, dir, prefix, suffix, output_format,
        strategy, selections=None, blacklist=None, normalization=None, include_for_norm=None,
        exclude_for_norm=None, min_frag_length=None, max_frag_length=None, smooth_base=None,
-       compression=None, compression_level=None, temp_dir=None, num_threads=None))]
+       compression=None, compression_level=None, temp_dir=None, num_threads=None, bias_genome=None,
+       fragment_suffix=None, barcodes=None, fragment_compression=None, fragment_compression_level=None,
+       max_value=None, winsorize=None, smooth_kernel="flat", track_stats=None, spike_in=None,
+       spike_in_scale=10000.0, effective_genome_size=None, group_by_obs=false, missing="error",
+       modality=None, chunk_size=None))]
 pub fn export_coverage(
     anndata: AnnDataLike,
     group_by: Vec<PyBackedStr>,
@@ -82,8 +161,40 @@ pub fn export_coverage(
     compression_level: Option<u32>,
     temp_dir: Option<PathBuf>,
     num_threads: Option<usize>,
-) -> Result<HashMap<String, PathBuf>> {
-    let group_by = group_by.iter().map(|x| x.as_ref()).collect();
+    bias_genome: Option<PathBuf>,
+    fragment_suffix: Option<&str>,
+    barcodes: Option<Vec<PyBackedStr>>,
+    fragment_compression: Option<&str>,
+    fragment_compression_level: Option<u32>,
+    max_value: Option<f64>,
+    winsorize: Option<f64>,
+    smooth_kernel: &str,
+    track_stats: Option<Vec<f64>>,
+    spike_in: Option<Vec<String>>,
+    spike_in_scale: f64,
+    effective_genome_size: Option<u64>,
+    group_by_obs: bool,
+    missing: &str,
+    modality: Option<&str>,
+    chunk_size: Option<usize>,
+) -> Result<(
+    HashMap<String, PathBuf>,
+    HashMap<String, PathBuf>,
+    HashMap<String, (f64, f64, f64, Vec<(f64, f64)>)>,
+    HashMap<String, (usize, usize)>,
+    HashMap<String, f64>,
+)> {
+    let group_by: Vec<&str> = group_by.iter().map(|x| x.as_ref()).collect();
+    let missing = MissingGroup::from_str(missing).map_err(anyhow::Error::msg)?;
+    let barcodes: Option<Vec<&str>> = barcodes
+        .as_ref()
+        .map(|x| x.iter().map(|x| x.as_ref()).collect());
+    let fragment_output = fragment_suffix.map(|suffix| export::FragmentOutput {
+        suffix,
+        barcodes: barcodes.as_ref(),
+        compression: fragment_compression.map(|x| utils::Compression::from_str(x).unwrap()),
+        compression_level: fragment_compression_level,
+    });
     let selections = selections
         .as_ref()
         .map(|s| s.iter().map(|x| x.as_ref()).collect());
@@ -102,49 +213,283 @@ pub fn export_coverage(
             .collect()
     });
 
-    let black: Option<GIntervalMap<()>> = blacklist.map(|black| {
-        Reader::new(utils::open_file_for_read(black), None)
-            .into_records::<GenomicRange>()
-            .map(|x| (x.unwrap(), ()))
-            .collect()
-    });
+    let black = blacklist.map(read_region_map).transpose()?;
 
     let normalization = normalization.map(|x| Normalization::from_str(x).unwrap());
     let output_format = CoverageOutputFormat::from_str(output_format).unwrap();
+    let smooth_kernel = SmoothKernel::try_from(smooth_kernel)?;
+    let value_cap = match (max_value, winsorize) {
+        (Some(_), Some(_)) => anyhow::bail!("max_value and winsorize cannot be used together"),
+        (Some(x), None) => Some(ValueCap::Max(x)),
+        (None, Some(q)) => {
+            ensure!(q > 0.0 && q <= 1.0, "winsorize must be in (0, 1]");
+            Some(ValueCap::Quantile(q))
+        }
+        (None, None) => None,
+    };
+    let spike_in = match spike_in {
+        None => None,
+        Some(patterns) => {
+            ensure!(!patterns.is_empty(), "no spike-in chromosomes are given");
+            ensure!(spike_in_scale > 0.0, "spike_in_scale must be positive");
+            Some(SpikeIn::new(patterns, spike_in_scale))
+        }
+    };
 
     macro_rules! run {
-        ($data:expr) => {
+        ($data:expr) => {{
+            let bias = match bias_genome.as_ref() {
+                None => None,
+                Some(genome) => {
+                    let model = BiasModel::read($data)?.with_context(|| {
+                        format!("no bias model is found in '.uns[\"{}\"]', please run `fit_tn5_bias` first", BIAS_MODEL)
+                    })?;
+                    Some((model, genome))
+                }
+            };
+            // With `group_by_obs`, `group_by` are the keys of the groups in `.obs`.
+            let groups = if group_by_obs {
+                Some(export::obs_group_labels($data, &group_by, missing)?)
+            } else {
+                None
+            };
+            let (group_by, selections) = match groups.as_ref() {
+                Some(groups) => groups.select(selections),
+                None => (group_by.clone(), selections),
+            };
             $data.export_coverage(
                 &group_by,
                 selections,
                 resolution,
                 black.as_ref(),
                 normalization,
+                value_cap,
                 include_for_norm.as_ref(),
                 exclude_for_norm.as_ref(),
+                spike_in.as_ref(),
+                effective_genome_size,
                 min_frag_length,
                 max_frag_length,
+                modality,
                 strategy.try_into()?,
+                bias.as_ref().map(|(m, g)| (m, g.as_path())),
                 smooth_base,
+                smooth_kernel,
                 dir,
                 prefix,
                 suffix,
                 output_format,
                 compression.map(|x| utils::Compression::from_str(x).unwrap()),
                 compression_level,
+                fragment_output,
+                track_stats.as_deref(),
                 temp_dir,
                 num_threads,
+            )?
+        }};
+    }
+    let mut coverage = HashMap::new();
+    let mut fragments = HashMap::new();
+    let mut stats = HashMap::new();
+    let mut out_of_bounds = HashMap::new();
+    let mut spike_in_counts = HashMap::new();
+    let tracks = config::with_chunk_size(chunk_size, || -> Result<_> {
+        Ok(crate::with_anndata!(&anndata, run))
+    })?;
+    for (group, track) in tracks {
+        out_of_bounds.insert(
+            group.clone(),
+            (track.out_of_bounds.clipped, track.out_of_bounds.dropped),
+        );
+        if let Some(file) = track.fragments {
+            fragments.insert(group.clone(), file);
+        }
+        if let Some(x) = track.stats {
+            stats.insert(group.clone(), (x.mean, x.max, x.coverage_fraction, x.quantiles));
+        }
+        if let Some(x) = track.spike_in_count {
+            spike_in_counts.insert(group.clone(), x);
+        }
+        coverage.insert(group, track.coverage);
+    }
+    Ok((coverage, fragments, stats, out_of_bounds, spike_in_counts))
+}
+
+/// The groups of the cells given by the `.obs` columns `keys`: the label of
+/// every cell if `labels` is true, and the values of every label.
+#[pyfunction]
+#[pyo3(signature = (anndata, keys, missing="error", labels=false))]
+pub fn obs_group_labels(
+    anndata: AnnDataLike,
+    keys: Vec<PyBackedStr>,
+    missing: &str,
+    labels: bool,
+) -> Result<(Option<Vec<Option<String>>>, HashMap<String, Vec<Option<String>>>)> {
+    let keys: Vec<&str> = keys.iter().map(|x| x.as_ref()).collect();
+    let missing = MissingGroup::from_str(missing).map_err(anyhow::Error::msg)?;
+    macro_rules! run {
+        ($data:expr) => {
+            export::obs_group_labels($data, &keys, missing)
+        };
+    }
+    let groups = crate::with_anndata!(&anndata, run)?;
+    let values = groups.values.into_iter().collect();
+    Ok((labels.then_some(groups.labels), values))
+}
+
+#[pyfunction]
+#[pyo3(signature = (anndata, group_by, dir, prefix, suffix, output_format, selections=None,
+       normalization=None, compression=None, compression_level=None))]
+pub fn export_coverage_from_cache(
+    anndata: AnnDataLike,
+    group_by: Vec<PyBackedStr>,
+    dir: PathBuf,
+    prefix: &str,
+    suffix: &str,
+    output_format: &str,
+    selections: Option<HashSet<PyBackedStr>>,
+    normalization: Option<&str>,
+    compression: Option<&str>,
+    compression_level: Option<u32>,
+) -> Result<HashMap<String, PathBuf>> {
+    let group_by = group_by.iter().map(|x| x.as_ref()).collect();
+    let selections = selections
+        .as_ref()
+        .map(|s| s.iter().map(|x| x.as_ref()).collect());
+    let normalization = normalization.map(|x| Normalization::from_str(x).unwrap());
+    let output_format = CoverageOutputFormat::from_str(output_format).unwrap();
+
+    macro_rules! run {
+        ($data:expr) => {
+            $data.export_coverage_from_cache(
+                &group_by,
+                selections,
+                normalization,
+                dir,
+                prefix,
+                suffix,
+                output_format,
+                compression.map(|x| utils::Compression::from_str(x).unwrap()),
+                compression_level,
+            )
+        };
+    }
+    crate::with_anndata!(&anndata, run)
+}
+
+/// Export the per-base values of each group of cells, see
+/// `Exporter::export_base_values`.
+#[pyfunction]
+#[pyo3(signature = (anndata, group_by, resolution, dir, prefix, suffix, output_format,
+       value_type="fraction", summary_type="mean", selections=None, compression=None,
+       compression_level=None))]
+pub fn export_base_values(
+    anndata: AnnDataLike,
+    group_by: Vec<PyBackedStr>,
+    resolution: usize,
+    dir: PathBuf,
+    prefix: &str,
+    suffix: &str,
+    output_format: &str,
+    value_type: &str,
+    summary_type: &str,
+    selections: Option<HashSet<PyBackedStr>>,
+    compression: Option<&str>,
+    compression_level: Option<u32>,
+) -> Result<HashMap<String, PathBuf>> {
+    let group_by = group_by.iter().map(|x| x.as_ref()).collect();
+    let selections = selections
+        .as_ref()
+        .map(|s| s.iter().map(|x| x.as_ref()).collect());
+    let output_format = CoverageOutputFormat::from_str(output_format).unwrap();
+    let value_type = match value_type {
+        "target" => ValueType::Numerator,
+        "total" => ValueType::Denominator,
+        "fraction" => ValueType::Ratio,
+        _ => anyhow::bail!("value_type must be one of 'target', 'total' or 'fraction'"),
+    };
+    let summary_type = match summary_type {
+        "sum" => SummaryType::Sum,
+        "mean" => SummaryType::Mean,
+        "count" => SummaryType::Count,
+        _ => anyhow::bail!("aggregation must be one of 'sum', 'mean' or 'count'"),
+    };
+
+    macro_rules! run {
+        ($data:expr) => {
+            $data.export_base_values(
+                &group_by,
+                selections,
+                resolution,
+                value_type,
+                summary_type,
+                dir,
+                prefix,
+                suffix,
+                output_format,
+                compression.map(|x| utils::Compression::from_str(x).unwrap()),
+                compression_level,
             )
         };
     }
     crate::with_anndata!(&anndata, run)
 }
 
+/// Exact per-base coverage of `region` for each group of cells.
+#[pyfunction]
+#[pyo3(signature = (adata, region, group_by, selections=None, counting_strategy="fragment",
+       min_frag_length=None, max_frag_length=None))]
+pub fn base_coverage<'py>(
+    py: Python<'py>,
+    adata: AnnDataLike,
+    region: &str,
+    group_by: Vec<PyBackedStr>,
+    selections: Option<HashSet<PyBackedStr>>,
+    counting_strategy: &str,
+    min_frag_length: Option<u64>,
+    max_frag_length: Option<u64>,
+) -> Result<HashMap<String, Bound<'py, PyArray1<u32>>>> {
+    let region = GenomicRange::from_str(region)
+        .map_err(|_| anyhow::anyhow!("invalid region: {}", region))?;
+    let group_by: Vec<&str> = group_by.iter().map(|x| x.as_ref()).collect();
+    let selections: Option<HashSet<&str>> = selections
+        .as_ref()
+        .map(|s| s.iter().map(|x| x.as_ref()).collect());
+    let counting_strategy = counting_strategy.try_into()?;
+    macro_rules! run {
+        ($data:expr) => {
+            export::base_coverage(
+                $data,
+                &region,
+                &group_by,
+                selections.as_ref(),
+                counting_strategy,
+                min_frag_length,
+                max_frag_length,
+            )?
+        };
+    }
+    Ok(crate::with_anndata!(&adata, run)
+        .into_iter()
+        .map(|(k, v)| (k, v.into_pyarray(py)))
+        .collect())
+}
+
+/// Coverage of `region` for each group of cells, in reads per million. If a
+/// valid coverage cache exists and `use_cache` is true, the coverage is the
+/// number of insertions in each bin of the cache overlapping `region`;
+/// otherwise it is computed at base resolution from the fragments.
 #[pyfunction]
-pub fn get_coverage(adata: AnnDataLike, region: &str, groups: Vec<String>) -> Result<HashMap<String, Vec<f64>>> {
+#[pyo3(signature = (adata, region, groups, use_cache=true))]
+pub fn get_coverage(
+    adata: AnnDataLike,
+    region: &str,
+    groups: Vec<String>,
+    use_cache: bool,
+) -> Result<HashMap<String, Vec<f64>>> {
     macro_rules! run {
         ($data:expr) => {
-            get_coverage_helper($data, region, &groups)
+            get_coverage_helper($data, region, &groups, use_cache)
         };
     }
     crate::with_anndata!(&adata, run)
@@ -154,6 +499,7 @@ fn get_coverage_helper<A: SnapData>(
     adata: &A,
     region: &str,
     groups: &[String],
+    use_cache: bool,
 ) -> Result<HashMap<String, Vec<f64>>> {
     ensure!(
         adata.n_obs() == groups.len(),
@@ -161,10 +507,15 @@ fn get_coverage_helper<A: SnapData>(
     );
 
     let region = GenomicRange::from_str(region).unwrap();
+    if use_cache {
+        if let Some(cache) = read_coverage_cache(adata, adata.fragment_chunk_size()?)? {
+            return get_coverage_from_cache(cache, &region, groups);
+        }
+    }
     let mut total_counts = HashMap::new();
     let mut counts = HashMap::new();
     adata
-        .get_fragment_iter(1000)?
+        .get_fragment_iter(adata.fragment_chunk_size()?)?
         .into_fragment_groups(|i| &groups[i])
         .for_each(|frags| {
             frags.into_iter().for_each(|(k, frags)| {
@@ -191,3 +542,162 @@ fn get_coverage_helper<A: SnapData>(
     });
     Ok(counts)
 }
+
+fn get_coverage_from_cache(
+    cache: CoverageCache,
+    region: &GenomicRange,
+    groups: &[String],
+) -> Result<HashMap<String, Vec<f64>>> {
+    let bin_size = cache.info.bin_size as u64;
+    let offset = cache
+        .index
+        .get_range(region.chrom())
+        .with_context(|| format!("chromosome {} is not found", region.chrom()))?
+        .start;
+    let first = offset + (region.start() / bin_size) as usize;
+    let last = offset + region.end().div_ceil(bin_size) as usize;
+    let mut total_counts: HashMap<&str, u64> = HashMap::new();
+    let mut counts: HashMap<&str, Vec<f64>> = HashMap::new();
+    cache.data.for_each(|(mat, s, _)| {
+        mat.row_iter().enumerate().for_each(|(i, row)| {
+            let k = groups[s + i].as_str();
+            let total = total_counts.entry(k).or_insert(0);
+            let count = counts.entry(k).or_insert(vec![0.0; last - first]);
+            row.col_indices().iter().zip(row.values()).for_each(|(j, v)| {
+                *total += *v as u64;
+                if (first..last).contains(j) {
+                    count[j - first] += *v as f64;
+                }
+            });
+        })
+    });
+    Ok(counts
+        .into_iter()
+        .map(|(k, mut v)| {
+            let total = total_counts[k] as f64 / 1e6;
+            v.iter_mut().for_each(|x| *x /= total);
+            (k.to_string(), v)
+        })
+        .collect())
+}
+
+#[pyfunction]
+#[pyo3(signature = (anndata, genome, k=6, max_insertions=10_000_000, seed=0))]
+pub fn fit_tn5_bias(
+    anndata: AnnDataLike,
+    genome: PathBuf,
+    k: usize,
+    max_insertions: usize,
+    seed: u64,
+) -> Result<String> {
+    macro_rules! run {
+        ($data:expr) => {{
+            let model = BiasModel::fit($data, &genome, k, max_insertions, seed)?;
+            model.write($data)?;
+            Ok(serde_json::to_string(&model)?)
+        }};
+    }
+    crate::with_anndata!(&anndata, run)
+}
+
+#[pyfunction]
+#[pyo3(signature = (anndata, genome, bin_size, out_file, output_format, compression=None,
+       compression_level=None))]
+pub fn export_expected_bias(
+    anndata: AnnDataLike,
+    genome: PathBuf,
+    bin_size: u64,
+    out_file: PathBuf,
+    output_format: &str,
+    compression: Option<&str>,
+    compression_level: Option<u32>,
+) -> Result<()> {
+    let output_format = CoverageOutputFormat::from_str(output_format).unwrap();
+    macro_rules! run {
+        ($data:expr) => {{
+            let model = BiasModel::read($data)?.with_context(|| {
+                format!("no bias model is found in '.uns[\"{}\"]', please run `fit_tn5_bias` first", BIAS_MODEL)
+            })?;
+            export::export_expected_bias(
+                &model,
+                genome.as_path(),
+                &$data.read_chrom_sizes()?,
+                bin_size,
+                out_file.as_path(),
+                output_format,
+                compression.map(|x| utils::Compression::from_str(x).unwrap()),
+                compression_level,
+            )
+        }};
+    }
+    crate::with_anndata!(&anndata, run)
+}
+
+/// Combine bedGraph or bigWig tracks position by position. `op` is one of
+/// "mean", "sum", "max" or "min".
+#[pyfunction]
+#[pyo3(signature = (tracks, chrom_sizes, op, out_file, output_format, compression=None,
+       compression_level=None))]
+pub fn combine_tracks(
+    tracks: Vec<PathBuf>,
+    chrom_sizes: Vec<(String, u64)>,
+    op: &str,
+    out_file: PathBuf,
+    output_format: &str,
+    compression: Option<&str>,
+    compression_level: Option<u32>,
+) -> Result<()> {
+    let output_format = CoverageOutputFormat::from_str(output_format).unwrap();
+    utils::track_ops::combine_tracks(
+        &tracks,
+        &chrom_sizes.into_iter().collect(),
+        op.try_into()?,
+        out_file,
+        output_format,
+        compression.map(|x| utils::Compression::from_str(x).unwrap()),
+        compression_level,
+    )
+}
+
+/// Summarize bedGraph or bigWig tracks in every region. `op` is one of
+/// "mean", "sum", "max" or "min". Returns one list of values per track.
+#[pyfunction]
+#[pyo3(signature = (tracks, regions, op, num_threads=None))]
+pub fn track_signal(
+    tracks: Vec<PathBuf>,
+    regions: Vec<String>,
+    op: &str,
+    num_threads: Option<usize>,
+) -> Result<Vec<Vec<f64>>> {
+    let regions = regions
+        .iter()
+        .map(|x| GenomicRange::from_str(x).map_err(|_| anyhow::anyhow!("invalid region: {}", x)))
+        .collect::<Result<Vec<_>>>()?;
+    utils::track_ops::region_signal(&tracks, &regions, op.try_into()?, num_threads)
+}
+
+/// Correlation of every track with every reference track over the bins of the
+/// genome. `method` is "pearson" or "spearman".
+#[pyfunction]
+#[pyo3(signature = (tracks, references, chrom_sizes, bin_size, method, skip_zeros, num_threads=None))]
+pub fn track_correlation<'py>(
+    py: Python<'py>,
+    tracks: Vec<PathBuf>,
+    references: Vec<PathBuf>,
+    chrom_sizes: Vec<(String, u64)>,
+    bin_size: u64,
+    method: &str,
+    skip_zeros: bool,
+    num_threads: Option<usize>,
+) -> Result<Bound<'py, PyArray2<f64>>> {
+    let cor = utils::track_ops::track_correlation(
+        &tracks,
+        &references,
+        &chrom_sizes.into_iter().collect(),
+        bin_size,
+        method.try_into()?,
+        skip_zeros,
+        num_threads,
+    )?;
+    Ok(cor.into_pyarray(py))
+}
@@ -0,0 +1,91 @@
+use crate::utils::AnnDataLike;
+use snapatac2_core::{
+    bias::{BiasModel, GenomeSequence, BIAS_MODEL},
+    footprint::{expected_bias, footprint_counts as count_footprints},
+    nucleosome::Anchor,
+};
+
+use anndata::Backend;
+use anndata_hdf5::H5;
+use anyhow::{Context, Result};
+use numpy::{IntoPyArray, Ix1, Ix2, PyArray};
+use pyo3::prelude::*;
+use std::ops::Deref;
+use std::path::PathBuf;
+
+/// The anchors of every motif, given as (chromosome, position, reverse).
+fn to_anchors(motifs: Vec<Vec<(String, u64, bool)>>) -> Vec<Vec<Anchor>> {
+    motifs
+        .into_iter()
+        .map(|anchors| {
+            anchors
+                .into_iter()
+                .map(|(chrom, pos, reverse)| Anchor { chrom, pos, reverse })
+                .collect()
+        })
+        .collect()
+}
+
+/// Count the insertions around the anchors of every motif, for the footprint
+/// comparison of two groups of cells. `groups` gives the group, 0 or 1, of
+/// each cell, or `None` for the cells left out. Returns, for every motif, the
+/// insertions of the two groups at every offset from the anchors, and the
+/// insertions of every cell of the two groups, in the order of the cells, in
+/// the center and in the flanks of the anchors.
+#[pyfunction]
+#[pyo3(signature = (anndata, motifs, groups, flank, center))]
+pub(crate) fn footprint_counts<'py>(
+    py: Python<'py>,
+    anndata: AnnDataLike,
+    motifs: Vec<Vec<(String, u64, bool)>>,
+    groups: Vec<Option<usize>>,
+    flank: u64,
+    center: u64,
+) -> Result<
+    Vec<(
+        Bound<'py, PyArray<u64, Ix2>>,
+        Bound<'py, PyArray<u32, Ix1>>,
+        Bound<'py, PyArray<u32, Ix1>>,
+    )>,
+> {
+    let motifs = to_anchors(motifs);
+    macro_rules! run {
+        ($data:expr) => {
+            count_footprints($data, &motifs, &groups, flank, center)?
+        };
+    }
+    Ok(crate::with_anndata!(&anndata, run)
+        .into_iter()
+        .map(|x| {
+            (
+                x.profiles.into_pyarray(py),
+                x.center.into_pyarray(py),
+                x.flanks.into_pyarray(py),
+            )
+        })
+        .collect())
+}
+
+/// Expected Tn5 insertion bias at every offset from the anchors of every
+/// motif, computed from the genome sequence with the bias model of `anndata`.
+#[pyfunction]
+#[pyo3(signature = (anndata, motifs, genome, flank))]
+pub(crate) fn footprint_expected_bias<'py>(
+    py: Python<'py>,
+    anndata: AnnDataLike,
+    motifs: Vec<Vec<(String, u64, bool)>>,
+    genome: PathBuf,
+    flank: u64,
+) -> Result<Bound<'py, PyArray<f64, Ix2>>> {
+    let motifs = to_anchors(motifs);
+    macro_rules! run {
+        ($data:expr) => {
+            BiasModel::read($data)?.with_context(|| {
+                format!("no bias model is found in '.uns[\"{}\"]', please run `fit_tn5_bias` first", BIAS_MODEL)
+            })?
+        };
+    }
+    let model = crate::with_anndata!(&anndata, run);
+    let mut genome = GenomeSequence::open(&genome)?;
+    Ok(expected_bias(&model, &mut genome, &motifs, flank)?.into_pyarray(py))
+}
@@ -1,8 +1,10 @@
 use anndata::ArrayData;
+use anyhow::{ensure, Result};
+use ndarray::s;
 use pyanndata::data::PyArrayData;
 use pyo3::{prelude::*, PyResult};
-use numpy::{PyReadonlyArray, Ix2};
-use snapatac2_core::utils::knn;
+use numpy::{PyArray1, PyReadonlyArray, PyReadonlyArray1, Ix2};
+use snapatac2_core::utils::{knn, matmul::Device};
 
 #[pyfunction]
 pub(crate) fn nearest_neighbour_graph(
@@ -15,6 +17,25 @@ pub(crate) fn nearest_neighbour_graph(
     Ok(ArrayData::from(knn).into())
 }
 
+/// Exact nearest neighbor search by blocked brute force, with the distances
+/// computed on `device`.
+#[pyfunction]
+#[pyo3(signature = (data, k, block_size=4096, device="cpu"))]
+pub(crate) fn exact_nearest_neighbour_graph(
+    data: PyReadonlyArray<'_, f64, Ix2>,
+    k: usize,
+    block_size: usize,
+    device: &str,
+) -> Result<PyArrayData>
+{
+    let data = data.as_array();
+    let backend = Device::try_from(device)?.backend()?;
+    let knn = snapatac2_core::config::install(None, || {
+        knn::exact_nearest_neighbour_graph(data, k, block_size, backend.as_ref())
+    })?;
+    Ok(ArrayData::from(knn).into())
+}
+
 // Search for and save nearest neighbors using ANN
 #[pyfunction]
 pub(crate) fn approximate_nearest_neighbour_graph(
@@ -25,4 +46,60 @@ pub(crate) fn approximate_nearest_neighbour_graph(
     let data = data.as_array();
     let knn = knn::approximate_nearest_neighbour_graph(data, k);
     Ok(ArrayData::from(knn).into())
-}
\ No newline at end of file
+}
+
+/// Build a HNSW index and search nearest neighbors.
+/// Returns the kNN graph and the serialized index.
+#[pyfunction]
+pub(crate) fn build_ann_index<'py>(
+    py: Python<'py>,
+    data: PyReadonlyArray<'_, f32, Ix2>,
+    k: usize,
+) -> Result<(PyArrayData, Bound<'py, PyArray1<u8>>)>
+{
+    let data = data.as_array();
+    let mut index = knn::AnnIndex::new(data)?;
+    let graph = index.search(data, k);
+    Ok((ArrayData::from(graph).into(), PyArray1::from_vec(py, index.to_bytes()?)))
+}
+
+/// Append the rows of `data` that are not yet in the index, i.e., rows
+/// after the first `len(index)` ones, and search nearest neighbors of all rows.
+/// The index is rebuilt if it cannot hold all the rows.
+/// Returns the kNN graph and the updated index.
+#[pyfunction]
+pub(crate) fn update_ann_index<'py>(
+    py: Python<'py>,
+    index: PyReadonlyArray1<'_, u8>,
+    data: PyReadonlyArray<'_, f32, Ix2>,
+    k: usize,
+) -> Result<(PyArrayData, Bound<'py, PyArray1<u8>>)>
+{
+    let data = data.as_array();
+    let mut index = knn::AnnIndex::from_bytes(index.as_slice()?)?;
+    ensure!(
+        index.len() <= data.nrows(),
+        "the index contains {} items, but the data has only {} rows",
+        index.len(),
+        data.nrows(),
+    );
+    if data.nrows() > index.capacity() {
+        index = knn::AnnIndex::new(data)?;
+    } else if index.len() < data.nrows() {
+        index.add(data.slice(s![index.len().., ..]))?;
+    }
+    let graph = index.search(data, k);
+    Ok((ArrayData::from(graph).into(), PyArray1::from_vec(py, index.to_bytes()?)))
+}
+
+/// Search the nearest neighbors of `query` in a serialized index.
+#[pyfunction]
+pub(crate) fn query_ann_index(
+    index: PyReadonlyArray1<'_, u8>,
+    query: PyReadonlyArray<'_, f32, Ix2>,
+    k: usize,
+) -> Result<PyArrayData>
+{
+    let index = knn::AnnIndex::from_bytes(index.as_slice()?)?;
+    Ok(ArrayData::from(index.search(query.as_array(), k)).into())
+}
@@ -6,6 +6,20 @@ mod embedding;
 mod network;
 mod motif;
 mod knn;
+mod metrics;
+mod clustering;
+mod model;
+mod config;
+mod validation;
+mod provenance;
+mod pipeline;
+mod segmentation;
+mod nucleosome;
+mod footprint;
+#[cfg(feature = "onnx")]
+mod onnx;
+#[cfg(feature = "server")]
+mod server;
 
 use pyo3::{prelude::*, PyResult};
 use pyanndata;
@@ -33,42 +47,127 @@ fn _snapatac2(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(pyanndata::py_get_default_write_config, m)?)?;
     m.add_function(wrap_pyfunction!(pyanndata::py_set_default_write_config, m)?)?;
 
+    // Runtime configuration
+    m.add_function(wrap_pyfunction!(config::set_num_threads, m)?)?;
+    m.add_function(wrap_pyfunction!(config::get_num_threads, m)?)?;
+    m.add_function(wrap_pyfunction!(config::set_memory_limit, m)?)?;
+    m.add_function(wrap_pyfunction!(config::get_memory_limit, m)?)?;
+    m.add_function(wrap_pyfunction!(config::set_chunk_size, m)?)?;
+    m.add_function(wrap_pyfunction!(config::get_chunk_size, m)?)?;
+    m.add_function(wrap_pyfunction!(config::set_chrom_filter, m)?)?;
+    m.add_function(wrap_pyfunction!(config::get_chrom_filter, m)?)?;
+    m.add_function(wrap_pyfunction!(config::set_cell_mask, m)?)?;
+    m.add_function(wrap_pyfunction!(config::get_cell_mask, m)?)?;
+    m.add_function(wrap_pyfunction!(config::set_missing_chrom_policy, m)?)?;
+    m.add_function(wrap_pyfunction!(config::get_missing_chrom_policy, m)?)?;
+    m.add_function(wrap_pyfunction!(config::set_out_of_bounds_policy, m)?)?;
+    m.add_function(wrap_pyfunction!(config::get_out_of_bounds_policy, m)?)?;
+
     // Motif analysis related functions
     m.add_class::<motif::PyDNAMotif>().unwrap();
     m.add_class::<motif::PyDNAMotifScanner>().unwrap();
     m.add_class::<motif::PyDNAMotifTest>().unwrap();
+    m.add_class::<provenance::PyMemoryTracker>().unwrap();
     m.add_function(wrap_pyfunction!(motif::read_motifs, m)?)?;
+    m.add_function(wrap_pyfunction!(motif::kmer_counts, m)?)?;
+    m.add_function(wrap_pyfunction!(motif::gc_content, m)?)?;
  
     // Preprocessing related functions
     m.add_function(wrap_pyfunction!(preprocessing::make_fragment_file, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::import_fragments, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::simulate_fragments, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::convert_fragment_storage, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::compact_fragment_storage, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::sort_fragment_file, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::long_read_methylation, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::build_coverage_cache, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::coverage_cache_info, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::import_contacts, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::import_values, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::mk_tile_matrix, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::mk_window_matrix, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::recommend_bin_size, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::mk_gene_matrix, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::mk_peak_matrix, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::append_matrix_rows, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::reindex_vars, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::hstack, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::gc_correct, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::mk_region_bin_matrix, m)?)?;
 
     m.add_function(wrap_pyfunction!(preprocessing::tss_enrichment, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::tss_profile, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::add_frip, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::spot, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::peak_allocation, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::fragment_overlap_loci, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::signal_partition, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::fragment_size_distribution, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::summary_by_chrom, m)?)?;
 
     m.add_function(wrap_pyfunction!(export::export_fragments, m)?)?;
+    m.add_function(wrap_pyfunction!(export::export_group_fragments, m)?)?;
+    m.add_function(wrap_pyfunction!(export::map_barcodes, m)?)?;
     m.add_function(wrap_pyfunction!(export::export_coverage, m)?)?;
+    m.add_function(wrap_pyfunction!(export::export_coverage_from_cache, m)?)?;
+    m.add_function(wrap_pyfunction!(export::obs_group_labels, m)?)?;
+    m.add_function(wrap_pyfunction!(export::export_base_values, m)?)?;
+    m.add_function(wrap_pyfunction!(export::export_expected_bias, m)?)?;
+    m.add_function(wrap_pyfunction!(export::fit_tn5_bias, m)?)?;
     m.add_function(wrap_pyfunction!(export::get_coverage, m)?)?;
+    m.add_function(wrap_pyfunction!(export::base_coverage, m)?)?;
+    m.add_function(wrap_pyfunction!(export::combine_tracks, m)?)?;
+    m.add_function(wrap_pyfunction!(export::track_signal, m)?)?;
+    m.add_function(wrap_pyfunction!(export::track_correlation, m)?)?;
 
     m.add_function(wrap_pyfunction!(call_peaks::export_tags, m)?)?;
     m.add_function(wrap_pyfunction!(call_peaks::create_fwtrack_obj, m)?)?;
     m.add_function(wrap_pyfunction!(call_peaks::fetch_peaks, m)?)?;
     m.add_function(wrap_pyfunction!(call_peaks::py_merge_peaks, m)?)?;
     m.add_function(wrap_pyfunction!(call_peaks::find_reproducible_peaks, m)?)?;
+    m.add_function(wrap_pyfunction!(call_peaks::peak_score_tracks, m)?)?;
+    m.add_function(wrap_pyfunction!(segmentation::segment_genome, m)?)?;
+    m.add_function(wrap_pyfunction!(nucleosome::nucleosome_positions, m)?)?;
+    m.add_function(wrap_pyfunction!(nucleosome::vplot, m)?)?;
+    m.add_function(wrap_pyfunction!(footprint::footprint_counts, m)?)?;
+    m.add_function(wrap_pyfunction!(footprint::footprint_expected_bias, m)?)?;
     m.add_function(wrap_pyfunction!(call_peaks::call_peaks_bulk, m)?)?;
 
     m.add_function(wrap_pyfunction!(knn::nearest_neighbour_graph, m)?)?;
     m.add_function(wrap_pyfunction!(knn::approximate_nearest_neighbour_graph, m)?)?;
+    m.add_function(wrap_pyfunction!(knn::exact_nearest_neighbour_graph, m)?)?;
+    m.add_function(wrap_pyfunction!(knn::build_ann_index, m)?)?;
+    m.add_function(wrap_pyfunction!(knn::update_ann_index, m)?)?;
+    m.add_function(wrap_pyfunction!(knn::query_ann_index, m)?)?;
 
     m.add_function(wrap_pyfunction!(network::link_region_to_gene, m)?)?;
 
+    m.add_function(wrap_pyfunction!(metrics::kbet, m)?)?;
+    m.add_function(wrap_pyfunction!(metrics::lisi, m)?)?;
+    m.add_function(wrap_pyfunction!(metrics::bootstrap_stability, m)?)?;
+    m.add_function(wrap_pyfunction!(metrics::adjusted_rand_index, m)?)?;
+    m.add_function(wrap_pyfunction!(metrics::normalized_mutual_info, m)?)?;
+    m.add_function(wrap_pyfunction!(metrics::homogeneity_completeness_v_measure, m)?)?;
+    m.add_function(wrap_pyfunction!(metrics::silhouette, m)?)?;
+    m.add_function(wrap_pyfunction!(metrics::pseudobulk_qc, m)?)?;
+
+    m.add_function(wrap_pyfunction!(clustering::merge_clusters, m)?)?;
+    m.add_function(wrap_pyfunction!(clustering::rarity_score, m)?)?;
+
+    m.add_function(wrap_pyfunction!(model::save_reference_model, m)?)?;
+    m.add_function(wrap_pyfunction!(model::load_reference_model, m)?)?;
+    m.add_function(wrap_pyfunction!(model::project_to_reference, m)?)?;
+
+    m.add_function(wrap_pyfunction!(validation::validate, m)?)?;
+    m.add_function(wrap_pyfunction!(validation::repair, m)?)?;
+    m.add_function(wrap_pyfunction!(provenance::record_provenance, m)?)?;
+    m.add_function(wrap_pyfunction!(provenance::read_provenance, m)?)?;
+    m.add_function(wrap_pyfunction!(provenance::diff_provenance, m)?)?;
+    m.add_function(wrap_pyfunction!(pipeline::parse_recipe, m)?)?;
+    m.add_function(wrap_pyfunction!(pipeline::pipeline_completed_steps, m)?)?;
+    m.add_function(wrap_pyfunction!(pipeline::pipeline_resume_step, m)?)?;
+    m.add_function(wrap_pyfunction!(pipeline::mark_pipeline_step, m)?)?;
+
     m.add_function(wrap_pyfunction!(utils::aggregate_x, m)?)?;
     m.add_function(wrap_pyfunction!(utils::jaccard_similarity, m)?)?;
     m.add_function(wrap_pyfunction!(utils::cosine_similarity, m)?)?;
@@ -78,11 +177,25 @@ fn _snapatac2(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(utils::jm_regress, m)?)?;
     m.add_function(wrap_pyfunction!(utils::read_regions, m)?)?;
     m.add_function(wrap_pyfunction!(utils::intersect_bed, m)?)?;
+    m.add_function(wrap_pyfunction!(utils::variant_overlaps, m)?)?;
+    m.add_function(wrap_pyfunction!(utils::liftover, m)?)?;
     m.add_function(wrap_pyfunction!(utils::kmeans, m)?)?;
     m.add_function(wrap_pyfunction!(utils::total_size_of_peaks, m)?)?;
+    m.add_function(wrap_pyfunction!(utils::stratified_subsample, m)?)?;
+    m.add_function(wrap_pyfunction!(utils::geometric_sketch, m)?)?;
+    m.add_function(wrap_pyfunction!(utils::derive_seed, m)?)?;
+    m.add_function(wrap_pyfunction!(utils::query, m)?)?;
     m.add_function(wrap_pyfunction!(embedding::spectral_embedding, m)?)?;
     m.add_function(wrap_pyfunction!(embedding::multi_spectral_embedding, m)?)?;
     m.add_function(wrap_pyfunction!(embedding::spectral_embedding_nystrom, m)?)?;
+    m.add_function(wrap_pyfunction!(embedding::pca_embedding, m)?)?;
+    #[cfg(feature = "onnx")]
+    {
+        m.add_function(wrap_pyfunction!(onnx::onnx_embed_cells, m)?)?;
+        m.add_function(wrap_pyfunction!(onnx::onnx_embed_regions, m)?)?;
+    }
+    #[cfg(feature = "server")]
+    m.add_function(wrap_pyfunction!(server::serve_tiles, m)?)?;
 
     Ok(())
 }
@@ -0,0 +1,139 @@
+use crate::utils::{csr_to_rust, AnnDataLike};
+
+use anndata::Backend;
+use anndata_hdf5::H5;
+use anyhow::Result;
+use numpy::{IntoPyArray, Ix1, Ix2, PyArray, PyReadonlyArray};
+use pyo3::prelude::*;
+use snapatac2_core::feature_count::aggregator;
+use snapatac2_core::metrics;
+use std::ops::Deref;
+
+/// Compute the kBET rejection rate on a kNN graph.
+/// Returns the rejection rate and the per-cell p-values.
+#[pyfunction]
+pub(crate) fn kbet<'py>(
+    py: Python<'py>,
+    graph: &Bound<'py, PyAny>,
+    batch: Vec<String>,
+    alpha: f64,
+) -> Result<(f64, Bound<'py, PyArray<f64, Ix1>>)> {
+    let graph = csr_to_rust(graph)?;
+    let result = metrics::kbet(&graph, &batch, alpha)?;
+    Ok((result.rejection_rate, result.p_values.into_pyarray(py)))
+}
+
+/// Compute the Local Inverse Simpson's Index for each cell on a kNN graph.
+#[pyfunction]
+pub(crate) fn lisi<'py>(
+    py: Python<'py>,
+    graph: &Bound<'py, PyAny>,
+    labels: Vec<String>,
+    perplexity: f64,
+) -> Result<Bound<'py, PyArray<f64, Ix1>>> {
+    let graph = csr_to_rust(graph)?;
+    Ok(metrics::lisi(&graph, &labels, perplexity)?.into_pyarray(py))
+}
+
+/// Assess cluster stability by bootstrap resampling. `cluster_fn` is called
+/// with the indices of the drawn cells and the selected features and must
+/// return the cluster labels of the drawn cells.
+#[pyfunction]
+#[pyo3(signature = (reference, cluster_fn, n_iter, cell_fraction, n_features=None, feature_fraction=1.0, seed=2024))]
+pub(crate) fn bootstrap_stability<'py>(
+    py: Python<'py>,
+    reference: Vec<String>,
+    cluster_fn: Bound<'py, PyAny>,
+    n_iter: usize,
+    cell_fraction: f64,
+    n_features: Option<usize>,
+    feature_fraction: f64,
+    seed: u64,
+) -> Result<(Bound<'py, PyArray<f64, Ix1>>, Bound<'py, PyArray<f64, Ix1>>)> {
+    let options = metrics::BootstrapOptions {
+        n_iter,
+        cell_fraction,
+        features: n_features.map(|n| (n, feature_fraction)),
+        seed,
+    };
+    let result = metrics::bootstrap_stability(&reference, &options, |cells, features| {
+        let cells = cells.to_vec().into_pyarray(py);
+        let features = features.map(|x| x.to_vec().into_pyarray(py));
+        let labels: Vec<String> = cluster_fn.call1((cells, features))?.extract()?;
+        Ok(labels)
+    })?;
+    Ok((result.jaccard.into_pyarray(py), result.ari.into_pyarray(py)))
+}
+
+#[pyfunction]
+pub(crate) fn adjusted_rand_index(labels_true: Vec<String>, labels_pred: Vec<String>) -> Result<f64> {
+    metrics::adjusted_rand_index(&labels_true, &labels_pred)
+}
+
+#[pyfunction]
+pub(crate) fn normalized_mutual_info(labels_true: Vec<String>, labels_pred: Vec<String>) -> Result<f64> {
+    metrics::normalized_mutual_info(&labels_true, &labels_pred)
+}
+
+#[pyfunction]
+pub(crate) fn homogeneity_completeness_v_measure(
+    labels_true: Vec<String>,
+    labels_pred: Vec<String>,
+) -> Result<(f64, f64, f64)> {
+    metrics::homogeneity_completeness_v_measure(&labels_true, &labels_pred)
+}
+
+/// Compute the silhouette coefficient of every observation.
+#[pyfunction]
+#[pyo3(signature = (data, labels, chunk_size=1000))]
+pub(crate) fn silhouette<'py>(
+    py: Python<'py>,
+    data: PyReadonlyArray<'_, f64, Ix2>,
+    labels: Vec<String>,
+    chunk_size: usize,
+) -> Result<Bound<'py, PyArray<f64, Ix1>>> {
+    Ok(metrics::silhouette_samples(data.as_array(), &labels, chunk_size)?.into_pyarray(py))
+}
+
+/// Aggregate the cells of every sample into a pseudobulk profile, transform it
+/// to log2(CPM + 1), and compute the PCA and the pairwise correlations of the
+/// samples. Returns the sample names, the number of cells of every sample, the
+/// PCA scores, the explained variance ratios and the correlation matrix.
+#[pyfunction]
+#[pyo3(signature = (anndata, samples, n_comps, method, n_top_features=None))]
+pub(crate) fn pseudobulk_qc<'py>(
+    py: Python<'py>,
+    anndata: AnnDataLike,
+    samples: Vec<Option<String>>,
+    n_comps: usize,
+    method: &str,
+    n_top_features: Option<usize>,
+) -> Result<(
+    Vec<String>,
+    Vec<usize>,
+    Bound<'py, PyArray<f64, Ix2>>,
+    Vec<f64>,
+    Bound<'py, PyArray<f64, Ix2>>,
+)> {
+    let method = method.try_into()?;
+    macro_rules! run {
+        ($data:expr) => {
+            aggregator::aggregate_x(&$data, Some(&samples))?
+        };
+    }
+    let (names, mut mat) = crate::with_anndata!(&anndata, run);
+    let names = names.unwrap();
+    let n_cells = names
+        .iter()
+        .map(|x| samples.iter().filter(|s| s.as_deref() == Some(x.as_str())).count())
+        .collect();
+    metrics::log_cpm(&mut mat);
+    let qc = metrics::sample_qc(&mat, n_comps, n_top_features, method)?;
+    Ok((
+        names,
+        n_cells,
+        qc.pca.into_pyarray(py),
+        qc.variance_ratio,
+        qc.correlation.into_pyarray(py),
+    ))
+}
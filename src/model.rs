@@ -0,0 +1,105 @@
+use crate::utils::AnnDataLike;
+
+use anndata::Backend;
+use anndata_hdf5::H5;
+use anyhow::{ensure, Result};
+use numpy::{IntoPyArray, Ix2, PyArray, PyReadonlyArray1, PyReadonlyArray2};
+use pyo3::prelude::*;
+use snapatac2_core::model::{
+    BatchCorrectionModel, ClusterModel, EmbeddingModel, KnnModel, ReferenceModel,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Deref;
+
+/// Save a fitted reference model.
+#[pyfunction]
+#[pyo3(signature = (
+    filename, feature_names, method, basis, eigenvalues, mean=None, feature_weights=None,
+    reference=None, labels=None, n_neighbors=15, batch_method=None, batch_parameters=None,
+))]
+pub(crate) fn save_reference_model(
+    filename: &str,
+    feature_names: Vec<String>,
+    method: String,
+    basis: PyReadonlyArray2<'_, f64>,
+    eigenvalues: Vec<f64>,
+    mean: Option<PyReadonlyArray1<'_, f64>>,
+    feature_weights: Option<PyReadonlyArray1<'_, f64>>,
+    reference: Option<PyReadonlyArray2<'_, f64>>,
+    labels: Option<Vec<String>>,
+    n_neighbors: usize,
+    batch_method: Option<String>,
+    batch_parameters: Option<HashMap<String, PyReadonlyArray2<'_, f64>>>,
+) -> Result<()> {
+    let basis = basis.as_array();
+    ensure!(
+        basis.ncols() == feature_names.len(),
+        "the basis has {} columns, but {} feature names are given",
+        basis.ncols(),
+        feature_names.len(),
+    );
+    let mut model = ReferenceModel::new(feature_names);
+    model.embedding = Some(EmbeddingModel {
+        method,
+        feature_weights: feature_weights.map(|x| x.as_array().to_vec()),
+        mean: mean.map(|x| x.as_array().to_vec()),
+        basis: basis.into(),
+        eigenvalues,
+    });
+    if let Some(reference) = reference {
+        let reference = reference.as_array();
+        if let Some(labels) = labels.as_ref() {
+            model.clusters = Some(ClusterModel::from_labels(reference, labels)?);
+        }
+        model.knn = Some(KnnModel {
+            n_neighbors,
+            reference: reference.into(),
+            labels,
+        });
+    }
+    if let Some(method) = batch_method {
+        let parameters: BTreeMap<_, _> = batch_parameters
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(k, v)| (k, v.as_array().into()))
+            .collect();
+        model.batch_correction = Some(BatchCorrectionModel { method, parameters });
+    }
+    model.save(filename)
+}
+
+/// Read a reference model and return it as a JSON string.
+#[pyfunction]
+pub(crate) fn load_reference_model(filename: &str) -> Result<String> {
+    Ok(serde_json::to_string(&ReferenceModel::load(filename)?)?)
+}
+
+/// Project the cells in `anndata` onto a saved reference model.
+/// Returns the embedding and the label of every cell, given by a majority
+/// vote among its nearest labeled reference cells or, if the model has no
+/// labeled reference cells, by the nearest cluster centroid.
+#[pyfunction]
+#[pyo3(signature = (anndata, filename, chunk_size=5000))]
+pub(crate) fn project_to_reference<'py>(
+    py: Python<'py>,
+    anndata: AnnDataLike,
+    filename: &str,
+    chunk_size: usize,
+) -> Result<(Bound<'py, PyArray<f64, Ix2>>, Option<Vec<String>>)> {
+    let model = ReferenceModel::load(filename)?;
+    macro_rules! run {
+        ($data:expr) => {
+            model.transform_anndata($data, chunk_size)?
+        };
+    }
+    let embedding = crate::with_anndata!(&anndata, run);
+    let labels = match model.knn.as_ref().filter(|x| x.labels.is_some()) {
+        Some(knn) => Some(knn.predict(embedding.view())?),
+        None => model
+            .clusters
+            .as_ref()
+            .map(|x| x.predict(embedding.view()))
+            .transpose()?,
+    };
+    Ok((embedding.into_pyarray(py), labels))
+}
@@ -1,11 +1,16 @@
+use anndata::ArrayData;
+use bed_utils::bed::GenomicRange;
 use numpy::{PyArray2, PyArrayMethods, PyUntypedArrayMethods};
+use pyanndata::data::PyArrayData;
 use pyo3::{prelude::*, pybacked::PyBackedStr};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use statrs::distribution::{Binomial, DiscreteCDF};
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+use snapatac2_core::bias::{region_gc_content, region_kmer_counts, GenomeSequence};
 use snapatac2_core::motif;
 
 /** Python object representing DNA position weight matrix.
@@ -209,9 +214,11 @@ impl PyDNAMotifScanner {
     ///     A list of booleans indicating whether the motif exists in each sequence.
     #[pyo3(signature = (seqs, pvalue=1e-5, rc=true))]
     fn exists(&self, seqs: Vec<PyBackedStr>, pvalue: f64, rc: bool) -> Vec<bool> {
-        seqs.into_par_iter()
-            .map(|x| self.exist(x.as_ref(), pvalue, rc))
-            .collect()
+        snapatac2_core::config::install(None, || {
+            seqs.into_par_iter()
+                .map(|x| self.exist(x.as_ref(), pvalue, rc))
+                .collect()
+        })
     }
 
     /// Create a motif test object using background sequences.
@@ -235,10 +242,11 @@ impl PyDNAMotifScanner {
         PyDNAMotifTest {
             scanner: self.clone(),
             pvalue,
-            occurrence_background: seqs
-                .into_par_iter()
-                .filter(|x| self.exist(x, pvalue, true))
-                .count(),
+            occurrence_background: snapatac2_core::config::install(None, || {
+                seqs.into_par_iter()
+                    .filter(|x| self.exist(x, pvalue, true))
+                    .count()
+            }),
             total_background: n,
         }
     }
@@ -306,12 +314,12 @@ impl PyDNAMotifTest {
     #[pyo3(signature = (seqs))]
     fn test(&self, seqs: Vec<PyBackedStr>) -> (f64, f64) {
         let n = seqs.len().try_into().unwrap();
-        let occurrence: u64 = seqs
-            .into_par_iter()
-            .filter(|x| self.scanner.exist(x, self.pvalue, true))
-            .count()
-            .try_into()
-            .unwrap();
+        let occurrence = snapatac2_core::config::install(None, || {
+            seqs.into_par_iter()
+                .filter(|x| self.scanner.exist(x, self.pvalue, true))
+                .count()
+        });
+        let occurrence: u64 = occurrence.try_into().unwrap();
         let p = self.occurrence_background as f64 / self.total_background as f64;
         let log_fc = ((occurrence as f64 / n as f64) / p).log2();
         let bion = Binomial::new(p, n).unwrap();
@@ -349,3 +357,32 @@ pub(crate) fn read_motifs(filename: &str) -> Vec<PyDNAMotif> {
         .map(|x| PyDNAMotif(x))
         .collect()
 }
+
+/// Count the k-mers of every region. Returns the sparse region by k-mer
+/// matrix and the names of the k-mers.
+#[pyfunction]
+pub(crate) fn kmer_counts(
+    genome: PathBuf,
+    regions: Vec<String>,
+    k: usize,
+    collapse_strand: bool,
+) -> anyhow::Result<(PyArrayData, Vec<String>)> {
+    let regions = regions
+        .iter()
+        .map(|x| GenomicRange::from_str(x).map_err(|_| anyhow::anyhow!("invalid region: {}", x)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut genome = GenomeSequence::open(genome)?;
+    let (counts, names) = region_kmer_counts(&mut genome, &regions, k, collapse_strand)?;
+    Ok((ArrayData::from(counts).into(), names))
+}
+
+/// GC content of every region.
+#[pyfunction]
+pub(crate) fn gc_content(genome: PathBuf, regions: Vec<String>) -> anyhow::Result<Vec<f64>> {
+    let regions = regions
+        .iter()
+        .map(|x| GenomicRange::from_str(x).map_err(|_| anyhow::anyhow!("invalid region: {}", x)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut genome = GenomeSequence::open(genome)?;
+    region_gc_content(&mut genome, &regions)
+}
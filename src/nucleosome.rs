@@ -0,0 +1,145 @@
+use crate::utils::{read_genomic_ranges, AnnDataLike};
+use snapatac2_core::{
+    nucleosome::{fragment_profiles, vplots, Anchor, NucleosomeOptions},
+    utils,
+};
+
+use anndata::Backend;
+use anndata_hdf5::H5;
+use anyhow::{bail, Result};
+use bed_utils::bed::{BEDLike, BedGraph};
+use numpy::{IntoPyArray, Ix2, PyArray};
+use pyo3::{prelude::*, pybacked::PyBackedStr};
+use std::collections::HashMap;
+use std::io::Write;
+use std::ops::Deref;
+use std::path::PathBuf;
+
+/// Make a name usable as a file name: characters other than letters, digits,
+/// ".", "-" and "_" are replaced by "_".
+fn escape_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() || matches!(c, '.' | '-' | '_') { c } else { '_' })
+        .collect()
+}
+
+/// Compute the nucleosome occupancy and call the dyads in the given regions
+/// for every group of cells. For each group, the occupancy is written to
+/// `{prefix}{group}.occupancy.bedGraph` and the dyads to `{prefix}{group}.dyads.bed`,
+/// where the group name is escaped by [`escape_filename`]. Returns the paths
+/// of the two files for every group.
+#[pyfunction]
+#[pyo3(signature = (anndata, regions, group_by, dir, prefix, nfr_max, nuc_min, nuc_max,
+       smooth_sd, min_spacing, min_signal))]
+pub(crate) fn nucleosome_positions(
+    anndata: AnnDataLike,
+    regions: Bound<'_, PyAny>,
+    group_by: Vec<PyBackedStr>,
+    dir: PathBuf,
+    prefix: &str,
+    nfr_max: u64,
+    nuc_min: u64,
+    nuc_max: u64,
+    smooth_sd: f64,
+    min_spacing: u64,
+    min_signal: f64,
+) -> Result<HashMap<String, (PathBuf, PathBuf)>> {
+    let regions = read_genomic_ranges(&regions)?;
+    let group_by: Vec<&str> = group_by.iter().map(|x| x.as_ref()).collect();
+    let opts = NucleosomeOptions {
+        nfr_max,
+        nuc_min,
+        nuc_max,
+        smooth_sd,
+        min_spacing,
+        min_signal,
+    };
+    macro_rules! run {
+        ($data:expr) => {
+            fragment_profiles($data, &regions, &group_by, &opts)?
+        };
+    }
+    let profiles = crate::with_anndata!(&anndata, run);
+    std::fs::create_dir_all(&dir)?;
+
+    let mut filenames = HashMap::new();
+    for group in profiles.keys() {
+        let name = prefix.to_string() + &escape_filename(group);
+        if let Some(other) = filenames.insert(name.clone(), group) {
+            bail!("groups '{}' and '{}' have the same file name: {}", other, group, name);
+        }
+    }
+    profiles
+        .into_iter()
+        .map(|(group, profiles)| {
+            let name = prefix.to_string() + &escape_filename(&group);
+            let occupancy_file = dir.join(format!("{}.occupancy.bedGraph", name));
+            let dyad_file = dir.join(format!("{}.dyads.bed", name));
+            let mut occupancy_writer = utils::open_file_for_write(&occupancy_file, None, None)?;
+            let mut dyad_writer = utils::open_file_for_write(&dyad_file, None, None)?;
+            for profile in profiles {
+                // Consecutive positions with the same rounded occupancy are merged.
+                let mut prev: Option<BedGraph<f64>> = None;
+                for (i, x) in profile.occupancy(&opts).into_iter().enumerate() {
+                    let x = (x * 1000.0).round() / 1000.0;
+                    let pos = profile.region.start() + i as u64;
+                    match prev.as_mut() {
+                        Some(p) if p.value == x => p.set_end(pos + 1),
+                        _ => {
+                            if let Some(p) = prev.take() {
+                                writeln!(occupancy_writer, "{}", p)?;
+                            }
+                            prev = Some(BedGraph::new(profile.region.chrom(), pos, pos + 1, x));
+                        }
+                    }
+                }
+                if let Some(p) = prev {
+                    writeln!(occupancy_writer, "{}", p)?;
+                }
+                for dyad in profile.call_dyads(&opts) {
+                    writeln!(
+                        dyad_writer,
+                        "{}\t{}\t{}\t{:.4}\t{:.3}",
+                        dyad.chrom,
+                        dyad.pos,
+                        dyad.pos + 1,
+                        dyad.signal,
+                        dyad.occupancy
+                    )?;
+                }
+            }
+            occupancy_writer.finish()?;
+            dyad_writer.finish()?;
+            Ok((group, (occupancy_file, dyad_file)))
+        })
+        .collect()
+}
+
+/// Compute the V-plot, i.e., the counts of fragments by length and by offset
+/// of their midpoint from the anchors, of every group of cells. Anchors are
+/// given as (chromosome, position, is on the reverse strand).
+#[pyfunction]
+#[pyo3(signature = (anndata, anchors, group_by, flank, max_frag_length))]
+pub(crate) fn vplot<'py>(
+    py: Python<'py>,
+    anndata: AnnDataLike,
+    anchors: Vec<(String, u64, bool)>,
+    group_by: Vec<PyBackedStr>,
+    flank: u64,
+    max_frag_length: u64,
+) -> Result<HashMap<String, Bound<'py, PyArray<u32, Ix2>>>> {
+    let anchors: Vec<Anchor> = anchors
+        .into_iter()
+        .map(|(chrom, pos, reverse)| Anchor { chrom, pos, reverse })
+        .collect();
+    let group_by: Vec<&str> = group_by.iter().map(|x| x.as_ref()).collect();
+    macro_rules! run {
+        ($data:expr) => {
+            vplots($data, &anchors, &group_by, flank, max_frag_length)?
+        };
+    }
+    Ok(crate::with_anndata!(&anndata, run)
+        .into_iter()
+        .map(|(k, v)| (k, v.into_pyarray(py)))
+        .collect())
+}
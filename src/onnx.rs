@@ -0,0 +1,173 @@
+//! Inference with pretrained models in the ONNX format, e.g., scBasset or
+//! PeakVI exported from PyTorch. The inputs are assembled in Rust, one batch at
+//! a time, so that neither the count matrix nor the sequences are materialized
+//! in Python.
+
+use crate::utils::AnnDataLike;
+use snapatac2_core::bias::GenomeSequence;
+
+use anndata::{data::ArrayConvert, data::DynCsrMatrix, AnnDataOp, ArrayElemOp, Backend};
+use anndata_hdf5::H5;
+use anyhow::{anyhow, bail, Context, Result};
+use bed_utils::bed::{BEDLike, GenomicRange};
+use nalgebra_sparse::CsrMatrix;
+use ndarray::{s, Array2, Array3, ArrayD, Axis};
+use numpy::{IntoPyArray, PyArray2};
+use ort::session::Session;
+use ort::value::Tensor;
+use pyo3::prelude::*;
+use std::{ops::Deref, path::PathBuf, str::FromStr};
+
+struct Model {
+    session: Session,
+    input: String,
+    output: String,
+}
+
+impl Model {
+    fn open(path: &PathBuf, output: Option<String>) -> Result<Self> {
+        let session = Session::builder()?
+            .commit_from_file(path)
+            .with_context(|| format!("cannot load model: {}", path.display()))?;
+        if session.inputs.len() != 1 {
+            bail!("the model must have exactly one input, found {}", session.inputs.len());
+        }
+        let input = session.inputs[0].name.clone();
+        let output = match output {
+            Some(x) => {
+                if !session.outputs.iter().any(|o| o.name == x) {
+                    bail!("the model has no output named '{}'", x);
+                }
+                x
+            }
+            None => session
+                .outputs
+                .first()
+                .ok_or_else(|| anyhow!("the model has no output"))?
+                .name
+                .clone(),
+        };
+        Ok(Self { session, input, output })
+    }
+
+    /// Run the model on a batch, returning the output as a matrix with one row
+    /// per sample.
+    fn run(&mut self, batch: ArrayD<f32>) -> Result<Array2<f32>> {
+        let n = batch.shape()[0];
+        let outputs = self
+            .session
+            .run(ort::inputs![self.input.as_str() => Tensor::from_array(batch)?])?;
+        let embedding = outputs[self.output.as_str()].try_extract_array::<f32>()?;
+        if embedding.shape().first() != Some(&n) {
+            bail!("the first dimension of the output must be the batch size");
+        }
+        let dim = embedding.len() / n.max(1);
+        Ok(embedding.to_owned().into_shape_with_order((n, dim))?)
+    }
+}
+
+fn stack(rows: Vec<Array2<f32>>, dim: usize) -> Result<Array2<f32>> {
+    if rows.is_empty() {
+        return Ok(Array2::zeros((0, dim)));
+    }
+    let views: Vec<_> = rows.iter().map(|x| x.view()).collect();
+    Ok(ndarray::concatenate(Axis(0), &views)?)
+}
+
+/// Embed the cells by running the model on the rows of `.X`, given as a
+/// dense float32 matrix of shape `batch_size` x `n_vars`.
+#[pyfunction]
+#[pyo3(signature = (anndata, model, batch_size, output=None))]
+pub(crate) fn onnx_embed_cells<'py>(
+    py: Python<'py>,
+    anndata: AnnDataLike,
+    model: PathBuf,
+    batch_size: usize,
+    output: Option<String>,
+) -> Result<Bound<'py, PyArray2<f32>>> {
+    let mut model = Model::open(&model, output)?;
+    macro_rules! run {
+        ($data:expr) => {{
+            let n_vars = $data.n_vars();
+            let mut result = Vec::new();
+            for (chunk, _, _) in $data.x().iter::<DynCsrMatrix>(batch_size) {
+                let mat: CsrMatrix<f32> = chunk.try_convert()?;
+                let mut batch = Array2::<f32>::zeros((mat.nrows(), n_vars));
+                mat.triplet_iter().for_each(|(i, j, v)| batch[[i, j]] = *v);
+                result.push(model.run(batch.into_dyn())?);
+            }
+            let dim = result.first().map_or(0, |x| x.ncols());
+            stack(result, dim)?
+        }};
+    }
+    Ok(crate::with_anndata!(&anndata, run).into_pyarray(py))
+}
+
+/// One-hot encode `seq` into `out` of shape `len(seq)` x 4, with the columns
+/// A, C, G and T. Other bases are left as zeros.
+fn one_hot(seq: &[u8], mut out: ndarray::ArrayViewMut2<f32>) {
+    for (i, b) in seq.iter().enumerate() {
+        let j = match b {
+            b'A' | b'a' => 0,
+            b'C' | b'c' => 1,
+            b'G' | b'g' => 2,
+            b'T' | b't' => 3,
+            _ => continue,
+        };
+        out[[i, j]] = 1.0;
+    }
+}
+
+/// Embed the regions by running the model on their one-hot encoded sequences,
+/// given as a float32 array of shape `batch_size` x `window` x 4. Every region
+/// is resized to `window` bases around its center; bases beyond the ends of
+/// the chromosome are encoded as zeros.
+#[pyfunction]
+#[pyo3(signature = (genome, regions, window, model, batch_size, output=None))]
+pub(crate) fn onnx_embed_regions<'py>(
+    py: Python<'py>,
+    genome: PathBuf,
+    regions: Vec<String>,
+    window: u64,
+    model: PathBuf,
+    batch_size: usize,
+    output: Option<String>,
+) -> Result<Bound<'py, PyArray2<f32>>> {
+    let mut model = Model::open(&model, output)?;
+    let regions = regions
+        .iter()
+        .map(|x| GenomicRange::from_str(x).map_err(|_| anyhow::anyhow!("invalid region: {}", x)))
+        .collect::<Result<Vec<_>>>()?;
+    // Visit the regions by chromosome, so that every chromosome is read once.
+    let mut order: Vec<usize> = (0..regions.len()).collect();
+    order.sort_by(|a, b| regions[*a].chrom().cmp(regions[*b].chrom()));
+
+    let mut genome = GenomeSequence::open(genome)?;
+    let mut embeddings: Vec<(usize, Array2<f32>)> = Vec::new();
+    for idx in order.chunks(batch_size.max(1)) {
+        let mut batch = Array3::<f32>::zeros((idx.len(), window as usize, 4));
+        for (k, i) in idx.iter().enumerate() {
+            let region = &regions[*i];
+            let seq = genome.fetch(region.chrom())?;
+            let start = ((region.start() + region.end()) / 2) as i64 - (window / 2) as i64;
+            let from = start.max(0) as usize;
+            let to = ((start + window as i64).max(0) as usize).min(seq.len());
+            if from < to {
+                let offset = (from as i64 - start) as usize;
+                one_hot(
+                    &seq[from..to],
+                    batch.slice_mut(s![k, offset..offset + to - from, ..]),
+                );
+            }
+        }
+        let out = model.run(batch.into_dyn())?;
+        embeddings.extend(
+            idx.iter()
+                .copied()
+                .zip(out.outer_iter().map(|x| x.insert_axis(Axis(0)).to_owned())),
+        );
+    }
+    embeddings.sort_by_key(|x| x.0);
+    let dim = embeddings.first().map_or(0, |x| x.1.ncols());
+    Ok(stack(embeddings.into_iter().map(|x| x.1).collect(), dim)?.into_pyarray(py))
+}
@@ -0,0 +1,55 @@
+use crate::utils::AnnDataLike;
+
+use anndata::Backend;
+use anndata_hdf5::H5;
+use anyhow::Result;
+use pyo3::prelude::*;
+use snapatac2_core::pipeline::{self, Recipe};
+use std::ops::Deref;
+
+/// Parse and validate a recipe. Returns the recipe with default values
+/// filled in, together with the checkpoint keys of its steps, as JSON.
+#[pyfunction]
+pub(crate) fn parse_recipe(text: &str, format: &str) -> Result<String> {
+    let recipe = Recipe::parse(text, format)?;
+    let keys: Vec<String> = (0..recipe.steps.len()).map(|i| recipe.step_key(i)).collect();
+    Ok(serde_json::to_string(&serde_json::json!({
+        "input": recipe.input,
+        "steps": recipe.steps,
+        "keys": keys,
+    }))?)
+}
+
+#[pyfunction]
+pub(crate) fn pipeline_completed_steps(anndata: AnnDataLike) -> Result<Vec<String>> {
+    macro_rules! run {
+        ($data:expr) => {
+            pipeline::completed_steps($data)?
+        };
+    }
+    Ok(crate::with_anndata!(&anndata, run))
+}
+
+/// Index of the first step of `recipe` (in JSON) whose checkpoint is missing
+/// from, or does not match, the checkpoints stored in `anndata`.
+#[pyfunction]
+pub(crate) fn pipeline_resume_step(anndata: AnnDataLike, recipe: &str) -> Result<usize> {
+    let recipe = Recipe::parse(recipe, "json")?;
+    macro_rules! run {
+        ($data:expr) => {
+            pipeline::resume_step(&recipe, $data)?
+        };
+    }
+    Ok(crate::with_anndata!(&anndata, run))
+}
+
+#[pyfunction]
+pub(crate) fn mark_pipeline_step(anndata: AnnDataLike, index: usize, key: String) -> Result<()> {
+    macro_rules! run {
+        ($data:expr) => {
+            pipeline::mark_completed($data, index, key)?
+        };
+    }
+    crate::with_anndata!(&anndata, run);
+    Ok(())
+}
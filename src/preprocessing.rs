@@ -1,36 +1,49 @@
 use crate::utils::*;
 
-use anndata::Backend;
+use anndata::data::SelectInfoElem;
+use anndata::{AnnDataOp, ArrayData, ArrayElemOp, Backend};
+use anndata::backend::ScalarType;
 use anndata_hdf5::H5;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bed_utils::bed::BEDLike;
 use bed_utils::extsort::ExternalSorterBuilder;
 use bed_utils::{bed, bed::GenomicRange};
 use itertools::Itertools;
+use nalgebra_sparse::CsrMatrix;
 use num::rational::Ratio;
-use pyanndata::PyAnnData;
+use polars::prelude::{Column, DataFrame};
+use pyanndata::{data::PyArrayData, PyAnnData};
 use pyo3::{prelude::*, pybacked::PyBackedStr};
 use snapatac2_core::feature_count::ValueType;
-use snapatac2_core::preprocessing::{PairRead, SingleRead, SummaryType};
+use snapatac2_core::preprocessing::{LongRead, PairRead, SingleRead, SummaryType};
 use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Write};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::{collections::BTreeMap, collections::HashSet, ops::Deref, str::FromStr};
 
 use snapatac2_core::{
-    feature_count::{create_gene_matrix, create_peak_matrix, create_tile_matrix, BaseValue},
+    feature_count,
+    feature_count::{
+        create_gene_matrix, create_peak_matrix, create_region_bin_matrix, create_tile_matrix,
+        create_window_matrix, BaseValue, FragmentStorage,
+    },
     genome::TranscriptParserOptions,
     preprocessing,
-    preprocessing::{Contact, Fragment},
-    utils, QualityControl,
+    preprocessing::{Contact, DedupPolicy, Fragment},
+    simulation::{Simulation, SimulationOptions},
+    utils,
+    utils::checkpoint::Checkpoint,
+    QualityControl,
 };
 
 #[pyfunction]
 #[pyo3(signature = (
     bam_file, output_file, is_paired, shift_left, shift_right, chunk_size,
     barcode_tag=None, barcode_regex=None, umi_tag=None, umi_regex=None, mapq=None,
-    mitochondrial_dna=None, source=None, compression=None, compression_level=None, temp_dir=None
+    mitochondrial_dna=None, source=None, compression=None, compression_level=None, temp_dir=None,
+    dedup_policy=None,
 ))]
 pub(crate) fn make_fragment_file(
     bam_file: PathBuf,
@@ -49,6 +62,7 @@ pub(crate) fn make_fragment_file(
     compression: Option<&str>,
     compression_level: Option<u32>,
     temp_dir: Option<PathBuf>,
+    dedup_policy: Option<&str>,
 ) -> Result<HashMap<String, f64>> {
     fn parse_tag(tag: &str) -> [u8; 2] {
         let tag_b = tag.as_bytes();
@@ -66,6 +80,9 @@ pub(crate) fn make_fragment_file(
         barcode_regex,
         umi_tag.map(|x| parse_tag(x)),
         umi_regex,
+        dedup_policy
+            .map(|x| DedupPolicy::from_str(x).map_err(anyhow::Error::msg))
+            .transpose()?,
         shift_left,
         shift_right,
         mapq,
@@ -83,32 +100,174 @@ pub(crate) fn make_fragment_file(
         .collect())
 }
 
-fn read_fragments(fragment_file: &PathBuf, is_paired: bool) -> Box<dyn Iterator<Item = Fragment>> {
-    if is_paired {
-        Box::new(
-            bed::io::Reader::new(
-                utils::open_file_for_read(&fragment_file),
-                Some("#".to_string()),
-            )
-            .into_records::<PairRead>()
-            .map(|x| x.unwrap().into()),
-        )
-    } else {
-        Box::new(
-            bed::io::Reader::new(
-                utils::open_file_for_read(&fragment_file),
-                Some("#".to_string()),
-            )
-            .into_records::<SingleRead>()
-            .map(|x| x.unwrap().into()),
-        )
+/// The first parsing error encountered while reading a fragment file.
+type ParseErrorSlot = Arc<Mutex<Option<anyhow::Error>>>;
+
+/// Type of the records of a fragment file.
+#[derive(Debug, Clone, Copy)]
+enum ReadType {
+    Single,
+    Paired,
+    Long,
+}
+
+/// Read fragments from a file. Reading stops at the first malformed record,
+/// and the error is stored in `error`, so the caller can report it instead of
+/// panicking in the middle of the import.
+fn read_fragments(
+    fragment_file: &PathBuf,
+    read_type: ReadType,
+    error: &ParseErrorSlot,
+) -> Result<Box<dyn Iterator<Item = Fragment>>> {
+    fn stop_at_error<R, E, I>(
+        records: I,
+        file: PathBuf,
+        error: ParseErrorSlot,
+    ) -> impl Iterator<Item = Fragment>
+    where
+        R: Into<Fragment>,
+        E: std::fmt::Debug,
+        I: Iterator<Item = std::result::Result<R, E>>,
+    {
+        records.enumerate().map_while(move |(i, x)| match x {
+            Ok(x) => Some(x.into()),
+            Err(e) => {
+                *error.lock().unwrap() = Some(anyhow::anyhow!(
+                    "failed to parse record {} of {}: {:?}",
+                    i + 1,
+                    file.display(),
+                    e
+                ));
+                None
+            }
+        })
+    }
+
+    let reader = bed::io::Reader::new(
+        utils::open_file_for_read(&fragment_file)?,
+        Some("#".to_string()),
+    );
+    let fragments: Box<dyn Iterator<Item = Fragment>> = match read_type {
+        ReadType::Paired => Box::new(stop_at_error(
+            reader.into_records::<PairRead>(),
+            fragment_file.clone(),
+            error.clone(),
+        )),
+        ReadType::Single => Box::new(stop_at_error(
+            reader.into_records::<SingleRead>(),
+            fragment_file.clone(),
+            error.clone(),
+        )),
+        ReadType::Long => Box::new(stop_at_error(
+            reader.into_records::<LongRead>(),
+            fragment_file.clone(),
+            error.clone(),
+        )),
+    };
+    Ok(fragments)
+}
+
+/// Return the error stored by [`read_fragments`], if any.
+fn check_parse_error(error: &ParseErrorSlot) -> Result<()> {
+    match error.lock().unwrap().take() {
+        Some(e) => Err(e),
+        None => Ok(()),
     }
 }
 
+/// Sort fragments by cell barcode using an external sorter. Reading the sorted
+/// fragments stops at the first error, which is stored in `error`.
+fn sort_by_barcode<I>(
+    fragments: I,
+    tempdir: Option<PathBuf>,
+    error: &ParseErrorSlot,
+) -> Result<impl Iterator<Item = Fragment>>
+where
+    I: Iterator<Item = Fragment>,
+{
+    let mut sorter = ExternalSorterBuilder::new()
+        .with_chunk_size(snapatac2_core::config::buffer_size::<Fragment>(50000000))
+        .with_compression(2);
+    if let Some(tmp) = tempdir {
+        sorter = sorter.with_tmp_dir(tmp);
+    }
+    let sorted = sorter
+        .build()?
+        .sort_by(fragments, |a, b| a.name().cmp(&b.name()))?;
+    Ok(stop_at_sort_error(sorted, error.clone()))
+}
+
+/// Stop at the first error of the sorted items, storing it in `error`.
+fn stop_at_sort_error<T, E, I>(sorted: I, error: ParseErrorSlot) -> impl Iterator<Item = T>
+where
+    E: Into<anyhow::Error>,
+    I: Iterator<Item = std::result::Result<T, E>>,
+{
+    sorted.map_while(move |x| match x {
+        Ok(x) => Some(x),
+        Err(e) => {
+            let e: anyhow::Error = e.into();
+            *error.lock().unwrap() = Some(e.context("failed to read the sorted fragments"));
+            None
+        }
+    })
+}
+
+/// Sort fragments tagged with their modality by modality and then by cell
+/// barcode, see `preprocessing::split_modality`, dropping the fragments
+/// without a modality. Returns the sorted fragments, the number of fragments of
+/// every cell over all modalities, and the number of fragments dropped.
+fn sort_by_modality<I>(
+    fragments: I,
+    sep: &str,
+    tempdir: Option<PathBuf>,
+    error: &ParseErrorSlot,
+) -> Result<(Box<dyn Iterator<Item = Fragment>>, HashMap<String, u64>, usize)>
+where
+    I: Iterator<Item = Fragment>,
+{
+    fn key<'a>(x: &'a Fragment, sep: &str) -> Option<(&'a str, &'a str)> {
+        preprocessing::split_modality(x.name()?, sep).map(|(cell, modality)| (modality, cell))
+    }
+
+    let mut barcode_count = HashMap::new();
+    let mut n_untagged = 0;
+    let tagged = fragments.filter(|x| match key(x, sep) {
+        Some((_, cell)) => {
+            *barcode_count.entry(cell.to_string()).or_insert(0) += 1;
+            true
+        }
+        None => {
+            n_untagged += 1;
+            false
+        }
+    });
+    let mut sorter = ExternalSorterBuilder::new()
+        .with_chunk_size(snapatac2_core::config::buffer_size::<Fragment>(50000000))
+        .with_compression(2);
+    if let Some(tmp) = tempdir {
+        sorter = sorter.with_tmp_dir(tmp);
+    }
+    let sep = sep.to_string();
+    let sorted = sorter
+        .build()?
+        .sort_by(tagged, move |a, b| key(a, &sep).cmp(&key(b, &sep)))?;
+    let sorted = stop_at_sort_error(sorted, error.clone());
+    Ok((Box::new(sorted), barcode_count, n_untagged))
+}
+
+/// Import fragments into `anndata`.
+///
+/// If `checkpoint_dir` is provided, the cell barcodes passing the filters,
+/// the fragments sorted by barcode and every chunk of imported cells are
+/// saved in this directory, so that an interrupted import does not need to
+/// count and sort the fragments again, and resumes after the last imported
+/// chunk. The directory is removed once the import has finished.
 #[pyfunction]
 #[pyo3(signature = (
     anndata, fragment_file, is_paired, chrom_size, mitochondrial_dna, min_num_fragment,
-    fragment_is_sorted_by_name, chunk_size, white_list=None, tempdir=None
+    fragment_is_sorted_by_name, chunk_size, white_list=None, tempdir=None, checkpoint_dir=None,
+    contig_policy="keep", chain_file=None, long_reads=false, blacklist=None, modality_sep=None,
 ))]
 pub(crate) fn import_fragments(
     anndata: AnnDataLike,
@@ -121,62 +280,376 @@ pub(crate) fn import_fragments(
     chunk_size: usize,
     white_list: Option<HashSet<String>>,
     tempdir: Option<PathBuf>,
+    checkpoint_dir: Option<PathBuf>,
+    contig_policy: &str,
+    chain_file: Option<PathBuf>,
+    long_reads: bool,
+    blacklist: Option<PathBuf>,
+    modality_sep: Option<String>,
 ) -> Result<()> {
+    let blacklist = blacklist.map(read_region_map).transpose()?;
+    let contig_policy = preprocessing::ContigPolicy::new(contig_policy, chain_file.as_deref())?;
+    // Long reads are stored as paired-end fragments.
+    let (read_type, is_paired) = match (long_reads, is_paired) {
+        (true, _) => (ReadType::Long, true),
+        (false, true) => (ReadType::Paired, true),
+        (false, false) => (ReadType::Single, false),
+    };
     let mitochondrial_dna: HashSet<String> = mitochondrial_dna.into_iter().collect();
-    let final_white_list = if fragment_is_sorted_by_name || min_num_fragment <= 0 {
-        white_list
-    } else {
-        let mut barcode_count =
-            preprocessing::get_barcode_count(read_fragments(&fragment_file, is_paired));
-        let list: HashSet<String> = barcode_count
-            .drain()
-            .filter_map(|(k, v)| if v >= min_num_fragment { Some(k) } else { None })
-            .collect();
-        match white_list {
-            None => Some(list),
-            Some(x) => Some(list.intersection(&x).map(Clone::clone).collect()),
+    let parse_error = ParseErrorSlot::default();
+    let mut checkpoint = match checkpoint_dir {
+        None => None,
+        Some(dir) => {
+            let fingerprint = serde_json::json!({
+                "fragment_file": fragment_file,
+                "file_size": std::fs::metadata(&fragment_file)?.len(),
+                "is_paired": is_paired,
+                "long_reads": long_reads,
+                "min_num_fragment": min_num_fragment,
+                "fragment_is_sorted_by_name": fragment_is_sorted_by_name,
+                "white_list": white_list.as_ref().map(|x| x.iter().sorted().collect::<Vec<_>>()),
+                "modality_sep": modality_sep,
+            });
+            Some(Checkpoint::open(dir, &fingerprint.to_string())?)
         }
     };
-    let chrom_sizes = chrom_size.into_iter().collect();
-    let fragments = read_fragments(&fragment_file, is_paired);
-    let sorted_fragments: Box<dyn Iterator<Item = Fragment>> = if !fragment_is_sorted_by_name {
-        let mut sorter = ExternalSorterBuilder::new()
-            .with_chunk_size(50000000)
-            .with_compression(2);
-        if let Some(tmp) = tempdir {
-            sorter = sorter.with_tmp_dir(tmp);
+
+    // With `modality_sep`, the fragments of every modality are stored in their
+    // own layer. They are sorted by modality in a single pass over the file,
+    // which also counts the fragments of every cell to filter the cells.
+    if let Some(sep) = modality_sep.as_deref() {
+        let stage = "sorted_fragments.tsv.gz";
+        let saved_cells = checkpoint
+            .as_ref()
+            .filter(|c| c.is_completed(stage))
+            .map(|c| c.load::<HashSet<String>>("white_list"))
+            .transpose()?
+            .flatten();
+        let (cells, sorted_fragments) = match (saved_cells, checkpoint.as_mut()) {
+            (Some(cells), Some(c)) => (cells, read_fragments(&c.path(stage), read_type, &parse_error)?),
+            (_, c) => {
+                let (sorted, mut barcode_count, n_untagged) = sort_by_modality(
+                    read_fragments(&fragment_file, read_type, &parse_error)?,
+                    sep,
+                    tempdir,
+                    &parse_error,
+                )?;
+                check_parse_error(&parse_error)?;
+                if n_untagged > 0 {
+                    log::warn!("{} fragments without a modality are ignored.", n_untagged);
+                }
+                let cells: HashSet<String> = barcode_count
+                    .drain()
+                    .filter_map(|(k, v)| if v >= min_num_fragment { Some(k) } else { None })
+                    .collect();
+                let cells = match white_list {
+                    None => cells,
+                    Some(x) => cells.intersection(&x).map(Clone::clone).collect(),
+                };
+                match c {
+                    None => (cells, sorted),
+                    Some(c) => {
+                        c.save("white_list", &cells)?;
+                        let mut writer = utils::open_file_for_write(
+                            c.path(stage),
+                            Some(utils::Compression::Gzip),
+                            None,
+                        )?;
+                        for fragment in sorted {
+                            writeln!(writer, "{}", fragment)?;
+                        }
+                        writer.finish()?;
+                        check_parse_error(&parse_error)?;
+                        c.mark_completed(stage)?;
+                        (cells, read_fragments(&c.path(stage), read_type, &parse_error)?)
+                    }
+                }
+            }
+        };
+        let cells = cells.into_iter().sorted().collect::<Vec<_>>();
+        let fragments = sorted_fragments.filter_map(|mut x| {
+            let (cell, modality) = preprocessing::split_modality(x.name()?, sep)
+                .map(|(cell, modality)| (cell.to_string(), modality.to_string()))?;
+            x.set_barcode(Some(&cell));
+            contig_policy.apply(x).map(|x| (modality, x))
+        });
+        let chrom_sizes = contig_policy.chrom_sizes(&chrom_size.into_iter().collect());
+
+        macro_rules! run {
+            ($data:expr) => {
+                preprocessing::import_fragment_modalities(
+                    $data,
+                    fragments,
+                    is_paired,
+                    &mitochondrial_dna,
+                    &chrom_sizes,
+                    cells,
+                    blacklist.as_ref(),
+                    chunk_size,
+                )?
+            };
+        }
+
+        crate::with_anndata!(&anndata, run);
+        check_parse_error(&parse_error)?;
+        if let Some(c) = checkpoint {
+            c.finish()?;
+        }
+        return Ok(());
+    }
+
+    let saved_white_list = checkpoint
+        .as_ref()
+        .map(|c| c.load::<HashSet<String>>("white_list"))
+        .transpose()?
+        .flatten();
+    let final_white_list = match saved_white_list {
+        Some(x) => Some(x),
+        None if fragment_is_sorted_by_name || min_num_fragment <= 0 => white_list,
+        None => {
+            let mut barcode_count = preprocessing::get_barcode_count(read_fragments(
+                &fragment_file,
+                read_type,
+                &parse_error,
+            )?);
+            check_parse_error(&parse_error)?;
+            let list: HashSet<String> = barcode_count
+                .drain()
+                .filter_map(|(k, v)| if v >= min_num_fragment { Some(k) } else { None })
+                .collect();
+            let list = match white_list {
+                None => list,
+                Some(x) => list.intersection(&x).map(Clone::clone).collect(),
+            };
+            if let Some(c) = checkpoint.as_mut() {
+                c.save("white_list", &list)?;
+            }
+            Some(list)
+        }
+    };
+    let chrom_sizes = contig_policy.chrom_sizes(&chrom_size.into_iter().collect());
+    let sorted_fragments: Box<dyn Iterator<Item = Fragment>> = if fragment_is_sorted_by_name {
+        read_fragments(&fragment_file, read_type, &parse_error)?
+    } else if let Some(c) = checkpoint.as_mut() {
+        let stage = "sorted_fragments.tsv.gz";
+        if !c.is_completed(stage) {
+            let sorted = sort_by_barcode(
+                read_fragments(&fragment_file, read_type, &parse_error)?,
+                tempdir,
+                &parse_error,
+            )?;
+            check_parse_error(&parse_error)?;
+            let mut writer =
+                utils::open_file_for_write(c.path(stage), Some(utils::Compression::Gzip), None)?;
+            for fragment in sorted {
+                writeln!(writer, "{}", fragment)?;
+            }
+            writer.finish()?;
+            check_parse_error(&parse_error)?;
+            c.mark_completed(stage)?;
         }
-        Box::new(
-            sorter
-                .build()
-                .unwrap()
-                .sort_by(fragments, |a, b| a.name().cmp(&b.name()))
-                .unwrap()
-                .map(Result::unwrap),
-        )
+        read_fragments(&c.path(stage), read_type, &parse_error)?
     } else {
-        Box::new(fragments)
+        let sorted = sort_by_barcode(
+            read_fragments(&fragment_file, read_type, &parse_error)?,
+            tempdir,
+            &parse_error,
+        )?;
+        check_parse_error(&parse_error)?;
+        Box::new(sorted)
     };
 
     macro_rules! run {
         ($data:expr) => {
             preprocessing::import_fragments(
                 $data,
-                sorted_fragments,
+                sorted_fragments.filter_map(|x| contig_policy.apply(x)),
                 is_paired,
                 &mitochondrial_dna,
                 &chrom_sizes,
                 final_white_list.as_ref(),
+                blacklist.as_ref(),
                 min_num_fragment,
                 chunk_size,
+                checkpoint.as_mut(),
             )?
         };
     }
 
     crate::with_anndata!(&anndata, run);
+    check_parse_error(&parse_error)?;
+    if let Some(c) = checkpoint {
+        c.finish()?;
+    }
     Ok(())
 }
 
+/// Simulate single-cell ATAC-seq fragments and write them to a gzipped
+/// fragment file sorted by cell barcode.
+/// Returns the barcode, cell type, batch and doublet partner of every cell,
+/// and the simulated peaks.
+#[pyfunction]
+#[pyo3(signature = (
+    output_file, chrom_sizes, n_cells, n_cell_types, n_peaks, specific_peak_fraction,
+    peak_width, mean_depth, depth_sd, frip, doublet_rate, n_batches, batch_effect, seed,
+))]
+pub(crate) fn simulate_fragments(
+    output_file: PathBuf,
+    chrom_sizes: Vec<(String, u64)>,
+    n_cells: usize,
+    n_cell_types: usize,
+    n_peaks: usize,
+    specific_peak_fraction: f64,
+    peak_width: u64,
+    mean_depth: f64,
+    depth_sd: f64,
+    frip: f64,
+    doublet_rate: f64,
+    n_batches: usize,
+    batch_effect: f64,
+    seed: u64,
+) -> Result<(Vec<String>, Vec<usize>, Vec<usize>, Vec<Option<usize>>, Vec<String>)> {
+    let options = SimulationOptions {
+        n_cells,
+        n_cell_types,
+        n_peaks,
+        specific_peak_fraction,
+        peak_width,
+        mean_depth,
+        depth_sd,
+        frip,
+        doublet_rate,
+        n_batches,
+        batch_effect,
+        chrom_sizes: chrom_sizes.into_iter().collect(),
+        seed,
+    };
+    let sim = Simulation::new(options)?;
+    let mut writer =
+        utils::open_file_for_write(&output_file, Some(utils::Compression::Gzip), None)?;
+    for fragment in sim.fragments() {
+        writeln!(writer, "{}", fragment)?;
+    }
+    writer.finish()?;
+
+    let barcodes = sim.cells.iter().map(|x| x.barcode.clone()).collect();
+    let cell_types = sim.cells.iter().map(|x| x.cell_type).collect();
+    let batches = sim.cells.iter().map(|x| x.batch).collect();
+    let doublets = sim.cells.iter().map(|x| x.doublet_with).collect();
+    let peaks = sim.peaks.iter().map(|x| x.pretty_show()).collect();
+    Ok((barcodes, cell_types, batches, doublets, peaks))
+}
+
+/// Convert the fragments stored in `.obsm` to the given storage format
+/// ("v1" or "v2"). Returns false if they are already stored in this format.
+#[pyfunction]
+#[pyo3(signature = (anndata, format, chunk_size=2000, modality=None))]
+pub(crate) fn convert_fragment_storage(
+    anndata: AnnDataLike,
+    format: &str,
+    chunk_size: usize,
+    modality: Option<&str>,
+) -> Result<bool> {
+    let format = FragmentStorage::from_str(format).map_err(anyhow::Error::msg)?;
+    macro_rules! run {
+        ($data:expr) => {
+            feature_count::convert_fragment_storage($data, format, chunk_size, modality)
+        };
+    }
+    crate::with_anndata!(&anndata, run)
+}
+
+/// Rewrite the fragments stored in `.obsm` in coordinate-sorted chunks.
+/// Returns the number of cells whose fragments were reordered.
+#[pyfunction]
+#[pyo3(signature = (anndata, chunk_size=2000, modality=None))]
+pub(crate) fn compact_fragment_storage(
+    anndata: AnnDataLike,
+    chunk_size: usize,
+    modality: Option<&str>,
+) -> Result<usize> {
+    macro_rules! run {
+        ($data:expr) => {
+            feature_count::compact_fragment_storage($data, chunk_size, modality)
+        };
+    }
+    crate::with_anndata!(&anndata, run)
+}
+
+/// Sort a fragment file by coordinate, compress it with BGZF and index it with
+/// tabix. Returns the path of the index.
+#[pyfunction]
+#[pyo3(signature = (input, output, tempdir=None))]
+pub(crate) fn sort_fragment_file(
+    input: PathBuf,
+    output: PathBuf,
+    tempdir: Option<PathBuf>,
+) -> Result<PathBuf> {
+    preprocessing::sort_fragment_file(input, output, tempdir)
+}
+
+/// The number of methylation calls and the number of methylated calls of the
+/// long reads of every cell of `cells`, read from the `ML` tags of a fragment
+/// file. See [`preprocessing::count_methylation_calls`].
+#[pyfunction]
+#[pyo3(signature = (fragment_file, cells, modality_sep=None))]
+pub(crate) fn long_read_methylation(
+    fragment_file: PathBuf,
+    cells: Vec<String>,
+    modality_sep: Option<&str>,
+) -> Result<(Vec<u64>, Vec<u64>)> {
+    let reader = bed::io::Reader::new(
+        utils::open_file_for_read(&fragment_file)?,
+        Some("#".to_string()),
+    );
+    let mut error = None;
+    let reads = reader.into_records::<LongRead>().enumerate().map_while(|(i, x)| {
+        x.map_err(|e| {
+            error = Some(anyhow::anyhow!(
+                "failed to parse record {} of {}: {:?}",
+                i + 1,
+                fragment_file.display(),
+                e
+            ))
+        })
+        .ok()
+    });
+    let counts = preprocessing::count_methylation_calls(reads, modality_sep);
+    if let Some(e) = error {
+        return Err(e);
+    }
+    Ok(cells
+        .iter()
+        .map(|x| counts.get(x).copied().unwrap_or_default())
+        .unzip())
+}
+
+/// Build the coverage cache, i.e., the number of insertions of every cell in
+/// bins of `bin_size` bases. Returns the metadata of the cache as JSON.
+#[pyfunction]
+#[pyo3(signature = (anndata, bin_size=500))]
+pub(crate) fn build_coverage_cache(anndata: AnnDataLike, bin_size: usize) -> Result<String> {
+    macro_rules! run {
+        ($data:expr) => {
+            feature_count::build_coverage_cache($data, bin_size)
+        };
+    }
+    let info = crate::with_anndata!(&anndata, run)?;
+    Ok(serde_json::to_string(&info)?)
+}
+
+/// Metadata of the coverage cache as JSON, or None if there is no valid cache.
+#[pyfunction]
+pub(crate) fn coverage_cache_info(anndata: AnnDataLike) -> Result<Option<String>> {
+    macro_rules! run {
+        ($data:expr) => {
+            feature_count::coverage_cache_info($data)
+        };
+    }
+    let info = crate::with_anndata!(&anndata, run)?;
+    Ok(info.map(|x| serde_json::to_string(&x)).transpose()?)
+}
+
 #[pyfunction]
 #[pyo3(signature = (
     anndata, contact_file, chrom_size, fragment_is_sorted_by_name, bin_size, chunk_size, tempdir=None
@@ -195,26 +668,25 @@ pub(crate) fn import_contacts(
         .map(|(chr, s)| GenomicRange::new(chr, 0, s))
         .collect();
 
-    let contacts = BufReader::new(utils::open_file_for_read(&contact_file))
-        .lines()
-        .map(|r| Contact::from_str(&r.unwrap()).unwrap());
-    let sorted_contacts: Box<dyn Iterator<Item = Contact>> = if !fragment_is_sorted_by_name {
+    let mut parse_error = None;
+    let mut sort_error = None;
+    let sorted_contacts: Box<dyn Iterator<Item = Contact> + '_> = if !fragment_is_sorted_by_name {
         let mut sorter = ExternalSorterBuilder::new()
-            .with_chunk_size(50000000)
+            .with_chunk_size(snapatac2_core::config::buffer_size::<Contact>(50000000))
             .with_compression(2);
         if let Some(tmp) = tempdir {
             sorter = sorter.with_tmp_dir(tmp);
         }
-        Box::new(
-            sorter
-                .build()
-                .unwrap()
-                .sort_by(contacts, |a, b| a.barcode.cmp(&b.barcode))
-                .unwrap()
-                .map(Result::unwrap),
-        )
+        let mut error = None;
+        let sorted = sorter
+            .build()?
+            .sort_by(read_contacts(&contact_file, &mut error)?, |a, b| a.barcode.cmp(&b.barcode))?;
+        if let Some(e) = error {
+            return Err(e);
+        }
+        Box::new(sorted.map_while(|x| x.map_err(|e| sort_error = Some(e.into())).ok()))
     } else {
-        Box::new(contacts)
+        Box::new(read_contacts(&contact_file, &mut parse_error)?)
     };
 
     macro_rules! run {
@@ -230,9 +702,30 @@ pub(crate) fn import_contacts(
     }
 
     crate::with_anndata!(&anndata, run);
+    if let Some(e) = parse_error.or(sort_error) {
+        return Err(e);
+    }
     Ok(())
 }
 
+/// Read the contacts in a file, stopping at the first invalid line, which is
+/// stored in `error`.
+fn read_contacts<'a>(
+    path: &'a PathBuf,
+    error: &'a mut Option<anyhow::Error>,
+) -> Result<impl Iterator<Item = Contact> + 'a> {
+    Ok(BufReader::new(utils::open_file_for_read(path)?)
+        .lines()
+        .enumerate()
+        .map_while(move |(i, line)| {
+            line.map_err(anyhow::Error::from)
+                .and_then(|x| Contact::from_str(&x).map_err(|e| anyhow::anyhow!("{:?}", e)))
+                .with_context(|| format!("failed to parse line {} of {}", i + 1, path.display()))
+                .map_err(|e| *error = Some(e))
+                .ok()
+        }))
+}
+
 #[pyfunction]
 #[pyo3(signature = (anndata, input_dir, chrom_size, chunk_size, white_list=None))]
 pub(crate) fn import_values(
@@ -242,7 +735,7 @@ pub(crate) fn import_values(
     chunk_size: usize,
     white_list: Option<HashSet<String>>,
 ) -> Result<()> {
-    fn read_chrom_values(path: PathBuf) -> impl Iterator<Item = (String, BaseValue)> {
+    fn read_chrom_values(path: PathBuf) -> Result<impl Iterator<Item = (String, BaseValue)>> {
         let barcode = if path.ends_with(".gz") {
             <OsStr as AsRef<std::path::Path>>::as_ref(path.file_stem().unwrap()).file_stem()
         } else {
@@ -252,8 +745,8 @@ pub(crate) fn import_values(
         .to_str()
         .unwrap()
         .to_string();
-        let reader = BufReader::new(utils::open_file_for_read(&path));
-        reader.lines().skip(1).map(move |line| {
+        let reader = BufReader::new(utils::open_file_for_read(&path)?);
+        Ok(reader.lines().skip(1).map(move |line| {
             let line = line.unwrap();
             let mut parts = line.split_whitespace();
             let chrom = parts.next().unwrap();
@@ -263,12 +756,20 @@ pub(crate) fn import_values(
             let value =
                 BaseValue::from_ratio(chrom, pos, Ratio::new_raw(methyl, unmethyl + methyl));
             (barcode.clone(), value)
-        })
+        }))
     }
 
+    // Files are opened one at a time; reading stops at the first file that
+    // cannot be opened.
+    let mut error = None;
     let sorted_values = std::fs::read_dir(input_dir)?
-        .map(|x| x.unwrap().path())
-        .flat_map(read_chrom_values);
+        .map_while(|x| {
+            x.map_err(anyhow::Error::from)
+                .and_then(|x| read_chrom_values(x.path()))
+                .map_err(|e| error = Some(e))
+                .ok()
+        })
+        .flatten();
     let chrom_sizes = chrom_size.into_iter().collect();
 
     macro_rules! run {
@@ -284,13 +785,16 @@ pub(crate) fn import_values(
     }
 
     crate::with_anndata!(&anndata, run);
+    if let Some(e) = error {
+        return Err(e);
+    }
     Ok(())
 }
 
 #[pyfunction]
 #[pyo3(signature = (
     anndata, bin_size, chunk_size, strategy, val_type, summuary_type, exclude_chroms=None,
-    min_fragment_size=None, max_fragment_size=None, out=None
+    min_fragment_size=None, max_fragment_size=None, out=None, cell_weights=None, modality=None
 ))]
 pub(crate) fn mk_tile_matrix(
     anndata: AnnDataLike,
@@ -303,6 +807,8 @@ pub(crate) fn mk_tile_matrix(
     min_fragment_size: Option<u64>,
     max_fragment_size: Option<u64>,
     out: Option<AnnDataLike>,
+    cell_weights: Option<Vec<f64>>,
+    modality: Option<&str>,
 ) -> Result<()> {
     let exclude_chroms = exclude_chroms
         .as_ref()
@@ -322,6 +828,8 @@ pub(crate) fn mk_tile_matrix(
                             strategy.try_into()?,
                             str_to_value_type(val_type),
                             str_to_summary_type(summuary_type),
+                            cell_weights.as_deref(),
+                            modality,
                             Some($out_data),
                         )?
                     };
@@ -338,6 +846,104 @@ pub(crate) fn mk_tile_matrix(
                     strategy.try_into()?,
                     str_to_value_type(val_type),
                     str_to_summary_type(summuary_type),
+                    cell_weights.as_deref(),
+                    modality,
+                    None::<&PyAnnData>,
+                )?;
+            }
+        };
+    }
+
+    crate::with_anndata!(&anndata, run);
+    Ok(())
+}
+
+/// Recommend a tile size from the density of the tiles of the sampled cells,
+/// see `feature_count::tile_density`. Returns the bin size and the median
+/// density of every candidate.
+#[pyfunction]
+#[pyo3(signature = (anndata, candidates, target_density, max_cells, exclude_chroms=None))]
+pub(crate) fn recommend_bin_size(
+    anndata: AnnDataLike,
+    candidates: Vec<usize>,
+    target_density: f64,
+    max_cells: usize,
+    exclude_chroms: Option<Vec<PyBackedStr>>,
+) -> Result<(usize, Vec<(usize, f64)>)> {
+    let exclude_chroms = exclude_chroms
+        .as_ref()
+        .map(|s| s.iter().map(|x| x.as_ref()).collect::<Vec<_>>());
+    macro_rules! run {
+        ($data:expr) => {
+            feature_count::tile_density(
+                $data,
+                &candidates,
+                max_cells,
+                exclude_chroms.as_ref().map(|x| x.as_slice()),
+            )?
+        };
+    }
+    let densities = crate::with_anndata!(&anndata, run);
+    Ok((feature_count::choose_bin_size(&densities, target_density), densities))
+}
+
+#[pyfunction]
+#[pyo3(signature = (
+    anndata, window_size, step, chunk_size, strategy, val_type, summuary_type, exclude_chroms=None,
+    min_fragment_size=None, max_fragment_size=None, out=None, cell_weights=None
+))]
+pub(crate) fn mk_window_matrix(
+    anndata: AnnDataLike,
+    window_size: u64,
+    step: u64,
+    chunk_size: usize,
+    strategy: &str,
+    val_type: &str,
+    summuary_type: &str,
+    exclude_chroms: Option<Vec<PyBackedStr>>,
+    min_fragment_size: Option<u64>,
+    max_fragment_size: Option<u64>,
+    out: Option<AnnDataLike>,
+    cell_weights: Option<Vec<f64>>,
+) -> Result<()> {
+    let exclude_chroms = exclude_chroms
+        .as_ref()
+        .map(|s| s.iter().map(|x| x.as_ref()).collect::<Vec<_>>());
+    macro_rules! run {
+        ($data:expr) => {
+            if let Some(out) = out {
+                macro_rules! run2 {
+                    ($out_data:expr) => {
+                        create_window_matrix(
+                            $data,
+                            window_size,
+                            step,
+                            chunk_size,
+                            exclude_chroms.as_ref().map(|x| x.as_slice()),
+                            min_fragment_size,
+                            max_fragment_size,
+                            strategy.try_into()?,
+                            str_to_value_type(val_type),
+                            str_to_summary_type(summuary_type),
+                            cell_weights.as_deref(),
+                            Some($out_data),
+                        )?
+                    };
+                }
+                crate::with_anndata!(&out, run2);
+            } else {
+                create_window_matrix(
+                    $data,
+                    window_size,
+                    step,
+                    chunk_size,
+                    exclude_chroms.as_ref().map(|x| x.as_slice()),
+                    min_fragment_size,
+                    max_fragment_size,
+                    strategy.try_into()?,
+                    str_to_value_type(val_type),
+                    str_to_summary_type(summuary_type),
+                    cell_weights.as_deref(),
                     None::<&PyAnnData>,
                 )?;
             }
@@ -369,7 +975,7 @@ fn str_to_summary_type(ty: &str) -> SummaryType {
 #[pyfunction]
 #[pyo3(signature = (
     anndata, peaks, chunk_size, use_x, strategy, val_type, summuary_type,
-    min_fragment_size=None, max_fragment_size=None, out=None
+    min_fragment_size=None, max_fragment_size=None, out=None, cell_weights=None, modality=None
 ))]
 pub(crate) fn mk_peak_matrix(
     anndata: AnnDataLike,
@@ -382,6 +988,8 @@ pub(crate) fn mk_peak_matrix(
     min_fragment_size: Option<u64>,
     max_fragment_size: Option<u64>,
     out: Option<AnnDataLike>,
+    cell_weights: Option<Vec<f64>>,
+    modality: Option<&str>,
 ) -> Result<()> {
     let peaks = peaks
         .try_iter()?
@@ -401,6 +1009,8 @@ pub(crate) fn mk_peak_matrix(
                             str_to_summary_type(summuary_type),
                             min_fragment_size,
                             max_fragment_size,
+                            cell_weights.as_deref(),
+                            modality,
                             Some($out_data),
                             use_x,
                         )?
@@ -417,6 +1027,8 @@ pub(crate) fn mk_peak_matrix(
                     str_to_summary_type(summuary_type),
                     min_fragment_size,
                     max_fragment_size,
+                    cell_weights.as_deref(),
+                    modality,
                     None::<&PyAnnData>,
                     use_x,
                 )?;
@@ -427,11 +1039,239 @@ pub(crate) fn mk_peak_matrix(
     Ok(())
 }
 
+/// Write to `out` the rows of `matrix` followed by the counts of the cells of
+/// `anndata` in the features of `matrix`.
+#[pyfunction]
+#[pyo3(signature = (
+    anndata, matrix, out, chunk_size, strategy, min_fragment_size=None, max_fragment_size=None
+))]
+pub(crate) fn append_matrix_rows(
+    anndata: AnnDataLike,
+    matrix: AnnDataLike,
+    out: AnnDataLike,
+    chunk_size: usize,
+    strategy: &str,
+    min_fragment_size: Option<u64>,
+    max_fragment_size: Option<u64>,
+) -> Result<()> {
+    macro_rules! run {
+        ($data:expr) => {{
+            macro_rules! run2 {
+                ($matrix:expr) => {{
+                    macro_rules! run3 {
+                        ($out_data:expr) => {
+                            feature_count::append_matrix_rows(
+                                $data,
+                                $matrix,
+                                chunk_size,
+                                strategy.try_into()?,
+                                min_fragment_size,
+                                max_fragment_size,
+                                $out_data,
+                            )?
+                        };
+                    }
+                    crate::with_anndata!(&out, run3)
+                }};
+            }
+            crate::with_anndata!(&matrix, run2)
+        }};
+    }
+    crate::with_anndata!(&anndata, run);
+    Ok(())
+}
+
+/// Map the cell by region matrix in `.X` onto `regions` and store the result
+/// in `out`.
+#[pyfunction]
+#[pyo3(signature = (anndata, regions, policy, chunk_size, out))]
+pub(crate) fn reindex_vars(
+    anndata: AnnDataLike,
+    regions: Vec<String>,
+    policy: &str,
+    chunk_size: usize,
+    out: AnnDataLike,
+) -> Result<()> {
+    let regions = regions
+        .iter()
+        .map(|x| GenomicRange::from_str(x).map_err(|_| anyhow::anyhow!("invalid region: {}", x)))
+        .collect::<Result<Vec<_>>>()?;
+    let policy = feature_count::ReindexPolicy::try_from(policy)?;
+    macro_rules! run {
+        ($data:expr) => {{
+            macro_rules! run2 {
+                ($out_data:expr) => {
+                    feature_count::reindex_vars($data, &regions, policy, chunk_size, $out_data)?
+                };
+            }
+            crate::with_anndata!(&out, run2)
+        }};
+    }
+    crate::with_anndata!(&anndata, run);
+    Ok(())
+}
+
+/// Concatenate the matrices in `.X` of `inputs` column-wise and store the
+/// result in `out`. `.var` records the feature space and the original name of
+/// every feature.
+#[pyfunction]
+#[pyo3(signature = (inputs, keys, out, chunk_size=2000, prefix=true))]
+pub(crate) fn hstack(
+    inputs: Vec<AnnDataLike>,
+    keys: Vec<String>,
+    out: AnnDataLike,
+    chunk_size: usize,
+    prefix: bool,
+) -> Result<()> {
+    anyhow::ensure!(!inputs.is_empty(), "no matrices to concatenate");
+    anyhow::ensure!(inputs.len() == keys.len(), "the number of keys must match the number of matrices");
+    macro_rules! describe {
+        ($data:expr) => {
+            (
+                $data.obs_names().into_vec(),
+                $data.var_names().into_vec(),
+                $data.x().dtype().and_then(|x| x.scalar_type()),
+            )
+        };
+    }
+    let info: Vec<_> = inputs.iter().map(|x| crate::with_anndata!(x, describe)).collect();
+    let obs_names = info[0].0.clone();
+    for (key, (names, _, dtype)) in keys.iter().zip(&info) {
+        anyhow::ensure!(dtype.is_some(), "'{}' has no X matrix", key);
+        anyhow::ensure!(*names == obs_names, "the cells of '{}' differ from those of '{}'", key, keys[0]);
+    }
+    let names: Vec<_> = info.iter().map(|x| x.1.clone()).collect();
+    let var_names = feature_count::stacked_var_names(&keys, &names, prefix)?;
+    let integer = info
+        .iter()
+        .all(|x| matches!(x.2, Some(ScalarType::U8 | ScalarType::U16 | ScalarType::U32)));
+
+    let n_obs = obs_names.len();
+    let n_vars = var_names.len();
+    let mut error = None;
+    let chunks = (0..n_obs).step_by(chunk_size.max(1)).map_while(|start| {
+        let rows: Vec<usize> = (start..(start + chunk_size).min(n_obs)).collect();
+        let result = inputs
+            .iter()
+            .zip(&keys)
+            .map(|(x, key)| {
+                macro_rules! slice {
+                    ($data:expr) => {
+                        $data.x().slice_axis::<ArrayData, _>(0, SelectInfoElem::from(rows.clone()))
+                    };
+                }
+                let mat = crate::with_anndata!(x, slice)?
+                    .with_context(|| format!("'{}' has no X matrix", key))?;
+                feature_count::to_csr(mat)
+            })
+            .collect::<Result<Vec<_>>>()
+            .and_then(|mats| feature_count::hstack_csr(&mats));
+        match result {
+            Ok(mat) if integer => {
+                let (offsets, indices, values) = mat.disassemble();
+                let values: Vec<u32> = values.into_iter().map(|x| x as u32).collect();
+                let mat = CsrMatrix::try_from_csr_data(offsets.len() - 1, n_vars, offsets, indices, values)
+                    .unwrap();
+                Some(ArrayData::from(mat))
+            }
+            Ok(mat) => Some(ArrayData::from(mat)),
+            Err(e) => {
+                error = Some(e);
+                None
+            }
+        }
+    });
+
+    let sources: Vec<&str> = keys
+        .iter()
+        .zip(&names)
+        .flat_map(|(k, x)| std::iter::repeat(k.as_str()).take(x.len()))
+        .collect();
+    let features: Vec<&str> = names.iter().flatten().map(|x| x.as_str()).collect();
+    let var = DataFrame::new(vec![
+        Column::new("source".into(), sources),
+        Column::new("feature".into(), features),
+    ])?;
+    macro_rules! run {
+        ($out:expr) => {{
+            $out.set_n_vars(n_vars)?;
+            snapatac2_core::recovery::guarded($out, snapatac2_core::recovery::Element::X, || {
+                $out.set_x_from_iter(chunks)
+            })?;
+            if let Some(e) = error {
+                return Err(e);
+            }
+            $out.set_obs_names(obs_names.into())?;
+            $out.set_var_names(var_names.into())?;
+            $out.set_var(var)?;
+            snapatac2_core::provenance::record(
+                $out,
+                "hstack",
+                serde_json::json!({"keys": keys, "prefix": prefix}),
+            );
+        }};
+    }
+    crate::with_anndata!(&out, run);
+    Ok(())
+}
+
+/// Correct `.X` for the GC content of the features and store the result in
+/// `.layers[key_added]`. Returns the fold change removed from every feature.
+#[pyfunction]
+#[pyo3(signature = (anndata, gc, span=0.3, chunk_size=2000, key_added="gc_corrected"))]
+pub(crate) fn gc_correct(
+    anndata: AnnDataLike,
+    gc: Vec<f64>,
+    span: f64,
+    chunk_size: usize,
+    key_added: &str,
+) -> Result<Vec<f64>> {
+    macro_rules! run {
+        ($data:expr) => {
+            feature_count::gc_correct($data, &gc, span, chunk_size, key_added)
+        };
+    }
+    crate::with_anndata!(&anndata, run)
+}
+
+/// Cell by bin coverage of the given regions, returned in memory together
+/// with the names of the bins.
+#[pyfunction]
+#[pyo3(signature = (anndata, regions, bin_size, strategy, min_fragment_size=None, max_fragment_size=None))]
+pub(crate) fn mk_region_bin_matrix(
+    anndata: AnnDataLike,
+    regions: Vec<String>,
+    bin_size: u64,
+    strategy: &str,
+    min_fragment_size: Option<u64>,
+    max_fragment_size: Option<u64>,
+) -> Result<(PyArrayData, Vec<String>)> {
+    let regions: Vec<GenomicRange> = regions
+        .iter()
+        .map(|x| GenomicRange::from_str(x).unwrap())
+        .collect();
+
+    macro_rules! run {
+        ($data:expr) => {
+            create_region_bin_matrix(
+                $data,
+                &regions,
+                bin_size,
+                strategy.try_into()?,
+                min_fragment_size,
+                max_fragment_size,
+            )?
+        };
+    }
+    let (mat, names) = crate::with_anndata!(&anndata, run);
+    Ok((ArrayData::from(mat).into(), names))
+}
+
 #[pyfunction]
 #[pyo3(signature = (
     anndata, gff_file, chunk_size, use_x, id_type, upstream, downstream, include_gene_body,
     transcript_name_key, transcript_id_key, gene_name_key, gene_id_key, strategy,
-    min_fragment_size=None, max_fragment_size=None, out=None
+    min_fragment_size=None, max_fragment_size=None, out=None, cell_weights=None, modality=None
 ))]
 pub(crate) fn mk_gene_matrix(
     anndata: AnnDataLike,
@@ -450,6 +1290,8 @@ pub(crate) fn mk_gene_matrix(
     min_fragment_size: Option<u64>,
     max_fragment_size: Option<u64>,
     out: Option<AnnDataLike>,
+    cell_weights: Option<Vec<f64>>,
+    modality: Option<&str>,
 ) -> Result<()> {
     let options = TranscriptParserOptions {
         transcript_name_key,
@@ -457,7 +1299,7 @@ pub(crate) fn mk_gene_matrix(
         gene_name_key,
         gene_id_key,
     };
-    let transcripts = read_transcripts(gff_file, &options);
+    let transcripts = read_transcripts(gff_file, &options)?;
     macro_rules! run {
         ($data:expr) => {
             if let Some(out) = out {
@@ -474,6 +1316,8 @@ pub(crate) fn mk_gene_matrix(
                             strategy.try_into()?,
                             min_fragment_size,
                             max_fragment_size,
+                            cell_weights.as_deref(),
+                            modality,
                             Some($out_data),
                             use_x,
                         )?
@@ -492,6 +1336,8 @@ pub(crate) fn mk_gene_matrix(
                     strategy.try_into()?,
                     min_fragment_size,
                     max_fragment_size,
+                    cell_weights.as_deref(),
+                    modality,
                     None::<&PyAnnData>,
                     use_x,
                 )?;
@@ -516,7 +1362,7 @@ pub(crate) fn tss_enrichment<'py>(
         Some(chrs) => chrs.into_iter().collect(),
         None => HashSet::new(),
     };
-    let tss = preprocessing::read_tss(utils::open_file_for_read(gtf_file))
+    let tss = preprocessing::read_tss(utils::open_file_for_read(gtf_file)?)
         .unique()
         .filter(|(chr, _, _)| !exclude_chroms.contains(chr));
     let promoters = preprocessing::TssRegions::new(tss, 2000);
@@ -542,6 +1388,102 @@ pub(crate) fn tss_enrichment<'py>(
     Ok(result)
 }
 
+#[pyfunction]
+#[pyo3(signature = (anndata, gtf_file, window_size, bin_size, key, exclude_chroms=None))]
+pub(crate) fn tss_profile(
+    anndata: AnnDataLike,
+    gtf_file: PathBuf,
+    window_size: u64,
+    bin_size: usize,
+    key: &str,
+    exclude_chroms: Option<Vec<String>>,
+) -> Result<Vec<(i64, i64)>> {
+    let exclude_chroms = match exclude_chroms {
+        Some(chrs) => chrs.into_iter().collect(),
+        None => HashSet::new(),
+    };
+    let tss = preprocessing::read_tss(utils::open_file_for_read(gtf_file)?)
+        .unique()
+        .filter(|(chr, _, _)| !exclude_chroms.contains(chr));
+    let promoters = preprocessing::TssRegions::new(tss, window_size);
+
+    macro_rules! run {
+        ($data:expr) => {
+            $data.tss_profile(&promoters, bin_size, key)
+        };
+    }
+    crate::with_anndata!(&anndata, run)
+}
+
+/// Fraction of fragments in promoters, exons, distal peaks and blacklisted
+/// regions. Distal peaks are the peaks that do not overlap any promoter.
+#[pyfunction]
+#[pyo3(signature = (anndata, gtf_file, promoter_size, count_as_insertion, peaks=None,
+       blacklist=None, exclude_chroms=None))]
+pub(crate) fn signal_partition(
+    anndata: AnnDataLike,
+    gtf_file: PathBuf,
+    promoter_size: u64,
+    count_as_insertion: bool,
+    peaks: Option<Vec<String>>,
+    blacklist: Option<Vec<String>>,
+    exclude_chroms: Option<Vec<String>>,
+) -> Result<BTreeMap<String, Vec<f64>>> {
+    let exclude_chroms: HashSet<_> = exclude_chroms.unwrap_or_default().into_iter().collect();
+    let promoters: bed::map::GIntervalMap<()> =
+        preprocessing::read_tss(utils::open_file_for_read(&gtf_file)?)
+            .unique()
+            .filter(|(chr, _, _)| !exclude_chroms.contains(chr))
+            .map(|(chr, tss, _)| {
+                let region =
+                    GenomicRange::new(chr, tss.saturating_sub(promoter_size), tss + promoter_size + 1);
+                (region, ())
+            })
+            .collect();
+    let exons = preprocessing::read_exons(utils::open_file_for_read(&gtf_file)?)
+        .filter(|x| !exclude_chroms.contains(x.chrom()))
+        .map(|x| (x, ()))
+        .collect();
+    let parse = |regions: Vec<String>| -> Vec<GenomicRange> {
+        regions
+            .iter()
+            .map(|x| GenomicRange::from_str(x).unwrap())
+            .collect()
+    };
+
+    // Distal peaks must be computed before the promoters are moved.
+    let distal_peaks = peaks.map(|peaks| {
+        parse(peaks)
+            .into_iter()
+            .filter(|x| !promoters.is_overlapped(x))
+            .map(|x| (x, ()))
+            .collect()
+    });
+    let mut keys = vec!["promoter".to_string(), "exon".to_string()];
+    let mut trees: Vec<bed::map::GIntervalMap<()>> = vec![promoters, exons];
+    if let Some(distal) = distal_peaks {
+        keys.push("distal_peak".to_string());
+        trees.push(distal);
+    }
+    if let Some(blacklist) = blacklist {
+        keys.push("blacklist".to_string());
+        trees.push(parse(blacklist).into_iter().map(|x| (x, ())).collect());
+    }
+
+    macro_rules! run {
+        ($data:expr) => {
+            $data.frac_read_in_region(&trees, true, count_as_insertion)
+        };
+    }
+
+    let frac = crate::with_anndata!(&anndata, run)?;
+    Ok(keys
+        .into_iter()
+        .zip(frac.columns())
+        .map(|(k, v)| (k, v.to_vec()))
+        .collect())
+}
+
 #[pyfunction]
 pub(crate) fn add_frip(
     anndata: AnnDataLike,
@@ -572,6 +1514,111 @@ pub(crate) fn add_frip(
         .collect())
 }
 
+/// Signal portion of tags (SPOT) and enrichment in the peaks of every group of
+/// cells. See [`QualityControl::spot`].
+#[pyfunction]
+#[pyo3(signature = (anndata, peaks, group_by, min_frag_length=None, max_frag_length=None, blacklist=None))]
+pub(crate) fn spot(
+    anndata: AnnDataLike,
+    peaks: Vec<String>,
+    group_by: Vec<PyBackedStr>,
+    min_frag_length: Option<u64>,
+    max_frag_length: Option<u64>,
+    blacklist: Option<PathBuf>,
+) -> Result<BTreeMap<String, BTreeMap<String, f64>>> {
+    let peaks: Vec<GenomicRange> = peaks
+        .iter()
+        .map(|x| GenomicRange::from_str(x).unwrap())
+        .collect();
+    let group_by: Vec<&str> = group_by.iter().map(|x| x.as_ref()).collect();
+    let blacklist = blacklist.map(read_region_map).transpose()?;
+
+    macro_rules! run {
+        ($data:expr) => {
+            $data.spot(&peaks, &group_by, min_frag_length, max_frag_length, blacklist.as_ref())
+        };
+    }
+
+    let result = crate::with_anndata!(&anndata, run)?;
+    Ok(result
+        .into_iter()
+        .map(|(group, x)| {
+            let stats = [
+                ("n_insertions".to_string(), x.n_insertions as f64),
+                ("n_insertions_in_peaks".to_string(), x.n_in_peaks as f64),
+                ("spot".to_string(), x.spot),
+                ("enrichment".to_string(), x.enrichment),
+            ];
+            (group, stats.into_iter().collect())
+        })
+        .collect())
+}
+
+/// Allocation of the fragments of every group of cells to the peaks under
+/// every counting strategy. Returns, for every group and strategy, the
+/// numbers of fragments allocated to no peak, one peak and several peaks,
+/// and the total counts. See [`QualityControl::peak_allocation`].
+#[pyfunction]
+pub(crate) fn peak_allocation(
+    anndata: AnnDataLike,
+    peaks: Vec<String>,
+    group_by: Vec<PyBackedStr>,
+) -> Result<Vec<(String, String, u64, u64, u64, u64)>> {
+    let peaks: Vec<GenomicRange> = peaks
+        .iter()
+        .map(|x| GenomicRange::from_str(x).map_err(|_| anyhow::anyhow!("invalid region: {}", x)))
+        .collect::<Result<_>>()?;
+    let group_by: Vec<&str> = group_by.iter().map(|x| x.as_ref()).collect();
+
+    macro_rules! run {
+        ($data:expr) => {
+            $data.peak_allocation(&peaks, &group_by)
+        };
+    }
+
+    let mut result = crate::with_anndata!(&anndata, run)?
+        .into_iter()
+        .flat_map(|(group, x)| {
+            ["fragment", "insertion", "paired-insertion"]
+                .into_iter()
+                .zip(x)
+                .map(move |(strategy, a)| {
+                    (
+                        group.clone(),
+                        strategy.to_string(),
+                        a.n_none,
+                        a.n_single,
+                        a.n_multiple,
+                        a.n_counts,
+                    )
+                })
+        })
+        .collect::<Vec<_>>();
+    result.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(result)
+}
+
+/// Number of loci of every cell covered by more than two of its fragments.
+/// See [`QualityControl::fragment_overlap_loci`].
+#[pyfunction]
+#[pyo3(signature = (anndata, exclude_chroms, blacklist=None))]
+pub(crate) fn fragment_overlap_loci(
+    anndata: AnnDataLike,
+    exclude_chroms: Vec<String>,
+    blacklist: Option<PathBuf>,
+) -> Result<Vec<u64>> {
+    let exclude_chroms: HashSet<&str> = exclude_chroms.iter().map(|x| x.as_str()).collect();
+    let blacklist = blacklist.map(read_region_map).transpose()?;
+
+    macro_rules! run {
+        ($data:expr) => {
+            $data.fragment_overlap_loci(blacklist.as_ref(), &exclude_chroms)
+        };
+    }
+
+    crate::with_anndata!(&anndata, run)
+}
+
 #[pyfunction]
 pub(crate) fn fragment_size_distribution(
     anndata: AnnDataLike,
@@ -0,0 +1,74 @@
+use crate::utils::AnnDataLike;
+
+use anndata::Backend;
+use anndata_hdf5::H5;
+use anyhow::Result;
+use pyo3::prelude::*;
+use snapatac2_core::provenance::{self, ProvenanceEntry};
+use snapatac2_core::utils::memory::MemoryTracker;
+use std::ops::Deref;
+
+/// Tracks the peak memory used by an operation, i.e., the peak resident set
+/// size of the process above that at the creation of the tracker, until
+/// `finish` is called.
+#[pyclass(name = "MemoryTracker")]
+pub(crate) struct PyMemoryTracker(Option<MemoryTracker>);
+
+#[pymethods]
+impl PyMemoryTracker {
+    #[new]
+    fn new() -> Self {
+        PyMemoryTracker(Some(MemoryTracker::start()))
+    }
+
+    /// Stop tracking and return the peak memory used in bytes, or None if it
+    /// is not available on this platform.
+    fn finish(&mut self) -> Option<u64> {
+        self.0.take().and_then(MemoryTracker::finish)
+    }
+}
+
+/// Append an entry to the history of the AnnData object. `parameters` is a
+/// JSON document.
+#[pyfunction]
+#[pyo3(signature = (anndata, operation, parameters, peak_memory=None))]
+pub(crate) fn record_provenance(
+    anndata: AnnDataLike,
+    operation: &str,
+    parameters: &str,
+    peak_memory: Option<u64>,
+) -> Result<()> {
+    let entry = ProvenanceEntry {
+        peak_memory,
+        ..ProvenanceEntry::new(operation, serde_json::from_str(parameters)?)
+    };
+    macro_rules! run {
+        ($data:expr) => {
+            if let Err(e) = provenance::append($data, entry) {
+                log::warn!("Failed to record the provenance of '{}': {}", operation, e);
+            }
+        };
+    }
+    crate::with_anndata!(&anndata, run);
+    Ok(())
+}
+
+/// Read the history of the AnnData object as a JSON array.
+#[pyfunction]
+pub(crate) fn read_provenance(anndata: AnnDataLike) -> Result<String> {
+    macro_rules! run {
+        ($data:expr) => {
+            provenance::read_history($data)?
+        };
+    }
+    let history = crate::with_anndata!(&anndata, run);
+    Ok(serde_json::to_string(&history)?)
+}
+
+/// Compare two histories given as JSON arrays.
+#[pyfunction]
+pub(crate) fn diff_provenance(left: &str, right: &str) -> Result<String> {
+    let left: Vec<ProvenanceEntry> = serde_json::from_str(left)?;
+    let right: Vec<ProvenanceEntry> = serde_json::from_str(right)?;
+    Ok(serde_json::to_string(&provenance::diff(&left, &right))?)
+}
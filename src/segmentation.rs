@@ -0,0 +1,59 @@
+use anyhow::{ensure, Result};
+use bed_utils::bed::BEDLike;
+use pyo3::prelude::*;
+use snapatac2_core::genome::ChromSizes;
+use snapatac2_core::segmentation::{binarize, read_binned_tracks, to_segments, BernoulliHmm};
+use snapatac2_core::utils;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Learn a chromatin state model from binned coverage tracks, and write the
+/// segmentation of the genome in BED format. Returns the model as JSON.
+#[pyfunction]
+#[pyo3(signature = (tracks, chrom_sizes, out_file, n_states, bin_size, pvalue, max_iter,
+       tol, random_state))]
+pub(crate) fn segment_genome(
+    tracks: Vec<PathBuf>,
+    chrom_sizes: Vec<(String, u64)>,
+    out_file: PathBuf,
+    n_states: usize,
+    bin_size: u64,
+    pvalue: f64,
+    max_iter: usize,
+    tol: f64,
+    random_state: u64,
+) -> Result<String> {
+    ensure!(n_states > 0, "the number of states must be positive");
+    let chrom_sizes: ChromSizes = chrom_sizes.into_iter().collect();
+    let data = binarize(read_binned_tracks(&tracks, &chrom_sizes, bin_size)?, pvalue);
+    let mut model = BernoulliHmm::new(n_states, tracks.len(), random_state);
+    model.fit(&data, max_iter, tol);
+
+    let mut writer = utils::open_file_for_write(&out_file, None, None)?;
+    for chrom in data.iter() {
+        let states = model.viterbi(&chrom.data);
+        let size = chrom_sizes.get(&chrom.chrom).unwrap();
+        for (region, state) in to_segments(&chrom.chrom, &states, bin_size, size) {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\tE{}",
+                region.chrom(),
+                region.start(),
+                region.end(),
+                state + 1
+            )?;
+        }
+    }
+    writer.finish()?;
+
+    let rows = |x: &ndarray::Array2<f64>| -> Vec<Vec<f64>> {
+        x.rows().into_iter().map(|r| r.to_vec()).collect()
+    };
+    Ok(serde_json::json!({
+        "initial": model.initial.to_vec(),
+        "transition": rows(&model.transition),
+        "emission": rows(&model.emission),
+        "log_likelihood": model.log_likelihood,
+    })
+    .to_string())
+}
@@ -0,0 +1,137 @@
+//! A tile server exposing the binned signal of groups of cells over HTTP, so
+//! that genome browsers can query a dataset directly instead of exported
+//! tracks.
+//!
+//! Endpoints:
+//!
+//! - `GET /groups`: the groups and their numbers of cells.
+//! - `GET /tiles?region=chr1:1000-2000&bin_size=100&group=a,b&track=insertion&normalize=true`:
+//!   the signal of the requested groups (all groups if omitted) in bins tiling
+//!   the region.
+
+use crate::utils::AnnDataLike;
+use snapatac2_core::tile::{FragmentIndex, TrackType};
+
+use anndata::Backend;
+use anndata_hdf5::H5;
+use anyhow::{ensure, Result};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use bed_utils::bed::{BEDLike, GenomicRange};
+use pyo3::{prelude::*, pybacked::PyBackedStr};
+use serde_json::{json, Map, Value};
+use std::{collections::HashMap, ops::Deref, path::PathBuf, str::FromStr, sync::Arc};
+
+struct TileServer {
+    index: FragmentIndex,
+    max_bins: usize,
+}
+
+impl TileServer {
+    fn groups(&self) -> Value {
+        self.index
+            .groups()
+            .map(|(name, n_cells)| json!({"name": name, "n_cells": n_cells}))
+            .collect()
+    }
+
+    fn tiles(&self, params: &HashMap<String, String>) -> Result<Value> {
+        let region = params
+            .get("region")
+            .ok_or_else(|| anyhow::anyhow!("missing parameter: region"))?;
+        let region = GenomicRange::from_str(region)
+            .map_err(|_| anyhow::anyhow!("invalid region: {}", region))?;
+        let bin_size: u64 = params.get("bin_size").map_or(Ok(1), |x| x.parse())?;
+        ensure!(bin_size > 0, "bin_size must be positive");
+        ensure!(region.end() > region.start(), "the region must end after it starts");
+        let n_bins = (region.end() - region.start()).div_ceil(bin_size) as usize;
+        ensure!(
+            n_bins <= self.max_bins,
+            "too many bins requested ({} > {}), increase bin_size",
+            n_bins,
+            self.max_bins
+        );
+        let track = TrackType::try_from(params.get("track").map_or("insertion", |x| x.as_str()))?;
+        let normalize = params
+            .get("normalize")
+            .map_or(Ok(false), |x| x.parse::<bool>())?;
+        let groups: Vec<&str> = match params.get("group") {
+            Some(x) => x.split(',').collect(),
+            None => self.index.groups().map(|(name, _)| name).collect(),
+        };
+        let mut tracks = Map::new();
+        for group in groups {
+            let values = self.index.query(group, &region, bin_size, track, normalize)?;
+            tracks.insert(group.to_string(), json!(values));
+        }
+        Ok(json!({
+            "region": region.pretty_show(),
+            "bin_size": bin_size,
+            "tracks": tracks,
+        }))
+    }
+}
+
+async fn get_groups(State(server): State<Arc<TileServer>>) -> Json<Value> {
+    Json(server.groups())
+}
+
+async fn get_tiles(
+    State(server): State<Arc<TileServer>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    // Querying the index reads from disk, which must not block the workers of
+    // the async runtime.
+    tokio::task::spawn_blocking(move || server.tiles(&params))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+/// Index the fragments of the groups of cells and serve their signal until
+/// interrupted.
+#[pyfunction]
+#[pyo3(signature = (anndata, groups, host="127.0.0.1", port=8000, max_bins=10000, temp_dir=None))]
+pub fn serve_tiles(
+    py: Python<'_>,
+    anndata: AnnDataLike,
+    groups: Vec<Option<PyBackedStr>>,
+    host: &str,
+    port: u16,
+    max_bins: usize,
+    temp_dir: Option<PathBuf>,
+) -> Result<()> {
+    let groups: Vec<Option<&str>> = groups.iter().map(|x| x.as_deref()).collect();
+    macro_rules! run {
+        ($data:expr) => {
+            FragmentIndex::new($data, &groups, temp_dir.as_deref())?
+        };
+    }
+    let index = crate::with_anndata!(&anndata, run);
+    let server = Arc::new(TileServer { index, max_bins });
+    let app = Router::new()
+        .route("/groups", get(get_groups))
+        .route("/tiles", get(get_tiles))
+        .with_state(server);
+
+    py.allow_threads(|| {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind((host, port)).await?;
+            log::info!("Serving tiles at http://{}", listener.local_addr()?);
+            axum::serve(listener, app)
+                .with_graceful_shutdown(async {
+                    tokio::signal::ctrl_c().await.ok();
+                })
+                .await?;
+            Ok::<_, anyhow::Error>(())
+        })
+    })
+}
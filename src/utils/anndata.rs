@@ -8,9 +8,15 @@ use pyanndata::anndata::memory;
 use pyanndata::{AnnData, AnnDataSet};
 use pyo3::prelude::*;
 
-use snapatac2_core::feature_count::{BaseData, CompressedFragmentIter, FragmentData};
+use snapatac2_core::feature_count::{
+    configure_fragments, fragment_key, read_cell_mask, BaseData, CompressedFragmentIter,
+    EncodedFragmentIter, FragmentChunks, FragmentData,
+};
 use snapatac2_core::{
-    feature_count::{BASE_VALUE, FRAGMENT_PAIRED, FRAGMENT_SINGLE},
+    feature_count::{
+        BASE_VALUE, FRAGMENT_PAIRED, FRAGMENT_PAIRED_V2, FRAGMENT_SINGLE, FRAGMENT_SINGLE_V2,
+    },
+    utils::query::{Predicate, Queryable},
     SnapData,
 };
 
@@ -144,22 +150,45 @@ impl<'py> AnnDataOp for PyAnnData<'py> {
     }
 }
 
+/// In-memory AnnData objects are queried on a copy of their `.obs` or `.var`.
+impl<'py> Queryable for PyAnnData<'py> {
+    fn query_obs(&self, predicate: &Predicate, chunk_size: usize) -> Result<Vec<bool>> {
+        predicate.evaluate(&mut self.read_obs()?, chunk_size)
+    }
+
+    fn query_var(&self, predicate: &Predicate, chunk_size: usize) -> Result<Vec<bool>> {
+        predicate.evaluate(&mut self.read_var()?, chunk_size)
+    }
+}
+
 impl<'py> SnapData for PyAnnData<'py> {
-    fn get_fragment_iter(&self, chunk_size: usize) -> Result<FragmentData> {
+    fn get_modality_fragment_iter(
+        &self,
+        chunk_size: usize,
+        modality: Option<&str>,
+    ) -> Result<FragmentData> {
+        let chrom_sizes = self.read_chrom_sizes()?;
+        let key = |x: &str| fragment_key(x, modality);
         let obsm = self.obsm();
-        let matrices: CompressedFragmentIter =
-            if let Some(insertion) = obsm.get_item_iter(FRAGMENT_SINGLE, chunk_size) {
-                CompressedFragmentIter::FragmentSingle(Box::new(insertion))
-            } else if let Some(fragment) = obsm.get_item_iter(FRAGMENT_PAIRED, chunk_size) {
-                CompressedFragmentIter::FragmentPaired(Box::new(fragment))
+        let matrices: FragmentChunks =
+            if let Some(insertion) = obsm.get_item_iter(&key(FRAGMENT_SINGLE), chunk_size) {
+                CompressedFragmentIter::FragmentSingle(Box::new(insertion)).into()
+            } else if let Some(fragment) = obsm.get_item_iter(&key(FRAGMENT_PAIRED), chunk_size) {
+                CompressedFragmentIter::FragmentPaired(Box::new(fragment)).into()
+            } else if let Some(insertion) = obsm.get_item_iter(&key(FRAGMENT_SINGLE_V2), chunk_size) {
+                EncodedFragmentIter::FragmentSingle(Box::new(insertion)).into()
+            } else if let Some(fragment) = obsm.get_item_iter(&key(FRAGMENT_PAIRED_V2), chunk_size) {
+                EncodedFragmentIter::FragmentPaired(Box::new(fragment)).into()
             } else {
                 bail!(
-                    "one of the following keys must be present in the '.obsm': '{}', '{}'",
-                    FRAGMENT_SINGLE,
-                    FRAGMENT_PAIRED
+                    "one of the following keys must be present in the '.obsm': '{}', '{}', '{}', '{}'",
+                    key(FRAGMENT_SINGLE),
+                    key(FRAGMENT_PAIRED),
+                    key(FRAGMENT_SINGLE_V2),
+                    key(FRAGMENT_PAIRED_V2),
                 )
             };
-        Ok(FragmentData::new(self.read_chrom_sizes()?, matrices))
+        configure_fragments(self, FragmentData::new(chrom_sizes, matrices))
     }
 
     fn get_base_iter(
@@ -168,7 +197,11 @@ impl<'py> SnapData for PyAnnData<'py> {
     ) -> Result<BaseData<impl ExactSizeIterator<Item = (DynCsrMatrix, usize, usize)>>> {
         let obsm = self.obsm();
         if let Some(data) = obsm.get_item_iter(BASE_VALUE, chunk_size) {
-            Ok(BaseData::new(self.read_chrom_sizes()?, data))
+            let data = BaseData::new(self.read_chrom_sizes()?, data);
+            Ok(match read_cell_mask(self)? {
+                Some(mask) => data.with_cell_mask(mask),
+                None => data,
+            })
         } else {
             bail!("key '_values' is not present in the '.obsm'")
         }
@@ -4,7 +4,7 @@ pub use self::anndata::AnnDataLike;
 
 use ::anndata::Backend;
 use anndata_hdf5::H5;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bed_utils::bed::{BEDLike, MergeBed};
 use bed_utils::extsort::ExternalSorterBuilder;
 use numpy::{
@@ -16,12 +16,13 @@ use snapatac2_core::genome::{
     read_transcripts_from_gff, read_transcripts_from_gtf, Transcript, TranscriptParserOptions,
 };
 use snapatac2_core::utils;
+use snapatac2_core::utils::query::Queryable;
 use std::ops::Deref;
 
 use bed_utils::{bed, bed::GenomicRange, bed::BED};
 use linreg::lin_reg_imprecise;
 use nalgebra_sparse::CsrMatrix;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 use std::str::FromStr;
 
@@ -233,7 +234,7 @@ pub(crate) fn spearman<'py>(
     }
 }
 
-fn csr_to_rust<'py>(csr: &Bound<'py, PyAny>) -> PyResult<CsrMatrix<f64>> {
+pub(crate) fn csr_to_rust<'py>(csr: &Bound<'py, PyAny>) -> PyResult<CsrMatrix<f64>> {
     let shape: Vec<usize> = csr.getattr("shape")?.extract()?;
     let indices = cast_pyarray(&csr.getattr("indices")?)?;
     let indptr = cast_pyarray(&csr.getattr("indptr")?)?;
@@ -317,7 +318,7 @@ pub(crate) fn read_genomic_ranges(input: &Bound<'_, PyAny>) -> Result<Vec<Genomi
             .collect()
     } else {
         let file: PathBuf = input.extract()?;
-        let mut reader = bed::io::Reader::new(utils::open_file_for_read(file), None);
+        let mut reader = bed::io::Reader::new(utils::open_file_for_read(file)?, None);
         Ok(reader
             .records::<GenomicRange>()
             .map(|x| x.unwrap())
@@ -325,15 +326,25 @@ pub(crate) fn read_genomic_ranges(input: &Bound<'_, PyAny>) -> Result<Vec<Genomi
     }
 }
 
+/// Read the regions of a bed file, e.g., a blacklist, into an interval map.
+/// Returns an error on an invalid region.
+pub(crate) fn read_region_map(file: PathBuf) -> Result<bed::map::GIntervalMap<()>> {
+    bed::io::Reader::new(utils::open_file_for_read(&file)?, None)
+        .into_records::<GenomicRange>()
+        .map(|x| Ok((x?, ())))
+        .collect::<Result<_>>()
+        .with_context(|| format!("invalid region in {}", file.display()))
+}
+
 /// Read genomic regions from a bed file.
 /// Returns a list of strings
 #[pyfunction]
-pub(crate) fn read_regions(file: PathBuf) -> Vec<String> {
-    let mut reader = bed::io::Reader::new(utils::open_file_for_read(file), Some("#".to_string()));
-    reader
+pub(crate) fn read_regions(file: PathBuf) -> Result<Vec<String>> {
+    let mut reader = bed::io::Reader::new(utils::open_file_for_read(file)?, Some("#".to_string()));
+    Ok(reader
         .records::<GenomicRange>()
         .map(|x| x.unwrap().pretty_show())
-        .collect()
+        .collect())
 }
 
 #[pyfunction]
@@ -342,7 +353,7 @@ pub(crate) fn intersect_bed<'py>(
     bed_file: &str,
 ) -> PyResult<Vec<bool>> {
     let bed_tree: bed::map::GIntervalMap<()> =
-        bed::io::Reader::new(utils::open_file_for_read(bed_file), None)
+        bed::io::Reader::new(utils::open_file_for_read(bed_file)?, None)
             .into_records()
             .map(|x: Result<BED<3>, _>| (x.unwrap(), ()))
             .collect();
@@ -354,6 +365,84 @@ pub(crate) fn intersect_bed<'py>(
     Ok(res)
 }
 
+/// Find the variants overlapping each region. `variants` is a VCF file, or a
+/// BED file whose 4th column, if present, is the identifier of the variant.
+/// Returns pairs of region indices and variant identifiers.
+#[pyfunction]
+pub(crate) fn variant_overlaps(
+    regions: Vec<String>,
+    variants: PathBuf,
+) -> Result<Vec<(usize, String)>> {
+    let is_vcf = variants.to_string_lossy().to_lowercase().contains(".vcf");
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(utils::open_file_for_read(&variants)?).lines().enumerate() {
+        let line = line?;
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let parse = || -> Result<(GenomicRange, String)> {
+            if is_vcf {
+                anyhow::ensure!(fields.len() >= 5, "expecting at least 5 fields");
+                let start = fields[1]
+                    .parse::<u64>()?
+                    .checked_sub(1)
+                    .ok_or_else(|| anyhow::anyhow!("POS must be at least 1"))?;
+                let end = start + fields[3].len().max(1) as u64;
+                let range = GenomicRange::new(fields[0], start, end);
+                let id = if fields[2] == "." {
+                    format!("{}:{}:{}:{}", fields[0], fields[1], fields[3], fields[4])
+                } else {
+                    fields[2].to_string()
+                };
+                Ok((range, id))
+            } else {
+                anyhow::ensure!(fields.len() >= 3, "expecting at least 3 fields");
+                let range = GenomicRange::new(fields[0], fields[1].parse()?, fields[2].parse()?);
+                let id = fields.get(3).map_or_else(|| range.pretty_show(), |x| x.to_string());
+                Ok((range, id))
+            }
+        };
+        records.push(
+            parse().map_err(|e| anyhow::anyhow!("line {} of {}: {}", i + 1, variants.display(), e))?,
+        );
+    }
+    let tree: bed::map::GIntervalMap<String> = records.into_iter().collect();
+    regions
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            let region = GenomicRange::from_str(x)
+                .map_err(|_| anyhow::anyhow!("invalid region: {}", x))?;
+            Ok(tree.find(&region).map(|(_, id)| (i, id.clone())).collect::<Vec<_>>())
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(|x| x.into_iter().flatten().collect())
+}
+
+/// Map regions to another genome assembly or species with a chain file.
+/// Regions that cannot be mapped are `None`.
+#[pyfunction]
+pub(crate) fn liftover(
+    regions: Vec<String>,
+    chain_file: PathBuf,
+    min_match: f64,
+) -> Result<Vec<Option<String>>> {
+    let liftover = snapatac2_core::liftover::LiftOver::from_file(chain_file)?;
+    regions
+        .iter()
+        .map(|x| {
+            let region = GenomicRange::from_str(x)
+                .map_err(|_| anyhow::anyhow!("invalid region: {}", x))?;
+            Ok(liftover.map(&region, min_match).map(|x| x.pretty_show()))
+        })
+        .collect()
+}
+
 #[pyfunction]
 pub(crate) fn kmeans<'py>(
     py: Python<'py>,
@@ -395,25 +484,22 @@ pub(crate) fn read_promoters(
 pub fn read_transcripts<P: AsRef<std::path::Path>>(
     file_path: P,
     options: &TranscriptParserOptions,
-) -> Vec<Transcript> {
-    let path = if file_path.as_ref().extension().unwrap() == "gz" {
+) -> Result<Vec<Transcript>> {
+    let path = if file_path.as_ref().extension().map_or(false, |x| x == "gz") {
         file_path.as_ref().file_stem().unwrap().as_ref()
     } else {
         file_path.as_ref()
     };
-    let file = BufReader::new(utils::open_file_for_read(&file_path));
-    if path.extension().unwrap() == "gff" {
-        read_transcripts_from_gff(file, options).unwrap()
-    } else if path.extension().unwrap() == "gtf" {
-        read_transcripts_from_gtf(file, options).unwrap()
-    } else {
-        read_transcripts_from_gff(file, options).unwrap_or_else(|_| {
+    let file = BufReader::new(utils::open_file_for_read(&file_path)?);
+    match path.extension().and_then(|x| x.to_str()) {
+        Some("gff") => read_transcripts_from_gff(file, options),
+        Some("gtf") => read_transcripts_from_gtf(file, options),
+        _ => read_transcripts_from_gff(file, options).or_else(|_| {
             read_transcripts_from_gtf(
-                BufReader::new(utils::open_file_for_read(file_path)),
+                BufReader::new(utils::open_file_for_read(file_path)?),
                 options,
             )
-            .unwrap()
-        })
+        }),
     }
 }
 
@@ -431,3 +517,57 @@ pub(crate) fn total_size_of_peaks(peaks: Vec<String>) -> Result<u64> {
         .map(|x| x.len())
         .sum())
 }
+
+/// Select at most `max_per_group` cells from every group.
+/// Returns a boolean mask over the cells.
+#[pyfunction]
+#[pyo3(signature = (groups, max_per_group, seed=0))]
+pub(crate) fn stratified_subsample(
+    groups: Vec<Option<String>>,
+    max_per_group: usize,
+    seed: u64,
+) -> Vec<bool> {
+    utils::sampling::stratified_subsample(&groups, max_per_group, seed)
+}
+
+/// Select `n` cells by geometric sketching of their embedding.
+/// Returns a boolean mask over the cells.
+#[pyfunction]
+#[pyo3(signature = (data, n, seed=0))]
+pub(crate) fn geometric_sketch(
+    data: PyReadonlyArray<'_, f64, Ix2>,
+    n: usize,
+    seed: u64,
+) -> Vec<bool> {
+    utils::sampling::geometric_sketch(data.as_array(), n, seed)
+}
+
+/// Derive the seed of a named component from `seed`, so that the random
+/// draws of the components are independent of each other.
+#[pyfunction]
+pub(crate) fn derive_seed(seed: u64, name: &str) -> u64 {
+    utils::rng::SeedStream::new(seed).derive(name).seed()
+}
+
+/// Evaluate a predicate, e.g., "tsse > 7 && n_fragment > 1000", on the rows
+/// of `.obs`, or of `.var` if `axis` is 1. Returns a boolean mask.
+#[pyfunction]
+#[pyo3(signature = (anndata, expr, axis=0, chunk_size=100000))]
+pub(crate) fn query(
+    anndata: AnnDataLike,
+    expr: &str,
+    axis: usize,
+    chunk_size: usize,
+) -> Result<Vec<bool>> {
+    let predicate = utils::query::Predicate::parse(expr)?;
+    macro_rules! run {
+        ($data:expr) => {
+            match axis {
+                0 => $data.query_obs(&predicate, chunk_size),
+                1 => $data.query_var(&predicate, chunk_size),
+                _ => anyhow::bail!("axis must be 0 or 1"),
+            }
+        };
+    }
+    crate::with_anndata!(&anndata, run)
+}
@@ -0,0 +1,42 @@
+use crate::utils::AnnDataLike;
+
+use anndata::Backend;
+use anndata_hdf5::H5;
+use anyhow::Result;
+use pyo3::prelude::*;
+use snapatac2_core::genome::ChromSizes;
+use std::collections::BTreeMap;
+use std::ops::Deref;
+
+/// Audit an AnnData object and return the report as a JSON string.
+#[pyfunction]
+#[pyo3(signature = (anndata, reference=None, chunk_size=500, modality=None))]
+pub(crate) fn validate(
+    anndata: AnnDataLike,
+    reference: Option<BTreeMap<String, u64>>,
+    chunk_size: usize,
+    modality: Option<&str>,
+) -> Result<String> {
+    let reference: Option<ChromSizes> = reference.map(|x| x.into_iter().collect());
+    macro_rules! run {
+        ($data:expr) => {
+            snapatac2_core::validation::validate($data, reference.as_ref(), chunk_size, modality)?
+        };
+    }
+    let report = crate::with_anndata!(&anndata, run);
+    Ok(serde_json::to_string(&report)?)
+}
+
+/// Delete the elements left partly written by an interrupted operation and
+/// return the report as a JSON string.
+#[pyfunction]
+#[pyo3(signature = (anndata, dry_run=false, chunk_size=2000))]
+pub(crate) fn repair(anndata: AnnDataLike, dry_run: bool, chunk_size: usize) -> Result<String> {
+    macro_rules! run {
+        ($data:expr) => {
+            snapatac2_core::recovery::repair($data, dry_run, chunk_size)?
+        };
+    }
+    let report = crate::with_anndata!(&anndata, run);
+    Ok(serde_json::to_string(&report)?)
+}
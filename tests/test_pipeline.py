@@ -1,6 +1,11 @@
 import snapatac2 as snap
 from pathlib import Path
+import os
+import sys
+import gzip
+import json
 import numpy as np
+import pytest
 
 def h5ad(dir=Path("./")):
     import uuid
@@ -48,7 +53,17 @@ def pipeline(data, tmp_path):
 
     snap.tl.macs3(data, groupby="leiden", call_broad_peaks=True)
     snap.tl.macs3(data, groupby="leiden")
+    snap.tl.macs3(data, groupby="leiden", selections={"0"}, bdg_dir=tmp_path / "bdg", inplace=False)
+    for name in ["treat_pileup", "control_lambda", "pscore", "qscore"]:
+        assert (tmp_path / "bdg" / f"0_{name}.bdg").exists()
     peaks = snap.tl.merge_peaks(data.uns["macs3"], snap.genome.hg38)
+    rep_peaks = snap.tl.reproducible_peaks(data, groupby="leiden", n_replicates=2, inplace=False)
+    assert all(len(rep_peaks[k]) <= len(data.uns["macs3"][k]) for k in rep_peaks)
+    saturation = snap.tl.peak_saturation(data, "leiden", fractions=[0.5, 1.0], selections={"0", "1"})
+    assert set(saturation["group"]) <= {"0", "1"}
+    full = saturation.filter(saturation["fraction"] == 1.0)
+    for group, n_peaks in zip(full["group"], full["n_peaks"]):
+        assert n_peaks == len(data.uns["macs3"][group])
 
     snap.pp.make_gene_matrix(data, gene_anno=snap.genome.hg38)
     snap.pp.make_gene_matrix(data, use_x=True, gene_anno=snap.genome.hg38)
@@ -80,4 +95,1720 @@ def test_in_memory(tmp_path):
         chrom_sizes=snap.genome.hg38,
         sorted_by_barcode=False,
     )
-    pipeline(data, tmp_path)
\ No newline at end of file
+    pipeline(data, tmp_path)
+
+def test_simulate():
+    data = snap.datasets.simulate(n_cells=200, n_peaks=500, mean_depth=1000, n_batches=2, random_state=1)
+    assert data.n_obs == 200
+    assert set(data.obs["batch"]) == {"batch_0", "batch_1"}
+
+    snap.pp.add_tile_matrix(data)
+    snap.pp.select_features(data)
+    snap.tl.spectral(data)
+    snap.pp.knn(data)
+    snap.tl.leiden(data)
+
+    data.obs["cluster"] = [None if i % 10 == 0 else x for i, x in enumerate(data.obs["leiden"])]
+    merged, _ = snap.tl.merge_clusters(data, "cluster", threshold=0.5, inplace=False)
+    assert all((x is None) == (i % 10 == 0) for i, x in enumerate(merged))
+
+    other = snap.datasets.simulate(n_cells=200, n_peaks=500, mean_depth=1000, n_batches=2, random_state=1)
+    np.testing.assert_array_equal(data.obsm["fragment_paired"].data, other.obsm["fragment_paired"].data)
+
+def test_knn_exact():
+    rng = np.random.default_rng(0)
+    x = rng.normal(size=(300, 20))
+    exact = snap.pp.knn(x, n_neighbors=10, method="exact", block_size=64, inplace=False)
+    kdtree = snap.pp.knn(x, n_neighbors=10, method="kdtree", inplace=False)
+    assert (exact != kdtree).nnz == 0
+    with pytest.raises(Exception, match="rebuild"):
+        snap.pp.knn(x, n_neighbors=10, method="exact", device="cuda", inplace=False)
+
+def test_knn_index():
+    data = snap.datasets.simulate(n_cells=100, n_peaks=200, mean_depth=500, random_state=3)
+    rng = np.random.default_rng(0)
+    data.obsm["X_rep"] = rng.normal(size=(100, 5))
+    snap.pp.knn(data, n_neighbors=5, use_rep="X_rep", method="hora", store_index=True, inplace=False)
+    assert "hnsw_index" not in data.uns
+
+    snap.pp.knn(data, n_neighbors=5, use_rep="X_rep", method="hora", store_index=True)
+    info = data.uns["hnsw_index_info"]
+    assert info["key"] == "X_rep" and info["n_obs"] == 100
+    labels = np.array(["a" if x > 0 else "b" for x in data.obsm["X_rep"][:, 0]], dtype=object)
+    labels[:10] = None
+    predicted = snap.tl.transfer_labels(data, "X_rep", list(labels), n_neighbors=5, use_index=True)
+    assert all(x is not None for x in predicted)
+
+    # The index of modified coordinates is not reused.
+    data.obsm["X_rep"] = rng.normal(size=(100, 5))
+    snap.pp.knn(data, n_neighbors=5, use_rep="X_rep", method="hora", store_index=True)
+    assert data.uns["hnsw_index_info"]["fingerprint"] != info["fingerprint"]
+
+def test_validate():
+    data = snap.datasets.simulate(n_cells=50, n_peaks=100, mean_depth=500, random_state=2)
+    snap.pp.add_tile_matrix(data)
+    report = snap.validate(data)
+    assert report['valid'], report['issues']
+    assert 'fragment_order' in report['checks']
+
+    chrom_sizes = {f"chr{i}": 20_000_000 for i in range(1, 6)}
+    assert snap.validate(data, chrom_sizes)['valid']
+    chrom_sizes['chr1'] = 10_000_000
+    report = snap.validate(data, chrom_sizes, verbose=False)
+    assert not report['valid']
+    assert report['issues'][0]['check'] == 'reference_genome'
+
+def test_repair(tmp_path):
+    data = snap.datasets.simulate(n_cells=50, n_peaks=100, mean_depth=500, random_state=4, file=tmp_path / "data.h5ad")
+    out = snap.pp.add_tile_matrix(data, bin_size=500, inplace=False, file=tmp_path / "tiles.h5ad")
+    assert out.n_vars > 0 and "snapatac2_pending" not in out.uns
+    assert sorted(x.name for x in tmp_path.iterdir()) == ["data.h5ad", "tiles.h5ad"]
+    out.close()
+
+    # Simulate a tile matrix left partly written by an interrupted process.
+    snap.pp.add_tile_matrix(data, bin_size=500)
+    data.uns["snapatac2_pending"] = np.array(["X"])
+    report = snap.validate(data, verbose=False)
+    assert not report["valid"]
+    assert report["issues"][-1]["check"] == "pending_writes"
+
+    assert snap.repair(data, dry_run=True)["removed"] == []
+    assert data.X is not None
+    report = snap.repair(data)
+    assert report["partial"] == ["X"] and report["removed"] == ["X"]
+    assert data.X is None and "snapatac2_pending" not in data.uns
+    assert snap.validate(data, verbose=False)["valid"]
+    data.close()
+
+def test_append_cells(tmp_path):
+    old = snap.datasets.simulate(n_cells=40, n_peaks=100, mean_depth=500, random_state=5, file=tmp_path / "old.h5ad")
+    new = snap.datasets.simulate(n_cells=20, n_peaks=100, mean_depth=500, random_state=6, file=tmp_path / "new.h5ad")
+    matrix = snap.pp.add_tile_matrix(old, bin_size=5000, inplace=False)
+    expected = snap.pp.add_tile_matrix(new, bin_size=5000, inplace=False)
+
+    combined = snap.pp.append_cells(matrix, new)
+    assert combined.shape == (60, matrix.n_vars)
+    assert list(combined.var_names) == list(matrix.var_names)
+    assert list(combined.obs_names) == list(matrix.obs_names) + list(expected.obs_names)
+    assert (combined.X[:40] != matrix.X).nnz == 0
+    assert (combined.X[40:] != expected.X).nnz == 0
+
+    backed = snap.pp.append_cells(matrix, new, file=tmp_path / "combined.h5ad")
+    assert (backed.X[:] != combined.X).nnz == 0
+    backed.close()
+
+    genes = matrix[:, :2].copy()
+    genes.var_names = ["GENE1", "GENE2"]
+    with pytest.raises(Exception, match="not a genomic region"):
+        snap.pp.append_cells(genes, new)
+
+def test_gc_correct(tmp_path):
+    chrom_sizes = {"chr1": 200_000, "chr2": 100_000}
+    data = snap.datasets.simulate(
+        n_cells=30, n_peaks=50, mean_depth=500, chrom_sizes=chrom_sizes, random_state=3,
+    )
+    rng = np.random.default_rng(0)
+    fasta = tmp_path / "genome.fa"
+    with open(fasta, "w") as f:
+        for chrom, size in chrom_sizes.items():
+            seq = "".join(rng.choice(list("ACGT"), size, p=[0.3, 0.2, 0.2, 0.3]))
+            f.write(f">{chrom}\n")
+            f.write("\n".join(seq[i:i+60] for i in range(0, size, 60)) + "\n")
+    tiles = snap.pp.add_tile_matrix(data, bin_size=5000, inplace=False)
+
+    with pytest.raises(ValueError, match="genome_fasta"):
+        snap.pp.gc_correct(tiles)
+    snap.pp.gc_correct(tiles, fasta)
+    gc = np.asarray(tiles.var['gc'])
+    assert np.all((gc > 0.3) & (gc < 0.5))
+    fold_change = np.asarray(tiles.var['gc_corrected_fold_change'])
+    np.testing.assert_allclose(
+        tiles.layers['gc_corrected'].toarray(), tiles.X.toarray() / fold_change[None, :],
+    )
+
+    tiles.var['gc'] = 0.4
+    snap.pp.gc_correct(tiles, key_added="flat")
+    np.testing.assert_allclose(tiles.layers['flat'].toarray(), tiles.X.toarray())
+
+def test_cell_weights():
+    data = snap.datasets.simulate(n_cells=30, n_peaks=100, mean_depth=500, random_state=9)
+    counts = snap.pp.add_tile_matrix(data, bin_size=5000, inplace=False)
+    weights = np.linspace(0.5, 2.0, data.n_obs)
+    weighted = snap.pp.add_tile_matrix(data, bin_size=5000, inplace=False, cell_weights=weights)
+    np.testing.assert_allclose(weighted.X.toarray(), counts.X.toarray() * weights[:, None])
+
+    data.obs['w'] = weights
+    peaks = list(counts.var_names[:50])
+    by_key = snap.pp.make_peak_matrix(data, use_rep=peaks, cell_weights='w')
+    np.testing.assert_allclose(by_key.X.toarray(), weighted[:, peaks].X.toarray())
+
+    with pytest.raises(Exception, match="number of cell weights"):
+        snap.pp.add_tile_matrix(data, bin_size=5000, inplace=False, cell_weights=weights[:10])
+
+def test_recommend_bin_size():
+    data = snap.datasets.simulate(n_cells=30, n_peaks=100, mean_depth=500, random_state=9)
+    candidates = [500, 1000, 5000, 20000]
+    sizes = [
+        snap.pp.recommend_bin_size(data, target_density=x, candidates=candidates)
+        for x in [0.0, 1e-4, 1e-2, 1.0]
+    ]
+    assert sizes[0] == 500 and sizes[-1] == 20000
+    assert sizes == sorted(sizes)
+
+    bin_size = snap.pp.recommend_bin_size(data, target_density=1e-3, candidates=candidates)
+    auto = snap.pp.add_tile_matrix(data, bin_size="auto", inplace=False)
+    fixed = snap.pp.add_tile_matrix(
+        data, bin_size=snap.pp.recommend_bin_size(data), inplace=False,
+    )
+    assert list(auto.var_names) == list(fixed.var_names)
+    assert bin_size in candidates
+
+def test_cytoband_matrix(tmp_path):
+    data = snap.datasets.simulate(
+        n_cells=30, n_peaks=100, mean_depth=500, random_state=9,
+        chrom_sizes={"chr1": 1_000_000, "chr2": 500_000},
+    )
+    cytoband = tmp_path / "cytoBand.txt.gz"
+    with gzip.open(cytoband, "wt") as f:
+        f.write(
+            "chr1\t0\t150000\tp12\tgneg\n"
+            "chr1\t150000\t400000\tp11\tacen\n"
+            "chr1\t400000\t1000000\tq11\tgpos50\n"
+            "chr2\t0\t200000\tp11\tgneg\n"
+            "chr2\t200000\t500000\tq11\tgneg\n"
+        )
+    arms = snap.pp.make_cytoband_matrix(data, cytoband)
+    assert list(arms.var_names) == ["chr1p", "chr1q", "chr2p", "chr2q"]
+    assert list(arms.var["start"]) == [0, 400000, 0, 200000]
+
+    bands = snap.pp.make_cytoband_matrix(data, cytoband, level="band")
+    assert list(bands.var_names) == ["chr1p12", "chr1p11", "chr1q11", "chr2p11", "chr2q11"]
+    X = bands.X.toarray()
+    np.testing.assert_array_equal(arms.X[:, 0].toarray().ravel(), X[:, 0] + X[:, 1])
+
+    tiles = snap.pp.add_tile_matrix(
+        data, bin_size=5000, inplace=False, exclude_chroms=None, counting_strategy="insertion",
+    )
+    np.testing.assert_array_equal(
+        np.ravel(arms.X.sum(axis=1)), np.ravel(tiles.X.sum(axis=1)),
+    )
+
+    chr2 = snap.pp.make_cytoband_matrix(data, cytoband, chroms=["chr2"])
+    assert list(chr2.var_names) == ["chr2p", "chr2q"]
+
+def test_window_matrix():
+    data = snap.datasets.simulate(n_cells=30, n_peaks=100, mean_depth=500, random_state=9)
+    tiles = snap.pp.add_tile_matrix(data, bin_size=5000, inplace=False, counting_strategy="insertion")
+    windows = snap.pp.add_window_matrix(
+        data, window_size=5000, step=5000, inplace=False, counting_strategy="insertion",
+    )
+    assert list(windows.var_names) == list(tiles.var_names)
+    np.testing.assert_array_equal(windows.X.toarray(), tiles.X.toarray())
+
+    # Windows of two tiles starting at every tile.
+    windows = snap.pp.add_window_matrix(
+        data, window_size=10000, step=5000, inplace=False, counting_strategy="insertion",
+    )
+    chrom, rest = windows.var_names[0].split(":")
+    assert rest == "0-10000"
+    assert windows.var_names[1] == chrom + ":5000-15000"
+    tile = tiles.X.toarray()
+    np.testing.assert_array_equal(windows.X[:, :2].toarray(), tile[:, :2] + tile[:, 1:3])
+
+    with pytest.raises(Exception, match="step"):
+        snap.pp.add_window_matrix(data, window_size=1000, step=2000, inplace=False)
+
+def test_reindex_vars(tmp_path):
+    data = snap.datasets.simulate(n_cells=30, n_peaks=100, mean_depth=500, random_state=8)
+    fine = snap.pp.add_tile_matrix(data, bin_size=500, inplace=False)
+    coarse = snap.pp.add_tile_matrix(data, bin_size=1000, inplace=False)
+
+    mapped = snap.pp.reindex_vars(fine, list(coarse.var_names), policy="overlap")
+    assert list(mapped.var_names) == list(coarse.var_names)
+    assert (mapped.X != coarse.X).nnz == 0
+
+    subset = list(fine.var_names[::7])
+    exact = snap.pp.reindex_vars(fine, subset, policy="exact")
+    assert (exact.X != fine[:, subset].X).nnz == 0
+
+    bed = tmp_path / "regions.bed"
+    bed.write_text("".join(x.replace(":", "\t").replace("-", "\t") + "\n" for x in coarse.var_names))
+    half = snap.pp.reindex_vars(coarse, bed, policy="fraction")
+    np.testing.assert_allclose(half.X.toarray(), coarse.X.toarray())
+
+def test_hstack(tmp_path):
+    import scipy.sparse as sp
+    from anndata import AnnData
+
+    data = snap.datasets.simulate(n_cells=30, n_peaks=100, mean_depth=500, random_state=9)
+    tiles = snap.pp.add_tile_matrix(data, bin_size=5000, inplace=False)
+    scores = AnnData(
+        X=np.random.default_rng(0).normal(size=(tiles.n_obs, 3)),
+        obs=tiles.obs, var=dict(index=["CTCF", "SP1", "GATA1"]),
+    )
+
+    combined = snap.pp.hstack({"tile": tiles, "motif": scores})
+    assert combined.shape == (tiles.n_obs, tiles.n_vars + 3)
+    assert list(combined.var_names[-3:]) == ["motif:CTCF", "motif:SP1", "motif:GATA1"]
+    assert list(combined.var["source"][:2]) == ["tile", "tile"]
+    assert list(combined.var["feature"][-1:]) == ["GATA1"]
+    np.testing.assert_allclose(
+        sp.csr_matrix(combined.X).toarray(),
+        np.hstack([tiles.X.toarray(), scores.X]),
+    )
+
+    counts = snap.pp.hstack([tiles, tiles], file=tmp_path / "counts.h5ad")
+    assert counts.X[:].dtype == np.uint32
+    counts.close()
+    with pytest.raises(Exception, match="duplicated"):
+        snap.pp.hstack([tiles, tiles], prefix=False)
+    with pytest.raises(Exception, match="no X matrix"):
+        snap.pp.hstack({"tile": tiles, "empty": AnnData(obs=tiles.obs)})
+
+def test_history():
+    data = snap.datasets.simulate(n_cells=50, n_peaks=100, mean_depth=500, random_state=3)
+    snap.pp.add_tile_matrix(data, bin_size=500)
+    snap.pp.select_features(data)
+    snap.tl.spectral(data)
+    ops = [x['operation'] for x in snap.history(data)]
+    assert ops == ['import_fragments', 'tile_matrix', 'spectral']
+    if sys.platform.startswith("linux"):
+        assert all(0 <= x['peak_memory'] < 1 << 40 for x in snap.history(data))
+
+    out = snap.pp.add_tile_matrix(data, bin_size=1000, inplace=False)
+    ops = [x['operation'] for x in snap.history(out)]
+    assert ops == ['import_fragments', 'tile_matrix', 'spectral', 'tile_matrix']
+
+    other = snap.datasets.simulate(n_cells=50, n_peaks=100, mean_depth=500, random_state=3)
+    snap.pp.add_tile_matrix(other, bin_size=1000)
+    diff = snap.diff_history(data, other)
+    assert {'kind': 'parameter', 'operation': 'tile_matrix', 'parameter': 'bin_size', 'left': 500, 'right': 1000} in diff
+    assert {'kind': 'missing_operation', 'operation': 'spectral', 'present_in': 'left'} in diff
+
+def test_run_recipe(tmp_path):
+    fragment_file = tmp_path / "fragments.tsv.gz"
+    snap.datasets.simulate(n_cells=100, n_peaks=200, mean_depth=1000, fragment_file=fragment_file, random_state=4)
+    recipe = {
+        "input": {
+            "fragment_file": str(fragment_file),
+            "output": str(tmp_path / "result.h5ad"),
+            "chrom_sizes": {f"chr{i}": 20_000_000 for i in range(1, 6)},
+        },
+        "steps": [
+            {"step": "import"},
+            {"step": "qc_filter", "min_counts": 100, "min_tsse": None},
+            {"step": "tile_matrix"},
+            {"step": "select_features"},
+            {"step": "embed", "n_comps": 10},
+            {"step": "cluster", "n_neighbors": 10},
+        ],
+    }
+    data = snap.run_recipe(recipe)
+    assert "leiden" in data.obs
+    data.close()
+
+    recipe["steps"][-1]["resolution"] = 0.5
+    data = snap.run_recipe(recipe)
+    ops = [x['operation'] for x in snap.history(data)]
+    assert ops.count('import_fragments') == 1
+    assert ops.count('spectral') == 1
+    data.close()
+
+    with pytest.raises(FileExistsError):
+        snap.run_recipe(recipe, resume=False)
+
+    from snapatac2.__main__ import main
+    recipe_file = tmp_path / "recipe.json"
+    recipe_file.write_text(json.dumps(recipe))
+    main(["run", str(recipe_file), "--no-resume", "--overwrite"])
+    data = snap.read(tmp_path / "result.h5ad")
+    ops = [x['operation'] for x in snap.history(data)]
+    assert ops.count('import_fragments') == 1
+    data.close()
+
+def test_checkpoint(tmp_path):
+    fragment_file = tmp_path / "fragments.tsv.gz"
+    snap.datasets.simulate(n_cells=100, n_peaks=200, mean_depth=1000, fragment_file=fragment_file, random_state=5)
+    chrom_sizes = {f"chr{i}": 20_000_000 for i in range(1, 6)}
+    checkpoint_dir = tmp_path / "checkpoint"
+
+    data = snap.pp.import_fragments(
+        fragment_file, chrom_sizes, sorted_by_barcode=False, min_num_fragments=100,
+        checkpoint_dir=checkpoint_dir,
+    )
+    assert not checkpoint_dir.exists()
+    snap.pp.add_tile_matrix(data)
+    snap.pp.select_features(data)
+    expected = snap.tl.pca(data, n_comps=5, inplace=False)[1]
+    np.testing.assert_array_equal(
+        snap.tl.pca(data, n_comps=5, checkpoint_dir=checkpoint_dir, inplace=False)[1],
+        expected,
+    )
+    assert not checkpoint_dir.exists()
+
+    data.close()
+
+    # Features already tested are not tested again.
+    import scipy.sparse as sp
+    from snapatac2.tools._diff import _Checkpoint, _likelihood_ratio_test_many
+    rng = np.random.default_rng(0)
+    Y = sp.csc_matrix(rng.poisson(0.5, size=(40, 6)).astype(float))
+    X = np.log1p(np.asarray(Y.sum(axis=1)))
+    z = np.repeat([0, 1], 20).reshape((-1, 1))
+    expected = _likelihood_ratio_test_many(X, z, Y.copy())
+    checkpoint = _Checkpoint(checkpoint_dir, "test")
+    checkpoint.save([-1.0, -1.0])
+    resumed = _likelihood_ratio_test_many(X, z, Y.copy(), checkpoint)
+    assert resumed[:2] == [-1.0, -1.0]
+    np.testing.assert_allclose(resumed[2:], expected[2:])
+    assert not checkpoint_dir.exists()
+
+    # Results computed with other covariates are discarded.
+    from snapatac2.tools._diff import _diff_test_helper
+    checkpoint = _Checkpoint(checkpoint_dir)
+    _diff_test_helper(Y.copy(), z.ravel(), covariate=X, checkpoint=checkpoint)
+    fingerprint = checkpoint.fingerprint
+    checkpoint.save([-1.0, -1.0])
+    resumed = _diff_test_helper(Y.copy(), z.ravel(), covariate=2 * X, checkpoint=_Checkpoint(checkpoint_dir))
+    assert fingerprint is not None and -1.0 not in resumed
+
+def test_chunk_size(tmp_path):
+    data = snap.datasets.simulate(n_cells=60, n_peaks=100, mean_depth=500, random_state=6)
+    groups = ["a" if i % 2 == 0 else "b" for i in range(data.n_obs)]
+
+    def export(out_dir, chunk_size=None):
+        files = snap.ex.export_fragments(
+            data, groups, out_dir=out_dir, suffix=".bed.gz", chunk_size=chunk_size,
+        )
+        return {k: gzip.open(v, "rt").read() for k, v in files.items()}
+
+    assert snap.get_chunk_size() is None
+    expected = export(tmp_path / "adaptive")
+    assert export(tmp_path / "per_call", chunk_size=7) == expected
+    assert snap.get_chunk_size() is None
+    snap.set_chunk_size(7)
+    try:
+        assert snap.get_chunk_size() == 7
+        assert export(tmp_path / "fixed") == expected
+    finally:
+        snap.set_chunk_size(None)
+
+def test_export_selected_chunks(tmp_path):
+    data = snap.datasets.simulate(
+        n_cells=60, n_peaks=100, mean_depth=500, random_state=6, file=tmp_path / "data.h5ad",
+    )
+    # Cells of group "a" fill the first chunks only.
+    groups = ["a" if i < 20 else "b" if i < 45 else "c" for i in range(data.n_obs)]
+
+    def export(out_dir, selections=None):
+        files = snap.ex.export_fragments(
+            data, groups, selections=selections, out_dir=out_dir, suffix=".bed.gz",
+        )
+        return {k: gzip.open(v, "rt").read() for k, v in files.items()}
+
+    expected = export(tmp_path / "all")
+    assert "fragment_group_rows" not in data.uns
+    snap.set_chunk_size(7)
+    try:
+        for selections in [{"a"}, {"c"}, {"a", "c"}]:
+            result = export(tmp_path / "".join(sorted(selections)), selections)
+            assert result == {k: expected[k] for k in selections}
+
+        # Cells that are not sorted by group.
+        groups = ["a" if i % 3 == 0 else "b" for i in range(data.n_obs)]
+        expected = export(tmp_path / "all_unsorted")
+        result = export(tmp_path / "a_unsorted", {"a"})
+        assert result == {"a": expected["a"]}
+    finally:
+        snap.set_chunk_size(None)
+    data.close()
+
+def test_chrom_filter(tmp_path):
+    data = snap.datasets.simulate(
+        n_cells=40, n_peaks=100, mean_depth=500, random_state=6,
+        chrom_sizes={"chr1": 1_000_000, "chr2": 1_000_000, "chr2_alt": 100_000},
+    )
+    full = snap.pp.add_tile_matrix(data, bin_size=1000, inplace=False)
+
+    snap.set_chrom_filter(exclude=["*_alt"])
+    try:
+        assert snap.get_chrom_filter() == ([], ["*_alt"])
+        mat = snap.pp.add_tile_matrix(data, bin_size=1000, inplace=False)
+        assert all(not x.startswith("chr2_alt") for x in mat.var_names)
+        assert list(mat.var_names) == [x for x in full.var_names if not x.startswith("chr2_alt")]
+
+        snap.set_chrom_filter(include=["chr1"])
+        files = snap.ex.export_fragments(data, ["a"] * data.n_obs, out_dir=tmp_path, suffix=".bed.gz")
+        chroms = {line.split("\t")[0] for line in gzip.open(files["a"], "rt")}
+        assert chroms == {"chr1"}
+    finally:
+        snap.set_chrom_filter()
+    assert snap.get_chrom_filter() is None
+
+def test_cell_mask(tmp_path):
+    data = snap.datasets.simulate(
+        n_cells=40, n_cell_types=2, n_peaks=100, mean_depth=500, random_state=8,
+        chrom_sizes={"chr1": 1_000_000}, file=tmp_path / "data.h5ad",
+    )
+    full = snap.pp.add_tile_matrix(data, bin_size=1000, inplace=False).X[:]
+    excluded = np.arange(data.n_obs) % 3 == 0
+    data.obs["excluded"] = excluded
+
+    snap.set_cell_mask("excluded")
+    try:
+        assert snap.get_cell_mask() == "excluded"
+        mat = snap.pp.add_tile_matrix(data, bin_size=1000, inplace=False).X[:]
+        assert mat.shape == full.shape
+        assert mat[excluded].sum() == 0
+        assert (mat[~excluded] != full[~excluded]).nnz == 0
+
+        snap.metrics.frag_size_distr(data)
+        files = snap.ex.export_fragments(data, ["a"] * data.n_obs, out_dir=tmp_path, suffix=".bed.gz")
+        barcodes = {line.split("\t")[3] for line in gzip.open(files["a"], "rt")}
+        assert barcodes == set(np.array(data.obs_names)[~excluded])
+
+        snap.set_cell_mask("missing")
+        with pytest.raises(Exception, match="cell mask"):
+            snap.pp.add_tile_matrix(data, bin_size=1000, inplace=False)
+    finally:
+        snap.set_cell_mask()
+    assert snap.get_cell_mask() is None
+    data.close()
+
+def test_fragment_storage(tmp_path):
+    import h5py
+
+    def storage_size(key):
+        with h5py.File(tmp_path / "data.h5ad", "r") as f:
+            group = f["obsm"][key]
+            return sum(group[k].id.get_storage_size() for k in group if isinstance(group[k], h5py.Dataset))
+
+    data = snap.datasets.simulate(n_cells=50, n_peaks=100, mean_depth=2000, random_state=7, file=tmp_path / "data.h5ad")
+    expected = snap.pp.add_tile_matrix(data, bin_size=500, inplace=False).X[:]
+    data.close()
+    v1_size = storage_size("fragment_paired")
+    data = snap.read(tmp_path / "data.h5ad")
+
+    assert snap.pp.convert_fragments(data, "v2")
+    data.close()
+    assert storage_size("fragment_paired_v2") < v1_size
+    data = snap.read(tmp_path / "data.h5ad")
+    assert not snap.pp.convert_fragments(data, "v2")
+    assert "fragment_paired_v2" in data.obsm and "fragment_paired" not in data.obsm
+    assert snap.validate(data, verbose=False)["valid"]
+    assert (snap.pp.add_tile_matrix(data, bin_size=500, inplace=False).X[:] != expected).nnz == 0
+
+    assert snap.pp.convert_fragments(data, "v1")
+    assert "fragment_paired" in data.obsm
+    assert (snap.pp.add_tile_matrix(data, bin_size=500, inplace=False).X[:] != expected).nnz == 0
+    data.close()
+
+def test_compact_fragments(tmp_path):
+    data = snap.datasets.simulate(n_cells=50, n_peaks=100, mean_depth=500, random_state=9, file=tmp_path / "data.h5ad")
+    expected = snap.pp.add_tile_matrix(data, bin_size=500, inplace=False).X[:]
+
+    # Without repacking, the space of the original fragments is not released.
+    assert snap.pp.compact_fragments(data, chunk_size=16, repack=False) == 0
+    size = os.path.getsize(tmp_path / "data.h5ad")
+    assert snap.pp.compact_fragments(data, chunk_size=16) == 0
+    assert os.path.getsize(tmp_path / "data.h5ad") < size
+    assert list(data.obsm.keys()).count("fragment_paired") == 1
+    assert "fragment_paired_compacted" not in data.obsm
+    assert (snap.pp.add_tile_matrix(data, bin_size=500, inplace=False).X[:] != expected).nnz == 0
+
+    snap.pp.convert_fragments(data, "v2")
+    assert snap.pp.compact_fragments(data) == 0
+    assert "fragment_paired_v2" in data.obsm
+    assert (snap.pp.add_tile_matrix(data, bin_size=500, inplace=False).X[:] != expected).nnz == 0
+    data.close()
+
+def test_coverage_cache(tmp_path):
+    import pandas as pd
+
+    data = snap.datasets.simulate(n_cells=50, n_peaks=100, mean_depth=500, random_state=3, file=tmp_path / "data.h5ad")
+    expected = snap.metrics.summary_by_chrom(data, mode='count')
+    groups = ['a'] * 25 + ['b'] * 25
+
+    info = snap.pp.build_coverage_cache(data, bin_size=500)
+    assert info['bin_size'] == 500 and info['n_obs'] == data.n_obs
+    assert 'fragment_checksum' not in data.uns
+    assert 'coverage_cache' in data.obsm
+    summary = snap.metrics.summary_by_chrom(data, mode='count')
+    assert all(np.allclose(summary[k], expected[k]) for k in expected)
+
+    files = snap.ex.export_coverage(
+        data, groupby=groups, normalization=None, suffix='.bedgraph', out_dir=tmp_path, use_cache=True,
+    )
+    total = sum(
+        pd.read_csv(files[k], sep='\t', header=None)[3].sum() for k in ['a', 'b']
+    )
+    assert total == 2 * sum(v.sum() for v in expected.values())
+
+    # The checksum does not depend on the storage format, so the cache remains valid.
+    snap.pp.convert_fragments(data, "v2")
+    assert snap._snapatac2.coverage_cache_info(data) is not None
+
+    # Replacing the fragments invalidates the cache.
+    other = snap.datasets.simulate(n_cells=50, n_peaks=100, mean_depth=500, random_state=4)
+    del data.obsm['fragment_paired_v2']
+    data.obsm['fragment_paired'] = other.obsm['fragment_paired']
+    assert snap._snapatac2.coverage_cache_info(data) is None
+    data.close()
+
+def test_map_barcodes(tmp_path):
+    data = snap.datasets.simulate(n_cells=20, n_peaks=50, mean_depth=200, random_state=1, file=tmp_path / "data.h5ad")
+    obs_names = list(data.obs_names)
+    ids = [f"{x}-1" for x in obs_names]
+    samples = ['s1'] * 10 + ['s2'] * 10
+    names = snap.ex.map_barcodes(data, ids=ids, strip_suffix="-1", sample_prefix=samples)
+    assert names == [f"{s}_{x}" for s, x in zip(samples, data.obs_names)]
+
+    with pytest.raises(Exception, match="renamed"):
+        snap.ex.map_barcodes(data, ids=["AAA-1"] * 10 + ["AAA-2"] * 10, strip_suffix=r"-\d+")
+
+    files = snap.ex.export_fragments(
+        data, groupby=['a'] * 20, ids=ids, strip_suffix="-1", sample_prefix=samples,
+        out_dir=tmp_path, suffix='.bed.gz',
+    )
+    with gzip.open(files['a'], 'rt') as fl:
+        exported = {line.split('\t')[3] for line in fl}
+    assert exported <= set(names)
+    assert list(data.obs_names) == obs_names
+    data.close()
+
+def test_sort_fragment_file(tmp_path):
+    fragment_file = tmp_path / "fragments.tsv.gz"
+    snap.datasets.simulate(n_cells=20, n_peaks=50, mean_depth=200, random_state=2, fragment_file=fragment_file)
+    output = tmp_path / "sorted.tsv.gz"
+    index = snap.pp.sort_fragment_file(fragment_file, output)
+    assert index.exists()
+
+    with gzip.open(fragment_file, 'rt') as fl:
+        expected = sorted(line for line in fl if not line.startswith('#'))
+    with gzip.open(output, 'rt') as fl:
+        records = [line for line in fl if not line.startswith('#')]
+    assert sorted(records) == expected
+    keys = [(x.split('\t')[0], int(x.split('\t')[1]), int(x.split('\t')[2])) for x in records]
+    assert keys == sorted(keys)
+
+    from snapatac2.__main__ import main
+    output = tmp_path / "sorted_cli.tsv.gz"
+    main(["sort-fragments", str(fragment_file), str(output)])
+    with gzip.open(output, 'rt') as fl:
+        assert [line for line in fl if not line.startswith('#')] == records
+
+    with pytest.raises(Exception, match="cannot open file"):
+        snap.pp.sort_fragment_file(tmp_path / "missing.tsv.gz", output)
+
+def test_tn5_bias(tmp_path):
+    import pandas as pd
+
+    chrom_sizes = {"chr1": 200_000, "chr2": 100_000}
+    data = snap.datasets.simulate(
+        n_cells=30, n_peaks=50, mean_depth=500, chrom_sizes=chrom_sizes,
+        random_state=2, file=tmp_path / "data.h5ad",
+    )
+    rng = np.random.default_rng(0)
+    fasta = tmp_path / "genome.fa"
+    with open(fasta, "w") as f:
+        for chrom, size in chrom_sizes.items():
+            seq = "".join(rng.choice(list("ACGT"), size))
+            f.write(f">{chrom}\n")
+            f.write("\n".join(seq[i:i+60] for i in range(0, size, 60)) + "\n")
+
+    model = snap.tl.fit_tn5_bias(data, fasta, k=4, max_insertions=5000)
+    assert len(model['bias']) == 4**4
+    assert 4900 < sum(model['observed']) <= 5000
+    assert 'tn5_bias' in data.uns
+
+    groups = ['a'] * 15 + ['b'] * 15
+    files = snap.ex.export_coverage(
+        data, groupby=groups, bin_size=100, normalization=None, counting_strategy='insertion',
+        suffix='.bedgraph', out_dir=tmp_path, bias_correction=True, genome_fasta=fasta,
+    )
+    assert all(pd.read_csv(files[k], sep='\t', header=None)[3].sum() > 0 for k in ['a', 'b'])
+
+    out = snap.ex.export_expected_bias(data, fasta, tmp_path / "bias.bedgraph", bin_size=1000)
+    bias = pd.read_csv(out, sep='\t', header=None)
+    assert len(bias) == 300
+    assert np.isclose(bias[3].mean(), 1.0, atol=0.2)
+    data.close()
+
+def test_tss_profile(tmp_path):
+    data = snap.datasets.simulate(n_cells=30, n_peaks=200, mean_depth=1000, random_state=4, file=tmp_path / "data.h5ad")
+    gtf = tmp_path / "genes.gtf"
+    with open(gtf, "w") as f:
+        for i in range(1, 200):
+            strand = "+" if i % 2 == 0 else "-"
+            f.write(f"chr1\ttest\ttranscript\t{i * 50000}\t{i * 50000 + 3000}\t.\t{strand}\t.\tgene_id \"g{i}\";\n")
+
+    snap.metrics.tss_profile(data, gtf, window_size=2000, bin_size=10)
+    mat = data.obsm['tss_profile']
+    assert mat.shape == (data.n_obs, 401)
+    positions = data.uns['tss_profile_positions']
+    assert positions[0] == -2000 and positions[-1] == 2000
+    widths = data.uns['tss_profile_widths']
+    assert widths[0] == 10 and widths[-1] == 1 and widths.sum() == 4001
+
+    # The aggregated profile agrees with the library-level profile of `tsse`.
+    result = snap.metrics.tsse(data, gtf, inplace=False)
+    assert mat.sum() == result['TSS_profile'].sum()
+    data.close()
+
+def test_signal_partition(tmp_path):
+    data = snap.datasets.simulate(n_cells=30, n_peaks=200, mean_depth=1000, random_state=5, file=tmp_path / "data.h5ad")
+    gtf = tmp_path / "genes.gtf"
+    with open(gtf, "w") as f:
+        for i in range(1, 100):
+            start = i * 100000
+            f.write(f"chr1\ttest\ttranscript\t{start}\t{start + 5000}\t.\t+\t.\tgene_id \"g{i}\";\n")
+            f.write(f"chr1\ttest\texon\t{start}\t{start + 500}\t.\t+\t.\tgene_id \"g{i}\";\n")
+    peaks = list(data.uns['simulated_peaks'])
+
+    snap.metrics.signal_partition(data, gtf, peaks=peaks, blacklist=["chr2:1-1000000"])
+    for k in ['frac_promoter', 'frac_exon', 'frac_distal_peak', 'frac_blacklist']:
+        assert ((data.obs[k] >= 0) & (data.obs[k] <= 1)).all()
+    assert 'promoter_distal_ratio' in data.obs
+
+    # Distal peaks are a subset of the peaks.
+    frip = snap.metrics.frip(data, {"peaks": peaks}, inplace=False)
+    assert np.all(np.array(frip['peaks']) >= data.obs['frac_distal_peak'].to_numpy() - 1e-12)
+    data.close()
+
+def test_chromatin_states(tmp_path):
+    import pandas as pd
+
+    chrom_sizes = {"chr1": 2_000_000, "chr2": 1_000_000}
+    data = snap.datasets.simulate(
+        n_cells=100, n_cell_types=2, n_peaks=300, mean_depth=2000, chrom_sizes=chrom_sizes,
+        random_state=6, file=tmp_path / "data.h5ad",
+    )
+    tracks = snap.ex.export_coverage(
+        data, groupby="cell_type", bin_size=200, normalization=None,
+        suffix=".bedgraph", out_dir=tmp_path,
+    )
+    model = snap.tl.chromatin_states(
+        tracks, chrom_sizes, tmp_path / "states.bed", n_states=3, max_iter=20,
+    )
+    assert model['emission'].shape == (3, 2)
+    assert np.allclose(model['transition'].sum(axis=1), 1)
+    assert np.all(np.diff(model['log_likelihood']) > -1e-6)
+
+    segments = pd.read_csv(tmp_path / "states.bed", sep="\t", header=None)
+    assert set(segments[3]) <= {"E1", "E2", "E3"}
+    for chrom, size in chrom_sizes.items():
+        seg = segments[segments[0] == chrom]
+        assert seg[1].iloc[0] == 0 and seg[2].iloc[-1] == size
+        assert np.all(seg[1].to_numpy()[1:] == seg[2].to_numpy()[:-1])
+    data.close()
+
+def test_nucleosome_positions(tmp_path):
+    import pandas as pd
+
+    data = snap.datasets.simulate(
+        n_cells=100, n_cell_types=2, n_peaks=50, mean_depth=5000,
+        chrom_sizes={"chr1": 1_000_000}, random_state=7, file=tmp_path / "data.h5ad",
+    )
+    peaks = list(data.uns['simulated_peaks'])
+    files = snap.tl.nucleosome_positions(
+        data, peaks, groupby="cell_type", out_dir=tmp_path, min_signal=0.5,
+    )
+    assert set(files.keys()) == set(data.obs['cell_type'])
+    for occupancy_file, dyad_file in files.values():
+        occupancy = pd.read_csv(occupancy_file, sep="\t", header=None)
+        assert ((occupancy[3] >= 0) & (occupancy[3] <= 1)).all()
+        dyads = pd.read_csv(dyad_file, sep="\t", header=None)
+        assert (dyads[3] >= 0.5).all()
+        assert ((dyads[4] >= 0) & (dyads[4] <= 1)).all()
+
+    files = snap.tl.nucleosome_positions(
+        data, peaks, groupby=["T/NK"] * data.n_obs, out_dir=tmp_path, min_signal=0.5,
+    )
+    assert Path(files["T/NK"][0]).name == "T_NK.occupancy.bedGraph"
+    data.close()
+
+def test_diff_footprint(tmp_path):
+    fragment_file = tmp_path / "fragments.tsv.gz"
+    tf_sites = [1000 * k + 500 for k in range(20)]
+    null_sites = [100000 + s for s in tf_sites]
+    with gzip.open(fragment_file, "wt") as f:
+        for group in ["A", "B"]:
+            for i in range(8):
+                for s in tf_sites:
+                    # Cells of group A leave the center of the sites inaccessible.
+                    start, end = (s - 40, s - 10) if group == "A" else (s - 2, s + 40)
+                    f.write(f"chr1\t{start}\t{end}\t{group}{i}\t1\n")
+                for s in null_sites:
+                    f.write(f"chr1\t{s - 2}\t{s + 40}\t{group}{i}\t1\n")
+    data = snap.pp.import_fragments(
+        fragment_file,
+        chrom_sizes={"chr1": 200000},
+        sorted_by_barcode=False,
+        min_num_fragments=0,
+    )
+    motifs = {
+        "TF": [f"chr1:{s}-{s + 1}" for s in tf_sites],
+        "NULL": [f"chr1:{s}-{s + 1}" for s in null_sites],
+    }
+    result = snap.tl.diff_footprint(
+        data, motifs, [f"A{i}" for i in range(8)], [f"B{i}" for i in range(8)],
+        flank=50, center=5,
+    )
+    tf, null = result.row(0, named=True), result.row(1, named=True)
+    assert tf["motif"] == "TF" and tf["n_sites"] == 20
+    assert tf["n_insertions_1"] == tf["n_insertions_2"] == 8 * 20 * 2
+    assert tf["footprint_depth_1"] == 1.0 and tf["depth_difference"] > 0
+    assert tf["p-value"] < 0.01 and tf["n_diff_positions"] == 4
+    assert null["depth_difference"] == 0 and null["p-value"] == 1.0
+    assert null["n_diff_positions"] == 0
+
+    # The expected bias of the sites scales the depths of both groups alike.
+    rng = np.random.default_rng(0)
+    fasta = tmp_path / "genome.fa"
+    with open(fasta, "w") as f:
+        seq = "".join(rng.choice(list("ACGT"), 200000))
+        f.write(">chr1\n" + "\n".join(seq[i:i+60] for i in range(0, len(seq), 60)) + "\n")
+    snap.tl.fit_tn5_bias(data, fasta, k=2)
+    with pytest.raises(ValueError, match="genome_fasta"):
+        snap.tl.diff_footprint(data, motifs, [0], [1], bias_correction=True)
+    corrected = snap.tl.diff_footprint(
+        data, motifs, [f"A{i}" for i in range(8)], [f"B{i}" for i in range(8)],
+        flank=50, center=5, bias_correction=True, genome_fasta=fasta,
+    )
+    tf, null = corrected.row(0, named=True), corrected.row(1, named=True)
+    assert tf["footprint_depth_1"] == 1.0 and tf["depth_difference"] > 0
+    assert null["depth_difference"] == 0
+
+def test_export_vplot(tmp_path):
+    import pandas as pd
+
+    data = snap.datasets.simulate(
+        n_cells=50, n_cell_types=2, n_peaks=50, mean_depth=2000,
+        chrom_sizes={"chr1": 1_000_000}, random_state=8, file=tmp_path / "data.h5ad",
+    )
+    peaks = list(data.uns['simulated_peaks'])
+    files = snap.ex.export_vplot(
+        data, peaks, groupby="cell_type", flank=500, max_frag_length=400, out_dir=tmp_path,
+    )
+    assert set(files.keys()) == set(data.obs['cell_type'])
+    mats = {k: np.load(v) for k, v in files.items()}
+    assert all(m.shape == (401, 1001) for m in mats.values())
+    assert sum(m.sum() for m in mats.values()) > 0
+
+    files = snap.ex.export_vplot(
+        data, peaks, flank=500, max_frag_length=400, out_dir=tmp_path, suffix=".parquet",
+    )
+    df = pd.read_parquet(files["all"])
+    assert df['count'].sum() == sum(m.sum() for m in mats.values())
+    assert df['offset'].between(-500, 500).all()
+    data.close()
+
+def test_base_coverage(tmp_path):
+    data = snap.datasets.simulate(
+        n_cells=50, n_cell_types=2, n_peaks=50, mean_depth=1000,
+        chrom_sizes={"chr1": 1_000_000}, random_state=9, file=tmp_path / "data.h5ad",
+    )
+    peak = data.uns['simulated_peaks'][0]
+    cov = snap.ex.base_coverage(data, peak, groupby="cell_type")
+    assert set(cov.keys()) == set(data.obs['cell_type'])
+    chrom, rest = peak.split(":")
+    start, end = map(int, rest.split("-"))
+    assert all(len(v) == end - start for v in cov.values())
+
+    total = snap.ex.base_coverage(data, peak)["all"]
+    np.testing.assert_array_equal(total, sum(cov.values()))
+
+    # The fragments spanning the first base are counted once.
+    files = snap.ex.export_fragments(data, ["all"] * data.n_obs, out_dir=tmp_path, suffix=".bed.gz")
+    n = 0
+    with gzip.open(files["all"], "rt") as f:
+        for line in f:
+            c, s, e = line.split("\t")[:3]
+            n += c == chrom and int(s) <= start < int(e)
+    assert total[0] == n
+
+    ins = snap.ex.base_coverage(data, peak, counting_strategy="insertion", selections=["all"])
+    assert list(ins.keys()) == ["all"] and ins["all"].sum() > 0
+    data.close()
+
+def test_combine_tracks(tmp_path):
+    a = tmp_path / "a.bedgraph"
+    b = tmp_path / "b.bedgraph.gz"
+    a.write_text("chr1\t0\t10\t1\nchr1\t10\t20\t3\nchr2\t0\t5\t2\n")
+    with gzip.open(b, "wt") as f:
+        f.write("chr1\t5\t15\t1\nchrUn\t0\t5\t9\n")
+    chrom_sizes = {"chr1": 100, "chr2": 100}
+
+    def read(file):
+        return [line.rstrip("\n").split("\t") for line in open(file)]
+
+    out = snap.ex.combine_tracks([a, b], chrom_sizes, tmp_path / "sum.bedgraph", op="sum")
+    assert read(out) == [
+        ["chr1", "0", "5", "1"], ["chr1", "5", "10", "2"], ["chr1", "10", "15", "4"],
+        ["chr1", "15", "20", "3"], ["chr2", "0", "5", "2"],
+    ]
+    out = snap.ex.combine_tracks({"a": a, "b": b}, chrom_sizes, tmp_path / "max.bedgraph", op="max")
+    assert read(out) == [["chr1", "0", "10", "1"], ["chr1", "10", "20", "3"], ["chr2", "0", "5", "2"]]
+    out = snap.ex.combine_tracks([a, b], chrom_sizes, tmp_path / "mean.bw")
+    assert out.exists()
+    with pytest.raises(Exception, match="not found"):
+        snap.ex.combine_tracks([a, tmp_path / "missing.bedgraph"], chrom_sizes, tmp_path / "x.bedgraph")
+
+def test_add_track_signal(tmp_path):
+    chrom_sizes = {"chr1": 20_000, "chr2": 10_000}
+    data = snap.datasets.simulate(
+        n_cells=20, n_peaks=20, mean_depth=200, chrom_sizes=chrom_sizes, random_state=5,
+    )
+    tiles = snap.pp.add_tile_matrix(data, bin_size=5000, inplace=False)
+    a = tmp_path / "h3k27ac.bedgraph"
+    a.write_text("chr1\t0\t2500\t4\nchr1\t5000\t10000\t1\nchr2\t0\t5000\t3\n")
+    bw = snap.ex.combine_tracks([a], chrom_sizes, tmp_path / "ctcf.bw")
+
+    snap.pp.add_track_signal(tiles, [a, bw])
+    signal = dict(zip(tiles.var_names, np.asarray(tiles.var["h3k27ac"])))
+    assert signal["chr1:0-5000"] == 2 and signal["chr1:5000-10000"] == 1
+    assert signal["chr2:0-5000"] == 3 and signal["chr2:5000-10000"] == 0
+    np.testing.assert_allclose(tiles.var["ctcf"], tiles.var["h3k27ac"])
+
+    snap.pp.add_track_signal(tiles, {"a": a}, op="max", key_added="chip")
+    assert list(tiles.uns["chip_names"]) == ["a"]
+    assert np.asarray(tiles.varm["chip"]).shape == (tiles.n_vars, 1)
+
+def test_track_correlation(tmp_path):
+    chrom_sizes = {"chr1": 1000}
+    a, b, c = (tmp_path / f"{x}.bedgraph" for x in "abc")
+    a.write_text("chr1\t0\t100\t1\nchr1\t100\t200\t5\nchr1\t300\t400\t2\n")
+    b.write_text("chr1\t0\t100\t2\nchr1\t100\t200\t9\nchr1\t300\t400\t3\n")
+    c.write_text("chr1\t0\t100\t5\nchr1\t100\t200\t1\nchr1\t300\t400\t2\n")
+
+    cor = snap.ex.track_correlation({"x": a}, [b, c], chrom_sizes, bin_size=100)
+    assert cor.columns == ["group", "b", "c"]
+    assert cor["group"].to_list() == ["x"]
+    assert cor["b"][0] == pytest.approx(1.0)
+    assert cor["c"][0] < 0
+
+def test_quantile_zscore_normalization(tmp_path):
+    import pandas as pd
+
+    chrom_sizes = {"chr1": 1_000_000}
+    data = snap.datasets.simulate(
+        n_cells=50, n_cell_types=2, n_peaks=100, mean_depth=1000, chrom_sizes=chrom_sizes,
+        random_state=11, file=tmp_path / "data.h5ad",
+    )
+    def export(norm):
+        tracks = snap.ex.export_coverage(
+            data, groupby="cell_type", bin_size=100, normalization=norm,
+            suffix=".bedgraph", out_dir=tmp_path, prefix=f"{norm}_",
+        )
+        return {k: pd.read_csv(v, sep="\t", header=None) for k, v in tracks.items()}
+
+    raw = export(None)
+    for group, track in export("quantile").items():
+        assert track[3].min() > 0 and track[3].max() == 1
+        assert np.all(np.diff(track[3].to_numpy()[np.argsort(raw[group][3].to_numpy())]) >= 0)
+    for group, track in export("zscore").items():
+        # The z-scores are a linear function of the counts.
+        slope, intercept = np.polyfit(raw[group][3], track[3], 1)
+        assert slope > 0 and intercept < 0
+        np.testing.assert_allclose(raw[group][3] * slope + intercept, track[3], atol=1e-6)
+    data.close()
+
+def test_rpgc_normalization(tmp_path):
+    import pandas as pd
+
+    genome_size = 1_000_000
+    data = snap.datasets.simulate(
+        n_cells=50, n_cell_types=2, n_peaks=100, mean_depth=1000,
+        chrom_sizes={"chr1": genome_size}, random_state=11, file=tmp_path / "data.h5ad",
+    )
+    def mean_coverage(effective_genome_size):
+        tracks = snap.ex.export_coverage(
+            data, groupby="cell_type", bin_size=1, normalization="RPGC",
+            effective_genome_size=effective_genome_size, suffix=".bedgraph",
+            out_dir=tmp_path, prefix=f"{effective_genome_size}_",
+        )
+        return [
+            (track[3] * (track[2] - track[1])).sum() / genome_size
+            for track in (pd.read_csv(v, sep="\t", header=None) for v in tracks.values())
+        ]
+
+    np.testing.assert_allclose(mean_coverage(None), 1.0)
+    np.testing.assert_allclose(mean_coverage(genome_size // 2), 2.0)
+    data.close()
+
+def test_export_paired_insertion(tmp_path):
+    import pandas as pd
+
+    data = snap.datasets.simulate(
+        n_cells=50, n_cell_types=2, n_peaks=100, mean_depth=1000,
+        chrom_sizes={"chr1": 1_000_000}, random_state=11, file=tmp_path / "data.h5ad",
+    )
+    def total(counting_strategy, bin_size):
+        tracks = snap.ex.export_coverage(
+            data, groupby="cell_type", bin_size=bin_size, normalization=None,
+            counting_strategy=counting_strategy, suffix=".bedgraph",
+            out_dir=tmp_path, prefix=f"{counting_strategy}_{bin_size}_",
+        )
+        return sum(
+            (track[3] * (track[2] - track[1])).sum() / bin_size
+            for track in (pd.read_csv(v, sep="\t", header=None) for v in tracks.values())
+        )
+
+    assert total("paired-insertion", 1) == total("insertion", 1)
+    assert total("paired-insertion", 100_000) < total("insertion", 100_000)
+    data.close()
+
+def test_spot(tmp_path):
+    import json
+
+    data = snap.datasets.simulate(
+        n_cells=50, n_cell_types=2, n_peaks=100, mean_depth=1000, frip=0.6,
+        chrom_sizes={"chr1": 1_000_000}, random_state=12, file=tmp_path / "data.h5ad",
+    )
+    peaks = list(data.uns['simulated_peaks'])
+    result = snap.metrics.spot(data, peaks, "cell_type", inplace=False)
+    assert set(result.keys()) == set(data.obs['cell_type'])
+    for qc in result.values():
+        assert qc["spot"] == qc["n_insertions_in_peaks"] / qc["n_insertions"]
+        assert 0 < qc["spot"] < 1 and qc["enrichment"] > 1
+
+    total = snap.metrics.spot(data, peaks, inplace=False)["all"]
+    assert total["n_insertions"] == sum(x["n_insertions"] for x in result.values())
+    no_peaks = snap.metrics.spot(data, ["chr2:1-100"], inplace=False)["all"]
+    assert no_peaks["spot"] == 0
+    short = snap.metrics.spot(data, peaks, max_frag_length=1, inplace=False)["all"]
+    assert short["n_insertions"] == 0
+
+    files = snap.ex.export_coverage(
+        data, groupby="cell_type", suffix=".bedgraph", out_dir=tmp_path, peaks=peaks,
+    )
+    with open(tmp_path / "manifest.json") as f:
+        manifest = json.load(f)["groups"]
+    assert set(manifest.keys()) == set(files.keys())
+    for group, x in manifest.items():
+        assert x["file"] == str(files[group])
+        assert x["spot"] == result[group]["spot"]
+    assert list(data.uns["spot"]["group"]) == sorted(files.keys())
+    data.close()
+
+def test_peak_allocation():
+    data = snap.datasets.simulate(
+        n_cells=50, n_cell_types=2, n_peaks=100, mean_depth=1000, frip=0.6,
+        chrom_sizes={"chr1": 1_000_000}, random_state=12,
+    )
+    peaks = list(data.uns['simulated_peaks'])
+    report = snap.metrics.peak_allocation(data, peaks, "cell_type")
+    assert report.height == 2 * 3
+    assert report["strategy"].to_list()[:3] == ["fragment", "insertion", "paired-insertion"]
+    for group in set(data.obs['cell_type']):
+        rows = report.filter(report["group"] == group)
+        n_fragments = rows["n_no_peak"] + rows["n_one_peak"] + rows["n_multiple_peaks"]
+        assert n_fragments.n_unique() == 1
+
+    total = snap.metrics.peak_allocation(data, peaks)
+    insertion = total.filter(total["strategy"] == "insertion").row(0, named=True)
+    pic = total.filter(total["strategy"] == "paired-insertion").row(0, named=True)
+    # Both strategies count the same peaks, but insertion counts twice the
+    # fragments whose insertions fall in the same peak.
+    assert insertion["n_one_peak"] == pic["n_one_peak"]
+    assert insertion["n_counts"] >= pic["n_counts"]
+
+    with pytest.raises(Exception, match="invalid region"):
+        snap.metrics.peak_allocation(data, ["chr1-100"])
+
+    mat = snap.pp.make_peak_matrix(data, use_rep=peaks, counting_strategy="paired-insertion")
+    assert pic["n_counts"] == mat.X.sum()
+
+def test_export_multiple_keys(tmp_path):
+    data = snap.datasets.simulate(
+        n_cells=60, n_cell_types=2, n_peaks=50, mean_depth=500,
+        chrom_sizes={"chr1": 1_000_000}, random_state=13, file=tmp_path / "data.h5ad",
+    )
+    data.obs['condition'] = ["ctrl/a" if i % 2 == 0 else "treated" for i in range(data.n_obs)]
+    files = snap.ex.export_fragments(
+        data, ["cell_type", "condition"], out_dir=tmp_path / "frag", prefix="p_", suffix=".bed.gz",
+    )
+    combos = set(zip(map(str, data.obs['cell_type']), data.obs['condition']))
+    assert set(files.keys()) == combos
+    n = 0
+    for (cell_type, condition), file in files.items():
+        assert Path(file) == tmp_path / "frag" / cell_type / f"p_{condition.replace('/', '+')}.bed.gz"
+        with gzip.open(file, "rt") as f:
+            n += sum(1 for _ in f)
+    single = snap.ex.export_fragments(data, "cell_type", out_dir=tmp_path / "single", suffix=".bed.gz")
+    with gzip.open(list(single.values())[0], "rt") as f:
+        assert sum(1 for _ in f) > 0
+    assert n == sum(sum(1 for _ in gzip.open(x, "rt")) for x in single.values())
+
+    selected = sorted(combos)[0]
+    tracks = snap.ex.export_coverage(
+        data, ["cell_type", "condition"], selections=[selected],
+        out_dir=tmp_path / "cov", suffix=".bedgraph",
+    )
+    assert list(tracks.keys()) == [selected]
+    assert Path(tracks[selected]).exists()
+    data.close()
+
+def test_export_coverage_missing_groups(tmp_path):
+    data = snap.datasets.simulate(
+        n_cells=60, n_cell_types=2, n_peaks=50, mean_depth=500,
+        chrom_sizes={"chr1": 1_000_000}, random_state=14, file=tmp_path / "data.h5ad",
+    )
+    data.obs['batch'] = [None if i % 3 == 0 else f"b{i % 2}" for i in range(data.n_obs)]
+    export = lambda groupby, missing, **kwargs: snap.ex.export_coverage(
+        data, groupby, missing=missing, normalization=None,
+        out_dir=tmp_path / missing, suffix=".bedgraph", **kwargs,
+    )
+    with pytest.raises(Exception, match="missing value"):
+        export("batch", "error")
+    assert set(export("batch", "drop").keys()) == {"b0", "b1"}
+    cell_types = set(map(str, data.obs['cell_type']))
+    kept = export(["cell_type", "batch"], "keep")
+    assert {x[1] for x in kept.keys()} == {"b0", "b1", None}
+    assert {x[0] for x in kept.keys()} == cell_types
+    assert (tmp_path / "keep" / sorted(cell_types)[0] / "NA.bedgraph").exists()
+
+    # Missing values are told apart from the values "NA", and the cells with
+    # an empty value are kept.
+    data.obs['batch'] = [[None, "NA", ""][i % 3] for i in range(data.n_obs)]
+    assert set(export("batch", "drop").keys()) == {"NA", ""}
+    selected = (sorted(cell_types)[0], None)
+    kept = export(["cell_type", "batch"], "keep", selections=[selected])
+    assert list(kept.keys()) == [selected]
+    assert Path(kept[selected]).name == "NA_.bedgraph"
+
+    # The other exporters name the missing groups as before.
+    data.obs['batch'] = [None if i % 3 == 0 else f"b{i % 2}" for i in range(data.n_obs)]
+    files = snap.ex.export_fragments(data, "batch", out_dir=tmp_path / "fragments", suffix=".bed.gz")
+    assert set(files.keys()) == {"None", "b0", "b1"}
+    data.close()
+
+def test_export_fragments_stream(tmp_path):
+    import os
+    import threading
+
+    data = snap.datasets.simulate(
+        n_cells=40, n_cell_types=2, n_peaks=50, mean_depth=500,
+        chrom_sizes={"chr1": 1_000_000}, random_state=2, file=tmp_path / "data.h5ad",
+    )
+    group = str(data.obs['cell_type'][0])
+    files = snap.ex.export_fragments(
+        data, "cell_type", selections=[group], out_dir=tmp_path, suffix=".bed",
+    )
+    with open(files[group]) as f:
+        expected = sorted(f.readlines())
+
+    fifo = tmp_path / "fragments.pipe"
+    os.mkfifo(fifo)
+    received = []
+    def read_pipe():
+        with open(fifo) as f:
+            received.extend(f.readlines())
+    reader = threading.Thread(target=read_pipe)
+    reader.start()
+    out = snap.ex.export_fragments(data, "cell_type", selections=[group], out_file=fifo)
+    reader.join()
+    assert out == {group: str(fifo)}
+    assert sorted(received) == expected
+
+    with pytest.raises(ValueError):
+        snap.ex.export_fragments(data, "cell_type", out_file="-")
+    data.close()
+
+def test_export_coverage_capping(tmp_path):
+    data = snap.datasets.simulate(
+        n_cells=40, n_cell_types=2, n_peaks=50, mean_depth=500,
+        chrom_sizes={"chr1": 1_000_000}, random_state=3, file=tmp_path / "data.h5ad",
+    )
+    def read_values(files):
+        return {
+            k: np.array([float(line.split("\t")[3]) for line in open(v)])
+            for k, v in files.items()
+        }
+    raw = read_values(snap.ex.export_coverage(
+        data, groupby="cell_type", suffix=".bedgraph", normalization=None,
+        out_dir=tmp_path / "raw",
+    ))
+    capped = read_values(snap.ex.export_coverage(
+        data, groupby="cell_type", suffix=".bedgraph", normalization=None,
+        max_value=2, out_dir=tmp_path / "capped",
+    ))
+    winsorized = read_values(snap.ex.export_coverage(
+        data, groupby="cell_type", suffix=".bedgraph", normalization=None,
+        winsorize=0.9, out_dir=tmp_path / "winsorized",
+    ))
+    for k in raw:
+        assert capped[k].max() <= 2
+        assert winsorized[k].max() <= raw[k].max()
+        assert winsorized[k].max() >= np.quantile(raw[k], 0.5)
+    with pytest.raises(Exception):
+        snap.ex.export_coverage(
+            data, groupby="cell_type", suffix=".bedgraph", max_value=2, winsorize=0.9,
+            out_dir=tmp_path / "both",
+        )
+    data.close()
+
+def test_export_coverage_streamed(tmp_path):
+    chrom_sizes = {"chr1": 50_000, "chr2": 30_000}
+    data = snap.datasets.simulate(
+        n_cells=30, n_cell_types=2, n_peaks=30, mean_depth=300,
+        chrom_sizes=chrom_sizes, random_state=6, file=tmp_path / "data.h5ad",
+    )
+    def in_memory(file):
+        coverage = {k: np.zeros(v) for k, v in chrom_sizes.items()}
+        with gzip.open(file, "rt") as f:
+            for line in f:
+                chrom, start, end = line.split("\t")[:3]
+                coverage[chrom][int(start):int(end)] += 1
+        return coverage
+    def read(file):
+        coverage = {k: np.zeros(v) for k, v in chrom_sizes.items()}
+        for line in open(file):
+            chrom, start, end, value = line.rstrip("\n").split("\t")
+            coverage[chrom][int(start):int(end)] = float(value)
+        return coverage
+
+    # The tracks streamed to the output match the coverage of the fragments
+    # computed in memory.
+    fragments = snap.ex.export_fragments(
+        data, groupby="cell_type", out_dir=tmp_path / "fragments", suffix=".bed.gz",
+    )
+    kwargs = dict(groupby="cell_type", bin_size=1, normalization=None, max_frag_length=None)
+    bedgraph = snap.ex.export_coverage(
+        data, suffix=".bedgraph", out_dir=tmp_path / "bedgraph", **kwargs,
+    )
+    bigwig = snap.ex.export_coverage(data, suffix=".bw", out_dir=tmp_path / "bigwig", **kwargs)
+    assert set(bedgraph) == set(bigwig) == set(fragments)
+    for i, (k, file) in enumerate(fragments.items()):
+        expected = in_memory(file)
+        converted = snap.ex.combine_tracks(
+            [bigwig[k]], chrom_sizes, tmp_path / f"converted_{i}.bedgraph", op="sum",
+        )
+        for track in [read(bedgraph[k]), read(converted)]:
+            for chrom in chrom_sizes:
+                np.testing.assert_allclose(track[chrom], expected[chrom])
+    data.close()
+
+def test_export_coverage_smooth_kernel(tmp_path):
+    data = snap.datasets.simulate(
+        n_cells=40, n_cell_types=2, n_peaks=50, mean_depth=500,
+        chrom_sizes={"chr1": 1_000_000}, random_state=4, file=tmp_path / "data.h5ad",
+    )
+    def total_signal(files):
+        total = {}
+        for k, v in files.items():
+            fields = [line.split("\t") for line in open(v)]
+            total[k] = sum((int(x[2]) - int(x[1])) * float(x[3]) for x in fields)
+        return total
+    raw = total_signal(snap.ex.export_coverage(
+        data, groupby="cell_type", suffix=".bedgraph", normalization=None, bin_size=1,
+        out_dir=tmp_path / "raw",
+    ))
+    for kernel in ["flat", "gaussian", "epanechnikov"]:
+        smoothed = total_signal(snap.ex.export_coverage(
+            data, groupby="cell_type", suffix=".bedgraph", normalization=None, bin_size=1,
+            smooth_base=51, smooth_kernel=kernel, out_dir=tmp_path / kernel,
+        ))
+        for k in raw:
+            assert np.isclose(smoothed[k], raw[k], rtol=1e-3)
+    with pytest.raises(Exception, match="kernel"):
+        snap.ex.export_coverage(
+            data, groupby="cell_type", suffix=".bedgraph", smooth_base=51, smooth_kernel="box",
+            out_dir=tmp_path / "box",
+        )
+    data.close()
+
+def test_export_coverage_with_fragments(tmp_path):
+    data = snap.datasets.simulate(
+        n_cells=50, n_cell_types=2, n_peaks=50, mean_depth=500,
+        chrom_sizes={"chr1": 1_000_000, "chr2": 500_000}, random_state=14, file=tmp_path / "data.h5ad",
+    )
+    tracks, fragments = snap.ex.export_coverage(
+        data, groupby="cell_type", suffix=".bedgraph", out_dir=tmp_path / "combined",
+        fragment_suffix=".bed.gz",
+    )
+    expected_tracks = snap.ex.export_coverage(
+        data, groupby="cell_type", suffix=".bedgraph", out_dir=tmp_path / "coverage",
+    )
+    expected_fragments = snap.ex.export_fragments(
+        data, groupby="cell_type", suffix=".bed.gz", out_dir=tmp_path / "fragments",
+        max_frag_length=2000,
+    )
+    assert set(fragments.keys()) == set(tracks.keys()) == set(expected_tracks.keys())
+    for group in tracks:
+        assert open(tracks[group]).read() == open(expected_tracks[group]).read()
+        with gzip.open(fragments[group], "rt") as f:
+            records = [line.rstrip("\n").split("\t") for line in f]
+        with gzip.open(expected_fragments[group], "rt") as f:
+            expected = [line.rstrip("\n").split("\t") for line in f]
+        assert sorted(records) == sorted(expected)
+        keys = [(r[0], int(r[1]), int(r[2])) for r in records]
+        assert keys == sorted(keys)
+
+    tracks, fragments = snap.ex.export_coverage(
+        data, groupby="cell_type", suffix=".bedgraph", out_dir=tmp_path / "insertion",
+        counting_strategy="insertion", fragment_suffix=".bed",
+    )
+    assert all(Path(x).stat().st_size > 0 for x in fragments.values())
+    data.close()
+
+def test_region_bin_matrix():
+    data = snap.datasets.simulate(
+        n_cells=40, n_peaks=100, mean_depth=1000, random_state=15,
+        chrom_sizes={"chr1": 1_000_000, "chr2": 1_000_000},
+    )
+    full = snap.pp.add_tile_matrix(data, bin_size=500, counting_strategy="insertion", inplace=False)
+    mat, names = snap.pp.make_region_bin_matrix(
+        data, ["chr1:100000-150000", "chr2:0-20000"], bin_size=500, counting_strategy="insertion",
+    )
+    assert mat.shape == (data.n_obs, 140) and len(names) == 140
+    columns = {x: i for i, x in enumerate(full.var_names)}
+    expected = full.X[:, [columns[x] for x in names]]
+    np.testing.assert_array_equal(mat.toarray(), expected.toarray())
+
+    mat, names = snap.pp.make_region_bin_matrix(data, "chr1:100000-100120", bin_size=50)
+    assert names == ["chr1:100000-100050", "chr1:100050-100100", "chr1:100100-100120"]
+    assert mat.shape == (data.n_obs, 3)
+
+def test_export_training_data(tmp_path):
+    import json
+    import tarfile
+    import io
+    chrom_sizes = {"chr1": 200_000, "chr2": 100_000}
+    data = snap.datasets.simulate(
+        n_cells=30, n_cell_types=3, n_peaks=40, mean_depth=2000,
+        chrom_sizes=chrom_sizes, random_state=16,
+    )
+    rng = np.random.default_rng(0)
+    fasta = tmp_path / "genome.fa"
+    with open(fasta, "w") as f:
+        for chrom, size in chrom_sizes.items():
+            f.write(f">{chrom}\n" + "".join(rng.choice(list("ACGTN"), size)) + "\n")
+    regions = ["chr1:10000-10500", "chr2:50000-51000", "chr1:10-20", "chr3:0-100"]
+
+    shards = snap.ex.export_training_data(
+        data, fasta, tmp_path / "cells", regions=regions, window=1000, binarize=True,
+        output_format="npz", shuffle=False,
+    )
+    shard = np.load(shards[0])
+    assert shard["sequence"].shape == (2, 1000, 4)
+    assert shard["target"].shape == (2, 30, 1)
+    assert list(shard["region"]) == ["chr1:9750-10750", "chr2:50000-51000"]
+    mat, _ = snap.pp.make_region_bin_matrix(data, list(shard["region"]), bin_size=1000)
+    np.testing.assert_array_equal(shard["target"][:, :, 0], (mat.toarray() > 0).T)
+    with open(fasta) as f:
+        seq = f.read().split("\n")[1][9750:10750]
+    np.testing.assert_array_equal(shard["sequence"][0].sum(axis=1), [x != "N" for x in seq])
+    assert all(shard["sequence"][0][i, "ACGT".index(x)] == 1 for i, x in enumerate(seq) if x != "N")
+
+    shards = snap.ex.export_training_data(
+        data, fasta, tmp_path / "groups", regions=regions, groupby="cell_type",
+        window=1000, target_bin_size=100, shard_size=1,
+    )
+    assert len(shards) == 2
+    meta = json.load(open(tmp_path / "groups" / "metadata.json"))
+    assert meta["target_shape"] == [3, 10] and sum(meta["n_cells"]) == 30
+    total = 0
+    for shard in shards:
+        with tarfile.open(shard) as tar:
+            members = {m.name.split(".", 1)[1]: tar.extractfile(m).read() for m in tar.getmembers()}
+        assert set(members) == {"seq.npy", "target.npy", "region.txt"}
+        assert np.load(io.BytesIO(members["seq.npy"])).shape == (1000, 4)
+        total += np.load(io.BytesIO(members["target.npy"])).sum()
+    assert total == mat.sum()
+
+def test_kmer_counts(tmp_path):
+    import scipy.sparse as sp
+
+    chrom_sizes = {"chr1": 20_000, "chr2": 10_500}
+    data = snap.datasets.simulate(
+        n_cells=10, n_peaks=10, mean_depth=200, chrom_sizes=chrom_sizes, random_state=17,
+    )
+    snap.pp.add_tile_matrix(data, bin_size=1000, exclude_chroms=None)
+    rng = np.random.default_rng(0)
+    seqs = {chrom: "".join(rng.choice(list("ACGTN"), size)) for chrom, size in chrom_sizes.items()}
+    fasta = tmp_path / "genome.fa"
+    with open(fasta, "w") as f:
+        for chrom, seq in seqs.items():
+            f.write(f">{chrom}\n" + "\n".join(seq[i:i+60] for i in range(0, len(seq), 60)) + "\n")
+
+    snap.tl.kmer_counts(data, fasta, k=1)
+    assert list(data.uns["kmer_names"]) == ["A", "C"]
+    counts = data.varm["kmer"]
+    assert counts.shape == (data.n_vars, 2)
+    for i, name in enumerate(data.var_names):
+        chrom, coord = name.split(":")
+        start, end = map(int, coord.split("-"))
+        seq = seqs[chrom][start:end]
+        assert counts[i, 0] == seq.count("A") + seq.count("T")
+        assert counts[i, 1] == seq.count("C") + seq.count("G")
+
+    counts, names = snap.tl.kmer_counts(data, fasta, k=3, collapse_strand=False, inplace=False)
+    assert len(names) == 64
+    assert sp.issparse(counts)
+    seq = seqs["chr1"][:1000]
+    assert counts[0, names.index("ACG")] == sum(seq[i:i+3] == "ACG" for i in range(998))
+
+def test_export_ldsc_annot(tmp_path):
+    import pandas as pd
+    panel = tmp_path / "panel"
+    panel.mkdir()
+    for chrom in [1, 2, 10]:
+        with open(panel / f"ref.{chrom}.bim", "w") as f:
+            for i, bp in enumerate([100, 500, 501, 1000, 2000]):
+                f.write(f"{chrom}\trs{chrom}_{i}\t0\t{bp}\tA\tG\n")
+    regions = {
+        "a": ["chr1:99-500", "chr2:1990-2100"],
+        "b": ["chr10:600-700"],
+    }
+    files = snap.ex.export_ldsc_annot(regions, panel / "ref.", out_dir=tmp_path / "annot")
+    assert [f.name for f in files["a"]] == ["a.1.annot.gz", "a.2.annot.gz", "a.10.annot.gz"]
+    annot = [pd.read_csv(f, sep="\t")["ANNOT"].tolist() for f in files["a"]]
+    assert annot == [[1, 1, 0, 0, 0], [0, 0, 0, 0, 1], [0, 0, 0, 0, 0]]
+
+    files = snap.ex.export_ldsc_annot(
+        regions, panel / "ref.", out_dir=tmp_path / "annot", window=400, thin=False,
+    )
+    table = pd.read_csv(files["b"][2], sep="\t")
+    assert list(table.columns) == ["CHR", "BP", "SNP", "CM", "ANNOT"]
+    assert table["ANNOT"].tolist() == [0, 1, 1, 1, 0]
+
+    files = snap.ex.export_ldsc_annot({"../T cell": regions["a"]}, panel / "ref.", out_dir=tmp_path / "annot")
+    assert files["../T cell"][0] == tmp_path / "annot" / ".._T_cell.1.annot.gz"
+
+def test_amulet():
+    data = snap.datasets.simulate(
+        n_cells=200, n_peaks=50, mean_depth=2000, doublet_rate=0.1,
+        chrom_sizes={"chr1": 1_000_000, "chr2": 1_000_000}, random_state=18,
+    )
+    snap.pp.amulet(data)
+    counts = data.obs["multiplet_overlaps"].to_numpy()
+    doublet = data.obs["doublet"].to_numpy()
+    assert counts[doublet].mean() > counts[~doublet].mean()
+    assert data.obs["multiplet_qvalue"].to_numpy().min() >= 0
+    assert data.obs["is_multiplet"].to_numpy()[doublet].mean() >= data.obs["is_multiplet"].to_numpy()[~doublet].mean()
+
+    counts, qvalues = snap.pp.amulet(data, exclude_chroms=["chr1", "chr2"], inplace=False)
+    assert (counts == 0).all() and (qvalues == 1).all()
+
+def test_sample_qc():
+    data = snap.datasets.simulate(
+        n_cells=80, n_cell_types=2, n_peaks=100, mean_depth=1000,
+        chrom_sizes={"chr1": 1_000_000}, random_state=19,
+    )
+    snap.pp.add_tile_matrix(data, bin_size=5000)
+    # Two samples of each cell type.
+    samples = [f"{t}_{i % 2}" for i, t in enumerate(data.obs["cell_type"])]
+    pca, variance, cor = snap.metrics.sample_qc(data, samples, n_comps=10, n_top_features=None)
+    assert pca.shape == (4, 2 + 3) and variance.shape == (3, 2)
+    assert np.isclose(variance["variance_ratio"].sum(), 1.0)
+    assert dict(zip(pca["sample"], pca["n_cells"])) == {s: samples.count(s) for s in set(samples)}
+    # PC1 separates the cell types.
+    types = np.array([s.rsplit("_", 1)[0] for s in pca["sample"]])
+    pc1 = pca["PC1"].to_numpy()
+    assert len(set(np.sign(pc1[types == types[0]]))) == 1
+    assert np.sign(pc1[types == types[0]][0]) != np.sign(pc1[types != types[0]][0])
+
+    mat = np.array(cor.drop("sample").to_numpy())
+    np.testing.assert_allclose(np.diag(mat), 1.0)
+    _, _, cor = snap.metrics.sample_qc(data, samples, method="spearman", n_top_features=50)
+    assert cor.columns == ["sample"] + list(pca["sample"])
+
+def test_export_values(tmp_path):
+    input_dir = tmp_path / "cells"
+    input_dir.mkdir()
+    cells = {
+        "a1": [("chr1", 10, 1, 1), ("chr1", 11, 2, 0), ("chr1", 50, 0, 4)],
+        "a2": [("chr1", 10, 3, 1), ("chr1", 50, 1, 1)],
+        "b1": [("chr1", 10, 0, 2), ("chr2", 5, 2, 2)],
+    }
+    for cell, values in cells.items():
+        with open(input_dir / f"{cell}.tsv", "w") as f:
+            f.write("chrom\tpos\tmethylated\tunmethylated\n")
+            for chrom, pos, m, u in values:
+                f.write(f"{chrom}\t{pos}\t{m}\t{u}\n")
+    data = snap.pp.import_values(input_dir, {"chr1": 100, "chr2": 100})
+    data.obs["group"] = [x[0] for x in data.obs_names]
+
+    files = snap.ex.export_values(data, "group", out_dir=tmp_path, suffix=".bedgraph")
+    assert set(files.keys()) == {"a", "b"}
+
+    def read(file):
+        with open(file) as f:
+            return [(c, int(s), int(e), float(v)) for c, s, e, v in (l.split() for l in f)]
+
+    a = read(files["a"])
+    assert [x[3] for x in a if x[0] == "chr1"] == pytest.approx([0.625, 1.0, 0.25])
+    assert all(e - s == 1 for _, s, e, _ in a)
+    b = read(files["b"])
+    assert [(x[0], x[3]) for x in b] == [("chr1", 0.0), ("chr2", 0.5)]
+
+    files = snap.ex.export_values(
+        data, "group", selections=["a"], bin_size=100, aggregation="sum",
+        value_type="total", out_dir=tmp_path, prefix="total_", suffix=".bedgraph",
+    )
+    assert list(files.keys()) == ["a"]
+    assert read(files["a"]) == [("chr1", 0, 100, 14.0)]
+
+def test_long_reads(tmp_path):
+    fragment_file = tmp_path / "long_reads.tsv.gz"
+    records = [
+        ("chr1", 1000, 6000, "AAAA", 1, "+", "MM:Z:C+m,0;", "ML:B:C,250"),
+        ("chr1", 8000, 20000, "AAAA", 1, "-", "MM:Z:C+m,2,0;", "ML:B:C,10,200"),
+        ("chr2", 500, 3500, "CCCC", 2, "+", "MM:Z:C+m,1;"),
+    ]
+    with gzip.open(fragment_file, "wt") as f:
+        for r in records:
+            f.write("\t".join(map(str, r)) + "\n")
+
+    data = snap.pp.import_fragments(
+        fragment_file,
+        chrom_sizes={"chr1": 50000, "chr2": 50000},
+        is_paired=False,
+        long_reads=True,
+        sorted_by_barcode=False,
+        min_num_fragments=0,
+    )
+    assert "fragment_paired" in data.obsm
+    assert sorted(data.obsm["fragment_paired"].data.tolist()) == [3000, 5000, 12000]
+
+    snap.pp.add_tile_matrix(data, bin_size=10000, counting_strategy="insertion", exclude_chroms=None)
+    counts = dict(zip(data.obs_names, np.asarray(data.X.sum(axis=1)).ravel()))
+    assert counts == {"AAAA": 4, "CCCC": 2}
+
+    assert data.uns["read_type"] == "long"
+    assert dict(zip(data.obs_names, data.obs["n_methylation_calls"])) == {"AAAA": 3, "CCCC": 0}
+    assert dict(zip(data.obs_names, data.obs["frac_methylated"])) == {"AAAA": 2 / 3, "CCCC": 0}
+    # Long reads are limited to 100-100,000 bases unless other limits are given.
+    snap.pp.add_tile_matrix(
+        data, bin_size=10000, counting_strategy="insertion", exclude_chroms=None, max_frag_size=4000,
+    )
+    counts = dict(zip(data.obs_names, np.asarray(data.X.sum(axis=1)).ravel()))
+    assert counts == {"AAAA": 0, "CCCC": 2}
+
+def test_import_blacklist(tmp_path):
+    fragment_file = tmp_path / "fragments.tsv.gz"
+    with gzip.open(fragment_file, "wt") as f:
+        for barcode, n in [("A", 10), ("B", 4)]:
+            for i in range(n):
+                f.write(f"chr1\t{i * 1000}\t{i * 1000 + 100}\t{barcode}\t1\n")
+    blacklist = tmp_path / "blacklist.bed"
+    blacklist.write_text("chr1\t0\t2050\n")
+
+    data = snap.pp.import_fragments(
+        fragment_file, chrom_sizes={"chr1": 100000}, sorted_by_barcode=False,
+        min_num_fragments=0, blacklist=blacklist,
+    )
+    assert dict(zip(data.obs_names, data.obs["n_blacklisted"])) == {"A": 3, "B": 3}
+    assert dict(zip(data.obs_names, data.obs["n_fragment"])) == {"A": 7, "B": 1}
+    assert data.obsm["fragment_paired"].nnz == 8
+
+    data = snap.pp.import_fragments(
+        fragment_file, chrom_sizes={"chr1": 100000}, sorted_by_barcode=False,
+        min_num_fragments=0,
+    )
+    assert "n_blacklisted" not in data.obs
+    assert data.obsm["fragment_paired"].nnz == 14
+
+def test_import_modalities(tmp_path):
+    fragment_file = tmp_path / "fragments.tsv.gz"
+    counts = {("A", "H3K27ac"): 5, ("A", "H3K4me3"): 2, ("B", "H3K4me3"): 4, ("C", "H3K27ac"): 1}
+    with gzip.open(fragment_file, "wt") as f:
+        for (cell, mark), n in counts.items():
+            offset = 0 if mark == "H3K27ac" else 50000
+            for i in range(n):
+                start = offset + i * 1000
+                f.write(f"chr1\t{start}\t{start + 100}\t{cell}:{mark}\t1\n")
+
+    data = snap.pp.import_fragments(
+        fragment_file, chrom_sizes={"chr1": 100000}, sorted_by_barcode=False,
+        min_num_fragments=2, modality_sep=":", file=tmp_path / "data.h5ad",
+    )
+    assert list(data.obs_names) == ["A", "B"]
+    assert list(data.obs["n_fragment"]) == [7, 4]
+    assert list(data.obs["n_fragment_H3K27ac"]) == [5, 0]
+    assert list(data.obs["n_fragment_H3K4me3"]) == [2, 4]
+    assert "fragment_paired:H3K27ac" in data.obsm
+
+    # The analyses without modality use the fragments of all modalities.
+    snap.pp.add_tile_matrix(data, bin_size=1000, counting_strategy="insertion", exclude_chroms=None)
+    np.testing.assert_array_equal(np.asarray(data.X.sum(axis=1)).ravel(), [14, 8])
+    snap.metrics.frag_size_distr(data)
+
+    snap.pp.add_tile_matrix(
+        data, bin_size=1000, counting_strategy="insertion", exclude_chroms=None, modality="H3K4me3",
+    )
+    np.testing.assert_array_equal(np.asarray(data.X.sum(axis=1)).ravel(), [4, 8])
+    snap.pp.add_tile_matrix(
+        data, bin_size=1000, counting_strategy="insertion", exclude_chroms=None, modality="H3K27ac",
+    )
+    np.testing.assert_array_equal(np.asarray(data.X.sum(axis=1)).ravel(), [10, 0])
+    data.close()
+
+def test_sex_chrom_ratio(tmp_path):
+    fragment_file = tmp_path / "fragments.tsv.gz"
+    with gzip.open(fragment_file, "wt") as f:
+        for barcode, n_y in [("M1", 5), ("M2", 4), ("F1", 0), ("F2", 0)]:
+            for i in range(20):
+                f.write(f"chr1\t{i * 1000}\t{i * 1000 + 100}\t{barcode}\t1\n")
+            for i in range(20 - n_y):
+                f.write(f"chrX\t{i * 1000}\t{i * 1000 + 100}\t{barcode}\t1\n")
+            for i in range(n_y):
+                f.write(f"chrY\t{i * 1000}\t{i * 1000 + 100}\t{barcode}\t1\n")
+    data = snap.pp.import_fragments(
+        fragment_file,
+        chrom_sizes={"chr1": 100000, "chrX": 100000, "chrY": 100000},
+        sorted_by_barcode=False,
+        min_num_fragments=0,
+    )
+    sex = dict(zip(data.obs_names, snap.metrics.sex_chrom_ratio(data, inplace=False)["sex"]))
+    assert sex == {"M1": "male", "M2": "male", "F1": "female", "F2": "female"}
+
+    samples = [x[0] for x in data.obs_names]
+    table = snap.metrics.sex_chrom_ratio(data, groupby=samples)
+    assert table["sex"].to_list() == ["female", "male"]
+    assert table["chrY"].to_list() == [0, 9]
+    assert list(data.obs["sex"]) == ["female" if s == "F" else "male" for s in samples]
+    np.testing.assert_allclose(data.obs["chrX_frac"] + data.obs["chrY_frac"], 0.5)
+
+def test_replication_timing(tmp_path):
+    fragment_file = tmp_path / "fragments.tsv.gz"
+    with gzip.open(fragment_file, "wt") as f:
+        for barcode, n_early in [("G1", 19), ("G2", 20), ("G3", 21), ("G4", 20), ("S1", 40), ("L1", 2)]:
+            n_late = 2 if barcode == "L1" else 20
+            for i in range(n_early):
+                f.write(f"chr1\t{i * 1000}\t{i * 1000 + 100}\t{barcode}\t1\n")
+            for i in range(n_late):
+                f.write(f"chr1\t{50000 + i * 1000}\t{50000 + i * 1000 + 100}\t{barcode}\t1\n")
+    data = snap.pp.import_fragments(
+        fragment_file,
+        chrom_sizes={"chr1": 100000},
+        sorted_by_barcode=False,
+        min_num_fragments=0,
+    )
+    result = snap.metrics.replication_timing(
+        data, ["chr1:0-50000"], ["chr1:50000-100000"], min_fragments=10, inplace=False,
+    )
+    score = dict(zip(data.obs_names, result["rt_score"]))
+    cycling = dict(zip(data.obs_names, result["rt_cycling"]))
+    assert np.isnan(score["L1"]) and not cycling["L1"]
+    np.testing.assert_allclose(score["S1"], np.log2(41 / 21))
+    assert {k for k, v in cycling.items() if v} == {"S1"}
+
+    snap.metrics.replication_timing(data, ["chr1:0-50000"], ["chr1:50000-100000"], min_fragments=10)
+    assert data.obs["rt_cycling"].sum() == 1
+
+    # Most cells have the same score, so the median absolute deviation is zero.
+    fragment_file = tmp_path / "fragments_tied.tsv.gz"
+    with gzip.open(fragment_file, "wt") as f:
+        for barcode, n_early in [("G1", 20), ("G2", 20), ("G3", 20), ("G4", 20), ("S1", 40)]:
+            for i in range(n_early):
+                f.write(f"chr1\t{i * 1000}\t{i * 1000 + 100}\t{barcode}\t1\n")
+            for i in range(20):
+                f.write(f"chr1\t{50000 + i * 1000}\t{50000 + i * 1000 + 100}\t{barcode}\t1\n")
+    data = snap.pp.import_fragments(
+        fragment_file,
+        chrom_sizes={"chr1": 100000},
+        sorted_by_barcode=False,
+        min_num_fragments=0,
+    )
+    result = snap.metrics.replication_timing(
+        data, ["chr1:0-50000"], ["chr1:50000-100000"], min_fragments=10, inplace=False,
+    )
+    zscore = dict(zip(data.obs_names, result["rt_zscore"]))
+    assert np.isfinite(result["rt_zscore"]).all()
+    assert zscore["G1"] == 0 and zscore["S1"] > 0
+
+def test_export_qtl_phenotypes(tmp_path):
+    import pandas as pd
+
+    data = snap.datasets.simulate(n_cells=120, n_peaks=100, mean_depth=1000, random_state=7)
+    snap.pp.add_tile_matrix(data, bin_size=5000, exclude_chroms=None)
+    donors = [f"d{i % 6}" for i in range(data.n_obs)]
+    table = pd.DataFrame(
+        {"sex": ["F", "M", "F", "M", "F", "M"], "age": [30, 40, 50, 60, 70, 80]},
+        index=[f"d{i}" for i in range(6)],
+    )
+    files = snap.ex.export_qtl_phenotypes(
+        data, donors, covariates=["sex", "age"], donor_covariates=table, n_pcs=2,
+        out_dir=tmp_path, prefix="caqtl.",
+    )
+    assert files["phenotypes"].name == "caqtl.phenotypes.bed.gz"
+    bed = pd.read_csv(files["phenotypes"], sep="\t")
+    assert list(bed.columns) == ["#chr", "start", "end", "phenotype_id"] + [f"d{i}" for i in range(6)]
+    assert (bed["end"] - bed["start"] == 1).all()
+    for chrom, group in bed.groupby("#chr"):
+        assert group["start"].is_monotonic_increasing
+    # Inverse normal transformation of the ranks of 6 donors.
+    from scipy.stats import norm
+    assert np.abs(bed.iloc[:, 4:].to_numpy()).max() <= norm.ppf(1 - 0.5 / 6) + 1e-9
+
+    cov = pd.read_csv(files["covariates"], sep="\t", index_col=0)
+    assert list(cov.index) == ["sex_M", "age", "PC1", "PC2"]
+    assert list(cov.columns) == [f"d{i}" for i in range(6)]
+    assert cov.loc["age"].tolist() == [30, 40, 50, 60, 70, 80]
+
+def test_sketch_cells():
+    rng = np.random.default_rng(0)
+    # An abundant population of 1900 cells and a rare one of 100 cells.
+    embedding = np.concatenate([
+        rng.normal(0, 1, size=(1900, 5)),
+        rng.normal(20, 1, size=(100, 5)),
+    ])
+    data = snap.datasets.simulate(n_cells=2000, n_peaks=50, mean_depth=100, random_state=1)
+    data.obsm["X_spectral"] = embedding
+
+    mask = snap.pp.sketch_cells(data, 100, inplace=False)
+    assert mask.sum() == 100
+    assert mask[1900:].sum() > 100 * 100 / 2000
+    np.testing.assert_array_equal(mask, snap.pp.sketch_cells(data, 100, inplace=False))
+
+    snap.pp.sketch_cells(data, 100)
+    assert data.n_obs == 100
+
+def test_query(tmp_path):
+    data = snap.datasets.simulate(
+        n_cells=100, n_cell_types=2, n_peaks=50, mean_depth=100, random_state=1,
+        file=tmp_path / "data.h5ad",
+    )
+    rng = np.random.default_rng(0)
+    tsse = rng.uniform(0, 20, data.n_obs)
+    data.obs["tsse"] = tsse
+    cell_type = np.array(data.obs["cell_type"])
+
+    mask = snap.pp.query(data, "tsse > 7 && cell_type != 'type_1'", chunk_size=7)
+    np.testing.assert_array_equal(mask, (tsse > 7) & (cell_type != "type_1"))
+    mask = snap.pp.query(data, "not (tsse <= 7 or `cell_type` == 'type_1')")
+    np.testing.assert_array_equal(mask, (tsse > 7) & (cell_type != "type_1"))
+    with pytest.raises(Exception, match="not found"):
+        snap.pp.query(data, "unknown > 1")
+    data.close()
+
+def test_export_track_stats(tmp_path):
+    import json
+    import pandas as pd
+
+    data = snap.datasets.simulate(
+        n_cells=60, n_cell_types=2, n_peaks=50, mean_depth=500,
+        chrom_sizes={"chr1": 1_000_000}, random_state=14, file=tmp_path / "data.h5ad",
+    )
+    files = snap.ex.export_coverage(
+        data, groupby="cell_type", suffix=".bedgraph", out_dir=tmp_path,
+        normalization=None, track_stats=[0.5, 0.99],
+    )
+    with open(tmp_path / "manifest.json") as f:
+        manifest = json.load(f)["groups"]
+    assert set(manifest.keys()) == set(files.keys())
+    for group, x in manifest.items():
+        bedgraph = pd.read_csv(files[group], sep="\t", header=None, names=["chrom", "start", "end", "value"])
+        length = bedgraph["end"] - bedgraph["start"]
+        covered = bedgraph["value"] != 0
+        stats = x["stats"]
+        assert stats["mean"] == pytest.approx((bedgraph["value"] * length).sum() / 1_000_000)
+        assert stats["max"] == bedgraph["value"].max()
+        assert stats["coverage_fraction"] == pytest.approx(length[covered].sum() / 1_000_000)
+        values = np.repeat(bedgraph["value"][covered].to_numpy(), length[covered].to_numpy())
+        median = np.quantile(values, 0.5, method="inverted_cdf")
+        assert stats["quantiles"]["0.5"] == pytest.approx(median, rel=0.011)
+        assert stats["view_limit"] == stats["quantiles"]["0.99"]
+    data.close()
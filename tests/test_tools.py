@@ -127,6 +127,26 @@ def test_make_fragment(datadir, tmp_path):
     
     assert expected == actual
 
+def test_dedup_policy(datadir, tmp_path):
+    bam = str(datadir.join('test.bam'))
+    def run(policy):
+        output = str(tmp_path) + f"/{policy}.bed.gz"
+        snap.pp.make_fragment_file(
+            bam, output, True, barcode_regex="(^[ATCG]+):", chunk_size=5000, dedup_policy=policy,
+        )
+        with gzip.open(output, 'rt') as fl:
+            return [line.strip().split('\t') for line in fl]
+
+    coordinate = run("coordinate")
+    five_prime = run("5prime")
+    no_dedup = run("none")
+    assert all(int(x[4]) == 1 for x in no_dedup)
+    assert len(five_prime) <= len(coordinate) <= len(no_dedup)
+    assert sum(int(x[4]) for x in coordinate) == len(no_dedup)
+
+    with pytest.raises(BaseException):
+        run("umi")
+
 @given(
     mat = arrays(
         np.float64, (50, 100),
@@ -190,6 +210,79 @@ def test_import(datadir):
         for i, (g, t) in enumerate(zip(gold, test)):
             assert g == t, f"Line {i} mismatch: {g} != {t}"
 
+def test_import_contig_policy(tmp_path):
+    fragments = tmp_path / "fragments.tsv"
+    with open(fragments, "w") as f:
+        f.write("chr1\t100\t300\tAAA\t1\n")
+        f.write("chr1_alt\t10\t60\tAAA\t1\n")
+        f.write("chr1_alt\t900\t950\tAAA\t1\n")
+    chain = tmp_path / "alt.chain"
+    with open(chain, "w") as f:
+        f.write("chain 1000 chr1_alt 1000 + 0 500 chr1 100000 + 5000 5500 1\n500\n")
+    chrom_sizes = {"chr1": 100000, "chr1_alt": 1000}
+
+    def imported(**kwargs):
+        data = snap.pp.import_fragments(
+            fragments, chrom_sizes, min_num_fragments=0, sorted_by_barcode=False, **kwargs,
+        )
+        data.obs['group'] = 'all'
+        out = snap.ex.export_fragments(data, groupby="group", out_dir=tmp_path, suffix='.bed')
+        with open(out['all']) as f:
+            frags = sorted(tuple(line.split("\t")[:3]) for line in f)
+        return frags, set(data.uns['reference_sequences']['reference_seq_name'])
+
+    frags, chroms = imported()
+    assert len(frags) == 3 and chroms == {"chr1", "chr1_alt"}
+    frags, chroms = imported(contig_policy="drop")
+    assert frags == [("chr1", "100", "300")] and chroms == {"chr1"}
+    frags, chroms = imported(contig_policy="remap", chain_file=chain)
+    assert frags == [("chr1", "100", "300"), ("chr1", "5010", "5060")] and chroms == {"chr1"}
+    with pytest.raises(Exception):
+        imported(contig_policy="remap")
+
+def test_missing_chrom_policy(tmp_path):
+    fragments = tmp_path / "fragments.tsv"
+    with open(fragments, "w") as f:
+        f.write("chr1\t100\t300\tAAA\t1\n")
+        f.write("chr2\t10\t60\tAAA\t1\n")
+    chrom_sizes = {"chr1": 100000}
+
+    assert snap.get_missing_chrom_policy() == "skip"
+    data = snap.pp.import_fragments(fragments, chrom_sizes, min_num_fragments=0, sorted_by_barcode=False)
+    assert data.obs['n_fragment'].iloc[0] == 1
+
+    snap.set_missing_chrom_policy("error")
+    try:
+        with pytest.raises(Exception, match="chr2"):
+            snap.pp.import_fragments(fragments, chrom_sizes, min_num_fragments=0, sorted_by_barcode=False)
+        with pytest.raises(Exception):
+            snap.set_missing_chrom_policy("ignore")
+        assert snap.get_missing_chrom_policy() == "error"
+    finally:
+        snap.set_missing_chrom_policy("skip")
+
+def test_out_of_bounds_policy(tmp_path):
+    fragments = tmp_path / "fragments.tsv"
+    with open(fragments, "w") as f:
+        f.write("chr1\t100\t300\tAAA\t1\n")
+        f.write("chr1\t900\t1200\tAAA\t1\n")
+    chrom_sizes = {"chr1": 1000}
+
+    assert snap.get_out_of_bounds_policy() == "warn"
+    data = snap.pp.import_fragments(fragments, chrom_sizes, min_num_fragments=0, sorted_by_barcode=False)
+    assert data.obs['n_fragment'].iloc[0] == 1
+    assert snap.history(data)[-1]["parameters"]["n_out_of_bounds_fragments"] == 1
+
+    snap.set_out_of_bounds_policy("error")
+    try:
+        with pytest.raises(Exception, match="beyond the end"):
+            snap.pp.import_fragments(fragments, chrom_sizes, min_num_fragments=0, sorted_by_barcode=False)
+        with pytest.raises(Exception):
+            snap.set_out_of_bounds_policy("clip")
+        assert snap.get_out_of_bounds_policy() == "error"
+    finally:
+        snap.set_out_of_bounds_policy("warn")
+
 def test_tile_matrix(datadir):
     def total_count(adata, bin_size):
         return snap.pp.add_tile_matrix(
@@ -224,4 +317,299 @@ def test_spectral():
     sp1 = snap.tl.spectral(data, random_state=0, inplace=False)[0]
     sp2 = snap.tl.spectral(data, random_state=0, inplace=False)[0]
     np.testing.assert_array_equal(sp1, sp2)
- 
\ No newline at end of file
+ 
+def test_leiden_sweep():
+    rng = np.random.default_rng(0)
+    centers = rng.normal(scale=10, size=(3, 5))
+    embedding = np.concatenate([c + rng.normal(size=(50, 5)) for c in centers])
+    adata = ad.AnnData(X=csr_matrix((150, 10)), obsm={"X_spectral": embedding})
+    snap.pp.knn(adata, n_neighbors=10, random_state=0)
+    resolutions = [0.1, 0.5, 1.0]
+    stats = snap.tl.leiden_sweep(adata, resolutions, n_jobs=1, n_repeats=2)
+    assert [x["resolution"] for x in stats] == resolutions
+    assert adata.obsm["leiden_sweep"].shape == (150, 3)
+    np.testing.assert_array_equal(adata.uns["leiden_sweep"], resolutions)
+    for i, x in enumerate(stats):
+        assert x["n_clusters"] == len(np.unique(adata.obsm["leiden_sweep"][:, i]))
+        assert -0.5 <= x["modularity"] <= 1
+        assert -1 <= x["stability"] <= 1
+    labels = snap.tl.leiden(adata, resolution=0.5, inplace=False)
+    np.testing.assert_array_equal(labels, adata.obsm["leiden_sweep"][:, 1].astype(str))
+
+def test_random_state():
+    import random
+
+    rng = np.random.default_rng(0)
+    centers = rng.normal(scale=10, size=(3, 5))
+    embedding = np.concatenate([c + rng.normal(size=(50, 5)) for c in centers])
+    adata = ad.AnnData(X=csr_matrix((150, 10)), obsm={"X_spectral": embedding})
+    snap.pp.knn(adata, n_neighbors=10, random_state=0)
+
+    random.seed(1)
+    state = random.getstate()
+    labels1 = snap.tl.leiden(adata, random_state=-1, inplace=False)
+    labels2 = snap.tl.leiden(adata, random_state=-1, inplace=False)
+    np.testing.assert_array_equal(labels1, labels2)
+    assert random.getstate() == state
+
+    with pytest.raises(ValueError):
+        snap.tl.leiden(adata, random_state=2**64, inplace=False)
+    with pytest.raises(TypeError):
+        snap.tl.leiden(adata, random_state=0.5, inplace=False)
+
+def test_consensus_clustering():
+    rng = np.random.default_rng(0)
+    centers = rng.normal(scale=10, size=(3, 5))
+    embedding = np.concatenate([c + rng.normal(size=(50, 5)) for c in centers])
+    adata = ad.AnnData(X=csr_matrix((150, 10)), obsm={"X_spectral": embedding})
+    snap.pp.knn(adata, n_neighbors=10, random_state=0)
+    labels, confidence, coassignment = snap.tl.consensus_clustering(
+        adata, resolution=0.2, n_runs=5, subsample=0.8, inplace=False,
+    )
+    assert len(np.unique(labels)) == 3
+    for i in range(3):
+        assert len(np.unique(labels[i * 50:(i + 1) * 50])) == 1
+    assert coassignment.shape == (150, 150)
+    assert coassignment.max() <= 1
+    assert np.all((confidence >= 0) & (confidence <= 1))
+
+def test_annotate_cells():
+    import pandas as pd
+
+    rng = np.random.default_rng(0)
+    signatures = np.full((3, 90), 5.0)
+    for k in range(3):
+        signatures[k, k * 30:(k + 1) * 30] = 50.0
+    reference = pd.DataFrame(
+        signatures, index=["A", "B", "C"], columns=[f"peak{i}" for i in range(90)],
+    )
+    # The query lacks the first 10 peaks of the reference and has extra features.
+    truth = np.repeat([0, 1, 2], 20)
+    X = np.hstack([rng.poisson(signatures[truth, 10:] / 5), rng.poisson(1, size=(60, 10))])
+    adata = ad.AnnData(X=csr_matrix(X.astype(np.float64)))
+    adata.var_names = [f"peak{i}" for i in range(10, 90)] + [f"other{i}" for i in range(10)]
+
+    for method in ["correlation", "logistic"]:
+        labels, probs = snap.tl.annotate_cells(adata, reference, method=method, inplace=False)
+        assert probs.columns == ["A", "B", "C"]
+        np.testing.assert_allclose(probs.to_numpy().sum(axis=1), 1)
+        assert (labels == np.array(["A", "B", "C"])[truth]).mean() > 0.9
+
+    groups = [str(x) for x in truth]
+    snap.tl.annotate_cells(adata, reference, groupby=groups)
+    np.testing.assert_array_equal(adata.obs["predicted_celltype"], np.array(["A", "B", "C"])[truth])
+    assert adata.obsm["predicted_celltype_probabilities"].shape == (60, 3)
+
+def test_rank_tf_drivers():
+    groups = np.repeat(["a", "b"], 10)
+    # GATA1 is accessible and expressed in "a", CTCF in "b"; SPI1 has no gene.
+    deviation = np.zeros((20, 3))
+    deviation[:10, 0] = 3
+    deviation[10:, 1] = 3
+    motifs = ad.AnnData(X=deviation)
+    motifs.var_names = ["MA0035.4_GATA1", "MA0139.1_CTCF", "MA0080.6_SPI1"]
+    expr = np.ones((20, 3))
+    expr[:10, 0] = 10
+    expr[10:, 1] = 10
+    expr[:, 2] = 0
+    expr_adata = ad.AnnData(X=csr_matrix(expr))
+    expr_adata.var_names = ["Gata1", "Ctcf", "Actb"]
+
+    df = snap.tl.rank_tf_drivers(motifs, expr_adata, groups)
+    assert df.shape[0] == 4
+    top = df.filter(df["rank"] == 1)
+    assert top["group"].to_list() == ["a", "b"]
+    assert top["tf"].to_list() == ["Gata1", "Ctcf"]
+    assert top["motif"].to_list() == ["MA0035.4_GATA1", "MA0139.1_CTCF"]
+
+    df = snap.tl.rank_tf_drivers(
+        motifs, expr_adata, groups, motif_to_gene={"MA0080.6_SPI1": "Actb"},
+        min_fraction_expressed=0.5,
+    )
+    assert df.shape[0] == 0
+
+@pytest.mark.skipif(
+    not hasattr(snap._snapatac2, "onnx_embed_cells"), reason="built without ONNX support",
+)
+def test_onnx_embedding(tmp_path):
+    onnx = pytest.importorskip("onnx")
+    from onnx import helper, TensorProto, numpy_helper
+
+    data = snap.datasets.simulate(n_cells=30, n_peaks=20, mean_depth=200, random_state=1)
+    snap.pp.add_tile_matrix(data, bin_size=100_000)
+    weights = np.random.default_rng(0).random((data.n_vars, 3)).astype(np.float32)
+    graph = helper.make_graph(
+        [helper.make_node("MatMul", ["x", "w"], ["y"])], "linear",
+        [helper.make_tensor_value_info("x", TensorProto.FLOAT, [None, data.n_vars])],
+        [helper.make_tensor_value_info("y", TensorProto.FLOAT, [None, 3])],
+        [numpy_helper.from_array(weights, "w")],
+    )
+    model = tmp_path / "linear.onnx"
+    onnx.save(helper.make_model(graph), model)
+
+    result = snap.tl.onnx_embedding(data, model, batch_size=7, inplace=False)
+    expected = data.X[:].astype(np.float32) @ weights
+    np.testing.assert_allclose(result, expected, rtol=1e-5)
+
+def test_variant_enrichment(tmp_path):
+    peaks = [f"chr1:{i * 1000}-{i * 1000 + 500}" for i in range(100)]
+    vcf = tmp_path / "gwas.vcf"
+    with open(vcf, "w") as f:
+        f.write("##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\n")
+        # Two variants in each of the first 10 peaks, one between peaks.
+        for i in range(10):
+            f.write(f"chr1\t{i * 1000 + 1}\trs{i}\tA\tG\n")
+            f.write(f"chr1\t{i * 1000 + 500}\t.\tC\tT\n")
+        f.write("chr1\t800\trs_out\tA\tG\n")
+
+    overlap = snap.tl.variant_overlap(peaks, vcf)
+    assert len(overlap) == 20
+    assert set(overlap.filter(overlap["region"] == peaks[0])["variant"]) == {"rs0", "chr1:500:C:T"}
+
+    bed = tmp_path / "gwas.bed"
+    with open(bed, "w") as f:
+        f.write("chr1\t0\t1\trs0\nchr1\t600\t700\n")
+    overlap = snap.tl.variant_overlap(peaks[:1], bed)
+    assert list(overlap["variant"]) == ["rs0"]
+
+    result = snap.tl.variant_enrichment(
+        {"enriched": peaks[:10], "depleted": peaks[50:60]}, vcf,
+        background=peaks, n_permutations=200,
+    )
+    enriched = result.filter(result["group"] == "enriched").row(0, named=True)
+    depleted = result.filter(result["group"] == "depleted").row(0, named=True)
+    assert enriched["n_variants"] == 20 and depleted["n_variants"] == 0
+    assert np.isclose(enriched["expected"], 2.0, atol=0.5)
+    assert enriched["p-value"] < 0.01 and depleted["p-value"] == 1.0
+
+    with pytest.raises(ValueError):
+        snap.tl.variant_enrichment({"a": ["chr2:0-10"]}, vcf, background=peaks)
+
+    with open(vcf, "a") as f:
+        f.write("chr1\t0\trs_telomere\tN\t.[chr2:100[\n")
+    with pytest.raises(Exception, match="POS must be at least 1"):
+        snap.tl.variant_overlap(peaks, vcf)
+
+def test_cross_species(tmp_path):
+    # chr1 of the source genome is aligned to chrA of the target genome with
+    # an offset of 1000 bases.
+    chain = tmp_path / "ab.chain"
+    with open(chain, "w") as f:
+        f.write("chain 1000 chr1 100000 + 0 100000 chrA 200000 + 1000 101000 1\n100000\n\n")
+    assert snap.tl.liftover(["chr1:100-200", "chr2:100-200"], chain) == ["chrA:1100-1200", None]
+
+    peaks = ["chr1:100-200", "chr1:5000-5500", "chr2:0-100"]
+    other_peaks = ["chrA:1150-1300", "chrA:1190-1210", "chrA:50000-50500"]
+    table, stats = snap.tl.compare_peaks(peaks, other_peaks, chain)
+    assert table["ortholog"].to_list() == ["chrA:1150-1300", None, None]
+    assert table["mapped_region"].to_list() == ["chrA:1100-1200", "chrA:6000-6500", None]
+    assert stats["n_mapped"] == 2 and stats["n_conserved"] == 1
+    assert np.isclose(stats["fraction_other_conserved"], 1 / 3)
+
+    a = snap.datasets.simulate(
+        n_cells=20, n_peaks=20, mean_depth=500, chrom_sizes={"chr1": 100_000}, random_state=1,
+    )
+    b = snap.datasets.simulate(
+        n_cells=30, n_peaks=20, mean_depth=500, chrom_sizes={"chrA": 200_000}, random_state=2,
+    )
+    peaks = [f"chr1:{i}-{i + 500}" for i in range(0, 99_000, 1000)]
+    other_peaks = [f"chrA:{i}-{i + 500}" for i in range(1000, 100_000, 1000)]
+    table, _ = snap.tl.compare_peaks(peaks, other_peaks, chain)
+    bulk = snap.tl.cross_species_pseudobulk(
+        a, b, table, ["x"] * 10 + ["y"] * 10, "cell_type", species=("human", "mouse"),
+    )
+    assert bulk.n_vars == table["ortholog"].is_not_null().sum()
+    assert list(bulk.obs["species"]).count("human") == 2
+    mat, _ = snap.pp.make_region_bin_matrix(a, list(bulk.var["human"]), bin_size=500)
+    np.testing.assert_array_equal(bulk.X[:2].toarray(), [mat[:10].sum(axis=0).A1, mat[10:].sum(axis=0).A1])
+
+def test_diff_methylation(tmp_path):
+    rng = np.random.default_rng(0)
+    input_dir = tmp_path / "cells"
+    input_dir.mkdir()
+    # The first region is methylated in group "a" only.
+    for cell in [f"a{i}" for i in range(10)] + [f"b{i}" for i in range(10)]:
+        with open(input_dir / f"{cell}.tsv", "w") as f:
+            f.write("chrom\tpos\tmethylated\tunmethylated\n")
+            for pos in range(100, 200, 10):
+                p = 0.9 if cell.startswith("a") else 0.1
+                m = rng.binomial(5, p)
+                f.write(f"chr1\t{pos}\t{m}\t{5 - m}\n")
+            for pos in range(1000, 1100, 10):
+                m = rng.binomial(5, 0.5)
+                f.write(f"chr1\t{pos}\t{m}\t{5 - m}\n")
+    data = snap.pp.import_values(input_dir, {"chr1": 2000})
+    group1 = [x.startswith("a") for x in data.obs_names]
+    group2 = [not x for x in group1]
+    regions = ["chr1:50-250", "chr1:950-1150"]
+
+    for method in ["fisher", "betabinomial"]:
+        df = snap.tl.diff_methylation(data, regions, group1, group2, method=method)
+        df = {r: row for r, *row in df.iter_rows()}
+        assert set(df.keys()) == set(regions)
+        level1, level2, diff, coverage, pval, _ = df["chr1:50-250"]
+        assert level1 > 0.7 and level2 < 0.3 and diff == pytest.approx(level1 - level2)
+        assert coverage == 20 * 10 * 5
+        assert pval < 1e-6
+        assert df["chr1:950-1150"][4] > 1e-3
+
+    df = snap.tl.diff_methylation(data, regions, group1, group2, min_diff=0.3)
+    assert df["region"].to_list() == ["chr1:50-250"]
+
+def test_donor_association():
+    import pandas as pd
+
+    rng = np.random.default_rng(0)
+    n_donors, n_cells = 12, 20
+    age = np.linspace(20, 80, n_donors)
+    sex = np.array(["F", "M"] * (n_donors // 2))
+    rates = np.ones((n_donors, 50)) * 5.0
+    # Feature 0 increases with age, feature 1 depends on sex only.
+    rates[:, 0] *= age / 20
+    rates[:, 1] *= np.where(sex == "M", 4.0, 1.0)
+    X = np.concatenate([rng.poisson(np.tile(r, (n_cells, 1))) for r in rates])
+    donors = np.repeat([f"d{i}" for i in range(n_donors)], n_cells)
+    adata = ad.AnnData(
+        X=csr_matrix(X.astype(np.float64)),
+        obs=pd.DataFrame(
+            {"donor": donors, "age": np.repeat(age, n_cells), "sex": np.repeat(sex, n_cells)},
+            index=[f"c{i}" for i in range(len(donors))],
+        ),
+    )
+    adata.var_names = [f"f{i}" for i in range(50)]
+
+    result = snap.tl.donor_association(adata, "donor", "age", covariates=["sex"])
+    assert result.height == 50
+    top = result.row(0, named=True)
+    assert top["feature"] == "f0" and top["coefficient"] > 0
+    assert top["adjusted p-value"] < 0.01
+    assert result.filter(result["feature"] == "f1")["p-value"][0] > 0.01
+
+    table = pd.DataFrame({"sex": sex}, index=[f"d{i}" for i in range(n_donors)])
+    result = snap.tl.donor_association(adata, "donor", "sex", donor_covariates=table)
+    assert result["feature"][0] == "f1"
+
+    with pytest.raises(ValueError):
+        snap.tl.donor_association(adata, "donor", "age", min_cells=n_cells + 1)
+
+def test_peak_set_overlap(tmp_path):
+    a = ["chr1:100-200", "chr1:150-300", "chr1:1000-1100", "chr2:0-100"]
+    b = ["chr1:250-400", "chr2:50-150", "chr3:0-100"]
+    bed = tmp_path / "b.bed"
+    bed.write_text("".join(x.replace(":", "\t").replace("-", "\t") + "\n" for x in b))
+
+    result = snap.tl.peak_set_overlap({"a": a, "b": bed}, genome={"chr1": 10000, "chr2": 10000, "chr3": 10000})
+    row = result.row(0, named=True)
+    assert (row["set1"], row["set2"]) == ("a", "b")
+    assert (row["n_peaks1"], row["n_peaks2"]) == (4, 3)
+    assert (row["n_overlap1"], row["n_overlap2"]) == (2, 2)
+    # Covered bases: a = 200 + 100 + 100, b = 150 + 100 + 100, both = 50 + 50.
+    assert row["jaccard"] == pytest.approx(100 / (400 + 350 - 100))
+    assert row["jaccard_lower"] <= row["jaccard_upper"]
+    assert 0 < row["p-value"] < 0.05
+
+    result = snap.tl.peak_set_overlap({"a": a, "b": b, "c": a}, n_bootstrap=0)
+    assert result.height == 3
+    row = result.filter((result["set1"] == "a") & (result["set2"] == "c")).row(0, named=True)
+    assert row["jaccard"] == 1.0 and row["n_overlap1"] == 4
+    assert row["jaccard_lower"] is None and row["p-value"] is None